@@ -1,28 +1,72 @@
 //! AHCI Disk
 
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
 use core::fmt::{self, Debug, Formatter};
+use core::mem::size_of;
+use core::task::{Context, Poll, Waker};
 
 use spin::Mutex;
 
 use sunrise_libuser::error::{Error, AhciError};
-use sunrise_libuser::ahci::IDisk as IDiskInterface;
+use sunrise_libuser::ahci::IDiskAsync as IDiskInterface;
 use sunrise_libuser::futures::WorkQueue;
+use sunrise_libuser::futures_rs::future::{self, FutureObj};
+use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+use sunrise_libuser::syscalls::MemoryPermissions;
+use sunrise_libuser::types::{MappedSharedMemory, SharedMemory};
 use sunrise_libuser::zero_box::ZeroBox;
 use sunrise_libuser::ahci::Block;
 
 use crate::hba::*;
+use sunrise_libuser::dma::Dma;
 
+/// The state of a command slot.
+#[derive(Debug)]
+pub enum Slot {
+    /// No command is using this slot.
+    Free,
+    /// A command was issued in this slot, and the device is processing it.
+    ///
+    /// Holds the waker of the task waiting for its completion, if it already polled it.
+    Issued(Option<Waker>),
+    /// The command was completed, but the task that issued it hasn't collected its result yet.
+    Completed(Result<(), Error>),
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Slot::Free
+    }
+}
 
 /// An AHCI Disk
 ///
 /// Manages an AHCI port, and provides functions to read and write sectors.
 ///
+/// The port is either connected to an ATA device, like a hard drive, or to a read-only ATAPI
+/// device, like a CD-ROM drive. The sectors of the latter are 2048 octets long, but a Disk
+/// always exposes 512-octet sectors.
+///
+/// # Commands
+///
+/// Up to `slot_count` commands can be outstanding at the same time, each of them in its own
+/// command slot. Tasks issuing a command when every slot is in use are woken up when one
+/// is released.
+///
+/// Commands are completed by [handle_interrupt], which is called when the HBA raises an interrupt
+/// for this port. It wakes up the tasks waiting for them.
+///
+/// [handle_interrupt]: Disk::handle_interrupt
+///
 /// # Memory
 ///
-/// A disk is responsible for all allocated memory in use by the port. When dropped, the port
-/// is put to a stop, pointers to these regions are cleared from the hardware, and the regions
-/// are eventually de-allocated.
+/// A disk is responsible for all allocated memory in use by the port, which it maps in the
+/// device address space of the HBA. When dropped, the port is put to a stop, pointers to these
+/// regions are cleared from the hardware, they are unmapped from the device address space, and
+/// the regions are eventually de-allocated.
 ///
 /// # Lifetime
 ///
@@ -36,6 +80,8 @@ pub struct Disk {
 
     /// Pointer back to the corresponding Port Control Registers, found at `BAR5[100h]`-`BAR5[10FFh]`.
     pub(super) px:         &'static mut Px,
+    /// How the HBA accesses memory, shared with the other ports.
+    pub(super) dma:        Arc<Mutex<Dma>>,
     /// The allocated Received FIS memory zone that the port uses.
     pub(super) rfis:       ZeroBox<ReceivedFis>,
     /// The allocated Command List memory zone that the port uses.
@@ -43,114 +89,301 @@ pub struct Disk {
     /// An allocated Command Table for each implemented Command List slot.
     pub(super) cmd_tables: [Option<ZeroBox<CmdTable>>; 32],
 
+    // command slots
+
+    /// The state of every command slot. Only the first `slot_count` ones are used.
+    pub(super) slots: [Slot; 32],
+    /// The number of slots we use: the number of slots implemented by the HBA,
+    /// capped by the device's queue depth if we use NCQ.
+    pub(super) slot_count: usize,
+    /// The wakers of the tasks waiting for a slot to be released.
+    pub(super) slot_waiters: Vec<Waker>,
+    /// Set when the port could not be recovered from an error. No command can be issued anymore.
+    pub(super) failed: bool,
+
     // info obtained by the IDENTIFY command
 
     /// Number of addressable sectors of this disk. Each sector is 512 octets.
+    ///
+    /// For an ATAPI device, this is four times the number of sectors of its medium.
     pub(super) sectors: u64,
     /// Indicates if the device supports 48 bit addresses.
     pub(super) supports_48_bit: bool,
+    /// Indicates if both the HBA and the device support NCQ, in which case we only issue queued commands.
+    pub(super) supports_ncq: bool,
+    /// Indicates if the device is an ATAPI device, which we only read with packet commands.
+    pub(super) atapi: bool,
 }
 
 impl Disk {
-    /// Returns the number of addressable 512-octet sectors for this disk.
-    #[inline(never)]
-    fn sector_count(&self, ) -> Result<u64, Error> {
-        Ok(self.sectors)
-    }
-
-    /// Reads sectors from disk.
+    /// Checks the arguments of a transfer of `sector_count` sectors starting from `lba`,
+    /// to or from the `buffer_len` bytes at `buffer`.
     ///
-    /// Reads `sector_count` sectors starting from `lba`.
-    #[inline(never)]
-    fn read_dma(&mut self, buffer: *mut u8, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
-        if ((buffer as usize) % 2) != 0 {
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - `buffer` is not word aligned,
+    ///     - `buffer_len` is smaller than `sector_count` sectors,
+    ///     - `lba + sector_count` is higher than the number of addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    fn check_transfer(&self, buffer: usize, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
+        if buffer % 2 != 0 || sector_count == 0 {
             return Err(AhciError::InvalidArg.into());
         }
-
-        if (buffer_len as u64) < sector_count * 512 {
+        if sector_count.checked_mul(512).filter(|size| *size <= (buffer_len as u64)).is_none() {
             return Err(AhciError::InvalidArg.into());
         }
         if lba.checked_add(sector_count).filter(|sum| *sum <= self.sectors).is_none() {
             return Err(AhciError::InvalidArg.into());
         }
-        // todo: AHCI: Read CI and figure out which slot to use
-        // body: For now AHCI driver is single-threaded and blocking,
-        // body: which means that the first slot is always available for use.
-        // body:
-        // body: If we want to make a multi-threaded implementation,
-        // body: we will have to implement some logic to choose the slot.
-        let command_slot_index = 0;
-        let step = if !self.supports_48_bit {
+        Ok(())
+    }
+
+    /// Returns the maximum number of sectors a single command can transfer.
+    ///
+    /// For ATAPI devices, it is a multiple of 4, so a command always transfers whole 2048-octet
+    /// sectors.
+    fn max_sectors_per_command(&self) -> u64 {
+        if self.supports_48_bit || self.supports_ncq || self.atapi {
+            65536
+        } else {
             256
+        }
+    }
+
+    /// Issues a transfer of `sector_count` sectors starting from `lba`, to or from the buffer made
+    /// of `segments`, as the HBA sees them, in a free slot.
+    ///
+    /// If every slot is in use, the task is registered to be woken up when one is released.
+    ///
+    /// # Returns
+    ///
+    /// The slot the command was issued in. Its result must be collected with
+    /// [poll_completion](Disk::poll_completion).
+    ///
+    /// # Unsafety
+    ///
+    /// `segments` must be the device addresses and lengths of a buffer of at least
+    /// `sector_count * 512` bytes, which must stay mapped until the command is completed.
+    ///
+    /// # Error
+    ///
+    /// - IoError: the port failed, and cannot process commands anymore.
+    /// - ReadOnly: this is an ATAPI device, and `transfer` is a write.
+    /// - InvalidArg: this is an ATAPI device, and `lba` or `sector_count` is not a multiple of 4.
+    /// - see [Px::issue_dma] and [Px::issue_atapi_read].
+    unsafe fn poll_issue(&mut self, cx: &mut Context, transfer: Transfer, segments: &[(u64, u32)], lba: u64, sector_count: u64) -> Poll<Result<usize, Error>> {
+        if self.failed {
+            return Poll::Ready(Err(AhciError::IoError.into()));
+        }
+        let slot = match self.slots[..self.slot_count].iter().position(|slot| matches!(slot, Slot::Free)) {
+            Some(slot) => slot,
+            None => {
+                self.slot_waiters.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        // safe: - the slot is free, so its bit is clear in PxCI and PxSACT,
+        //       - slot < slot_count, so it is implemented, and we give its cmd_header and cmd_table,
+        //       - px is initialised and started,
+        //       - the buffer is mapped for the duration of the command, our caller guarantees it.
+        let issued = if self.atapi {
+            if transfer == Transfer::Write {
+                return Poll::Ready(Err(AhciError::ReadOnly.into()));
+            }
+            if lba % 4 != 0 || sector_count % 4 != 0 {
+                return Poll::Ready(Err(AhciError::InvalidArg.into()));
+            }
+            self.px.issue_atapi_read(
+                segments,
+                lba / 4,
+                sector_count / 4,
+                &mut self.cmd_list.slots[slot],
+                self.cmd_tables[slot].as_mut().unwrap(),
+                slot
+            )
         } else {
-            65536
+            self.px.issue_dma(
+                transfer,
+                segments,
+                lba,
+                sector_count,
+                &mut self.cmd_list.slots[slot],
+                self.cmd_tables[slot].as_mut().unwrap(),
+                slot,
+                self.supports_48_bit,
+                self.supports_ncq
+            )
         };
+        Poll::Ready(issued.map(|()| {
+            self.slots[slot] = Slot::Issued(None);
+            slot
+        }))
+    }
 
-        for sector_step in (0..sector_count).step_by(step) {
-            unsafe {
-                // safe: - we just mapped buffer, so it is valid memory,
-                //         and buffer_len is its length
-                //         otherwise mapping it would have failed.
-                //       - buffer[0..buffer_len] falls in a single mapping,
-                //         we just mapped it.
-                //       - command_slot_index is 0, which is always implemented (spec),
-                //         and we give the cmd_header and cmd_table of this index.
-                //       - px is initialised.
-                Px::read_dma(
-                    buffer.add(sector_step as usize * 512),
-                    buffer_len - sector_step as usize * 512,
-                    lba + sector_step,
-                    core::cmp::min(sector_count - sector_step, step as u64),
-                    self.px,
-                    &mut self.cmd_list.slots[command_slot_index],
-                    self.cmd_tables[command_slot_index].as_mut().unwrap(),
-                    command_slot_index,
-                    self.supports_48_bit
-                )?
-            }
+    /// Collects the result of the command issued in `slot`, releasing the slot.
+    ///
+    /// If the command is not completed yet, the task is registered to be woken up when it is.
+    ///
+    /// # Panics
+    ///
+    /// * No command was issued in `slot`.
+    fn poll_completion(&mut self, cx: &mut Context, slot: usize) -> Poll<Result<(), Error>> {
+        match core::mem::replace(&mut self.slots[slot], Slot::Free) {
+            Slot::Completed(result) => {
+                for waker in self.slot_waiters.drain(..) {
+                    waker.wake();
+                }
+                Poll::Ready(result)
+            },
+            Slot::Issued(_) => {
+                self.slots[slot] = Slot::Issued(Some(cx.waker().clone()));
+                Poll::Pending
+            },
+            Slot::Free => panic!("Waiting for the completion of free slot {}", slot),
         }
-        Ok(())
     }
 
-    /// Writes sectors to disk.
+    /// Handles the interrupts of this disk's port.
     ///
-    /// Writes `sector_count` sectors starting from `lba`.
-    #[inline(never)]
-    fn write_dma(&mut self, buffer: *const u8, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
-        if ((buffer as usize) % 2) != 0 {
-            return Err(AhciError::InvalidArg.into());
+    /// Clears the pending interrupts, and completes every command whose slot is not running
+    /// anymore, waking up the task waiting for it.
+    ///
+    /// If an error occurred, the port is recovered, and all issued commands are failed.
+    /// If it cannot be recovered, the disk is marked failed, and will not accept commands anymore.
+    pub fn handle_interrupt(&mut self) {
+        let (running, errored) = match self.px.acknowledge_interrupts() {
+            Ok(()) => (self.px.running_commands(), false),
+            Err(_) => {
+                self.failed = !self.px.recover();
+                // stopping the port aborted every issued command.
+                (0, true)
+            }
+        };
+        for (index, slot) in self.slots[..self.slot_count].iter_mut().enumerate() {
+            if running & (1u32 << index) != 0 {
+                continue;
+            }
+            if let Slot::Issued(waker) = slot {
+                let waker = waker.take();
+                *slot = Slot::Completed(if errored {
+                    Err(AhciError::IoError.into())
+                } else {
+                    Ok(())
+                });
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
         }
+    }
+}
 
-        if (buffer_len as u64) < sector_count * 512 {
-            return Err(AhciError::InvalidArg.into());
+/// Reads or writes `sector_count` sectors starting from `lba`, to or from the `buffer_len` bytes
+/// at `buffer`.
+///
+/// ATAPI devices can only read whole 2048-octet sectors. When the transfer is not aligned on
+/// them, the sectors covering it are read to a bounce buffer, and the requested part is copied
+/// to `buffer`.
+///
+/// The buffer must stay valid until the returned future is resolved.
+///
+/// # Error
+///
+/// - see [Disk::check_transfer].
+/// - ReadOnly: this is an ATAPI device, and `transfer` is a write.
+/// - see [transfer_mapped].
+async fn transfer_sectors(disk: Arc<Mutex<Disk>>, transfer: Transfer, buffer: usize, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
+    let atapi = {
+        let disk = disk.lock();
+        disk.check_transfer(buffer, buffer_len, lba, sector_count)?;
+        if disk.atapi && transfer == Transfer::Write {
+            return Err(AhciError::ReadOnly.into());
         }
-        if lba.checked_add(sector_count).filter(|sum| *sum <= self.sectors).is_none() {
-            return Err(AhciError::InvalidArg.into());
+        disk.atapi
+    };
+    if !atapi || (lba % 4 == 0 && sector_count % 4 == 0) {
+        return transfer_mapped(disk, transfer, buffer, lba, sector_count).await;
+    }
+
+    // can't overflow, the sector count of an ATAPI disk is a multiple of 4.
+    let first_lba = lba & !3;
+    let last_lba = (lba + sector_count + 3) & !3;
+    // word aligned, as required for DMA.
+    let mut bounce = vec![0u32; ((last_lba - first_lba) * 512 / 4) as usize];
+    let bounce_address = bounce.as_mut_ptr() as usize;
+    transfer_mapped(disk, Transfer::Read, bounce_address, first_lba, last_lba - first_lba).await?;
+    unsafe {
+        // safe: the bounce buffer covers lba..lba + sector_count, and check_transfer made sure
+        //       the `buffer_len` bytes at `buffer` can hold them. Our caller keeps it valid.
+        core::ptr::copy_nonoverlapping(
+            (bounce.as_ptr() as *const u8).add(((lba - first_lba) * 512) as usize),
+            buffer as *mut u8,
+            sector_count as usize * 512);
+    }
+    Ok(())
+}
+
+/// Reads or writes `sector_count` sectors starting from `lba`, to or from the buffer at `buffer`.
+///
+/// The buffer is mapped in the device address space of the HBA, and the transfer is split in as
+/// many commands as needed, issued in free slots as they become available. They are all
+/// processed concurrently, along with the commands issued by other tasks.
+///
+/// The arguments must have been checked by [Disk::check_transfer], and the buffer must stay
+/// valid until the returned future is resolved.
+///
+/// # Error
+///
+/// - mapping `buffer` in the device address space failed.
+/// - getting the physical segments of `buffer` failed.
+/// - IoError: the device failed processing a command.
+/// - see [Disk::poll_issue].
+async fn transfer_mapped(disk: Arc<Mutex<Disk>>, transfer: Transfer, buffer: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
+    let length = sector_count as usize * 512;
+    let (dma, step) = {
+        let disk = disk.lock();
+        let perm = match transfer {
+            Transfer::Read => MemoryPermissions::WRITABLE,
+            Transfer::Write => MemoryPermissions::READABLE,
+        };
+        disk.dma.lock().map(buffer as *const u8, length, perm)?;
+        (Arc::clone(&disk.dma), disk.max_sectors_per_command())
+    };
+
+    let mut slots = Vec::new();
+    let mut result = Ok(());
+    for sector_step in (0..sector_count).step_by(step as usize) {
+        let count = min(sector_count - sector_step, step);
+        let segments = match dma.lock().segments(buffer + sector_step as usize * 512, count as usize * 512) {
+            Ok(segments) => segments,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        let issued = future::poll_fn(|cx| unsafe {
+            // safe: the buffer stays mapped until every issued command is completed.
+            disk.lock().poll_issue(cx, transfer, &segments, lba + sector_step, count)
+        }).await;
+        match issued {
+            Ok(slot) => slots.push(slot),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
         }
-        let command_slot_index = 0;
-        unsafe {
-            // safe: - we just mapped buffer, so it is valid memory,
-            //         and buffer_len is its length
-            //         otherwise mapping it would have failed.
-            //       - buffer[0..buffer_len] falls in a single mapping,
-            //         we just mapped it.
-            //       - command_slot_index is 0, which is always implemented (spec),
-            //         and we give the cmd_header and cmd_table of this index.
-            //       - px is initialised.
-            Px::write_dma(
-                buffer,
-                buffer_len,
-                lba,
-                sector_count,
-                self.px,
-                &mut self.cmd_list.slots[command_slot_index],
-                self.cmd_tables[command_slot_index].as_mut().unwrap(),
-                command_slot_index,
-                self.supports_48_bit
-            )?
+    }
+    // wait for every issued command, even on error, the device might still be using the buffer.
+    for slot in slots {
+        let completed = future::poll_fn(|cx| disk.lock().poll_completion(cx, slot)).await;
+        if result.is_ok() {
+            result = completed;
         }
-        Ok(())
     }
+    // the commands are over, the device is done with the buffer.
+    dma.lock().unmap(buffer as *const u8, length);
+    result
 }
 
 impl Drop for Disk {
@@ -158,6 +391,7 @@ impl Drop for Disk {
     fn drop(&mut self) {
         self.px.stop();
         self.px.clear_addresses();
+        unmap_port_structures(&mut self.dma.lock(), &self.rfis, &self.cmd_list, &self.cmd_tables);
     }
 }
 
@@ -165,26 +399,103 @@ impl Debug for Disk {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         f.debug_struct("Disk")
             .field("sectors", &self.sectors)
+            .field("supports_ncq", &self.supports_ncq)
+            .field("atapi", &self.atapi)
+            .field("slot_count", &self.slot_count)
             .field("px", &self.px)
             .finish()
     }
 }
 
+/// A buffer shared with a client, registered with
+/// [register_shared_buffer](IDiskInterface::register_shared_buffer).
+///
+/// It is mapped in our address space, and in the device address space of the HBA for as long as
+/// it is registered, so the transfers using it don't have to map it again.
+#[derive(Debug)]
+struct SharedBuffer {
+    /// Our mapping of the shared memory.
+    memory: MappedSharedMemory,
+    /// The device address space of the HBA, where the shared memory is mapped.
+    dma: Arc<Mutex<Dma>>,
+}
+
+impl SharedBuffer {
+    /// Maps the `size` bytes of `buffer`, in our address space and in `dma`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `size` is 0, or not page aligned.
+    /// - `size` is not the size of the shared memory.
+    /// - mapping it in the device address space failed.
+    fn new(buffer: SharedMemory, size: u64, dma: Arc<Mutex<Dma>>) -> Result<Self, Error> {
+        let size = size as usize;
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        let address = find_free_address(size, PAGE_SIZE)?;
+        let memory = buffer.map(address, size, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
+        // the device both reads and writes it.
+        dma.lock().map(memory.as_ptr(), size, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
+        Ok(SharedBuffer { memory, dma })
+    }
+
+    /// Returns the address of the buffer at `offset`, and the number of bytes after it.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `offset` is out of the buffer.
+    fn range(&self, offset: u64) -> Result<(usize, usize), Error> {
+        if offset >= self.memory.len() as u64 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        Ok((self.memory.as_ptr() as usize + offset as usize, self.memory.len() - offset as usize))
+    }
+}
+
+impl Drop for SharedBuffer {
+    /// Removes the buffer from the device address space. No transfer can be using it, as they
+    /// hold a reference to it.
+    fn drop(&mut self) {
+        self.dma.lock().unmap(self.memory.as_ptr(), self.memory.len());
+    }
+}
+
+/// Transfers sectors to or from the shared buffer, at `offset`.
+///
+/// # Error
+///
+/// - InvalidArg: no buffer was registered, or `offset` is out of it.
+/// - see [transfer_sectors].
+async fn transfer_shared(disk: Arc<Mutex<Disk>>, shared_buffer: Option<Arc<SharedBuffer>>, transfer: Transfer, offset: u64, lba: u64, sector_count: u64) -> Result<(), Error> {
+    let shared_buffer = shared_buffer.ok_or(AhciError::InvalidArg)?;
+    let (buffer, buffer_len) = shared_buffer.range(offset)?;
+    // holding the shared buffer keeps it mapped until the transfer is over.
+    transfer_sectors(disk, transfer, buffer, buffer_len, lba, sector_count).await
+}
+
 /// Interface to a disk.
+///
+/// Each session has its own interface, and its own shared buffer.
 #[derive(Debug, Clone)]
-pub struct IDisk(Arc<Mutex<Disk>>);
+pub struct IDisk {
+    /// The disk this session accesses.
+    disk: Arc<Mutex<Disk>>,
+    /// The buffer registered by the client, if any.
+    shared_buffer: Option<Arc<SharedBuffer>>,
+}
 
 impl IDisk {
     /// Creates an IDisk from the wrapped [Disk].
     pub fn new(value: Arc<Mutex<Disk>>) -> Self {
-        Self(value)
+        Self { disk: value, shared_buffer: None }
     }
 }
 
 impl IDiskInterface for IDisk {
     /// Returns the number of addressable 512-octet sectors for this disk.
-    fn sector_count(&mut self, _work_queue: WorkQueue<'static>) -> Result<u64, Error> {
-        Ok(self.0.lock().sectors)
+    fn sector_count<'a>(&'a mut self, _work_queue: WorkQueue<'static>) -> FutureObj<'a, Result<u64, Error>> {
+        FutureObj::new(Box::new(future::ready(Ok(self.disk.lock().sectors))))
     }
 
     /// Reads sectors from disk.
@@ -194,16 +505,17 @@ impl IDiskInterface for IDisk {
     /// # Error
     ///
     /// - InvalidArg:
-    ///     - `mapping_size` does not reflect the passed handle's size, or mapping it failed,
     ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
     ///        addressable sectors on this disk,
     ///     - `sector_count` == 0.
-    /// - BufferTooScattered:
-    ///     - The passed handle points to memory that is so physically scattered it overflows
-    ///       the PRDT. This can only happen for read/writes of 1985 sectors or more.
-    ///       You should consider retrying with a smaller `sector_count`.
-    fn read_dma(&mut self, _manager: WorkQueue<'static>, address: u64, out_blocks: &mut [sunrise_libuser::ahci::Block]) -> Result<(), Error> {
-        self.0.lock().read_dma(out_blocks.as_mut_ptr() as *mut u8, out_blocks.len() * core::mem::size_of::<Block>(), address, out_blocks.len() as u64)
+    /// - IoError: the device failed reading the sectors.
+    fn read_dma<'a>(&'a mut self, _work_queue: WorkQueue<'static>, address: u64, out_blocks: &'a mut [Block]) -> FutureObj<'a, Result<(), Error>> {
+        let disk = Arc::clone(&self.disk);
+        FutureObj::new(Box::new(async move {
+            let buffer = out_blocks.as_mut_ptr() as usize;
+            let buffer_len = out_blocks.len() * size_of::<Block>();
+            transfer_sectors(disk, Transfer::Read, buffer, buffer_len, address, out_blocks.len() as u64).await
+        }))
     }
 
     /// Writes sectors to disk.
@@ -213,15 +525,75 @@ impl IDiskInterface for IDisk {
     /// # Error
     ///
     /// - InvalidArg:
-    ///     - `mapping_size` does not reflect the passed handle's size, or mapping it failed,
     ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
     ///        addressable sectors on this disk,
     ///     - `sector_count` == 0.
-    /// - BufferTooScattered:
-    ///     - The passed handle points to memory that is so physically scattered it overflows
-    ///       the PRDT. This can only happen for read/writes of 1985 sectors or more.
-    ///       You should consider retrying with a smaller `sector_count`.
-    fn write_dma(&mut self, _manager: WorkQueue<'static>, address: u64, in_blocks: &[sunrise_libuser::ahci::Block]) -> Result<(), Error> {
-        self.0.lock().write_dma(in_blocks.as_ptr() as *const u8, in_blocks.len() * core::mem::size_of::<Block>(), address, in_blocks.len() as u64)
-    }
-}
\ No newline at end of file
+    /// - IoError: the device failed writing the sectors.
+    /// - ReadOnly: this is an ATAPI device, which cannot be written to.
+    fn write_dma<'a>(&'a mut self, _work_queue: WorkQueue<'static>, address: u64, in_blocks: &'a [Block]) -> FutureObj<'a, Result<(), Error>> {
+        let disk = Arc::clone(&self.disk);
+        FutureObj::new(Box::new(async move {
+            let buffer = in_blocks.as_ptr() as usize;
+            let buffer_len = in_blocks.len() * size_of::<Block>();
+            transfer_sectors(disk, Transfer::Write, buffer, buffer_len, address, in_blocks.len() as u64).await
+        }))
+    }
+
+    /// Registers a buffer shared with the client, for [read_shared] and [write_shared].
+    ///
+    /// It replaces the previously registered buffer, which is released once the transfers
+    /// using it are over.
+    ///
+    /// [read_shared]: IDiskInterface::read_shared
+    /// [write_shared]: IDiskInterface::write_shared
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `size` is 0, or not page aligned.
+    /// - `size` is not the size of the shared memory.
+    /// - mapping it in the device address space failed.
+    fn register_shared_buffer<'a>(&'a mut self, _work_queue: WorkQueue<'static>, buffer: SharedMemory, size: u64) -> FutureObj<'a, Result<(), Error>> {
+        let dma = Arc::clone(&self.disk.lock().dma);
+        let registered = SharedBuffer::new(buffer, size, dma).map(|shared_buffer| {
+            self.shared_buffer = Some(Arc::new(shared_buffer));
+        });
+        FutureObj::new(Box::new(future::ready(registered)))
+    }
+
+    /// Reads sectors from disk to the shared buffer.
+    ///
+    /// Reads `sector_count` sectors starting from `lba`, to the shared buffer at `offset`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - no buffer was registered,
+    ///     - `offset + sector_count * 512` is out of the shared buffer,
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the device failed reading the sectors.
+    fn read_shared<'a>(&'a mut self, _work_queue: WorkQueue<'static>, offset: u64, lba: u64, sector_count: u64) -> FutureObj<'a, Result<(), Error>> {
+        let transfer = transfer_shared(Arc::clone(&self.disk), self.shared_buffer.clone(), Transfer::Read, offset, lba, sector_count);
+        FutureObj::new(Box::new(transfer))
+    }
+
+    /// Writes sectors to disk from the shared buffer.
+    ///
+    /// Writes `sector_count` sectors starting from `lba`, from the shared buffer at `offset`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - no buffer was registered,
+    ///     - `offset + sector_count * 512` is out of the shared buffer,
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the device failed writing the sectors.
+    /// - ReadOnly: this is an ATAPI device, which cannot be written to.
+    fn write_shared<'a>(&'a mut self, _work_queue: WorkQueue<'static>, offset: u64, lba: u64, sector_count: u64) -> FutureObj<'a, Result<(), Error>> {
+        let transfer = transfer_shared(Arc::clone(&self.disk), self.shared_buffer.clone(), Transfer::Write, offset, lba, sector_count);
+        FutureObj::new(Box::new(transfer))
+    }
+}
@@ -6,8 +6,8 @@
 //! [Serial ATA AHCI: Specification, Rev. 1.3.1]: http://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/serial-ata-ahci-spec-rev1-3-1.pdf
 
 use sunrise_libuser::io::{Io, Mmio};
-use sunrise_libuser::syscalls::{sleep_thread, query_physical_address};
-use sunrise_libuser::mem::{map_mmio, virt_to_phys};
+use sunrise_libuser::syscalls::{sleep_thread, MemoryPermissions};
+use sunrise_libuser::mem::map_mmio;
 use sunrise_libuser::error::{Error, AhciError};
 use sunrise_libuser::zero_box::*;
 use core::fmt::{self, Debug, Formatter};
@@ -15,8 +15,11 @@ use core::mem::size_of;
 use core::cmp::min;
 use core::time::Duration;
 use alloc::vec::Vec;
+use alloc::sync::Arc;
+use spin::Mutex;
 use crate::fis::*;
 use crate::disk::Disk;
+use sunrise_libuser::dma::Dma;
 use static_assertions::assert_eq_size;
 
 // ---------------------------------------------------------------------------------------------- //
@@ -178,44 +181,61 @@ impl Debug for PortIterator<'_> {
 impl HbaMemoryRegisters {
     /// Initializes an AHCI Controller.
     ///
-    /// `BAR5` is the physical address of HBA Memory Register, usually obtained via PCI discovery.
+    /// `BAR5` is the physical address of HBA Memory Register, and `device` the requester id of
+    /// the controller, usually obtained via PCI discovery.
     ///
-    /// This function will map the root Mmio region, allocate memory for every 'implemented' port,
-    /// put them in the running state, and return an interface to the plugged devices which is up and running.
+    /// This function will attach the controller to a device address space if the system has an
+    /// IOMMU, map the root Mmio region,
+    /// allocate memory for every 'implemented' port, put them in the running state, enable the
+    /// controller's interrupts, and return an interface to the controller and its plugged devices,
+    /// which are up and running.
+    ///
+    /// The interrupts it raises must then be handled by calling [Hba::handle_interrupt].
     ///
     /// # Error
     ///
-    /// If an error occurred, this function will stop trying to initialize the HBA and return an
-    /// empty Vec as if no disks were found. This can happen if:
+    /// If an error occurred, this function will stop trying to initialize the HBA and return
+    /// `None`. This can happen if:
     ///
+    /// * attaching the controller to a device address space failed.
     /// * mapping `BAR5` failed.
     /// * GHC.AE is not set (controller is in legacy support mode), because conditions specified in
     ///   section 10.2 are tedious.
-    pub fn init(bar5: usize) -> Vec<Disk> {
+    pub fn init(bar5: usize, device: u16) -> Option<Hba> {
+        let dma = match Dma::new(device) {
+            Ok(dma) => Arc::new(Mutex::new(dma)),
+            Err(e) => {
+                error!("HBA {:#010x}, initialization failed: failed attaching it to a device address space, {:?}.", bar5, e);
+                return None
+            }
+        };
         let mapping = match map_mmio::<HbaMemoryRegisters>(bar5 as _) {
             Ok(vaddr) => vaddr,
             Err(e) => {
                 error!("HBA {:#010x}, initialization failed: failed mapping BAR5, {:?}.", bar5, e);
-                return Vec::new()
+                return None
             }
         };
         let ghc_registers = unsafe {
             // constructing a reference to the Generic Host Control registers we just mapped.
             // safe, nobody has a reference to it yet.
+            // the reference's lifetime is tied to the returned Hba structure,
+            // which will never outlive the mapping.
             &mut (*mapping).generic_host_control
         };
         // check that system software is AHCI aware by setting GHC.AE to ‘1’.
         if !ghc_registers.ghc.read().ae() {
             error!("HBA {:#010x}, initialization failed: controller is in legacy support mode.", bar5);
-            return Vec::new();
+            return None;
         }
 
-        // globally disable interrupts for this controller
+        // globally disable interrupts for this controller while we initialize the ports
         let mut ghc = ghc_registers.ghc.read();
         ghc.set_ie(false);
         ghc_registers.ghc.write(ghc);
 
-        let command_list_len = ghc_registers.cap.read().ncs() as usize + 1;
+        let cap = ghc_registers.cap.read();
+        let command_list_len = cap.ncs() as usize + 1;
         let pi = ghc_registers.pi.read();
 
         let port_registers = unsafe {
@@ -226,14 +246,79 @@ impl HbaMemoryRegisters {
             &mut (*mapping).ports
         };
         // Initialize ports marked implemented by PI.
-        port_registers.iter_mut()
+        let disks = port_registers.iter_mut()
             .enumerate()
             // filter out ports not implemented
             .filter(|(index, _)| (pi & (1u32 << index)) != 0)
             // init each port, keep only successful ones
-            .filter_map(|(_port_index, px)| Px::init(px, command_list_len))
+            .filter_map(|(port_index, px)| {
+                Px::init(px, command_list_len, cap.sncq(), &dma)
+                    .map(|disk| (port_index, Arc::new(Mutex::new(disk))))
+            })
             // put that in a vec
-            .collect()
+            .collect();
+
+        // clear the pending interrupts of every port, and enable interrupts.
+        ghc_registers.is.write(pi);
+        let mut ghc = ghc_registers.ghc.read();
+        ghc.set_ie(true);
+        ghc_registers.ghc.write(ghc);
+
+        Some(Hba { ghc: ghc_registers, disks })
+    }
+}
+
+/// An initialized AHCI Controller.
+///
+/// Holds the controller's Generic Host Control registers, and the [Disk]s found on its ports.
+///
+/// The controller raises an interrupt when a command completes or fails on any of its ports.
+/// It is the responsibility of the owner of the Hba to call [handle_interrupt] when it does,
+/// so the disks can complete their commands.
+///
+/// [handle_interrupt]: Hba::handle_interrupt
+#[derive(Debug)]
+pub struct Hba {
+    /// The Generic Host Control registers, found at `BAR5[0x00]-BAR5[0x2B]`.
+    ghc: &'static mut GenericHostControl,
+    /// The disks connected to the controller, and the index of the port they're connected to.
+    disks: Vec<(usize, Arc<Mutex<Disk>>)>,
+}
+
+impl Hba {
+    /// Returns the disks connected to the controller.
+    pub fn disks(&self) -> impl Iterator<Item = &Arc<Mutex<Disk>>> {
+        self.disks.iter().map(|(_, disk)| disk)
+    }
+
+    /// Handles the controller's pending interrupts.
+    ///
+    /// For every port whose bit is set in `IS`, the port's interrupts are handled by its [Disk],
+    /// and the bit is cleared from `IS`, as described in section 10.7.2.
+    ///
+    /// This is repeated until `IS` reads 0, so that the interrupt line is de-asserted before
+    /// we return, and any later command completion raises a new interrupt.
+    /// Ports without a disk never have their interrupts enabled.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the controller had no pending interrupt, as the IRQ line may be shared with
+    /// other devices, and `Some(())` otherwise.
+    pub fn handle_interrupt(&mut self) -> Option<()> {
+        let mut is = self.ghc.is.read();
+        if is == 0 {
+            return None
+        }
+        while is != 0 {
+            for (port_index, disk) in self.disks.iter() {
+                if is & (1u32 << port_index) != 0 {
+                    disk.lock().handle_interrupt();
+                }
+            }
+            self.ghc.is.write(is);
+            is = self.ghc.is.read();
+        }
+        Some(())
     }
 }
 
@@ -271,6 +356,16 @@ pub struct Px {
     vendor: [Mmio<u32>; 4], // 0x70 ~ 0x7F, vendor specific
 }
 
+/// The value of `PxSIG` when the port is connected to an ATAPI device.
+///
+/// See section 3.3.9, and ATA spec's signatures.
+const SATA_SIG_ATAPI: u32 = 0xEB14_0101;
+
+/// The length of a sector of an ATAPI device, in octets.
+///
+/// We only support media with 2048-octet sectors, which is what CD-ROMs use.
+pub const ATAPI_SECTOR_SIZE: u32 = 2048;
+
 bitfield!{
     /// `PxIS` "Port x Interrupt status" register bitfield.
     ///
@@ -450,7 +545,7 @@ impl Px {
 
     /// Start this port.
     ///
-    /// Makes `PxCLB` point to `command_list`,
+    /// Makes `PxCLB` point to `command_list`, the device address of the command list,
     /// sets `PxCMD.ST`, and waits for `PxCMD.CR` to be set.
     ///
     /// See section 10.3.1 for conditions to meet before calling this function.
@@ -465,15 +560,13 @@ impl Px {
     /// * `PxCMD.FRE` is not set.
     /// * `PxCMD.CR` is already set.
     /// * A functional device is not present: `probe` returned false.
-    unsafe fn start(&mut self, command_list: &mut CmdHeaderArray) {
+    unsafe fn start(&mut self, command_list: u64) {
         let mut cmd = self.cmd.read();
         assert_eq!(cmd.fre(), true, "Trying to start port: PxCMD.FRE is not set");
         assert_eq!(cmd.cr(), false, "Trying to start port: PxCMD.CR is already set");
         assert_eq!(self.probe(),    true, "Trying to start port: No device detected");
         // write PxCLB
-        self.clb.write(
-            virt_to_phys(command_list) as u64
-        );
+        self.clb.write(command_list);
         // set PxCMD.ST
         cmd.set_st(true);
         self.cmd.write(cmd);
@@ -514,7 +607,7 @@ impl Px {
 
     /// Enable FIS Receive.
     ///
-    /// Make `PxFB` point to `memory_area`, and sets `PxCMD.FRE`.
+    /// Make `PxFB` point to `memory_area`, the device address of a [ReceivedFis], and sets `PxCMD.FRE`.
     ///
     /// # Unsafety
     ///
@@ -525,13 +618,11 @@ impl Px {
     ///
     /// * Port is running: `PxCMD.ST` or `PxCMD.CR` is set.
     /// * FIS Receive is already running: `PxCMD.FRE` or `PxCMD.FR` is set.
-    pub unsafe fn enable_fis_receive(&mut self, memory_area: &mut ReceivedFis) {
+    pub unsafe fn enable_fis_receive(&mut self, memory_area: u64) {
         let mut cmd = self.cmd.read();
         assert_eq!(cmd.st()  || cmd.cr(), false, "Trying to enable port FIS Receive: Port is running");
         assert_eq!(cmd.fre() || cmd.fr(), false, "Trying to enable port FIS Receive: FIS Receive is already running");
-        self.fb.write(
-            virt_to_phys(memory_area) as u64
-        );
+        self.fb.write(memory_area);
         // set PxCMD.FRE
         cmd.set_fre(true);
         self.cmd.write(cmd);
@@ -567,9 +658,15 @@ impl Px {
 
     /// Initializes a port, returning a [Disk] to interface with it.
     ///
+    /// The memory structures of the port are mapped in `dma`, through which the HBA accesses memory.
+    ///
+    /// The disk will use NCQ if both the HBA (`hba_supports_ncq`) and the device support it.
+    /// Once initialized, the port's interrupts are enabled.
+    ///
     /// If the port is not connected to anything, or initialisation failed,
     /// this function returns `None`.
-    fn init(port_registers: &'static mut Px, command_list_length: usize) -> Option<Disk> {
+    fn init(port_registers: &'static mut Px, command_list_length: usize, hba_supports_ncq: bool, dma: &Arc<Mutex<Dma>>) -> Option<Disk> {
+        port_registers.ie.write(PxIE(0x00)); // no interrupts
         port_registers.stop();
         port_registers.disable_fis_receive();
         if !port_registers.probe() {
@@ -578,56 +675,138 @@ impl Px {
             return None;
         }
 
-        port_registers.ie.write(PxIE(0x00)); // no interrupts
-        let mut received_fis = ZeroBox::<ReceivedFis>::new_zeroed();
+        let received_fis = ZeroBox::<ReceivedFis>::new_zeroed();
+        let cmd_list = ZeroBox::<CmdHeaderArray>::new_zeroed();
+        let mut cmd_tables: [Option<ZeroBox<CmdTable>>; 32] = Default::default();
+        for table in cmd_tables[0..command_list_length].iter_mut() {
+            *table = Some(ZeroBox::<CmdTable>::new_zeroed());
+        }
+        let (rfis_address, cmd_list_address, cmd_table_addresses) =
+            match map_port_structures(&mut dma.lock(), &received_fis, &cmd_list, &cmd_tables) {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    error!("Initializing port failed: mapping its structures for DMA failed. Error: {:?}", e);
+                    port_registers.clear_addresses();
+                    return None
+                }
+            };
+
+        // From now on, dropping the disk stops the port and unmaps its structures.
+        let mut disk = Disk {
+            px: port_registers,
+            dma: Arc::clone(dma),
+            rfis: received_fis,
+            cmd_list,
+            cmd_tables,
+            slots: Default::default(),
+            slot_count: command_list_length,
+            slot_waiters: Vec::new(),
+            failed: false,
+            sectors: 0,
+            supports_48_bit: false,
+            supports_ncq: false,
+            atapi: false,
+        };
+
         unsafe {
             // safe: when the Disk is dropped we make sure to call `clear_addresses`.
-            port_registers.enable_fis_receive(&mut *received_fis);
+            disk.px.enable_fis_receive(rfis_address);
         }
-        let mut cmd_list = ZeroBox::<CmdHeaderArray>::new_zeroed();
         // init the command list
-        let mut cmd_tables: [Option<ZeroBox<CmdTable>>; 32] = Default::default();
-        for (i, header) in cmd_list.slots[0..command_list_length].iter_mut().enumerate() {
-            let mut table = ZeroBox::<CmdTable>::new_zeroed();
+        for (header, table_address) in disk.cmd_list.slots.iter_mut().zip(cmd_table_addresses) {
             unsafe {
                 // safe: - `table` has just been allocated, it is not pointed to by anyone else.
                 //       - port is stopped.
-                header.init(&mut table);
+                header.init(table_address);
             }
-            cmd_tables[i] = Some(table);
         }
         unsafe {
             // safe: when the port is dropped we make sure to call `clear_addresses`.
-            port_registers.start(&mut cmd_list);
+            disk.px.start(cmd_list_address);
         }
         // clear PxSERR by writing '1' to every non-reserved bit.
         // would have done a bitfield, but it provides no "set all" function.
-        port_registers.serr.write(0b00000111111111110000111100000011u32);
+        disk.px.serr.write(0b00000111111111110000111100000011u32);
+
+        if disk.px.sig.read() == SATA_SIG_ATAPI {
+            return Self::init_atapi(disk);
+        }
 
-        let (sectors, supports_48_bit) = unsafe {
+        let identified = unsafe {
             // safe: - port is started,
             //       - index is 0, which is always implemented (required by spec),
             //       - no command has been issued yet, so CI is clear.
-            match Self::identify(port_registers, &mut cmd_list.slots[0], cmd_tables[0].as_mut().unwrap(), 0) {
-                Ok(x) => x,
-                Err(e) => {
-                    error!("Initializing port failed: IDENTIFY DEVICE command failed. Error: {:?}. Status: {:?}", e, port_registers);
-                    port_registers.stop();
-                    port_registers.disable_fis_receive();
-                    port_registers.clear_addresses();
-                    return None
+            Self::identify(disk.px, &mut disk.cmd_list.slots[0], disk.cmd_tables[0].as_mut().unwrap(), 0, &mut disk.dma.lock())
+        };
+        match identified {
+            Ok((sectors, supports_48_bit, queue_depth)) => {
+                disk.sectors = sectors;
+                disk.supports_48_bit = supports_48_bit;
+                if hba_supports_ncq && queue_depth != 0 {
+                    // a tag is a slot index, we can't have more commands queued than the device accepts.
+                    disk.supports_ncq = true;
+                    disk.slot_count = min(command_list_length, queue_depth);
                 }
+                // clear the pending interrupts, and enable the ones we handle.
+                disk.px.is.write(disk.px.is.read());
+                disk.px.ie.write(Px::enabled_interrupts());
+                Some(disk)
+            },
+            Err(e) => {
+                error!("Initializing port failed: IDENTIFY DEVICE command failed. Error: {:?}. Status: {:?}", e, disk.px);
+                None
             }
-        };
+        }
+    }
 
-        Some(Disk {
-            px: port_registers,
-            rfis: received_fis,
-            cmd_list,
-            cmd_tables,
-            sectors,
-            supports_48_bit
-        })
+    /// Finishes the initialization of a port connected to an ATAPI device, typically a CD-ROM drive.
+    ///
+    /// The port must be started. Sets `PxCMD.ATAPI`, and gets the capacity of the medium
+    /// with the `READ CAPACITY` command, which we retry a few times: the first command a device
+    /// receives after a reset or a medium change fails with a `UNIT ATTENTION` sense key.
+    ///
+    /// If the drive is empty, or its medium doesn't have 2048-octet sectors,
+    /// this function returns `None`.
+    fn init_atapi(mut disk: Disk) -> Option<Disk> {
+        /// The number of times we send READ CAPACITY before giving up on the device.
+        const READ_CAPACITY_TRIES: usize = 3;
+
+        let mut cmd = disk.px.cmd.read();
+        cmd.set_atapi(true);
+        disk.px.cmd.write(cmd);
+        disk.atapi = true;
+
+        let mut capacity = Err(AhciError::IoError.into());
+        for _ in 0..READ_CAPACITY_TRIES {
+            capacity = unsafe {
+                // safe: - port is started,
+                //       - index is 0, which is always implemented (required by spec),
+                //       - the previous command either completed, or failed and the port was
+                //         recovered, so CI is clear.
+                Self::read_capacity(disk.px, &mut disk.cmd_list.slots[0], disk.cmd_tables[0].as_mut().unwrap(), 0, &mut disk.dma.lock())
+            };
+            if capacity.is_ok() || !disk.px.recover() {
+                break;
+            }
+        }
+        match capacity {
+            Ok((last_lba, ATAPI_SECTOR_SIZE)) => {
+                // we expose 512-octet sectors, like for any other disk.
+                disk.sectors = (u64::from(last_lba) + 1) * (ATAPI_SECTOR_SIZE as u64 / 512);
+                // clear the pending interrupts, and enable the ones we handle.
+                disk.px.is.write(disk.px.is.read());
+                disk.px.ie.write(Px::enabled_interrupts());
+                Some(disk)
+            },
+            Ok((_, block_length)) => {
+                error!("Initializing ATAPI port failed: unsupported block length {}", block_length);
+                None
+            },
+            Err(e) => {
+                error!("Initializing ATAPI port failed: READ CAPACITY command failed, is the drive empty? Error: {:?}. Status: {:?}", e, disk.px);
+                None
+            }
+        }
     }
 
     /// Checks if the command issued in `slot` is still running.
@@ -638,43 +817,9 @@ impl Px {
     }
 
     /// Polls the port until the command in `slot` is completed, or an error occurred.
-    // todo: AHCI interrupts - command completion
-    // body: The AHCI driver does not make any use of AHCI interruptions.
-    // body: To check that a command has been completed, it polls the port repeatedly
-    // body: until the PxCI bit cleared.
-    // body:
-    // body: This is bad for performances, uses unnecessary time slices, and is an
-    // body: awful design.
-    // body:
-    // body: We need to figure out how to make AHCI interrupts work for us.
-    // body:
-    // body: The problem that we faced is the following:
-    // body:
-    // body: When enabled, after a command has completed, the HBA sends an irq to the PIC.
-    // body: The irq kernel-side top-half `acknowledges the irq` (sends EOI to the 8259A),
-    // body: and delays the actual handling to the userspace-side bottom-half.
-    // body: It then rets to the interrupted context, and re-enables interrupts by doing so.
-    // body:
-    // body: At this point the HBA, whose state has not been resolved, re-triggers an interrupt.
-    // body:
-    // body: Hence the kernel is spending 100% cpu time servicing an infinite irq
-    // body: and the OS completely freezes.
-    // body:
-    // body: This happens even while the 8359A is in `edge triggered mode`, which means that the
-    // body: PCI irq line is cycling through an HIGH and LOW, which is really odd according to the
-    // body: spec, where the irq are supposed to be "level sensitive".
-    // body:
-    // body: I think a little more digging up would be necessary to find out if we just configured
-    // body: something incorrectly, or if AHCI interrupts truly cannot be handled in the bottom-half.
-    // body:
-    // body: In the few open-source microkernels I reviewed that have an AHCI driver:
-    // body:
-    // body: - Redox does not implement interrupts (well ... just like us),
-    // body: - HelenOS seems to allow userspace drivers to have their own top-half routine.
-    // body:   I don't know if they run in ring 0 or ring 3, how the page-tables switching is handled,
-    // body:   but I'm really curious and will try to find out more about it.
-    // body: - Minix3 seems to be actually using interrupts, through its blockdriver multithreading framework.
-    // body:   I should look this up.
+    ///
+    /// Only used during the initialization of the port, when its interrupts are still disabled.
+    /// Afterwards, commands are completed by [Disk::handle_interrupt].
     fn wait_command_completion(&self, slot: usize) -> Result<(), Error> {
         while self.command_running(slot) {
             sleep_thread(0).unwrap();
@@ -692,14 +837,16 @@ impl Px {
     ///
     /// - The number of addressable sectors this device possesses.
     /// - Whether the device supports 48-bit addresses.
+    /// - The maximum number of commands the device can queue if it supports NCQ, 0 otherwise.
     ///
     /// # Unsafety
     ///
     /// * The port must be started
     /// * `command_slot_index` must not have its bit set in `PxCI`.
     /// * `command_header` and `command_table` must belong to `command_slot_index`'s command slot.
+    /// * `dma` must be how the HBA accesses memory.
     #[allow(clippy::cast_lossless)] // trust me, types won't change
-    unsafe fn identify(px: &mut Px, command_header: &mut CmdHeader, command_table: &mut CmdTable, command_slot_index: usize) -> Result<(u64, bool), Error> {
+    unsafe fn identify(px: &mut Px, command_header: &mut CmdHeader, command_table: &mut CmdTable, command_slot_index: usize, dma: &mut Dma) -> Result<(u64, bool, usize), Error> {
 
         /// The IDENTIFY DEVICE command. See ATA spec.
         const ATA_CMD_IDENTIFY: u8 = 0xEC;
@@ -724,21 +871,23 @@ impl Px {
         fis.device.write(0);
 
         // fill the prdt
-        unsafe {
-            // safe: `&mut output` points to valid memory.
-            command_table.fill_prdt(&mut *output as *mut _ as _, size_of::<IdentifyOutput>(), command_header)?
-        }
-
-        // fill the command header
-        let mut ch_flags = CmdHeaderFlags(0);
-        ch_flags.set_c(true);
-        ch_flags.set_w(false);
-        ch_flags.set_cfl((size_of::<FisRegH2D>() / 4) as u16);
-        command_header.flags.write(ch_flags);
-
-        // set PxCI
-        px.ci.write(1u32 << command_slot_index);
-        px.wait_command_completion(command_slot_index)?;
+        dma.map(&*output, size_of::<IdentifyOutput>(), MemoryPermissions::WRITABLE)?;
+        let result = dma.segments(&*output as *const IdentifyOutput as usize, size_of::<IdentifyOutput>())
+            .and_then(|segments| command_table.fill_prdt(&segments, command_header))
+            .and_then(|()| {
+                // fill the command header
+                let mut ch_flags = CmdHeaderFlags(0);
+                ch_flags.set_c(true);
+                ch_flags.set_w(false);
+                ch_flags.set_cfl((size_of::<FisRegH2D>() / 4) as u16);
+                command_header.flags.write(ch_flags);
+
+                // set PxCI
+                px.ci.write(1u32 << command_slot_index);
+                px.wait_command_completion(command_slot_index)
+            });
+        dma.unmap(&*output, size_of::<IdentifyOutput>());
+        result?;
 
         let mut supports_48_bit = true;
 
@@ -752,157 +901,234 @@ impl Px {
             supports_48_bit = false;
         };
 
-        Ok((sectors, supports_48_bit))
+        // NCQ support is found at output[76] bit 8, and the queue depth minus one at output[75] bits 0-4.
+        let queue_depth = if output.bytes[76] != 0xFFFF && output.bytes[76] & (1 << 8) != 0 {
+            (output.bytes[75] & 0x1f) as usize + 1
+        } else {
+            0
+        };
+
+        Ok((sectors, supports_48_bit, queue_depth))
     }
 
-    /// Read `sector_count` contiguous sectors from the disk into `buffer`.
+    /// Sends the READ CAPACITY command to an ATAPI device to get the size of its medium.
+    ///
+    /// # Returns
+    ///
+    /// - The address of the last sector of the medium.
+    /// - The length of a sector, in octets. Should be 2048 for CD-ROMs.
+    ///
+    /// # Unsafety
     ///
-    /// This function places a command in `command_slot_index`, signals it to the port,
-    /// and waits for an interruption indicating its completion.
+    /// * The port must be started
+    /// * `command_slot_index` must not have its bit set in `PxCI`.
+    /// * `command_header` and `command_table` must belong to `command_slot_index`'s command slot.
+    /// * `dma` must be how the HBA accesses memory.
+    unsafe fn read_capacity(px: &mut Px, command_header: &mut CmdHeader, command_table: &mut CmdTable, command_slot_index: usize, dma: &mut Dma) -> Result<(u32, u32), Error> {
+
+        /// The READ CAPACITY (10) command. See SCSI Block Commands spec.
+        const SCSI_CMD_READ_CAPACITY: u8 = 0x25;
+
+        /// The ouptut of the READ CAPACITY command: two big endian dwords.
+        #[repr(C, align(2))]
+        struct ReadCapacityOutput {
+            /// The last logical block address, and the block length in octets.
+            bytes: [u8; 8]
+        }
+        unsafe impl ZeroInitialized for ReadCapacityOutput {}
+
+        let output = ZeroBox::<ReadCapacityOutput>::new_zeroed();
+        let mut packet = [0u8; 12];
+        packet[0] = SCSI_CMD_READ_CAPACITY;
+
+        dma.map(&*output, size_of::<ReadCapacityOutput>(), MemoryPermissions::WRITABLE)?;
+        // this command is not worth a DMA, and every device supports PIO.
+        let result = dma.segments(&*output as *const ReadCapacityOutput as usize, size_of::<ReadCapacityOutput>())
+            .and_then(|segments| px.issue_packet(&packet, false, &segments, size_of::<ReadCapacityOutput>(),
+                                                 command_header, command_table, command_slot_index))
+            .and_then(|()| px.wait_command_completion(command_slot_index));
+        dma.unmap(&*output, size_of::<ReadCapacityOutput>());
+        result?;
+
+        let mut last_lba = [0u8; 4];
+        let mut block_length = [0u8; 4];
+        last_lba.copy_from_slice(&output.bytes[0..4]);
+        block_length.copy_from_slice(&output.bytes[4..8]);
+        Ok((u32::from_be_bytes(last_lba), u32::from_be_bytes(block_length)))
+    }
+
+    /// Issues a DMA read of `sector_count` contiguous 2048-octet sectors starting from `lba`
+    /// on an ATAPI device, to the buffer made of `segments`, as the HBA sees them.
     ///
-    /// Based on `supports_48_bits_addresses`, this function will either use the
-    /// `READ DMA` or `READ DMA EXT` command.
+    /// This function uses the `READ (10)` packet command. Like [issue_dma](Px::issue_dma),
+    /// it does not wait for its completion.
     ///
     /// # Unsafety
     ///
-    /// * `buffer` must point to valid memory.
-    /// * `buffer_len` must reflect `buffer`'s length.
-    /// * `buffer[0] - buffer[buffer_len - 1]` must fall in a single mapping.
+    /// * `segments` must be the device addresses and lengths of a buffer of at least
+    ///    `sector_count * 2048` bytes, which must stay mapped until the command is completed.
     /// * `command_slot_index` must be free to use, implemented,
     ///    and must point to `command_header` and `command_table`.
-    /// * `px` must be properly initialized.
+    /// * the port must be started, and connected to an ATAPI device.
     ///
     /// # Error
     ///
-    /// * `buffer_len` < `sector_count * 512`.
-    /// * `sector_count` == 0.
-    /// * `sector_count` is greater than supported maximum (256 for 28-bit devices, 65536 for 48-bit ones).
-    /// * `lba + sector_count` is not representable on a 28-bit/48-bit address.
-    /// * query_physical_address() failed.
-    /// * AhciError::BufferTooScattered: `buffer` is so scattered it overflows PRDT.
-    #[allow(clippy::too_many_arguments)] // heh
-    #[allow(clippy::missing_docs_in_private_items)]
-    pub unsafe fn read_dma(
-        buffer: *const u8,
-        buffer_len: usize,
+    /// * `sector_count` == 0, or is greater than 65535.
+    /// * `lba` is not representable on 32 bits.
+    /// * AhciError::BufferTooScattered: `buffer` is so big it overflows PRDT.
+    pub unsafe fn issue_atapi_read(
+        &mut self,
+        segments: &[(u64, u32)],
         lba: u64,
         sector_count: u64,
-        px: &mut Px,
         command_header: &mut CmdHeader,
         command_table: &mut CmdTable,
-        command_slot_index: usize,
-        supports_48_bit: bool) -> Result<(), Error> {
+        command_slot_index: usize) -> Result<(), Error> {
 
-        const ATA_CMD_READ_DMA:     u8 = 0xC8;
-        const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+        /// The READ (10) command. See SCSI Block Commands spec.
+        const SCSI_CMD_READ_10: u8 = 0x28;
 
-        if sector_count.checked_mul(512).filter(|sec_size| *sec_size <= (buffer_len as u64)).is_none() {
-            return Err(AhciError::InvalidArg.into())
-        }
-        if sector_count == 0 || (!supports_48_bit && sector_count > 256) || sector_count > 65536 {
-            return Err(AhciError::InvalidArg.into())
-        }
-        if (!supports_48_bit && lba.saturating_add(sector_count) >= (1u64 << 28))
-            || lba.saturating_add(sector_count) >= (1u64 << 48) {
+        if sector_count == 0 || sector_count > 0xFFFF || lba > 0xFFFF_FFFF {
             return Err(AhciError::InvalidArg.into())
         }
+        let mut packet = [0u8; 12];
+        packet[0] = SCSI_CMD_READ_10;
+        packet[2..6].copy_from_slice(&(lba as u32).to_be_bytes());
+        packet[7..9].copy_from_slice(&(sector_count as u16).to_be_bytes());
+
+        self.issue_packet(&packet, true, segments, sector_count as usize * ATAPI_SECTOR_SIZE as usize,
+                          command_header, command_table, command_slot_index)
+    }
+
+    /// Issues a `PACKET` command, sending the 12-byte SCSI command `packet` to an ATAPI device,
+    /// which transfers its data to the `length` octets of the buffer made of `segments`, as the
+    /// HBA sees them.
+    ///
+    /// If `dma` is set, the device transfers the data by DMA, otherwise by PIO, which the HBA
+    /// handles for us all the same.
+    ///
+    /// This function does not wait for the completion of the command.
+    ///
+    /// # Unsafety
+    ///
+    /// * `segments` must be the device addresses and lengths of a buffer of at least `length`
+    ///    bytes, which must stay mapped until the command is completed.
+    /// * `command_slot_index` must be free to use, implemented,
+    ///    and must point to `command_header` and `command_table`.
+    /// * the port must be started, and connected to an ATAPI device.
+    ///
+    /// # Error
+    ///
+    /// * AhciError::BufferTooScattered: `buffer` is so big it overflows PRDT.
+    unsafe fn issue_packet(
+        &mut self,
+        packet: &[u8; 12],
+        dma: bool,
+        segments: &[(u64, u32)],
+        length: usize,
+        command_header: &mut CmdHeader,
+        command_table: &mut CmdTable,
+        command_slot_index: usize) -> Result<(), Error> {
+
+        /// The PACKET command. See ATA spec.
+        const ATA_CMD_PACKET: u8 = 0xA0;
+
         // write the FIS
         let fis = &mut command_table.cfis.h2d;
         fis.fis_type.write(FisType::RegH2D as u8);
+        fis.command.write(ATA_CMD_PACKET);
         fis.pm.write(1 << 7); // this is an update of the Command register
-        if !supports_48_bit {
-            // 28 bits
-            fis.command.write(ATA_CMD_READ_DMA);
-            fis.countl.write(sector_count as u8); // 0x00 means 256 sectors
-            fis.counth.write(0);
-            fis.lba0.write(lba as u8);
-            fis.lba1.write((lba >> 8) as u8);
-            fis.lba2.write((lba >> 16) as u8);
-            fis.lba3.write(0);
-            fis.lba4.write(0);
-            fis.lba5.write(0);
-            fis.device.write((1 << 6) | ((lba >> 24) as u8));
-        } else {
-            // 48 bits
-            fis.command.write(ATA_CMD_READ_DMA_EXT);
-            fis.countl.write(sector_count as u8); // 0000h means 65536 sectors
-            fis.counth.write((sector_count >> 8) as u8);
-            fis.lba0.write(lba as u8);
-            fis.lba1.write((lba >> 8) as u8);
-            fis.lba2.write((lba >> 16) as u8);
-            fis.lba3.write((lba >> 24) as u8);
-            fis.lba4.write((lba >> 32) as u8);
-            fis.lba5.write((lba >> 40) as u8);
-            fis.device.write(1u8 << 6);
-        };
+        fis.icc.write(0);
+        fis.control.write(0);
+        fis.featurel.write(dma as u8);
+        fis.featureh.write(0);
+        fis.countl.write(0);
+        fis.counth.write(0);
+        // the byte count limit of a PIO data transfer goes in LBA mid and LBA high.
+        let byte_count_limit = min(length, 0xFFFE) as u16;
+        fis.lba0.write(0);
+        fis.lba1.write(byte_count_limit as u8);
+        fis.lba2.write((byte_count_limit >> 8) as u8);
+        fis.lba3.write(0);
+        fis.lba4.write(0);
+        fis.lba5.write(0);
+        fis.device.write(0);
 
-        // fill the prdt
-        unsafe {
-            // safe: we have the same contract.
-            command_table.fill_prdt(buffer, buffer_len, command_header)?
+        // write the ATAPI command
+        for (acmd, byte) in command_table.acmd.iter_mut().zip(packet.iter()) {
+            acmd.write(*byte);
         }
 
+        // fill the prdt
+        command_table.fill_prdt(segments, command_header)?;
+
         // fill the command header
         let mut ch_flags = CmdHeaderFlags(0);
         ch_flags.set_c(true);
         ch_flags.set_w(false);
+        ch_flags.set_a(true);
         ch_flags.set_cfl((size_of::<FisRegH2D>() / 4) as u16);
         command_header.flags.write(ch_flags);
+        command_header.prdbc.write(0);
 
-        // set PxCI
-        px.ci.write(1u32 << command_slot_index);
-        px.wait_command_completion(command_slot_index)?;
+        self.ci.write(1u32 << command_slot_index);
         Ok(())
     }
 
-    /// Write `sector_count` contiguous sectors to the disk from `buffer`.
+    /// Issues a DMA transfer of `sector_count` contiguous sectors starting from `lba`,
+    /// to or from the buffer made of `segments`, as the HBA sees them.
     ///
-    /// This function places a command in `command_slot_index`, signals it to the port,
-    /// and waits for an interruption indicating its completion.
+    /// This function places a command in `command_slot_index` and signals it to the port,
+    /// but does not wait for its completion. The command is completed when its slot bit is cleared
+    /// from both `PxCI` and `PxSACT`, which the port signals with an interrupt.
     ///
-    /// Based on `supports_48_bits_addresses`, this function will either use the
-    /// `WRITE DMA` or `WRITE DMA EXT` command.
+    /// If `ncq` is set, this function uses the `READ FPDMA QUEUED` or `WRITE FPDMA QUEUED` command,
+    /// tagged with `command_slot_index`. Otherwise, based on `supports_48_bit`, it will either use
+    /// the `READ DMA`/`WRITE DMA` or the `READ DMA EXT`/`WRITE DMA EXT` command.
+    ///
+    /// A port must not be given queued and non-queued commands at the same time.
     ///
     /// # Unsafety
     ///
-    /// * `buffer` must point to valid memory.
-    /// * `buffer_len` must reflect `buffer`'s length.
-    /// * `buffer[0] - buffer[buffer_len - 1]` must fall in a single mapping.
+    /// * `segments` must be the device addresses and lengths of a buffer of at least
+    ///    `sector_count * 512` bytes, which must stay mapped until the command is completed.
     /// * `command_slot_index` must be free to use, implemented,
     ///    and must point to `command_header` and `command_table`.
-    /// * `px` must be properly initialized.
+    /// * the port must be started.
     ///
     /// # Error
     ///
-    /// * `buffer_len` < `sector_count * 512`.
     /// * `sector_count` == 0.
-    /// * `sector_count` is greater than supported maximum (256 for 28-bit devices, 65536 for 48-bit ones).
+    /// * `sector_count` is greater than supported maximum (256 for 28-bit devices, 65536 for 48-bit
+    ///   and NCQ ones).
     /// * `lba + sector_count` is not representable on a 28-bit/48-bit address.
-    /// * query_physical_address() failed.
-    /// * AhciError::BufferTooScattered: `buffer` is so scattered it overflows PRDT.
+    /// * AhciError::BufferTooScattered: `buffer` is so big it overflows PRDT.
     #[allow(clippy::too_many_arguments)] // heh
     #[allow(clippy::missing_docs_in_private_items)]
-    pub unsafe fn write_dma(
-        buffer: *const u8,
-        buffer_len: usize,
+    pub unsafe fn issue_dma(
+        &mut self,
+        transfer: Transfer,
+        segments: &[(u64, u32)],
         lba: u64,
         sector_count: u64,
-        px: &mut Px,
         command_header: &mut CmdHeader,
         command_table: &mut CmdTable,
         command_slot_index: usize,
-        supports_48_bit: bool) -> Result<(), Error> {
-
-        const ATA_CMD_WRITE_DMA:     u8 = 0xCA;
-        const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
-
-        if sector_count.checked_mul(512).filter(|sec_size| *sec_size <= (buffer_len as u64)).is_none() {
-            return Err(AhciError::InvalidArg.into())
-        }
-        if sector_count == 0 || (!supports_48_bit && sector_count > 256) || sector_count > 65536 {
+        supports_48_bit: bool,
+        ncq: bool) -> Result<(), Error> {
+
+        const ATA_CMD_READ_DMA:            u8 = 0xC8;
+        const ATA_CMD_READ_DMA_EXT:        u8 = 0x25;
+        const ATA_CMD_READ_FPDMA_QUEUED:   u8 = 0x60;
+        const ATA_CMD_WRITE_DMA:           u8 = 0xCA;
+        const ATA_CMD_WRITE_DMA_EXT:       u8 = 0x35;
+        const ATA_CMD_WRITE_FPDMA_QUEUED:  u8 = 0x61;
+
+        let is_48_bit = supports_48_bit || ncq;
+        if sector_count == 0 || (!is_48_bit && sector_count > 256) || sector_count > 65536 {
             return Err(AhciError::InvalidArg.into())
         }
-        if (!supports_48_bit && lba.saturating_add(sector_count) >= (1u64 << 28))
+        if (!is_48_bit && lba.saturating_add(sector_count) >= (1u64 << 28))
             || lba.saturating_add(sector_count) >= (1u64 << 48) {
             return Err(AhciError::InvalidArg.into())
         }
@@ -910,50 +1136,214 @@ impl Px {
         let fis = &mut command_table.cfis.h2d;
         fis.fis_type.write(FisType::RegH2D as u8);
         fis.pm.write(1 << 7); // this is an update of the Command register
-        if !supports_48_bit {
+        fis.icc.write(0);
+        fis.control.write(0);
+        if ncq {
+            // the sector count goes in the feature register, and the tag in the count register.
+            fis.command.write(match transfer {
+                Transfer::Read => ATA_CMD_READ_FPDMA_QUEUED,
+                Transfer::Write => ATA_CMD_WRITE_FPDMA_QUEUED,
+            });
+            fis.featurel.write(sector_count as u8); // 0000h means 65536 sectors
+            fis.featureh.write((sector_count >> 8) as u8);
+            fis.countl.write((command_slot_index as u8) << 3);
+            fis.counth.write(0);
+        } else if !supports_48_bit {
             // 28 bits
-            fis.command.write(ATA_CMD_WRITE_DMA);
+            fis.command.write(match transfer {
+                Transfer::Read => ATA_CMD_READ_DMA,
+                Transfer::Write => ATA_CMD_WRITE_DMA,
+            });
+            fis.featurel.write(0);
+            fis.featureh.write(0);
             fis.countl.write(sector_count as u8); // 0x00 means 256 sectors
             fis.counth.write(0);
-            fis.lba0.write(lba as u8);
-            fis.lba1.write((lba >> 8) as u8);
-            fis.lba2.write((lba >> 16) as u8);
-            fis.lba3.write(0);
-            fis.lba4.write(0);
-            fis.lba5.write(0);
-            fis.device.write((1 << 6) | ((lba >> 24) as u8));
         } else {
             // 48 bits
-            fis.command.write(ATA_CMD_WRITE_DMA_EXT);
+            fis.command.write(match transfer {
+                Transfer::Read => ATA_CMD_READ_DMA_EXT,
+                Transfer::Write => ATA_CMD_WRITE_DMA_EXT,
+            });
+            fis.featurel.write(0);
+            fis.featureh.write(0);
             fis.countl.write(sector_count as u8); // 0000h means 65536 sectors
             fis.counth.write((sector_count >> 8) as u8);
-            fis.lba0.write(lba as u8);
-            fis.lba1.write((lba >> 8) as u8);
-            fis.lba2.write((lba >> 16) as u8);
+        };
+        fis.lba0.write(lba as u8);
+        fis.lba1.write((lba >> 8) as u8);
+        fis.lba2.write((lba >> 16) as u8);
+        if is_48_bit {
             fis.lba3.write((lba >> 24) as u8);
             fis.lba4.write((lba >> 32) as u8);
             fis.lba5.write((lba >> 40) as u8);
             fis.device.write(1u8 << 6);
-        };
+        } else {
+            fis.lba3.write(0);
+            fis.lba4.write(0);
+            fis.lba5.write(0);
+            fis.device.write((1 << 6) | ((lba >> 24) as u8));
+        }
 
         // fill the prdt
-        unsafe {
-            // safe: we have the same contract.
-            command_table.fill_prdt(buffer, buffer_len, command_header)?
-        }
+        command_table.fill_prdt(segments, command_header)?;
 
         // fill the command header
         let mut ch_flags = CmdHeaderFlags(0);
         ch_flags.set_c(true);
-        ch_flags.set_w(true);
+        ch_flags.set_w(transfer == Transfer::Write);
         ch_flags.set_cfl((size_of::<FisRegH2D>() / 4) as u16);
         command_header.flags.write(ch_flags);
+        command_header.prdbc.write(0);
 
-        // set PxCI
-        px.ci.write(1u32 << command_slot_index);
-        px.wait_command_completion(command_slot_index)?;
+        // set PxSACT for queued commands, and then PxCI. See section 5.6.4.1
+        if ncq {
+            self.sact.write(1u32 << command_slot_index);
+        }
+        self.ci.write(1u32 << command_slot_index);
         Ok(())
     }
+
+    /// The interrupts we enable on an initialized port: command completion, and errors.
+    ///
+    /// Non-queued commands complete with a D2H Register FIS, and queued ones with a
+    /// Set Device Bits FIS.
+    fn enabled_interrupts() -> PxIE {
+        let mut ie = PxIE(0);
+        ie.set_dhre(true);
+        ie.set_sdbe(true);
+        ie.set_tfee(true);
+        ie.set_hbfe(true);
+        ie.set_hbde(true);
+        ie.set_ife(true);
+        ie.set_infe(true);
+        ie.set_ofe(true);
+        ie
+    }
+
+    /// Clears the pending interrupts of this port.
+    ///
+    /// # Error
+    ///
+    /// * AhciError::IoError: one of the interrupts signals an error. The commands issued on this
+    ///   port will not complete, and it must be recovered with [recover](Px::recover).
+    pub fn acknowledge_interrupts(&mut self) -> Result<(), Error> {
+        let is = self.is.read();
+        self.is.write(is);
+        if is.is_err() {
+            error!("Port error: {:?}. Status: {:?}", is, self);
+            Err(AhciError::IoError.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the commands still running: the slots whose bit is set in `PxCI` or `PxSACT`.
+    pub fn running_commands(&self) -> u32 {
+        self.ci.read() | self.sact.read()
+    }
+
+    /// Recovers the port after an error.
+    ///
+    /// The port is stopped, which clears `PxCI` and `PxSACT`, aborting every issued command.
+    /// Its errors are cleared, and it is started again if the device is not busy anymore.
+    ///
+    /// See section 6.2.2.
+    ///
+    /// # Returns
+    ///
+    /// Whether the port could be started again. If not, it must not be given any command anymore.
+    pub fn recover(&mut self) -> bool {
+        self.stop();
+        // clear PxSERR and PxIS by writing '1' to every non-reserved bit.
+        self.serr.write(0b00000111111111110000111100000011u32);
+        self.is.write(self.is.read());
+        if !self.probe() {
+            return false;
+        }
+        unsafe {
+            // safe: the command list PxCLB points to is still owned by the Disk,
+            //       which will call `clear_addresses` before it is outlived.
+            let command_list = self.clb.read();
+            self.start(command_list);
+        }
+        true
+    }
+}
+
+/// The direction of a DMA transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// The device writes sectors to memory.
+    Read,
+    /// The device reads sectors from memory.
+    Write,
+}
+
+/// Maps the memory structures of a port for the DMAs of its HBA.
+///
+/// The HBA writes to the received FIS and the command list, and only reads the command tables.
+///
+/// # Returns
+///
+/// The device addresses of the received FIS, of the command list, and of the command tables.
+///
+/// # Error
+///
+/// * Mapping a structure failed. The structures mapped so far are unmapped.
+/// * AhciError::BufferTooScattered: a structure is not physically contiguous. They are aligned
+///   to their size, and never cross a page boundary, so this should not happen.
+pub fn map_port_structures(dma: &mut Dma, rfis: &ReceivedFis, cmd_list: &CmdHeaderArray,
+                           cmd_tables: &[Option<ZeroBox<CmdTable>>]) -> Result<(u64, u64, Vec<u64>), Error> {
+    dma.map(rfis, size_of::<ReceivedFis>(), MemoryPermissions::RW)?;
+    if let Err(e) = dma.map(cmd_list, size_of::<CmdHeaderArray>(), MemoryPermissions::RW) {
+        dma.unmap(rfis, size_of::<ReceivedFis>());
+        return Err(e)
+    }
+    let mut mapped_tables = 0;
+    for table in cmd_tables.iter().flatten() {
+        if let Err(e) = dma.map(&**table, size_of::<CmdTable>(), MemoryPermissions::READABLE) {
+            for table in cmd_tables.iter().flatten().take(mapped_tables) {
+                dma.unmap(&**table, size_of::<CmdTable>());
+            }
+            dma.unmap(cmd_list, size_of::<CmdHeaderArray>());
+            dma.unmap(rfis, size_of::<ReceivedFis>());
+            return Err(e)
+        }
+        mapped_tables += 1;
+    }
+    let addresses = port_structure_addresses(dma, rfis, cmd_list, cmd_tables);
+    if addresses.is_err() {
+        unmap_port_structures(dma, rfis, cmd_list, cmd_tables);
+    }
+    addresses
+}
+
+/// Gets the device addresses of the memory structures of a port, mapped by [map_port_structures].
+///
+/// # Error
+///
+/// * AhciError::BufferTooScattered: a structure is not physically contiguous.
+fn port_structure_addresses(dma: &Dma, rfis: &ReceivedFis, cmd_list: &CmdHeaderArray,
+                            cmd_tables: &[Option<ZeroBox<CmdTable>>]) -> Result<(u64, u64, Vec<u64>), Error> {
+    let rfis_address = dma.contiguous(rfis as *const ReceivedFis as usize, size_of::<ReceivedFis>())?;
+    let cmd_list_address = dma.contiguous(cmd_list as *const CmdHeaderArray as usize, size_of::<CmdHeaderArray>())?;
+    let mut cmd_table_addresses = Vec::new();
+    for table in cmd_tables.iter().flatten() {
+        cmd_table_addresses.push(dma.contiguous(&**table as *const CmdTable as usize, size_of::<CmdTable>())?);
+    }
+    Ok((rfis_address, cmd_list_address, cmd_table_addresses))
+}
+
+/// Unmaps the memory structures of a port mapped by [map_port_structures].
+///
+/// The port must be stopped, and its addresses cleared.
+pub fn unmap_port_structures(dma: &mut Dma, rfis: &ReceivedFis, cmd_list: &CmdHeaderArray,
+                             cmd_tables: &[Option<ZeroBox<CmdTable>>]) {
+    for table in cmd_tables.iter().flatten() {
+        dma.unmap(&**table, size_of::<CmdTable>());
+    }
+    dma.unmap(cmd_list, size_of::<CmdHeaderArray>());
+    dma.unmap(rfis, size_of::<ReceivedFis>());
 }
 
 // Implementing Drop to do some clean-up is useless, we never hold a Px, always a reference to one,
@@ -1031,15 +1421,15 @@ assert_eq_size!(CmdHeaderArray, [u8; 1024]);
 unsafe impl ZeroInitialized for CmdHeaderArray {}
 
 impl CmdHeader {
-    /// Initializes a CmdHeader, making it point to its [CmdTable].
+    /// Initializes a CmdHeader, making it point to its [CmdTable], found at the device address
+    /// `command_table`.
     ///
     /// # Safety
     ///
     /// - `command_table` should not already be pointed to by any other CmdHeader.
     /// - port must not be running.
-    pub unsafe fn init(&mut self, command_table: &mut CmdTable) {
-        let phys_addr = virt_to_phys(command_table as _);
-        self.ctba.write(phys_addr as u64);
+    pub unsafe fn init(&mut self, command_table: u64) {
+        self.ctba.write(command_table);
     }
 }
 
@@ -1114,53 +1504,37 @@ impl Debug for PrdtEntry {
 impl CmdTable {
     /// Fills a PRDT with the given buffer.
     ///
-    /// The buffer can be physically scattered, this function will query all its physical regions
-    /// from the kernel.
+    /// The buffer is made of `segments`, their device address and length, which are split in
+    /// regions of at most 4MiB, the maximum size of a PRDT entry.
     ///
     /// When finished, this function will update the PRDTL count in `header`.
     ///
-    /// # Unsafety
-    ///
-    /// * `buffer` must point to valid memory.
-    /// * `buffer[0] - buffer[len - 1]` must fall in a single mapping.
-    ///
     /// # Error
     ///
-    /// * AhciError::BufferTooScattered: `buffer` is so scattered it overflows PRDT.
-    /// * query_physical_address() failed.
+    /// * AhciError::BufferTooScattered: `buffer` is so big or scattered it overflows PRDT.
     ///
     /// # Panics
     ///
-    /// * length must be even.
-    /// * `buffer[0]` must be the very start of the mapping.
-    pub unsafe fn fill_prdt(&mut self, buffer: *const u8, mut length: usize, header: &mut CmdHeader) -> Result<(), Error> {
-        assert_eq!(length % 2, 0, "fill_prdt: length is odd.");
-        assert_eq!(buffer as usize % 2, 0, "fill_prdt: buffer is not word aligned.");
-
-        let mut temp_buffer_addr = buffer as usize;
+    /// * the length of a segment must be even.
+    /// * the address of a segment must be word aligned.
+    pub fn fill_prdt(&mut self, segments: &[(u64, u32)], header: &mut CmdHeader) -> Result<(), Error> {
         let mut index = 0;
-        while length > 0 {
-            let (mut phys_addr, base_addr, mut phys_len) = query_physical_address(temp_buffer_addr)?;
-            let phys_off = temp_buffer_addr as usize - base_addr;
-            temp_buffer_addr = base_addr + phys_len;
-            phys_addr += phys_off;
-            phys_len -= phys_off;
+        for &(mut address, length) in segments {
+            let mut length = length as usize;
+            assert_eq!(length % 2, 0, "fill_prdt: length is odd.");
+            assert_eq!(address % 2, 0, "fill_prdt: buffer is not word aligned.");
             // divide into 4M regions.
-            // Range iterator has no .chunks() T.T
-            while phys_len > 0 && length > 0 {
+            while length > 0 {
                 let entry = self.prdt.get_mut(index)
                     .ok_or(AhciError::BufferTooScattered)?;
-                // if buffer has spare space, ignore it.
-                let region_len = min(min(phys_len, 0x400000), length);
+                let region_len = min(0x400000, length);
 
-                entry.dba.write(phys_addr as u64);
+                entry.dba.write(address);
                 entry.dbc.write((region_len - 1) as u32);
 
-                phys_len -= region_len;
-                phys_addr += region_len;
-                index += 1;
-                // also decrement total length.
+                address += region_len as u64;
                 length -= region_len;
+                index += 1;
             }
         }
         // Interrupt on Completion on the last PRDT entry
@@ -7,11 +7,8 @@
 //!
 //! Here's a list of wonderful features this driver does **not** provide:
 //!
-//! - ATAPI support
-//! - NCQ support
 //! - hotplug/remove of a device
 //! - hotplug/remove of a controller
-//! - interrupts notifying command has been completed (WIP)
 //! - real error management
 //! - Port Multipliers
 //! - PCI-to-PCI bridges
@@ -25,19 +22,32 @@
 //! You can then ask the [AhciInterface] to give you a session to any [IDisk] from its id,
 //! and finally read/write some sectors.
 //!
+//! ATAPI devices, like CD-ROM drives, are discovered along with the other disks. They are
+//! read-only, and although their sectors are 2048 octets long, their [IDisk] exposes 512-octet
+//! sectors like any other.
+//!
 //! We read and write disk sectors only by DMA, letting the device do all the copying.
-//! Our client will provide us a handle to a shared memory, and we will make the device
-//! DMA read/write to it.
+//! Sectors are either transferred to the IPC buffers of a request, or to a buffer our client
+//! shares with us, registered once per session. The device then DMAs directly to and from the
+//! client's memory.
+//!
+//! Every controller is attached to its own device address space, so it can only DMA to the
+//! memory we mapped there for it. Without an IOMMU, it accesses physical memory instead.
+//! See the [dma](sunrise_libuser::dma) module.
 //!
 //! # Parallelism
 //!
-//! For now this driver is "highly single-threaded" and blocking, this means that only one
-//! request can be outstanding at any moment, and the driver will wait for it to be completed
-//! before accepting other requests.
+//! This driver is single-threaded, but asynchronous: every [IDisk] session is served by its own
+//! future on the [WaitableManager], and a request issues its commands without blocking.
 //!
-//! This is highly unsatisfying, since AHCI supports up to 32 commands being issued
-//! simultaneously. Unfortunately we can't take advantage of that until we manage to
-//! make command-completion interrupts work.
+//! Each port has up to 32 command slots, so as many commands can be outstanding on a disk.
+//! When the device supports Native Command Queuing, we use the `READ/WRITE FPDMA QUEUED`
+//! commands, and the device can process them in any order. Otherwise the HBA processes
+//! them one after the other.
+//!
+//! The HBA raises an interrupt when a command completes. For each HBA, a future waits on
+//! its interrupt event, and completes the commands of its disks, waking up the requests
+//! that were waiting for them.
 
 #![feature(box_syntax, untagged_unions)]
 #![no_std]
@@ -64,12 +74,11 @@ extern crate log;
 #[macro_use]
 extern crate bitfield;
 
-mod pci;
 mod hba;
 mod fis;
 mod disk;
 
-use crate::hba::HbaMemoryRegisters;
+use crate::hba::{Hba, HbaMemoryRegisters};
 use crate::disk::{Disk, IDisk};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
@@ -77,9 +86,11 @@ use alloc::sync::Arc;
 use sunrise_libuser::error::{Error, AhciError};
 use sunrise_libuser::futures::{WaitableManager, WorkQueue};
 use sunrise_libuser::ipc::server::{port_handler, new_session_wrapper};
+use sunrise_libuser::types::ReadableEvent;
 use spin::Mutex;
 use sunrise_libuser::syscalls;
-use sunrise_libuser::ahci::{AhciInterface as IAhciInterface, IDiskProxy, IDisk as _};
+use sunrise_libuser::pci::{self, BAR};
+use sunrise_libuser::ahci::{AhciInterface as IAhciInterface, IDiskProxy, IDiskAsync as _};
 use sunrise_libuser::futures_rs::future::FutureObj;
 
 /// Array of discovered disk.
@@ -97,18 +108,27 @@ static DISKS: Mutex<Vec<Arc<Mutex<Disk>>>> = Mutex::new(Vec::new());
 ///
 /// 1. Discover HBAs on the PCI.
 /// 2. For every found HBA:
+///     - Subscribe to its interrupt line.
 ///     - Initialize each implemented port if we detect it is connected to a device.
 ///     - Push the created [Disk]s in [DISKS].
-/// 3. Start the event loop.
+/// 3. Start the event loop, handling the interrupts of every HBA.
 fn main() {
     debug!("AHCI driver starting up");
-    let ahci_controllers = pci::get_ahci_controllers();
+    let ahci_controllers = get_ahci_controllers();
     debug!("AHCI controllers : {:#x?}", ahci_controllers);
-    for (bar5, _) in ahci_controllers {
-        DISKS.lock().extend(
-            HbaMemoryRegisters::init(bar5 as _)
-                .drain(..).map(|disk| Arc::new(Mutex::new(disk)))
-        );
+    let mut hbas = Vec::new();
+    for (bar5, _, irq, device) in ahci_controllers {
+        let irq_event = match syscalls::create_interrupt_event(irq as usize, 0) {
+            Ok(irq_event) => irq_event,
+            Err(e) => {
+                error!("HBA {:#010x}, initialization failed: failed subscribing to IRQ {}, {:?}.", bar5, irq, e);
+                continue;
+            }
+        };
+        if let Some(hba) = HbaMemoryRegisters::init(bar5 as _, device) {
+            DISKS.lock().extend(hba.disks().cloned());
+            hbas.push((irq_event, hba));
+        }
     }
     debug!("AHCI initialised disks : {:#x?}", DISKS);
 
@@ -116,9 +136,47 @@ fn main() {
     let mut man = WaitableManager::new();
     let handler = port_handler(man.work_queue(), "ahci:\0", AhciInterface::dispatch).unwrap();
     man.work_queue().spawn(FutureObj::new(Box::new(handler)));
+    for (irq_event, hba) in hbas {
+        let interrupts_future = handle_interrupts(man.work_queue(), irq_event, hba);
+        man.work_queue().spawn(FutureObj::new(Box::new(interrupts_future)));
+    }
     man.run();
 }
 
+/// Gets the ahci controllers found by pci discovery.
+///
+/// # Returns
+///
+/// Returns the controller's BAR5 address and size, its interrupt line, and its requester id,
+/// if one was found.
+///
+/// The controllers are allowed to raise interrupts.
+fn get_ahci_controllers() -> Vec<(u32, u32, u8, u16)> {
+    pci::discover().iter()
+        .filter(|device| device.class == 0x01 && device.subclass == 0x06 && device.prog_if == 0x01)
+        .map(|device| {
+            match (device.bar(5), device.interrupt_line()) {
+                (Some(BAR::Memory(addr, size)), Some(irq)) => {
+                    device.enable_interrupts();
+                    (addr, size, irq, device.requester_id())
+                },
+                (Some(_), _) => panic!("PCI device with unexpected BAR 5"),
+                _ => panic!("PCI device with unexpected header")
+            }
+        })
+        .collect()
+}
+
+/// Task responsible for handling the interrupts of an HBA, completing the commands of its disks.
+// https://github.com/rust-lang/rust-clippy/issues/3988
+// Should remove on next toolchain upgrade.
+#[allow(clippy::needless_lifetimes)]
+async fn handle_interrupts(work_queue: WorkQueue<'_>, irq_event: ReadableEvent, mut hba: Hba) {
+    loop {
+        irq_event.wait_async_cb(work_queue.clone(), || hba.handle_interrupt()).await;
+    }
+}
+
 /// Main interface to the AHCI driver.
 ///
 /// Registered under the name `"ahci:\0"` to the Service Manager, after the discovery stage.
@@ -174,15 +232,23 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
         sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
 
         sunrise_libuser::syscalls::nr::SetHeapSize,
         sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::MapSharedMemory,
+        sunrise_libuser::syscalls::nr::UnmapSharedMemory,
         sunrise_libuser::syscalls::nr::ConnectToNamedPort,
         sunrise_libuser::syscalls::nr::CreateInterruptEvent,
+        sunrise_libuser::syscalls::nr::CreateDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::AttachDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::MapDeviceAddressSpaceByForce,
+        sunrise_libuser::syscalls::nr::UnmapDeviceAddressSpace,
         sunrise_libuser::syscalls::nr::QueryPhysicalAddress,
         sunrise_libuser::syscalls::nr::MapMmioRegion,
         sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
@@ -203,7 +269,12 @@ capabilities!(CAPABILITIES = Capabilities {
         // body: - Declaring every IRQ line in our capabilities, but only effectively using one ?
         // body: - Deporting the PIC management to a userspace module, and allow it to accept
         // body:   dynamic irq capabilities in yet undefined way.
+        // body:
+        // body: For now we declare the lines the BIOS usually routes PCI interrupts to.
+        sunrise_libuser::caps::irq_pair(5, 9), sunrise_libuser::caps::irq_pair(10, 11),
         sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 0), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 1), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 2), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 3),
         sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 0), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 1), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 2), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 3),
+        // AHCI controllers.
+        sunrise_libuser::caps::pci_class(0x01, 0x06),
     ]
 });
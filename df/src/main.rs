@@ -22,6 +22,7 @@ fn get_filesystem_type(filesystem_type: FileSystemType) -> &'static str {
         FileSystemType::FAT16 => "fat16fs",
         FileSystemType::FAT32 => "fat32fs",
         FileSystemType::PackageFileSubmission => "packagefs",
+        FileSystemType::ISO9660 => "iso9660fs",
         _ => "???"
     }
 }
@@ -81,8 +82,11 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         nr::SleepThread,
         nr::ExitProcess,
+        nr::ExitProcessWithCode,
         nr::CreateThread,
         nr::StartThread,
+        nr::MapStackMirror,
+        nr::UnmapStackMirror,
         nr::ExitThread,
         nr::CloseHandle,
         nr::WaitSynchronization,
@@ -0,0 +1,91 @@
+//! ISO 9660 filesystem implementation of DirectoryOperations
+use crate::LibUserResult;
+use sunrise_libuser::error::FileSystemError;
+use crate::interface::filesystem::*;
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use sunrise_libuser::fs::{DirectoryEntry, DirectoryEntryType};
+
+use arrayvec::ArrayString;
+
+use super::volume::Entry;
+
+/// A directory of an ISO 9660 volume implementing ``DirectoryOperations``.
+///
+/// As the volume is read-only, its entries are all read when it's opened.
+pub struct DirectoryInterface {
+    /// The opened directory path. Used to get the complete path of every entries.
+    base_path: ArrayString<[u8; PATH_LEN]>,
+
+    /// The entries of the directory, already filtered.
+    entries: Vec<Entry>,
+
+    /// The index of the next entry to read.
+    position: usize,
+}
+
+impl fmt::Debug for DirectoryInterface {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DirectoryInterface")
+           .field("base_path", &&self.base_path[..])
+           .field("entry_count", &self.entries.len())
+           .field("position", &self.position)
+           .finish()
+    }
+}
+
+impl DirectoryInterface {
+    /// Create a new DirectoryInterface.
+    pub fn new(base_path: ArrayString<[u8; PATH_LEN]>, entries: Vec<Entry>) -> Self {
+        DirectoryInterface { base_path, entries, position: 0 }
+    }
+
+    /// convert a volume's Entry to libfs's DirectoryEntry.
+    fn convert_entry(
+        entry: &Entry,
+        base_path: &ArrayString<[u8; PATH_LEN]>,
+    ) -> LibUserResult<DirectoryEntry> {
+        let mut path_str: ArrayString<[u8; PATH_LEN]> = ArrayString::new();
+
+        let directory_entry_type = if entry.is_directory {
+            DirectoryEntryType::Directory
+        } else {
+            DirectoryEntryType::File
+        };
+
+        if path_str.try_push_str(base_path.as_str()).is_err() || path_str.try_push_str(&entry.name).is_err() {
+            return Err(FileSystemError::InvalidInput.into())
+        }
+
+        let mut path = [0x0; PATH_LEN];
+
+        let path_str_slice = path_str.as_bytes();
+        path[..path_str_slice.len()].copy_from_slice(path_str_slice);
+
+        Ok(DirectoryEntry {
+            path,
+            // ISO 9660 has no archive bit.
+            attribute: 0,
+            directory_entry_type,
+            file_size: entry.data_length,
+        })
+    }
+}
+
+impl DirectoryOperations for DirectoryInterface {
+    fn read(&mut self, buf: &mut [DirectoryEntry]) -> LibUserResult<u64> {
+        let mut count = 0;
+        for (entry, out) in self.entries[self.position..].iter().zip(buf.iter_mut()) {
+            *out = Self::convert_entry(entry, &self.base_path)?;
+            count += 1;
+        }
+        self.position += count;
+        Ok(count as u64)
+    }
+
+    fn entry_count(&self) -> LibUserResult<u64> {
+        Ok(self.entries.len() as u64)
+    }
+}
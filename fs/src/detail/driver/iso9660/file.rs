@@ -0,0 +1,58 @@
+//! ISO 9660 filesystem implementation of FileOperations
+use crate::LibUserResult;
+use crate::interface::filesystem::*;
+
+use spin::Mutex;
+use alloc::sync::Arc;
+
+use sunrise_libuser::error::FileSystemError;
+
+use super::volume::{Entry, Volume};
+
+/// A file of an ISO 9660 volume implementing ``FileOperations``.
+#[derive(Debug)]
+pub struct FileInterface {
+    /// The volume holding the file.
+    volume: Arc<Mutex<Volume>>,
+
+    /// The entry of this file.
+    entry: Entry,
+
+    /// File mode flags.
+    mode: FileModeFlags
+}
+
+impl FileInterface {
+    /// Create a new FileInterface.
+    pub fn new(volume: Arc<Mutex<Volume>>, entry: Entry, mode: FileModeFlags) -> Self {
+        FileInterface { volume, entry, mode }
+    }
+}
+
+impl FileOperations for FileInterface {
+    /// Read the content of a file at a given ``offset`` in ``buf``.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> LibUserResult<u64> {
+        if (self.mode & FileModeFlags::READABLE) != FileModeFlags::READABLE {
+            return Err(FileSystemError::AccessDenied.into());
+        }
+
+        self.volume.lock().read_file(&self.entry, offset, buf)
+    }
+
+    fn write(&mut self, _offset: u64, _buf: &[u8]) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn flush(&mut self) -> LibUserResult<()> {
+        // NOP
+        Ok(())
+    }
+
+    fn set_len(&mut self, _size: u64) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn get_len(&mut self) -> LibUserResult<u64> {
+        Ok(self.entry.data_length)
+    }
+}
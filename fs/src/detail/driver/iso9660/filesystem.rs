@@ -0,0 +1,145 @@
+//! IFileSystem implementation for ISO 9660 volumes.
+
+use crate::LibUserResult;
+use sunrise_libuser::error::{Error, FileSystemError};
+use crate::interface::filesystem::*;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use sunrise_libuser::fs::{DirectoryEntryType, FileTimeStampRaw, FileSystemType};
+
+use spin::Mutex;
+
+use storage_device::StorageDevice;
+
+use super::file::FileInterface;
+use super::directory::DirectoryInterface;
+use super::volume::Volume;
+
+use arrayvec::ArrayString;
+
+/// A read-only ISO 9660 filesystem implementing ``FileSystemOperations``.
+#[derive(Debug)]
+pub struct Iso9660FileSystem {
+    /// The volume, shared with the opened files.
+    inner: Arc<Mutex<Volume>>,
+}
+
+impl Iso9660FileSystem {
+    /// Construct an ISO 9660 filesystem instance with an IStorage.
+    pub fn from_storage(storage: Box<dyn StorageDevice<Error = Error> + Send>) -> LibUserResult<Self> {
+        let volume = Volume::from_storage(storage)?;
+        Ok(Iso9660FileSystem { inner: Arc::new(Mutex::new(volume)) })
+    }
+}
+
+impl FileSystemOperations for Iso9660FileSystem {
+    fn create_file(&self, _path: &str, _size: u64) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn create_directory(&self, _path: &str) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn rename_file(&self, _old_path: &str, _new_path: &str) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn rename_directory(&self, _old_path: &str, _new_path: &str) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn delete_file(&self, _path: &str) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn delete_directory(&self, _path: &str) -> LibUserResult<()> {
+        Err(FileSystemError::ReadOnlyFileSystem.into())
+    }
+
+    fn get_entry_type(&self, path: &str) -> LibUserResult<DirectoryEntryType> {
+        let entry = self.inner.lock().lookup(path)?;
+        if entry.is_directory {
+            Ok(DirectoryEntryType::Directory)
+        } else {
+            Ok(DirectoryEntryType::File)
+        }
+    }
+
+    fn open_file(
+        &self,
+        path: &str,
+        mode: FileModeFlags,
+    ) -> LibUserResult<Box<dyn FileOperations>> {
+        if mode.intersects(FileModeFlags::WRITABLE | FileModeFlags::APPENDABLE) {
+            return Err(FileSystemError::ReadOnlyFileSystem.into());
+        }
+
+        let entry = self.inner.lock().lookup(path)?;
+        if entry.is_directory {
+            return Err(FileSystemError::NotAFile.into());
+        }
+        let res = Box::new(FileInterface::new(self.inner.clone(), entry, mode));
+
+        Ok(res as Box<dyn FileOperations>)
+    }
+
+    fn open_directory(
+        &self,
+        path: &str,
+        filter: DirFilterFlags,
+    ) -> LibUserResult<Box<dyn DirectoryOperations>> {
+        let include_directories = filter.contains(DirFilterFlags::DIRECTORY);
+        let include_files = filter.contains(DirFilterFlags::FILE) || !include_directories;
+
+        let mut volume = self.inner.lock();
+        let directory = volume.lookup(path)?;
+        let mut entries = volume.read_directory(&directory)?;
+        entries.retain(|entry| if entry.is_directory { include_directories } else { include_files });
+
+        let mut data: ArrayString<[u8; PATH_LEN]> = ArrayString::new();
+
+        if data.try_push_str(path).is_err() {
+            return Err(FileSystemError::InvalidInput.into());
+        }
+
+        // Add '/' if missing at the end
+        if let Some('/') = path.chars().last() {
+            // Already valid
+        } else if data.try_push('/').is_err() {
+            return Err(FileSystemError::InvalidInput.into());
+        }
+
+        let res = Box::new(DirectoryInterface::new(data, entries));
+
+        Ok(res as Box<dyn DirectoryOperations>)
+    }
+
+    fn get_free_space_size(&self, _path: &str) -> LibUserResult<u64> {
+        Ok(0)
+    }
+
+    fn get_total_space_size(&self, _path: &str) -> LibUserResult<u64> {
+        Ok(self.inner.lock().volume_size())
+    }
+
+    fn get_file_timestamp_raw(&self, path: &str) -> LibUserResult<FileTimeStampRaw> {
+        let entry = self.inner.lock().lookup(path)?;
+
+        // ISO 9660 only records a single date.
+        let result = FileTimeStampRaw {
+            creation_timestamp: entry.timestamp,
+            modified_timestamp: entry.timestamp,
+            accessed_timestamp: entry.timestamp,
+            is_valid: true,
+        };
+
+        Ok(result)
+    }
+
+    fn get_filesystem_type(&self) -> FileSystemType {
+        FileSystemType::ISO9660
+    }
+}
@@ -0,0 +1,51 @@
+//! ISO 9660 driver implementation layer
+//!
+//! A read-only driver for the filesystem of CD-ROMs, supporting Rock Ridge and Joliet names.
+
+use alloc::boxed::Box;
+use crate::LibUserResult;
+use crate::interface::driver::FileSystemDriver;
+use crate::interface::filesystem::FileSystemOperations;
+
+mod directory;
+mod file;
+mod filesystem;
+mod record;
+mod volume;
+
+use storage_device::StorageDevice;
+
+use sunrise_libuser::fs::FileSystemType;
+use sunrise_libuser::error::{Error, FileSystemError};
+use filesystem::Iso9660FileSystem;
+use volume::Volume;
+
+/// An ISO 9660 driver.
+pub struct Iso9660Driver;
+
+impl FileSystemDriver for Iso9660Driver {
+    fn construct(&self, storage: Box<dyn StorageDevice<Error = Error> + Send>) -> LibUserResult<Box<dyn FileSystemOperations>> {
+        let filesystem_instance = Iso9660FileSystem::from_storage(storage)?;
+        Ok(Box::new(filesystem_instance) as Box<dyn FileSystemOperations>)
+    }
+
+    fn probe(&self, storage: &mut (dyn StorageDevice<Error = Error> + Send)) -> Option<FileSystemType> {
+        if Volume::probe(storage) {
+            Some(FileSystemType::ISO9660)
+        } else {
+            None
+        }
+    }
+
+    fn is_supported(&self, filesytem_type: FileSystemType) -> bool {
+        match filesytem_type {
+            FileSystemType::ISO9660 => true,
+            _ => false
+        }
+    }
+
+    fn format(&self, _storage: Box<dyn StorageDevice<Error = Error> + Send>, _filesytem_type: FileSystemType) -> LibUserResult<()> {
+        // Read-only, ISO 9660 images are made by mastering tools.
+        Err(FileSystemError::UnsupportedOperation.into())
+    }
+}
@@ -0,0 +1,340 @@
+//! ISO 9660 directory records, and the decoding of their names.
+//!
+//! See ECMA-119 section 9.1 for the layout of a directory record, the Joliet specification for
+//! the UCS-2 names of the supplementary volume descriptor, and IEEE P1281 (SUSP) and P1282 (RRIP)
+//! for the Rock Ridge extensions found in the System Use area of a record.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use byteorder::{LE, ByteOrder};
+
+/// Size of the fixed part of a directory record, before its file identifier.
+pub const RECORD_HEADER_LEN: usize = 33;
+
+/// The record describes a directory.
+const FLAG_DIRECTORY: u8 = 1 << 1;
+/// The record describes an associated file, like a Macintosh resource fork.
+const FLAG_ASSOCIATED_FILE: u8 = 1 << 2;
+
+/// How the names of the files are recorded on a volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameEncoding {
+    /// Plain ISO 9660 names: upper-case, with a version suffix.
+    Iso,
+    /// Joliet names, found in a supplementary volume descriptor: UCS-2 big endian, with a version
+    /// suffix.
+    Joliet,
+    /// Rock Ridge names, found in the `NM` entries of the System Use area of the records.
+    ///
+    /// The first `skip` octets of every System Use area are to be ignored, as given by the
+    /// `SP` entry of the root directory.
+    RockRidge {
+        /// Octets to skip at the start of a System Use area.
+        skip: usize
+    },
+}
+
+/// A directory record, describing a file or a directory.
+#[derive(Debug, Clone)]
+pub struct DirectoryRecord<'a> {
+    /// The raw record, without the padding that may follow it.
+    bytes: &'a [u8],
+}
+
+impl<'a> DirectoryRecord<'a> {
+    /// Parses the directory record at the start of `bytes`.
+    ///
+    /// Returns `None` if the record is truncated, or if its length is 0, which marks the end of
+    /// the records of a logical block.
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let length = *bytes.get(0)? as usize;
+        let identifier_len = *bytes.get(32)? as usize;
+        if length < RECORD_HEADER_LEN + identifier_len || length > bytes.len() {
+            return None;
+        }
+        Some(DirectoryRecord { bytes: &bytes[..length] })
+    }
+
+    /// The length of the record, in octets.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The logical block where the data of the file starts, after its extended attribute record.
+    pub fn extent(&self) -> u64 {
+        u64::from(LE::read_u32(&self.bytes[2..6])) + u64::from(self.bytes[1])
+    }
+
+    /// The size of the data of the file, in octets.
+    ///
+    /// Files bigger than 4GiB are recorded in several extents, of which we only know the first.
+    pub fn data_length(&self) -> u64 {
+        u64::from(LE::read_u32(&self.bytes[10..14]))
+    }
+
+    /// Whether this record describes a directory.
+    pub fn is_directory(&self) -> bool {
+        self.bytes[25] & FLAG_DIRECTORY != 0
+    }
+
+    /// Whether this record describes an associated file, which we hide.
+    pub fn is_associated_file(&self) -> bool {
+        self.bytes[25] & FLAG_ASSOCIATED_FILE != 0
+    }
+
+    /// Whether this is the `.` or the `..` record, found at the start of every directory.
+    pub fn is_self_or_parent(&self) -> bool {
+        match self.identifier() {
+            [0] | [1] => true,
+            _ => false
+        }
+    }
+
+    /// The recording date of the file, as a UNIX timestamp.
+    pub fn timestamp(&self) -> u64 {
+        recording_date_to_timestamp(&self.bytes[18..25])
+    }
+
+    /// The raw file identifier.
+    pub fn identifier(&self) -> &'a [u8] {
+        &self.bytes[RECORD_HEADER_LEN..RECORD_HEADER_LEN + self.bytes[32] as usize]
+    }
+
+    /// The System Use area, following the identifier and its padding.
+    pub fn system_use(&self) -> &'a [u8] {
+        let identifier_len = self.bytes[32] as usize;
+        // a padding byte makes the System Use area start on an even offset.
+        let start = RECORD_HEADER_LEN + identifier_len + (1 - identifier_len % 2);
+        self.bytes.get(start..).unwrap_or(&[])
+    }
+
+    /// Decodes the identifier as an ISO 9660 or Joliet name, dropping its version suffix, and
+    /// the dot of a name without extension.
+    ///
+    /// Rock Ridge names are found in the System Use area, see [SuspEntries].
+    pub fn decode_identifier(&self, encoding: NameEncoding) -> String {
+        let mut name = match encoding {
+            NameEncoding::Joliet => {
+                let units = self.identifier().chunks_exact(2).map(|unit| u16::from(unit[0]) << 8 | u16::from(unit[1]));
+                core::char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect::<String>()
+            },
+            _ => String::from_utf8_lossy(self.identifier()).into_owned()
+        };
+        if let Some(version) = name.rfind(';') {
+            name.truncate(version);
+        }
+        if name.ends_with('.') {
+            name.pop();
+        }
+        name
+    }
+}
+
+/// An entry of a System Use area, as defined by SUSP.
+#[derive(Debug, Clone, Copy)]
+pub struct SuspEntry<'a> {
+    /// The two characters identifying the entry.
+    pub signature: [u8; 2],
+    /// The content of the entry, after its 4-octet header.
+    pub data: &'a [u8],
+}
+
+/// An iterator over the entries of a System Use area.
+#[derive(Debug, Clone)]
+pub struct SuspEntries<'a> {
+    /// The entries that weren't iterated yet.
+    area: &'a [u8],
+}
+
+impl<'a> SuspEntries<'a> {
+    /// Iterates over the entries of `area`.
+    pub fn new(area: &'a [u8]) -> Self {
+        SuspEntries { area }
+    }
+}
+
+impl<'a> Iterator for SuspEntries<'a> {
+    type Item = SuspEntry<'a>;
+
+    fn next(&mut self) -> Option<SuspEntry<'a>> {
+        if self.area.len() < 4 {
+            return None;
+        }
+        let length = self.area[2] as usize;
+        if length < 4 || length > self.area.len() || &self.area[..2] == b"ST" {
+            // a corrupted entry, or the terminator.
+            self.area = &[];
+            return None;
+        }
+        let entry = SuspEntry { signature: [self.area[0], self.area[1]], data: &self.area[4..length] };
+        self.area = &self.area[length..];
+        Some(entry)
+    }
+}
+
+/// Where the System Use area of a record continues, as given by a `CE` entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Continuation {
+    /// The logical block of the continuation area.
+    pub block: u64,
+    /// The offset of the continuation area in its block.
+    pub offset: u64,
+    /// The length of the continuation area.
+    pub length: usize,
+}
+
+impl Continuation {
+    /// Parses the content of a `CE` entry.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 24 {
+            return None;
+        }
+        Some(Continuation {
+            block: u64::from(LE::read_u32(&data[0..4])),
+            offset: u64::from(LE::read_u32(&data[8..12])),
+            length: LE::read_u32(&data[16..20]) as usize,
+        })
+    }
+}
+
+/// Gets the number of octets to skip at the start of every System Use area from the `SP` entry,
+/// found in the System Use area of the `.` record of the root directory.
+///
+/// Returns `None` if the volume doesn't use SUSP, and thus Rock Ridge.
+pub fn susp_skip(root_system_use: &[u8]) -> Option<usize> {
+    let entry = SuspEntries::new(root_system_use).next()?;
+    match (&entry.signature, entry.data) {
+        (b"SP", [0xBE, 0xEF, skip, ..]) => Some(*skip as usize),
+        _ => None
+    }
+}
+
+/// Appends the name fragments of the `NM` entries of a System Use area to `name`.
+///
+/// # Returns
+///
+/// - Whether the name is complete. If not, it continues in a following `NM` entry, possibly in
+///   a continuation area.
+/// - The continuation area of this System Use area, if any.
+pub fn rock_ridge_name(area: &[u8], name: &mut Vec<u8>) -> (bool, Option<Continuation>) {
+    /// The name continues in the next NM entry.
+    const NM_CONTINUE: u8 = 1 << 0;
+
+    let mut continuation = None;
+    for entry in SuspEntries::new(area) {
+        match (&entry.signature, entry.data) {
+            (b"NM", [flags, fragment @ ..]) => {
+                name.extend_from_slice(fragment);
+                if flags & NM_CONTINUE == 0 {
+                    return (true, None);
+                }
+            },
+            (b"CE", data) => continuation = Continuation::parse(data),
+            _ => ()
+        }
+    }
+    (false, continuation)
+}
+
+/// Converts the 7-octet recording date of a directory record to a UNIX timestamp.
+///
+/// It holds the years since 1900, the month, the day, the hour, the minute, the second, and the
+/// offset from UTC in 15 minutes intervals. An unspecified date is all zeroes, and gives 0.
+pub fn recording_date_to_timestamp(date: &[u8]) -> u64 {
+    let (year, month, day) = (i64::from(date[0]) + 1900, i64::from(date[1]), i64::from(date[2]));
+    if month < 1 || month > 12 || day < 1 || day > 31 {
+        return 0;
+    }
+    // days since 1970-01-01, from Howard Hinnant's days_from_civil.
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = i64::from(date[3]) * 3600 + i64::from(date[4]) * 60 + i64::from(date[5]);
+    let utc_offset = i64::from(date[6] as i8) * 15 * 60;
+    let timestamp = days * 86400 + seconds - utc_offset;
+    if timestamp < 0 { 0 } else { timestamp as u64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a file record named `identifier`, followed by the `system_use` area.
+    fn record(identifier: &[u8], system_use: &[u8]) -> Vec<u8> {
+        let mut bytes = [0u8; RECORD_HEADER_LEN].to_vec();
+        bytes.extend_from_slice(identifier);
+        if identifier.len() % 2 == 0 {
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(system_use);
+        bytes[0] = bytes.len() as u8;
+        bytes[2..6].copy_from_slice(&20u32.to_le_bytes());
+        bytes[10..14].copy_from_slice(&1234u32.to_le_bytes());
+        bytes[18..25].copy_from_slice(&[119, 10, 18, 12, 30, 0, 8]);
+        bytes[32] = identifier.len() as u8;
+        bytes
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let bytes = record(b"README.TXT;1", b"");
+        let parsed = DirectoryRecord::parse(&bytes).unwrap();
+        assert_eq!(parsed.len(), bytes.len());
+        assert_eq!(parsed.extent(), 20);
+        assert_eq!(parsed.data_length(), 1234);
+        assert!(!parsed.is_directory());
+        assert!(!parsed.is_self_or_parent());
+        assert_eq!(parsed.decode_identifier(NameEncoding::Iso), "README.TXT");
+        assert!(DirectoryRecord::parse(&bytes[..bytes.len() - 1]).is_none());
+        assert!(DirectoryRecord::parse(&[0; 34]).is_none());
+    }
+
+    #[test]
+    fn test_decode_identifier() {
+        let bytes = record(b"BOOT.;1", b"");
+        assert_eq!(DirectoryRecord::parse(&bytes).unwrap().decode_identifier(NameEncoding::Iso), "BOOT");
+        let bytes = record(b"\0b\0o\0o\0t\0.\0c\0f\0g\0;\01", b"");
+        assert_eq!(DirectoryRecord::parse(&bytes).unwrap().decode_identifier(NameEncoding::Joliet), "boot.cfg");
+    }
+
+    #[test]
+    fn test_rock_ridge_name() {
+        let bytes = record(b"LONGNA~1.TXT;1", b"PX\x04\x01NM\x0b\x01\x01long_nNM\x0c\x01\x00ame.txt");
+        let parsed = DirectoryRecord::parse(&bytes).unwrap();
+        let mut name = Vec::new();
+        assert_eq!(rock_ridge_name(parsed.system_use(), &mut name), (true, None));
+        assert_eq!(&name[..], b"long_name.txt");
+
+        let mut ce = b"CE\x1c\x01".to_vec();
+        for value in &[30u32, 100, 64] {
+            ce.extend_from_slice(&value.to_le_bytes());
+            ce.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut name = Vec::new();
+        assert_eq!(rock_ridge_name(&ce, &mut name), (false, Some(Continuation { block: 30, offset: 100, length: 64 })));
+        assert!(name.is_empty());
+    }
+
+    #[test]
+    fn test_susp_skip() {
+        assert_eq!(susp_skip(b"SP\x07\x01\xbe\xef\x00"), Some(0));
+        assert_eq!(susp_skip(b"SP\x07\x01\xbe\xef\x02"), Some(2));
+        assert_eq!(susp_skip(b"PX\x04\x01"), None);
+        assert_eq!(susp_skip(b""), None);
+    }
+
+    #[test]
+    fn test_recording_date_to_timestamp() {
+        // 2019-10-18 12:30:00 UTC+2
+        assert_eq!(recording_date_to_timestamp(&[119, 10, 18, 12, 30, 0, 8]), 1_571_394_600);
+        assert_eq!(recording_date_to_timestamp(&[70, 1, 1, 0, 0, 0, 0]), 0);
+        assert_eq!(recording_date_to_timestamp(&[0; 7]), 0);
+    }
+}
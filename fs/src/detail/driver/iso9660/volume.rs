@@ -0,0 +1,282 @@
+//! ISO 9660 volume: its volume descriptors, and the lookup of its files.
+
+use crate::LibUserResult;
+use sunrise_libuser::error::{Error, FileSystemError};
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt;
+use core::fmt::{Debug, Formatter};
+
+use byteorder::{LE, ByteOrder};
+use storage_device::StorageDevice;
+
+use super::record::{self, DirectoryRecord, NameEncoding};
+
+/// The size of a volume descriptor, which is the size of a CD-ROM sector.
+const VOLUME_DESCRIPTOR_SIZE: u64 = 2048;
+/// The first volume descriptor follows the 16 sectors of the System Area.
+const FIRST_VOLUME_DESCRIPTOR: u64 = 16;
+/// The maximum number of volume descriptors we look at before giving up on the terminator.
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+/// The standard identifier found in every volume descriptor.
+const STANDARD_IDENTIFIER: &[u8; 5] = b"CD001";
+
+/// Type of the Primary Volume Descriptor.
+const PRIMARY_VOLUME_DESCRIPTOR: u8 = 1;
+/// Type of a Supplementary Volume Descriptor, used by Joliet.
+const SUPPLEMENTARY_VOLUME_DESCRIPTOR: u8 = 2;
+/// Type of the Volume Descriptor Set Terminator.
+const VOLUME_DESCRIPTOR_SET_TERMINATOR: u8 = 255;
+
+/// The maximum number of continuation areas we follow for a single System Use area.
+const MAX_CONTINUATIONS: usize = 16;
+
+/// A file or a directory found on the volume.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The name of the entry, decoded from its Rock Ridge, Joliet, or ISO 9660 name.
+    pub name: String,
+    /// The offset of its data on the volume, in octets.
+    pub data_offset: u64,
+    /// The size of its data, in octets.
+    pub data_length: u64,
+    /// Whether this entry is a directory.
+    pub is_directory: bool,
+    /// Its recording date, as a UNIX timestamp.
+    pub timestamp: u64,
+}
+
+/// A read-only ISO 9660 volume.
+///
+/// Names are taken from the Rock Ridge extensions if the volume has them, from the Joliet
+/// supplementary volume descriptor otherwise, falling back to plain ISO 9660 names.
+///
+/// Files recorded in several extents (bigger than 4GiB), and the relocated directories of Rock
+/// Ridge are not supported.
+pub struct Volume {
+    /// The storage holding the volume.
+    storage: Box<dyn StorageDevice<Error = Error> + Send>,
+    /// The size of a logical block, in octets. Usually 2048.
+    block_size: u64,
+    /// The size of the volume, in octets.
+    volume_size: u64,
+    /// How names are recorded on this volume.
+    encoding: NameEncoding,
+    /// The root directory.
+    root: Entry,
+}
+
+impl Debug for Volume {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Volume")
+         .field("block_size", &self.block_size)
+         .field("volume_size", &self.volume_size)
+         .field("encoding", &self.encoding)
+         .finish()
+    }
+}
+
+/// Reads the volume descriptor at `index`.
+fn read_volume_descriptor(storage: &mut (dyn StorageDevice<Error = Error> + Send), index: u64) -> LibUserResult<[u8; VOLUME_DESCRIPTOR_SIZE as usize]> {
+    let mut descriptor = [0; VOLUME_DESCRIPTOR_SIZE as usize];
+    storage.read(index * VOLUME_DESCRIPTOR_SIZE, &mut descriptor)?;
+    Ok(descriptor)
+}
+
+/// Checks if a Supplementary Volume Descriptor is a Joliet one, from the UCS-2 escape
+/// sequence of its character set.
+fn is_joliet(descriptor: &[u8]) -> bool {
+    match &descriptor[88..91] {
+        b"%/@" | b"%/C" | b"%/E" => true,
+        _ => false
+    }
+}
+
+impl Volume {
+    /// Checks if the storage holds an ISO 9660 volume, from the standard identifier of its first
+    /// volume descriptor.
+    pub fn probe(storage: &mut (dyn StorageDevice<Error = Error> + Send)) -> bool {
+        read_volume_descriptor(storage, FIRST_VOLUME_DESCRIPTOR)
+            .map(|descriptor| &descriptor[1..6] == STANDARD_IDENTIFIER)
+            .unwrap_or(false)
+    }
+
+    /// Opens the ISO 9660 volume found on the storage.
+    ///
+    /// # Error
+    ///
+    /// - InvalidPartition: the storage doesn't hold a valid ISO 9660 volume.
+    pub fn from_storage(mut storage: Box<dyn StorageDevice<Error = Error> + Send>) -> LibUserResult<Self> {
+        let mut primary = None;
+        let mut joliet = None;
+        for index in FIRST_VOLUME_DESCRIPTOR..FIRST_VOLUME_DESCRIPTOR + MAX_VOLUME_DESCRIPTORS {
+            let descriptor = read_volume_descriptor(storage.as_mut(), index)?;
+            if &descriptor[1..6] != STANDARD_IDENTIFIER {
+                return Err(FileSystemError::InvalidPartition.into());
+            }
+            match descriptor[0] {
+                PRIMARY_VOLUME_DESCRIPTOR if primary.is_none() => primary = Some(descriptor),
+                SUPPLEMENTARY_VOLUME_DESCRIPTOR if joliet.is_none() && is_joliet(&descriptor) => joliet = Some(descriptor),
+                VOLUME_DESCRIPTOR_SET_TERMINATOR => break,
+                _ => ()
+            }
+        }
+        let primary = primary.ok_or(FileSystemError::InvalidPartition)?;
+
+        let block_size = u64::from(LE::read_u16(&primary[128..130]));
+        if block_size == 0 || !block_size.is_power_of_two() {
+            return Err(FileSystemError::InvalidPartition.into());
+        }
+        let volume_size = u64::from(LE::read_u32(&primary[80..84])) * block_size;
+
+        let mut volume = Volume {
+            storage,
+            block_size,
+            volume_size,
+            encoding: NameEncoding::Iso,
+            root: Self::root_entry(&primary, block_size)?,
+        };
+
+        // Rock Ridge is announced by the SP entry of the first record of the root directory.
+        let mut first_record = [0; 0x100];
+        let record_len = min(first_record.len() as u64, volume.root.data_length) as usize;
+        volume.storage.read(volume.root.data_offset, &mut first_record[..record_len])?;
+        let rock_ridge_skip = DirectoryRecord::parse(&first_record[..record_len])
+            .and_then(|record| record::susp_skip(record.system_use()));
+
+        if let Some(skip) = rock_ridge_skip {
+            volume.encoding = NameEncoding::RockRidge { skip };
+        } else if let Some(joliet) = joliet {
+            volume.encoding = NameEncoding::Joliet;
+            volume.root = Self::root_entry(&joliet, block_size)?;
+        }
+        Ok(volume)
+    }
+
+    /// Gets the root directory from the record found in a volume descriptor.
+    fn root_entry(descriptor: &[u8], block_size: u64) -> LibUserResult<Entry> {
+        let record = DirectoryRecord::parse(&descriptor[156..190])
+            .ok_or(FileSystemError::InvalidPartition)?;
+        Ok(Entry {
+            name: String::new(),
+            data_offset: record.extent() * block_size,
+            data_length: record.data_length(),
+            is_directory: true,
+            timestamp: record.timestamp(),
+        })
+    }
+
+    /// The size of the volume, in octets.
+    pub fn volume_size(&self) -> u64 {
+        self.volume_size
+    }
+
+    /// Gets the name of a record, decoded according to the encoding of the volume.
+    fn entry_name(&mut self, record: &DirectoryRecord) -> LibUserResult<String> {
+        if let NameEncoding::RockRidge { skip } = self.encoding {
+            let mut name = Vec::new();
+            let mut area = record.system_use().get(skip..).unwrap_or(&[]).to_vec();
+            for _ in 0..MAX_CONTINUATIONS {
+                match record::rock_ridge_name(&area, &mut name) {
+                    (true, _) => return Ok(String::from_utf8_lossy(&name).into_owned()),
+                    // a continuation area is contained in a single logical block.
+                    (false, Some(continuation)) if continuation.length as u64 <= self.block_size => {
+                        area.resize(continuation.length, 0);
+                        self.storage.read(continuation.block * self.block_size + continuation.offset, &mut area)?;
+                    },
+                    (false, _) => break
+                }
+            }
+        }
+        // Files without NM entry keep their ISO 9660 name.
+        Ok(record.decode_identifier(self.encoding))
+    }
+
+    /// Reads the entries of a directory, skipping `.`, `..`, and associated files.
+    ///
+    /// # Error
+    ///
+    /// - NotADirectory: `directory` is a file.
+    /// - OutOfRange: the directory doesn't fit on the volume.
+    pub fn read_directory(&mut self, directory: &Entry) -> LibUserResult<Vec<Entry>> {
+        if !directory.is_directory {
+            return Err(FileSystemError::NotADirectory.into());
+        }
+        if directory.data_offset.saturating_add(directory.data_length) > self.storage.len()? {
+            return Err(FileSystemError::OutOfRange.into());
+        }
+        let mut data = Vec::new();
+        data.resize(directory.data_length as usize, 0);
+        self.storage.read(directory.data_offset, &mut data)?;
+
+        let mut entries = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            let record = match DirectoryRecord::parse(&data[position..]) {
+                Some(record) => record,
+                None => {
+                    // records don't cross logical blocks, the rest of this one is padding.
+                    position = (position / self.block_size as usize + 1) * self.block_size as usize;
+                    continue;
+                }
+            };
+            position += record.len();
+            if record.is_self_or_parent() || record.is_associated_file() {
+                continue;
+            }
+            entries.push(Entry {
+                name: self.entry_name(&record)?,
+                data_offset: record.extent() * self.block_size,
+                data_length: record.data_length(),
+                is_directory: record.is_directory(),
+                timestamp: record.timestamp(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Compares a path component to the name of an entry.
+    ///
+    /// Rock Ridge names are case sensitive, ISO 9660 and Joliet ones are not.
+    fn name_matches(&self, entry: &Entry, component: &str) -> bool {
+        match self.encoding {
+            NameEncoding::RockRidge { .. } => entry.name == component,
+            _ => entry.name.eq_ignore_ascii_case(component)
+        }
+    }
+
+    /// Looks up the entry at `path`.
+    ///
+    /// # Error
+    ///
+    /// - PathNotFound: no entry exists at `path`.
+    pub fn lookup(&mut self, path: &str) -> LibUserResult<Entry> {
+        let mut entry = self.root.clone();
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !entry.is_directory {
+                return Err(FileSystemError::PathNotFound.into());
+            }
+            entry = self.read_directory(&entry)?
+                .into_iter()
+                .find(|child| self.name_matches(child, component))
+                .ok_or(FileSystemError::PathNotFound)?;
+        }
+        Ok(entry)
+    }
+
+    /// Reads the data of a file at `offset` in `buf`.
+    ///
+    /// Returns the number of octets read, which is smaller than the length of `buf` if it
+    /// reaches the end of the file.
+    pub fn read_file(&mut self, file: &Entry, offset: u64, buf: &mut [u8]) -> LibUserResult<u64> {
+        if offset >= file.data_length {
+            return Ok(0);
+        }
+        let length = min(buf.len() as u64, file.data_length - offset) as usize;
+        self.storage.read(file.data_offset + offset, &mut buf[..length])?;
+        Ok(length as u64)
+    }
+}
@@ -1,9 +1,11 @@
 //! Contains driver implementations of file system.
 
 pub mod fat;
+pub mod iso9660;
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::mem::size_of;
 use spin::Mutex;
 
 use crate::LibUserResult;
@@ -14,11 +16,15 @@ use storage_device::block::Block;
 use storage_device::block_device::*;
 use storage_device::storage_device::StorageBlockDevice;
 use storage_device::cached_block_device::CachedBlockDevice;
+use storage_device::StorageDevice;
 
 use sunrise_libuser::fs::{DiskId, FileSystemType, PartitionId};
 use sunrise_libuser::ahci::*;
 use sunrise_libuser::ahci::Block as AhciBlock;
 use sunrise_libuser::error::{Error, FileSystemError};
+use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+use sunrise_libuser::syscalls::MemoryPermissions;
+use sunrise_libuser::types::{MappedSharedMemory, SharedMemory};
 
 use lazy_static::lazy_static;
 use alloc::sync::{Arc, Weak};
@@ -43,17 +49,17 @@ pub struct DriverManager {
     /// The partitions opened in drives.
     partitions: PartitionHashMap<Box<dyn FileSystemOperations>>,
 
-    /// AHCI IPC interface.
-    ahci_interface: AhciInterfaceProxy
+    /// The IPC interfaces of the disk drivers, in the order their disks are numbered.
+    disk_drivers: Vec<AhciInterfaceProxy>
 }
 
 impl Default for DriverManager {
     fn default() -> Self {
         DriverManager {
             registry: Vec::new(),
-            ahci_interface: AhciInterfaceProxy::raw_new().expect("Cannot create AHCI interface"),
             drives: HashMap::new(),
-            partitions: HashMap::new()
+            partitions: HashMap::new(),
+            disk_drivers: Vec::new()
         }
     }
 }
@@ -64,29 +70,43 @@ impl DriverManager {
         self.registry.push(driver);
     }
 
+    /// Register a new disk driver, whose disks will be opened by [init_drives].
+    ///
+    /// [init_drives]: DriverManager::init_drives
+    pub fn register_disk_driver(&mut self, disk_driver: AhciInterfaceProxy) {
+        self.disk_drivers.push(disk_driver);
+    }
+
     /// Add a new drive to the open hashmap.
     pub fn add_opened_drive(&mut self, disk_id: DiskId, drive: Arc<Mutex<BoxedIStorage>>) {
         self.drives.insert(disk_id, drive);
         self.partitions.insert(disk_id, HashMap::new());
     }
 
-    /// Do the disk init using AHCI
+    /// Do the disk init, opening the disks of every registered disk driver.
+    ///
+    /// Disks are numbered in the order their drivers were registered, and then in the order
+    /// each driver discovered them.
     pub fn init_drives(&mut self) -> LibUserResult<()> {
-        let disk_count = self.ahci_interface.discovered_disks_count()?;
-        if disk_count == 0 {
+        let mut disks = Vec::new();
+        for disk_driver in &self.disk_drivers {
+            for driver_disk_id in 0..disk_driver.discovered_disks_count()? {
+                disks.push(disk_driver.get_disk(driver_disk_id)?);
+            }
+        }
+        if disks.is_empty() {
             warn!("No drive have been found!");
         }
 
-        for disk_id in 0..disk_count {
-            let ahci_disk = self.ahci_interface.get_disk(disk_id)?;
-            let device = Arc::new(Mutex::new(Box::new(StorageBlockDevice::new(CachedBlockDevice::new(AhciDiskStorage::new(ahci_disk), 0x100))) as BoxedIStorage));
-            self.add_opened_drive(disk_id, device);
+        for (disk_id, disk) in disks.into_iter().enumerate() {
+            let device = Arc::new(Mutex::new(Box::new(StorageBlockDevice::new(CachedBlockDevice::new(DiskStorage::new(disk), 0x100))) as BoxedIStorage));
+            self.add_opened_drive(disk_id as DiskId, device);
         }
 
         Ok(())
     }
 
-    /// Open a AHCI disk as a IStorage.
+    /// Open a disk as a IStorage.
     pub fn open_disk_storage(&mut self, disk_id: DiskId) -> LibUserResult<Arc<Mutex<BoxedIStorage>>> {
         self.drives.get(&disk_id).ok_or_else(|| FileSystemError::DiskNotFound.into()).map(|arc| arc.clone())
     }
@@ -96,6 +116,14 @@ impl DriverManager {
         self.drives.len() as u32
     }
 
+    /// Write back the data cached for every opened drive.
+    pub fn flush_disks(&mut self) -> LibUserResult<()> {
+        for drive in self.drives.values() {
+            drive.lock().flush()?;
+        }
+        Ok(())
+    }
+
     /// Open an instance of a filesystem.
     pub fn construct_filesystem_from_disk_partition(&mut self, disk_id: DiskId, partition_id: PartitionId, mut storage: PartitionStorage) -> LibUserResult<Arc<Mutex<Box<dyn FileSystemOperations>>>> {
         let disk_hashmap_opt  = self.partitions.get_mut(&disk_id);
@@ -140,32 +168,75 @@ lazy_static! {
     pub static ref DRIVER_MANAGER: Mutex<DriverManager> = Mutex::default();
 }
 
+/// Size of the buffer shared with the disk driver for the big reads.
+const SHARED_BUFFER_SIZE: usize = 0x20000;
+
+/// The number of blocks from which a read goes through the shared buffer.
+///
+/// Smaller reads are passed in IPC buffers, which saves a round-trip through the shared memory.
+const SHARED_READ_MIN_BLOCKS: usize = 8;
+
 #[derive(Debug)]
-/// A wrapper to a ahci IDisk.
-pub struct AhciDiskStorage {
+/// A wrapper to an IDisk, served by any disk driver.
+pub struct DiskStorage {
     /// The inner IDisk.
-    inner: IDiskProxy
+    inner: IDiskProxy,
+
+    /// The buffer shared with the disk driver, where it reads sectors directly.
+    ///
+    /// None if registering it failed, in which case we only use IPC buffers.
+    shared_buffer: Option<MappedSharedMemory>
 }
 
-impl AhciDiskStorage {
-    /// Create a new AhciDiskStorage.
+impl DiskStorage {
+    /// Create a new DiskStorage.
     pub fn new(device: IDiskProxy) -> Self {
-        AhciDiskStorage {
-            inner: device
+        let shared_buffer = Self::register_shared_buffer(&device);
+        if let Err(error) = &shared_buffer {
+            warn!("Cannot share a buffer with the disk driver, falling back to IPC buffers: {:?}", error);
         }
+        DiskStorage {
+            inner: device,
+            shared_buffer: shared_buffer.ok()
+        }
+    }
+
+    /// Create a buffer and share it with the disk driver.
+    fn register_shared_buffer(device: &IDiskProxy) -> LibUserResult<MappedSharedMemory> {
+        let rw = MemoryPermissions::READABLE | MemoryPermissions::WRITABLE;
+        let shared_memory = SharedMemory::new(SHARED_BUFFER_SIZE, rw, rw)?;
+        let address = find_free_address(SHARED_BUFFER_SIZE, PAGE_SIZE)?;
+        let shared_buffer = shared_memory.map(address, SHARED_BUFFER_SIZE, rw)?;
+        device.register_shared_buffer(shared_buffer.as_shared_mem(), SHARED_BUFFER_SIZE as u64)?;
+        Ok(shared_buffer)
     }
 }
 
-impl BlockDevice for AhciDiskStorage {
+impl BlockDevice for DiskStorage {
     type Block = Block;
     type Error = Error;
 
     /// Read blocks from the block device starting at the given ``index``.
     fn read(&mut self, blocks: &mut [Self::Block], index: BlockIndex) -> Result<(), Error> {
-        self.inner.read_dma(index.0, unsafe {
-            // Safety: This operation is safe as AhciBlock and Block have the same memory representation and the same alignment requirements.
-            core::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut AhciBlock, blocks.len())
-        })
+        match &self.shared_buffer {
+            Some(shared_buffer) if blocks.len() >= SHARED_READ_MIN_BLOCKS => {
+                let blocks_per_read = shared_buffer.len() / size_of::<Self::Block>();
+                for (chunk_index, chunk) in blocks.chunks_mut(blocks_per_read).enumerate() {
+                    let lba = index.0 + (chunk_index * blocks_per_read) as u64;
+                    self.inner.read_shared(0, lba, chunk.len() as u64)?;
+                    unsafe {
+                        // Safety: the driver is done writing to the shared buffer, which is big enough
+                        // for the chunk, and we are its only user.
+                        core::ptr::copy_nonoverlapping(shared_buffer.as_ptr(), chunk.as_mut_ptr() as *mut u8, chunk.len() * size_of::<Self::Block>());
+                    }
+                }
+                Ok(())
+            },
+            _ => self.inner.read_dma(index.0, unsafe {
+                // Safety: This operation is safe as AhciBlock and Block have the same memory representation and the same alignment requirements.
+                core::slice::from_raw_parts_mut(blocks.as_mut_ptr() as *mut AhciBlock, blocks.len())
+            })
+        }
     }
 
     /// Write blocks to the block device starting at the given ``index``.
@@ -269,22 +269,31 @@ pub struct FileSystemProxy {
 impl FileSystemProxy {
     /// Open a disk partition filesystem.
     /// This may fail if no compatible driver i
+    ///
+    /// A disk without partition table, like a CD-ROM, is considered to have a single partition,
+    /// with id 0, spanning the whole disk.
     pub fn open_disk_partition(&mut self, disk_id: DiskId, partition_id: PartitionId) -> LibUserResult<Arc<Mutex<Box<dyn FileSystemOperations>>>> {
         let storage = self.open_disk_storage(disk_id)?;
-        let partition_option = PartitionIterator::new(storage.lock().as_mut(), true)?.nth(partition_id as usize);
-
-        if let Some(partition) = partition_option {
-            let partition = partition?;
-
-            let partition_start = partition.first_lba * BLOCK_SIZE_U64;
-            let partition_len = (partition.last_lba * BLOCK_SIZE_U64) - partition_start;
-
-            let storage = PartitionStorage::new(storage, partition_start, partition_len);
-            return DRIVER_MANAGER.lock().construct_filesystem_from_disk_partition(disk_id, partition_id, storage);
-        }
-
+        let partition_option = PartitionIterator::new(storage.lock().as_mut(), true)
+            .map(|mut partitions| partitions.nth(partition_id as usize));
+
+        let (partition_start, partition_len) = match partition_option {
+            Ok(Some(partition)) => {
+                let partition = partition?;
+
+                let partition_start = partition.first_lba * BLOCK_SIZE_U64;
+                (partition_start, (partition.last_lba * BLOCK_SIZE_U64) - partition_start)
+            },
+            Err(Error::FileSystem(FileSystemError::PartitionNotFound, _)) if partition_id == 0 => {
+                let disk_len = storage.lock().len()?;
+                (0, disk_len)
+            },
+            Err(error) => return Err(error),
+            Ok(None) => return Err(FileSystemError::PartitionNotFound.into())
+        };
 
-        Err(FileSystemError::PartitionNotFound.into())
+        let storage = PartitionStorage::new(storage, partition_start, partition_len);
+        DRIVER_MANAGER.lock().construct_filesystem_from_disk_partition(disk_id, partition_id, storage)
     }
 
     /// Open a disk as a block device.
@@ -324,5 +333,10 @@ impl FileSystemProxy {
         let mut partition_manager = PartitionManager::new(storage.as_mut());
         partition_manager.initialize()
     }
+
+    /// Write back the data cached for every disk.
+    pub fn flush_disks(&mut self) -> LibUserResult<()> {
+        DRIVER_MANAGER.lock().flush_disks()
+    }
 }
 
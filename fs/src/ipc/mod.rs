@@ -124,6 +124,10 @@ impl sunrise_libuser::fs::IFileSystemService for FileSystemService {
     fn initialize_disk(&mut self, _manager: WorkQueue<'static>, disk_id: DiskId) -> Result<(), Error> {
         self.inner.initialize_disk(disk_id)
     }
+
+    fn flush_disks(&mut self, _manager: WorkQueue<'static>) -> Result<(), Error> {
+        self.inner.flush_disks()
+    }
 }
 
 /// Represent a file in the IPC.
@@ -31,6 +31,7 @@ extern crate static_assertions;
 use sunrise_libuser::error::Error;
 use sunrise_libuser::futures::WaitableManager;
 use sunrise_libuser::fs::IFileSystemService;
+use sunrise_libuser::ahci::AhciInterfaceProxy;
 use sunrise_libuser::ipc::server::port_handler;
 use sunrise_libuser::futures_rs::future::FutureObj;
 
@@ -43,6 +44,7 @@ mod ipc;
 use detail::driver::DRIVER_MANAGER;
 use interface::driver::FileSystemDriver;
 use detail::driver::fat::FATDriver;
+use detail::driver::iso9660::Iso9660Driver;
 
 /// A libuser result.
 pub type LibUserResult<T> = Result<T, Error>;
@@ -51,6 +53,10 @@ fn main() {
     {
         let mut driver_manager = DRIVER_MANAGER.lock();
         driver_manager.register_driver(Box::new(FATDriver) as Box<dyn FileSystemDriver>);
+        driver_manager.register_driver(Box::new(Iso9660Driver) as Box<dyn FileSystemDriver>);
+        driver_manager.register_disk_driver(AhciInterfaceProxy::raw_new_ahci_().expect("Cannot create AHCI interface"));
+        driver_manager.register_disk_driver(AhciInterfaceProxy::raw_new_vblk_().expect("Cannot create virtio-blk interface"));
+        driver_manager.register_disk_driver(AhciInterfaceProxy::raw_new_nvme_().expect("Cannot create NVMe interface"));
         driver_manager.init_drives().unwrap();
     }
 
@@ -80,6 +86,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
@@ -92,5 +99,8 @@ capabilities!(CAPABILITIES = Capabilities {
         sunrise_libuser::syscalls::nr::AcceptSession,
         sunrise_libuser::syscalls::nr::CreateSession,
         sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::CreateSharedMemory,
+        sunrise_libuser::syscalls::nr::MapSharedMemory,
+        sunrise_libuser::syscalls::nr::UnmapSharedMemory,
     ]
 });
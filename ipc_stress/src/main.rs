@@ -0,0 +1,131 @@
+//! IPC stress tests
+//!
+//! Hammers the loader's `ldr:shel` service, to catch leaks and races in the
+//! IPC paths of the kernel and of the libuser servers. Run by the testrunner,
+//! see `/etc/testrunner.manifest`.
+//!
+//! Usage: `ipc_stress <requests|sessions|threads>`
+//!
+//! - `requests`: sends many requests over a single session, each reply
+//!   copying a handle to us, that we close right away.
+//! - `sessions`: opens many sessions one after the other, sending a request
+//!   on each before closing it.
+//! - `threads`: sends requests from several threads at the same time, each
+//!   with its own session.
+//!
+//! Prints `ok` and exits with 0 if every request succeeded.
+
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+use std::os::sunrise::prelude::*;
+use std::{env, process, thread};
+
+use sunrise_libuser::error::Error;
+use sunrise_libuser::ldr::ILoaderInterfaceProxy;
+
+/// Usage string.
+static USAGE: &str = "usage: ipc_stress <requests|sessions|threads>";
+
+/// Number of requests sent by the `requests` test.
+const REQUEST_COUNT: usize = 10_000;
+
+/// Number of sessions opened by the `sessions` test, one after the other.
+const SESSION_COUNT: usize = 2_000;
+
+/// Number of threads of the `threads` test.
+const THREAD_COUNT: usize = 8;
+
+/// Number of requests sent by each thread of the `threads` test.
+const REQUESTS_PER_THREAD: usize = 1_000;
+
+/// Sends `count` requests to the loader over `loader`. Every reply carries a
+/// copy of a handle, which is closed when dropped.
+fn send_requests(loader: &ILoaderInterfaceProxy, count: usize) -> Result<(), Error> {
+    for _ in 0..count {
+        let _event = loader.get_process_state_changed_event()?;
+    }
+    Ok(())
+}
+
+/// Sends many requests over a single session.
+fn requests() -> Result<(), Error> {
+    let loader = ILoaderInterfaceProxy::raw_new()?;
+    send_requests(&loader, REQUEST_COUNT)
+}
+
+/// Opens and closes many sessions to the loader.
+fn sessions() -> Result<(), Error> {
+    for _ in 0..SESSION_COUNT {
+        let loader = ILoaderInterfaceProxy::raw_new()?;
+        send_requests(&loader, 1)?;
+    }
+    Ok(())
+}
+
+/// Sends requests from several threads at once.
+fn threads() -> Result<(), Error> {
+    let threads: Vec<_> = (0..THREAD_COUNT).map(|_| thread::spawn(|| {
+        let loader = ILoaderInterfaceProxy::raw_new()?;
+        send_requests(&loader, REQUESTS_PER_THREAD)
+    })).collect();
+
+    let mut result = Ok(());
+    for thread in threads {
+        let thread_result = thread.join().expect("IPC stress thread panicked");
+        result = result.and(thread_result);
+    }
+    result
+}
+
+/// The entry point of the program.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let test = match args.get(1).map(|test| test.as_str()) {
+        Some("requests") if args.len() == 2 => requests,
+        Some("sessions") if args.len() == 2 => sessions,
+        Some("threads") if args.len() == 2 => threads,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = test() {
+        eprintln!("ipc_stress {}: {:?}", args[1], err);
+        process::exit(1);
+    }
+    println!("ok");
+}
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        nr::SleepThread,
+        nr::ExitProcess,
+        nr::ExitProcessWithCode,
+        nr::CreateThread,
+        nr::StartThread,
+        nr::MapStackMirror,
+        nr::UnmapStackMirror,
+        nr::ExitThread,
+        nr::CloseHandle,
+        nr::WaitSynchronization,
+        nr::OutputDebugString,
+        nr::SetThreadArea,
+
+        nr::ConnectToNamedPort,
+        nr::SetHeapSize,
+        nr::SendSyncRequestWithUserBuffer,
+        nr::QueryMemory,
+        nr::CreateSharedMemory,
+        nr::MapSharedMemory,
+        nr::UnmapSharedMemory,
+    ]
+});
@@ -537,3 +537,41 @@ pub unsafe fn init(hpet: &acpi::Hpet) -> bool {
     HPET_INSTANCE = Some(hpet_instance);
     true
 }
+
+/// Sets up a spare HPET timer to fire periodically, for the NMI watchdog.
+///
+/// Returns the I/O APIC input the timer is routed to. It is up to the caller
+/// to program the I/O APIC so that this input gets delivered as an NMI.
+///
+/// Returns None if the HPET isn't in use, or has no suitable spare timer.
+///
+/// # Safety
+///
+/// May only be called once, after [init].
+pub unsafe fn init_watchdog_timer(period_ns: u64) -> Option<u32> {
+    let hpet_instance = HPET_INSTANCE.as_ref()?;
+    let watchdog_timer = hpet_instance.get_timer(1)?;
+
+    if !watchdog_timer.support_periodic_interrupt() {
+        return None;
+    }
+
+    // IRQ 16 is the kernel timer, and no IRQ above it has a handler, so any
+    // route in 17..24 is ours to take.
+    let route = (17..24).rev().find(|route| watchdog_timer.support_interrupt_routing(*route))?;
+
+    let period_fs = period_ns * 1_000_000;
+    let period_tick = period_fs / u64::from(hpet_instance.get_period());
+
+    // NMIs are edge triggered.
+    watchdog_timer.set_edge_trigger();
+    watchdog_timer.set_periodic_mode();
+    watchdog_timer.set_interrupt_route(route);
+    // The main counter is already running: first set the next deadline, and
+    // then the period.
+    watchdog_timer.set_accumulator_value(hpet_instance.get_main_counter_value() + period_tick);
+    watchdog_timer.set_comparator_value(period_tick);
+    watchdog_timer.enable_interrupt();
+
+    Some(route)
+}
@@ -0,0 +1,517 @@
+//! Intel VT-d DMA remapping driver
+//!
+//! Without an IOMMU, a device DMAs to physical addresses, so a driver that can
+//! program it can make it overwrite anything, including the kernel. The DMA
+//! remapping hardware translates the addresses used by the devices through page
+//! tables we control, blocking any access that isn't mapped.
+//!
+//! Each remapping unit has a root table with an entry per PCI bus, pointing to
+//! a context table with an entry per device. A present context entry points to
+//! the second-level page tables of a [DeviceAddressSpace], and tags the
+//! translations with its domain id, used to invalidate the caches of the unit.
+//!
+//! Translation is enabled as soon as the kernel boots. From then on, devices
+//! can only DMA once a driver attached them to a [DeviceAddressSpace], and only
+//! to the memory it mapped there.
+//!
+//! The remapping units are found in the DMAR, see [crate::i386::acpi::dmar].
+//! QEMU emulates one with `-machine q35 -device intel-iommu`.
+//!
+//! The documentation is the [Intel Virtualization Technology for Directed I/O
+//! specification].
+//!
+//! [DeviceAddressSpace]: crate::paging::device_address_space::DeviceAddressSpace
+//! [Intel Virtualization Technology for Directed I/O specification]: https://software.intel.com/content/dam/develop/external/us/en/documents/vt-directed-io-spec.pdf
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+use core::sync::atomic::spin_loop_hint;
+use failure::Backtrace;
+
+use crate::error::KernelError;
+use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTrait};
+use crate::i386::acpi::dmar::{self, RemappingUnit};
+use crate::mem::{VirtualAddress, PhysicalAddress};
+use crate::paging::{PAGE_SIZE, MappingAccessRights};
+use crate::paging::kernel_memory::get_kernel_memory;
+use crate::sync::{Once, SpinLock};
+
+/// Version register.
+const VER_REG: usize = 0x00;
+/// Capability register.
+const CAP_REG: usize = 0x08;
+/// Extended capability register.
+const ECAP_REG: usize = 0x10;
+/// Global command register.
+const GCMD_REG: usize = 0x18;
+/// Global status register.
+const GSTS_REG: usize = 0x1C;
+/// Root table address register.
+const RTADDR_REG: usize = 0x20;
+/// Context command register.
+const CCMD_REG: usize = 0x28;
+
+/// GCMD/GSTS: translation enable.
+const GLOBAL_TE: u32 = 1 << 31;
+/// GCMD/GSTS: set root table pointer.
+const GLOBAL_SRTP: u32 = 1 << 30;
+/// GCMD/GSTS: write buffer flush.
+const GLOBAL_WBF: u32 = 1 << 27;
+/// Bits of GSTS that must be preserved when writing GCMD, see section 10.4.4.
+const GLOBAL_PRESERVED: u32 = 0x96FF_FFFF;
+
+/// CAP: required write buffer flushing.
+const CAP_RWBF: u64 = 1 << 4;
+/// CAP: offset of the SAGAW field, telling which page table depths are supported.
+const CAP_SAGAW_SHIFT: u64 = 8;
+/// SAGAW: 39-bit address width, 3-level page tables.
+const SAGAW_39_BIT: u64 = 1 << 1;
+/// ECAP: page walks snoop the processor caches.
+const ECAP_COHERENT: u64 = 1 << 0;
+
+/// CCMD: invalidate context cache. Cleared by the hardware when done.
+const CCMD_ICC: u64 = 1 << 63;
+/// CCMD: global invalidation request.
+const CCMD_GLOBAL: u64 = 1 << 61;
+/// IOTLB register: invalidate IOTLB. Cleared by the hardware when done.
+const IOTLB_IVT: u64 = 1 << 63;
+/// IOTLB register: global invalidation request.
+const IOTLB_GLOBAL: u64 = 1 << 60;
+/// IOTLB register: domain-selective invalidation request.
+const IOTLB_DOMAIN: u64 = 2 << 60;
+/// IOTLB register: offset of the domain id.
+const IOTLB_DID_SHIFT: u64 = 32;
+
+/// Root and context entries: present.
+const ENTRY_PRESENT: u64 = 1 << 0;
+/// Context entries: address width field value for 3-level page tables.
+const CONTEXT_AW_39_BIT: u64 = 1;
+/// Context entries: offset of the domain id in the high qword.
+const CONTEXT_DID_SHIFT: u64 = 8;
+
+/// Second-level page table entries: readable.
+pub const SL_READ: u64 = 1 << 0;
+/// Second-level page table entries: writable.
+pub const SL_WRITE: u64 = 1 << 1;
+/// Depth of the second-level page tables we use.
+pub const SL_LEVELS: usize = 3;
+/// Size of the device address space the second-level page tables translate.
+pub const SL_ADDRESS_WIDTH: u32 = 39;
+
+/// Maximum number of iterations to wait for the hardware to complete a command.
+const COMMAND_TIMEOUT: usize = 1_000_000;
+
+/// A zeroed page the remapping hardware reads one of its tables from, mapped in
+/// KernelLand. The frame is freed when it is dropped.
+#[derive(Debug)]
+pub struct TablePage {
+    /// Where the page is mapped in KernelLand.
+    address: VirtualAddress,
+    /// The physical address of the page, which is what the hardware sees.
+    frame: PhysicalAddress,
+}
+
+impl TablePage {
+    /// Allocates a zeroed table.
+    ///
+    /// # Errors
+    ///
+    /// * `PhysicalMemoryExhaustion`: no frame could be allocated.
+    pub fn new() -> Result<TablePage, KernelError> {
+        let frame = FrameAllocator::allocate_frame()?;
+        let frame_address = frame.address();
+        let address = get_kernel_memory().map_phys_region(frame, MappingAccessRights::k_rw());
+        unsafe {
+            // safe: the frame was just allocated and mapped.
+            core::ptr::write_bytes(address.addr() as *mut u8, 0, PAGE_SIZE);
+        }
+        Ok(TablePage { address, frame: frame_address })
+    }
+
+    /// The physical address of the table.
+    pub fn frame(&self) -> PhysicalAddress {
+        self.frame
+    }
+
+    /// Reads the qword at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of the page.
+    pub fn read(&self, index: usize) -> u64 {
+        assert!(index < PAGE_SIZE / 8, "TablePage index out of bounds");
+        unsafe {
+            // safe: the page is ours, and index is in bounds.
+            (self.address.addr() as *const u64).add(index).read_volatile()
+        }
+    }
+
+    /// Writes the qword at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of the page.
+    pub fn write(&self, index: usize, value: u64) {
+        assert!(index < PAGE_SIZE / 8, "TablePage index out of bounds");
+        unsafe {
+            // safe: the page is ours, and index is in bounds.
+            (self.address.addr() as *mut u64).add(index).write_volatile(value)
+        }
+    }
+}
+
+impl Drop for TablePage {
+    fn drop(&mut self) {
+        // frees the frame.
+        get_kernel_memory().unmap(self.address, PAGE_SIZE);
+    }
+}
+
+/// A DMA remapping hardware unit.
+#[derive(Debug)]
+struct RemappingHardware {
+    /// Where the registers are mapped in KernelLand.
+    registers: VirtualAddress,
+    /// Offset of the IOTLB invalidate register.
+    iotlb_register: usize,
+    /// Whether the write buffer must be flushed after modifying the tables.
+    needs_write_buffer_flush: bool,
+    /// Whether the page walks snoop the processor caches. If not, the caches
+    /// must be written back after modifying the tables.
+    coherent: bool,
+    /// Number of domain ids supported.
+    domain_count: usize,
+    /// The devices and scope of this unit, from the DMAR.
+    description: RemappingUnit,
+    /// The root table, one entry per bus.
+    root_table: TablePage,
+    /// The context tables, by bus.
+    context_tables: BTreeMap<u8, TablePage>,
+    /// The domain of the attached devices, by requester id.
+    attached: BTreeMap<u16, u16>,
+}
+
+impl RemappingHardware {
+    /// Reads a 32-bit register.
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe {
+            // safe: offset is one of the registers we mapped.
+            ((self.registers.addr() + offset) as *const u32).read_volatile()
+        }
+    }
+
+    /// Writes a 32-bit register.
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe {
+            // safe: offset is one of the registers we mapped.
+            ((self.registers.addr() + offset) as *mut u32).write_volatile(value)
+        }
+    }
+
+    /// Reads a 64-bit register, as two 32-bit reads.
+    fn read64(&self, offset: usize) -> u64 {
+        u64::from(self.read32(offset)) | u64::from(self.read32(offset + 4)) << 32
+    }
+
+    /// Writes a 64-bit register, as two 32-bit writes. The high dword is
+    /// written last, as it holds the bits triggering the commands.
+    fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+
+    /// Polls `done` until it returns true, or we give up. Returns whether it
+    /// succeeded.
+    fn wait_for<F: Fn(&Self) -> bool>(&self, done: F) -> bool {
+        for _ in 0..COMMAND_TIMEOUT {
+            if done(self) {
+                return true;
+            }
+            spin_loop_hint();
+        }
+        false
+    }
+
+    /// Issues a one-shot command through GCMD, and waits for GSTS to report it
+    /// as `expected_status`.
+    fn global_command(&self, command: u32, expected_status: bool) -> bool {
+        let status = self.read32(GSTS_REG) & GLOBAL_PRESERVED;
+        self.write32(GCMD_REG, status | command);
+        self.wait_for(|unit| (unit.read32(GSTS_REG) & command != 0) == expected_status)
+    }
+
+    /// Makes the tables we modified visible to the unit.
+    fn flush_tables(&self) {
+        if !self.coherent {
+            unsafe {
+                // safe: only writes the caches back.
+                llvm_asm!("wbinvd" :::: "volatile");
+            }
+        }
+        if self.needs_write_buffer_flush && !self.global_command(GLOBAL_WBF, false) {
+            error!("IOMMU at {}: write buffer flush timed out", self.registers);
+        }
+    }
+
+    /// Invalidates the cached context entries.
+    fn invalidate_context_cache(&self) {
+        self.write64(CCMD_REG, CCMD_ICC | CCMD_GLOBAL);
+        if !self.wait_for(|unit| unit.read64(CCMD_REG) & CCMD_ICC == 0) {
+            error!("IOMMU at {}: context cache invalidation timed out", self.registers);
+        }
+    }
+
+    /// Invalidates the cached translations of `domain`, or of all domains.
+    fn invalidate_iotlb(&self, domain: Option<u16>) {
+        let request = match domain {
+            Some(domain) => IOTLB_DOMAIN | u64::from(domain) << IOTLB_DID_SHIFT,
+            None => IOTLB_GLOBAL,
+        };
+        self.write64(self.iotlb_register, IOTLB_IVT | request);
+        if !self.wait_for(|unit| unit.read64(unit.iotlb_register) & IOTLB_IVT == 0) {
+            error!("IOMMU at {}: IOTLB invalidation timed out", self.registers);
+        }
+    }
+
+    /// Whether this unit translates the DMAs of `device`, knowing whether
+    /// another unit explicitly lists it.
+    fn handles(&self, device: u16, listed_elsewhere: bool) -> bool {
+        self.description.devices.contains(&device)
+            || (self.description.include_pci_all && !listed_elsewhere)
+    }
+
+    /// Initializes the unit described by `description`, and enables translation.
+    ///
+    /// Returns None if the unit isn't usable.
+    fn new(description: &RemappingUnit) -> Option<RemappingHardware> {
+        if description.segment != 0 {
+            info!("IOMMU at {:#x}: ignoring PCI segment {}", description.register_base, description.segment);
+            return None;
+        }
+        if description.register_base % PAGE_SIZE as u64 != 0 || description.register_base > u64::from(u32::max_value()) {
+            error!("IOMMU at {:#x}: unsupported register address", description.register_base);
+            return None;
+        }
+
+        let register_base = PhysicalAddress(description.register_base as usize);
+        let mmio = unsafe {
+            // safe: the DMAR says the registers are there, and nobody else maps them.
+            PhysicalMemRegion::on_fixed_mmio(register_base, PAGE_SIZE)
+        }.ok()?;
+        let mut registers = get_kernel_memory().map_phys_region(mmio, MappingAccessRights::k_rw());
+
+        let read64 = |registers: VirtualAddress, offset: usize| unsafe {
+            // safe: the first page holds all the registers we read here.
+            u64::from(((registers.addr() + offset) as *const u32).read_volatile())
+                | u64::from(((registers.addr() + offset + 4) as *const u32).read_volatile()) << 32
+        };
+        let cap = read64(registers, CAP_REG);
+        let ecap = read64(registers, ECAP_REG);
+        let iotlb_register = ((ecap >> 8) & 0x3FF) as usize * 16 + 8;
+
+        // The IOTLB registers may be past the first page.
+        if iotlb_register + 8 > PAGE_SIZE {
+            let length = crate::utils::align_up(iotlb_register + 8, PAGE_SIZE);
+            get_kernel_memory().unmap_no_dealloc(registers, PAGE_SIZE);
+            let mmio = unsafe {
+                // safe: same as above.
+                PhysicalMemRegion::on_fixed_mmio(register_base, length)
+            }.ok()?;
+            registers = get_kernel_memory().map_phys_region(mmio, MappingAccessRights::k_rw());
+        }
+
+        if (cap >> CAP_SAGAW_SHIFT) & SAGAW_39_BIT == 0 {
+            error!("IOMMU at {}: 3-level page tables are not supported", register_base);
+            return None;
+        }
+
+        let unit = RemappingHardware {
+            registers,
+            iotlb_register,
+            needs_write_buffer_flush: cap & CAP_RWBF != 0,
+            coherent: ecap & ECAP_COHERENT != 0,
+            domain_count: 1 << (4 + 2 * (cap & 0b111)),
+            description: description.clone(),
+            root_table: TablePage::new().ok()?,
+            context_tables: BTreeMap::new(),
+            attached: BTreeMap::new(),
+        };
+
+        unit.flush_tables();
+        unit.write64(RTADDR_REG, unit.root_table.frame().addr() as u64);
+        if !unit.global_command(GLOBAL_SRTP, true) {
+            error!("IOMMU at {}: setting the root table timed out", register_base);
+            return None;
+        }
+        unit.invalidate_context_cache();
+        unit.invalidate_iotlb(None);
+        if !unit.global_command(GLOBAL_TE, true) {
+            error!("IOMMU at {}: enabling translation timed out", register_base);
+            return None;
+        }
+
+        info!("IOMMU at {} (version {:#x}) enabled, {} domains",
+              register_base, unit.read32(VER_REG), unit.domain_count);
+        Some(unit)
+    }
+
+    /// Points the context entry of `device` to the page tables at `table`,
+    /// tagged with `domain`.
+    fn attach(&mut self, device: u16, domain: u16, table: PhysicalAddress) -> Result<(), KernelError> {
+        if self.attached.contains_key(&device) {
+            return Err(KernelError::InvalidState { backtrace: Backtrace::new() });
+        }
+        let bus = (device >> 8) as u8;
+        if !self.context_tables.contains_key(&bus) {
+            let context_table = TablePage::new()?;
+            self.root_table.write(usize::from(bus) * 2, context_table.frame().addr() as u64 | ENTRY_PRESENT);
+            self.context_tables.insert(bus, context_table);
+        }
+        let context_table = &self.context_tables[&bus];
+        let index = usize::from(device & 0xFF) * 2;
+        // The present bit goes last.
+        context_table.write(index + 1, CONTEXT_AW_39_BIT | u64::from(domain) << CONTEXT_DID_SHIFT);
+        context_table.write(index, table.addr() as u64 | ENTRY_PRESENT);
+        self.attached.insert(device, domain);
+
+        self.flush_tables();
+        self.invalidate_context_cache();
+        self.invalidate_iotlb(None);
+        Ok(())
+    }
+
+    /// Clears the context entry of `device`, blocking its DMAs.
+    fn detach(&mut self, device: u16, domain: u16) -> Result<(), KernelError> {
+        if self.attached.get(&device) != Some(&domain) {
+            return Err(KernelError::InvalidState { backtrace: Backtrace::new() });
+        }
+        let context_table = &self.context_tables[&((device >> 8) as u8)];
+        let index = usize::from(device & 0xFF) * 2;
+        context_table.write(index, 0);
+        context_table.write(index + 1, 0);
+        self.attached.remove(&device);
+
+        self.flush_tables();
+        self.invalidate_context_cache();
+        self.invalidate_iotlb(None);
+        Ok(())
+    }
+}
+
+/// The state of the DMA remapping hardware.
+#[derive(Debug)]
+struct Iommu {
+    /// The remapping units we enabled.
+    units: Vec<RemappingHardware>,
+    /// The domain ids in use. 0 is never used, as it is reserved on units
+    /// caching non-present entries.
+    domains: BTreeSet<u16>,
+    /// Number of domain ids supported by all the units.
+    domain_count: usize,
+}
+
+impl Iommu {
+    /// Finds the unit translating the DMAs of `device`.
+    fn unit_for(&mut self, device: u16) -> Option<&mut RemappingHardware> {
+        let listed = self.units.iter().any(|unit| unit.description.devices.contains(&device));
+        self.units.iter_mut().find(|unit| unit.handles(device, listed))
+    }
+}
+
+/// The DMA remapping hardware, set by [init] if there is any.
+static IOMMU: Once<SpinLock<Iommu>> = Once::new();
+
+/// Enables DMA remapping on the units described by the DMAR.
+///
+/// Must be called after the ACPI init.
+pub fn init() {
+    let units = dmar::remapping_units().iter()
+        .filter_map(RemappingHardware::new)
+        .collect::<Vec<_>>();
+    if units.is_empty() {
+        info!("No IOMMU, devices DMA to physical addresses");
+        return;
+    }
+    let domain_count = units.iter().map(|unit| unit.domain_count).min().unwrap_or(0).min(1 << 16);
+    IOMMU.call_once(|| SpinLock::new(Iommu {
+        units,
+        domains: BTreeSet::new(),
+        domain_count,
+    }));
+}
+
+/// Gets the IOMMU, or a `NotImplemented` error if the system has none.
+fn get_iommu() -> Result<&'static SpinLock<Iommu>, KernelError> {
+    IOMMU.r#try().ok_or(KernelError::NotImplemented {
+        msg: "device address spaces without an IOMMU", backtrace: Backtrace::new()
+    })
+}
+
+/// Reserves a domain id, to tag the translations of a device address space.
+///
+/// # Errors
+///
+/// * `NotImplemented`: the system has no IOMMU.
+/// * `ExceedingMaximum`: all the domain ids are in use.
+pub fn allocate_domain() -> Result<u16, KernelError> {
+    let mut iommu = get_iommu()?.lock();
+    let domain = (1..iommu.domain_count)
+        .map(|domain| domain as u16)
+        .find(|domain| !iommu.domains.contains(domain))
+        .ok_or(KernelError::ExceedingMaximum {
+            value: iommu.domain_count as u64, maximum: iommu.domain_count as u64, backtrace: Backtrace::new()
+        })?;
+    iommu.domains.insert(domain);
+    Ok(domain)
+}
+
+/// Releases a domain id. No device may still be attached to it.
+pub fn free_domain(domain: u16) {
+    if let Some(iommu) = IOMMU.r#try() {
+        iommu.lock().domains.remove(&domain);
+    }
+}
+
+/// Makes the DMAs of the PCI device with the given requester id go through
+/// the second-level page tables at `table`, tagged with `domain`.
+///
+/// # Errors
+///
+/// * `NotImplemented`: the system has no IOMMU.
+/// * `InvalidAddress`: no unit translates the DMAs of this device.
+/// * `InvalidState`: the device is already attached.
+/// * `PhysicalMemoryExhaustion`: a context table could not be allocated.
+pub fn attach(device: u16, domain: u16, table: PhysicalAddress) -> Result<(), KernelError> {
+    let mut iommu = get_iommu()?.lock();
+    let unit = iommu.unit_for(device)
+        .ok_or(KernelError::InvalidAddress { address: usize::from(device), backtrace: Backtrace::new() })?;
+    unit.attach(device, domain, table)
+}
+
+/// Blocks the DMAs of the PCI device with the given requester id, which must
+/// have been attached to `domain`.
+///
+/// # Errors
+///
+/// * `NotImplemented`: the system has no IOMMU.
+/// * `InvalidAddress`: no unit translates the DMAs of this device.
+/// * `InvalidState`: the device is not attached to `domain`.
+pub fn detach(device: u16, domain: u16) -> Result<(), KernelError> {
+    let mut iommu = get_iommu()?.lock();
+    let unit = iommu.unit_for(device)
+        .ok_or(KernelError::InvalidAddress { address: usize::from(device), backtrace: Backtrace::new() })?;
+    unit.detach(device, domain)
+}
+
+/// Makes the modifications of the page tables of `domain` visible to the
+/// devices attached to it.
+///
+/// Must be called after unmapping anything, before the memory can be reused.
+pub fn flush_domain(domain: u16) {
+    if let Some(iommu) = IOMMU.r#try() {
+        for unit in iommu.lock().units.iter() {
+            unit.flush_tables();
+            unit.invalidate_iotlb(Some(domain));
+        }
+    }
+}
@@ -7,6 +7,8 @@ pub mod rs232;
 
 pub mod lapic;
 pub mod ioapic;
+pub mod iommu;
+pub mod pci;
 
 use crate::i386::acpi;
 
@@ -0,0 +1,47 @@
+//! PCI configuration space
+//!
+//! PCI devices are driven by userspace drivers. The kernel only reads the
+//! class of a device, to check that a driver is allowed to attach it to a
+//! [DeviceAddressSpace].
+//!
+//! Drivers talk to the configuration space through the same two ports, and
+//! may have been preempted between writing CONFIG_ADDRESS and reading
+//! CONFIG_DATA. We restore CONFIG_ADDRESS after our read, with interrupts
+//! disabled, so they don't notice.
+//!
+//! [DeviceAddressSpace]: crate::paging::device_address_space::DeviceAddressSpace
+
+use crate::sync::SpinLockIRQ;
+use crate::io::Io;
+use crate::i386::pio::Pio;
+
+/// The CONFIG_ADDRESS I/O location.
+const CONFIG_ADDRESS: u16 = 0xCF8;
+/// The CONFIG_DATA I/O location.
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// The CONFIG_ADDRESS and CONFIG_DATA ports.
+static PCI_CONFIG_PORTS: SpinLockIRQ<(Pio<u32>, Pio<u32>)> =
+    SpinLockIRQ::new((Pio::new(CONFIG_ADDRESS), Pio::new(CONFIG_DATA)));
+
+/// Reads the 32-bit `register` of the function identified by `requester_id`:
+/// `bus << 8 | device << 3 | function`.
+fn read_config_register(requester_id: u16, register: u8) -> u32 {
+    let mut ports = PCI_CONFIG_PORTS.lock();
+    let (address, data) = &mut *ports;
+    let saved_address = address.read();
+    address.write(0x8000_0000 | u32::from(requester_id) << 8 | u32::from(register) << 2);
+    let value = data.read();
+    address.write(saved_address);
+    value
+}
+
+/// Gets the class and subclass of the function identified by `requester_id`,
+/// or None if no function answers at this address.
+pub fn class(requester_id: u16) -> Option<(u8, u8)> {
+    if read_config_register(requester_id, 0) as u16 == 0xFFFF {
+        return None;
+    }
+    let class = read_config_register(requester_id, 2);
+    Some(((class >> 24) as u8, (class >> 16) as u8))
+}
@@ -10,20 +10,20 @@ use crate::i386::pio::Pio;
 pub struct ComPort(u16);
 
 /// COM1: I/O port 0x3F8, IRQ 4
-#[cfg(any(all(target_arch="x86", not(test)), doc))]
+#[cfg(any(all(target_arch="x86", any(not(test), target_os = "none")), doc))]
 const COM1: ComPort = ComPort(0x3F8);
 /// COM2: I/O port 0x2F8, IRQ 3
-#[cfg(any(all(target_arch="x86", not(test)), doc))]
+#[cfg(any(all(target_arch="x86", any(not(test), target_os = "none")), doc))]
 const COM2: ComPort = ComPort(0x2F8);
 /// COM3: I/O port 0x3E8, IRQ 4
-#[cfg(any(all(target_arch="x86", not(test)), doc))]
+#[cfg(any(all(target_arch="x86", any(not(test), target_os = "none")), doc))]
 const COM3: ComPort = ComPort(0x3E8);
 /// COM4: I/O port 0x2E8, IRQ 3
-#[cfg(any(all(target_arch="x86", not(test)), doc))]
+#[cfg(any(all(target_arch="x86", any(not(test), target_os = "none")), doc))]
 const COM4: ComPort = ComPort(0x2E8);
 
 // TODO: device drivers should be compiled only for i386
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 const COM1: ComPort = ComPort(0x7777);
 
 /// The possible colors for serial
@@ -110,7 +110,7 @@ struct SerialInternal<T> {
 
 impl SerialInternal<Pio<u8>> {
     /// Creates a COM port from it's base IO address.
-    #[cfg(any(all(target_arch="x86", not(test)), doc))]
+    #[cfg(any(all(target_arch="x86", any(not(test), target_os = "none")), doc))]
     #[allow(unused)]
     pub fn new(com_port: ComPort) -> SerialInternal<Pio<u8>> {
         let mut data_port       = Pio::<u8>::new(com_port.0 + 0);
@@ -134,7 +134,7 @@ impl SerialInternal<Pio<u8>> {
         SerialInternal { data_port, status_port }
     }
 
-    #[cfg(test)]
+    #[cfg(all(test, not(target_os = "none")))]
     pub fn new(_com_port: ComPort) -> SerialInternal<Pio<u8>> { panic!("mock implementation !") }
 
     /// Outputs a string to this COM.
@@ -174,14 +174,14 @@ impl SerialLogger {
 
 impl Write for SerialLogger {
     /// Writes a string to COM1.
-    #[cfg(not(test))]
+    #[cfg(any(not(test), target_os = "none"))]
     fn write_str(&mut self, s: &str) -> Result<(), ::core::fmt::Error> {
         let mut internal = G_SERIAL.call_once(|| SpinLockIRQ::new(SerialInternal::<Pio<u8>>::new(COM1))).lock();
         internal.send_string(s);
         Ok(())
     }
 
-    #[cfg(test)]
+    #[cfg(all(test, not(target_os = "none")))]
     /// When printing in tests, write to stdout.
     fn write_str(&mut self, s: &str) -> Result<(), ::core::fmt::Error> {
         use std::println;
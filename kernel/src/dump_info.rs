@@ -0,0 +1,146 @@
+//! Snapshots of the kernel objects, for chasing leaks
+//!
+//! The [dump_info] syscall formats the state of the kernel as text, and gives
+//! it to userspace and to the kernel log. It contains:
+//!
+//! - Every living process, with the contents of its handle table and its
+//!   mappings.
+//! - The named ports, with their pending connections.
+//! - The physical memory usage.
+//!
+//! Kernel objects reachable from several handles are given an id, so the two
+//! sides of a port or session can be matched across processes. Sessions also
+//! show their pending requests, and the one currently being serviced, with the
+//! thread that sent it.
+//!
+//! The snapshot is not atomic: every object is locked in turn while it is
+//! formatted, so the state may change between two processes of the dump.
+//!
+//! [dump_info]: crate::syscalls::dump_info
+
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use crate::process::{Handle, ProcessStruct, PROCESS_LIST};
+use crate::paging::{PAGE_SIZE, MappingAccessRights};
+use crate::paging::mapping::Mapping;
+use crate::ipc::{self, PortInfo, SessionInfo};
+use crate::frame_allocator;
+
+/// Formats a snapshot of the kernel objects.
+pub fn dump() -> String {
+    let mut out = String::new();
+    // Writing to a String never fails.
+    let _ = write_dump(&mut out);
+    out
+}
+
+/// Writes a snapshot of the kernel objects to `f`.
+fn write_dump(f: &mut impl Write) -> fmt::Result {
+    // Copy the list before upgrading its elements, so dropping the last
+    // reference to a process here does not deadlock on it.
+    let processes = PROCESS_LIST.lock().clone();
+    for process in processes.iter().filter_map(Weak::upgrade) {
+        write_process(f, &process)?;
+    }
+
+    writeln!(f, "Named ports:")?;
+    for (name, port) in ipc::named_ports() {
+        write!(f, "  {:<12} ", name)?;
+        write_port(f, &port)?;
+        writeln!(f)?;
+    }
+
+    let usage = frame_allocator::usage();
+    let used = usage.usable - usage.free;
+    writeln!(f, "Physical memory: {} of {} frames used ({} KiB of {} KiB)",
+             used, usage.usable, used * PAGE_SIZE / 1024, usage.usable * PAGE_SIZE / 1024)
+}
+
+/// Writes the handle table and the mappings of a process.
+fn write_process(f: &mut impl Write, process: &ProcessStruct) -> fmt::Result {
+    writeln!(f, "Process {} {} ({:?}, {} threads):", process.pid, process.name,
+             process.state(), process.threads.lock().len())?;
+
+    // Don't hold the handle table while locking the objects it points to.
+    let handles: Vec<(u32, Arc<Handle>)> = process.phandles.lock().iter()
+        .map(|(handlenum, handle)| (handlenum, handle.clone()))
+        .collect();
+    let mut counts: Vec<(&'static str, usize)> = Vec::new();
+    for (_, handle) in &handles {
+        match counts.iter_mut().find(|(name, _)| *name == handle.type_name()) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((handle.type_name(), 1)),
+        }
+    }
+    counts.sort();
+    write!(f, "  Handles: {}", handles.len())?;
+    for (name, count) in counts {
+        write!(f, ", {} {}", count, name)?;
+    }
+    writeln!(f)?;
+    for (handlenum, handle) in &handles {
+        write!(f, "    {:#06x} {:<18} ", handlenum, handle.type_name())?;
+        write_handle(f, handle)?;
+        writeln!(f)?;
+    }
+
+    let pmemory = process.pmemory.lock();
+    writeln!(f, "  Mappings: {} KiB used", pmemory.used_memory_size() / 1024)?;
+    for mapping in pmemory.mappings() {
+        write_mapping(f, mapping)?;
+    }
+    Ok(())
+}
+
+/// Writes what is known about the object a handle points to.
+fn write_handle(f: &mut impl Write, handle: &Handle) -> fmt::Result {
+    match handle {
+        Handle::ServerPort(port) => write_port(f, &port.info()),
+        Handle::ClientPort(port) => write_port(f, &port.info()),
+        Handle::ServerSession(session) => write_session(f, &session.info()),
+        Handle::ClientSession(session) => write_session(f, &session.info()),
+        Handle::Thread(thread) => match thread.upgrade() {
+            Some(thread) => write!(f, "tid {} ({:?})", thread.tid, thread.state.load(Ordering::SeqCst)),
+            None => write!(f, "dead"),
+        },
+        Handle::Process(process) => write!(f, "pid {} {}", process.pid, process.name),
+        Handle::SharedMemory(mem) => write!(f, "{} KiB, {} mappings", mem.size() / 1024, mem.mapping_count()),
+        Handle::InterruptEvent(_) | Handle::ReadableEvent(_) | Handle::WritableEvent(_) |
+        Handle::DeviceAddressSpace(_) => Ok(()),
+    }
+}
+
+/// Writes the state of a port.
+fn write_port(f: &mut impl Write, port: &PortInfo) -> fmt::Result {
+    write!(f, "port {:#010x}, {} servers, {} pending connections",
+           port.id, port.server_count, port.pending_connections)
+}
+
+/// Writes the state of a session, and its requests.
+fn write_session(f: &mut impl Write, session: &SessionInfo) -> fmt::Result {
+    write!(f, "session {:#010x}, {} servers, {} pending requests",
+           session.id, session.server_count, session.incoming_requests.len())?;
+    if let Some(request) = &session.active_request {
+        write!(f, ", active request from pid {} tid {} ({} bytes, {} buffers)",
+               request.sender_pid, request.sender_tid, request.bufsize, request.buffers)?;
+    }
+    for request in &session.incoming_requests {
+        write!(f, "\n        pending request from pid {} tid {} ({} bytes, {} buffers)",
+               request.sender_pid, request.sender_tid, request.bufsize, request.buffers)?;
+    }
+    Ok(())
+}
+
+/// Writes the range, permissions, type and commit state of a mapping.
+fn write_mapping(f: &mut impl Write, mapping: &Mapping) -> fmt::Result {
+    let flags = mapping.flags();
+    writeln!(f, "    {:#010x}-{:#010x} {}{}{} {:?}, {} KiB committed",
+             mapping.address().addr(), mapping.address().addr() + mapping.length(),
+             if flags.contains(MappingAccessRights::READABLE) { 'r' } else { '-' },
+             if flags.contains(MappingAccessRights::WRITABLE) { 'w' } else { '-' },
+             if flags.contains(MappingAccessRights::EXECUTABLE) { 'x' } else { '-' },
+             mapping.state().ty(), mapping.committed_length() / 1024)
+}
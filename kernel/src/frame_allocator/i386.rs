@@ -12,7 +12,7 @@
 //!
 //! We do not distinguish between reserved and occupied frames.
 
-use super::{PhysicalMemRegion, FrameAllocatorTrait, FrameAllocatorTraitPrivate};
+use super::{PhysicalMemRegion, FrameAllocatorTrait, FrameAllocatorTraitPrivate, FrameUsage};
 
 use crate::paging::PAGE_SIZE;
 use multiboot2::BootInformation;
@@ -53,11 +53,11 @@ const FRAME_BASE_MASK:   usize = !FRAME_OFFSET_MASK;
 const FRAME_BASE_LOG: usize = 12;
 
 /// The size of the frames_bitmap (~128ko)
-#[cfg(not(any(test, doc)))]
+#[cfg(not(any(all(test, not(target_os = "none")), doc)))]
 const FRAMES_BITMAP_SIZE: usize = usize::max_value() / PAGE_SIZE / 8 + 1;
 
 /// For unit tests we use a much smaller array.
-#[cfg(any(test, doc))]
+#[cfg(any(all(test, not(target_os = "none")), doc))]
 const FRAMES_BITMAP_SIZE: usize = 32 / 8;
 
 /// Gets the frame number from a physical address
@@ -81,6 +81,10 @@ pub struct FrameAllocatori386 {
     /// and it can be put in the bss by the compiler
     memory_bitmap: [u8; FRAMES_BITMAP_SIZE],
 
+    /// The number of frames that were free once the allocator was initialized,
+    /// i.e. the frames that can ever be allocated.
+    usable_frames: usize,
+
     /// All operations have to check that the Allocator has been initialized
     initialized: bool
 }
@@ -92,7 +96,7 @@ const FRAME_OCCUPIED: bool = false;
 
 /// A physical memory manger to allocate and free memory frames
 // When running tests, each thread has its own view of the `FRAME_ALLOCATOR`.
-#[cfg_attr(test, thread_local)]
+#[cfg_attr(all(test, not(target_os = "none")), thread_local)]
 static FRAME_ALLOCATOR : SpinLock<FrameAllocatori386> = SpinLock::new(FrameAllocatori386::new());
 
 impl FrameAllocatori386 {
@@ -101,6 +105,7 @@ impl FrameAllocatori386 {
         FrameAllocatori386 {
             // 0 is allocated/reserved
             memory_bitmap: [0x00; FRAMES_BITMAP_SIZE],
+            usable_frames: 0,
             initialized: false
         }
     }
@@ -303,7 +308,7 @@ impl FrameAllocatorTrait for FrameAllocator {
 
 /// Initialize the [FrameAllocator] by parsing the multiboot information
 /// and marking some memory areas as unusable
-#[cfg(not(test))]
+#[cfg(any(not(test), target_os = "none"))]
 pub fn init(boot_info: &BootInformation) {
     let mut allocator = FRAME_ALLOCATOR.lock();
 
@@ -329,6 +334,8 @@ pub fn init(boot_info: &BootInformation) {
     // Reserve everything mapped in KernelLand
     drop(allocator); // prevent deadlock
     get_kernel_memory().reserve_kernel_land_frames();
+    // Also looks up the kernel page tables.
+    let report_region = crate::panic::report::find_region(boot_info);
     let mut allocator = FRAME_ALLOCATOR.lock(); // retake the mutex
 
     // Don't free the modules. We need to keep the kernel around so we get symbols in panics!
@@ -337,6 +344,13 @@ pub fn init(boot_info: &BootInformation) {
                                            module.start_address() as usize, module.end_address() as usize);
     }
 
+    // Keep the kernel panic report of the previous boot
+    if let Some(region) = report_region {
+        mark_area_reserved(&mut allocator.memory_bitmap,
+                                           region.addr(),
+                                           region.addr() + crate::panic::report::REPORT_REGION_SIZE);
+    }
+
     // Reserve the very first frame for null pointers when paging is off
     mark_area_reserved(&mut allocator.memory_bitmap,
                                        0x00000000,
@@ -376,10 +390,30 @@ pub fn init(boot_info: &BootInformation) {
             _ => ()
         }
     }
+    allocator.usable_frames = count_free_frames(&allocator.memory_bitmap);
     allocator.initialized = true
 }
 
-#[cfg(test)]
+/// Counts the frames marked free in the bitmap.
+fn count_free_frames(bitmap: &[u8]) -> usize {
+    bitmap.iter().map(|byte| byte.count_ones() as usize).sum()
+}
+
+/// Gets the current usage of physical memory.
+///
+/// # Panic
+///
+/// * Panics if FRAME_ALLOCATOR was not initialized.
+pub fn usage() -> FrameUsage {
+    let allocator = FRAME_ALLOCATOR.lock();
+    assert!(allocator.initialized, "The frame allocator was not initialized");
+    FrameUsage {
+        free: count_free_frames(&allocator.memory_bitmap),
+        usable: allocator.usable_frames,
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
 pub use self::test::init;
 
 /// Marks a physical memory area as reserved and will never give it when requesting a frame.
@@ -432,7 +466,7 @@ pub fn mark_frame_bootstrap_allocated(addr: PhysicalAddress) {
     allocator.memory_bitmap.set_bit(bit, FRAME_OCCUPIED);
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod test {
     use super::*;
 
@@ -452,6 +486,7 @@ mod test {
         // reserve one frame, in the middle, just for fun
         mark_area_reserved(&mut allocator.memory_bitmap, PAGE_SIZE * 3, PAGE_SIZE * 3 + 1);
 
+        allocator.usable_frames = count_free_frames(&allocator.memory_bitmap);
         allocator.initialized = true;
 
         FrameAllocatorInitialized(())
@@ -505,6 +540,21 @@ mod test {
         assert_eq!(frames[1].size(), 3 * PAGE_SIZE);
     }
 
+    /// Usage counts the frames in use, and frees them back on drop.
+    #[test]
+    fn usage_counts_allocated_frames() {
+        let _f = crate::frame_allocator::init();
+        let before = usage();
+        assert_eq!(before.usable, ALL_MEMORY / PAGE_SIZE - 1);
+        assert_eq!(before.free, before.usable);
+
+        let region = FrameAllocator::allocate_region(2 * PAGE_SIZE).unwrap();
+        assert_eq!(usage().free, before.free - 2);
+
+        drop(region);
+        assert_eq!(usage().free, before.free);
+    }
+
     /// You can't give it a size of 0.
     #[test]
     fn zero() {
@@ -11,7 +11,17 @@ pub use self::physical_mem_region::{PhysicalMemRegion, PhysicalMemRegionIter};
 
 /// Architecture specific-behaviour
 mod i386;
-pub use self::i386::{FrameAllocator, init, mark_frame_bootstrap_allocated};
+pub use self::i386::{FrameAllocator, init, mark_frame_bootstrap_allocated, usage};
+
+/// A snapshot of the physical memory usage, in frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameUsage {
+    /// The number of frames currently free.
+    pub free: usize,
+    /// The number of frames that can ever be allocated, excluding the memory
+    /// holes and the frames reserved during init.
+    pub usable: usize,
+}
 
 /// An arch-specific FrameAllocator must expose the following functions
 pub trait FrameAllocatorTrait: FrameAllocatorTraitPrivate {
@@ -4,6 +4,7 @@
 
 #![allow(dead_code)]
 
+use alloc::vec::Vec;
 use acpi::Acpi;
 use acpi::AcpiHandler;
 use acpi::PhysicalMapping;
@@ -21,6 +22,9 @@ use crate::utils;
 
 use super::multiboot;
 
+pub mod power;
+pub mod dmar;
+
 /// Stores the ACPI data
 static ACPI_INFO: Once<Acpi> = Once::new();
 
@@ -72,6 +76,96 @@ impl AcpiHandler for MemoryHandler {
     }
 }
 
+/// Size of the header common to all ACPI tables.
+const SDT_HEADER_SIZE: usize = 36;
+
+/// Copies `length` bytes of physical memory.
+fn read_physical(address: usize, length: usize) -> Vec<u8> {
+    let mut handler = MemoryHandler;
+    let mapping = handler.map_physical_region::<u8>(address, length);
+    let data = unsafe {
+        // safe: just mapped, and ACPI tables are never modified.
+        core::slice::from_raw_parts(mapping.virtual_start.as_ptr(), length)
+    }.to_vec();
+    handler.unmap_physical_region(mapping);
+    data
+}
+
+/// Reads a little-endian integer of `size` bytes at `offset`, or 0 if the
+/// data is too short. Newer fields of the tables are absent from older revisions.
+fn read_le(data: &[u8], offset: usize, size: usize) -> u64 {
+    data.get(offset..offset + size)
+        .map(|bytes| bytes.iter().rev().fold(0, |acc, &byte| acc << 8 | u64::from(byte)))
+        .unwrap_or(0)
+}
+
+/// Reads the ACPI table at `address`, checking its checksum.
+fn read_table(address: usize) -> Option<Vec<u8>> {
+    let header = read_physical(address, SDT_HEADER_SIZE);
+    let length = read_le(&header, 4, 4) as usize;
+    if length < SDT_HEADER_SIZE || length > 1024 * 1024 {
+        return None;
+    }
+    let table = read_physical(address, length);
+    if table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+        return None;
+    }
+    Some(table)
+}
+
+/// Finds the RSDP in the BIOS memory, in the first KiB of the EBDA or in
+/// 0xE0000-0xFFFFF.
+fn search_rsdp_bios() -> Option<usize> {
+    let ebda = (read_le(&read_physical(0x40E, 2), 0, 2) as usize) << 4;
+    let areas = [(ebda, 1024), (0xE0000, 0x20000)];
+    for &(start, length) in areas.iter().filter(|(start, _)| *start != 0) {
+        let area = read_physical(start, length);
+        if let Some(offset) = (0..length - 20).step_by(16)
+            .find(|&offset| &area[offset..offset + 8] == b"RSD PTR "
+                && area[offset..offset + 20].iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0) {
+            return Some(start + offset);
+        }
+    }
+    None
+}
+
+/// Finds the table with the given signature through the RSDT, or the XSDT if
+/// there is no RSDT.
+fn find_table(rsdp_address: usize, signature: &[u8; 4]) -> Option<Vec<u8>> {
+    let rsdp = read_physical(rsdp_address, 36);
+    let revision = rsdp[15];
+    let rsdt_address = read_le(&rsdp, 16, 4);
+    let xsdt_address = if revision >= 2 { read_le(&rsdp, 24, 8) } else { 0 };
+
+    let (sdt_address, entry_size) = if rsdt_address != 0 {
+        (rsdt_address, 4)
+    } else if xsdt_address != 0 && xsdt_address <= u64::from(u32::max_value()) {
+        (xsdt_address, 8)
+    } else {
+        return None;
+    };
+
+    let sdt = read_table(sdt_address as usize)?;
+    sdt[SDT_HEADER_SIZE..].chunks_exact(entry_size)
+        .map(|entry| read_le(entry, 0, entry_size))
+        .filter(|&address| address != 0 && address <= u64::from(u32::max_value()))
+        .filter_map(|address| read_table(address as usize))
+        .find(|table| &table[0..4] == signature)
+}
+
+/// Gathers the information the `acpi` crate doesn't provide from the tables.
+///
+/// When `rsdp_address` is None, the RSDP is searched in the BIOS memory.
+fn parse_extra_tables(rsdp_address: Option<usize>) {
+    match rsdp_address.or_else(search_rsdp_bios) {
+        Some(rsdp_address) => {
+            power::init(rsdp_address);
+            dmar::init(rsdp_address);
+        },
+        None => info!("RSDP not found, shutdown, ACPI reboot and DMA remapping are not supported"),
+    }
+}
+
 /// Parse RSDP from multiboot2 a tag.
 unsafe fn parse_rsdp_tag(memory_handler: &mut MemoryHandler, rsdp_virtual_address: usize) -> bool {
     let rsdp_virtual_address_aligned = utils::align_down(rsdp_virtual_address, PAGE_SIZE);
@@ -86,6 +180,7 @@ unsafe fn parse_rsdp_tag(memory_handler: &mut MemoryHandler, rsdp_virtual_addres
         ACPI_INFO.call_once(|| {
             acpi
         });
+        parse_extra_tables(Some(rsdp_physical_address));
         true
     } else {
         false
@@ -123,6 +218,7 @@ pub unsafe fn init() {
             ACPI_INFO.call_once(|| {
                 acpi
             });
+            parse_extra_tables(None);
 
             is_init = true;
         }
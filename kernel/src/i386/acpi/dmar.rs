@@ -0,0 +1,172 @@
+//! DMA Remapping Reporting table
+//!
+//! The DMAR tells us where the registers of the Intel VT-d remapping hardware
+//! units are, and which PCI devices each of them translates the DMAs of. See
+//! [crate::devices::iommu] for the driver using it.
+//!
+//! The format is described in chapter 8 of the [Intel Virtualization
+//! Technology for Directed I/O specification].
+//!
+//! We only look at the DMA Remapping Hardware Unit Definitions, and of their
+//! device scopes, only at the PCI endpoints directly on their start bus.
+//! Devices behind a PCI-to-PCI bridge can only be found by walking the PCI
+//! configuration space, which the kernel doesn't do. Reserved Memory Region
+//! Reporting structures are ignored too: devices relying on one, such as an
+//! USB controller emulating a PS/2 keyboard, will have their DMAs blocked.
+//!
+//! [Intel Virtualization Technology for Directed I/O specification]: https://software.intel.com/content/dam/develop/external/us/en/documents/vt-directed-io-spec.pdf
+
+use alloc::vec::Vec;
+
+use crate::sync::Once;
+use super::{SDT_HEADER_SIZE, read_le, find_table};
+
+/// A DMA Remapping Hardware Unit Definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemappingUnit {
+    /// The PCI segment the devices of this unit are on.
+    pub segment: u16,
+    /// Physical address of the remapping registers of this unit.
+    pub register_base: u64,
+    /// Whether this unit translates the DMAs of all the devices of its segment
+    /// that aren't explicitly listed in the scope of another unit.
+    pub include_pci_all: bool,
+    /// Requester IDs (bus << 8 | device << 3 | function) of the PCI endpoints
+    /// explicitly in the scope of this unit.
+    pub devices: Vec<u16>,
+}
+
+/// The remapping units found in the DMAR, set by [init].
+static REMAPPING_UNITS: Once<Vec<RemappingUnit>> = Once::new();
+
+/// Type of the DMA Remapping Hardware Unit Definition structures.
+const DRHD_TYPE: u16 = 0;
+/// INCLUDE_PCI_ALL bit of the DRHD flags.
+const INCLUDE_PCI_ALL: u8 = 1 << 0;
+/// Type of the device scope entries describing a PCI endpoint.
+const SCOPE_PCI_ENDPOINT: u8 = 1;
+/// Offset of the first remapping structure in the DMAR.
+const REMAPPING_STRUCTURES_OFFSET: usize = SDT_HEADER_SIZE + 12;
+
+/// Decodes the DMA Remapping Hardware Unit Definitions of a DMAR table.
+///
+/// Stops at the first malformed structure, returning the units decoded so far.
+pub fn parse_dmar(dmar: &[u8]) -> Vec<RemappingUnit> {
+    let mut units = Vec::new();
+    let mut pos = REMAPPING_STRUCTURES_OFFSET;
+    while pos + 4 <= dmar.len() {
+        let ty = read_le(dmar, pos, 2) as u16;
+        let length = read_le(dmar, pos + 2, 2) as usize;
+        if length < 4 || pos + length > dmar.len() {
+            break;
+        }
+        let structure = &dmar[pos..pos + length];
+        pos += length;
+        if ty != DRHD_TYPE || structure.len() < 16 {
+            continue;
+        }
+
+        let mut devices = Vec::new();
+        let mut scope_pos = 16;
+        while scope_pos + 6 <= structure.len() {
+            let scope_type = structure[scope_pos];
+            let scope_length = structure[scope_pos + 1] as usize;
+            if scope_length < 6 || scope_pos + scope_length > structure.len() {
+                break;
+            }
+            let start_bus = u16::from(structure[scope_pos + 5]);
+            let path = &structure[scope_pos + 6..scope_pos + scope_length];
+            if scope_type == SCOPE_PCI_ENDPOINT && path.len() == 2 {
+                devices.push(start_bus << 8 | u16::from(path[0]) << 3 | u16::from(path[1] & 0b111));
+            } else if scope_type == SCOPE_PCI_ENDPOINT {
+                info!("DMAR: ignoring the scope of a device behind a PCI bridge");
+            }
+            scope_pos += scope_length;
+        }
+
+        units.push(RemappingUnit {
+            segment: read_le(structure, 6, 2) as u16,
+            register_base: read_le(structure, 8, 8),
+            include_pci_all: structure[4] & INCLUDE_PCI_ALL != 0,
+            devices,
+        });
+    }
+    units
+}
+
+/// Gathers the remapping units from the DMAR table.
+///
+/// Called by the ACPI init.
+pub(super) fn init(rsdp_address: usize) {
+    let units = match find_table(rsdp_address, b"DMAR") {
+        Some(dmar) => parse_dmar(&dmar),
+        None => {
+            info!("No DMAR, DMA remapping is not supported");
+            return;
+        }
+    };
+    info!("DMA remapping units: {:x?}", units);
+    REMAPPING_UNITS.call_once(|| units);
+}
+
+/// Gets the remapping units described by the DMAR. Empty if the system has no
+/// DMAR.
+pub fn remapping_units() -> &'static [RemappingUnit] {
+    REMAPPING_UNITS.r#try().map(|units| &units[..]).unwrap_or(&[])
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::{parse_dmar, RemappingUnit};
+
+    /// Builds a DMAR with the given remapping structures, without caring
+    /// about the header.
+    fn dmar(structures: &[u8]) -> Vec<u8> {
+        let mut dmar = vec![0; 48];
+        dmar.extend_from_slice(structures);
+        dmar
+    }
+
+    /// The single DRHD QEMU's intel-iommu describes.
+    #[test]
+    fn parse_dmar_include_all() {
+        let drhd = [0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0xd9, 0xfe, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(parse_dmar(&dmar(&drhd)), vec![RemappingUnit {
+            segment: 0,
+            register_base: 0xfed9_0000,
+            include_pci_all: true,
+            devices: vec![],
+        }]);
+    }
+
+    /// A DRHD with an endpoint at 00:02.0 and one behind a bridge, followed
+    /// by a RMRR.
+    #[test]
+    fn parse_dmar_scopes() {
+        let structures = [
+            // DRHD
+            0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xd9, 0xfe, 0x00, 0x00, 0x00, 0x00,
+            // PCI endpoint 00:02.0
+            0x01, 0x08, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00,
+            // PCI endpoint behind the bridge 00:1c.0
+            0x01, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // RMRR
+            0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(parse_dmar(&dmar(&structures)), vec![RemappingUnit {
+            segment: 0,
+            register_base: 0xfed9_0000,
+            include_pci_all: false,
+            devices: vec![0x0010],
+        }]);
+    }
+
+    #[test]
+    fn parse_dmar_truncated() {
+        let drhd = [0x00, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(parse_dmar(&dmar(&drhd)), vec![]);
+    }
+}
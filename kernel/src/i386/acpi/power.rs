@@ -0,0 +1,237 @@
+//! ACPI power management
+//!
+//! Powers the computer off by entering the S5 sleep state, and reboots it
+//! through the ACPI reset register, the keyboard controller, or as a last
+//! resort a triple fault.
+//!
+//! Everything we need is in the FADT, except the values to write in the
+//! SLP_TYP fields of the PM1 control registers to enter S5. Those come from
+//! the `\_S5` package of the DSDT, which [parse_s5] gets by running the DSDT
+//! through the `aml` interpreter.
+
+use acpi::AcpiHandler;
+use aml::{AmlContext, AmlName, AmlValue, DebugVerbosity};
+use failure::Backtrace;
+
+use crate::error::KernelError;
+use crate::i386::pio::Pio;
+use crate::io::Io;
+use crate::sync::Once;
+use super::{MemoryHandler, SDT_HEADER_SIZE, read_le, read_table, find_table};
+
+/// Everything we need from the ACPI tables to shut down or reboot.
+#[derive(Debug)]
+struct PowerInfo {
+    /// Port of the SMI command register, 0 if the system has no SMM.
+    smi_command_port: u16,
+    /// Value to write to the SMI command register to switch to ACPI mode.
+    acpi_enable: u8,
+    /// Port of the PM1a control register.
+    pm1a_control: u16,
+    /// Port of the PM1b control register, 0 if there is none.
+    pm1b_control: u16,
+    /// SLP_TYPa and SLP_TYPb values for S5, if the DSDT has an `\_S5` object.
+    s5_sleep_types: Option<(u16, u16)>,
+    /// The reset register and the value to write to it, if supported.
+    reset: Option<(GenericAddress, u8)>,
+}
+
+/// An ACPI Generic Address Structure, as used by the reset register.
+#[derive(Debug, Clone, Copy)]
+struct GenericAddress {
+    /// 0 for system memory, 1 for system I/O. We don't handle the others.
+    address_space: u8,
+    /// The address of the register.
+    address: u64,
+}
+
+/// The power management information, set by [init].
+static POWER_INFO: Once<PowerInfo> = Once::new();
+
+/// SCI_EN bit of the PM1 control registers. Set when the system is in ACPI mode.
+const SCI_EN: u16 = 1 << 0;
+/// SLP_EN bit of the PM1 control registers. Enters the sleep state in SLP_TYP.
+const SLP_EN: u16 = 1 << 13;
+/// Offset of the SLP_TYP field in the PM1 control registers.
+const SLP_TYP_SHIFT: u16 = 10;
+/// RESET_REG_SUP bit of the FADT flags.
+const RESET_REG_SUP: u32 = 1 << 10;
+
+/// Gets the SLP_TYPa and SLP_TYPb values from the `\_S5` package defined by
+/// the given AML, the content of the DSDT.
+///
+/// Returns None if the AML couldn't be parsed, or doesn't define `\_S5` as a
+/// package starting with two integers.
+pub fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let mut context = AmlContext::new(DebugVerbosity::None);
+    if let Err(err) = context.parse_table(aml) {
+        warn!("Failed parsing the DSDT: {:?}", err);
+        return None;
+    }
+    let s5 = context.namespace.get_by_path(&AmlName::from_str("\\_S5_").ok()?).ok()?;
+    match s5 {
+        AmlValue::Package(elements) => match (elements.get(0)?, elements.get(1)?) {
+            (AmlValue::Integer(slp_typa), AmlValue::Integer(slp_typb)) => Some((*slp_typa as u16, *slp_typb as u16)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Gathers the power management information from the ACPI tables.
+///
+/// Called by the ACPI init.
+pub(super) fn init(rsdp_address: usize) {
+    let fadt = match find_table(rsdp_address, b"FACP") {
+        Some(fadt) => fadt,
+        None => {
+            info!("No FADT, shutdown and ACPI reboot are not supported");
+            return;
+        }
+    };
+
+    let mut dsdt_address = read_le(&fadt, 40, 4);
+    if dsdt_address == 0 {
+        dsdt_address = read_le(&fadt, 140, 8);
+    }
+    let s5_sleep_types = if dsdt_address != 0 && dsdt_address <= u64::from(u32::max_value()) {
+        read_table(dsdt_address as usize).and_then(|dsdt| parse_s5(&dsdt[SDT_HEADER_SIZE..]))
+    } else {
+        None
+    };
+
+    let reset = if read_le(&fadt, 112, 4) as u32 & RESET_REG_SUP != 0 {
+        Some((GenericAddress {
+            address_space: read_le(&fadt, 116, 1) as u8,
+            address: read_le(&fadt, 120, 8),
+        }, read_le(&fadt, 128, 1) as u8))
+    } else {
+        None
+    };
+
+    let info = PowerInfo {
+        smi_command_port: read_le(&fadt, 48, 4) as u16,
+        acpi_enable: read_le(&fadt, 52, 1) as u8,
+        pm1a_control: read_le(&fadt, 64, 4) as u16,
+        pm1b_control: read_le(&fadt, 68, 4) as u16,
+        s5_sleep_types,
+        reset,
+    };
+    info!("ACPI power management: {:x?}", info);
+    POWER_INFO.call_once(|| info);
+}
+
+/// Switches the system to ACPI mode, if the firmware didn't already.
+fn enable_acpi_mode(info: &PowerInfo) {
+    let pm1a_control = Pio::<u16>::new(info.pm1a_control);
+    if pm1a_control.read() & SCI_EN != 0 || info.smi_command_port == 0 || info.acpi_enable == 0 {
+        return;
+    }
+    Pio::<u8>::new(info.smi_command_port).write(info.acpi_enable);
+    for _ in 0..1_000_000 {
+        if pm1a_control.read() & SCI_EN != 0 {
+            break;
+        }
+    }
+}
+
+/// Powers the computer off by entering the S5 sleep state.
+///
+/// # Errors
+///
+/// * NotImplemented:
+///     * There is no FADT, or its DSDT has no `\_S5` object.
+///     * The firmware ignored the request.
+pub fn shutdown() -> Result<(), KernelError> {
+    let info = POWER_INFO.r#try().ok_or(KernelError::NotImplemented {
+        msg: "shutdown without ACPI", backtrace: Backtrace::new()
+    })?;
+    let (slp_typa, slp_typb) = info.s5_sleep_types.ok_or(KernelError::NotImplemented {
+        msg: "shutdown without an \\_S5 object", backtrace: Backtrace::new()
+    })?;
+
+    info!("Entering S5, goodbye");
+    enable_acpi_mode(info);
+    let mut pm1a_control = Pio::<u16>::new(info.pm1a_control);
+    let value = pm1a_control.read() & !(0b111 << SLP_TYP_SHIFT);
+    pm1a_control.write(value | slp_typa << SLP_TYP_SHIFT | SLP_EN);
+    if info.pm1b_control != 0 {
+        let mut pm1b_control = Pio::<u16>::new(info.pm1b_control);
+        let value = pm1b_control.read() & !(0b111 << SLP_TYP_SHIFT);
+        pm1b_control.write(value | slp_typb << SLP_TYP_SHIFT | SLP_EN);
+    }
+
+    // Powering off takes a little while.
+    for _ in 0..10_000_000 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    Err(KernelError::NotImplemented { msg: "the firmware ignored the S5 request", backtrace: Backtrace::new() })
+}
+
+/// Reboots the computer.
+///
+/// Tries the ACPI reset register, then the keyboard controller, and finally
+/// triple faults.
+pub fn reboot() -> ! {
+    if let Some((register, value)) = POWER_INFO.r#try().and_then(|info| info.reset) {
+        info!("Rebooting through the ACPI reset register");
+        match register.address_space {
+            0 if register.address <= u64::from(u32::max_value()) => {
+                let mut handler = MemoryHandler;
+                let mapping = handler.map_physical_region::<u8>(register.address as usize, 1);
+                unsafe {
+                    // safe: the FADT says it's the reset register.
+                    mapping.virtual_start.as_ptr().write_volatile(value);
+                }
+                handler.unmap_physical_region(mapping);
+            }
+            1 => Pio::<u8>::new(register.address as u16).write(value),
+            space => info!("Unsupported reset register address space {}", space),
+        }
+    }
+
+    info!("Rebooting through the keyboard controller");
+    let mut keyboard_controller = Pio::<u8>::new(0x64);
+    // Wait for the input buffer to be empty.
+    for _ in 0..1_000_000 {
+        if keyboard_controller.read() & 0b10 == 0 {
+            break;
+        }
+    }
+    keyboard_controller.write(0xFE);
+
+    info!("Rebooting through a triple fault");
+    unsafe {
+        // safe: with an empty IDT, the breakpoint triple faults, and we never return.
+        let empty_idt = [0u16; 3];
+        llvm_asm!("lidt ($0)
+                   int3" :: "r"(&empty_idt) :: "volatile");
+    }
+    loop { unsafe { llvm_asm!("hlt" :::: "volatile"); } }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::parse_s5;
+
+    /// `Name (_S5, Package (0x04) { 0x05, Zero, Zero, Zero })`.
+    #[test]
+    fn parse_s5_simple() {
+        let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x07, 0x04, 0x0A, 0x05, 0x00, 0x00, 0x00];
+        assert_eq!(parse_s5(&aml), Some((5, 0)));
+    }
+
+    /// `Scope (\) { Name (_S5, Package (0x02) { 0x0007, One }) }`.
+    #[test]
+    fn parse_s5_in_scope() {
+        let aml = [0x10, 0x0E, b'\\', 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0B, 0x07, 0x00, 0x01];
+        assert_eq!(parse_s5(&aml), Some((7, 1)));
+    }
+
+    /// `Name (_S4, Package (0x02) { 0x05, Zero })`.
+    #[test]
+    fn parse_s5_missing() {
+        let aml = [0x08, b'_', b'S', b'4', b'_', 0x12, 0x05, 0x02, 0x0A, 0x05, 0x00];
+        assert_eq!(parse_s5(&aml), None);
+    }
+}
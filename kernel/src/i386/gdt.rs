@@ -88,6 +88,7 @@ use crate::i386::instructions::segmentation::*;
 use crate::paging::PAGE_SIZE;
 use sunrise_libkern::TLS;
 use crate::sync::SpinLock;
+use crate::process::IOPB_SIZE;
 use bitfield::fmt::Debug;
 
 /// The global GDT. Needs to be initialized with [init_gdt].
@@ -380,7 +381,10 @@ pub struct MainTask {
     ///
     /// * `0`: this port is addressable.
     /// * `1`: this port is not addressable.
-    pub iopb: [u8; 0x2001]
+    pub iopb: [u8; 0x2001],
+    /// Pid of the process whose ports are allowed by `iopb`, or None if it allows
+    /// none. Lies past the limit of the TSS segment, the CPU never reads it.
+    iopb_owner: Option<usize>,
 }
 
 impl Debug for MainTask {
@@ -405,7 +409,8 @@ impl MainTask {
     const fn empty() -> MainTask {
         MainTask {
             tss: TssStruct::empty(),
-            iopb: [0u8; 0x2001]
+            iopb: [0u8; 0x2001],
+            iopb_owner: None,
         }
     }
 
@@ -417,6 +422,24 @@ impl MainTask {
     fn init(&mut self) {
         self.tss.init();
         for v in &mut self.iopb[..] { *v = 0xFF }
+        self.iopb_owner = None;
+    }
+
+    /// Loads the IO permission bitmap of the process `pid`, None meaning that it
+    /// can't access any port.
+    ///
+    /// Does nothing if it is already loaded, so that switching between threads of the
+    /// same process, or between processes without IO ports, doesn't copy it.
+    pub fn load_iopb(&mut self, pid: usize, iopb: Option<&[u8]>) {
+        let owner = iopb.map(|_| pid);
+        if self.iopb_owner == owner {
+            return;
+        }
+        match iopb {
+            Some(iopb) => self.iopb[..iopb.len()].copy_from_slice(iopb),
+            None => for v in &mut self.iopb[..IOPB_SIZE] { *v = 0xFF },
+        }
+        self.iopb_owner = owner;
     }
 }
 
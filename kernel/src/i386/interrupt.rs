@@ -6,7 +6,7 @@
 
 use crate::devices::pic;
 use crate::devices::lapic::LocalApic;
-use crate::devices::ioapic::IoApic;
+use crate::devices::ioapic::{IoApic, DeliveryMode};
 use acpi::interrupt::{InterruptModel, InterruptSourceOverride};
 use crate::sync::Once;
 use alloc::vec::Vec;
@@ -128,6 +128,31 @@ pub fn unmask(irq: u8) {
     ioapic.set_redirection_entry((irq - ioapic.interrupt_base()) as u8, redirection_entry);
 }
 
+/// Makes the given IOAPIC input deliver an NMI to the boot cpu.
+///
+/// Used by the [watchdog], which needs to run even when interrupts are
+/// disabled. The input must not be shared with any other device.
+///
+/// # Panic
+///
+/// Panics if called before calling `init`, or if no IOAPIC handles this input.
+///
+/// [watchdog]: crate::watchdog
+pub fn route_nmi(irq: u32) {
+    let ioapics = &INTERRUPT_HANDLER.r#try().unwrap().ioapics;
+
+    let ioapic = ioapics.iter().find(|ioapic|
+                                     ioapic.interrupt_base() <= irq &&
+                                     irq < ioapic.interrupt_base() + ioapic.redirection_entry_count()).unwrap();
+
+    let mut redirection_entry = ioapic.redirection_entry((irq - ioapic.interrupt_base()) as u8);
+    redirection_entry.set_delivery_mode(DeliveryMode::NMI);
+    // NMIs are always edge triggered.
+    redirection_entry.set_trigger_mode(false);
+    redirection_entry.set_interrupt_mask(false);
+    ioapic.set_redirection_entry((irq - ioapic.interrupt_base()) as u8, redirection_entry);
+}
+
 /// Gets the IOAPIC pin associated with an ISA (i8259) IRQ.
 ///
 /// # Panic
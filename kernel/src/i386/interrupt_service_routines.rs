@@ -32,22 +32,22 @@ use crate::i386::instructions::interrupts::sti;
 use crate::mem::VirtualAddress;
 use crate::paging::kernel_memory::get_kernel_memory;
 use crate::i386::PrivilegeLevel;
-use crate::scheduler::{get_current_thread, get_current_process};
+use crate::scheduler::get_current_thread;
 use crate::process::{ProcessStruct, ThreadState};
+use crate::process::coredump;
 use crate::sync::{SpinLock, SpinLockIRQ};
 use core::sync::atomic::{AtomicU8, Ordering};
 
 use crate::scheduler;
 use crate::i386::gdt::GdtIndex;
-use crate::i386::gdt::DOUBLE_FAULT_TASK;
+use crate::i386::gdt::{DOUBLE_FAULT_TASK, MAIN_TASK};
+use crate::stack::KernelStack;
 use crate::panic::{kernel_panic, PanicOrigin};
 use crate::i386::structures::gdt::SegmentSelector;
 use crate::i386::registers::eflags::EFlags;
-use crate::mem::{UserSpacePtr, UserSpacePtrMut};
+use crate::i386::usercopy;
 use crate::error::UserspaceError;
 use crate::syscalls::*;
-use bit_field::BitArray;
-use sunrise_libkern::{nr, SYSCALL_NAMES};
 
 /// Contains the number of interrupts we are currently inside.
 ///
@@ -382,8 +382,10 @@ macro_rules! trap_gate_asm {
 ///
 ///     } else {
 ///
-///         // we come from userspace, backup the hardware context in the thread struct
+///         // we come from userspace, charge elapsed time to userspace, and
+///         // backup the hardware context in the thread struct
 ///         {
+///             get_current_thread().cpu_time.enter_kernel();
 ///             *get_current_thread().userspace_hwcontext.lock() = *userspace_context
 ///         }
 ///
@@ -404,12 +406,13 @@ macro_rules! trap_gate_asm {
 ///         let thread = get_current_thread();                                       //
 ///         error!("{}, errorcode: {}, in {:#?}",                                    // handler_strategy
 ///             $exception_name, $hwcontext.errcode, thread);                        // (here: kill)
-///         ProcessStruct::kill_current_process();                                   //
+///         ProcessStruct::crash_current_process(exception_signal($exception_name)); //
 ///     }
 ///
 ///     // if we're returning to userspace, check we haven't been killed
 ///     if comming from Ring == 3 {
 ///         check_thread_killed();
+///         get_current_thread().cpu_time.leave_kernel();
 ///     }
 /// }
 /// ```
@@ -483,7 +486,7 @@ macro_rules! generate_trap_gate_handler {
         {
             let thread = get_current_thread();
             error!("{}, errorcode: {}, in {:#?}", $exception_name, $hwcontext.errcode, thread);
-            ProcessStruct::kill_current_process();
+            ProcessStruct::crash_current_process(coredump::exception_signal($exception_name));
         }
     };
 
@@ -491,7 +494,7 @@ macro_rules! generate_trap_gate_handler {
         {
             let thread = get_current_thread();
             error!("{}, in {:#?}", $exception_name, thread);
-            ProcessStruct::kill_current_process();
+            ProcessStruct::crash_current_process(coredump::exception_signal($exception_name));
         }
     };
     // end handler
@@ -578,7 +581,9 @@ macro_rules! generate_trap_gate_handler {
             } else {
                 // we come from userspace, backup the hardware context in the thread struct
                 {
-                    *get_current_thread().userspace_hwcontext.lock() = userspace_context.clone();
+                    let thread = get_current_thread();
+                    thread.cpu_time.enter_kernel();
+                    *thread.userspace_hwcontext.lock() = userspace_context.clone();
                     // don't leave an Arc in case we're killed in the handler.
                 }
 
@@ -597,6 +602,7 @@ macro_rules! generate_trap_gate_handler {
             // if we're returning to userspace, check we haven't been killed
             if let PrivilegeLevel::Ring3 = SegmentSelector(userspace_context.cs as u16).rpl() {
                 check_thread_killed();
+                get_current_thread().cpu_time.leave_kernel();
             }
         }
     };
@@ -624,13 +630,27 @@ generate_trap_gate_handler!(name: "Debug Exception",
                 handler_strategy: panic
 );
 
+/// Non-maskable interrupt handler.
+///
+/// NMIs are used by the [watchdog](crate::watchdog). Any other NMI is
+/// unexpected, and panics.
+fn nmi_handler(exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+    if !crate::watchdog::nmi(hwcontext) {
+        kernel_panic(&PanicOrigin::KernelFault {
+            exception_message: format_args!("Unexpected exception: {}", exception_name),
+            kernel_hardware_context: hwcontext.clone()
+        });
+    }
+}
+
 generate_trap_gate_handler!(name: "An unexpected non-maskable (but still kinda maskable) interrupt occurred",
                 has_errcode: false,
                 wrapper_asm_fnname: nmi_exception_asm_wrapper,
                 wrapper_rust_fnname: nmi_exception_rust_wrapper,
-                kernel_fault_strategy: panic,
-                user_fault_strategy: panic,
-                handler_strategy: panic
+                kernel_fault_strategy: ignore, // the watchdog NMI can interrupt the kernel.
+                user_fault_strategy: ignore,
+                handler_strategy: nmi_handler,
+                interrupt_context: true
 );
 
 generate_trap_gate_handler!(name: "Breakpoint Exception",
@@ -681,8 +701,16 @@ generate_trap_gate_handler!(name: "Device Not Available Exception",
 /// Double fault handler. Panics the kernel unconditionally.
 ///
 /// This one is called via a Task Gate, we don't generate a wrapper for it.
+///
+/// The usual cause is a kernel stack overflow: the first fault happens in the page guard,
+/// and the cpu can't push the exception frame on that same stack. We recognize it from
+/// `cr2` and the `esp` saved in the MAIN_TASK, so the panic can say so.
 fn double_fault_handler() {
-    kernel_panic(&PanicOrigin::DoubleFault);
+    let kernel_stack_overflow = match MAIN_TASK.try_lock() {
+        Some(main_task) => KernelStack::is_stack_overflow(crate::paging::read_cr2().addr(), main_task.tss.esp as usize),
+        None => false
+    };
+    kernel_panic(&PanicOrigin::DoubleFault { kernel_stack_overflow });
 }
 
 generate_trap_gate_handler!(name: "Invalid TSS Exception",
@@ -731,7 +759,14 @@ generate_trap_gate_handler!(name: "Page Fault Exception",
 );
 
 /// Overriding the default panic strategy so we can display cr2
+///
+/// Faults in [usercopy::copy] are not kernel bugs, but bogus userspace
+/// pointers: we make the copy fail instead of panicking.
 fn kernel_page_fault_panic(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+    if usercopy::fixup(hwcontext) {
+        return;
+    }
+
     let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
     let cause_address = crate::paging::read_cr2();
 
@@ -743,11 +778,31 @@ fn kernel_page_fault_panic(_exception_name: &'static str, hwcontext: &mut Usersp
     });
 }
 
-/// Overriding the default panic strategy so we can display cr2
+/// Overriding the default panic strategy so we can display cr2, and report
+/// faults in the page guard of a stack as stack overflows.
+///
+/// The first access to a page of a lazy mapping is not a bug, it is
+/// committed, and [user_page_fault_handler] lets the instruction be retried.
 fn user_page_fault_panic(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
     let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
     let cause_address = crate::paging::read_cr2();
 
+    if commit_lazy_page(errcode, cause_address) {
+        return;
+    }
+
+    let thread = get_current_thread();
+    let is_stack_overflow = thread.process.pmemory.lock().is_stack_guard(cause_address);
+    if is_stack_overflow {
+        kernel_panic(&PanicOrigin::UserspaceFault {
+            exception_message: format_args!("Stack overflow in thread {} of {}, accessing {:?}",
+                thread.tid,
+                thread.process.name,
+                cause_address),
+            userspace_hardware_context: hwcontext.clone()
+        });
+    }
+
     kernel_panic(&PanicOrigin::UserspaceFault {
         exception_message: format_args!("Page Fault accessing {:?}, exception errcode: {:?}",
             cause_address,
@@ -756,14 +811,39 @@ fn user_page_fault_panic(_exception_name: &'static str, hwcontext: &mut Userspac
     });
 }
 
+/// Commits the page of the current process that caused a page fault, if it
+/// wasn't present and falls in a lazy mapping.
+///
+/// Returns whether the page is now mapped, and the faulting instruction can be
+/// retried. Committing twice is harmless, so this can be called both by the
+/// panic strategy and by the handler.
+fn commit_lazy_page(errcode: PageFaultErrorCode, cause_address: VirtualAddress) -> bool {
+    !errcode.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && get_current_thread().process.pmemory.lock().commit_on_fault(cause_address)
+}
+
 /// Overriding the default kill strategy so we can display cr2
 fn user_page_fault_handler(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+    if let PrivilegeLevel::Ring0 = SegmentSelector(hwcontext.cs as u16).rpl() {
+        // A fault in a user copy, already recovered by kernel_page_fault_panic.
+        return;
+    }
+
     let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
     let cause_address = crate::paging::read_cr2();
 
+    if commit_lazy_page(errcode, cause_address) {
+        // First access to a lazily allocated page, retry the instruction.
+        return;
+    }
     let thread = get_current_thread();
-    error!("Page Fault accessing {:?}, exception errcode: {:?} in {:#?}", cause_address, errcode, thread);
-    ProcessStruct::kill_current_process();
+    let is_stack_overflow = thread.process.pmemory.lock().is_stack_guard(cause_address);
+    if is_stack_overflow {
+        error!("Stack overflow in thread {} of {}, accessing {:?}", thread.tid, thread.process.name, cause_address);
+    } else {
+        error!("Page Fault accessing {:?}, exception errcode: {:?} in {:#?}", cause_address, errcode, thread);
+    }
+    ProcessStruct::crash_current_process(coredump::SIGSEGV);
 }
 
 generate_trap_gate_handler!(name: "x87 FPU floating-point error",
@@ -830,26 +910,6 @@ generate_trap_gate_handler!(name: "Syscall Interrupt",
 );
 
 impl UserspaceHardwareContext {
-    /// Update the Registers with the passed result.
-    fn apply0(&mut self, ret: Result<(), UserspaceError>) {
-        self.apply3(ret.map(|_| (0, 0, 0)))
-    }
-
-    /// Update the Registers with the passed result.
-    fn apply1(&mut self, ret: Result<usize, UserspaceError>) {
-        self.apply3(ret.map(|v| (v, 0, 0)))
-    }
-
-    /// Update the Registers with the passed result.
-    fn apply2(&mut self, ret: Result<(usize, usize), UserspaceError>) {
-        self.apply3(ret.map(|(v0, v1)| (v0, v1, 0)))
-    }
-
-    /// Update the Registers with the passed result.
-    fn apply3(&mut self, ret: Result<(usize, usize, usize), UserspaceError>) {
-        self.apply4(ret.map(|(v0, v1, v2)| (v0, v1, v2, 0)))
-    }
-
     /// Update the Registers with the passed result.
     fn apply4(&mut self, ret: Result<(usize, usize, usize, usize), UserspaceError>) {
         match ret {
@@ -909,8 +969,8 @@ impl UserspaceHardwareContext {
 /// We do *NOT* restore registers before returning, as they all are used for parameter passing.
 /// It is the caller's job to save the one it needs.
 ///
-/// Dispatches to the various syscall handling functions based on `hwcontext.eax`,
-/// and updates the hwcontext with the correct return values.
+/// Dispatches to the various syscall handling functions based on `hwcontext.eax`
+/// through [crate::syscalls::dispatch], and updates the hwcontext with the correct return values.
 // TODO: Missing argument slot for SVCs on i386 backend
 // BODY: Our i386 SVC ABI is currently fairly different from the ABI used by
 // BODY: Horizon/NX. This is for two reasons:
@@ -932,84 +992,8 @@ impl UserspaceHardwareContext {
 // BODY: our performances, it doesn't really hurt it either, and having a uniform
 // BODY: ABI across platforms would make for lower maintenance.
 fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
-
     let (syscall_nr, x0, x1, x2, x3, x4, x5) = (hwcontext.eax, hwcontext.ebx, hwcontext.ecx, hwcontext.edx, hwcontext.esi, hwcontext.edi, hwcontext.ebp);
-    let syscall_name = SYSCALL_NAMES.get(syscall_nr).unwrap_or(&"Unknown");
-
-    debug!("Handling syscall {} - x0: {}, x1: {}, x2: {}, x3: {}, x4: {}, x5: {}",
-          syscall_name, x0, x1, x2, x3, x4, x5);
-
-    let allowed = get_current_process().capabilities.syscall_mask.get_bit(syscall_nr);
-
-    if cfg!(feature = "no-security-check") && !allowed {
-        let curproc = get_current_process();
-        error!("Process {} attempted to use unauthorized syscall {} ({:#04x})",
-               curproc.name, syscall_name, syscall_nr);
-    }
-
-    let allowed = cfg!(feature = "no-security-check") || allowed;
-
-    match (allowed, syscall_nr) {
-        // Horizon-inspired syscalls!
-        (true, nr::SetHeapSize) => hwcontext.apply1(set_heap_size(x0)),
-        (true, nr::QueryMemory) => hwcontext.apply1(query_memory(UserSpacePtrMut(x0 as _), x1, x2)),
-        (true, nr::ExitProcess) => hwcontext.apply0(exit_process()),
-        (true, nr::CreateThread) => hwcontext.apply1(create_thread(x0, x1, x2, x3 as _, x4 as _)),
-        (true, nr::StartThread) => hwcontext.apply0(start_thread(x0 as _)),
-        (true, nr::ExitThread) => hwcontext.apply0(exit_thread()),
-        (true, nr::SleepThread) => hwcontext.apply0(sleep_thread(x0)),
-        (true, nr::SignalEvent) => hwcontext.apply0(signal_event(x0 as _)),
-        (true, nr::ClearEvent) => hwcontext.apply0(clear_event(x0 as _)),
-        (true, nr::MapSharedMemory) => hwcontext.apply0(map_shared_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
-        (true, nr::UnmapSharedMemory) => hwcontext.apply0(unmap_shared_memory(x0 as _, x1 as _, x2 as _)),
-        (true, nr::CloseHandle) => hwcontext.apply0(close_handle(x0 as _)),
-        (true, nr::ResetSignal) => hwcontext.apply0(reset_signal(x0 as _)),
-        (true, nr::WaitSynchronization) => hwcontext.apply1(wait_synchronization(UserSpacePtr::from_raw_parts(x0 as _, x1), x2)),
-        (true, nr::ConnectToNamedPort) => hwcontext.apply1(connect_to_named_port(UserSpacePtr(x0 as _))),
-        (true, nr::SendSyncRequestWithUserBuffer) => hwcontext.apply0(send_sync_request_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2 as _)),
-        (true, nr::GetProcessId) => hwcontext.apply1(get_process_id(x0 as _)),
-        (true, nr::OutputDebugString) => hwcontext.apply0(output_debug_string(UserSpacePtr::from_raw_parts(x0 as _, x1), x2, UserSpacePtr::from_raw_parts(x3 as _, x4))),
-        (true, nr::CreateSession) => hwcontext.apply2(create_session(x0 != 0, x1 as _)),
-        (true, nr::AcceptSession) => hwcontext.apply1(accept_session(x0 as _)),
-        (true, nr::ReplyAndReceiveWithUserBuffer) => hwcontext.apply1(reply_and_receive_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), UserSpacePtr::from_raw_parts(x2 as _, x3), x4 as _, x5)),
-        (true, nr::CreateEvent) => hwcontext.apply2(create_event()),
-        (true, nr::CreateSharedMemory) => hwcontext.apply1(create_shared_memory(x0 as _, x1 as _, x2 as _)),
-        (true, nr::CreateInterruptEvent) => hwcontext.apply1(create_interrupt_event(x0, x1 as u32)),
-        (true, nr::QueryPhysicalAddress) => hwcontext.apply3(query_physical_address(x0 as _)),
-        (true, nr::GetProcessList) => hwcontext.apply1(get_process_list(x0 as _, x1 as _)),
-        (true, nr::CreatePort) => hwcontext.apply2(create_port(x0 as _, x1 != 0, UserSpacePtr(x2 as _))),
-        (true, nr::ManageNamedPort) => hwcontext.apply1(manage_named_port(UserSpacePtr(x0 as _), x1 as _)),
-        (true, nr::ConnectToPort) => hwcontext.apply1(connect_to_port(x0 as _)),
-        (true, nr::SetProcessMemoryPermission) => hwcontext.apply0(set_process_memory_permission(x0 as _, x1 as _, x2 as _, x3 as _)),
-        (true, nr::MapProcessMemory) => hwcontext.apply0(map_process_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
-        (true, nr::UnmapProcessMemory) => hwcontext.apply0(unmap_process_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
-        (true, nr::CreateProcess) => hwcontext.apply1(create_process(UserSpacePtr(x0 as _), UserSpacePtr::from_raw_parts(x1 as _, x2 * 4))),
-        (true, nr::StartProcess) => hwcontext.apply0(start_process(x0 as _, x1 as _, x2 as _, x3 as _)),
-        (true, nr::TerminateProcess) => hwcontext.apply0(terminate_process(x0 as _)),
-        (true, nr::GetProcessInfo) => hwcontext.apply1(get_process_info(x0 as _, x1 as _)),
-
-        // sunrise extensions
-        (true, nr::MapFramebuffer) => hwcontext.apply4(map_framebuffer()),
-        (true, nr::MapMmioRegion) => hwcontext.apply0(map_mmio_region(x0, x1, x2, x3 != 0)),
-        (true, nr::SetThreadArea) => hwcontext.apply0(set_thread_area(x0)),
-
-        // Unknown/unauthorized syscall.
-        (false, _) => {
-            // Attempted to call unauthorized SVC. Horizon invokes usermode
-            // exception handling in some cases. Let's just kill the process for
-            // now.
-            let curproc = get_current_process();
-            error!("Process {} attempted to use unauthorized syscall {} ({:#04x}), killing",
-                   curproc.name, syscall_name, syscall_nr);
-            ProcessStruct::kill_current_process();
-        },
-        _ => {
-            let curproc = get_current_process();
-            error!("Process {} attempted to use unknown syscall {} ({:#04x}), killing",
-                   curproc.name, syscall_name, syscall_nr);
-            ProcessStruct::kill_current_process();
-        }
-    }
+    hwcontext.apply4(dispatch(syscall_nr, [x0, x1, x2, x3, x4, x5]));
 }
 
 /// Generates irq handlers.
@@ -1031,8 +1015,12 @@ macro_rules! irq_handler {
 
         $(
             /// Auto generated irq handler. See [`irq_handler`].
-            fn $handler_name(_exception_name: &'static str, _hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+            fn $handler_name(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
                 crate::i386::interrupt::acknowledge($irq_nbr);
+                if crate::timer::is_timer_irq($irq_nbr) {
+                    crate::profiler::sample(hwcontext);
+                    crate::watchdog::timer_tick(hwcontext);
+                }
                 crate::event::dispatch_event($irq_nbr);
             }
 
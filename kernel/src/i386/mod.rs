@@ -15,6 +15,7 @@ pub mod process_switch;
 pub mod gdt;
 pub mod interrupt;
 pub mod interrupt_service_routines;
+pub mod usercopy;
 
 pub mod pio {
     //! Port IO
@@ -311,6 +312,24 @@ pub mod instructions {
             ret
         }
     }
+
+    pub mod tsc {
+        //! Time Stamp Counter.
+
+        /// Reads the current value of the Time Stamp Counter.
+        ///
+        /// The TSC counts cycles since the last reset. It is used as the time
+        /// base for per-thread CPU time accounting.
+        pub fn read() -> u64 {
+            let low: u32;
+            let high: u32;
+            unsafe {
+                // Safety: rdtsc has no side-effects.
+                llvm_asm!("rdtsc" : "={eax}"(low), "={edx}"(high) : : : "volatile");
+            }
+            (u64::from(high) << 32) | u64::from(low)
+        }
+    }
 }
 
 /// Represents a protection ring level.
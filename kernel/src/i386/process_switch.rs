@@ -130,19 +130,6 @@ pub unsafe extern "C" fn process_switch(thread_b: Arc<ThreadStruct>, thread_curr
         esp_to_load
     };
 
-    // Set IOPB back to "nothing allowed" state
-    // todo do not change iopb if thread_b belongs to the same process.
-
-    // MAIN_TSS should otherwise only be locked during DOUBLE_FAULTING,
-    // in which case we really shouldn't be context-switching.
-    let mut main_tss = MAIN_TASK.try_lock()
-        .expect("Cannot lock main tss");
-    for ioport in &thread_current.process.capabilities.ioports {
-        let ioport = *ioport as usize;
-        main_tss.iopb[ioport / 8] = 0xFF;
-    }
-    drop(main_tss);
-
     // current is still stored in scheduler's global CURRENT_PROCESS, so it's not dropped yet.
     drop(thread_current);
 
@@ -188,18 +175,16 @@ pub unsafe extern "C" fn process_switch(thread_b: Arc<ThreadStruct>, thread_curr
     // recreate the Arc to our ThreadStruct from the pointer that was passed to us
     let me = unsafe { Arc::from_raw(whoami) };
 
-    // MAIN_TSS should have been unlocked during schedule-out. Re-take it.
+    // MAIN_TSS should otherwise only be locked during DOUBLE_FAULTING,
+    // in which case we really shouldn't be context-switching.
     let mut main_tss = MAIN_TASK.try_lock()
         .expect("Cannot lock main tss");
 
     // Set the ESP0
     main_tss.tss.esp0 = me.kstack.get_stack_start() as u32;
 
-    // Set IOPB
-    for ioport in &me.process.capabilities.ioports {
-        let ioport = *ioport as usize;
-        main_tss.iopb[ioport / 8] &= !(1 << (ioport % 8));
-    }
+    // Set IOPB, if we come from another process.
+    main_tss.load_iopb(me.process.pid, me.process.capabilities.iopb.as_deref());
 
     me
 }
@@ -308,12 +293,8 @@ unsafe fn first_schedule() {
         // Set the ESP0
         main_tss.tss.esp0 = current.kstack.get_stack_start() as u32;
 
-        // todo do not touch iopb if we come from a thread of the same process.
-        // Set IOPB
-        for ioport in &current.process.capabilities.ioports {
-            let ioport = *ioport as usize;
-            main_tss.iopb[ioport / 8] &= !(1 << (ioport % 8));
-        }
+        // Set IOPB, if we come from another process.
+        main_tss.load_iopb(current.process.pid, current.process.capabilities.iopb.as_deref());
 
         drop(main_tss); // unlock it
 
@@ -89,6 +89,17 @@ impl KernelStack {
         esp & (0xFFFFFFFF << STACK_ALIGNMENT) // 0x....0000
     }
 
+    /// Checks whether `fault_address` falls in the page guard of the kernel stack
+    /// `esp` points into, meaning this stack overflowed.
+    ///
+    /// Used by the double fault handler, with the registers saved in the MAIN_TASK.
+    pub fn is_stack_overflow(fault_address: usize, esp: usize) -> bool {
+        let stack_bottom = Self::align_to_stack_bottom(esp);
+        KernelLand::contains_address(VirtualAddress(fault_address))
+            && Self::align_to_stack_bottom(fault_address) == stack_bottom
+            && fault_address - stack_bottom < PAGE_SIZE
+    }
+
     /// Gets the bottom of the stack by `and`ing `$esp` with [STACK_ALIGNMENT].
     ///
     /// This is the value usually stored in `KernelStack.stack_address`.
@@ -149,22 +160,7 @@ impl KernelStack {
     ///
     /// It can accepts an elf symbols which will be used to enhance the stack dump.
     pub fn dump_current_stack<'a>(elf_symbols: Option<(&ElfFile<'a>, &'a [Entry32])>) {
-        let ebp;
-        let esp;
-        let eip;
-        unsafe {
-            llvm_asm!("
-                mov $0, ebp
-                mov $1, esp
-
-                // eip can only be read through the stack after a call instruction
-                call read_eip
-            read_eip:
-                pop $2"
-            : "=r"(ebp), "=r"(esp), "=r"(eip) ::: "volatile", "intel" );
-        }
-
-        let source = StackDumpSource::new(esp, ebp, eip);
+        let source = StackDumpSource::from_current_stack();
 
         unsafe {
             // safe: the constructed slice will be "under" the current stack top,
@@ -205,6 +201,22 @@ impl StackDumpSource {
     pub fn new(esp: usize, ebp: usize, eip: usize) -> Self {
         Self { esp, ebp, eip }
     }
+
+    /// Creates a StackDumpSource describing the frame of the caller.
+    ///
+    /// Our own frame is gone as soon as we return, so we start from the
+    /// saved ebp and return address it contains instead.
+    #[inline(never)]
+    pub fn from_current_stack() -> Self {
+        let ebp: usize;
+        unsafe { llvm_asm!("mov $0, ebp" : "=r"(ebp) ::: "volatile", "intel"); }
+        let frame = unsafe {
+            // safe: we're compiled with frame pointers, [ebp] is the caller's
+            //       ebp, and [ebp + 4] our return address.
+            core::slice::from_raw_parts(ebp as *const usize, 2)
+        };
+        Self { esp: ebp + 8, ebp: frame[0], eip: frame[1] }
+    }
 }
 
 /// Dumps the stack from the given information, displaying it
@@ -291,16 +303,64 @@ pub unsafe fn dump_stack<'a>(source: &StackDumpSource, elf_symbols: Option<(&Elf
         // does esp point to a mapping ?
         if let QueryMemory::Used(mapping) = pmemory.query_memory(VirtualAddress(esp)) {
             // a stack would at least be readable and writable
+            // read it through a mirror, SMAP forbids touching it directly.
             if mapping.flags().contains(MappingAccessRights::u_rw()) {
-                let stack_slice = unsafe { ::core::slice::from_raw_parts(mapping.address().addr() as *const u8,
-                                                                         mapping.length()) };
-                return dump_stack_from_slice(stack_slice, mapping.address().addr(), esp, ebp, eip, elf);
+                if let Ok(mirror) = pmemory.mirror_mapping(mapping.address(), mapping.length()) {
+                    let stack_slice = unsafe { ::core::slice::from_raw_parts(mirror.addr().addr() as *const u8,
+                                                                             mirror.len()) };
+                    return dump_stack_from_slice(stack_slice, mapping.address().addr(), esp, ebp, eip, elf);
+                }
             }
         }
         writeln!(SerialLogger, "# Invalid esp, does not point to a valid mapping. esp: {:#x}, ebp: {:#x}, eip: {:#x}", esp, ebp, eip);
     }
 }
 
+/// Finds the name of the function containing `eip` in the given symbol table.
+///
+/// The name is returned mangled.
+pub fn find_symbol<'a>(elf: Option<(&ElfFile<'a>, &'a [Entry32])>, eip: usize) -> Option<&'a str> {
+    let (elf, symbol_section) = elf?;
+    symbol_section.iter()
+        .find(|entry| entry.value() <= (eip as u64) && (eip as u64) < entry.value() + entry.size())
+        .and_then(|entry| entry.get_name(elf).ok())
+}
+
+/// Walks the frames of the KernelStack we're currently running on, starting
+/// from the given information, and calls `f` with the eip of every frame and
+/// its demangled function name.
+///
+/// Unlike [dump_stack], this neither locks anything nor hexdumps the frames,
+/// so it can be used to build the panic report. It stops after `max_frames`,
+/// or when a frame leaves the current KernelStack, which includes `source`
+/// not pointing to it in the first place.
+pub fn walk_current_kernel_stack<'a, F>(source: &StackDumpSource, elf: Option<(&ElfFile<'a>, &'a [Entry32])>, max_frames: usize, mut f: F)
+where
+    F: FnMut(usize, &dyn core::fmt::Display)
+{
+    // we know our own stack is mapped.
+    let stack_bottom = KernelStack::get_current_stack_bottom() + PAGE_SIZE;
+    let stack_top = stack_bottom + STACK_SIZE * PAGE_SIZE;
+
+    let mut ebp = source.ebp;
+    let mut eip = source.eip;
+    for _ in 0..max_frames {
+        if eip == 0x00000000 || ebp == 0x00000000 { break; } // reached end of stack
+
+        let funcname = find_symbol(elf, eip).unwrap_or("unknown");
+        f(eip, &rustc_demangle(funcname));
+
+        // fetch saved ebp/eip at [ebp], if it's still in the stack
+        if ebp < stack_bottom || ebp + 8 > stack_top { break; }
+        let saved = unsafe {
+            // safe: checked it's in our own KernelStack above, and aligned by the ABI.
+            core::slice::from_raw_parts(ebp as *const usize, 2)
+        };
+        ebp = saved[0];
+        eip = saved[1];
+    }
+}
+
 /// Dumps a stack, displaying it in a frame-by-frame format.
 ///
 /// The stack is passed as a slice. The function starts at given esp, and goes down, frame by frame.
@@ -326,16 +386,7 @@ fn dump_stack_from_slice<'a>(stack: &[u8], orig_address: usize, mut esp: usize,
     loop {
         if eip == 0x00000000 || ebp == 0x00000000 { break; } // reached end of stack
 
-        let mut funcname = "unknown";
-        if let Some((elf, symbol_section)) = elf {
-            if let Some(entry) = symbol_section.iter()
-                .find(|entry| entry.value() <= (eip as u64) && (eip as u64) < entry.value() + entry.size())
-            {
-                if let Ok(s) = entry.get_name(elf) {
-                    funcname = s;
-                }
-            }
-        }
+        let funcname = find_symbol(elf, eip).unwrap_or("unknown");
         writeln!(SerialLogger, "> Frame #{} - {}, eip: {:#010x} - esp: {:#010x} - ebp: {:#010x}", frame_nb, rustc_demangle(funcname), eip, esp, ebp);
         // todo: subtracts underflows ! This made me panic in the panic handler D:
         let esp_off = esp - orig_address;
@@ -364,3 +415,23 @@ fn dump_stack_from_slice<'a>(stack: &[u8], orig_address: usize, mut esp: usize,
         frame_nb += 1;
     }
 }
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn stack_overflow_is_recognized_in_page_guard_only() {
+        let stack = KernelStack::allocate_stack().unwrap();
+        let bottom = stack.stack_address.addr();
+        let esp = bottom + PAGE_SIZE + 0x10;
+
+        assert!(KernelStack::is_stack_overflow(bottom + PAGE_SIZE - 4, esp));
+        assert!(KernelStack::is_stack_overflow(bottom, bottom + 4));
+        assert!(!KernelStack::is_stack_overflow(bottom + PAGE_SIZE, esp));
+        assert!(!KernelStack::is_stack_overflow(0x1000, esp));
+        // The page guard of another stack.
+        let other = KernelStack::allocate_stack().unwrap();
+        assert!(!KernelStack::is_stack_overflow(other.stack_address.addr(), esp));
+    }
+}
@@ -0,0 +1,195 @@
+//! Copies between the kernel and userspace
+//!
+//! When the CPU supports them, we enable SMEP, which faults when the kernel
+//! executes code from a userspace page, and SMAP, which faults when the kernel
+//! reads or writes a userspace page. The only place where the kernel touches
+//! userspace memory is then [copy], which sets the AC flag with `stac` for the
+//! duration of the copy, and clears it with `clac` right after.
+//!
+//! Callers (see [UserSpacePtr]) validate the userspace range before copying,
+//! but a bogus range might still slip through. If the copy page faults, the
+//! page fault handler calls [fixup], which makes [copy] return an error
+//! instead of panicking the kernel.
+//!
+//! [UserSpacePtr]: crate::mem::UserSpacePtr
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::error::UserspaceError;
+use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
+
+/// CR4 bit enabling Supervisor Mode Execution Prevention.
+const CR4_SMEP: u32 = 1 << 20;
+/// CR4 bit enabling Supervisor Mode Access Prevention.
+const CR4_SMAP: u32 = 1 << 21;
+/// CPUID leaf 7 EBX bit telling SMEP is supported.
+const CPUID_7_EBX_SMEP: u32 = 1 << 7;
+/// CPUID leaf 7 EBX bit telling SMAP is supported.
+const CPUID_7_EBX_SMAP: u32 = 1 << 20;
+
+/// Whether SMAP is enabled. `stac` and `clac` are invalid opcodes on CPUs
+/// without SMAP, so [copy] only uses them when this is set.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// usercopy_memcpy(dst, src, len) -> bytes left to copy.
+//
+// A fault can only happen on the `rep movsb` between usercopy_fault_start and
+// usercopy_fault_end. The fault handler then resumes at usercopy_fixup, which
+// returns the number of bytes that weren't copied, still in ecx.
+#[cfg(any(not(test), target_os = "none"))]
+global_asm!("
+.intel_syntax noprefix
+.global usercopy_memcpy
+.type usercopy_memcpy, @function
+usercopy_memcpy:
+    push esi
+    push edi
+    mov edi, [esp + 0xC]
+    mov esi, [esp + 0x10]
+    mov ecx, [esp + 0x14]
+    xor eax, eax
+.global usercopy_fault_start
+usercopy_fault_start:
+    rep movsb
+.global usercopy_fault_end
+usercopy_fault_end:
+    pop edi
+    pop esi
+    ret
+.global usercopy_fixup
+usercopy_fixup:
+    mov eax, ecx
+    pop edi
+    pop esi
+    ret
+.att_syntax prefix
+");
+
+#[cfg(any(not(test), target_os = "none"))]
+extern "C" {
+    /// Copies `len` bytes from `src` to `dst`. Returns the number of bytes that
+    /// weren't copied because of a fault.
+    fn usercopy_memcpy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    /// Address of the only instruction of [usercopy_memcpy] that may fault.
+    static usercopy_fault_start: u8;
+    /// Address right after the faulting instruction.
+    static usercopy_fault_end: u8;
+    /// Where [usercopy_memcpy] resumes after a fault.
+    static usercopy_fixup: u8;
+}
+
+/// Executes cpuid, returning (eax, ebx, ecx, edx).
+#[cfg(any(not(test), target_os = "none"))]
+fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        llvm_asm!("cpuid"
+            : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            : "{eax}"(leaf), "{ecx}"(subleaf)
+            :
+            : "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+/// Enables SMEP and SMAP, if the CPU supports them.
+///
+/// Must be called after the IDT is set up, so we can recover from faults in
+/// [copy], and before the kernel first copies anything from or to userspace.
+#[cfg(any(not(test), target_os = "none"))]
+pub fn init() {
+    let (max_leaf, _, _, _) = cpuid(0, 0);
+    let features = if max_leaf >= 7 { cpuid(7, 0).1 } else { 0 };
+    let smep = features & CPUID_7_EBX_SMEP != 0;
+    let smap = features & CPUID_7_EBX_SMAP != 0;
+
+    unsafe {
+        let mut cr4: u32;
+        llvm_asm!("mov $0, cr4" : "=r"(cr4) ::: "intel", "volatile");
+        if smep { cr4 |= CR4_SMEP; }
+        if smap { cr4 |= CR4_SMAP; }
+        llvm_asm!("mov cr4, $0" :: "r"(cr4) : "memory" : "intel", "volatile");
+    }
+
+    SMAP_ENABLED.store(smap, Ordering::SeqCst);
+    info!("SMEP {}, SMAP {}",
+        if smep { "enabled" } else { "not supported" },
+        if smap { "enabled" } else { "not supported" });
+}
+
+/// Copies `len` bytes from `src` to `dst`, one of them being in userspace.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///   - The copy faulted. Part of `dst` may have been written.
+///
+/// # Safety
+///
+/// The kernel side of the copy must be valid for `len` bytes. The userspace
+/// side should have been validated, see [crate::mem::UserSpacePtr].
+#[cfg(any(not(test), target_os = "none"))]
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), UserspaceError> {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        llvm_asm!("stac" :::: "volatile");
+    }
+    let left = usercopy_memcpy(dst, src, len);
+    if smap {
+        llvm_asm!("clac" :::: "volatile");
+    }
+    match left {
+        0 => Ok(()),
+        _ => Err(UserspaceError::InvalidAddress)
+    }
+}
+
+/// Host tests have no userspace, this is just a memcpy.
+#[cfg(all(test, not(target_os = "none")))]
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), UserspaceError> {
+    core::ptr::copy_nonoverlapping(src, dst, len);
+    Ok(())
+}
+
+/// Recovers from a kernel page fault happening in [copy].
+///
+/// If `hwcontext` faulted in the middle of a copy, makes it resume at the
+/// copy's error path, and returns true. Otherwise, returns false, and the
+/// fault should be treated as a kernel bug.
+#[cfg(any(not(test), target_os = "none"))]
+pub fn fixup(hwcontext: &mut UserspaceHardwareContext) -> bool {
+    let (start, end, fixup) = unsafe {
+        // safe: we only take the addresses of those labels.
+        (&usercopy_fault_start as *const u8 as usize,
+         &usercopy_fault_end as *const u8 as usize,
+         &usercopy_fixup as *const u8 as usize)
+    };
+    if start <= hwcontext.eip && hwcontext.eip < end {
+        hwcontext.eip = fixup;
+        true
+    } else {
+        false
+    }
+}
+
+/// Host tests have no userspace, copies never fault.
+#[cfg(all(test, not(target_os = "none")))]
+pub fn fixup(_hwcontext: &mut UserspaceHardwareContext) -> bool {
+    false
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::paging::PAGE_SIZE;
+    use crate::scheduler::get_current_process;
+
+    #[test_case]
+    fn copy_recovers_from_page_faults() {
+        let unmapped = get_current_process().pmemory.lock().find_available_space(PAGE_SIZE).unwrap();
+        let mut buf = [0u8; 16];
+        let res = unsafe { copy(buf.as_mut_ptr(), unmapped.addr() as *const u8, buf.len()) };
+        assert_eq!(res, Err(UserspaceError::InvalidAddress));
+        let res = unsafe { copy(unmapped.addr() as *mut u8, buf.as_ptr(), buf.len()) };
+        assert_eq!(res, Err(UserspaceError::InvalidAddress));
+    }
+}
@@ -80,13 +80,15 @@
 use crate::sync::SpinRwLock;
 use alloc::string::String;
 use crate::error::UserspaceError;
+use alloc::vec::Vec;
 use hashbrown::HashMap;
 
 pub mod session;
 pub mod port;
 
 pub use self::session::{ClientSession, ServerSession};
-pub use self::port::{ClientPort, ServerPort};
+pub use self::port::{ClientPort, ServerPort, PortInfo};
+pub use self::session::{SessionInfo, RequestInfo};
 
 lazy_static! {
     // TODO: StringWrapper<[u8; 12]>
@@ -137,3 +139,12 @@ pub fn connect_to_named_port(name: [u8; 12]) -> Result<ClientSession, UserspaceE
         None => Err(UserspaceError::NoSuchEntry)
     }
 }
+
+/// Takes a snapshot of the named ports, sorted by name.
+pub fn named_ports() -> Vec<(String, PortInfo)> {
+    let mut ports: Vec<_> = NAMED_PORTS.read().iter()
+        .map(|(name, client)| (name.clone(), client.info()))
+        .collect();
+    ports.sort_by(|a, b| a.0.cmp(&b.0));
+    ports
+}
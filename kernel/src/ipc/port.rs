@@ -59,6 +59,15 @@ pub struct ClientPort(Arc<Port>);
 pub struct ServerPort(Arc<Port>);
 
 impl Port {
+    /// Takes a snapshot of the state of this port.
+    fn info(&self) -> PortInfo {
+        PortInfo {
+            id: self as *const Port as usize,
+            pending_connections: self.incoming_connections.lock().len(),
+            server_count: self.servercount.load(Ordering::SeqCst),
+        }
+    }
+
     /// Returns a ClientPort from this Port.
     fn client(this: Arc<Self>) -> ClientPort {
         ClientPort(this)
@@ -71,6 +80,18 @@ impl Port {
     }
 }
 
+/// A snapshot of the state of a Port, for debugging purposes.
+#[derive(Debug, Clone, Copy)]
+pub struct PortInfo {
+    /// Identifies the port: the ClientPort and ServerPort of a pair have the
+    /// same id.
+    pub id: usize,
+    /// Number of connection requests waiting to be accepted.
+    pub pending_connections: usize,
+    /// Number of live ServerPorts.
+    pub server_count: usize,
+}
+
 /// Create a new Port pair. Those ports are linked to each-other: The server will
 /// receive connections from the client.
 /// A port may only have max_sessions sessions active at a given time.
@@ -132,6 +153,11 @@ struct IncomingConnection {
 }
 
 impl ServerPort {
+    /// Takes a snapshot of the state of the underlying port.
+    pub fn info(&self) -> PortInfo {
+        self.0.info()
+    }
+
     /// Accept a new connection on the Port.
     pub fn accept(&self) -> Result<ServerSession, UserspaceError> {
         loop {
@@ -163,6 +189,11 @@ impl ServerPort {
 }
 
 impl ClientPort {
+    /// Takes a snapshot of the state of the underlying port.
+    pub fn info(&self) -> PortInfo {
+        self.0.info()
+    }
+
     /// Connects to this port.
     pub fn connect(&self) -> Result<ClientSession, UserspaceError> {
         let incoming = Arc::new(IncomingConnection {
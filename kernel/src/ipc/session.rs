@@ -34,6 +34,7 @@ use core::slice;
 use crate::paging::{PAGE_SIZE, MappingAccessRights, process_memory::ProcessMemory};
 use crate::paging::process_memory::QueryMemory;
 use crate::paging::mapping::MappingFrames;
+use crate::paging::cross_process::CrossProcessMapping;
 use crate::mem::{UserSpacePtr, UserSpacePtrMut, VirtualAddress};
 use bit_field::BitField;
 use crate::error::KernelError;
@@ -131,6 +132,17 @@ bitfield! {
 }
 
 impl Session {
+    /// Takes a snapshot of the state of this session.
+    fn info(&self) -> SessionInfo {
+        let internal = self.internal.lock();
+        SessionInfo {
+            id: self as *const Session as usize,
+            server_count: self.servercount.load(Ordering::SeqCst),
+            active_request: internal.active_request.as_ref().map(Request::info),
+            incoming_requests: internal.incoming_requests.iter().map(Request::info).collect(),
+        }
+    }
+
     /// Returns a ClientPort from this Port.
     fn client(this: Arc<Self>) -> ClientSession {
         ClientSession(this)
@@ -203,6 +215,45 @@ struct Request {
     buffers: Vec<Buffer>,
 }
 
+/// A snapshot of a Request, for debugging purposes.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestInfo {
+    /// Pid of the process that sent the request.
+    pub sender_pid: usize,
+    /// Tid of the thread that sent the request.
+    pub sender_tid: usize,
+    /// Size of the IPC buffer.
+    pub bufsize: usize,
+    /// Number of A/B/W buffers mapped for this request.
+    pub buffers: usize,
+}
+
+impl Request {
+    /// Takes a snapshot of this request.
+    fn info(&self) -> RequestInfo {
+        RequestInfo {
+            sender_pid: self.sender.process.pid,
+            sender_tid: self.sender.tid,
+            bufsize: self.sender_bufsize,
+            buffers: self.buffers.len(),
+        }
+    }
+}
+
+/// A snapshot of the state of a Session, for debugging purposes.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Identifies the session: the ClientSession and ServerSession of a pair
+    /// have the same id.
+    pub id: usize,
+    /// Number of live ServerSessions.
+    pub server_count: usize,
+    /// The request currently being serviced.
+    pub active_request: Option<RequestInfo>,
+    /// The requests waiting to be received.
+    pub incoming_requests: Vec<RequestInfo>,
+}
+
 /// Information about a Buffer during a Request.
 #[derive(Debug)]
 struct Buffer {
@@ -302,8 +353,8 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
             // memcpy the first page.
             let first_page_size = core::cmp::min(PAGE_SIZE - (addr % PAGE_SIZE), size);
 
+            from_mem.commit(VirtualAddress(addr), first_page_size)?;
             let from_mapping = from_mem.mirror_mapping(VirtualAddress(addr), first_page_size)?;
-            let from = UserSpacePtr::from_raw_parts(from_mapping.addr().addr() as *const u8, from_mapping.len());
 
             let res_mapping = to_mem.create_regular_mapping(to_addr_full, PAGE_SIZE, MemoryType::Ipc, MappingAccessRights::u_rw());
 
@@ -313,8 +364,9 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
 
             first_page_info_opt = Some((to_addr_full, PAGE_SIZE));
 
-            let mut to = UserSpacePtrMut::from_raw_parts_mut(to_addr.addr() as *mut u8, first_page_size);
-            to.copy_from_slice(&from);
+            if let Err(error) = copy_mirrored(&from_mapping, to_mem, to_addr, first_page_size) {
+                return mapping_error_handling_logic(to_mem, error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
+            }
             size_handled += first_page_size;
         }
 
@@ -323,8 +375,8 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
             let last_page = (VirtualAddress(addr) + size).floor();
             let last_page_size = (addr + size) % PAGE_SIZE;
 
+            from_mem.commit(last_page, last_page_size)?;
             let from_mapping = from_mem.mirror_mapping(last_page, last_page_size)?;
-            let from = UserSpacePtr::from_raw_parts(from_mapping.addr().addr() as *const u8, from_mapping.len());
 
             let to_last_page = (to_addr + size).floor();
             let res_mapping = to_mem.create_regular_mapping(to_last_page, PAGE_SIZE, MemoryType::Ipc, MappingAccessRights::u_rw());
@@ -335,8 +387,9 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
 
             last_page_info_opt = Some((to_last_page, PAGE_SIZE));
 
-            let mut to = UserSpacePtrMut::from_raw_parts_mut(to_last_page.addr() as *mut u8, last_page_size);
-            to.copy_from_slice(&from);
+            if let Err(error) = copy_mirrored(&from_mapping, to_mem, to_last_page, last_page_size) {
+                return mapping_error_handling_logic(to_mem, error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
+            }
             size_handled += last_page_size;
         }
 
@@ -356,18 +409,27 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
                                                     last_page_info_opt),
             };
 
-            let frames = match mapping.frames() {
-                MappingFrames::Shared(shared) => shared.clone(),
+            let offset = addr - mapping.address().addr();
+
+            let res_mapping = match mapping.frames() {
+                MappingFrames::Shared(shared) => {
+                    let frames = shared.clone();
+                    to_mem.map_partial_shared_mapping(frames, to_addr, mapping.phys_offset() + offset, size - size_handled, MemoryType::Ipc, MappingAccessRights::u_rw())
+                },
+                MappingFrames::Lazy(lazy) => {
+                    let frames = lazy.clone();
+                    let phys_offset = mapping.phys_offset() + offset;
+                    // Commit the pages from our side, so they are accounted
+                    // against the memory limit of the process owning them.
+                    from_mem.commit(VirtualAddress(addr), size - size_handled)
+                        .and_then(|()| to_mem.map_partial_lazy_mapping(frames, to_addr, phys_offset, size - size_handled, MemoryType::Ipc, MappingAccessRights::u_rw()))
+                },
                 _ =>
                 return mapping_error_handling_logic(to_mem, KernelError::InvalidMemState { address: mapping.address(), ty: mapping.state().ty(), backtrace: Backtrace::new() },
                                                     first_page_info_opt,
                                                     middle_page_info_opt,
                                                     last_page_info_opt),
             };
-
-            let offset = addr - mapping.address().addr();
-
-            let res_mapping = to_mem.map_partial_shared_mapping(frames, to_addr, mapping.phys_offset() + offset, size - size_handled, MemoryType::Ipc, MappingAccessRights::u_rw());
             if let Err(error) = res_mapping {
                 return mapping_error_handling_logic(to_mem, error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
             }
@@ -400,6 +462,25 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
     Ok(())
 }
 
+/// Copies `size` bytes from `from`, a mapping mirrored in KernelLand, to
+/// `to_addr` in `to_mem`.
+///
+/// The destination is mirrored as well, instead of being accessed as a
+/// [UserSpacePtrMut]: when it's in the current process, `to_mem` is already
+/// locked by the caller.
+fn copy_mirrored(from: &CrossProcessMapping, to_mem: &mut ProcessMemory, to_addr: VirtualAddress, size: usize) -> Result<(), KernelError> {
+    to_mem.commit(to_addr, size)?;
+    let to = to_mem.mirror_mapping(to_addr, size)?;
+    let (from, to) = unsafe {
+        // safe: both mappings are at least size long, and are in different
+        // processes, so they don't overlap.
+        (slice::from_raw_parts(from.addr().addr() as *const u8, size),
+         slice::from_raw_parts_mut(to.addr().addr() as *mut u8, size))
+    };
+    to.copy_from_slice(from);
+    Ok(())
+}
+
 /// Unmap an IPC Buffer from the receiver.
 fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut ProcessMemory) -> Result<(), UserspaceError> {
     let addr = buffer.dest_addr;
@@ -415,17 +496,10 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
 
         if buffer.writable {
             // memcpy the first page.
-            let from = UserSpacePtr::from_raw_parts(addr.addr() as *const u8, first_page_size);
-
             // This needs explicit error handling since the user might unmap `to_addr` in-between sending the request and receiving the response.
-            result = match to_mem.mirror_mapping(to_addr, first_page_size) {
-                Ok(to_mapping) => {
-                    let mut to = UserSpacePtrMut::from_raw_parts_mut(to_mapping.addr().addr() as *mut u8, first_page_size);
-                    to.copy_from_slice(&from);
-                    Ok(())
-                },
-                Err(err) => Err(err.into()),
-            };
+            result = from_mem.mirror_mapping(addr, first_page_size)
+                .and_then(|from_mapping| copy_mirrored(&from_mapping, to_mem, to_addr, first_page_size))
+                .map_err(|err| err.into());
         }
 
         from_mem.unmap(addr.floor(), PAGE_SIZE).expect("Cannot unmap first unaligned page of buffer");
@@ -438,19 +512,12 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
 
         if buffer.writable {
             // memcpy the last page.
-            let from = UserSpacePtr::from_raw_parts(last_page.addr() as *const u8, last_page_size);
-
             let to_last_page = (to_addr + size).floor();
 
             // This needs explicit error handling since the user might unmap `to_addr` in-between sending the request and receiving the response.
-            result = match to_mem.mirror_mapping(to_last_page, last_page_size) {
-                Ok(to_mapping) => {
-                    let mut to = UserSpacePtrMut::from_raw_parts_mut(to_mapping.addr().addr() as *mut u8, last_page_size);
-                    to.copy_from_slice(&from);
-                    Ok(())
-                },
-                Err(err) => Err(err.into()),
-            };
+            result = from_mem.mirror_mapping(last_page, last_page_size)
+                .and_then(|from_mapping| copy_mirrored(&from_mapping, to_mem, to_last_page, last_page_size))
+                .map_err(|err| err.into());
 
         }
 
@@ -467,6 +534,11 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
 }
 
 impl ClientSession {
+    /// Takes a snapshot of the state of the underlying session.
+    pub fn info(&self) -> SessionInfo {
+        self.0.info()
+    }
+
     /// Send an IPC request through the client pipe. Takes a userspace buffer
     /// containing the packed IPC request. When returning, the buffer will
     /// contain the IPC answer (unless an error occured).
@@ -491,7 +563,7 @@ impl ClientSession {
             }
 
             internal.incoming_requests.push(Request {
-                sender_buf: VirtualAddress(buf.as_ptr() as usize),
+                sender_buf: VirtualAddress(buf.as_mut_ptr() as usize),
                 sender_bufsize: buf.len(),
                 answered: answered.clone(),
                 sender: scheduler::get_current_thread(),
@@ -571,6 +643,11 @@ fn find_c_descriptors(buf: &mut [u8]) -> Result<CBufBehavior, KernelError> {
 }
 
 impl ServerSession {
+    /// Takes a snapshot of the state of the underlying session.
+    pub fn info(&self) -> SessionInfo {
+        self.0.info()
+    }
+
     /// Receive an IPC request through the server pipe. Takes a userspace buffer
     /// containing an empty IPC message. The request may optionally contain a
     /// C descriptor in order to receive X descriptors. The buffer will be filled
@@ -578,7 +655,11 @@ impl ServerSession {
     ///
     /// This function does **not** wait. It assumes an active_request has already
     /// been set by a prior call to wait.
-    pub fn receive(&self, mut buf: UserSpacePtrMut<[u8]>, has_c_descriptors: bool) -> Result<(), UserspaceError> {
+    pub fn receive(&self, buf: UserSpacePtrMut<[u8]>, has_c_descriptors: bool) -> Result<(), UserspaceError> {
+        // Work on a copy of our buffer, and write it back once the message is
+        // passed.
+        let mut our_buf = buf.read_to_vec()?;
+
         // Read active session
         let mut internal = self.0.internal.lock();
 
@@ -587,22 +668,23 @@ impl ServerSession {
         let active = internal.active_request.as_mut().unwrap();
 
         let sender = active.sender.process.clone();
-        let memlock = sender.pmemory.lock();
+        let mut memlock = sender.pmemory.lock();
 
+        memlock.commit(active.sender_buf, active.sender_bufsize)?;
         let mapping = memlock.mirror_mapping(active.sender_buf, active.sender_bufsize)?;
         let sender_buf = unsafe {
             slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
         };
 
         let c_bufs = if has_c_descriptors {
-            find_c_descriptors(&mut *buf)?
+            find_c_descriptors(&mut our_buf)?
         } else {
             CBufBehavior::Disabled
         };
 
-        pass_message(sender_buf, active.sender.clone(), &mut *buf, scheduler::get_current_thread(), false, memlock, &mut active.buffers, c_bufs)?;
+        pass_message(sender_buf, active.sender.clone(), &mut our_buf, scheduler::get_current_thread(), false, memlock, &mut active.buffers, c_bufs)?;
 
-        Ok(())
+        buf.write_from_slice(&our_buf)
     }
 
     /// Replies to the currently active IPC request on the server pipe. Takes a
@@ -622,18 +704,21 @@ impl ServerSession {
         // TODO: This probably has an errcode.
         assert!(self.0.internal.lock().active_request.is_some(), "Called reply without an active session");
 
+        let our_buf = buf.read_to_vec()?;
+
         let mut active = self.0.internal.lock().active_request.take().unwrap();
 
         let sender = active.sender.process.clone();
 
-        let memlock = sender.pmemory.lock();
+        let mut memlock = sender.pmemory.lock();
 
+        memlock.commit(active.sender_buf, active.sender_bufsize)?;
         let mapping = memlock.mirror_mapping(active.sender_buf, active.sender_bufsize)?;
         let sender_buf = unsafe {
             slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
         };
 
-        pass_message(&*buf, scheduler::get_current_thread(), sender_buf, active.sender.clone(), true, memlock, &mut active.buffers, CBufBehavior::Disabled)?;
+        pass_message(&our_buf, scheduler::get_current_thread(), sender_buf, active.sender.clone(), true, memlock, &mut active.buffers, CBufBehavior::Disabled)?;
 
         *active.answered.lock() = Some(Ok(()));
 
@@ -756,34 +841,30 @@ fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8]
             check_lower_than_usize(to_size, UserspaceError::InvalidAddress)?;
             check_lower_than_usize(to_addr.saturating_add(to_size), UserspaceError::InvalidAddress)?;
 
-            let (mapping, mut uspaceptr) = if !is_reply {
+            if !is_reply {
                 // We're receiving: C Buffers are in our address space, X buffers
                 // are in the other address space
+                other_memlock.commit(VirtualAddress(from_addr as usize), from_size as usize)?;
                 let mapping = other_memlock.mirror_mapping(VirtualAddress(from_addr as usize), from_size as usize)?;
-                let uspaceptr = UserSpacePtrMut::from_raw_parts_mut(to_addr as *mut u8, to_size as usize);
-                (mapping, uspaceptr)
+                let from = unsafe {
+                    slice::from_raw_parts(mapping.addr().addr() as *const u8, mapping.len())
+                };
+                UserSpacePtrMut::from_raw_parts_mut(to_addr as *mut u8, to_size as usize).write_from_slice(from)?;
             } else {
-                // We're receiving: C Buffers are in our address space, X buffers
+                // We're replying: X Buffers are in our address space, C buffers
                 // are in the other address space
+                other_memlock.commit(VirtualAddress(to_addr as usize), to_size as usize)?;
                 let mapping = other_memlock.mirror_mapping(VirtualAddress(to_addr as usize), to_size as usize)?;
-                let uspaceptr = UserSpacePtrMut::from_raw_parts_mut(from_addr as *mut u8, from_size as usize);
-                (mapping, uspaceptr)
-            };
-
-            let (from, to) = {
-                let ref_mapping = unsafe {
+                let to = unsafe {
                     slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
                 };
-                let ref_uspace = &mut *uspaceptr;
-                if !is_reply {
-                    (ref_mapping, ref_uspace)
-                } else {
-                    (ref_uspace, ref_mapping)
+                let from = UserSpacePtr::from_raw_parts(from_addr as *const u8, from_size as usize).read_to_vec()?;
+                if from.len() > to.len() {
+                    return Err(UserspaceError::InvalidSize);
                 }
-            };
-
-            to[..from.len()].copy_from_slice(from);
-            coff += from.len() as u64;
+                to[..from.len()].copy_from_slice(&from);
+            }
+            coff += from_size;
 
             let mut counter = counter;
             let counter = *counter
@@ -846,3 +927,89 @@ fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8]
 
     Ok(())
 }
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::event;
+    use crate::process::{Handle, ProcessStruct};
+    use crate::testing::*;
+    use sunrise_libkern::{nr, MemoryType};
+
+    /// Reads its session handle from `DATA + 0x200`, sends the request stored
+    /// at `DATA`, stores the result of the syscall at `DATA + 0x204`, and
+    /// exits.
+    const CLIENT_CODE: &[u8] = &[
+        0x8B, 0x15, 0x00, 0x02, 0x50, 0x00, // mov edx, [0x500200]
+        0xB8, 0x22, 0x00, 0x00, 0x00,       // mov eax, SendSyncRequestWithUserBuffer
+        0xBB, 0x00, 0x00, 0x50, 0x00,       // mov ebx, 0x500000
+        0xB9, 0x00, 0x01, 0x00, 0x00,       // mov ecx, 0x100
+        0xCD, 0x80,                         // int 0x80
+        0xA3, 0x04, 0x02, 0x50, 0x00,       // mov [0x500204], eax
+        0xB8, 0x07, 0x00, 0x00, 0x00,       // mov eax, ExitProcess
+        0xCD, 0x80,                         // int 0x80
+        0x0F, 0x0B,                         // ud2
+    ];
+
+    /// Builds a message with the given type and raw data.
+    fn message(ty: u64, raw: &[u32]) -> Vec<u8> {
+        let mut hdr = MsgPackedHdr(ty);
+        hdr.set_raw_section_size(raw.len() as u16);
+        let mut msg = Vec::from(&hdr.0.to_le_bytes()[..]);
+        for word in raw {
+            msg.extend_from_slice(&word.to_le_bytes());
+        }
+        msg
+    }
+
+    #[test_case]
+    fn request_and_reply_with_userspace_client() {
+        assert_eq!(nr::SendSyncRequestWithUserBuffer, 0x22);
+        assert_eq!(nr::ExitProcess, 0x07);
+
+        let (server, client) = new();
+        let process = create_test_process("ipc_client", CLIENT_CODE, &[nr::SendSyncRequestWithUserBuffer, nr::ExitProcess]);
+        let handle = process.phandles.lock().add_handle(Arc::new(Handle::ClientSession(client)));
+        write_process_memory(&process, TEST_PROCESS_DATA_ADDR, &message(4, &[0xCAFE_BABE, 0xDEAD_BEEF]));
+        write_process_memory(&process, TEST_PROCESS_DATA_ADDR + 0x200, &handle.to_le_bytes());
+        ProcessStruct::start(&process, 0, PAGE_SIZE).unwrap();
+
+        event::wait(Some(&server as &dyn Waitable)).unwrap();
+
+        // The server side lives in our own address space.
+        let current = scheduler::get_current_process();
+        let bufaddr = {
+            let mut pmemory = current.pmemory.lock();
+            let addr = pmemory.find_available_space(PAGE_SIZE).unwrap();
+            pmemory.create_regular_mapping(addr, PAGE_SIZE, MemoryType::Normal, MappingAccessRights::u_rw()).unwrap();
+            addr
+        };
+        let buf = UserSpacePtrMut::from_raw_parts_mut(bufaddr.addr() as *mut u8, 0x100);
+
+        server.receive(buf, false).unwrap();
+        assert_eq!(&buf.read_to_vec().unwrap()[..16], &message(4, &[0xCAFE_BABE, 0xDEAD_BEEF])[..]);
+
+        let reply = message(0, &[0x600D_F00D]);
+        buf.write_from_slice(&reply).unwrap();
+        server.reply(UserSpacePtr::from_raw_parts(bufaddr.addr() as *const u8, reply.len())).unwrap();
+
+        wait_process_exit(&process);
+
+        let mut answer = [0; 12];
+        read_process_memory(&process, TEST_PROCESS_DATA_ADDR, &mut answer);
+        assert_eq!(&answer[..], &reply[..]);
+        let mut result = [0; 4];
+        read_process_memory(&process, TEST_PROCESS_DATA_ADDR + 0x204, &mut result);
+        assert_eq!(u32::from_le_bytes(result), 0, "SendSyncRequest returned an error");
+
+        current.pmemory.lock().unmap(bufaddr, PAGE_SIZE).unwrap();
+    }
+
+    #[test_case]
+    fn request_to_dead_server_fails() {
+        let (server, client) = new();
+        drop(server);
+        let buf = UserSpacePtrMut::from_raw_parts_mut(core::ptr::null_mut(), 0);
+        assert_eq!(client.send_request(buf), Err(UserspaceError::PortRemoteDead));
+    }
+}
@@ -369,7 +369,7 @@ fn enabled(directives: &[Directive], level: Level, target: &str) -> bool {
     false
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod tests {
     use log::{Level, LevelFilter};
     use alloc::vec::Vec;
@@ -0,0 +1,92 @@
+//! In-memory history of the latest kernel logs
+//!
+//! Every line written by the logger is also appended to a fixed-size ring
+//! buffer, without its color codes, so that the kernel panic report can tell
+//! what happened right before the panic.
+
+use core::fmt::{self, Write};
+use crate::sync::SpinLockIRQ;
+
+/// Size of the ring buffer, in bytes.
+const HISTORY_SIZE: usize = 16 * 1024;
+
+/// A ring buffer of log lines.
+struct LogHistory {
+    /// The bytes of the logs. The oldest byte is at `pos` once wrapped.
+    buf: [u8; HISTORY_SIZE],
+    /// Where the next byte will be written.
+    pos: usize,
+    /// Whether the buffer was filled at least once.
+    wrapped: bool,
+}
+
+impl Write for LogHistory {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.pos] = byte;
+            self.pos += 1;
+            if self.pos == HISTORY_SIZE {
+                self.pos = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LogHistory {
+    /// Iterates over the bytes of the history, oldest first.
+    ///
+    /// Once wrapped, the oldest line was partially overwritten, so it is
+    /// skipped.
+    fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        let (older, newer) = if self.wrapped {
+            (&self.buf[self.pos..], &self.buf[..self.pos])
+        } else {
+            (&self.buf[..0], &self.buf[..self.pos])
+        };
+        let wrapped = self.wrapped;
+        older.iter().chain(newer.iter()).cloned()
+            .skip_while(move |byte| wrapped && *byte != b'\n')
+            .skip(if wrapped { 1 } else { 0 })
+    }
+}
+
+/// The history of the kernel logs.
+static HISTORY: SpinLockIRQ<LogHistory> = SpinLockIRQ::new(LogHistory {
+    buf: [0; HISTORY_SIZE],
+    pos: 0,
+    wrapped: false,
+});
+
+/// Appends a line to the history.
+pub fn record(line: fmt::Arguments<'_>) {
+    let _ = writeln!(HISTORY.lock(), "{}", line);
+}
+
+/// Writes the last `max_lines` lines of the history.
+///
+/// Does nothing if the history is locked, so it is safe to use from the
+/// panic handler. Non-ASCII bytes are replaced by `?`.
+pub fn dump_last_lines<W: Write>(w: &mut W, max_lines: usize) {
+    let history = match HISTORY.try_lock() {
+        Some(history) => history,
+        None => {
+            let _ = writeln!(w, "<log history is locked>");
+            return;
+        }
+    };
+
+    let total_lines = history.bytes().filter(|byte| *byte == b'\n').count();
+    let mut skipped_lines = total_lines.saturating_sub(max_lines);
+    for byte in history.bytes() {
+        if skipped_lines != 0 {
+            if byte == b'\n' {
+                skipped_lines -= 1;
+            }
+            continue;
+        }
+        let c = if byte.is_ascii() { byte as char } else { '?' };
+        let _ = w.write_char(c);
+    }
+}
@@ -1,6 +1,9 @@
 //! A simple log implementation based on env_logger
 #![allow(clippy::missing_docs_in_private_items)]
 mod filter;
+pub mod history;
+
+pub use self::filter::Filter;
 
 use log::{self, Log, Metadata, Record, LevelFilter};
 use crate::devices::rs232::SerialLogger;
@@ -8,18 +11,17 @@ use core::fmt::Write;
 use crate::i386::multiboot::get_boot_information;
 use crate::sync::{SpinRwLock, Once};
 use crate::scheduler;
+use crate::process::ProcessStruct;
 
 struct Logger {
     filter: SpinRwLock<filter::Filter>
 }
 
-#[allow(unused_must_use)]
-impl Log for Logger {
-    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
-        self.filter.read().enabled(metadata)
-    }
-
-    fn log(&self, record: &Record<'_>) {
+impl Logger {
+    /// Writes the record to the serial port and to the log history, without
+    /// checking it against any filter.
+    #[allow(unused_must_use)]
+    fn write(&self, record: &Record<'_>) {
         use crate::devices::rs232::{SerialAttributes, SerialColor};
         let color = SerialAttributes::fg(match record.level() {
             log::Level::Error => SerialColor::Red,
@@ -28,12 +30,24 @@ impl Log for Logger {
             log::Level::Debug => SerialColor::Cyan,
             log::Level::Trace => SerialColor::White,
         });
+        if let Some(thread) = scheduler::try_get_current_thread() {
+            writeln!(SerialLogger, "[{}{}{}] - {} - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), thread.process.name, record.args());
+            history::record(format_args!("[{}] - {} - {} - {}", record.level(), record.target(), thread.process.name, record.args()));
+        } else {
+            writeln!(SerialLogger, "[{}{}{}] - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), record.args());
+            history::record(format_args!("[{}] - {} - {}", record.level(), record.target(), record.args()));
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.read().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
         if self.filter.read().matches(record) {
-            if let Some(thread) = scheduler::try_get_current_thread() {
-                writeln!(SerialLogger, "[{}{}{}] - {} - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), thread.process.name, record.args());
-            } else {
-                writeln!(SerialLogger, "[{}{}{}] - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), record.args());
-            }
+            self.write(record);
         }
     }
 
@@ -55,8 +69,42 @@ pub fn early_init() {
 
 /// Reinitializes the logger using the cmdline. This requires the heap.
 pub fn init() {
-    let logger = LOGGER.r#try().expect("early_init to be called before init");
     let cmdline = get_boot_information().command_line_tag().unwrap().command_line();
-    let newfilter = filter::Builder::new().parse(cmdline).build();
+    set_filter(cmdline);
+}
+
+/// Parses an env_logger-style directive string (e.g.
+/// `sunrise_kernel::ipc=trace,info`), and replaces the global filter with it.
+pub fn set_filter(directives: &str) {
+    let logger = LOGGER.r#try().expect("early_init to be called before set_filter");
+    let newfilter = filter::Builder::new().parse(directives).build();
     *logger.filter.write() = newfilter;
 }
+
+/// Parses an env_logger-style directive string into a [Filter], to be used as a
+/// per-process filter by [log_userspace].
+pub fn parse_filter(directives: &str) -> Filter {
+    filter::Builder::new().parse(directives).build()
+}
+
+/// Logs a record coming from userspace through `output_debug_string`.
+///
+/// If the process has its own log filter, it is used instead of the global
+/// filter. This allows enabling verbose logs for a single process without
+/// flooding the serial port with the logs of every other process using the
+/// same crates.
+pub fn log_userspace(process: &ProcessStruct, record: &Record<'_>) {
+    let logger = match LOGGER.r#try() {
+        Some(logger) => logger,
+        None => return
+    };
+
+    let enabled = match &*process.log_filter.read() {
+        Some(filter) => filter.matches(record),
+        None => logger.filter.read().matches(record),
+    };
+
+    if enabled {
+        logger.write(record);
+    }
+}
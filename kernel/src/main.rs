@@ -10,9 +10,12 @@
 //! Currently doesn't do much, besides booting and printing Hello World on the
 //! screen. But hey, that's a start.
 
-#![feature(lang_items, start, llvm_asm, global_asm, naked_functions, core_intrinsics, const_fn, abi_x86_interrupt, allocator_api, box_syntax, no_more_cas, step_trait, step_trait_ext, thread_local, nll, exclusive_range_pattern)]
+#![feature(lang_items, start, llvm_asm, global_asm, naked_functions, core_intrinsics, const_fn, abi_x86_interrupt, allocator_api, box_syntax, no_more_cas, step_trait, step_trait_ext, thread_local, nll, exclusive_range_pattern, track_caller, const_in_array_repeat_expressions)]
 #![no_std]
 #![cfg_attr(target_os = "none", no_main)]
+#![cfg_attr(all(test, target_os = "none"), feature(custom_test_frameworks))]
+#![cfg_attr(all(test, target_os = "none"), test_runner(crate::testing::test_runner))]
+#![cfg_attr(all(test, target_os = "none"), reexport_test_harness_main = "test_main")]
 #![recursion_limit = "1024"]
 
 // rustc warnings
@@ -45,7 +48,7 @@ extern crate log;
 extern crate failure;
 #[macro_use]
 extern crate bitfield;
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 #[macro_use]
 extern crate mashup;
 
@@ -66,6 +69,8 @@ pub mod heap_allocator;
 pub mod devices;
 pub mod sync;
 pub mod timer;
+pub mod profiler;
+pub mod watchdog;
 pub mod process;
 pub mod scheduler;
 pub mod mem;
@@ -75,6 +80,9 @@ pub mod utils;
 pub mod checks;
 pub mod cpu_locals;
 pub mod panic;
+pub mod dump_info;
+#[cfg(all(test, target_os = "none"))]
+pub mod testing;
 
 #[cfg(target_os = "none")]
 // Make rust happy about rust_oom being no_mangle...
@@ -84,7 +92,7 @@ pub use crate::heap_allocator::rust_oom;
 ///
 /// Creation of a Box, Vec, Arc, ... will use its API.
 /// See the [heap_allocator] module for more info.
-#[cfg(not(test))]
+#[cfg(any(not(test), target_os = "none"))]
 #[global_allocator]
 static ALLOCATOR: heap_allocator::Allocator = heap_allocator::Allocator::new();
 
@@ -250,26 +258,43 @@ pub extern "C" fn common_start(multiboot_info_addr: usize) -> ! {
 
     log_impl::init();
 
+    panic::report::init();
+
     info!("Start ACPI detection");
     unsafe { i386::acpi::init(); }
 
+    info!("Enabling DMA remapping");
+    devices::iommu::init();
+
     info!("Allocating cpu_locals");
     init_cpu_locals(1);
 
     info!("Enabling interrupts");
     unsafe { i386::interrupt_service_routines::init(); }
 
+    i386::usercopy::init();
+
     devices::init_timer();
 
+    watchdog::init();
+
     //info!("Disable timer interrupt");
     //devices::pic::get().mask(0);
 
     info!("Becoming the first process");
     unsafe { scheduler::create_first_process() };
 
-    info!("Calling main()");
+    #[cfg(all(test, target_os = "none"))]
+    {
+        info!("Calling test_main()");
+        test_main();
+    }
 
-    main();
+    #[cfg(not(all(test, target_os = "none")))]
+    {
+        info!("Calling main()");
+        main();
+    }
     // Die !
     // We shouldn't reach this...
     loop {
@@ -3,14 +3,19 @@
 //! Contains definition for VirtualAddress and PhysicalAddress,
 //! and UserSpacePointer
 
-use core::ops::{Deref, DerefMut};
-use core::mem;
+use core::mem::{self, MaybeUninit};
 use core::fmt::{Formatter, Error, Display, Debug, LowerHex};
-use crate::error::KernelError;
+use alloc::vec::Vec;
+use crate::error::{KernelError, UserspaceError};
 use failure::Backtrace;
 use core::iter::Step;
+use sunrise_libkern::{MemoryState, MemoryPermissions, MemoryAttributes};
 
 use crate::paging::PAGE_SIZE;
+use crate::paging::lands::{UserLand, VirtualSpaceLand};
+use crate::paging::process_memory::ProcessMemory;
+use crate::i386::usercopy;
+use crate::scheduler;
 use crate::utils::{align_down, align_up, div_ceil};
 
 /// Rounds an address to its page address
@@ -183,6 +188,11 @@ impl VirtualAddress {
         self.0.checked_add(rhs).map(VirtualAddress)
     }
 
+    /// Tries to subtract an offset from a VirtualAddress, returning None if this would cause an underflow.
+    pub fn checked_sub(self, rhs: usize) -> Option<VirtualAddress> {
+        self.0.checked_sub(rhs).map(VirtualAddress)
+    }
+
     /// Checks that this address meets the given alignment.
     ///
     /// # Errors
@@ -214,20 +224,85 @@ unsafe impl core::iter::Step for VirtualAddress {
     fn backward_checked(start: Self, count: usize) -> Option<Self> { Step::backward_checked(start.0, count).map(VirtualAddress) }
 }
 
-// TODO: Properly implement UserSpacePtr
-// BODY: UserSpacePtr right now is just a glorified, horribly unsafe reference.
-// BODY: We should change its interface to provide a lot more safety. It should
-// BODY: have a get function returning a Result<T, UserspaceError> that copies
-// BODY: the underlying type, returning an error if the underlying pointer is
-// BODY: invalid (at least if it points to kernel memory. Maybe also if it is
-// BODY: not mapped?).
-// BODY:
-// BODY: We also have to handle unsized types. Maybe have a get_ref() which
-// BODY: returns a &T? Maybe don't allow unsized types? I should check how
-// BODY: Horizon/NX deals with it in sendsyncrequest (that's the only place that
-// BODY: allows huge data afaik)
-/// A pointer to read-only userspace memory. Prevents userspace from trying to
-/// use a syscall on kernel memory.
+/// Checks that the range `addr..addr + size` falls in UserLand, and is mapped
+/// in `pmemory` with at least the permissions `perms`.
+///
+/// The range may span several mappings. [ProcessMemory::check_range] wants
+/// homogeneous ranges, so it is called on the part of the range falling in
+/// each mapping.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///   - The range is not in UserLand, or a part of it is not mapped with
+///     `perms`.
+fn check_user_range(pmemory: &ProcessMemory, addr: usize, size: usize, perms: MemoryPermissions) -> Result<(), UserspaceError> {
+    if size == 0 {
+        return Ok(())
+    }
+    if !UserLand::contains_region(VirtualAddress(addr), size) {
+        return Err(UserspaceError::InvalidAddress)
+    }
+
+    // cannot overflow, checked it's in UserLand.
+    let end = addr + (size - 1);
+    let mut cur = addr;
+    loop {
+        let mapping_end = {
+            let query = pmemory.query_memory(VirtualAddress(cur));
+            let mapping = query.mapping();
+            mapping.address().addr() + (mapping.length() - 1)
+        };
+        let chunk_end = core::cmp::min(mapping_end, end);
+        pmemory.check_range(VirtualAddress(cur), chunk_end - cur + 1,
+            MemoryState::empty(), MemoryState::empty(),
+            perms, perms,
+            MemoryAttributes::empty(), MemoryAttributes::empty(),
+            MemoryAttributes::empty())
+            .map_err(|_| UserspaceError::InvalidAddress)?;
+        if chunk_end == end {
+            return Ok(())
+        }
+        cur = chunk_end + 1;
+    }
+}
+
+/// Copies `len` bytes between the kernel and the current process, after
+/// checking `user_addr..user_addr + len` is mapped with `perms` in the current
+/// process. `user_addr` is either `dst` or `src`.
+///
+/// The process' memory stays locked during the copy, so it cannot be unmapped
+/// under our feet. Lazy pages in the range are committed first, the kernel
+/// cannot fault them in.
+///
+/// # Safety
+///
+/// The kernel side of the copy must be valid for `len` bytes.
+unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize, user_addr: usize, perms: MemoryPermissions) -> Result<(), UserspaceError> {
+    if len == 0 {
+        return Ok(())
+    }
+    let process = scheduler::get_current_process();
+    let mut pmemory = process.pmemory.lock();
+    check_user_range(&pmemory, user_addr, len, perms)?;
+    pmemory.commit(VirtualAddress(user_addr), len)?;
+    usercopy::copy(dst, src, len)
+}
+
+/// Size in bytes of a slice of `len` `T`s.
+fn slice_size<T>(len: usize) -> Result<usize, UserspaceError> {
+    len.checked_mul(mem::size_of::<T>()).ok_or(UserspaceError::InvalidAddress)
+}
+
+/// A pointer to read-only userspace memory.
+///
+/// It cannot be dereferenced. Its content is copied to the kernel with
+/// [read](UserSpacePtr::read) or [read_to_vec](UserSpacePtr::read_to_vec),
+/// which check it points to memory readable by the current process, and
+/// return `InvalidAddress` instead of faulting if it doesn't.
+///
+/// The content is copied bit for bit, so `T` must be valid for any bit
+/// pattern.
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct UserSpacePtr<T: ?Sized>(pub *const T);
@@ -250,20 +325,77 @@ impl<I> UserSpacePtr<[I]> {
             }))
         }
     }
+
+    /// Returns the number of elements of the slice.
+    pub fn len(self) -> usize {
+        unsafe {
+            // safe: a slice pointer is a fat pointer.
+            mem::transmute::<*const [I], FatPtr>(self.0).len
+        }
+    }
+
+    /// Returns true if the slice has no elements.
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the address of the first element of the slice.
+    pub fn as_ptr(self) -> *const I {
+        self.0 as *const I
+    }
 }
 
-impl<T: ?Sized> Deref for UserSpacePtr<T> {
-    type Target = T;
+impl<T: Copy> UserSpacePtr<T> {
+    /// Copies the pointed value.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidAddress`
+    ///   - The value is not in memory readable by the current process.
+    pub fn read(self) -> Result<T, UserspaceError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            // safe: value is big enough. It is only assumed init if the copy succeeded.
+            copy_user(value.as_mut_ptr() as *mut u8, self.0 as *const u8, mem::size_of::<T>(),
+                self.0 as *const u8 as usize, MemoryPermissions::READABLE)?;
+            Ok(value.assume_init())
+        }
+    }
+}
 
-    fn deref(&self) -> &T {
+impl<T: Copy> UserSpacePtr<[T]> {
+    /// Copies the pointed slice to a Vec.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidAddress`
+    ///   - The slice is not in memory readable by the current process.
+    pub fn read_to_vec(self) -> Result<Vec<T>, UserspaceError> {
+        let len = self.len();
+        let size = slice_size::<T>(len)?;
+        {
+            // Check before allocating, a bogus length could exhaust the heap.
+            let process = scheduler::get_current_process();
+            check_user_range(&process.pmemory.lock(), self.as_ptr() as usize, size, MemoryPermissions::READABLE)?;
+        }
+        let mut vec = Vec::with_capacity(len);
         unsafe {
-            &*self.0
+            // safe: vec has the capacity, and is only set_len if the copy succeeded.
+            copy_user(vec.as_mut_ptr() as *mut u8, self.as_ptr() as *const u8, size,
+                self.as_ptr() as usize, MemoryPermissions::READABLE)?;
+            vec.set_len(len);
         }
+        Ok(vec)
     }
 }
 
-/// A pointer to read-write userspace memory. Prevents userspace from trying to
-/// use a syscall on kernel memory.
+/// A pointer to read-write userspace memory.
+///
+/// Like [UserSpacePtr], it cannot be dereferenced. Values are copied to
+/// userspace with [write](UserSpacePtrMut::write) or
+/// [write_from_slice](UserSpacePtrMut::write_from_slice), which check it
+/// points to memory writable by the current process, and return
+/// `InvalidAddress` instead of faulting if it doesn't.
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct UserSpacePtrMut<T: ?Sized>(pub *mut T);
@@ -279,6 +411,37 @@ impl<I> UserSpacePtrMut<[I]> {
             }))
         }
     }
+
+    /// Returns the number of elements of the slice.
+    pub fn len(self) -> usize {
+        UserSpacePtr(self.0 as *const [I]).len()
+    }
+
+    /// Returns true if the slice has no elements.
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the address of the first element of the slice.
+    pub fn as_mut_ptr(self) -> *mut I {
+        self.0 as *mut I
+    }
+
+    /// Checks the slice is in memory readable and writable by the current
+    /// process, without copying anything.
+    ///
+    /// Useful to fail early, before doing something that can't be undone.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidAddress`
+    ///   - The slice is not in memory writable by the current process.
+    pub fn check(self) -> Result<(), UserspaceError> {
+        let size = slice_size::<I>(self.len())?;
+        let process = scheduler::get_current_process();
+        let pmemory = process.pmemory.lock();
+        check_user_range(&pmemory, self.as_mut_ptr() as usize, size, MemoryPermissions::RW)
+    }
 }
 
 impl<T: ?Sized> Clone for UserSpacePtrMut<T> {
@@ -288,27 +451,51 @@ impl<T: ?Sized> Clone for UserSpacePtrMut<T> {
 }
 impl<T: ?Sized> Copy for UserSpacePtrMut<T> {}
 
-impl<T: ?Sized> Deref for UserSpacePtrMut<T> {
-    type Target = T;
-
-    fn deref(&self) -> &T {
+impl<T: Copy> UserSpacePtrMut<T> {
+    /// Copies `value` to the pointed memory.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidAddress`
+    ///   - The value is not in memory writable by the current process.
+    pub fn write(self, value: T) -> Result<(), UserspaceError> {
         unsafe {
-            &*self.0
+            // safe: value is a T.
+            copy_user(self.0 as *mut u8, &value as *const T as *const u8, mem::size_of::<T>(),
+                self.0 as *mut u8 as usize, MemoryPermissions::RW)
         }
     }
 }
 
-impl<T: ?Sized> DerefMut for UserSpacePtrMut<T> {
-    fn deref_mut(&mut self) -> &mut T {
+impl<T: Copy> UserSpacePtrMut<[T]> {
+    /// Copies `src` to the start of the pointed slice.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidSize`
+    ///   - `src` is longer than the pointed slice.
+    /// - `InvalidAddress`
+    ///   - The slice is not in memory writable by the current process.
+    pub fn write_from_slice(self, src: &[T]) -> Result<(), UserspaceError> {
+        if src.len() > self.len() {
+            return Err(UserspaceError::InvalidSize)
+        }
         unsafe {
-            &mut *self.0
+            // safe: src is valid for its length.
+            copy_user(self.as_mut_ptr() as *mut u8, src.as_ptr() as *const u8, slice_size::<T>(src.len())?,
+                self.as_mut_ptr() as usize, MemoryPermissions::RW)
         }
     }
+
+    /// Copies the pointed slice to a Vec. See [UserSpacePtr::read_to_vec].
+    pub fn read_to_vec(self) -> Result<Vec<T>, UserspaceError> {
+        UserSpacePtr::from(self).read_to_vec()
+    }
 }
 
-impl<T> Into<UserSpacePtr<T>> for UserSpacePtrMut<T> {
-    fn into(self) -> UserSpacePtr<T> {
-        UserSpacePtr(self.0)
+impl<T: ?Sized> From<UserSpacePtrMut<T>> for UserSpacePtr<T> {
+    fn from(ptr: UserSpacePtrMut<T>) -> UserSpacePtr<T> {
+        UserSpacePtr(ptr.0)
     }
 }
 
@@ -48,7 +48,7 @@ pub unsafe fn enable_paging(page_directory_address: PhysicalAddress) {
 
 /// Flush the Translation Lookaside Buffer [https://wiki.osdev.org/TLB]
 fn flush_tlb() {
-    #[cfg(not(test))]
+    #[cfg(any(not(test), target_os = "none"))]
     unsafe {
         llvm_asm!("mov eax, cr3
           mov cr3, eax  "
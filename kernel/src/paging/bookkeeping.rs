@@ -3,9 +3,9 @@
 use crate::mem::VirtualAddress;
 use crate::paging::lands::{UserLand, KernelLand, RecursiveTablesLand, VirtualSpaceLand};
 use crate::paging::mapping::MappingFrames;
-use crate::paging::MappingAccessRights;
+use crate::paging::{MappingAccessRights, PAGE_SIZE};
 use sunrise_libkern::MemoryType;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 use crate::error::KernelError;
 use crate::utils::check_nonzero_length;
 use failure::Backtrace;
@@ -210,4 +210,119 @@ impl UserspaceBookkeeping {
         }
         Err(KernelError::VirtualMemoryExhaustion { backtrace: Backtrace::new() })
     }
+
+    /// Returns the mappings of this address space, in ascending address order,
+    /// excluding the SystemReserved regions.
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.values()
+            .filter(|m| m.state().ty() != MemoryType::Reserved)
+    }
+
+    /// Returns the total length of the mappings of this address space,
+    /// excluding the SystemReserved regions and the uncommitted pages of lazy
+    /// mappings.
+    ///
+    /// A page of shared or lazy frames mapped several times, like the heap
+    /// pages mirrored by a stack, is only counted once.
+    pub fn used_memory_size(&self) -> usize {
+        // (frames, page index in the frames) of the shared pages counted so far.
+        let mut shared_pages = BTreeSet::new();
+        let mut size = 0;
+        for m in self.mappings.values().filter(|m| m.state().ty() != MemoryType::Reserved) {
+            let first_page = m.phys_offset() / PAGE_SIZE;
+            let pages = first_page..first_page + m.length() / PAGE_SIZE;
+            match m.frames() {
+                MappingFrames::Shared(frames) => {
+                    let id = &**frames as *const _ as usize;
+                    size += pages.filter(|page| shared_pages.insert((id, *page))).count() * PAGE_SIZE;
+                },
+                MappingFrames::Lazy(frames) => {
+                    let id = &**frames as *const _ as usize;
+                    let frames = frames.read();
+                    size += pages.filter(|page| frames[*page].is_some() && shared_pages.insert((id, *page)))
+                        .count() * PAGE_SIZE;
+                },
+                _ => size += m.length(),
+            }
+        }
+        size
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::frame_allocator::{FrameAllocator, FrameAllocatorTrait};
+    use crate::sync::SpinRwLock;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    /// Creates a Normal mapping of `length` bytes at `address`, backed by fresh frames.
+    fn normal_mapping(address: usize, length: usize) -> Mapping {
+        let frames = FrameAllocator::allocate_frames_fragmented(length).unwrap();
+        Mapping::new(VirtualAddress(address), MappingFrames::Owned(frames), 0, length, MemoryType::Normal, MappingAccessRights::u_rw())
+            .unwrap()
+    }
+
+    #[test_case]
+    fn add_query_remove_mapping() {
+        let mut bookkeeping = UserspaceBookkeeping::new();
+        bookkeeping.add_mapping(normal_mapping(0x400000, 2 * PAGE_SIZE)).unwrap();
+
+        match bookkeeping.mapping_at(VirtualAddress(0x401000)) {
+            QueryMemory::Used(m) => assert_eq!(m.address(), VirtualAddress(0x400000)),
+            QueryMemory::Available(_) => panic!("Mapping is not tracked"),
+        }
+        match bookkeeping.mapping_at(VirtualAddress(0x402000)) {
+            QueryMemory::Available(m) => assert_eq!(m.address(), VirtualAddress(0x402000)),
+            QueryMemory::Used(_) => panic!("Hole is reported as used"),
+        }
+
+        bookkeeping.add_mapping(normal_mapping(0x401000, PAGE_SIZE)).unwrap_err();
+        bookkeeping.remove_mapping(VirtualAddress(0x400000), PAGE_SIZE).unwrap_err();
+        bookkeeping.remove_mapping(VirtualAddress(0x401000), PAGE_SIZE).unwrap_err();
+        bookkeeping.remove_mapping(VirtualAddress(0x400000), 2 * PAGE_SIZE).unwrap();
+        assert!(bookkeeping.is_vacant(VirtualAddress(0x400000), 2 * PAGE_SIZE).unwrap());
+        assert_eq!(bookkeeping.mappings().count(), 0);
+    }
+
+    #[test_case]
+    fn find_available_space_skips_mappings() {
+        let mut bookkeeping = UserspaceBookkeeping::new();
+        let address = bookkeeping.find_available_space(PAGE_SIZE).unwrap();
+        assert_eq!(address, UserLand::START);
+        bookkeeping.add_mapping(normal_mapping(address.addr(), PAGE_SIZE)).unwrap();
+        let next = bookkeeping.find_available_space(PAGE_SIZE).unwrap();
+        assert_eq!(next, address + PAGE_SIZE);
+        bookkeeping.find_available_space(0).unwrap_err();
+    }
+
+    #[test_case]
+    fn used_memory_size_counts_shared_pages_once() {
+        let mut bookkeeping = UserspaceBookkeeping::new();
+        bookkeeping.add_mapping(normal_mapping(0x400000, PAGE_SIZE)).unwrap();
+
+        let shared = Arc::new(SpinRwLock::new(FrameAllocator::allocate_frames_fragmented(2 * PAGE_SIZE).unwrap()));
+        let flags = MappingAccessRights::u_rw();
+        bookkeeping.add_mapping(Mapping::new(VirtualAddress(0x500000), MappingFrames::Shared(shared.clone()), 0, 2 * PAGE_SIZE, MemoryType::SharedMemory, flags).unwrap()).unwrap();
+        bookkeeping.add_mapping(Mapping::new(VirtualAddress(0x600000), MappingFrames::Shared(shared), PAGE_SIZE, PAGE_SIZE, MemoryType::SharedMemory, flags).unwrap()).unwrap();
+
+        assert_eq!(bookkeeping.used_memory_size(), 3 * PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn used_memory_size_ignores_uncommitted_lazy_pages() {
+        let mut bookkeeping = UserspaceBookkeeping::new();
+        let mut frames = Vec::new();
+        frames.resize_with(3, || None);
+        frames[1] = Some(FrameAllocator::allocate_frame().unwrap());
+        let frames = Arc::new(SpinRwLock::new(frames));
+        let flags = MappingAccessRights::u_rw();
+        bookkeeping.add_mapping(Mapping::new(VirtualAddress(0x400000), MappingFrames::Lazy(frames.clone()), 0, 3 * PAGE_SIZE, MemoryType::Heap, flags).unwrap()).unwrap();
+        assert_eq!(bookkeeping.used_memory_size(), PAGE_SIZE);
+
+        // Mirroring the committed page does not count it twice.
+        bookkeeping.add_mapping(Mapping::new(VirtualAddress(0x500000), MappingFrames::Lazy(frames), PAGE_SIZE, PAGE_SIZE, MemoryType::Stack, flags).unwrap()).unwrap();
+        assert_eq!(bookkeeping.used_memory_size(), PAGE_SIZE);
+    }
 }
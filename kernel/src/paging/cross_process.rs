@@ -62,6 +62,7 @@ impl CrossProcessMapping {
     /// # Error
     ///
     /// * Error if the mapping is not Shared, as only refcounted mappings can be owned.
+    /// * Error if the mapping is Lazy, and the range is not committed.
     /// * Error if `offset` + `len` > `mapping` length.
     /// * Error if `offset` + `len` would overflow.
     // todo: should be offset + (len - 1), but need to check that it wouldn't overflow in our function
@@ -71,15 +72,24 @@ impl CrossProcessMapping {
     ///
     /// * Panics if `mapping.phys_offset()` + `offset` overflows.
     pub fn mirror_mapping(mapping: &Mapping, offset: usize, len: usize) -> Result<CrossProcessMapping, KernelError> {
-        // Ensure we have Shared frames.
+        // Get the full page length required for this mapping.
+        let full_len = align_up((offset % PAGE_SIZE) + len, PAGE_SIZE);
+
+        // Ensure we have Shared frames, or committed Lazy ones.
         let frames = match mapping.frames() {
             MappingFrames::Shared(frames) => MappingFrames::Shared(frames.clone()),
+            MappingFrames::Lazy(frames) => {
+                let first_page = (mapping.phys_offset() + align_down(offset, PAGE_SIZE)) / PAGE_SIZE;
+                let committed = frames.read().iter().skip(first_page).take(full_len / PAGE_SIZE)
+                    .all(|frame| frame.is_some());
+                if !committed {
+                    return Err(KernelError::InvalidMemState { address: mapping.address() + offset, ty: mapping.state().ty(), backtrace: Backtrace::new() })
+                }
+                MappingFrames::Lazy(frames.clone())
+            },
             _ => return Err(KernelError::InvalidMemState { address: mapping.address(), ty: mapping.state().ty(), backtrace: Backtrace::new() })
         };
 
-        // Get the full page length required for this mapping.
-        let full_len = align_up((offset % PAGE_SIZE) + len, PAGE_SIZE);
-
         let mut kmem = get_kernel_memory();
         let kernel_map_start = kmem.find_virtual_space(full_len)?;
 
@@ -0,0 +1,349 @@
+//! Device address spaces
+//!
+//! A device address space is the address space a PCI device sees when doing
+//! DMAs. It is made of the second-level page tables of the IOMMU, see
+//! [crate::devices::iommu], and starts empty: a driver attaches its devices to
+//! it, and then maps there the memory of a process it wants them to access.
+//!
+//! The mapped frames are kept alive by the device address space until they
+//! are unmapped from it, even if the process unmaps them or dies, so a device
+//! can never DMA to a frame that has been reused.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use failure::Backtrace;
+use sunrise_libkern::{MemoryState, MemoryPermissions, MemoryAttributes};
+
+use crate::devices::iommu::{self, TablePage, SL_READ, SL_WRITE, SL_LEVELS, SL_ADDRESS_WIDTH};
+use crate::error::KernelError;
+use crate::mem::VirtualAddress;
+use crate::paging::PAGE_SIZE;
+use crate::paging::mapping::{Mapping, MappingFrames};
+use crate::process::ProcessStruct;
+use crate::sync::Mutex;
+
+/// Number of bits of the address each level of the page tables translates.
+const LEVEL_BITS: usize = 9;
+
+/// A second-level page table, and the tables its entries point to.
+#[derive(Debug)]
+struct PageTable {
+    /// The entries, read by the IOMMU.
+    page: TablePage,
+    /// The tables pointed to by the entries, by index. Always empty in the
+    /// last level.
+    children: BTreeMap<usize, PageTable>,
+}
+
+impl PageTable {
+    /// Allocates an empty table.
+    fn new() -> Result<PageTable, KernelError> {
+        Ok(PageTable { page: TablePage::new()?, children: BTreeMap::new() })
+    }
+
+    /// Index of the entry translating `address` in a table of the given level,
+    /// 1 being the last one.
+    fn index(level: usize, address: usize) -> usize {
+        (address >> (12 + LEVEL_BITS * (level - 1))) & ((1 << LEVEL_BITS) - 1)
+    }
+
+    /// Sets the last level entry translating `address`, allocating the
+    /// intermediate tables as needed.
+    ///
+    /// # Errors
+    ///
+    /// * `PhysicalMemoryExhaustion`: a table could not be allocated.
+    fn set(&mut self, level: usize, address: usize, entry: u64) -> Result<(), KernelError> {
+        let index = Self::index(level, address);
+        if level == 1 {
+            self.page.write(index, entry);
+            return Ok(());
+        }
+        if !self.children.contains_key(&index) {
+            let child = PageTable::new()?;
+            // Permissions are enforced by the last level.
+            self.page.write(index, child.page.frame().addr() as u64 | SL_READ | SL_WRITE);
+            self.children.insert(index, child);
+        }
+        self.children.get_mut(&index).unwrap().set(level - 1, address, entry)
+    }
+
+    /// Clears the last level entry translating `address`, if any.
+    ///
+    /// The intermediate tables are kept, they are freed with the address space.
+    fn clear(&mut self, level: usize, address: usize) {
+        let index = Self::index(level, address);
+        if level == 1 {
+            self.page.write(index, 0);
+        } else if let Some(child) = self.children.get_mut(&index) {
+            child.clear(level - 1, address);
+        }
+    }
+}
+
+/// A range of a process' memory mapped in a device address space.
+#[derive(Debug)]
+struct DeviceMapping {
+    /// The pid of the process the memory was mapped from.
+    pid: usize,
+    /// The address the memory was mapped from in the process.
+    process_address: VirtualAddress,
+    /// A copy of the mapped part of the process mapping, keeping the frames
+    /// alive. Its address is meaningless.
+    mapping: Mapping,
+}
+
+/// The mutable part of a [DeviceAddressSpace].
+#[derive(Debug)]
+struct DeviceAddressSpaceInner {
+    /// The root of the page tables.
+    root: PageTable,
+    /// The mappings, by device address.
+    mappings: BTreeMap<usize, DeviceMapping>,
+    /// The requester ids of the attached devices.
+    devices: Vec<u16>,
+}
+
+impl DeviceAddressSpaceInner {
+    /// Clears the entries of `length` bytes starting at `device_address`.
+    fn clear(&mut self, device_address: usize, length: usize) {
+        for page in (device_address..device_address + length).step_by(PAGE_SIZE) {
+            self.root.clear(SL_LEVELS, page);
+        }
+    }
+}
+
+/// The address space of the devices attached to it, translated by the IOMMU.
+///
+/// Only the range given at creation can be mapped.
+#[derive(Debug)]
+pub struct DeviceAddressSpace {
+    /// The first device address of the range.
+    address: usize,
+    /// The size of the range.
+    size: usize,
+    /// The IOMMU domain id tagging the translations of this address space.
+    domain: u16,
+    /// The page tables and mappings.
+    inner: Mutex<DeviceAddressSpaceInner>,
+}
+
+impl DeviceAddressSpace {
+    /// Creates an empty device address space, where only `[address; address + size)`
+    /// can be mapped.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`: `address` is not page aligned.
+    /// * `InvalidSize`:
+    ///     * `size` is zero or not page aligned.
+    ///     * the range ends past the addresses the page tables can translate.
+    /// * `NotImplemented`: the system has no IOMMU.
+    /// * `ExceedingMaximum`: the IOMMU ran out of domain ids.
+    /// * `PhysicalMemoryExhaustion`: the root table could not be allocated.
+    pub fn new(address: usize, size: usize) -> Result<DeviceAddressSpace, KernelError> {
+        VirtualAddress(address).check_aligned_to(PAGE_SIZE)?;
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(KernelError::InvalidSize { size, backtrace: Backtrace::new() });
+        }
+        let end = (address as u64).checked_add(size as u64);
+        if end.map_or(true, |end| end > 1 << SL_ADDRESS_WIDTH) {
+            return Err(KernelError::InvalidSize { size, backtrace: Backtrace::new() });
+        }
+
+        let root = PageTable::new()?;
+        let domain = iommu::allocate_domain()?;
+        Ok(DeviceAddressSpace {
+            address,
+            size,
+            domain,
+            inner: Mutex::new(DeviceAddressSpaceInner {
+                root,
+                mappings: BTreeMap::new(),
+                devices: Vec::new(),
+            }),
+        })
+    }
+
+    /// Whether `[address; address + size)` is part of the range of this
+    /// address space.
+    pub fn contains(&self, address: usize, size: usize) -> bool {
+        address >= self.address && size <= self.size && address - self.address <= self.size - size
+    }
+
+    /// Makes the DMAs of the PCI device with the given requester id go through
+    /// this address space.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`: no IOMMU translates the DMAs of this device.
+    /// * `InvalidState`: the device is already attached to an address space.
+    /// * `PhysicalMemoryExhaustion`: the IOMMU could not allocate its tables.
+    pub fn attach(&self, device: u16) -> Result<(), KernelError> {
+        let mut inner = self.inner.lock();
+        iommu::attach(device, self.domain, inner.root.page.frame())?;
+        inner.devices.push(device);
+        Ok(())
+    }
+
+    /// Blocks the DMAs of the PCI device with the given requester id.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidState`: the device is not attached to this address space.
+    pub fn detach(&self, device: u16) -> Result<(), KernelError> {
+        let mut inner = self.inner.lock();
+        let index = inner.devices.iter().position(|&attached| attached == device)
+            .ok_or(KernelError::InvalidState { backtrace: Backtrace::new() })?;
+        iommu::detach(device, self.domain)?;
+        inner.devices.swap_remove(index);
+        Ok(())
+    }
+
+    /// Maps `size` bytes of the memory of `process` at `process_address` in
+    /// this address space at `device_address`, accessible with `perms`.
+    ///
+    /// The memory must have all the flags of `required_state`, and at least
+    /// the permissions `perms`. It is committed if it is lazily allocated.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`:
+    ///     * `process_address` or `device_address` is not page aligned.
+    ///     * the range does not fall in UserLand.
+    /// * `InvalidSize`: `size` is zero or not page aligned.
+    /// * `InvalidMemState`: the memory doesn't have the required state or
+    ///   permissions.
+    /// * `InvalidState`: part of the device range is already mapped.
+    /// * `PhysicalMemoryExhaustion`: the memory could not be committed, or a
+    ///   page table could not be allocated.
+    pub fn map(&self, process: &ProcessStruct, process_address: VirtualAddress, size: usize,
+               device_address: usize, perms: MemoryPermissions, required_state: MemoryState) -> Result<(), KernelError> {
+        process_address.check_aligned_to(PAGE_SIZE)?;
+        VirtualAddress(device_address).check_aligned_to(PAGE_SIZE)?;
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(KernelError::InvalidSize { size, backtrace: Backtrace::new() });
+        }
+        process_address.checked_add(size)
+            .ok_or(KernelError::InvalidAddress { address: process_address.addr(), backtrace: Backtrace::new() })?;
+
+        let mut pmem = process.pmemory.lock();
+        pmem.check_range(process_address, size,
+            required_state, required_state,
+            perms, perms,
+            MemoryAttributes::empty(), MemoryAttributes::empty(),
+            MemoryAttributes::empty())?;
+        pmem.commit(process_address, size)?;
+
+        let mut inner = self.inner.lock();
+        let overlapping = inner.mappings.range(..device_address + size).next_back()
+            .map_or(false, |(&address, mapping)| address + mapping.mapping.length() > device_address);
+        if overlapping {
+            return Err(KernelError::InvalidState { backtrace: Backtrace::new() });
+        }
+
+        // Keep the frames of every block of process memory in the range.
+        let mut mappings = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let address = process_address + offset;
+            let query = pmem.query_memory(address);
+            let block = query.mapping();
+            let length = core::cmp::min(block.length() - (address - block.address()), size - offset);
+            let frames = match block.frames() {
+                MappingFrames::Shared(frames) => MappingFrames::Shared(frames.clone()),
+                MappingFrames::Lazy(frames) => MappingFrames::Lazy(frames.clone()),
+                _ => return Err(KernelError::InvalidMemState { address, ty: block.state().ty(), backtrace: Backtrace::new() })
+            };
+            let phys_offset = block.phys_offset() + (address - block.address());
+            let mapping = Mapping::new(address, frames, phys_offset, length, block.state().ty(), block.flags())?;
+            mappings.push((device_address + offset, DeviceMapping { pid: process.pid, process_address: address, mapping }));
+            offset += length;
+        }
+        drop(pmem);
+
+        let mut entry_perms = 0;
+        if perms.contains(MemoryPermissions::READABLE) {
+            entry_perms |= SL_READ;
+        }
+        if perms.contains(MemoryPermissions::WRITABLE) {
+            entry_perms |= SL_WRITE;
+        }
+
+        let mut result = Ok(());
+        let mut mapped = 0;
+        'mappings: for (address, mapping) in mappings.iter() {
+            for (index, frame) in mapping.mapping.frames_it().enumerate() {
+                result = inner.root.set(SL_LEVELS, address + index * PAGE_SIZE, frame.addr() as u64 | entry_perms);
+                if result.is_err() {
+                    break 'mappings;
+                }
+                mapped += PAGE_SIZE;
+            }
+        }
+        if let Err(err) = result {
+            inner.clear(device_address, mapped);
+            iommu::flush_domain(self.domain);
+            return Err(err);
+        }
+
+        inner.mappings.extend(mappings);
+        // Caching hardware may remember the entries weren't present.
+        iommu::flush_domain(self.domain);
+        Ok(())
+    }
+
+    /// Unmaps `size` bytes at `device_address`, which must have been mapped
+    /// from the memory of `process` at `process_address`, in one or more
+    /// calls to [map](DeviceAddressSpace::map) covering exactly this range.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidSize`: `size` is zero.
+    /// * `InvalidMemState`: the range doesn't match the mappings.
+    pub fn unmap(&self, process: &ProcessStruct, process_address: VirtualAddress, size: usize,
+                 device_address: usize) -> Result<(), KernelError> {
+        if size == 0 {
+            return Err(KernelError::InvalidSize { size, backtrace: Backtrace::new() });
+        }
+        let mismatch = || KernelError::InvalidMemState {
+            address: process_address,
+            ty: sunrise_libkern::MemoryType::Unmapped,
+            backtrace: Backtrace::new()
+        };
+
+        let mut inner = self.inner.lock();
+        let mut offset = 0;
+        while offset < size {
+            let mapping = inner.mappings.get(&(device_address + offset)).ok_or_else(mismatch)?;
+            if mapping.pid != process.pid || mapping.process_address != process_address + offset
+                || mapping.mapping.length() > size - offset {
+                return Err(mismatch());
+            }
+            offset += mapping.mapping.length();
+        }
+
+        inner.clear(device_address, size);
+        let mut offset = 0;
+        while offset < size {
+            let mapping = inner.mappings.remove(&(device_address + offset)).unwrap();
+            offset += mapping.mapping.length();
+        }
+        // The frames must not be reused before the devices forgot about them.
+        iommu::flush_domain(self.domain);
+        Ok(())
+    }
+}
+
+impl Drop for DeviceAddressSpace {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        for device in inner.devices.drain(..) {
+            if let Err(err) = iommu::detach(device, self.domain) {
+                error!("Failed to detach device {:#06x}: {:?}", device, err);
+            }
+        }
+        iommu::free_domain(self.domain);
+        // The tables and frames are freed once all devices are detached.
+    }
+}
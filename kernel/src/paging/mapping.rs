@@ -62,6 +62,13 @@ pub enum MappingFrames {
     Shared(Arc<SpinRwLock<Vec<PhysicalMemRegion>>>),
     /// The frames are Owned by this mapping.
     Owned(Vec<PhysicalMemRegion>),
+    /// The frames are Shared between multiple mappings, and only allocated on
+    /// first access, see [ProcessMemory::commit].
+    ///
+    /// Holds one entry per page, which stays `None` until the page is committed.
+    ///
+    /// [ProcessMemory::commit]: crate::paging::process_memory::ProcessMemory::commit
+    Lazy(Arc<SpinRwLock<Vec<Option<PhysicalMemRegion>>>>),
     /// This Mapping has no frames.
     None,
 }
@@ -91,6 +98,7 @@ impl Mapping {
         let frames_len = match &frames {
             MappingFrames::Owned(v) => v.iter().flatten().count() * PAGE_SIZE,
             MappingFrames::Shared(v) => v.read().iter().flatten().count() * PAGE_SIZE,
+            MappingFrames::Lazy(v) => v.read().len() * PAGE_SIZE,
             MappingFrames::None => usize::max_value()
         };
 
@@ -111,6 +119,7 @@ impl Mapping {
             (MappingFrames::None, _, MemoryType::Reserved) => (),
             (MappingFrames::None, _, MemoryType::KernelStack) => (),
             (MappingFrames::Shared(_), true, _) => (),
+            (MappingFrames::Lazy(_), true, _) => (),
             (MappingFrames::Owned(_), false, _) => (),
             _ => return Err(KernelError::WrongMappingFramesForTy { ty, backtrace: Backtrace::new() })
         }
@@ -128,12 +137,31 @@ impl Mapping {
     /// Because we make guarantees about a mapping being always valid, this field cannot be public.
     pub fn length(&self) -> usize { self.length }
 
+    /// Returns the length of this mapping that is backed by memory. This is
+    /// [length](Mapping::length), minus the pages of a lazy mapping that were
+    /// never committed.
+    pub fn committed_length(&self) -> usize {
+        match &self.frames {
+            MappingFrames::Lazy(frames) => {
+                let first = self.offset / PAGE_SIZE;
+                frames.read()[first..first + self.length / PAGE_SIZE].iter()
+                    .filter(|frame| frame.is_some())
+                    .count() * PAGE_SIZE
+            },
+            _ => self.length
+        }
+    }
+
     /// Returns the frames in this mapping.
     pub fn frames(&self) -> &MappingFrames { &self.frames }
 
     /// Returns an iterator over the Physical Addresses mapped by this region.
     /// This takes into account the physical offset and the length of the
     /// mapping.
+    ///
+    /// # Panics
+    ///
+    /// Panics when reaching a page of a Lazy mapping that wasn't committed yet.
     pub fn frames_it(&self) -> impl Iterator<Item = PhysicalAddress> + Clone + core::fmt::Debug + '_ {
         /// Anonymous iterator over mapping frames' PhysicalAddresses.
         #[derive(Debug)]
@@ -142,6 +170,7 @@ impl Mapping {
             None,
             Owned(&'a [PhysicalMemRegion], usize, StepBy<Range<usize>>),
             Shared(&'a Arc<SpinRwLock<Vec<PhysicalMemRegion>>>, SpinRwLockReadGuard<'a, Vec<PhysicalMemRegion>>, usize, StepBy<Range<usize>>),
+            Lazy(&'a Arc<SpinRwLock<Vec<Option<PhysicalMemRegion>>>>, SpinRwLockReadGuard<'a, Vec<Option<PhysicalMemRegion>>>, usize),
         }
         impl<'a> Iterator for MappingFramesIt<'a> {
             type Item = PhysicalAddress;
@@ -153,6 +182,12 @@ impl Mapping {
                     MappingFramesIt::Shared(_, frames, ref mut curframe, ref mut rangeit) => {
                         (&***frames, curframe, rangeit)
                     },
+                    MappingFramesIt::Lazy(_, frames, ref mut curpage) => {
+                        // Lazy frames always are a single page.
+                        let frame = frames.get(*curpage)?;
+                        *curpage += 1;
+                        return Some(frame.as_ref().expect("Iterating over an uncommitted lazy page").address())
+                    },
                     _ => return None
                 };
 
@@ -174,6 +209,7 @@ impl Mapping {
                 match self {
                     MappingFramesIt::Owned(frames, curframe, rangeit) => MappingFramesIt::Owned(frames, *curframe, rangeit.clone()),
                     MappingFramesIt::Shared(frames, _lock, curframe, rangeit) => MappingFramesIt::Shared(frames, frames.read(), *curframe, rangeit.clone()),
+                    MappingFramesIt::Lazy(frames, _lock, curpage) => MappingFramesIt::Lazy(frames, frames.read(), *curpage),
                     MappingFramesIt::None => MappingFramesIt::None,
                 }
             }
@@ -185,10 +221,16 @@ impl Mapping {
         let it = match self.frames() {
             MappingFrames::Owned(frames) => MappingFramesIt::Owned(&frames[..], 0, (0..0).step_by(1)),
             MappingFrames::Shared(frames) => MappingFramesIt::Shared(frames, frames.read(), 0, (0..0).step_by(1)),
+            // Directly start at the offset, skipping would look at the pages before it.
+            MappingFrames::Lazy(frames) => MappingFramesIt::Lazy(frames, frames.read(), self.phys_offset() / PAGE_SIZE),
             MappingFrames::None => MappingFramesIt::None,
         };
+        let skipped = match self.frames() {
+            MappingFrames::Lazy(_) => 0,
+            _ => self.phys_offset() / PAGE_SIZE
+        };
         it
-            .skip(self.phys_offset() / PAGE_SIZE)
+            .skip(skipped)
             .take(self.length() / PAGE_SIZE)
     }
 
@@ -208,7 +250,7 @@ impl Mapping {
     pub fn flags(&self) -> MappingAccessRights { self.flags }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod test {
     use super::Mapping;
     use super::MappingAccessRights;
@@ -291,6 +333,32 @@ mod test {
         let _mapping = Mapping::new(VirtualAddress(0x40000000), MappingFrames::Shared(frames), 0, 2 * PAGE_SIZE, MemoryType::Stack, flags).unwrap();
     }
 
+    #[test]
+    fn mapping_lazy_ok() {
+        let mut frames = Vec::new();
+        frames.resize_with(2, || None);
+        let flags = MappingAccessRights::u_rw();
+        let mapping = Mapping::new(VirtualAddress(0x40000000), MappingFrames::Lazy(Arc::new(SpinRwLock::new(frames))), 0, 2 * PAGE_SIZE, MemoryType::Heap, flags).unwrap();
+        assert_eq!(mapping.length(), 2 * PAGE_SIZE);
+        assert_eq!(mapping.committed_length(), 0);
+    }
+
+    #[test]
+    fn mapping_lazy_committed_length() {
+        let _f = crate::frame_allocator::init();
+        let frames = vec![None, Some(FrameAllocator::allocate_frame().unwrap()), None];
+        let flags = MappingAccessRights::u_rw();
+        let mapping = Mapping::new(VirtualAddress(0x40000000), MappingFrames::Lazy(Arc::new(SpinRwLock::new(frames))), PAGE_SIZE, 2 * PAGE_SIZE, MemoryType::Heap, flags).unwrap();
+        assert_eq!(mapping.committed_length(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn mapping_lazy_too_short() {
+        let frames = vec![None];
+        let flags = MappingAccessRights::u_rw();
+        let _mapping_err = Mapping::new(VirtualAddress(0x40000000), MappingFrames::Lazy(Arc::new(SpinRwLock::new(frames))), 0, 2 * PAGE_SIZE, MemoryType::Heap, flags).unwrap_err();
+    }
+
     #[test]
     fn mapping_regular_empty_vec() {
         let _f = crate::frame_allocator::init();
@@ -21,6 +21,8 @@ pub mod kernel_memory;
 pub mod lands;
 pub mod mapping;
 pub mod cross_process;
+pub mod device_address_space;
+pub mod shared_memory;
 mod hierarchical_table;
 mod arch;
 mod bookkeeping;
@@ -28,6 +28,7 @@ use super::MappingAccessRights;
 use crate::mem::{VirtualAddress, PhysicalAddress};
 use crate::frame_allocator::{FrameAllocator, FrameAllocatorTrait, PhysicalMemRegion};
 use crate::paging::arch::Entry;
+use crate::paging::kernel_memory::get_kernel_memory;
 use crate::error::KernelError;
 use crate::utils::{check_size_aligned, check_nonzero_length};
 use crate::sync::SpinRwLock;
@@ -53,8 +54,20 @@ pub struct ProcessMemory {
     ///
     /// [set_heap_size]: crate::syscalls::set_heap_size
     heap_base_address: VirtualAddress,
+    /// The maximum amount of memory that can be committed in the lazy mappings
+    /// of this process, in bytes.
+    memory_limit: usize,
+    /// The amount of memory committed in the lazy mappings of this process,
+    /// in bytes. Accounted against [memory_limit](ProcessMemory::memory_limit).
+    lazy_committed_size: usize,
 }
 
+/// The memory limit of a process whose [ProcInfo] doesn't give a resource limit,
+/// the one Horizon gives to sysmodules.
+///
+/// [ProcInfo]: sunrise_libkern::process::ProcInfo
+pub const DEFAULT_MEMORY_LIMIT: usize = 0x1230_0000;
+
 /// Page tables selector.
 ///
 /// A process always stores its table_hierarchy as an inactive hierarchy. When it wants to modify
@@ -154,6 +167,8 @@ impl Default for ProcessMemory {
             userspace_bookkeping: UserspaceBookkeeping::new(),
             table_hierarchy: InactiveHierarchy::new(),
             heap_base_address,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            lazy_committed_size: 0,
         }
     }
 }
@@ -275,6 +290,176 @@ impl ProcessMemory {
         Ok(())
     }
 
+    /// Creates a mapping whose frames are only allocated when first accessed.
+    ///
+    /// The range is guarded in the page tables until then. Userspace accesses
+    /// page fault, and the page fault handler calls [commit_on_fault].
+    /// The kernel can't page fault on userspace memory, so it must [commit]
+    /// a range before accessing it.
+    ///
+    /// [commit_on_fault]: ProcessMemory::commit_on_fault
+    /// [commit]: ProcessMemory::commit
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`:
+    ///     * there was already a mapping in the range.
+    ///     * range does not fall in UserLand.
+    ///     * `address` is not page aligned.
+    /// * `InvalidSize` :
+    ///     * `length` is not page aligned.
+    ///     * `length` is 0.
+    /// * `WrongMappingFramesForTy`: `ty` is not reference counted.
+    pub fn create_lazy_mapping(&mut self, address: VirtualAddress, length: usize, ty: MemoryType, flags: MappingAccessRights) -> Result<(), KernelError> {
+        address.check_aligned_to(PAGE_SIZE)?;
+        check_size_aligned(length, PAGE_SIZE)?;
+        check_nonzero_length(length)?;
+        UserLand::check_contains_region(address, length)?;
+        self.userspace_bookkeping.check_vacant(address, length)?;
+
+        let mut frames = Vec::new();
+        frames.resize_with(length / PAGE_SIZE, || None);
+        let mapping = Mapping::new(address, MappingFrames::Lazy(Arc::new(SpinRwLock::new(frames))), 0, length, ty, flags)?;
+        // ok, everything seems good, from now on treat errors as unexpected
+
+        self.get_hierarchy().guard(address, length);
+        self.userspace_bookkeping.add_mapping(mapping)
+            .expect("We checked everything, but bookkeeping refuses to add the mapping");
+        Ok(())
+    }
+
+    /// Maps a previously created lazy mapping to specified address.
+    ///
+    /// The whole range is guarded, and pages are mapped as they are accessed,
+    /// whether they were already committed by another mapping or not.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`:
+    ///     * there was already a mapping in the range.
+    ///     * range does not fall in UserLand.
+    ///     * `address` is not page aligned.
+    /// * `InvalidSize` :
+    ///     * `length` is not page aligned.
+    ///     * `length` is 0.
+    pub fn map_partial_lazy_mapping(&mut self,
+                                    lazy_mapping: Arc<SpinRwLock<Vec<Option<PhysicalMemRegion>>>>,
+                                    address: VirtualAddress,
+                                    phys_offset: usize,
+                                    length: usize,
+                                    ty: MemoryType,
+                                    flags: MappingAccessRights)
+                                   -> Result<(), KernelError> {
+        address.check_aligned_to(PAGE_SIZE)?;
+        UserLand::check_contains_region(address, length)?;
+        self.userspace_bookkeping.check_vacant(address, length)?;
+        let mapping = Mapping::new(address, MappingFrames::Lazy(lazy_mapping), phys_offset, length, ty, flags)?;
+        // ok, everything seems good, from now on treat errors as unexpected
+
+        self.get_hierarchy().guard(address, length);
+        self.userspace_bookkeping.add_mapping(mapping)
+            .expect("We checked everything, but bookkeeping refuses to add the mapping");
+        Ok(())
+    }
+
+    /// Makes sure every page of `address..address + length` falling in a
+    /// lazy mapping is backed by a frame and mapped, allocating and zeroing
+    /// the frames of the pages that were never accessed.
+    ///
+    /// Pages of other mappings are left untouched.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`: range does not fall in UserLand.
+    /// * `PhysicalMemoryExhaustion`: Frames could not be allocated. The pages
+    ///   committed so far stay committed.
+    /// * `ExceedingMaximum`: Committing a page would exceed the memory limit
+    ///   of the process. The pages committed so far stay committed.
+    pub fn commit(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
+        if length == 0 {
+            return Ok(())
+        }
+        UserLand::check_contains_region(address, length)?;
+        let last_page = (address + (length - 1)).floor();
+        let mut page = address.floor();
+        loop {
+            self.commit_page(page)?;
+            if page == last_page {
+                return Ok(())
+            }
+            page += PAGE_SIZE;
+        }
+    }
+
+    /// Commits the page `address` falls in, if it is part of a lazy mapping,
+    /// and isn't mapped yet.
+    ///
+    /// Called by the page fault handler, returns whether the page is part of a
+    /// lazy mapping and is now mapped, in which case the faulting instruction
+    /// can be retried. This is also the case when another thread committed the
+    /// page in the meantime.
+    pub fn commit_on_fault(&mut self, address: VirtualAddress) -> bool {
+        if !UserLand::contains_address(address) {
+            return false
+        }
+        match self.commit_page(address.floor()) {
+            Ok(committed) => committed,
+            Err(err) => {
+                error!("Failed committing lazy page {:?}: {}", address, err);
+                false
+            }
+        }
+    }
+
+    /// Commits `page`, if it is part of a lazy mapping and isn't mapped yet.
+    /// Returns whether `page` is part of a lazy mapping, and thus mapped.
+    ///
+    /// Allocating a frame is accounted against the memory limit.
+    fn commit_page(&mut self, page: VirtualAddress) -> Result<bool, KernelError> {
+        let (frames, index, flags) = {
+            let query = self.userspace_bookkeping.mapping_at(page);
+            let mapping = query.mapping();
+            match mapping.frames() {
+                MappingFrames::Lazy(frames) =>
+                    (frames.clone(), (mapping.phys_offset() + (page - mapping.address())) / PAGE_SIZE, mapping.flags()),
+                _ => return Ok(false)
+            }
+        };
+
+        let mut is_mapped = false;
+        self.get_hierarchy().for_every_entry(page, PAGE_SIZE, |state, _| {
+            is_mapped = matches!(state, PageState::Present(_));
+        });
+        if is_mapped {
+            return Ok(true)
+        }
+
+        let paddr = {
+            let mut frames = frames.write();
+            if frames[index].is_none() {
+                let committed_size = self.lazy_committed_size + PAGE_SIZE;
+                if committed_size > self.memory_limit {
+                    return Err(KernelError::ExceedingMaximum {
+                        value: committed_size as u64,
+                        maximum: self.memory_limit as u64,
+                        backtrace: Backtrace::new()
+                    })
+                }
+                let frame = FrameAllocator::allocate_frame()?;
+                zero_frame(&frame);
+                frames[index] = Some(frame);
+                self.lazy_committed_size = committed_size;
+            }
+            frames[index].as_ref().unwrap().address()
+        };
+
+        let mut hierarchy = self.get_hierarchy();
+        // remove the guard, and map the frame in its place.
+        hierarchy.unmap(page, PAGE_SIZE, |_| ());
+        hierarchy.map_to_from_iterator(core::iter::once(paddr), page, flags);
+        Ok(true)
+    }
+
     /// Guards a range of addresses
     ///
     /// # Errors
@@ -316,6 +501,14 @@ impl ProcessMemory {
         self.get_hierarchy().unmap(address, length, |_| {
             /* leak the mapped frames here, we still have them in `mapping` */
         });
+        if let MappingFrames::Lazy(frames) = mapping.frames() {
+            // The frames are freed with the last mapping using them. If the
+            // kernel still holds a mirror of them, we keep accounting them.
+            if Arc::strong_count(frames) == 1 {
+                let committed = frames.read().iter().filter(|frame| frame.is_some()).count() * PAGE_SIZE;
+                self.lazy_committed_size = self.lazy_committed_size.saturating_sub(committed);
+            }
+        }
         Ok(mapping)
     }
 
@@ -324,6 +517,47 @@ impl ProcessMemory {
         self.userspace_bookkeping.mapping_at(address)
     }
 
+    /// Checks whether `address` falls in the page guard placed below a stack,
+    /// meaning an access to it is a stack overflow.
+    pub fn is_stack_guard(&self, address: VirtualAddress) -> bool {
+        if !UserLand::contains_address(address) {
+            return false;
+        }
+        let query = self.query_memory(address);
+        let guard = query.mapping();
+        if guard.state().ty() != MemoryType::Reserved {
+            return false;
+        }
+        let above = guard.address() + guard.length();
+        UserLand::contains_address(above)
+            && self.query_memory(above).mapping().state().ty() == MemoryType::Stack
+    }
+
+    /// Returns the mappings of this address space, in ascending address order.
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.userspace_bookkeping.mappings()
+    }
+
+    /// Returns the amount of memory mapped in this address space, in bytes.
+    pub fn used_memory_size(&self) -> usize {
+        self.userspace_bookkeping.used_memory_size()
+    }
+
+    /// Returns the amount of memory committed in the lazy mappings of this
+    /// address space, and the limit it is accounted against, in bytes.
+    pub fn lazy_memory_usage(&self) -> (usize, usize) {
+        (self.lazy_committed_size, self.memory_limit)
+    }
+
+    /// Sets the maximum amount of memory that can be committed in the lazy
+    /// mappings of this address space, in bytes.
+    ///
+    /// The memory already committed stays committed, even if it exceeds the
+    /// new limit.
+    pub fn set_memory_limit(&mut self, memory_limit: usize) {
+        self.memory_limit = memory_limit;
+    }
+
     /*/// Shrink the mapping at `address` to `new_size`.
     ///
     /// If `new_size` == 0, the mapping is unmapped entirely.
@@ -426,8 +660,12 @@ impl ProcessMemory {
         let added_length = new_size - old_size;
         self.userspace_bookkeping.check_vacant(start_addr + old_size, added_length)?;
 
-        // 3. allocate the new frames.
-        let mut new_frames = FrameAllocator::allocate_frames_fragmented(added_length)?;
+        // 3. allocate the new frames, unless they are lazily allocated.
+        let mut new_frames = if let MappingFrames::Shared(_) = old_mapping_ref.frames() {
+            FrameAllocator::allocate_frames_fragmented(added_length)?
+        } else {
+            Vec::new()
+        };
 
         // 4. remove old mapping from the bookkeeping.
         let old_mapping = self.userspace_bookkeping.remove_mapping(start_addr, old_size)
@@ -435,15 +673,24 @@ impl ProcessMemory {
         let flags = old_mapping.flags();
 
         // 5. construct a new bigger mapping, with the same type and flags.
-        let new_mapping = if let MappingFrames::Shared(frames) = old_mapping.frames() {
-            // 6. map the added part accordingly.
-            self.get_hierarchy().map_to_from_iterator(new_frames.iter().flatten(), start_addr + old_size, flags);
-            // create a mapping from the freshly allocated frames and the flags.
-            frames.write().append(&mut new_frames);
-            Mapping::new(start_addr, MappingFrames::Shared(frames.clone()), 0, new_size, MemoryType::Heap, flags)
-                .expect("expand_mapping: couldn't recreate mapping")
-        } else {
-            unreachable!("We checked we could only get a MappingFrames earlier.");
+        let new_mapping = match old_mapping.frames() {
+            MappingFrames::Shared(frames) => {
+                // 6. map the added part accordingly.
+                self.get_hierarchy().map_to_from_iterator(new_frames.iter().flatten(), start_addr + old_size, flags);
+                // create a mapping from the freshly allocated frames and the flags.
+                frames.write().append(&mut new_frames);
+                Mapping::new(start_addr, MappingFrames::Shared(frames.clone()), 0, new_size, MemoryType::Heap, flags)
+                    .expect("expand_mapping: couldn't recreate mapping")
+            },
+            MappingFrames::Lazy(frames) => {
+                // 6. guard the added part, it will be committed on first access.
+                self.get_hierarchy().guard(start_addr + old_size, added_length);
+                let pages = (old_mapping.phys_offset() + new_size) / PAGE_SIZE;
+                frames.write().resize_with(pages, || None);
+                Mapping::new(start_addr, MappingFrames::Lazy(frames.clone()), old_mapping.phys_offset(), new_size, MemoryType::Heap, flags)
+                    .expect("expand_mapping: couldn't recreate mapping")
+            },
+            _ => unreachable!("We checked we could only get a MappingFrames earlier.")
         };
         self.userspace_bookkeping.add_mapping(new_mapping)
             .expect("expand_mapping: failed re-adding the mapping to the bookkeeping");
@@ -465,7 +712,7 @@ impl ProcessMemory {
     ///
     /// # Error
     ///
-    /// Returns an Error if the mapping is not RefCounted.
+    /// Returns an Error if the mapping is not RefCounted, or is lazy and the range wasn't [committed](ProcessMemory::commit).
     pub fn mirror_mapping(&self, address: VirtualAddress, length: usize) -> Result<CrossProcessMapping, KernelError> {
         UserLand::check_contains_address(address)?;
         let mapping = self.userspace_bookkeping.occupied_mapping_at(address)?;
@@ -490,6 +737,7 @@ impl ProcessMemory {
     pub fn resize_heap(&mut self, new_size: usize) -> Result<VirtualAddress, KernelError> {
         #[allow(clippy::missing_docs_in_private_items)]
         enum HeapState { NoHeap, Heap(usize) };
+        check_size_aligned(new_size, PAGE_SIZE)?;
         UserLand::check_contains_region(self.heap_base_address, new_size)?;
         // get the previous heap size
         let previous_heap_state = {
@@ -504,7 +752,7 @@ impl ProcessMemory {
         let heap_base_address = self.heap_base_address;
         match previous_heap_state {
             HeapState::NoHeap if new_size == 0 => (), // don't do anything
-            HeapState::NoHeap => self.create_regular_mapping(heap_base_address, new_size, MemoryType::Heap, MappingAccessRights::u_rw())?,
+            HeapState::NoHeap => self.create_lazy_mapping(heap_base_address, new_size, MemoryType::Heap, MappingAccessRights::u_rw())?,
             // TODO: Shrink mapping
             HeapState::Heap(old_size) if new_size < old_size => (),
             //HeapState::Heap(old_size) if new_size < old_size => { self.shrink_mapping(heap_base_address, new_size)?; },
@@ -587,3 +835,144 @@ impl ProcessMemory {
     }
 }
 
+
+/// Fills a freshly allocated frame with zeroes, through a temporary mapping in KernelLand.
+fn zero_frame(frame: &PhysicalMemRegion) {
+    let mut kmem = get_kernel_memory();
+    unsafe {
+        // safe: the frame is not mapped anywhere else yet, and we unmap it before returning.
+        let va = kmem.map_frame_iterator(frame.into_iter(), MappingAccessRights::k_rw());
+        core::ptr::write_bytes(va.addr() as *mut u8, 0, PAGE_SIZE);
+        kmem.unmap_no_dealloc(va, PAGE_SIZE);
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::paging::lands::KernelLand;
+    use crate::process::ProcessStruct;
+    use crate::testing::*;
+    use sunrise_libkern::nr;
+
+    /// Address of the lazy mappings created in the test processes.
+    const LAZY_ADDR: VirtualAddress = VirtualAddress(0x600000);
+
+    /// Address of the mirrors of the lazy mappings created in the test processes.
+    const MIRROR_ADDR: VirtualAddress = VirtualAddress(0x700000);
+
+    /// Checks the memory mirrored by `mirror` is zeroed.
+    fn assert_zeroed(mirror: &CrossProcessMapping) {
+        let bytes = unsafe {
+            // safe: the mirror is alive for the whole function.
+            core::slice::from_raw_parts(mirror.addr().addr() as *const u8, mirror.len())
+        };
+        assert!(bytes.iter().all(|byte| *byte == 0), "Committed page is not zeroed");
+    }
+
+    #[test_case]
+    fn commit_only_commits_lazy_pages() {
+        let process = create_test_process("commit", &[0x0F, 0x0B], &[]);
+        let mut pmemory = process.pmemory.lock();
+        pmemory.create_lazy_mapping(LAZY_ADDR, 3 * PAGE_SIZE, MemoryType::Heap, MappingAccessRights::u_rw()).unwrap();
+        let used = pmemory.used_memory_size();
+        assert_eq!(pmemory.lazy_memory_usage().0, 0);
+        assert!(pmemory.mirror_mapping(LAZY_ADDR, PAGE_SIZE).is_err());
+
+        // An unaligned range straddling the first two pages.
+        pmemory.commit(LAZY_ADDR + (PAGE_SIZE - 4), 8).unwrap();
+        assert_eq!(pmemory.used_memory_size(), used + 2 * PAGE_SIZE);
+        assert_eq!(pmemory.lazy_memory_usage().0, 2 * PAGE_SIZE);
+        assert_zeroed(&pmemory.mirror_mapping(LAZY_ADDR, 2 * PAGE_SIZE).unwrap());
+        assert!(pmemory.mirror_mapping(LAZY_ADDR, 3 * PAGE_SIZE).is_err());
+
+        // Committed pages and regular mappings are left untouched.
+        pmemory.commit(LAZY_ADDR, 2 * PAGE_SIZE).unwrap();
+        pmemory.commit(VirtualAddress(TEST_PROCESS_DATA_ADDR), PAGE_SIZE).unwrap();
+        assert_eq!(pmemory.used_memory_size(), used + 2 * PAGE_SIZE);
+        assert_eq!(pmemory.lazy_memory_usage().0, 2 * PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn commit_on_fault_only_commits_lazy_pages() {
+        let process = create_test_process("fault", &[0x0F, 0x0B], &[]);
+        let mut pmemory = process.pmemory.lock();
+        pmemory.create_lazy_mapping(LAZY_ADDR, PAGE_SIZE, MemoryType::Heap, MappingAccessRights::u_rw()).unwrap();
+
+        assert!(!pmemory.commit_on_fault(VirtualAddress(TEST_PROCESS_DATA_ADDR)));
+        assert!(!pmemory.commit_on_fault(LAZY_ADDR + PAGE_SIZE));
+        assert!(!pmemory.commit_on_fault(KernelLand::start_addr()));
+        assert_eq!(pmemory.lazy_memory_usage().0, 0);
+
+        assert!(pmemory.commit_on_fault(LAZY_ADDR + 12));
+        assert_zeroed(&pmemory.mirror_mapping(LAZY_ADDR, PAGE_SIZE).unwrap());
+        // Another thread faulting on the same page can retry its instruction.
+        assert!(pmemory.commit_on_fault(LAZY_ADDR));
+        assert_eq!(pmemory.lazy_memory_usage().0, PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn mirrored_lazy_pages_are_committed_once() {
+        let process = create_test_process("mirror", &[0x0F, 0x0B], &[]);
+        let mut pmemory = process.pmemory.lock();
+        pmemory.create_lazy_mapping(LAZY_ADDR, 2 * PAGE_SIZE, MemoryType::Heap, MappingAccessRights::u_rw()).unwrap();
+        pmemory.commit(LAZY_ADDR, PAGE_SIZE).unwrap();
+        let frames = match pmemory.query_memory(LAZY_ADDR).mapping().frames() {
+            MappingFrames::Lazy(frames) => frames.clone(),
+            frames => panic!("Heap has non-lazy frames {:?}", frames)
+        };
+        let used = pmemory.used_memory_size();
+        pmemory.map_partial_lazy_mapping(frames, MIRROR_ADDR, 0, 2 * PAGE_SIZE, MemoryType::Stack, MappingAccessRights::u_rw()).unwrap();
+        assert_eq!(pmemory.used_memory_size(), used);
+
+        // The second page is committed through the mirror, and only mapped in the heap.
+        assert!(pmemory.commit_on_fault(MIRROR_ADDR + PAGE_SIZE));
+        assert!(pmemory.commit_on_fault(LAZY_ADDR + PAGE_SIZE));
+        assert_eq!(pmemory.used_memory_size(), used + PAGE_SIZE);
+        assert_eq!(pmemory.lazy_memory_usage().0, 2 * PAGE_SIZE);
+
+        // The frames are still used by the heap.
+        pmemory.unmap(MIRROR_ADDR, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(pmemory.lazy_memory_usage().0, 2 * PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn commit_is_accounted_against_the_memory_limit() {
+        let process = create_test_process("limit", &[0x0F, 0x0B], &[]);
+        let mut pmemory = process.pmemory.lock();
+        assert_eq!(pmemory.lazy_memory_usage(), (0, DEFAULT_MEMORY_LIMIT));
+        pmemory.create_lazy_mapping(LAZY_ADDR, 3 * PAGE_SIZE, MemoryType::Heap, MappingAccessRights::u_rw()).unwrap();
+        pmemory.set_memory_limit(2 * PAGE_SIZE);
+
+        pmemory.commit(LAZY_ADDR, 2 * PAGE_SIZE).unwrap();
+        match pmemory.commit(LAZY_ADDR, 3 * PAGE_SIZE) {
+            Err(KernelError::ExceedingMaximum { .. }) => (),
+            result => panic!("Committing past the limit returned {:?}", result)
+        }
+        assert!(!pmemory.commit_on_fault(LAZY_ADDR + 2 * PAGE_SIZE));
+        assert_eq!(pmemory.lazy_memory_usage(), (2 * PAGE_SIZE, 2 * PAGE_SIZE));
+
+        // Unmapping the last mapping of the frames gives the memory back.
+        pmemory.unmap(LAZY_ADDR, 3 * PAGE_SIZE).unwrap();
+        assert_eq!(pmemory.lazy_memory_usage().0, 0);
+    }
+
+    #[test_case]
+    fn user_access_commits_lazy_page() {
+        let process = create_test_process("lazyheap", &[
+            0xC7, 0x05, 0x00, 0x00, 0x60, 0x00, 0x2A, 0x00, 0x00, 0x00, // mov dword [0x600000], 42
+            0x8B, 0x1D, 0x00, 0x00, 0x60, 0x00,                         // mov ebx, [0x600000]
+            0xB8, 0x89, 0x00, 0x00, 0x00,                               // mov eax, ExitProcessWithCode
+            0xCD, 0x80,                                                 // int 0x80
+            0x0F, 0x0B,                                                 // ud2
+        ], &[nr::ExitProcessWithCode]);
+        process.pmemory.lock()
+            .create_lazy_mapping(LAZY_ADDR, 2 * PAGE_SIZE, MemoryType::Heap, MappingAccessRights::u_rw())
+            .unwrap();
+        ProcessStruct::start(&process, 0, PAGE_SIZE).expect("Failed to start test process");
+        wait_process_exit(&process);
+
+        assert_eq!(process.exit_code(), Some(42));
+        assert_eq!(process.pmemory.lock().lazy_memory_usage().0, PAGE_SIZE);
+    }
+}
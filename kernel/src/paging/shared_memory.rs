@@ -0,0 +1,122 @@
+//! Shared memory
+//!
+//! A shared memory is a set of frames that can be mapped in several processes
+//! at the same time, created by [create_shared_memory], or by
+//! [create_contiguous_shared_memory] for physically contiguous frames. It
+//! follows Horizon's rules:
+//!
+//! - It can only be mapped and unmapped whole: the size given to
+//!   [map_shared_memory] and [unmap_shared_memory] must be the size of the
+//!   shared memory.
+//! - The process that created it must map it with the permissions it chose
+//!   for itself, and the other processes with the permissions it chose for
+//!   them, unless it left those to them.
+//!
+//! The frames are reference counted: every mapping holds on to them, as well
+//! as the SharedMemory, which lives as long as a handle points to it. They are
+//! freed once the last mapping is unmapped and the last handle is closed.
+//!
+//! [create_shared_memory]: crate::syscalls::create_shared_memory
+//! [create_contiguous_shared_memory]: crate::syscalls::create_contiguous_shared_memory
+//! [map_shared_memory]: crate::syscalls::map_shared_memory
+//! [unmap_shared_memory]: crate::syscalls::unmap_shared_memory
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use sunrise_libkern::{MemoryPermissions, MemoryType};
+
+use crate::frame_allocator::PhysicalMemRegion;
+use crate::paging::mapping::{Mapping, MappingFrames};
+use crate::sync::SpinRwLock;
+
+/// A shared memory, see the [module level documentation](crate::paging::shared_memory).
+#[derive(Debug)]
+pub struct SharedMemory {
+    /// The frames, shared with the mappings.
+    frames: Arc<SpinRwLock<Vec<PhysicalMemRegion>>>,
+    /// The size of the frames.
+    size: usize,
+    /// Pid of the process that created it.
+    owner_pid: usize,
+    /// Permissions the owner must map it with.
+    owner_perm: MemoryPermissions,
+    /// Permissions the other processes must map it with, or None if they can
+    /// choose.
+    remote_perm: Option<MemoryPermissions>,
+}
+
+impl SharedMemory {
+    /// Creates a shared memory backed by `frames`, owned by the process `owner_pid`.
+    pub fn new(frames: Vec<PhysicalMemRegion>, owner_pid: usize, owner_perm: MemoryPermissions,
+               remote_perm: Option<MemoryPermissions>) -> SharedMemory {
+        SharedMemory {
+            size: frames.iter().map(|region| region.size()).sum(),
+            frames: Arc::new(SpinRwLock::new(frames)),
+            owner_pid,
+            owner_perm,
+            remote_perm,
+        }
+    }
+
+    /// Gets the frames, to create a mapping of this shared memory.
+    pub fn frames(&self) -> &Arc<SpinRwLock<Vec<PhysicalMemRegion>>> {
+        &self.frames
+    }
+
+    /// Gets the size of the shared memory.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Checks that the process `pid` can map this shared memory with `perm`.
+    pub fn can_map(&self, pid: usize, perm: MemoryPermissions) -> bool {
+        if pid == self.owner_pid {
+            perm == self.owner_perm
+        } else {
+            self.remote_perm.map(|remote_perm| perm == remote_perm).unwrap_or(true)
+        }
+    }
+
+    /// Checks that `mapping` is a mapping of this shared memory.
+    pub fn is_mapped_by(&self, mapping: &Mapping) -> bool {
+        match (mapping.state().ty(), mapping.frames()) {
+            (MemoryType::SharedMemory, MappingFrames::Shared(frames)) => Arc::ptr_eq(frames, &self.frames),
+            _ => false
+        }
+    }
+
+    /// Gets the number of mappings of this shared memory, in all processes.
+    pub fn mapping_count(&self) -> usize {
+        Arc::strong_count(&self.frames) - 1
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    /// The owner must use its own permissions.
+    #[test]
+    fn owner_uses_owner_perm() {
+        let mem = SharedMemory::new(Vec::new(), 1, MemoryPermissions::RW, Some(MemoryPermissions::READABLE));
+        assert!(mem.can_map(1, MemoryPermissions::RW));
+        assert!(!mem.can_map(1, MemoryPermissions::READABLE));
+    }
+
+    /// The other processes must use the remote permissions.
+    #[test]
+    fn remote_uses_remote_perm() {
+        let mem = SharedMemory::new(Vec::new(), 1, MemoryPermissions::RW, Some(MemoryPermissions::READABLE));
+        assert!(mem.can_map(2, MemoryPermissions::READABLE));
+        assert!(!mem.can_map(2, MemoryPermissions::RW));
+    }
+
+    /// The other processes choose their permissions when the owner doesn't care.
+    #[test]
+    fn remote_dont_care() {
+        let mem = SharedMemory::new(Vec::new(), 1, MemoryPermissions::READABLE, None);
+        assert!(mem.can_map(2, MemoryPermissions::READABLE));
+        assert!(mem.can_map(2, MemoryPermissions::RW));
+        assert!(!mem.can_map(1, MemoryPermissions::RW));
+    }
+}
@@ -11,6 +11,12 @@ use crate::i386::gdt::MAIN_TASK;
 use crate::scheduler::try_get_current_thread;
 use core::fmt::Write;
 use crate::i386::registers::eflags::EFlags;
+use crate::process::PROCESS_LIST;
+use crate::sync::lock_tracking;
+use alloc::sync::Weak;
+use core::sync::atomic::Ordering;
+
+pub mod report;
 
 /// Reason for a kernel panic. Must be passed to [kernel_panic].
 #[allow(missing_debug_implementations)] // want to display it ? pass it to kernel_panic() !
@@ -36,7 +42,10 @@ pub enum PanicOrigin<'a> {
     /// You fucked up on some quality level.
     ///
     /// Registers state before the second fault can be retrieved from the MAIN_TASK tss.
-    DoubleFault,
+    DoubleFault {
+        /// The first fault hit the page guard of the kernel stack in use.
+        kernel_stack_overflow: bool,
+    },
     /// Userspace exception.
     ///
     /// Normally this isn't a panic, the kernel should kill the faulty process,
@@ -50,6 +59,15 @@ pub enum PanicOrigin<'a> {
         /// Userspace registers state before exception.
         userspace_hardware_context: UserspaceHardwareContext,
     },
+    /// The [watchdog] found the cpu stuck.
+    ///
+    /// [watchdog]: crate::watchdog
+    Watchdog {
+        /// Formatted string describing the lockup.
+        reason: core::fmt::Arguments<'a>,
+        /// Registers state when the watchdog fired.
+        hardware_context: UserspaceHardwareContext,
+    },
 }
 
 /// The kernel panic function.
@@ -92,7 +110,12 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
             let _ = writeln!(SerialLogger, "! Kernel Fault !\n\
                                             ! {}", msg);
         }
-        PanicOrigin::DoubleFault => {
+        PanicOrigin::DoubleFault { kernel_stack_overflow: true } => {
+            let _ = writeln!(SerialLogger, "! Double Fault !\n\
+                                            ! Kernel stack overflow in thread {:?} of {:?}.",
+                                            current_thread.as_ref().map(|t| t.tid), current_process_name);
+        }
+        PanicOrigin::DoubleFault { kernel_stack_overflow: false } => {
             let _ = writeln!(SerialLogger, "! Double Fault !\n\
                                             ! Good luck.");
         }
@@ -100,6 +123,10 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
             let _ = writeln!(SerialLogger, "! Userspace exception in {:?}.\n\
                                             ! {}", current_process_name, msg);
         }
+        PanicOrigin::Watchdog { reason: msg, .. } => {
+            let _ = writeln!(SerialLogger, "! Watchdog !\n\
+                                            ! {}", msg);
+        }
     }
 
     let _ = writeln!(SerialLogger, "!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
@@ -120,37 +147,22 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
     let _ = writeln!(SerialLogger, "Process: {:?}", current_process_name);
 
     // Show hardware context
-    match panic_origin {
-        PanicOrigin::KernelAssert { .. } => { /* You shouldn't need it */ },
-        PanicOrigin::KernelFault { kernel_hardware_context: registers, .. } => {
-            let _ = writeln!(SerialLogger, "Kernel registers before fault:\n{}", registers);
-        },
-        PanicOrigin::UserspaceFault { userspace_hardware_context: registers, .. } => {
-            let _ = writeln!(SerialLogger, "Userspace registers before fault:\n{}", registers);
-        },
-        PanicOrigin::DoubleFault => {
-            // Get the Main TSS so I can recover some information about what happened.
-            if let Some(tss_main) = MAIN_TASK.try_lock() {
-                let _ = writeln!(SerialLogger, "Kernel registers before double fault:\n\
-                        EIP={:#010x} CR3={:#010x}\n\
-                        EAX={:#010x} EBX={:#010x} ECX={:#010x} EDX={:#010x}\n\
-                        ESI={:#010x} EDI={:#010X} ESP={:#010x} EBP={:#010x}\n\
-                        EFLAGS={:?}",
-                        tss_main.tss.eip, tss_main.tss.cr3,
-                        tss_main.tss.eax, tss_main.tss.ebx, tss_main.tss.ecx, tss_main.tss.edx,
-                        tss_main.tss.esi, tss_main.tss.edi, tss_main.tss.esp, tss_main.tss.ebp,
-                        EFlags::from_bits_truncate(tss_main.tss.eflags));
-            } else {
-                let _ = writeln!(SerialLogger, "Kernel registers before double fault: Cannot get main TSS, good luck");
-            }
-        }
-    }
+    write_hardware_context(&mut SerialLogger, panic_origin);
 
     // display the full thread struct
     if let Some(t) = &current_thread {
         let _ = writeln!(SerialLogger, "Current thread: {:#?}", t);
     }
 
+    // display who's doing what, to debug deadlocks
+    match panic_origin {
+        PanicOrigin::UserspaceFault { .. } => { /* The kernel is fine */ },
+        _ => {
+            dump_threads();
+            lock_tracking::dump_held_locks(&mut SerialLogger);
+        }
+    }
+
     // display a stack dump
 
     // Parse the ELF to get the symbol table.
@@ -197,15 +209,90 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
         _ => crate::stack::KernelStack::dump_current_stack(elf_and_st)
     }
 
+    // Save a report for the next boot
+    report::write_report(panic_origin, current_thread.as_deref(), elf_and_st);
+
     // Display the infamous "Blue Screen Of Death"
     display_bsod();
 
     let _ = writeln!(SerialLogger, "!!!!!!!!!!!!!!!END PANIC!!!!!!!!!!!!!!");
 
+    #[cfg(all(test, target_os = "none"))]
+    crate::testing::test_panic();
+
     loop { unsafe { llvm_asm!("HLT"); } }
 }
 
 
+/// Writes the registers saved by the panic origin, if any.
+fn write_hardware_context<W: Write>(w: &mut W, panic_origin: &PanicOrigin) {
+    match panic_origin {
+        PanicOrigin::KernelAssert { .. } => { /* You shouldn't need it */ },
+        PanicOrigin::KernelFault { kernel_hardware_context: registers, .. } => {
+            let _ = writeln!(w, "Kernel registers before fault:\n{}", registers);
+        },
+        PanicOrigin::UserspaceFault { userspace_hardware_context: registers, .. } => {
+            let _ = writeln!(w, "Userspace registers before fault:\n{}", registers);
+        },
+        PanicOrigin::Watchdog { hardware_context: registers, .. } => {
+            let _ = writeln!(w, "Registers when the watchdog fired:\n{}", registers);
+        },
+        PanicOrigin::DoubleFault { .. } => {
+            // Get the Main TSS so I can recover some information about what happened.
+            if let Some(tss_main) = MAIN_TASK.try_lock() {
+                let _ = writeln!(w, "Kernel registers before double fault:\n\
+                        EIP={:#010x} CR3={:#010x}\n\
+                        EAX={:#010x} EBX={:#010x} ECX={:#010x} EDX={:#010x}\n\
+                        ESI={:#010x} EDI={:#010X} ESP={:#010x} EBP={:#010x}\n\
+                        EFLAGS={:?}",
+                        tss_main.tss.eip, tss_main.tss.cr3,
+                        tss_main.tss.eax, tss_main.tss.ebx, tss_main.tss.ecx, tss_main.tss.edx,
+                        tss_main.tss.esi, tss_main.tss.edi, tss_main.tss.esp, tss_main.tss.ebp,
+                        EFlags::from_bits_truncate(tss_main.tss.eflags));
+            } else {
+                let _ = writeln!(w, "Kernel registers before double fault: Cannot get main TSS, good luck");
+            }
+        }
+    }
+}
+
+/// Displays every thread of every process, along with its state.
+///
+/// Only try-locks, so it does not deadlock if the panic interrupted someone
+/// holding one of those locks. The processes and threads are leaked, so that
+/// no destructor runs from the panic handler.
+fn dump_threads() {
+    if crate::scheduler::try_get_current_thread_id().is_none() {
+        // Early boot, there are no threads yet, and mutexes can't be used.
+        return;
+    }
+    let _ = writeln!(SerialLogger, "Threads:");
+    let processes = match PROCESS_LIST.try_lock() {
+        Ok(processes) => processes,
+        Err(()) => {
+            let _ = writeln!(SerialLogger, "  <process list is locked>");
+            return;
+        }
+    };
+    for process in processes.iter().filter_map(Weak::upgrade) {
+        let _ = writeln!(SerialLogger, "  {} (pid {}): {:?}", process.name, process.pid, process.try_state());
+        match process.threads.try_lock() {
+            Some(threads) => for thread in threads.iter().filter_map(Weak::upgrade) {
+                let _ = write!(SerialLogger, "    thread {}: {:?}", thread.tid, thread.state.load(Ordering::SeqCst));
+                if let Some(lock) = lock_tracking::waiting_on(thread.tid) {
+                    let _ = write!(SerialLogger, ", sleeping on Mutex {:#010x}", lock);
+                }
+                let _ = writeln!(SerialLogger);
+                core::mem::forget(thread);
+            },
+            None => {
+                let _ = writeln!(SerialLogger, "    <threads are locked>");
+            }
+        }
+        core::mem::forget(process);
+    }
+}
+
 /// The "Blue Screen Of Death"
 ///
 /// Stored as an uncompressed BMP, so we don't have to do decompression in the panic handler,
@@ -0,0 +1,327 @@
+//! Kernel panic reports persisted across reboots
+//!
+//! What [kernel_panic] prints on the serial port is lost unless someone was
+//! watching it. So it also writes a plain-text report to a physical memory
+//! region reserved at the top of the RAM, which survives a warm reboot.
+//!
+//! On the next boot, [init] picks up the report left in the region, if any,
+//! and the loader saves it to `/var/crash/kernel-<n>.txt` through the
+//! [read_kernel_panic_report] syscall.
+//!
+//! The region starts with a [ReportHeader], followed by the text of the
+//! report. The header is written last, so a panic in the panic handler
+//! leaves either the previous report, or no report at all.
+//!
+//! [kernel_panic]: super::kernel_panic
+//! [read_kernel_panic_report]: crate::syscalls::read_kernel_panic_report
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use multiboot2::BootInformation;
+use xmas_elf::ElfFile;
+use xmas_elf::symbol_table::Entry32;
+use crate::frame_allocator::PhysicalMemRegion;
+use crate::mem::{PhysicalAddress, VirtualAddress};
+use crate::paging::{PAGE_SIZE, MappingAccessRights, PageState, kernel_memory::get_kernel_memory};
+use crate::paging::lands::{KernelLand, VirtualSpaceLand};
+use crate::process::ThreadStruct;
+use crate::sync::SpinLock;
+use crate::sync::lock_tracking;
+use crate::i386::stack::{walk_current_kernel_stack, StackDumpSource};
+use crate::log_impl::history;
+use crate::watchdog;
+use super::{PanicOrigin, write_hardware_context};
+
+/// Size of the physical memory region holding the report, header included.
+pub const REPORT_REGION_SIZE: usize = 16 * PAGE_SIZE;
+
+/// The region is never placed below this address, where the bootloader and
+/// the kernel live.
+const REPORT_REGION_MIN_ADDRESS: usize = 16 * 1024 * 1024;
+
+/// Magic identifying a report.
+const REPORT_MAGIC: [u8; 8] = *b"SUNPANIC";
+
+/// Version of the report layout.
+const REPORT_VERSION: u32 = 1;
+
+/// Maximum number of frames in the backtrace.
+const MAX_BACKTRACE_FRAMES: usize = 32;
+
+/// Number of log lines included in the report.
+const MAX_LOG_LINES: usize = 50;
+
+/// Header at the start of the report region.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ReportHeader {
+    /// Must be [REPORT_MAGIC].
+    magic: [u8; 8],
+    /// Must be [REPORT_VERSION].
+    version: u32,
+    /// Length of the text following the header.
+    length: u32,
+    /// FNV-1a hash of the text following the header.
+    checksum: u32,
+    /// Padding.
+    _reserved: u32,
+}
+
+/// Size of the [ReportHeader].
+const HEADER_SIZE: usize = core::mem::size_of::<ReportHeader>();
+
+/// Physical address of the report region, or 0 if we couldn't find one.
+static REGION_PHYS: AtomicUsize = AtomicUsize::new(0);
+
+/// Virtual address of the report region, or 0 if it is not mapped yet.
+static REGION_VIRT: AtomicUsize = AtomicUsize::new(0);
+
+/// The report left by the previous boot.
+static PREVIOUS_REPORT: SpinLock<Option<Vec<u8>>> = SpinLock::new(None);
+
+/// Computes the 32-bit FNV-1a hash of `data`.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+}
+
+/// Chooses the physical memory region holding the report: the end of the
+/// highest usable memory area below 4GiB.
+///
+/// It must land at the same place on every boot, so it only depends on the
+/// memory map. It is skipped if it overlaps a GRUB module or the boot
+/// information.
+///
+/// Called by the frame allocator, which reserves the returned region. It looks
+/// up the page tables of KernelLand, the frame allocator must not be locked.
+pub fn find_region(boot_info: &BootInformation) -> Option<PhysicalAddress> {
+    let start = boot_info.memory_map_tag()?.memory_areas()
+        .filter(|area| area.memory_type() == 1 && area.end_address() <= u64::from(u32::max_value()))
+        .filter_map(|area| {
+            let end = area.end_address() as usize & !(PAGE_SIZE - 1);
+            let start = end.checked_sub(REPORT_REGION_SIZE)?;
+            if start < area.start_address() as usize || start < REPORT_REGION_MIN_ADDRESS {
+                None
+            } else {
+                Some(start)
+            }
+        })
+        .max()?;
+
+    let overlaps = |from: usize, to: usize| from < start + REPORT_REGION_SIZE && start < to;
+
+    let overlaps_module = boot_info.module_tags().any(|module| {
+        overlaps(module.start_address() as usize, module.end_address() as usize)
+    });
+
+    // The bootstrap copied the boot information to a page it mapped in
+    // KernelLand, find the frame backing it.
+    let boot_info_page = VirtualAddress(boot_info.start_address()).floor();
+    let boot_info_offset = boot_info.start_address() - boot_info_page.addr();
+    let boot_info_phys = if KernelLand::contains_address(boot_info_page) {
+        match get_kernel_memory().mapping_state(boot_info_page) {
+            PageState::Present(frame) => frame.addr() + boot_info_offset,
+            _ => panic!("Boot information is not mapped"),
+        }
+    } else {
+        boot_info.start_address()
+    };
+    let boot_info_size = boot_info.end_address() - boot_info.start_address();
+    let overlaps_boot_info = overlaps(boot_info_phys, boot_info_phys + boot_info_size);
+
+    if overlaps_module || overlaps_boot_info {
+        return None;
+    }
+
+    REGION_PHYS.store(start, Ordering::SeqCst);
+    Some(PhysicalAddress(start))
+}
+
+/// Maps the report region, and takes the report left by the previous boot.
+///
+/// Must be called after the frame allocator and the heap are initialized.
+pub fn init() {
+    let phys = REGION_PHYS.load(Ordering::SeqCst);
+    if phys == 0 {
+        info!("No memory region for kernel panic reports, they won't be saved");
+        return;
+    }
+
+    let region = unsafe {
+        // safe: the frame allocator reserved it for us, and nobody else uses it.
+        PhysicalMemRegion::on_fixed_mmio(PhysicalAddress(phys), REPORT_REGION_SIZE)
+    }.expect("Kernel panic report region is not reserved");
+    let virt = get_kernel_memory().map_phys_region(region, MappingAccessRights::READABLE | MappingAccessRights::WRITABLE);
+    let region = unsafe {
+        // safe: we just mapped it, and it is only accessed from here and the panic handler.
+        core::slice::from_raw_parts_mut(virt.addr() as *mut u8, REPORT_REGION_SIZE)
+    };
+
+    let header = unsafe {
+        // safe: the region is page-aligned, and bigger than a header.
+        (region.as_ptr() as *const ReportHeader).read_volatile()
+    };
+    let body = &region[HEADER_SIZE..];
+    if header.magic == REPORT_MAGIC && header.version == REPORT_VERSION
+        && header.length as usize <= body.len()
+        && fnv1a(&body[..header.length as usize]) == header.checksum {
+        info!("Found a kernel panic report from the previous boot ({} bytes)", header.length);
+        *PREVIOUS_REPORT.lock() = Some(body[..header.length as usize].to_vec());
+    }
+
+    // Don't find the same report again on the next boot.
+    for byte in &mut region[..HEADER_SIZE] {
+        *byte = 0;
+    }
+
+    info!("Kernel panic reports are saved at {:#010x}", phys);
+    REGION_VIRT.store(virt.addr(), Ordering::SeqCst);
+}
+
+/// Reads the report left by the previous boot, starting at `offset`.
+///
+/// Returns the number of bytes written to `out`, which is only smaller than
+/// the size of `out` when reaching the end of the report, and the size of the
+/// report. Returns None if the previous boot didn't leave a report.
+pub fn read_previous_report(offset: usize, out: &mut [u8]) -> Option<(usize, usize)> {
+    let report = PREVIOUS_REPORT.lock();
+    let report = report.as_ref()?;
+    let start = core::cmp::min(offset, report.len());
+    let read = core::cmp::min(out.len(), report.len() - start);
+    out[..read].copy_from_slice(&report[start..start + read]);
+    Some((read, report.len()))
+}
+
+/// Writes text to a buffer, silently truncating what doesn't fit.
+struct ReportWriter<'a> {
+    /// The buffer.
+    buf: &'a mut [u8],
+    /// Length of the text written so far.
+    pos: usize,
+}
+
+impl<'a> Write for ReportWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = core::cmp::min(s.len(), self.buf.len() - self.pos);
+        self.buf[self.pos..self.pos + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// Writes the report of the ongoing panic to the report region.
+///
+/// Only called by [kernel_panic]. Does nothing if the region isn't mapped.
+///
+/// [kernel_panic]: super::kernel_panic
+pub fn write_report<'a>(panic_origin: &PanicOrigin, current_thread: Option<&ThreadStruct>, elf_symbols: Option<(&ElfFile<'a>, &'a [Entry32])>) {
+    let virt = REGION_VIRT.load(Ordering::SeqCst);
+    if virt == 0 {
+        return;
+    }
+    let region = unsafe {
+        // safe: mapped by init, and we're the only cpu left running.
+        core::slice::from_raw_parts_mut(virt as *mut u8, REPORT_REGION_SIZE)
+    };
+    let (header, body) = region.split_at_mut(HEADER_SIZE);
+
+    let mut w = ReportWriter { buf: body, pos: 0 };
+    write_sections(&mut w, panic_origin, current_thread, elf_symbols);
+    let length = w.pos;
+
+    let checksum = fnv1a(&w.buf[..length]);
+    unsafe {
+        // safe: the region is page-aligned, and bigger than a header.
+        (header.as_mut_ptr() as *mut ReportHeader).write_volatile(ReportHeader {
+            magic: REPORT_MAGIC,
+            version: REPORT_VERSION,
+            length: length as u32,
+            checksum,
+            _reserved: 0,
+        });
+    }
+}
+
+/// Writes the text of the report.
+fn write_sections<'a, W: Write>(w: &mut W, panic_origin: &PanicOrigin, current_thread: Option<&ThreadStruct>, elf_symbols: Option<(&ElfFile<'a>, &'a [Entry32])>) {
+    let _ = writeln!(w, "SunriseOS kernel panic report");
+    let _ = match panic_origin {
+        PanicOrigin::KernelAssert { panic_message } =>
+            writeln!(w, "Origin: kernel assert\nMessage: {}", panic_message),
+        PanicOrigin::KernelFault { exception_message, .. } =>
+            writeln!(w, "Origin: kernel fault\nMessage: {}", exception_message),
+        PanicOrigin::DoubleFault { kernel_stack_overflow: true } =>
+            writeln!(w, "Origin: double fault\nMessage: kernel stack overflow in thread {:?}",
+                current_thread.map(|t| t.tid)),
+        PanicOrigin::DoubleFault { kernel_stack_overflow: false } =>
+            writeln!(w, "Origin: double fault"),
+        PanicOrigin::UserspaceFault { exception_message, .. } =>
+            writeln!(w, "Origin: userspace fault\nMessage: {}", exception_message),
+        PanicOrigin::Watchdog { reason, .. } =>
+            writeln!(w, "Origin: watchdog\nMessage: {}", reason),
+    };
+
+    let ticks = watchdog::ticks();
+    let _ = writeln!(w, "Uptime: {}ms ({} ticks)", watchdog::ticks_to_ms(ticks), ticks);
+
+    match current_thread {
+        Some(thread) => {
+            let _ = writeln!(w, "Process: {} (pid {})", thread.process.name.trim_end_matches('\0'), thread.process.pid);
+            let _ = writeln!(w, "Thread: {}", thread.tid);
+        }
+        None => {
+            let _ = writeln!(w, "Process: none");
+        }
+    }
+
+    let _ = writeln!(w);
+    write_hardware_context(w, panic_origin);
+
+    let _ = writeln!(w, "\nBacktrace:");
+    let source = match panic_origin {
+        PanicOrigin::KernelFault { kernel_hardware_context: registers, .. }
+        | PanicOrigin::Watchdog { hardware_context: registers, .. } =>
+            Some(StackDumpSource::new(registers.esp, registers.ebp, registers.eip)),
+        PanicOrigin::KernelAssert { .. } | PanicOrigin::DoubleFault { .. } =>
+            Some(StackDumpSource::from_current_stack()),
+        PanicOrigin::UserspaceFault { .. } => None,
+    };
+    match source {
+        Some(source) => {
+            let mut frame_nb = 0;
+            walk_current_kernel_stack(&source, elf_symbols, MAX_BACKTRACE_FRAMES, |eip, funcname| {
+                let _ = writeln!(w, "  #{} {:#010x} - {}", frame_nb, eip, funcname);
+                frame_nb += 1;
+            });
+        }
+        None => {
+            let _ = writeln!(w, "  <userspace, see the core dump of the process>");
+        }
+    }
+
+    let _ = writeln!(w, "\nHeld locks:");
+    lock_tracking::dump_held_locks(w);
+
+    let _ = writeln!(w, "\nLast {} log lines:", MAX_LOG_LINES);
+    history::dump_last_lines(w, MAX_LOG_LINES);
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn report_writer_truncates() {
+        let mut buf = [0u8; 8];
+        let mut w = ReportWriter { buf: &mut buf, pos: 0 };
+        let _ = write!(w, "Panic! at the {}", "disco");
+        assert_eq!(w.pos, 8);
+        assert_eq!(&buf, b"Panic! a");
+    }
+
+    #[test_case]
+    fn fnv1a_known_values() {
+        assert_eq!(fnv1a(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+    }
+}
@@ -3,6 +3,8 @@
 use crate::stack::KernelStack;
 use crate::i386::process_switch::*;
 use crate::paging::process_memory::ProcessMemory;
+use crate::paging::device_address_space::DeviceAddressSpace;
+use crate::paging::shared_memory::SharedMemory;
 use alloc::sync::{Arc, Weak};
 use alloc::collections::BTreeMap;
 use alloc::string::String;
@@ -15,14 +17,18 @@ use crate::error::{KernelError, UserspaceError};
 use crate::ipc::{ServerPort, ClientPort, ServerSession, ClientSession};
 use crate::mem::VirtualAddress;
 use failure::Backtrace;
-use crate::frame_allocator::PhysicalMemRegion;
 use crate::sync::SpinRwLock;
+use crate::log_impl::Filter;
 
 use atomic::Atomic;
 
 pub mod thread_local_storage;
 mod capabilities;
-pub use self::capabilities::ProcessCapabilities;
+pub mod cpu_time;
+pub mod coredump;
+pub use self::capabilities::{ProcessCapabilities, IOPB_SIZE};
+use self::cpu_time::{CpuTime, ThreadCpuTime};
+use self::coredump::CoreDump;
 use crate::paging::{InactiveHierarchy, InactiveHierarchyTrait, PAGE_SIZE, MappingAccessRights};
 use self::thread_local_storage::TLSManager;
 use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
@@ -46,6 +52,10 @@ struct ProcessStateData {
     /// The current state of the process.
     state: ProcessState,
 
+    /// The exit code of the process, meaningful once it is Exited. See
+    /// [ProcessStruct::exit_code].
+    exit_code: u32,
+
     /// Threads waiting on this process to get signaled.
     waiting_threads: Vec<Arc<ThreadStruct>>,
 
@@ -129,6 +139,20 @@ pub struct ProcessStruct {
 
     /// Tracks used and free allocated Thread Local Storage regions of this process.
     pub tls_manager: Mutex<TLSManager>,
+
+    /// Log filter applied to the messages this process sends through
+    /// `output_debug_string`. If None, the global kernel filter is used.
+    pub log_filter: SpinRwLock<Option<Filter>>,
+
+    /// CPU time accumulated by the threads of this process that already died.
+    ///
+    /// Updated when a thread is dropped. See [ProcessStruct::cpu_time].
+    exited_threads_cpu_time: SpinLockIRQ<CpuTime>,
+
+    /// Core dump of this process, made when it crashed.
+    ///
+    /// See [ProcessStruct::crash_current_process].
+    pub core_dump: Mutex<Option<CoreDump>>,
 }
 
 /// Next available PID.
@@ -136,9 +160,17 @@ pub struct ProcessStruct {
 /// PIDs are just allocated sequentially in ascending order, and reaching usize::max_value() causes a panic.
 static NEXT_PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
 
+/// Next available thread ID.
+///
+/// Thread IDs are global and allocated sequentially, the same way PIDs are.
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(0);
+
 /// The struct representing a thread. A process may own multiple threads.
 #[derive(Debug)]
 pub struct ThreadStruct {
+    /// The unique id of this thread.
+    pub tid: usize,
+
     /// The state of this thread.
     pub state: Atomic<ThreadState>,
 
@@ -177,6 +209,9 @@ pub struct ThreadStruct {
     /// Registers are backed up every time we enter the kernel via a syscall/exception, for debug purposes.
     pub userspace_hwcontext: SpinLockIRQ<UserspaceHardwareContext>,
 
+    /// Time this thread spent running in userspace and in the kernel.
+    pub cpu_time: ThreadCpuTime,
+
     /// Thread state event
     ///
     /// This is used when signaling that this thread as exited.
@@ -223,8 +258,12 @@ pub enum Handle {
     Process(Arc<ProcessStruct>),
     /// A shared memory region. The handle holds on to the underlying physical
     /// memory, which means the memory will only get freed once all handles to
-    /// it are dropped.
-    SharedMemory(Arc<SpinRwLock<Vec<PhysicalMemRegion>>>),
+    /// it are dropped, and all its mappings are unmapped. See
+    /// [crate::paging::shared_memory] for more information.
+    SharedMemory(Arc<SharedMemory>),
+    /// The address space of some PCI devices, translated by the IOMMU. See
+    /// [crate::paging::device_address_space] for more information.
+    DeviceAddressSpace(Arc<DeviceAddressSpace>),
 }
 
 /// The underlying shared object of a [Weak<ThreadStrct>].
@@ -339,15 +378,40 @@ impl Handle {
         }
     }
 
-    /// Casts the handle as an Arc<SpinRwLock<Vec<[PhysicalMemRegion]>>>, or returns a
-    /// `UserspaceError`.
-    pub fn as_shared_memory(&self) -> Result<Arc<SpinRwLock<Vec<PhysicalMemRegion>>>, UserspaceError> {
+    /// Casts the handle as an Arc<[SharedMemory]>, or returns a `UserspaceError`.
+    pub fn as_shared_memory(&self) -> Result<Arc<SharedMemory>, UserspaceError> {
         if let Handle::SharedMemory(ref s) = *self {
             Ok((*s).clone())
         } else {
             Err(UserspaceError::InvalidHandle)
         }
     }
+
+    /// Casts the handle as a [DeviceAddressSpace], or returns a `UserspaceError`.
+    pub fn as_device_address_space(&self) -> Result<Arc<DeviceAddressSpace>, UserspaceError> {
+        if let Handle::DeviceAddressSpace(ref s) = *self {
+            Ok((*s).clone())
+        } else {
+            Err(UserspaceError::InvalidHandle)
+        }
+    }
+
+    /// Gets the name of the kind of kernel object this handle points to.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Handle::InterruptEvent(_) => "InterruptEvent",
+            Handle::ReadableEvent(_) => "ReadableEvent",
+            Handle::WritableEvent(_) => "WritableEvent",
+            Handle::ServerPort(_) => "ServerPort",
+            Handle::ClientPort(_) => "ClientPort",
+            Handle::ServerSession(_) => "ServerSession",
+            Handle::ClientSession(_) => "ClientSession",
+            Handle::Thread(_) => "Thread",
+            Handle::Process(_) => "Process",
+            Handle::SharedMemory(_) => "SharedMemory",
+            Handle::DeviceAddressSpace(_) => "DeviceAddressSpace",
+        }
+    }
 }
 
 /// Holds the table associating userspace handle numbers to a kernel [Handle].
@@ -447,6 +511,12 @@ impl HandleTable {
         // TODO: Handle 0xFFFF8000 and 0xFFFF8001 ?
         self.table.remove(&handle).ok_or(UserspaceError::InvalidHandle)
     }
+
+    /// Iterates over the handles of this table, in ascending handle number
+    /// order. The meta-handles are not part of it.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Arc<Handle>)> {
+        self.table.iter().map(|(handlenum, handle)| (*handlenum, handle))
+    }
 }
 
 /// The state of a thread.
@@ -491,8 +561,19 @@ impl ProcessStruct {
     /// # Panics
     ///
     /// Panics if max PID has been reached, which it shouldn't have since we're the first process.
+    ///
+    /// # Errors
+    ///
+    /// - `NotImplemented`: `procinfo` gives a resource limit.
+    /// - see [ProcessCapabilities::parse_kcaps].
     // todo: return an error instead of panicking
     pub fn new(procinfo: &ProcInfo, kacs: Option<&[u8]>) -> Result<Arc<ProcessStruct>, KernelError> {
+        // We don't have resource limit objects yet, every process gets the
+        // memory limit of a process without one.
+        if procinfo.resource_limit_handle.is_some() {
+            return Err(KernelError::NotImplemented { msg: "Resource limits", backtrace: Backtrace::new() });
+        }
+
         // allocate its memory space
         let pmemory = Mutex::new(ProcessMemory::default());
 
@@ -517,6 +598,7 @@ impl ProcessStruct {
                 pmemory,
                 state: Mutex::new(ProcessStateData {
                     state: ProcessState::Created,
+                    exit_code: 0,
                     signaled: false,
                     waiting_threads: Vec::new(),
                     thread_maternity: Vec::new(),
@@ -524,6 +606,9 @@ impl ProcessStruct {
                 threads: SpinLockIRQ::new(Vec::new()),
                 phandles: SpinLockIRQ::new(HandleTable::default()),
                 tls_manager: Mutex::new(TLSManager::default()),
+                log_filter: SpinRwLock::new(None),
+                exited_threads_cpu_time: SpinLockIRQ::new(CpuTime::default()),
+                core_dump: Mutex::new(None),
                 capabilities
             }
         );
@@ -560,8 +645,14 @@ impl ProcessStruct {
         // Allocate stack within new map region.
         let stack_size = sunrise_libutils::align_up(stack_size, PAGE_SIZE);
         let mut pmem = this.pmemory.lock();
-        let stack_addr = pmem.find_available_space(stack_size)?;
-        pmem.create_regular_mapping(stack_addr, stack_size, MemoryType::Stack, MappingAccessRights::u_rw())?;
+        // A page guard below the stack turns an overflow into a page fault.
+        let guard_addr = pmem.find_available_space(stack_size + PAGE_SIZE)?;
+        let stack_addr = guard_addr + PAGE_SIZE;
+        pmem.guard(guard_addr, PAGE_SIZE, MemoryType::Reserved)?;
+        if let Err(err) = pmem.create_regular_mapping(stack_addr, stack_size, MemoryType::Stack, MappingAccessRights::u_rw()) {
+            pmem.unmap(guard_addr, PAGE_SIZE).expect("Unmap can't fail.");
+            return Err(err.into());
+        }
         core::mem::drop(pmem);
 
         // Set self.mainThreadStackSize = stack_size.
@@ -594,6 +685,33 @@ impl ProcessStruct {
         Ok(())
     }
 
+    /// Finds the living process with the given pid.
+    ///
+    /// The process list is copied before upgrading its elements, so dropping
+    /// the last reference to a process here does not deadlock on it.
+    pub fn find_by_pid(pid: usize) -> Option<Arc<ProcessStruct>> {
+        let processes = PROCESS_LIST.lock().clone();
+        processes.iter()
+            .filter_map(Weak::upgrade)
+            .find(|process| process.pid == pid)
+    }
+
+    /// Gets the CPU time used by this process so far.
+    ///
+    /// This is the sum of the time used by all of its threads, including the
+    /// ones that already exited.
+    pub fn cpu_time(&self) -> CpuTime {
+        let threads: Vec<Arc<ThreadStruct>> = self.threads.lock().iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+
+        let mut time = *self.exited_threads_cpu_time.lock();
+        for thread in &threads {
+            time += thread.cpu_time.get();
+        }
+        time
+    }
+
     /// Gets the state of this process.
     pub fn state(&self) -> ProcessState {
         // Note: In nintendo, this code is *always* protected by a critical
@@ -602,6 +720,26 @@ impl ProcessStruct {
         self.state.lock().state
     }
 
+    /// Gets the state of this process, or None if its state is locked.
+    ///
+    /// Never blocks, so it can be used from the panic handler.
+    pub fn try_state(&self) -> Option<ProcessState> {
+        self.state.try_lock().ok().map(|data| data.state)
+    }
+
+    /// Gets the exit code of this process, or None if it has not exited yet.
+    ///
+    /// It is the code given to `ExitProcessWithCode`, 0 if the process used
+    /// `ExitProcess`, or 128 + the unix signal if it crashed or was killed.
+    pub fn exit_code(&self) -> Option<u32> {
+        let statelock = self.state.lock();
+        if statelock.state == ProcessState::Exited {
+            Some(statelock.exit_code)
+        } else {
+            None
+        }
+    }
+
     /// Clears the signaled state of this process.
     ///
     /// If the state is Exited, this function will return an error and the
@@ -664,10 +802,14 @@ impl ProcessStruct {
                 state: Mutex::new(ProcessStateData {
                     signaled: false,
                     state: ProcessState::Started,
+                    exit_code: 0,
                     waiting_threads: Vec::new(),
                     thread_maternity: Vec::new(),
                 }),
                 tls_manager: Mutex::new(TLSManager::default()),
+                log_filter: SpinRwLock::new(None),
+                exited_threads_cpu_time: SpinLockIRQ::new(CpuTime::default()),
+                core_dump: Mutex::new(None),
                 capabilities: ProcessCapabilities::default(),
         }
     }
@@ -681,7 +823,10 @@ impl ProcessStruct {
     ///
     /// We also mark the process struct as killed to prevent race condition with
     /// another thread that would want to spawn a thread after we killed all ours.
-    pub fn kill_current_process() {
+    ///
+    /// `exit_code` is reported to whoever waits on the process, see
+    /// [ProcessStruct::exit_code].
+    pub fn kill_current_process(exit_code: u32) {
         let this = scheduler::get_current_process();
         let statelock = this.state.lock();
 
@@ -714,14 +859,40 @@ impl ProcessStruct {
         // KProcess::SignalExit()
 
         // We'll simply kill our subthreads for now.
-        this.kill_subthreads(statelock);
+        this.kill_subthreads(statelock, exit_code);
     }
 
-    /// Kill all the subthreads of this process, and set the state to exited.
-    fn kill_subthreads(&self, mut statelock: crate::sync::mutex::MutexGuard<ProcessStateData>) {
+    /// Kills the current process after it faulted, keeping a core dump of it
+    /// around for the loader to save. See [coredump].
+    ///
+    /// `signal` is the unix signal reported in the core dump.
+    pub fn crash_current_process(signal: u32) {
+        let this = scheduler::get_current_process();
+        let current_tid = scheduler::get_current_thread().tid;
+
+        // Don't upgrade the threads with the lock held, dropping the last
+        // reference to a thread locks it again.
+        let threads = this.threads.lock().clone();
+        let mut contexts: Vec<(usize, UserspaceHardwareContext)> = threads.iter()
+            .filter_map(Weak::upgrade)
+            .map(|thread| (thread.tid, *thread.userspace_hwcontext.lock()))
+            .collect();
+        // The crashing thread comes first.
+        contexts.sort_by_key(|(tid, _)| *tid != current_tid);
+
+        let core_dump = CoreDump::new(this.pid, &this.name, signal, &this.pmemory.lock(), &contexts);
+        *this.core_dump.lock() = Some(core_dump);
+
+        Self::kill_current_process(coredump::signal_exit_code(signal));
+    }
+
+    /// Kill all the subthreads of this process, and set the state to exited,
+    /// with the given exit code.
+    fn kill_subthreads(&self, mut statelock: crate::sync::mutex::MutexGuard<ProcessStateData>, exit_code: u32) {
         // We're going to make things a **lot** simpler. We're just
         // going to immediately set ourselves as exiting, kill our
         // threads, and set ourselves as exited.
+        statelock.exit_code = exit_code;
         statelock.set_state(ProcessState::Exiting);
 
         // kill our baby threads. Those threads have never run, we don't even bother
@@ -740,7 +911,8 @@ impl ProcessStruct {
     }
 
     /// Kills the given process, terminating the execution of all of its thread and
-    /// putting its state to Exiting/Exited.
+    /// putting its state to Exiting/Exited. Its exit code will be the one of a
+    /// process killed by SIGKILL.
     ///
     /// Returns an error if used on a process that wasn't started.
     ///
@@ -763,7 +935,7 @@ impl ProcessStruct {
                 // KProcess::SignalExit(self)
 
                 // Let's do the simple thing:
-                self.kill_subthreads(statelock);
+                self.kill_subthreads(statelock, coredump::signal_exit_code(coredump::SIGKILL));
             },
             ProcessState::Exiting | ProcessState::Exited => {
                 // Already exiting, do nothing.
@@ -838,6 +1010,7 @@ impl ThreadStruct {
 
         let t = Arc::new(
             ThreadStruct {
+                tid: NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst),
                 state,
                 kstack,
                 hwcontext : empty_hwcontext,
@@ -845,6 +1018,7 @@ impl ThreadStruct {
                 tls_region: tls,
                 tls_elf: SpinLock::new(VirtualAddress(0x00000000)),
                 userspace_hwcontext: SpinLockIRQ::new(UserspaceHardwareContext::default()),
+                cpu_time: ThreadCpuTime::default(),
                 state_event: ThreadStateEvent {
                     waiting_threads: SpinLock::new(Vec::new())
                 },
@@ -936,6 +1110,7 @@ impl ThreadStruct {
 
         let t = Arc::new(
             ThreadStruct {
+                tid: NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst),
                 state,
                 kstack,
                 hwcontext,
@@ -943,6 +1118,7 @@ impl ThreadStruct {
                 tls_region: tls,
                 tls_elf: SpinLock::new(VirtualAddress(0x00000000)),
                 userspace_hwcontext: SpinLockIRQ::new(UserspaceHardwareContext::default()),
+                cpu_time: ThreadCpuTime::default(),
                 state_event: ThreadStateEvent {
                     waiting_threads: SpinLock::new(Vec::new())
                 },
@@ -1023,12 +1199,95 @@ impl Drop for ThreadStruct {
     /// Late thread death notifications:
     ///
     /// * notifies our process that our TLS can be re-used.
+    /// * adds our CPU time to the process' total.
     fn drop(&mut self) {
         unsafe {
             // safe: we're being dropped, our TLS will not be reused by us.
             self.process.tls_manager.lock().free_tls(self.tls_region);
         }
+        *self.process.exited_threads_cpu_time.lock() += self.cpu_time.get();
         // todo this should be a debug !
         info!("💀 Dropped a thread : {}", self.process.name)
     }
 }
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use sunrise_libkern::nr;
+
+    /// Reads the ELF header of the core dump of the given process.
+    fn core_dump_header(process: &ProcessStruct) -> Option<[u8; 52]> {
+        let core_dump = process.core_dump.lock();
+        let core_dump = core_dump.as_ref()?;
+        let mut header = [0; 52];
+        let read = core_dump.read(&process.pmemory.lock(), 0, &mut header);
+        assert_eq!(read, header.len());
+        Some(header)
+    }
+
+    #[test_case]
+    fn exit_process_exits_cleanly() {
+        let process = spawn_test_process("exit", &[
+            0xB8, 0x07, 0x00, 0x00, 0x00, // mov eax, ExitProcess
+            0xCD, 0x80,                   // int 0x80
+            0x0F, 0x0B,                   // ud2
+        ], &[nr::ExitProcess]);
+        wait_process_exit(&process);
+        assert!(core_dump_header(&process).is_none(), "Clean exit produced a core dump");
+        assert_eq!(process.exit_code(), Some(0));
+    }
+
+    #[test_case]
+    fn exit_process_with_code_records_code() {
+        let process = spawn_test_process("exitcode", &[
+            0xB8, 0x89, 0x00, 0x00, 0x00, // mov eax, ExitProcessWithCode
+            0xBB, 0x2A, 0x00, 0x00, 0x00, // mov ebx, 42
+            0xCD, 0x80,                   // int 0x80
+            0x0F, 0x0B,                   // ud2
+        ], &[nr::ExitProcessWithCode]);
+        wait_process_exit(&process);
+        assert_eq!(process.exit_code(), Some(42));
+    }
+
+    #[test_case]
+    fn exit_process_reports_tls_exit_code() {
+        assert_eq!(sunrise_libkern::TLS_EXIT_CODE_OFFSET, 0x1F0);
+        let process = spawn_test_process("tlsexit", &[
+            0x64, 0xC7, 0x05, 0xF0, 0x01, 0x00, 0x00, // mov dword [fs:0x1F0], 1
+            0x01, 0x00, 0x00, 0x00,
+            0xB8, 0x07, 0x00, 0x00, 0x00,             // mov eax, ExitProcess
+            0xCD, 0x80,                               // int 0x80
+            0x0F, 0x0B,                               // ud2
+        ], &[nr::ExitProcess]);
+        wait_process_exit(&process);
+        assert!(core_dump_header(&process).is_none(), "Exiting with a TLS exit code produced a core dump");
+        assert_eq!(process.exit_code(), Some(1));
+    }
+
+    #[test_case]
+    fn page_fault_kills_process_with_core_dump() {
+        let process = spawn_test_process("pagefault", &[
+            0xA1, 0x00, 0x00, 0x00, 0x00, // mov eax, [0]
+            0x0F, 0x0B,                   // ud2
+        ], &[]);
+        wait_process_exit(&process);
+        let header = core_dump_header(&process).expect("Page fault did not produce a core dump");
+        assert_eq!(&header[..4], b"\x7fELF");
+        // e_type == ET_CORE
+        assert_eq!(u16::from_le_bytes([header[16], header[17]]), 4);
+    }
+
+    #[test_case]
+    fn unauthorized_syscall_kills_process() {
+        let process = spawn_test_process("nosyscall", &[
+            0xB8, 0x07, 0x00, 0x00, 0x00, // mov eax, ExitProcess
+            0xCD, 0x80,                   // int 0x80
+            0xEB, 0xFE,                   // jmp $
+        ], &[]);
+        wait_process_exit(&process);
+        assert!(core_dump_header(&process).is_some(), "Unauthorized syscall did not produce a core dump");
+        assert_eq!(process.exit_code(), Some(128 + coredump::SIGSYS));
+    }
+}
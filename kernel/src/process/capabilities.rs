@@ -12,6 +12,7 @@
 //! architecture to architecture. Arch-specific methods will be marked as so
 //! in their documentation.
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use crate::error::KernelError;
 use failure::Backtrace;
@@ -48,6 +49,30 @@ pub struct ProcessCapabilities {
     ///
     /// Present on x86 platforms.
     pub ioports:         Vec<u16>,
+
+    /// The IO permission bitmap allowing the `ioports`, built once when the
+    /// process is created and copied in the TSS when switching to it. None if
+    /// no port is allowed.
+    ///
+    /// Present on x86 platforms.
+    pub iopb:            Option<Box<[u8]>>,
+
+    /// Whether this process allows the debug syscalls to be used on it.
+    ///
+    /// Present on every architecture.
+    pub can_be_debugged: bool,
+
+    /// Whether this process can use the debug syscalls on other processes,
+    /// even those that don't allow it.
+    ///
+    /// Present on every architecture.
+    pub can_debug_others: bool,
+
+    /// The (class, subclass) pairs of the PCI devices this process is allowed
+    /// to attach to a DeviceAddressSpace.
+    ///
+    /// Present on x86 platforms.
+    pub pci_classes:     Vec<(u8, u8)>,
 }
 
 /// Wrapper around a bitfield that only prints the indices of set bits.
@@ -71,6 +96,9 @@ impl fmt::Debug for ProcessCapabilities {
             .field("syscall_mask", &MaskPrinter(&self.syscall_mask))
             .field("irq_access_mask", &MaskPrinter(&self.irq_access_mask))
             .field("ioports", &self.ioports)
+            .field("can_be_debugged", &self.can_be_debugged)
+            .field("can_debug_others", &self.can_debug_others)
+            .field("pci_classes", &self.pci_classes)
             .finish()
     }
 }
@@ -97,6 +125,12 @@ const DEBUG_FLAGS: u32 = 16;
 // Sunrise extension
 /// IOPorts the process is allowed to talk to
 const IO_PORTS_ALLOWED: u32 = 10;
+/// Class of the PCI devices the process is allowed to attach to a
+/// DeviceAddressSpace.
+const PCI_CLASS_ALLOWED: u32 = 9;
+
+/// Size of an IO permission bitmap, one bit for each of the 0x10000 IO ports.
+pub const IOPB_SIZE: usize = 0x10000 / 8;
 
 /// The highest defined svc.
 const MAX_SVC: usize = ::sunrise_libkern::nr::MaxSvc;
@@ -115,6 +149,10 @@ impl Default for ProcessCapabilities {
             syscall_mask: [0; 256 / (8 * 4)],
             irq_access_mask: [0; 128],
             ioports: Vec::new(),
+            iopb: None,
+            can_be_debugged: false,
+            can_debug_others: false,
+            pci_classes: Vec::new(),
         }
     }
 }
@@ -146,6 +184,8 @@ impl ProcessCapabilities {
     /// - HandleTableSize: bit set in the 31..26 range
     /// - DebugFlags: bits set in the 31..19 range
     /// - ApplicationType: bits set in the 31..17 range
+    /// - IoPortsAllowed: bits set in the 31..27 range
+    /// - PciClassAllowed: bits set in the 15..10 range
     ///
     /// [switchbrew]: http://switchbrew.org/index.php?title=NPDM#Kernel_Access_Control
     pub fn parse_kcaps(kacs: &[u8]) -> Result<ProcessCapabilities, KernelError> {
@@ -153,6 +193,10 @@ impl ProcessCapabilities {
             syscall_mask: [0; 256 / (8 * 4)],
             irq_access_mask: [0; 128],
             ioports: Vec::new(),
+            iopb: None,
+            can_be_debugged: false,
+            can_debug_others: false,
+            pci_classes: Vec::new(),
         };
 
         let mut kac_iter = kacs.chunks(4);
@@ -272,13 +316,13 @@ impl ProcessCapabilities {
                     }
                 }
                 DEBUG_FLAGS => {
-                    let _can_be_debugged = kac.get_bit(17);
-                    let _can_debug_others = kac.get_bit(18);
                     if kac.get_bits(19..32) != 0 {
                         return Err(KernelError::ReservedValue {
                             backtrace: Backtrace::new()
                         })
                     }
+                    capabilities.can_be_debugged = kac.get_bit(17);
+                    capabilities.can_debug_others = kac.get_bit(18);
                 }
                 IO_PORTS_ALLOWED => {
                     let ioport = kac.get_bits(11..27) as u16;
@@ -289,6 +333,16 @@ impl ProcessCapabilities {
                     }
                     capabilities.ioports.push(ioport);
                 }
+                PCI_CLASS_ALLOWED => {
+                    if kac.get_bits(10..16) != 0 {
+                        return Err(KernelError::ReservedValue {
+                            backtrace: Backtrace::new()
+                        })
+                    }
+                    let class = kac.get_bits(16..24) as u8;
+                    let subclass = kac.get_bits(24..32) as u8;
+                    capabilities.pci_classes.push((class, subclass));
+                }
                 _ => {
                     return Err(KernelError::InvalidKernelCaps {
                         kcap: kac,
@@ -298,6 +352,64 @@ impl ProcessCapabilities {
             }
         }
 
+        capabilities.iopb = build_iopb(&capabilities.ioports);
+
         Ok(capabilities)
     }
 }
+
+/// Builds the IO permission bitmap allowing only `ioports`, or None if there
+/// are none.
+fn build_iopb(ioports: &[u16]) -> Option<Box<[u8]>> {
+    if ioports.is_empty() {
+        return None;
+    }
+    let mut iopb = vec![0xFF; IOPB_SIZE].into_boxed_slice();
+    for ioport in ioports {
+        iopb.set_bit(usize::from(*ioport), false);
+    }
+    Some(iopb)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod test {
+    use super::*;
+
+    /// Encodes an IO_PORTS_ALLOWED kernel capability.
+    fn ioport_kac(ioport: u16) -> [u8; 4] {
+        (0b11_1111_1111 | u32::from(ioport) << 11).to_le_bytes()
+    }
+
+    /// The IOPB only clears the bits of the allowed ports.
+    #[test]
+    fn iopb_allows_only_ioports() {
+        let kacs: Vec<u8> = [0x60, 0x64, 0xFFFF].iter().flat_map(|port| ioport_kac(*port).to_vec()).collect();
+        let caps = ProcessCapabilities::parse_kcaps(&kacs).unwrap();
+        let iopb = caps.iopb.expect("Allowed ports but built no IOPB");
+        assert_eq!(iopb.len(), IOPB_SIZE);
+        assert_eq!(iopb[0x60 / 8], !(1 << 0 | 1 << 4));
+        assert_eq!(iopb[0xFFFF / 8], !(1 << 7));
+        let cleared: u32 = iopb.iter().map(|byte| byte.count_zeros()).sum();
+        assert_eq!(cleared, 3);
+    }
+
+    /// PCI class capabilities are collected, and their reserved bits checked.
+    #[test]
+    fn pci_classes() {
+        let kacs: Vec<u8> = [0x0601, 0x0801].iter()
+            .flat_map(|class: &u32| (0b1_1111_1111 | class << 16).to_le_bytes().to_vec())
+            .collect();
+        let caps = ProcessCapabilities::parse_kcaps(&kacs).unwrap();
+        assert_eq!(caps.pci_classes, [(0x01, 0x06), (0x01, 0x08)]);
+
+        let reserved = (0b1_1111_1111u32 | 1 << 10).to_le_bytes();
+        assert!(ProcessCapabilities::parse_kcaps(&reserved).is_err());
+    }
+
+    /// Processes without IO ports don't carry an IOPB.
+    #[test]
+    fn no_ioports_no_iopb() {
+        let caps = ProcessCapabilities::parse_kcaps(&[]).unwrap();
+        assert!(caps.iopb.is_none());
+    }
+}
@@ -0,0 +1,341 @@
+//! ELF core dumps of crashed processes
+//!
+//! When a userspace process faults, the kernel snapshots the registers of all
+//! its threads and the layout of its address space before killing it. Killing
+//! the threads leaves the address space alone: it lives as long as the
+//! ProcessStruct, which the loader keeps alive through its process handle.
+//! When the loader notices the process exited, it reads the core file through
+//! [read_core_dump] and saves it to `/var/crash/<name>-<pid>.core`.
+//!
+//! The core file is never fully materialized in the kernel. Only its headers
+//! are built at crash time. The contents of the memory segments are copied
+//! straight from the frames of the dead process as the loader reads them.
+//!
+//! The file follows the layout `gdb` expects for i386 Linux cores:
+//!
+//! - An ELF header of type `ET_CORE`.
+//! - A `PT_NOTE` segment, with a `NT_PRPSINFO` note holding the process name,
+//!   and a `NT_PRSTATUS` note for every thread holding its registers. The
+//!   thread that crashed comes first.
+//! - A `PT_LOAD` segment for every mapping of the process. Only the readable
+//!   mappings have their content in the file.
+//!
+//! [read_core_dump]: crate::syscalls::read_core_dump
+
+use core::cmp::min;
+use alloc::vec::Vec;
+use crate::mem::VirtualAddress;
+use crate::paging::{PAGE_SIZE, MappingAccessRights};
+use crate::paging::mapping::MappingFrames;
+use crate::paging::process_memory::ProcessMemory;
+use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
+use crate::utils::align_up;
+use sunrise_libkern::MemoryType;
+
+/// Illegal instruction.
+pub const SIGILL: u32 = 4;
+/// Trace/breakpoint trap.
+pub const SIGTRAP: u32 = 5;
+/// Arithmetic exception.
+pub const SIGFPE: u32 = 8;
+/// Killed.
+pub const SIGKILL: u32 = 9;
+/// Invalid memory reference.
+pub const SIGSEGV: u32 = 11;
+/// Bad system call.
+pub const SIGSYS: u32 = 31;
+
+/// Gets the exit code of a process killed by the given signal, following the
+/// convention of unix shells.
+pub fn signal_exit_code(signal: u32) -> u32 {
+    128 + signal
+}
+
+/// Gets the unix signal gdb should report for the given exception.
+pub fn exception_signal(exception_name: &str) -> u32 {
+    match exception_name {
+        "Divide Error Exception" | "x87 FPU floating-point error" |
+        "SIMD Floating-Point Exception" => SIGFPE,
+        "Invalid opcode Exception" | "Device Not Available Exception" => SIGILL,
+        "Breakpoint Exception" | "Debug Exception" => SIGTRAP,
+        _ => SIGSEGV,
+    }
+}
+
+/// Size of the ELF header of a 32-bit ELF.
+const EHDR_SIZE: usize = 52;
+/// Size of a program header of a 32-bit ELF.
+const PHDR_SIZE: usize = 32;
+/// Size of `struct elf_prpsinfo` on i386.
+const PRPSINFO_SIZE: usize = 124;
+/// Size of `struct elf_prstatus` on i386.
+const PRSTATUS_SIZE: usize = 144;
+/// Size of a note header, and of its "CORE" name padded to 4 bytes.
+const NOTE_OVERHEAD: usize = 12 + 8;
+
+/// Type of the note holding the registers of a thread.
+const NT_PRSTATUS: u32 = 1;
+/// Type of the note holding the name of the process.
+const NT_PRPSINFO: u32 = 3;
+
+/// Segment flag: executable.
+const PF_X: u32 = 1;
+/// Segment flag: writable.
+const PF_W: u32 = 2;
+/// Segment flag: readable.
+const PF_R: u32 = 4;
+
+/// Maximum amount of memory mirrored in KernelLand at once when reading a
+/// segment.
+const MAX_MIRROR_SIZE: usize = 16 * PAGE_SIZE;
+
+/// A memory segment of the core file whose content lives in the dead process'
+/// address space.
+#[derive(Debug)]
+struct CoreSegment {
+    /// Address of the segment in the dead process.
+    address: VirtualAddress,
+    /// Length of the segment.
+    length: usize,
+    /// Offset of the segment in the core file.
+    file_offset: usize,
+}
+
+/// The core dump of a crashed process. Stored in its ProcessStruct.
+#[derive(Debug)]
+pub struct CoreDump {
+    /// The ELF header, program headers and notes.
+    headers: Vec<u8>,
+    /// The segments whose content is part of the file, sorted by file offset.
+    segments: Vec<CoreSegment>,
+    /// The total size of the core file.
+    size: usize,
+}
+
+/// Little-endian writers for the header buffer.
+trait PushLe {
+    /// Appends a u16.
+    fn push_u16(&mut self, val: u16);
+    /// Appends a u32.
+    fn push_u32(&mut self, val: u32);
+    /// Appends a note header, followed by its name.
+    fn push_note_header(&mut self, ty: u32, desc_size: usize);
+}
+
+impl PushLe for Vec<u8> {
+    fn push_u16(&mut self, val: u16) {
+        self.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn push_u32(&mut self, val: u32) {
+        self.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn push_note_header(&mut self, ty: u32, desc_size: usize) {
+        self.push_u32(5);
+        self.push_u32(desc_size as u32);
+        self.push_u32(ty);
+        self.extend_from_slice(b"CORE\0\0\0\0");
+    }
+}
+
+impl CoreDump {
+    /// Builds the headers of the core dump of a process.
+    ///
+    /// `threads` are the registers of every thread, by tid, the crashing thread
+    /// first. `signal` is the signal reported for the crashing thread.
+    pub fn new(pid: usize, name: &str, signal: u32, pmemory: &ProcessMemory, threads: &[(usize, UserspaceHardwareContext)]) -> CoreDump {
+        let mappings: Vec<_> = pmemory.mappings().collect();
+
+        let note_size = NOTE_OVERHEAD + PRPSINFO_SIZE + threads.len() * (NOTE_OVERHEAD + PRSTATUS_SIZE);
+        let note_offset = EHDR_SIZE + (1 + mappings.len()) * PHDR_SIZE;
+        let mut data_offset = align_up(note_offset + note_size, PAGE_SIZE);
+
+        let mut headers = Vec::with_capacity(note_offset + note_size);
+
+        // ELF header.
+        headers.extend_from_slice(b"\x7FELF");
+        headers.extend_from_slice(&[1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]); // ELFCLASS32, ELFDATA2LSB, EV_CURRENT
+        headers.push_u16(4); // ET_CORE
+        headers.push_u16(3); // EM_386
+        headers.push_u32(1); // EV_CURRENT
+        headers.push_u32(0); // e_entry
+        headers.push_u32(EHDR_SIZE as u32); // e_phoff
+        headers.push_u32(0); // e_shoff
+        headers.push_u32(0); // e_flags
+        headers.push_u16(EHDR_SIZE as u16);
+        headers.push_u16(PHDR_SIZE as u16);
+        headers.push_u16((1 + mappings.len()) as u16);
+        headers.push_u16(0); // e_shentsize
+        headers.push_u16(0); // e_shnum
+        headers.push_u16(0); // e_shstrndx
+
+        // PT_NOTE.
+        headers.push_u32(4);
+        headers.push_u32(note_offset as u32);
+        headers.push_u32(0);
+        headers.push_u32(0);
+        headers.push_u32(note_size as u32);
+        headers.push_u32(0);
+        headers.push_u32(0);
+        headers.push_u32(4);
+
+        // PT_LOADs.
+        let mut segments = Vec::new();
+        for mapping in mappings {
+            let flags = mapping.flags();
+            let dumped = flags.contains(MappingAccessRights::READABLE)
+                && mapping.state().ty() != MemoryType::Io
+                && matches!(mapping.frames(), MappingFrames::Shared(_) | MappingFrames::Lazy(_));
+            let file_size = if dumped { mapping.length() } else { 0 };
+
+            let mut p_flags = 0;
+            if flags.contains(MappingAccessRights::READABLE) { p_flags |= PF_R; }
+            if flags.contains(MappingAccessRights::WRITABLE) { p_flags |= PF_W; }
+            if flags.contains(MappingAccessRights::EXECUTABLE) { p_flags |= PF_X; }
+
+            headers.push_u32(1);
+            headers.push_u32(data_offset as u32);
+            headers.push_u32(mapping.address().addr() as u32);
+            headers.push_u32(0);
+            headers.push_u32(file_size as u32);
+            headers.push_u32(mapping.length() as u32);
+            headers.push_u32(p_flags);
+            headers.push_u32(PAGE_SIZE as u32);
+
+            if dumped {
+                segments.push(CoreSegment {
+                    address: mapping.address(),
+                    length: mapping.length(),
+                    file_offset: data_offset,
+                });
+                data_offset += mapping.length();
+            }
+        }
+
+        // NT_PRPSINFO.
+        headers.push_note_header(NT_PRPSINFO, PRPSINFO_SIZE);
+        headers.extend_from_slice(&[b'R', b'R', 0, 0]); // pr_state, pr_sname, pr_zomb, pr_nice
+        headers.push_u32(0); // pr_flag
+        headers.push_u16(0); // pr_uid
+        headers.push_u16(0); // pr_gid
+        headers.push_u32(pid as u32); // pr_pid
+        headers.push_u32(0); // pr_ppid
+        headers.push_u32(pid as u32); // pr_pgrp
+        headers.push_u32(pid as u32); // pr_sid
+        let mut fname = [0; 16];
+        let mut psargs = [0; 80];
+        let name = name.trim_end_matches('\0').as_bytes();
+        fname[..min(name.len(), 15)].copy_from_slice(&name[..min(name.len(), 15)]);
+        psargs[..min(name.len(), 79)].copy_from_slice(&name[..min(name.len(), 79)]);
+        headers.extend_from_slice(&fname);
+        headers.extend_from_slice(&psargs);
+
+        // NT_PRSTATUS, one per thread.
+        for (idx, (tid, hwcontext)) in threads.iter().enumerate() {
+            let signal = if idx == 0 { signal } else { 0 };
+            headers.push_note_header(NT_PRSTATUS, PRSTATUS_SIZE);
+            headers.push_u32(signal); // si_signo
+            headers.push_u32(0); // si_code
+            headers.push_u32(0); // si_errno
+            headers.push_u16(signal as u16); // pr_cursig
+            headers.push_u16(0); // padding
+            headers.push_u32(0); // pr_sigpend
+            headers.push_u32(0); // pr_sighold
+            headers.push_u32(*tid as u32); // pr_pid
+            headers.push_u32(0); // pr_ppid
+            headers.push_u32(pid as u32); // pr_pgrp
+            headers.push_u32(pid as u32); // pr_sid
+            headers.extend_from_slice(&[0; 32]); // pr_utime, pr_stime, pr_cutime, pr_cstime
+            // pr_reg, in the order of the i386 user_regs_struct.
+            for reg in &[hwcontext.ebx, hwcontext.ecx, hwcontext.edx, hwcontext.esi,
+                         hwcontext.edi, hwcontext.ebp, hwcontext.eax,
+                         0, 0, 0, hwcontext.gs, // ds, es, fs, gs
+                         0xFFFF_FFFF, // orig_eax: not in a syscall
+                         hwcontext.eip, hwcontext.cs, hwcontext.eflags, hwcontext.esp,
+                         0] { // ss
+                headers.push_u32(*reg as u32);
+            }
+            headers.push_u32(0); // pr_fpvalid
+        }
+
+        debug_assert_eq!(headers.len(), note_offset + note_size);
+
+        CoreDump {
+            headers,
+            segments,
+            size: data_offset,
+        }
+    }
+
+    /// The total size of the core file.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the core file at `offset`, filling as much of `out` as possible.
+    ///
+    /// `pmemory` must be the memory of the process this core dump was made of.
+    ///
+    /// Returns the number of bytes read, which is only smaller than `out.len()`
+    /// when reaching the end of the file. Pages of the process that were freed
+    /// since the crash read as zeroes.
+    pub fn read(&self, pmemory: &ProcessMemory, offset: usize, out: &mut [u8]) -> usize {
+        let len = min(out.len(), self.size.saturating_sub(offset));
+        let mut done = 0;
+
+        while done < len {
+            let pos = offset + done;
+            let remaining = len - done;
+            let dst = &mut out[done..len];
+
+            if pos < self.headers.len() {
+                let count = min(remaining, self.headers.len() - pos);
+                dst[..count].copy_from_slice(&self.headers[pos..pos + count]);
+                done += count;
+                continue;
+            }
+
+            let segment = self.segments.iter()
+                .find(|segment| segment.file_offset + segment.length > pos);
+            let count = match segment {
+                Some(segment) if segment.file_offset <= pos => {
+                    let segment_offset = pos - segment.file_offset;
+                    let count = min(min(remaining, segment.length - segment_offset), MAX_MIRROR_SIZE);
+                    let address = segment.address + segment_offset;
+                    match pmemory.mirror_mapping(address, count) {
+                        Ok(mirror) => {
+                            let src = unsafe {
+                                // safe: the mirror maps count bytes, and is kept alive until we're done.
+                                core::slice::from_raw_parts(mirror.addr().addr() as *const u8, count)
+                            };
+                            dst[..count].copy_from_slice(src);
+                            count
+                        },
+                        Err(_) => {
+                            // Most likely an uncommitted lazy page, which reads
+                            // as zeroes. Only skip this page, the next ones
+                            // might be committed.
+                            let count = min(count, PAGE_SIZE - address.addr() % PAGE_SIZE);
+                            for byte in &mut dst[..count] { *byte = 0; }
+                            count
+                        }
+                    }
+                },
+                // Padding before the next segment.
+                Some(segment) => {
+                    let count = min(remaining, segment.file_offset - pos);
+                    for byte in &mut dst[..count] { *byte = 0; }
+                    count
+                },
+                None => {
+                    for byte in dst.iter_mut() { *byte = 0; }
+                    remaining
+                }
+            };
+            done += count;
+        }
+
+        len
+    }
+}
@@ -0,0 +1,108 @@
+//! CPU time accounting
+//!
+//! Every thread keeps track of the time it spent executing userspace code and
+//! kernel code. Time is measured in Time Stamp Counter ticks, the same unit
+//! as the one returned by [get_system_tick].
+//!
+//! The accounting is done by charging the time elapsed since the last
+//! accounting event to either the user or the kernel counter:
+//!
+//! - When entering the kernel from userspace (syscall, exception or IRQ), the
+//!   elapsed time is charged to userspace.
+//! - When returning to userspace, the elapsed time is charged to the kernel.
+//! - When the thread is scheduled out, the elapsed time is charged to the
+//!   kernel, as process switches always happen in kernel mode.
+//! - When the thread is scheduled in, the accounting timestamp is reset,
+//!   ensuring the time the thread spent waiting is not charged to it.
+//!
+//! [get_system_tick]: crate::syscalls::get_system_tick
+
+use crate::sync::SpinLockIRQ;
+use crate::i386::instructions::tsc;
+
+/// CPU time spent by a thread or a process, in TSC ticks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTime {
+    /// Ticks spent executing userspace code.
+    pub user_ticks: u64,
+    /// Ticks spent executing kernel code on behalf of the thread.
+    pub kernel_ticks: u64,
+}
+
+impl CpuTime {
+    /// Total ticks spent running, in both userspace and kernel.
+    pub fn total_ticks(&self) -> u64 {
+        self.user_ticks.saturating_add(self.kernel_ticks)
+    }
+}
+
+impl core::ops::AddAssign for CpuTime {
+    fn add_assign(&mut self, other: CpuTime) {
+        self.user_ticks = self.user_ticks.saturating_add(other.user_ticks);
+        self.kernel_ticks = self.kernel_ticks.saturating_add(other.kernel_ticks);
+    }
+}
+
+/// Internal state of a [ThreadCpuTime].
+#[derive(Debug)]
+struct CpuTimeData {
+    /// Time accumulated so far.
+    time: CpuTime,
+    /// TSC value at the last accounting event.
+    last_timestamp: u64,
+}
+
+/// CPU time accounting of a single thread. Stored in its ThreadStruct.
+#[derive(Debug)]
+pub struct ThreadCpuTime(SpinLockIRQ<CpuTimeData>);
+
+impl Default for ThreadCpuTime {
+    /// Creates a new accounting structure, with both counters at 0.
+    fn default() -> ThreadCpuTime {
+        ThreadCpuTime(SpinLockIRQ::new(CpuTimeData {
+            time: CpuTime::default(),
+            last_timestamp: tsc::read(),
+        }))
+    }
+}
+
+impl ThreadCpuTime {
+    /// Gets the time accumulated so far by this thread.
+    pub fn get(&self) -> CpuTime {
+        self.0.lock().time
+    }
+
+    /// Charges the time elapsed since the last accounting event to userspace
+    /// if `user` is true, or to the kernel otherwise.
+    fn charge(&self, user: bool) {
+        let now = tsc::read();
+        let mut data = self.0.lock();
+        let elapsed = now.saturating_sub(data.last_timestamp);
+        if user {
+            data.time.user_ticks = data.time.user_ticks.saturating_add(elapsed);
+        } else {
+            data.time.kernel_ticks = data.time.kernel_ticks.saturating_add(elapsed);
+        }
+        data.last_timestamp = now;
+    }
+
+    /// Called when the thread enters the kernel from userspace.
+    pub fn enter_kernel(&self) {
+        self.charge(true)
+    }
+
+    /// Called when the thread is about to return to userspace.
+    pub fn leave_kernel(&self) {
+        self.charge(false)
+    }
+
+    /// Called when the thread is about to be scheduled out.
+    pub fn schedule_out(&self) {
+        self.charge(false)
+    }
+
+    /// Called when the thread has just been scheduled in.
+    pub fn schedule_in(&self) {
+        self.0.lock().last_timestamp = tsc::read();
+    }
+}
@@ -0,0 +1,110 @@
+//! Sampling profiler
+//!
+//! When enabled, every tick of the kernel timer records which thread was
+//! interrupted, and the instruction it was executing. Those samples are stored
+//! in a fixed-size cpu-local buffer, that userspace periodically drains through
+//! [control_profiler], and symbolizes to find out where the time goes.
+//!
+//! Samples are recorded from the timer IRQ handler, so recording must never
+//! allocate. The buffer is allocated once, when the profiler is started. If it
+//! fills up before userspace drains it, new samples are dropped and counted.
+//!
+//! [control_profiler]: crate::syscalls::control_profiler
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
+use crate::sync::SpinLockIRQ;
+use crate::scheduler;
+use crate::i386::PrivilegeLevel;
+use crate::i386::structures::gdt::SegmentSelector;
+use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
+use sunrise_libkern::profiler::ProfilerSample;
+
+/// Maximum number of samples kept in the buffer before they get dropped.
+///
+/// With a 10ms timer period, this holds a bit more than 80 seconds of samples.
+const SAMPLE_CAPACITY: usize = 8192;
+
+/// Whether the profiler is currently recording samples.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The samples recorded on this cpu.
+#[derive(Debug)]
+struct SampleBuffer {
+    /// Recorded samples, oldest first. Has a capacity of [SAMPLE_CAPACITY]
+    /// once the profiler has been started.
+    samples: Vec<ProfilerSample>,
+    /// Number of samples dropped because the buffer was full.
+    dropped: usize,
+}
+
+/// The sample buffer of this cpu.
+#[thread_local] // this is a cpu_local
+static SAMPLES: SpinLockIRQ<SampleBuffer> = SpinLockIRQ::new(SampleBuffer {
+    samples: Vec::new(),
+    dropped: 0,
+});
+
+/// Discards the previously recorded samples, and starts recording.
+pub fn start() {
+    // Allocate the new buffer, and free the old one, outside of the lock, like
+    // drain does.
+    let samples = Vec::with_capacity(SAMPLE_CAPACITY);
+
+    let mut buffer = SAMPLES.lock();
+    let old_samples = core::mem::replace(&mut buffer.samples, samples);
+    buffer.dropped = 0;
+    drop(buffer);
+    drop(old_samples);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stops recording. Recorded samples are kept until drained.
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// Removes up to `max` samples from the buffer, oldest first.
+///
+/// Returns the removed samples, along with the number of samples that were
+/// dropped since the last drain.
+pub fn drain(max: usize) -> (Vec<ProfilerSample>, usize) {
+    // Allocate outside of the lock. The timer IRQ could otherwise be masked
+    // for longer than necessary.
+    let mut out = Vec::with_capacity(core::cmp::min(max, SAMPLE_CAPACITY));
+
+    let mut buffer = SAMPLES.lock();
+    let count = core::cmp::min(core::cmp::min(max, out.capacity()), buffer.samples.len());
+    out.extend(buffer.samples.drain(..count));
+    let dropped = core::mem::replace(&mut buffer.dropped, 0);
+    (out, dropped)
+}
+
+/// Records a sample of the interrupted thread. Called on every tick of the
+/// kernel timer, with the hardware context that was interrupted.
+pub fn sample(hwcontext: &UserspaceHardwareContext) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let thread = match scheduler::try_get_current_thread() {
+        Some(thread) => thread,
+        None => return,
+    };
+
+    let in_kernel = SegmentSelector(hwcontext.cs as u16).rpl() == PrivilegeLevel::Ring0;
+    let sample = ProfilerSample {
+        pid: thread.process.pid as u32,
+        tid: thread.tid as u32,
+        eip: hwcontext.eip as u32,
+        in_kernel: u32::from(in_kernel),
+    };
+
+    let mut buffer = SAMPLES.lock();
+    // Never grow the vec, we might be interrupting the allocator.
+    if buffer.samples.len() < buffer.samples.capacity() {
+        buffer.samples.push(sample);
+    } else {
+        buffer.dropped += 1;
+    }
+}
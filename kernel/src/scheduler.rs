@@ -10,6 +10,7 @@ use crate::sync::{Lock, SpinLockIRQ, SpinLockIRQGuard};
 use core::sync::atomic::Ordering;
 use crate::error::{UserspaceError};
 use sunrise_libkern::TLS;
+use crate::mem::UserSpacePtrMut;
 use core::cell::RefCell;
 use crate::cpu_locals::ARE_CPU_LOCALS_INITIALIZED_YET;
 
@@ -43,6 +44,18 @@ pub fn try_get_current_thread() -> Option<Arc<ThreadStruct>> {
     }
 }
 
+/// Gets the id of the current thread, without touching its refcount.
+/// Will return None if we're in an early boot state, or in the middle of switching threads.
+///
+/// Used by the lock tracking, which cannot afford to drop a thread from under a lock.
+pub fn try_get_current_thread_id() -> Option<usize> {
+    if !ARE_CPU_LOCALS_INITIALIZED_YET.load(Ordering::Relaxed) {
+        None
+    } else {
+        CURRENT_THREAD.try_borrow().ok()?.as_ref().map(|t| t.tid)
+    }
+}
+
 /// Gets the current ThreadStruct, incrementing its refcount.
 pub fn get_current_thread() -> Arc<ThreadStruct> {
     try_get_current_thread().unwrap()
@@ -261,6 +274,9 @@ where
     let mut interrupt_lock = interrupt_manager.lock();
 
     loop {
+        // We're scheduling, so we're not stuck.
+        crate::watchdog::touch();
+
         let mut queue = SCHEDULE_QUEUE.lock();
 
         let candidate_index = find_next_thread_to_run(&queue);
@@ -269,6 +285,9 @@ where
                 // There's nobody to schedule. Let's drop all the locks, HLT, and run internal_schedule again.
                 // NOTE: There's nobody running at this point. :O
                 drop(queue);
+                // Don't charge the time spent idling to the current thread.
+                let idle_thread = get_current_thread();
+                idle_thread.cpu_time.schedule_out();
                 // Temporarily revive interrupts for hlt.
                 drop(interrupt_lock);
                 unsafe {
@@ -277,6 +296,7 @@ where
 
                 // Kill interrupts again.
                 interrupt_lock = interrupt_manager.lock();
+                idle_thread.cpu_time.schedule_in();
 
                 // Rerun internal_schedule.
                 continue;
@@ -300,10 +320,13 @@ where
                 drop(queue);
 
                 let whoami = if !Arc::ptr_eq(&process_b, &proc) {
-                    unsafe {
+                    proc.cpu_time.schedule_out();
+                    let whoami = unsafe {
                         // safety: interrupts are disabled by the interrupt_lock.
                         process_switch(process_b, proc)
-                    }
+                    };
+                    whoami.cpu_time.schedule_in();
+                    whoami
                 } else {
                     // Avoid process switching if we're just rescheduling ourselves.
                     proc
@@ -339,6 +362,8 @@ where
 /// Interrupts must be off when calling this function. It will set [`CURRENT_THREAD`], and then
 /// turn them on, as we are running a new thread, no SpinLockIRQ is held.
 pub unsafe fn scheduler_first_schedule<F: FnOnce()>(current_thread: Arc<ThreadStruct>, jump_to_entrypoint: F) {
+    current_thread.cpu_time.schedule_in();
+
     // replace CURRENT_THREAD with ourself.
     // If previously running thread had deleted all other references to itself, this
     // is where its drop actually happens
@@ -354,13 +379,62 @@ pub unsafe fn scheduler_first_schedule<F: FnOnce()>(current_thread: Arc<ThreadSt
 
     // memset the TLS, to clear previous owner's data.
     // we do it here so don't have to CrossProcessMap it earlier.
-    unsafe {
-        // safe: we manage this memory, ptr is aligned, 0 is valid for every field of the TLS,
-        //       and TLS contains no padding bytes.
-        let tls_ptr = get_current_thread().tls_region.addr() as *mut TLS;
-        core::ptr::write_bytes(tls_ptr, 0u8, 1);
-        (*tls_ptr).ptr_self = tls_ptr
+    {
+        let tls_addr = get_current_thread().tls_region.addr();
+        let mut tls = [0u8; mem::size_of::<TLS>()];
+        // ptr_self is the first field of the TLS.
+        tls[..mem::size_of::<usize>()].copy_from_slice(&tls_addr.to_ne_bytes());
+        UserSpacePtrMut::from_raw_parts_mut(tls_addr as *mut u8, tls.len())
+            .write_from_slice(&tls)
+            .expect("Thread's TLS is not mapped");
     }
 
     jump_to_entrypoint()
 }
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::mem::VirtualAddress;
+    use crate::testing::*;
+    use sunrise_libkern::nr;
+    use sunrise_libkern::process::ProcessState;
+
+    #[test_case]
+    fn find_next_thread_skips_locked_threads() {
+        let process = create_test_process("locked", &[0x0F, 0x0B], &[]);
+        let thread = ThreadStruct::new(&process, VirtualAddress(TEST_PROCESS_CODE_ADDR), VirtualAddress(TEST_PROCESS_DATA_ADDR), Some(0))
+            .expect("Failed to create thread")
+            .upgrade().unwrap();
+        let queue = [thread.clone()];
+        {
+            let _hwcontext = thread.hwcontext.lock();
+            assert_eq!(find_next_thread_to_run(&queue), None);
+        }
+        assert_eq!(find_next_thread_to_run(&queue), Some(0));
+    }
+
+    #[test_case]
+    fn schedule_runs_other_threads() {
+        let process = spawn_test_process("yield", &[
+            0xB8, 0x07, 0x00, 0x00, 0x00, // mov eax, ExitProcess
+            0xCD, 0x80,                   // int 0x80
+            0x0F, 0x0B,                   // ud2
+        ], &[nr::ExitProcess]);
+        for _ in 0..1000 {
+            if process.state() == ProcessState::Exited {
+                break;
+            }
+            schedule();
+        }
+        assert_eq!(process.state(), ProcessState::Exited, "Process never got to run");
+    }
+
+    #[test_case]
+    fn running_thread_is_in_schedule_queue() {
+        let thread = get_current_thread();
+        assert_eq!(thread.state.load(Ordering::SeqCst), ThreadState::Running);
+        let queue = SCHEDULE_QUEUE.lock();
+        assert!(is_in_schedule_queue(&queue, &thread), "Running thread is not considered scheduled");
+    }
+}
@@ -0,0 +1,364 @@
+//! Lock owner tracking
+//!
+//! Every lock of the [sync] module registers itself in a global table of held
+//! locks when it is acquired, and removes itself when its guard is dropped.
+//! An entry remembers who holds the lock (the thread id), where it was
+//! acquired (through `#[track_caller]`), and when (in [watchdog] ticks).
+//!
+//! This lets us:
+//!
+//! * panic instead of hanging when a thread tries to re-lock a [SpinLock] it
+//!   already holds,
+//! * detect cycles of threads waiting on [Mutex]es held by each other,
+//! * display the held locks when the kernel panics, or when the [watchdog]
+//!   finds the cpu stuck.
+//!
+//! The table is a fixed-size array of atomics, so it can be used from any
+//! context - including the NMI handler - without allocating or locking. When
+//! it is full, new locks are simply not tracked.
+//!
+//! [sync]: crate::sync
+//! [watchdog]: crate::watchdog
+//! [SpinLock]: crate::sync::SpinLock
+//! [Mutex]: crate::sync::Mutex
+
+use core::fmt::{self, Write};
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use crate::scheduler;
+
+/// Maximum number of locks tracked at the same time.
+const MAX_HELD_LOCKS: usize = 256;
+
+/// Maximum number of threads tracked as waiting on a mutex at the same time.
+const MAX_WAITERS: usize = 64;
+
+/// Owner recorded for locks acquired before the first thread was created.
+const NO_THREAD: usize = usize::max_value();
+
+/// The kind of a tracked lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum LockKind {
+    /// A [SpinLock](crate::sync::SpinLock).
+    SpinLock = 1,
+    /// A [SpinLockIRQ](crate::sync::SpinLockIRQ).
+    SpinLockIRQ = 2,
+    /// A [Mutex](crate::sync::Mutex).
+    Mutex = 3,
+}
+
+impl LockKind {
+    /// LockKind is stored in the table as an AtomicUsize. This function casts it back to the enum.
+    fn from_primitive(v: usize) -> Option<LockKind> {
+        match v {
+            1 => Some(LockKind::SpinLock),
+            2 => Some(LockKind::SpinLockIRQ),
+            3 => Some(LockKind::Mutex),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the held locks table.
+///
+/// Free when `lock` is 0.
+struct HeldLockSlot {
+    /// Address of the lock.
+    lock: AtomicUsize,
+    /// The [LockKind] of the lock, as a usize. 0 while the entry is being
+    /// filled in.
+    kind: AtomicUsize,
+    /// Thread id of the owner, or [NO_THREAD].
+    owner: AtomicUsize,
+    /// Where the lock was acquired.
+    site: AtomicPtr<Location<'static>>,
+    /// [watchdog] tick at which the lock was acquired.
+    ///
+    /// [watchdog]: crate::watchdog
+    since: AtomicUsize,
+}
+
+/// An entry of the waiters table.
+///
+/// Free when `thread` is 0.
+struct WaiterSlot {
+    /// Thread id of the waiting thread, plus one.
+    thread: AtomicUsize,
+    /// Address of the mutex it waits on.
+    lock: AtomicUsize,
+}
+
+/// Table of the locks currently held.
+static HELD_LOCKS: [HeldLockSlot; MAX_HELD_LOCKS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: HeldLockSlot = HeldLockSlot {
+        lock: AtomicUsize::new(0),
+        kind: AtomicUsize::new(0),
+        owner: AtomicUsize::new(0),
+        site: AtomicPtr::new(core::ptr::null_mut()),
+        since: AtomicUsize::new(0),
+    };
+    [FREE; MAX_HELD_LOCKS]
+};
+
+/// Table of the threads currently sleeping on a mutex.
+static WAITERS: [WaiterSlot; MAX_WAITERS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: WaiterSlot = WaiterSlot {
+        thread: AtomicUsize::new(0),
+        lock: AtomicUsize::new(0),
+    };
+    [FREE; MAX_WAITERS]
+};
+
+/// Returns the id of the thread currently running, or [NO_THREAD] during
+/// early boot.
+fn current_tid() -> usize {
+    scheduler::try_get_current_thread_id().unwrap_or(NO_THREAD)
+}
+
+/// Proof that a lock was registered in the held locks table.
+///
+/// Held by the lock guards. Dropping it removes the lock from the table.
+#[derive(Debug)]
+pub struct HeldLock {
+    /// Index in [HELD_LOCKS], or None if the table was full.
+    slot: Option<usize>,
+}
+
+impl HeldLock {
+    /// Registers a lock that was just acquired by the current thread.
+    pub fn acquired(lock: usize, kind: LockKind, site: &'static Location<'static>) -> HeldLock {
+        let slot = HELD_LOCKS.iter().position(|slot| {
+            slot.lock.compare_exchange(0, lock, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        });
+        if let Some(slot) = slot {
+            let slot = &HELD_LOCKS[slot];
+            slot.owner.store(current_tid(), Ordering::SeqCst);
+            slot.site.store(site as *const _ as *mut _, Ordering::SeqCst);
+            slot.since.store(crate::watchdog::ticks(), Ordering::SeqCst);
+            // Set last, so that readers skip the entry until it's complete.
+            slot.kind.store(kind as usize, Ordering::SeqCst);
+        }
+        HeldLock { slot }
+    }
+}
+
+impl Drop for HeldLock {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            HELD_LOCKS[slot].kind.store(0, Ordering::SeqCst);
+            HELD_LOCKS[slot].lock.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// A snapshot of an entry of the held locks table.
+#[derive(Debug, Clone, Copy)]
+pub struct HeldLockInfo {
+    /// Address of the lock.
+    pub lock: usize,
+    /// The kind of lock.
+    pub kind: LockKind,
+    /// Thread id of the owner, or None if it was taken during early boot.
+    pub owner: Option<usize>,
+    /// Where the lock was acquired.
+    pub site: Option<&'static Location<'static>>,
+    /// [watchdog] tick at which the lock was acquired.
+    ///
+    /// [watchdog]: crate::watchdog
+    pub since: usize,
+}
+
+impl fmt::Display for HeldLockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {:#010x} held by ", self.kind, self.lock)?;
+        match self.owner {
+            Some(tid) => write!(f, "thread {}", tid)?,
+            None => write!(f, "early boot")?,
+        }
+        match self.site {
+            Some(site) => write!(f, ", acquired at {}", site)?,
+            None => write!(f, ", acquired at <unknown>")?,
+        }
+        let held_ms = crate::watchdog::ticks_to_ms(crate::watchdog::ticks().wrapping_sub(self.since));
+        write!(f, " {}ms ago", held_ms)
+    }
+}
+
+/// Iterates over the locks currently held.
+pub fn held_locks() -> impl Iterator<Item = HeldLockInfo> {
+    HELD_LOCKS.iter().filter_map(|slot| {
+        let lock = slot.lock.load(Ordering::SeqCst);
+        if lock == 0 {
+            return None;
+        }
+        let owner = slot.owner.load(Ordering::SeqCst);
+        let site = slot.site.load(Ordering::SeqCst);
+        Some(HeldLockInfo {
+            lock,
+            kind: LockKind::from_primitive(slot.kind.load(Ordering::SeqCst))?,
+            owner: if owner == NO_THREAD { None } else { Some(owner) },
+            site: unsafe {
+                // safe: only ever set from a &'static Location.
+                site.as_ref()
+            },
+            since: slot.since.load(Ordering::SeqCst),
+        })
+    })
+}
+
+/// Finds who holds the given lock.
+pub fn owner_of(lock: usize) -> Option<HeldLockInfo> {
+    held_locks().find(|info| info.lock == lock)
+}
+
+/// Panics if the current thread already holds this spinlock.
+///
+/// Called before spinning on a spinlock that is not immediately available,
+/// as spinning on a lock we hold ourselves would hang forever.
+pub fn check_spin_relock(lock: usize, kind: LockKind, site: &'static Location<'static>) {
+    let me = current_tid();
+    if me == NO_THREAD {
+        return;
+    }
+    if let Some(held) = held_locks().find(|info| info.lock == lock && info.owner == Some(me)) {
+        panic!("Deadlock ! Re-taking a {:?} at {} when we already are its owner: {}", kind, site, held);
+    }
+}
+
+/// Registers the current thread as waiting on a mutex, until the returned
+/// value is dropped.
+///
+/// # Panics
+///
+/// Panics if the owner of this mutex is itself waiting, directly or
+/// indirectly, on a mutex held by the current thread: nobody would ever
+/// wake up.
+pub fn wait_on_mutex(lock: usize, site: &'static Location<'static>) -> MutexWaiter {
+    let me = current_tid();
+
+    // Follow the chain of owners, looking for ourselves.
+    let mut waited = lock;
+    for _ in 0..MAX_WAITERS {
+        let owner = match owner_of(waited).and_then(|info| info.owner) {
+            Some(owner) => owner,
+            None => break
+        };
+        if owner == me {
+            panic!("Deadlock ! Thread {} waits at {} on mutex {:#010x}, in a cycle of threads waiting on each other", me, site, lock);
+        }
+        waited = match waiting_on(owner) {
+            Some(waited) => waited,
+            None => break
+        };
+    }
+
+    let slot = WAITERS.iter().position(|slot| {
+        slot.thread.compare_exchange(0, me.wrapping_add(1), Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    });
+    if let Some(slot) = slot {
+        WAITERS[slot].lock.store(lock, Ordering::SeqCst);
+    }
+    MutexWaiter { slot }
+}
+
+/// Returns the mutex the given thread is sleeping on, if any.
+pub fn waiting_on(tid: usize) -> Option<usize> {
+    WAITERS.iter()
+        .find(|slot| slot.thread.load(Ordering::SeqCst) == tid.wrapping_add(1))
+        .map(|slot| slot.lock.load(Ordering::SeqCst))
+        .filter(|lock| *lock != 0)
+}
+
+/// Registration of a thread sleeping on a mutex. See [wait_on_mutex].
+#[derive(Debug)]
+pub struct MutexWaiter {
+    /// Index in [WAITERS], or None if the table was full.
+    slot: Option<usize>,
+}
+
+impl Drop for MutexWaiter {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            WAITERS[slot].lock.store(0, Ordering::SeqCst);
+            WAITERS[slot].thread.store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Writes the list of held locks, and of threads waiting on a mutex.
+///
+/// Does not lock anything, so it is safe to use from the panic handler.
+pub fn dump_held_locks<W: Write>(w: &mut W) {
+    let _ = writeln!(w, "Held locks:");
+    let mut any = false;
+    for info in held_locks() {
+        any = true;
+        let _ = writeln!(w, "  {}", info);
+    }
+    if !any {
+        let _ = writeln!(w, "  <none>");
+    }
+    for slot in WAITERS.iter() {
+        let thread = slot.thread.load(Ordering::SeqCst);
+        let lock = slot.lock.load(Ordering::SeqCst);
+        if thread != 0 && lock != 0 {
+            let _ = writeln!(w, "  thread {} sleeps on Mutex {:#010x}", thread - 1, lock);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::sync::{SpinLock, SpinLockIRQ, Mutex};
+
+    /// Finds the held lock that was acquired at the given line of this file.
+    fn held_at(line: u32) -> Option<HeldLockInfo> {
+        held_locks().find(|info| info.site
+            .map(|site| site.file() == file!() && site.line() == line)
+            .unwrap_or(false))
+    }
+
+    #[test_case]
+    fn locks_are_tracked_while_held() {
+        let me = scheduler::try_get_current_thread_id();
+
+        let spin_lock = SpinLock::new(());
+        let (guard, line) = (spin_lock.lock(), line!());
+        let held = held_at(line).expect("SpinLock is not tracked");
+        assert_eq!(held.kind, LockKind::SpinLock);
+        assert_eq!(held.owner, me);
+        assert_eq!(held.lock, &spin_lock as *const _ as usize);
+        drop(guard);
+        assert!(held_at(line).is_none(), "SpinLock is still tracked after being released");
+
+        let spin_lock_irq = SpinLockIRQ::new(());
+        let (guard, line) = (spin_lock_irq.lock(), line!());
+        let held = held_at(line).expect("SpinLockIRQ is not tracked");
+        assert_eq!(held.kind, LockKind::SpinLockIRQ);
+        assert_eq!(held.owner, me);
+        drop(guard);
+        assert!(held_at(line).is_none(), "SpinLockIRQ is still tracked after being released");
+
+        let mutex = Mutex::new(());
+        let (guard, line) = (mutex.lock(), line!());
+        let held = held_at(line).expect("Mutex is not tracked");
+        assert_eq!(held.kind, LockKind::Mutex);
+        assert_eq!(held.owner, me);
+        drop(guard);
+        assert!(held_at(line).is_none(), "Mutex is still tracked after being released");
+    }
+
+    #[test_case]
+    fn failed_try_lock_is_not_tracked() {
+        let spin_lock = SpinLock::new(());
+        let guard = spin_lock.lock();
+        let (other, line) = (spin_lock.try_lock(), line!());
+        assert!(other.is_none());
+        assert!(held_at(line).is_none());
+        drop(guard);
+    }
+}
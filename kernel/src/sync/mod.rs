@@ -77,6 +77,13 @@
 //! You *can* preempt while holding such a lock, as long as the scheduler's code doesn't also use it
 //! for itself, but this would seem like a bad idea.
 //!
+//! # Lock tracking
+//!
+//! [SpinLock], [SpinLockIRQ] and [Mutex] register themselves in a table of held locks while they
+//! are held, remembering their owner and where they were acquired. Breaking the rules above is
+//! turned into a panic naming the culprit whenever we can detect it, instead of a frozen system.
+//! See the [lock_tracking] module.
+//!
 //! [SpinLock]: crate::sync::SpinLock
 //! [SpinRwLock]: crate::sync::SpinRwLock
 //! [Once]: crate::sync::Once
@@ -96,6 +103,8 @@ pub use self::spin_lock::{SpinLock, SpinLockGuard};
 pub mod mutex;
 pub use self::mutex::{Mutex, MutexGuard};
 
+pub mod lock_tracking;
+
 /// Boolean to [spin_lock_irq::permanently_disable_interrupts].
 ///
 /// If this bool is set, all attempts to enable interrupts through a SpinLockIRQ
@@ -38,6 +38,7 @@
 //! [`SpinLock`]: crate::sync::SpinLock
 
 use super::SpinLock;
+use super::lock_tracking::{self, HeldLock, LockKind};
 use crate::process::ThreadStruct;
 use crate::scheduler::{get_current_thread, add_to_schedule_queue, unschedule};
 use alloc::sync::Arc;
@@ -46,6 +47,7 @@ use core::cell::UnsafeCell;
 use core::fmt;
 use core::ops::{Deref, DerefMut};
 use core::marker::PhantomData;
+use core::panic::Location;
 
 /// A type alias for the result of a nonblocking locking method.
 pub type TryLockResult<Guard> = Result<Guard, ()>;
@@ -115,6 +117,8 @@ struct MutexInnerInner {
 pub struct MutexGuard<'a, T: 'a> {
     /// Reference to the Mutex we'll unlock when dropped.
     __lock: &'a Mutex<T>,
+    /// Registration of the Mutex in the held locks table.
+    __held: HeldLock,
     /// Raw pointer just to make MutexGuard !Send.
     __phantom: PhantomData<*mut ()>
 }
@@ -174,10 +178,12 @@ impl<T> Mutex<T> {
     ///
     /// This function panics when called if the lock is already held by
     /// the current thread.
+    #[track_caller]
     pub fn lock(&self) -> MutexGuard<'_, T> {
+        let site = Location::caller();
         unsafe {
-            self.inner.raw_lock();
-            MutexGuard::new(self)
+            self.inner.raw_lock(site);
+            MutexGuard::new(self, site)
         }
     }
 
@@ -189,8 +195,8 @@ impl<T> Mutex<T> {
     ///
     /// This function does not preempt.
     ///
-    /// Note however that it still needs to lock the internal [`SpinLock`], and might temporarily
-    /// be blocking.
+    /// This includes the internal [`SpinLock`]: if it is currently held, the mutex is considered
+    /// unavailable, and [`Err`] is returned.
     ///
     /// # Double locking
     ///
@@ -201,10 +207,12 @@ impl<T> Mutex<T> {
     /// locks to resources possibly already held by the current thread, without panicking once more.
     ///
     /// [`lock`]: Mutex::lock
+    #[track_caller]
     pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let site = Location::caller();
         unsafe {
             if self.inner.try_lock() {
-                Ok(MutexGuard::new(self))
+                Ok(MutexGuard::new(self, site))
             } else {
                 Err(())
             }
@@ -273,7 +281,7 @@ impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
 
 /* *************************************** MUTEX GUARD ****************************************** */
 
-// copied from std, no edits //
+// copied from std, added lock tracking //
 impl<'mutex, T> MutexGuard<'mutex, T> {
     /// Create an MutexGuard.
     ///
@@ -281,9 +289,10 @@ impl<'mutex, T> MutexGuard<'mutex, T> {
     ///
     /// Must only be called once we are ensured we are holing the lock,
     /// as it will unlock it when dropped
-    unsafe fn new(lock: &'mutex Mutex<T>) -> MutexGuard<'mutex, T> {
+    unsafe fn new(lock: &'mutex Mutex<T>, site: &'static Location<'static>) -> MutexGuard<'mutex, T> {
         MutexGuard {
             __lock: lock,
+            __held: HeldLock::acquired(lock.inner.addr(), LockKind::Mutex, site),
             __phantom: PhantomData,
         }
     }
@@ -333,7 +342,11 @@ impl MutexInner {
     ///
     /// Returns false if the mutex was not immediately available.
     unsafe fn try_lock(&self) -> bool {
-        let mut inner_guard = self.spin_lock.lock();
+        let mut inner_guard = match self.spin_lock.try_lock() {
+            Some(inner_guard) => inner_guard,
+            // someone is in the middle of locking/unlocking it.
+            None => return false
+        };
         if let Some(_owner) = inner_guard.owner {
             // already taken :/
             false
@@ -351,14 +364,20 @@ impl MutexInner {
     ///
     /// # Panics
     ///
-    /// Panics if we're already the owner of the mutex, as this is a deadlock otherwise.
-    unsafe fn raw_lock(&self) {
+    /// Panics if we're already the owner of the mutex, or if its owner is waiting on a mutex we
+    /// hold, as this is a deadlock otherwise.
+    unsafe fn raw_lock(&self, site: &'static Location<'static>) {
         let me = get_current_thread();
         let mut inner_guard = self.spin_lock.lock();
         if let Some(owner) = inner_guard.owner {
             if owner == &*me as *const ThreadStruct as usize {
-                panic!("Deadlock ! Re-taking the mutex when we already are its owner");
+                match lock_tracking::owner_of(self.addr()) {
+                    Some(held) => panic!("Deadlock ! Re-taking the mutex at {} when we already are its owner: {}", site, held),
+                    None => panic!("Deadlock ! Re-taking the mutex at {} when we already are its owner", site),
+                }
             }
+            // make sure its owner isn't waiting on us,
+            let _waiter = lock_tracking::wait_on_mutex(self.addr(), site);
             // add ourselves to the queue of waiters,
             inner_guard.waiters.push(me);
             // and unschedule.
@@ -378,6 +397,11 @@ impl MutexInner {
         }
     }
 
+    /// The address of this mutex, identifying it in the held locks table.
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
     /// Unlocks the mutex.
     ///
     /// Consider switching from the pair of raw_lock() and raw_unlock() to
@@ -411,7 +435,7 @@ impl MutexInner {
 
 /* Only tests what's trivially testable :/ */
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod tests {
     /*
     use crate::sync::mpsc::channel;
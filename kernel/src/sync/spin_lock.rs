@@ -5,8 +5,9 @@
 //! [sync]: crate::sync
 
 use core::fmt;
-
-pub use spin::MutexGuard as SpinLockGuard;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use super::lock_tracking::{self, HeldLock, LockKind};
 
 /// This type provides mutual exclusion based on spinning.
 /// It will panic if used in the context of an interrupt.
@@ -119,6 +120,7 @@ impl<T: ?Sized> SpinLock<T> {
     /// }
     ///
     /// ```
+    #[track_caller]
     pub fn lock(&self) -> SpinLockGuard<T> {
         use core::sync::atomic::Ordering;
         use crate::cpu_locals::ARE_CPU_LOCALS_INITIALIZED_YET;
@@ -130,7 +132,19 @@ impl<T: ?Sized> SpinLock<T> {
                 This is most likely a design flaw. \
                 See documentation of the sync module.");
         }
-        self.0.lock()
+        let site = Location::caller();
+        let guard = match self.0.try_lock() {
+            Some(guard) => guard,
+            None => {
+                // Spinning on a lock we already hold would hang forever.
+                lock_tracking::check_spin_relock(self.addr(), LockKind::SpinLock, site);
+                self.0.lock()
+            }
+        };
+        SpinLockGuard {
+            held: HeldLock::acquired(self.addr(), LockKind::SpinLock, site),
+            guard,
+        }
     }
 
     /// Force unlock the spinlock. If the lock isn't held, this is a no-op.
@@ -146,15 +160,56 @@ impl<T: ?Sized> SpinLock<T> {
 
     /// Tries to lock the spinlock. If it is already locked, it will return None. Otherwise it returns
     /// a guard within Some.
+    #[track_caller]
     pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
         use core::sync::atomic::Ordering;
-        if crate::i386::interrupt_service_routines::INSIDE_INTERRUPT_COUNT.load(Ordering::SeqCst) != 0 {
+        use crate::i386::interrupt_service_routines::INSIDE_INTERRUPT_COUNT;
+        use super::INTERRUPT_DISARM;
+        if !INTERRUPT_DISARM.load(Ordering::SeqCst) && INSIDE_INTERRUPT_COUNT.load(Ordering::SeqCst) != 0 {
             panic!("\
                 You have attempted to lock a spinlock in interrupt context. \
                 This is most likely a design flaw. \
                 See documentation of the sync module.");
         }
-        self.0.try_lock()
+        let site = Location::caller();
+        self.0.try_lock().map(|guard| SpinLockGuard {
+            held: HeldLock::acquired(self.addr(), LockKind::SpinLock, site),
+            guard,
+        })
+    }
+
+    /// The address of this lock, identifying it in the held locks table.
+    fn addr(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+}
+
+/// A guard to which the protected data can be accessed.
+///
+/// When the guard falls out of scope it will release the lock, and remove
+/// it from the held locks table.
+#[derive(Debug)]
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    /// Registration of the lock in the held locks table.
+    ///
+    /// Declared first, so that it is removed from the table before the lock
+    /// is released.
+    held: HeldLock,
+    /// The guard of the underlying spinlock.
+    guard: spin::MutexGuard<'a, T>,
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
     }
 }
 
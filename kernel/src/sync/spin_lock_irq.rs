@@ -29,6 +29,12 @@ pub unsafe fn permanently_disable_interrupts() {
     unsafe { interrupts::cli() }
 }
 
+/// Returns whether interrupts were permanently disabled by
+/// [permanently_disable_interrupts], meaning we're panicking.
+pub fn are_interrupts_disarmed() -> bool {
+    INTERRUPT_DISARM.load(Ordering::SeqCst)
+}
+
 /// SpinLock that disables IRQ.
 ///
 /// # Description
@@ -81,10 +87,12 @@ impl<T> SpinLockIRQ<T> {
 
 impl<T: ?Sized> SpinLockIRQ<T> {
     /// Disables interrupts and locks the mutex.
+    #[track_caller]
     pub fn lock(&self) -> SpinLockIRQGuard<T> {
+        let site = Location::caller();
         if INTERRUPT_DISARM.load(Ordering::SeqCst) {
-            let internalguard = self.internal.lock();
-            SpinLockIRQGuard(ManuallyDrop::new(internalguard), false)
+            let internalguard = self.spin(site);
+            SpinLockIRQGuard(ManuallyDrop::new(internalguard), false, self.held(site))
         } else {
             // Save current interrupt state.
             let saved_intpt_flag = interrupts::are_enabled();
@@ -92,16 +100,18 @@ impl<T: ?Sized> SpinLockIRQ<T> {
             // Disable interruptions
             unsafe { interrupts::cli(); }
 
-            let internalguard = self.internal.lock();
-            SpinLockIRQGuard(ManuallyDrop::new(internalguard), saved_intpt_flag)
+            let internalguard = self.spin(site);
+            SpinLockIRQGuard(ManuallyDrop::new(internalguard), saved_intpt_flag, self.held(site))
         }
     }
 
     /// Disables interrupts and locks the mutex.
+    #[track_caller]
     pub fn try_lock(&self) -> Option<SpinLockIRQGuard<T>> {
+        let site = Location::caller();
         if INTERRUPT_DISARM.load(Ordering::SeqCst) {
             self.internal.try_lock()
-                .map(|v| SpinLockIRQGuard(ManuallyDrop::new(v), false))
+                .map(|v| SpinLockIRQGuard(ManuallyDrop::new(v), false, self.held(site)))
         } else {
             // Save current interrupt state.
             let saved_intpt_flag = interrupts::are_enabled();
@@ -114,7 +124,7 @@ impl<T: ?Sized> SpinLockIRQ<T> {
 
             if let Some(internalguard) = internalguard {
                 // if lock is successful, return guard.
-                Some(SpinLockIRQGuard(ManuallyDrop::new(internalguard), saved_intpt_flag))
+                Some(SpinLockIRQGuard(ManuallyDrop::new(internalguard), saved_intpt_flag, self.held(site)))
             } else {
                 // Else, restore interrupt state
                 if saved_intpt_flag {
@@ -125,6 +135,29 @@ impl<T: ?Sized> SpinLockIRQ<T> {
         }
     }
 
+    /// Spins until the internal lock is acquired.
+    ///
+    /// Panics instead if we're the ones holding it, as we would spin forever.
+    fn spin(&self, site: &'static Location<'static>) -> SpinLockGuard<T> {
+        match self.internal.try_lock() {
+            Some(guard) => guard,
+            None => {
+                lock_tracking::check_spin_relock(self.addr(), LockKind::SpinLockIRQ, site);
+                self.internal.lock()
+            }
+        }
+    }
+
+    /// Registers this lock in the held locks table.
+    fn held(&self, site: &'static Location<'static>) -> ManuallyDrop<HeldLock> {
+        ManuallyDrop::new(HeldLock::acquired(self.addr(), LockKind::SpinLockIRQ, site))
+    }
+
+    /// The address of this lock, identifying it in the held locks table.
+    fn addr(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+
     /// Force unlocks the lock.
     ///
     /// # Safety
@@ -150,13 +183,17 @@ impl<T: fmt::Debug> fmt::Debug for SpinLockIRQ<T> {
 }
 
 /// The SpinLockIrq lock guard.
+///
+/// Holds the internal guard, the interrupt state to restore, and the
+/// registration of the lock in the held locks table.
 #[derive(Debug)]
-pub struct SpinLockIRQGuard<'a, T: ?Sized>(ManuallyDrop<SpinLockGuard<'a, T>>, bool);
+pub struct SpinLockIRQGuard<'a, T: ?Sized>(ManuallyDrop<SpinLockGuard<'a, T>>, bool, ManuallyDrop<HeldLock>);
 
 impl<'a, T: ?Sized + 'a> Drop for SpinLockIRQGuard<'a, T> {
     fn drop(&mut self) {
         // TODO: Spin release
-        // unlock
+        // forget about it, then unlock
+        unsafe { ManuallyDrop::drop(&mut self.2); }
         unsafe { ManuallyDrop::drop(&mut self.0); }
 
         // Restore irq
@@ -5,12 +5,16 @@
 //! The syscall handlers of Sunrise.
 
 use crate::i386;
+use crate::devices;
 use crate::mem::{VirtualAddress, PhysicalAddress};
 use crate::mem::{UserSpacePtr, UserSpacePtrMut};
 use crate::paging::{MappingAccessRights, PAGE_SIZE};
 use crate::paging::lands::{UserLand, VirtualSpaceLand};
 use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTrait};
 use crate::paging::mapping::MappingFrames;
+use crate::paging::process_memory::ProcessMemory;
+use crate::paging::device_address_space::DeviceAddressSpace;
+use crate::paging::shared_memory::SharedMemory;
 use crate::process::{Handle, ThreadStruct, ProcessStruct};
 use crate::event::{self, Waitable};
 use crate::scheduler::{self, get_current_thread, get_current_process};
@@ -19,11 +23,14 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use crate::ipc;
 use crate::error::{UserspaceError, KernelError};
-use crate::sync::SpinRwLock;
 use crate::timer;
 use failure::Backtrace;
 use sunrise_libkern::{MemoryInfo, MemoryAttributes, MemoryPermissions, MemoryType, MemoryState};
 use sunrise_libkern::process::*;
+use sunrise_libkern::profiler::{ProfilerCommand, ProfilerSample};
+use sunrise_libkern::power::SleepSystemMode;
+use sunrise_libkern::{nr, SYSCALL_NAMES, TLS_EXIT_CODE_OFFSET};
+use crate::process::coredump;
 use bit_field::BitArray;
 use crate::i386::gdt::{GDT, GdtIndex};
 use core::convert::{TryFrom, TryInto};
@@ -126,12 +133,21 @@ pub fn create_interrupt_event(irq_num: usize, _flag: u32) -> Result<usize, Users
 pub fn query_physical_address(virtual_address: usize) -> Result<(usize, usize, usize), UserspaceError> {
     let virtual_address = VirtualAddress(virtual_address);
     let proc = scheduler::get_current_process();
-    let mem = proc.pmemory.lock();
+    let mut mem = proc.pmemory.lock();
+    // The page must be backed for its physical address to make sense.
+    mem.commit(virtual_address, 1)?;
     let mapping = mem.query_memory(virtual_address);
     let keep_region;
     let frames = match mapping.mapping().frames() {
         MappingFrames::Owned(regions) => regions,
         MappingFrames::Shared(arc_regions) => { keep_region = arc_regions.read(); keep_region.as_ref() },
+        MappingFrames::Lazy(arc_regions) => {
+            // Lazy frames are never contiguous, report the page alone.
+            let index = (mapping.mapping().phys_offset() + (virtual_address.floor() - mapping.mapping().address())) / PAGE_SIZE;
+            let frame = arc_regions.read()[index].as_ref()
+                .expect("Page was committed, but has no frame").address();
+            return Ok((frame.addr(), virtual_address.floor().addr(), PAGE_SIZE))
+        },
         MappingFrames::None =>
             return Err(KernelError::InvalidAddress { address: virtual_address.addr(), backtrace: Backtrace::new() }.into()),
     };
@@ -187,9 +203,10 @@ pub fn wait_synchronization(handles_ptr: UserSpacePtr<[u32]>, timeout_ns: usize)
     let proc = scheduler::get_current_process();
     {
         // Make sure we drop proclock before waiting.
+        let handles = handles_ptr.read_to_vec()?;
         let handleslock = proc.phandles.lock();
-        for handle in handles_ptr.iter() {
-            let hnd = handleslock.get_handle(*handle)?;
+        for handle in handles {
+            let hnd = handleslock.get_handle(handle)?;
             let _ = hnd.as_waitable()?;
             handle_arr.push(hnd);
         }
@@ -246,13 +263,231 @@ pub fn output_debug_string(msg: UserSpacePtr<[u8]>, level: usize, target: UserSp
         _         => log::Level::Trace,
     };
 
-    log!(target: &*String::from_utf8_lossy(&*target), level, "{}", String::from_utf8_lossy(&*msg));
+    let target = target.read_to_vec()?;
+    let target = String::from_utf8_lossy(&target);
+    let msg = msg.read_to_vec()?;
+    crate::log_impl::log_userspace(&get_current_process(), &log::Record::builder()
+        .args(format_args!("{}", String::from_utf8_lossy(&msg)))
+        .level(level)
+        .target(&*target)
+        .build());
     Ok(())
 }
 
+/// Replaces the kernel log filter with the given env_logger-style directive
+/// string (e.g. `sunrise_kernel::ipc=trace,info`).
+///
+/// If `has_pid` is false, the global filter - used by the kernel and every
+/// process without a filter of its own - is replaced.
+///
+/// If `has_pid` is true, the filter is only applied to the messages sent by the
+/// process `pid` through [output_debug_string]. Passing an empty directive
+/// string removes the process' filter, making it use the global filter again.
+///
+/// # Errors
+///
+/// - `InvalidCombination`
+///   - The directive string is not valid UTF-8.
+/// - `NoSuchEntry`
+///   - No living process has the given pid.
+pub fn set_log_filter(directives: UserSpacePtr<[u8]>, pid: usize, has_pid: bool) -> Result<(), UserspaceError> {
+    let directives = directives.read_to_vec()?;
+    let directives = core::str::from_utf8(&directives)
+        .or(Err(UserspaceError::InvalidCombination))?;
+
+    if !has_pid {
+        crate::log_impl::set_filter(directives);
+        return Ok(())
+    }
+
+    let process = ProcessStruct::find_by_pid(pid)
+        .ok_or(UserspaceError::NoSuchEntry)?;
+
+    let filter = if directives.is_empty() {
+        None
+    } else {
+        Some(crate::log_impl::parse_filter(directives))
+    };
+    *process.log_filter.write() = filter;
+    Ok(())
+}
+
+/// Controls the sampling profiler. See [crate::profiler].
+///
+/// - `Start` discards the previously recorded samples and starts recording.
+/// - `Stop` stops recording, keeping the samples recorded so far.
+/// - `Drain` moves up to `out_samples.len()` samples to `out_samples`, oldest
+///   first.
+///
+/// # Returns
+///
+/// For `Drain`, the number of samples written to `out_samples`, and the number
+/// of samples that were dropped since the last drain because the buffer was
+/// full. Other commands return (0, 0).
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - The command is unknown.
+pub fn control_profiler(command: u32, out_samples: UserSpacePtrMut<[ProfilerSample]>) -> Result<(usize, usize), UserspaceError> {
+    match ProfilerCommand(command) {
+        ProfilerCommand::Start => {
+            crate::profiler::start();
+            Ok((0, 0))
+        },
+        ProfilerCommand::Stop => {
+            crate::profiler::stop();
+            Ok((0, 0))
+        },
+        ProfilerCommand::Drain => {
+            // Don't lose the samples to a bogus buffer.
+            out_samples.check()?;
+            let (samples, dropped) = crate::profiler::drain(out_samples.len());
+            out_samples.write_from_slice(&samples)?;
+            Ok((samples.len(), dropped))
+        },
+        _ => Err(UserspaceError::InvalidEnum)
+    }
+}
+
+/// Powers the computer off or reboots it. See [crate::i386::acpi::power].
+///
+/// Userspace is expected to have flushed whatever it cares about beforehand.
+///
+/// Rebooting never returns. Shutting down only returns on failure.
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - The mode is unknown.
+/// - `NotImplemented`
+///   - Shutting down is not supported by this system, or the firmware ignored
+///     the request.
+pub fn sleep_system(mode: u32) -> Result<(), UserspaceError> {
+    match SleepSystemMode(mode) {
+        SleepSystemMode::Shutdown => Ok(crate::i386::acpi::power::shutdown()?),
+        SleepSystemMode::Reboot => crate::i386::acpi::power::reboot(),
+        _ => Err(UserspaceError::InvalidEnum)
+    }
+}
+
+/// Copies the name of the process `pid` to `out_name`, truncating it if it
+/// doesn't fit. The name is not NUL-terminated.
+///
+/// Returns the length of the copied name.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has the given pid.
+pub fn get_process_name(pid: usize, out_name: UserSpacePtrMut<[u8]>) -> Result<usize, UserspaceError> {
+    let process = ProcessStruct::find_by_pid(pid)
+        .ok_or(UserspaceError::NoSuchEntry)?;
+
+    // KIP names are padded with NULs.
+    let name = process.name.trim_end_matches('\0').as_bytes();
+    let copied_len = core::cmp::min(name.len(), out_name.len());
+    out_name.write_from_slice(&name[..copied_len])?;
+    Ok(copied_len)
+}
+
+/// Reads the core dump of a crashed process, starting at `offset` in the core
+/// file. See [crate::process::coredump].
+///
+/// # Returns
+///
+/// The number of bytes written to `out`, which is only smaller than the size
+/// of `out` when reaching the end of the file, and the total size of the core
+/// file.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - `process_hnd` is not a valid process handle.
+/// - `InvalidState`
+///   - The process did not crash.
+pub fn read_core_dump(process_hnd: u32, offset: usize, out: UserSpacePtrMut<[u8]>) -> Result<(usize, usize), UserspaceError> {
+    out.check()?;
+    let process = get_current_process().phandles.lock().get_handle(process_hnd)?.as_process()?;
+    let core_dump = process.core_dump.lock();
+    let core_dump = core_dump.as_ref().ok_or(UserspaceError::InvalidState)?;
+    let mut buf = vec![0; core::cmp::min(out.len(), core_dump.size().saturating_sub(offset))];
+    let read = core_dump.read(&process.pmemory.lock(), offset, &mut buf);
+    out.write_from_slice(&buf[..read])?;
+    Ok((read, core_dump.size()))
+}
+
+/// Reads the report of the kernel panic that ended the previous boot,
+/// starting at `offset`. See [crate::panic::report].
+///
+/// # Returns
+///
+/// The number of bytes written to `out`, which is only smaller than the size
+/// of `out` when reaching the end of the report, and the total size of the
+/// report.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///   - The previous boot did not end with a kernel panic.
+pub fn read_kernel_panic_report(offset: usize, out: UserSpacePtrMut<[u8]>) -> Result<(usize, usize), UserspaceError> {
+    out.check()?;
+    let mut buf = vec![0; core::cmp::min(out.len(), crate::panic::report::REPORT_REGION_SIZE)];
+    let (read, size) = crate::panic::report::read_previous_report(offset, &mut buf)
+        .ok_or(UserspaceError::InvalidState)?;
+    out.write_from_slice(&buf[..read])?;
+    Ok((read, size))
+}
+
+/// Takes a snapshot of the kernel objects, and reads it starting at `offset`.
+/// See [crate::dump_info].
+///
+/// The snapshot is also written to the kernel log when `offset` is 0. Every
+/// call takes a new snapshot, so reading it in several parts may give an
+/// inconsistent result if the state changed in-between.
+///
+/// # Returns
+///
+/// The number of bytes written to `out`, which is only smaller than the size
+/// of `out` when reaching the end of the snapshot, and the total size of the
+/// snapshot.
+pub fn dump_info(offset: usize, out: UserSpacePtrMut<[u8]>) -> Result<(usize, usize), UserspaceError> {
+    out.check()?;
+    let dump = crate::dump_info::dump();
+    if offset == 0 {
+        for line in dump.lines() {
+            info!("{}", line);
+        }
+    }
+    let dump = dump.as_bytes();
+    let start = core::cmp::min(offset, dump.len());
+    let read = core::cmp::min(out.len(), dump.len() - start);
+    out.write_from_slice(&dump[start..start + read])?;
+    Ok((read, dump.len()))
+}
+
 /// Kills our own process.
+///
+/// The exit code is the `exit_code` field of the [TLS] of the calling thread,
+/// which is 0 unless the process set it. This lets processes report a failure
+/// without being allowed to use [exit_process_with_code].
+///
+/// [TLS]: sunrise_libkern::TLS
 pub fn exit_process() -> Result<(), UserspaceError> {
-    ProcessStruct::kill_current_process();
+    let tls = get_current_thread().tls_region;
+    // The TLS is always mapped, but exit anyway if it was tampered with.
+    let exit_code = UserSpacePtr((tls.addr() + TLS_EXIT_CODE_OFFSET) as *const u32).read()
+        .unwrap_or(0);
+    ProcessStruct::kill_current_process(exit_code);
+    Ok(())
+}
+
+/// Sunrise extension: Kills our own process, with the given exit code.
+///
+/// The exit code can be retrieved by [get_process_info] once the process is
+/// Exited.
+pub fn exit_process_with_code(exit_code: u32) -> Result<(), UserspaceError> {
+    ProcessStruct::kill_current_process(exit_code);
     Ok(())
 }
 
@@ -329,7 +564,7 @@ pub fn start_thread(thread_handle: u32) -> Result<(), UserspaceError> {
 /// - NoSuchEntry: No named port were registered with this name.
 /// - PortRemoteDead: All associated ServerPort handles are closed.
 pub fn connect_to_named_port(name: UserSpacePtr<[u8; 12]>) -> Result<usize, UserspaceError> {
-    let session = ipc::connect_to_named_port(*name)?;
+    let session = ipc::connect_to_named_port(name.read()?)?;
     let curproc = scheduler::get_current_process();
     let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ClientSession(session)));
     Ok(hnd as _)
@@ -347,7 +582,7 @@ pub fn connect_to_named_port(name: UserSpacePtr<[u8; 12]>) -> Result<usize, User
 ///
 /// - ExceedingMaximum: Name is bigger than 12 character, or is missing a \0.
 pub fn manage_named_port(name_ptr: UserSpacePtr<[u8; 12]>, max_sessions: u32) -> Result<usize, UserspaceError> {
-    let server = ipc::create_named_port(*name_ptr, max_sessions)?;
+    let server = ipc::create_named_port(name_ptr.read()?, max_sessions)?;
     let curproc = scheduler::get_current_process();
     let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ServerPort(server)));
     Ok(hnd as _)
@@ -384,6 +619,9 @@ pub fn accept_session(porthandle: u32) -> Result<usize, UserspaceError> {
 ///
 /// - PortRemoteDead: All ServerSession associated with this handle are closed.
 pub fn send_sync_request_with_user_buffer(buf: UserSpacePtrMut<[u8]>, handle: u32) -> Result<(), UserspaceError> {
+    // The server only reads the buffer once it receives the request. Fail now
+    // rather than leaving it to deal with our bogus buffer.
+    buf.check()?;
     let proc = scheduler::get_current_process();
     let sess = proc.phandles.lock().get_handle(handle)?.as_client_session()?;
     sess.send_request(buf)
@@ -406,16 +644,18 @@ pub fn send_sync_request_with_user_buffer(buf: UserSpacePtrMut<[u8]>, handle: u3
 /// returned.
 pub fn reply_and_receive_with_user_buffer(buf: UserSpacePtrMut<[u8]>, handles: UserSpacePtr<[u32]>, reply_target: u32, timeout: usize) -> Result<usize, UserspaceError> {
     let proc = scheduler::get_current_process();
+    buf.check()?;
     if reply_target != 0 {
         // get session
         let sess = proc.phandles.lock().get_handle(reply_target)?;
-        sess.as_server_session()?.reply(UserSpacePtr(buf.0))?;
+        sess.as_server_session()?.reply(buf.into())?;
     }
 
     // TODO: Ensure all handles are ClientSessions
     let idx = wait_synchronization(handles, timeout)?;
 
-    let servsess = proc.phandles.lock().get_handle(handles[idx])?.as_server_session()?;
+    let handle = handles.read_to_vec()?[idx];
+    let servsess = proc.phandles.lock().get_handle(handle)?.as_server_session()?;
     servsess.receive(buf, reply_target == 0)?;
     Ok(idx)
 }
@@ -492,90 +732,326 @@ pub fn create_port(max_sessions: u32, _is_light: bool, _name_ptr: UserSpacePtr<[
     Ok((clienthnd as _, serverhnd as _))
 }
 
+/// Checks that `perm` is a valid permission to map a SharedMemory with: R-- or RW-.
+fn check_shared_memory_perm(perm: u32) -> Result<MemoryPermissions, UserspaceError> {
+    match MemoryPermissions::from_bits(perm) {
+        Some(perm) if perm == MemoryPermissions::READABLE || perm == MemoryPermissions::RW => Ok(perm),
+        _ => Err(UserspaceError::InvalidMemPerms)
+    }
+}
+
+/// Checks that the size of a SharedMemory is page aligned and not 0.
+fn check_shared_memory_size(size: usize) -> Result<(), UserspaceError> {
+    if size == 0 || size % PAGE_SIZE != 0 {
+        return Err(UserspaceError::InvalidSize)
+    }
+    Ok(())
+}
+
 /// Allocate a new SharedMemory region. This is a memory region backed by
 /// DRAM allocated from the current process' pool partition, that can be mapped
-/// in different processes.
+/// in different processes. See [crate::paging::shared_memory].
+///
+/// The current process will have to map it with `myperm`, and the other
+/// processes with `otherperm`. `otherperm` can also be
+/// [MemoryPermissions::DONT_CARE], to let the other processes choose between
+/// R-- and RW-.
 ///
-/// Other perm can be used to enforce permission 1, 3, or 0x10000000 if don't
-/// care.
-pub fn create_shared_memory(size: u32, _myperm: u32, _otherperm: u32) -> Result<usize, UserspaceError> {
-    let frames = FrameAllocator::allocate_frames_fragmented(size as usize)?;
-    let handle = Arc::new(Handle::SharedMemory(Arc::new(SpinRwLock::new(frames))));
+/// # Errors
+///
+/// - `InvalidSize`
+///   - `size` is 0, or not page aligned.
+/// - `InvalidMemPerms`
+///   - `myperm` is not R-- or RW-.
+///   - `otherperm` is not R--, RW- or DONT_CARE.
+/// - `MemoryFull`
+///   - There is not enough physical memory.
+pub fn create_shared_memory(size: usize, myperm: u32, otherperm: u32) -> Result<usize, UserspaceError> {
+    new_shared_memory(size, myperm, otherperm, FrameAllocator::allocate_frames_fragmented)
+}
+
+/// Allocate a new SharedMemory region backed by physically contiguous memory.
+/// Same as [create_shared_memory], for drivers of devices accessing physical
+/// memory that need a structure to be contiguous.
+///
+/// # Errors
+///
+/// - `InvalidSize`
+///   - `size` is 0, or not page aligned.
+/// - `InvalidMemPerms`
+///   - `myperm` is not R-- or RW-.
+///   - `otherperm` is not R--, RW- or DONT_CARE.
+/// - `MemoryFull`
+///   - There is no free physical memory region of `size` bytes.
+pub fn create_contiguous_shared_memory(size: usize, myperm: u32, otherperm: u32) -> Result<usize, UserspaceError> {
+    new_shared_memory(size, myperm, otherperm, |size| Ok(vec![FrameAllocator::allocate_region(size)?]))
+}
+
+/// Checks the arguments of the CreateSharedMemory syscalls, and creates a
+/// SharedMemory backed by the frames returned by `allocate`.
+fn new_shared_memory<F>(size: usize, myperm: u32, otherperm: u32, allocate: F) -> Result<usize, UserspaceError>
+where
+    F: FnOnce(usize) -> Result<Vec<PhysicalMemRegion>, KernelError>
+{
+    check_shared_memory_size(size)?;
+    let myperm = check_shared_memory_perm(myperm)?;
+    let otherperm = if otherperm == MemoryPermissions::DONT_CARE.bits() {
+        None
+    } else {
+        Some(check_shared_memory_perm(otherperm)?)
+    };
+    let frames = allocate(size)?;
     let curproc = get_current_process();
-    let hnd = curproc.phandles.lock().add_handle(handle);
+    let mem = SharedMemory::new(frames, curproc.pid, myperm, otherperm);
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::SharedMemory(Arc::new(mem))));
     Ok(hnd as _)
 }
 
 /// Maps the block supplied by the handle. The required permissions are different
 /// for the process that created the handle and all other processes.
 ///
+/// A SharedMemory can only be mapped whole: `size` must be its size.
+///
 /// Increases reference count for the SharedMemory object. Thus in order to
 /// release the memory associated with the object, all handles to it must be
 /// closed and all mappings must be unmapped.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///   - `addr` is not page aligned.
+///   - The range is not in UserLand, or already contains a mapping.
+/// - `InvalidSize`
+///   - `size` is 0, or not page aligned.
+///   - `size` is not the size of the SharedMemory.
+/// - `InvalidMemPerms`
+///   - `perm` is not R-- or RW-.
+///   - `perm` is not the permission this process must map the SharedMemory with.
+/// - `InvalidHandle`
+///   - `handle` is not a SharedMemory handle.
 pub fn map_shared_memory(handle: u32, addr: usize, size: usize, perm: u32) -> Result<(), UserspaceError> {
-    let perm = MemoryPermissions::from_bits(perm).ok_or(UserspaceError::InvalidMemPerms)?;
+    let addr = VirtualAddress(addr);
+    addr.check_aligned_to(PAGE_SIZE)?;
+    check_shared_memory_size(size)?;
+    let perm = check_shared_memory_perm(perm)?;
     let curproc = get_current_process();
     let mem = curproc.phandles.lock().get_handle(handle)?.as_shared_memory()?;
-    // TODO: RE the switch: can we map a subsection of a shared memory?
-    if size != mem.read().iter().map(|v| v.size()).sum() {
+    if size != mem.size() {
         return Err(UserspaceError::InvalidSize)
     }
-    curproc.pmemory.lock().map_partial_shared_mapping(mem, VirtualAddress(addr), 0, size, MemoryType::SharedMemory, perm.into())?;
+    if !mem.can_map(curproc.pid, perm) {
+        return Err(UserspaceError::InvalidMemPerms)
+    }
+    curproc.pmemory.lock().map_partial_shared_mapping(mem.frames().clone(), addr, 0, size, MemoryType::SharedMemory, perm.into())?;
     Ok(())
 }
 
-/// Unmaps this shared memory region. This cannot be used to partially unmap a
-/// region: the address **must** be the start of the shared mapping, and the size
-/// **must** be the full size of the mapping.
+/// Unmaps this shared memory region. A SharedMemory can only be unmapped
+/// whole: the address **must** be the start of the shared mapping, and the size
+/// **must** be the size of the SharedMemory.
 ///
-/// # Error
+/// The frames of the SharedMemory are freed once its last mapping is unmapped,
+/// and its last handle is closed.
 ///
-/// - InvalidAddress: address is not the start of a shared mapping
-/// - InvalidSize: Size is not the same as the mapping size.
+/// # Errors
+///
+/// - `InvalidAddress`
+///   - `addr` is not page aligned.
+/// - `InvalidSize`
+///   - `size` is 0, or not page aligned.
+///   - `size` is not the size of the SharedMemory.
+/// - `InvalidMemState`
+///   - `addr` is not the start of a mapping of this SharedMemory.
+/// - `InvalidHandle`
+///   - `handle` is not a SharedMemory handle.
 pub fn unmap_shared_memory(handle: u32, addr: usize, size: usize) -> Result<(), UserspaceError> {
-    let curproc = get_current_process();
-    let hmem = curproc.phandles.lock().get_handle(handle)?.as_shared_memory()?;
     let addr = VirtualAddress(addr);
+    addr.check_aligned_to(PAGE_SIZE)?;
+    check_shared_memory_size(size)?;
+    let curproc = get_current_process();
+    let mem = curproc.phandles.lock().get_handle(handle)?.as_shared_memory()?;
+    if size != mem.size() {
+        return Err(UserspaceError::InvalidSize)
+    }
     let mut memlock = curproc.pmemory.lock();
     {
+        // A mapping of the SharedMemory starting at addr has the right size.
         let qmem = memlock.query_memory(addr);
         let mapping = qmem.mapping();
+        if mapping.address() != addr || !mem.is_mapped_by(mapping) {
+            return Err(UserspaceError::InvalidMemState)
+        }
+    }
+    memlock.unmap(addr, size)?;
+    Ok(())
+}
+
+/// Mirrors the src memory range at dst_addr, as a [Stack] mapping, with a page
+/// guard right below it. libuser uses this to place the stacks of the threads it
+/// allocates on its heap behind a page guard, so a stack overflow faults instead
+/// of corrupting the heap.
+///
+/// The src region must have the MAP_ALLOWED state, and lie in a single mapping.
+/// Unlike Horizon's svcMapMemory, the source keeps its permissions: the heap is a
+/// single mapping that can't be split, it's up to the caller not to use the range
+/// while it's mirrored.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - `src_addr + size` or `dst_addr + size` overflows.
+///    - The src region is outside of the UserLand address space.
+///    - The src memory pages does not have the MAP_ALLOWED state, or span several mappings.
+///    - The dst region, or the page below it, is not of the Unmapped type.
+/// - `InvalidMemRange`
+///    - The dst region, or the page below it, is outside of the UserLand address space.
+///
+/// [Stack]: sunrise_libkern::MemoryType::Stack
+pub fn map_stack_mirror(dst_addr: usize, src_addr: usize, size: usize) -> Result<(), UserspaceError> {
+    let dst_addr = VirtualAddress(dst_addr);
+    let src_addr = VirtualAddress(src_addr);
 
-        // Check that the given addr/size covers the full mapping.
-        // TODO: Can we unmap a subsection of a shared memory?
-        // BODY: I am unsure if it is allowed to unmap a subsection of a shared memory mapping.
-        // This will require some reverse engineering work.
-        if mapping.address() != addr {
-            return Err(UserspaceError::InvalidAddress)
+    src_addr.check_aligned_to(PAGE_SIZE)?;
+    dst_addr.check_aligned_to(PAGE_SIZE)?;
+
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if src_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+    if dst_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    if !UserLand::contains_region(src_addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    let guard_addr = dst_addr.checked_sub(PAGE_SIZE).ok_or(UserspaceError::InvalidMemRange)?;
+    if !UserLand::contains_region(guard_addr, size + PAGE_SIZE) {
+        return Err(UserspaceError::InvalidMemRange)
+    }
+
+    let curproc = scheduler::get_current_process();
+    let mut pmem = curproc.pmemory.lock();
+
+    pmem.check_range(src_addr, size,
+        MemoryState::MAP_ALLOWED, MemoryState::MAP_ALLOWED,
+        MemoryPermissions::empty(), MemoryPermissions::empty(),
+        MemoryAttributes::all(), MemoryAttributes::empty(),
+        MemoryAttributes::IPC_MAPPED | MemoryAttributes::DEVICE_MAPPED)?;
+
+    // Check the destination and its page guard are fully unmapped.
+    pmem.check_range(guard_addr, size + PAGE_SIZE,
+        MemoryState::all(), MemoryType::Unmapped.get_memory_state(),
+        MemoryPermissions::empty(), MemoryPermissions::empty(),
+        MemoryAttributes::empty(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    let (frames, offset) = {
+        let meminfo = pmem.query_memory(src_addr);
+        let mapping = meminfo.mapping();
+        let offset_in_mapping = src_addr - mapping.address();
+        if mapping.length() - offset_in_mapping < size {
+            return Err(UserspaceError::InvalidMemState);
         }
-        if mapping.length() != size {
-            return Err(UserspaceError::InvalidSize)
+        let frames = match mapping.frames() {
+            MappingFrames::Shared(frames) => MappingFrames::Shared(frames.clone()),
+            MappingFrames::Lazy(frames) => MappingFrames::Lazy(frames.clone()),
+            _ => panic!("Got a broken meminfo with non-arc'd frames: {:?}", meminfo)
+        };
+        (frames, offset_in_mapping + mapping.phys_offset())
+    };
+
+    pmem.guard(guard_addr, PAGE_SIZE, MemoryType::Reserved)?;
+    match frames {
+        MappingFrames::Shared(frames) =>
+            pmem.map_partial_shared_mapping(frames, dst_addr, offset, size, MemoryType::Stack, MappingAccessRights::u_rw()),
+        MappingFrames::Lazy(frames) =>
+            pmem.map_partial_lazy_mapping(frames, dst_addr, offset, size, MemoryType::Stack, MappingAccessRights::u_rw()),
+        _ => unreachable!()
+    }.unwrap_or_else(|err| panic!("Failed to map in dst mem: {:?}", err));
+
+    Ok(())
+}
+
+/// Unmaps a memory range mirrored with [map_stack_mirror()], along with its page guard.
+///
+/// The whole range must be unmapped at once.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - The dst region is not a Stack mapping created by [map_stack_mirror()].
+/// - `InvalidMemRange`
+///    - The dst region does not mirror the given src region.
+pub fn unmap_stack_mirror(dst_addr: usize, src_addr: usize, size: usize) -> Result<(), UserspaceError> {
+    let dst_addr = VirtualAddress(dst_addr);
+    let src_addr = VirtualAddress(src_addr);
+
+    src_addr.check_aligned_to(PAGE_SIZE)?;
+    dst_addr.check_aligned_to(PAGE_SIZE)?;
+
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    let guard_addr = dst_addr.checked_sub(PAGE_SIZE).ok_or(UserspaceError::InvalidMemRange)?;
+    if !UserLand::contains_region(guard_addr, size + PAGE_SIZE)
+        || !UserLand::contains_region(src_addr, size) {
+        return Err(UserspaceError::InvalidMemRange)
+    }
+
+    let curproc = scheduler::get_current_process();
+    let mut pmem = curproc.pmemory.lock();
+
+    {
+        let src = pmem.query_memory(src_addr);
+        let src = src.mapping();
+        let dst = pmem.query_memory(dst_addr);
+        let dst = dst.mapping();
+        let guard = pmem.query_memory(guard_addr);
+        let guard = guard.mapping();
+
+        if dst.state().ty() != MemoryType::Stack || dst.address() != dst_addr || dst.length() != size
+            || guard.state().ty() != MemoryType::Reserved || guard.address() != guard_addr || guard.length() != PAGE_SIZE {
+            return Err(UserspaceError::InvalidMemState);
         }
 
-        // Check that we have the correct shared mapping.
-        match (mapping.state().ty(), mapping.frames()) {
-            (MemoryType::SharedMemory, MappingFrames::Shared(frames))
-                if Arc::ptr_eq(frames, &hmem) => (),
-            _ => return Err(UserspaceError::InvalidAddress)
+        // Check dst really mirrors src.
+        match (src.frames(), dst.frames()) {
+            (MappingFrames::Shared(src_frames), MappingFrames::Shared(dst_frames))
+                if Arc::ptr_eq(src_frames, dst_frames)
+                && src.phys_offset() + (src_addr - src.address()) == dst.phys_offset() => (),
+            (MappingFrames::Lazy(src_frames), MappingFrames::Lazy(dst_frames))
+                if Arc::ptr_eq(src_frames, dst_frames)
+                && src.phys_offset() + (src_addr - src.address()) == dst.phys_offset() => (),
+            _ => return Err(UserspaceError::InvalidMemRange)
         }
     }
-    // We know that mapping = addr + size, and we know that handle == mapping.
-    // Let's unmap.
-    memlock.unmap(addr, size)?;
+
+    pmem.unmap(dst_addr, size)?;
+    pmem.unmap(guard_addr, PAGE_SIZE)?;
     Ok(())
 }
 
-
-/// Query information about an address. Will always fetch the lowest page-aligned
-/// mapping that contains the provided address. Writes the output to the
-/// given userspace pointer to a MemoryInfo structure.
-#[inline(never)]
-pub fn query_memory(mut meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, addr: usize) -> Result<usize, UserspaceError> {
-    let curproc = scheduler::get_current_process();
-    let memlock = curproc.pmemory.lock();
-    let qmem = memlock.query_memory(VirtualAddress(addr));
+/// Describes the mapping `addr` falls in, in the memory of a process.
+fn memory_info(pmem: &ProcessMemory, addr: usize) -> MemoryInfo {
+    let qmem = pmem.query_memory(VirtualAddress(addr));
     let mapping = qmem.mapping();
-    *meminfo = MemoryInfo {
+    MemoryInfo {
         baseaddr: mapping.address().addr(),
         size: mapping.length(),
         memtype: mapping.state(),
@@ -587,7 +1063,17 @@ pub fn query_memory(mut meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, addr:
         perms: mapping.flags().into(),
         ipc_ref_count: 0,
         device_ref_count: 0,
-    };
+    }
+}
+
+/// Query information about an address. Will always fetch the lowest page-aligned
+/// mapping that contains the provided address. Writes the output to the
+/// given userspace pointer to a MemoryInfo structure.
+#[inline(never)]
+pub fn query_memory(meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, addr: usize) -> Result<usize, UserspaceError> {
+    // Writing to meminfo locks our memory again, don't hold it.
+    let info = memory_info(&get_current_process().pmemory.lock(), addr);
+    meminfo.write(info)?;
     // TODO: PageInfo Handling
     // BODY: Properly return Page Information. The horizon/NX page-info stuff
     //       is not really documented yet, so this will require some RE work.
@@ -1012,6 +1498,197 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
     Ok(())
 }
 
+/// Same as [query_memory], but queries the memory of the process `proc_hnd`
+/// instead of our own.
+///
+/// The caller must be allowed to debug it: either it is querying itself, or
+/// it has the `can_debug_others` debug flag in its kernel capabilities, and
+/// the process has the `can_be_debugged` one.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - `proc_hnd` is not a Process handle.
+/// - `InvalidState`
+///   - The caller is not allowed to debug the process.
+pub fn query_process_memory(meminfo: UserSpacePtrMut<MemoryInfo>, proc_hnd: u32, addr: usize) -> Result<usize, UserspaceError> {
+    let curproc = get_current_process();
+    let process = curproc.phandles.lock().get_handle(proc_hnd)?.as_process()?;
+    if !Arc::ptr_eq(&curproc, &process)
+        && !(curproc.capabilities.can_debug_others && process.capabilities.can_be_debugged) {
+        return Err(UserspaceError::InvalidState);
+    }
+    // Writing to meminfo locks our memory, which may be the one we query.
+    let info = memory_info(&process.pmemory.lock(), addr);
+    meminfo.write(info)?;
+    Ok(0)
+}
+
+/// Creates an empty [DeviceAddressSpace], where only `[address; address + size)`
+/// can be mapped. Devices attached to it can only DMA to the memory mapped
+/// there, translated by the IOMMU.
+///
+/// # Errors
+///
+/// * `InvalidAddress`: `address` is not page aligned.
+/// * `InvalidSize`
+///    * `size` is zero or not page aligned.
+///    * The range ends past the 39-bit device addresses we can translate.
+/// * `NotImplemented`: the system has no IOMMU.
+/// * `ExceedingMaximum`: the IOMMU ran out of domain ids.
+pub fn create_device_address_space(address: usize, size: usize) -> Result<usize, UserspaceError> {
+    let das = DeviceAddressSpace::new(address, size)?;
+    let curproc = get_current_process();
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::DeviceAddressSpace(Arc::new(das))));
+    Ok(hnd as _)
+}
+
+/// Makes the DMAs of a PCI device go through a [DeviceAddressSpace]. The
+/// device is identified by its requester id: `bus << 8 | device << 3 | function`.
+///
+/// # Errors
+///
+/// * `InvalidHandle`: `das_hnd` is not a DeviceAddressSpace handle.
+/// * `InvalidEnum`: `device` is not a valid requester id.
+/// * `NoSuchEntry`: no device answers at this requester id, or its PCI class
+///   is not allowed by the capabilities of the current process.
+/// * `InvalidAddress`: no IOMMU translates the DMAs of this device.
+/// * `InvalidState`: the device is already attached to a DeviceAddressSpace.
+pub fn attach_device_address_space(device: u32, das_hnd: u32) -> Result<(), UserspaceError> {
+    let device = u16::try_from(device).map_err(|_| UserspaceError::InvalidEnum)?;
+    match devices::pci::class(device) {
+        Some(class) if get_current_process().capabilities.pci_classes.contains(&class) => (),
+        _ => return Err(UserspaceError::NoSuchEntry)
+    }
+    let das = get_current_process().phandles.lock().get_handle(das_hnd)?.as_device_address_space()?;
+    das.attach(device)?;
+    Ok(())
+}
+
+/// Blocks the DMAs of a PCI device attached to a [DeviceAddressSpace].
+///
+/// # Errors
+///
+/// * `InvalidHandle`: `das_hnd` is not a DeviceAddressSpace handle.
+/// * `InvalidEnum`: `device` is not a valid requester id.
+/// * `InvalidState`: the device is not attached to this DeviceAddressSpace.
+pub fn detach_device_address_space(device: u32, das_hnd: u32) -> Result<(), UserspaceError> {
+    let device = u16::try_from(device).map_err(|_| UserspaceError::InvalidEnum)?;
+    let das = get_current_process().phandles.lock().get_handle(das_hnd)?.as_device_address_space()?;
+    das.detach(device)?;
+    Ok(())
+}
+
+/// Gets the handles and checks the arguments shared by the MapDeviceAddressSpace
+/// syscalls.
+fn get_device_mapping_args(das_hnd: u32, proc_hnd: u32, size: usize, device_address: usize, perm: u32)
+    -> Result<(Arc<DeviceAddressSpace>, Arc<ProcessStruct>, MemoryPermissions), UserspaceError>
+{
+    let perm = MemoryPermissions::from_bits(perm)
+        .filter(|perm| !perm.is_empty() && MemoryPermissions::RW.contains(*perm))
+        .ok_or(UserspaceError::InvalidMemPerms)?;
+    let (das, process) = {
+        let handles = get_current_process().phandles.lock();
+        (handles.get_handle(das_hnd)?.as_device_address_space()?, handles.get_handle(proc_hnd)?.as_process()?)
+    };
+    if !das.contains(device_address, size) {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+    Ok((das, process, perm))
+}
+
+/// Maps `size` bytes of the memory of a process at `process_address` in a
+/// [DeviceAddressSpace] at `device_address`, where devices can access them
+/// with `perm`. The memory is committed and its frames stay alive until they
+/// are unmapped from the DeviceAddressSpace.
+///
+/// The memory must have the MAP_DEVICE_ALLOWED state, and at least the
+/// permissions `perm`.
+///
+/// # Errors
+///
+/// * `InvalidHandle`: `das_hnd` is not a DeviceAddressSpace handle, or
+///   `proc_hnd` not a Process handle.
+/// * `InvalidMemPerms`: `perm` is not R, W or RW.
+/// * `InvalidMemRange`: the device range is not part of the DeviceAddressSpace.
+/// * `InvalidAddress`: an address is not page aligned, or the process range is
+///   not in UserLand.
+/// * `InvalidSize`: `size` is zero or not page aligned.
+/// * `InvalidMemState`: the memory doesn't have the required state or
+///   permissions.
+/// * `InvalidState`: part of the device range is already mapped.
+pub fn map_device_address_space_by_force(das_hnd: u32, proc_hnd: u32, process_address: usize, size: usize, device_address: usize, perm: u32) -> Result<(), UserspaceError> {
+    let (das, process, perm) = get_device_mapping_args(das_hnd, proc_hnd, size, device_address, perm)?;
+    das.map(&process, VirtualAddress(process_address), size, device_address, perm, MemoryState::MAP_DEVICE_ALLOWED)?;
+    Ok(())
+}
+
+/// Same as [map_device_address_space_by_force], but the memory must have the
+/// MAP_DEVICE_ALIGNED_ALLOWED state, and the addresses must have the same
+/// offset in a 2MiB large page, so the mapping could use them.
+///
+/// # Errors
+///
+/// * `InvalidAddress`: the addresses have a different large page offset.
+/// * All the errors of [map_device_address_space_by_force].
+pub fn map_device_address_space_aligned(das_hnd: u32, proc_hnd: u32, process_address: usize, size: usize, device_address: usize, perm: u32) -> Result<(), UserspaceError> {
+    /// Size of the large pages of the IOMMU page tables.
+    const LARGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+    let (das, process, perm) = get_device_mapping_args(das_hnd, proc_hnd, size, device_address, perm)?;
+    if process_address % LARGE_PAGE_SIZE != device_address % LARGE_PAGE_SIZE {
+        return Err(UserspaceError::InvalidAddress);
+    }
+    das.map(&process, VirtualAddress(process_address), size, device_address, perm, MemoryState::MAP_DEVICE_ALIGNED_ALLOWED)?;
+    Ok(())
+}
+
+/// Same as [map_device_address_space_by_force], but only maps the part of the
+/// range that falls in the same memory block as `process_address`. Returns the
+/// size that got mapped.
+///
+/// # Errors
+///
+/// * All the errors of [map_device_address_space_by_force].
+pub fn map_device_address_space(das_hnd: u32, proc_hnd: u32, process_address: usize, size: usize, device_address: usize, perm: u32) -> Result<usize, UserspaceError> {
+    let (das, process, perm) = get_device_mapping_args(das_hnd, proc_hnd, size, device_address, perm)?;
+    let process_address = VirtualAddress(process_address);
+    if !UserLand::contains_address(process_address) {
+        return Err(UserspaceError::InvalidAddress);
+    }
+    let size = {
+        let pmem = process.pmemory.lock();
+        let query = pmem.query_memory(process_address);
+        let block = query.mapping();
+        core::cmp::min(size, block.length() - (process_address - block.address()))
+    };
+    das.map(&process, process_address, size, device_address, perm, MemoryState::MAP_DEVICE_ALLOWED)?;
+    Ok(size)
+}
+
+/// Unmaps `size` bytes at `device_address` from a [DeviceAddressSpace]. They
+/// must have been mapped from the memory of the process at `process_address`,
+/// by one or more MapDeviceAddressSpace calls covering exactly this range.
+///
+/// # Errors
+///
+/// * `InvalidHandle`: `das_hnd` is not a DeviceAddressSpace handle, or
+///   `proc_hnd` not a Process handle.
+/// * `InvalidMemRange`: the device range is not part of the DeviceAddressSpace.
+/// * `InvalidSize`: `size` is zero.
+/// * `InvalidMemState`: the range doesn't match the mappings.
+pub fn unmap_device_address_space(das_hnd: u32, proc_hnd: u32, process_address: usize, size: usize, device_address: usize) -> Result<(), UserspaceError> {
+    let (das, process) = {
+        let handles = get_current_process().phandles.lock();
+        (handles.get_handle(das_hnd)?.as_device_address_space()?, handles.get_handle(proc_hnd)?.as_process()?)
+    };
+    if !das.contains(device_address, size) {
+        return Err(UserspaceError::InvalidMemRange);
+    }
+    das.unmap(&process, VirtualAddress(process_address), size, device_address)?;
+    Ok(())
+}
+
 /// Creates a new process. This will create an empty address space without any
 /// thread yet. The size of this address space is controlled through
 /// the [ProcInfoAddrSpace] found in `procinfo`.
@@ -1039,6 +1716,9 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
 ///    * ProcInfo's `code_addr` is not within the allowed code region.
 /// * All the errors from [crate::process::capabilities::ProcessCapabilities#parse_kacs]
 pub fn create_process(procinfo: UserSpacePtr<ProcInfo>, caps: UserSpacePtr<[u8]>) -> Result<usize, UserspaceError> {
+    let procinfo = procinfo.read()?;
+    let caps = caps.read_to_vec()?;
+
     // Ensure the procinfo structure is well-formed.
     procinfo.flags.check()?;
 
@@ -1135,11 +1815,17 @@ pub fn start_process(hnd: u32, main_thread_prio: u32, default_cpuid: u32, main_t
 /// -----------------|--------------------------
 /// ProcessState = 0 | The state the current process is in. Returns an instance
 ///                  | of [sunrise_libkern::process::ProcessState].
+/// ExitCode = 0x5300_0000 | Sunrise extension. The exit code of the process:
+///                  | the one given to [exit_process_with_code], 0 after
+///                  | [exit_process], or 128 + the unix signal if it crashed
+///                  | or was terminated.
 ///
 /// # Errors
 ///
 /// - `InvalidHandle`
 ///   - The passed handle is invalid or not a process.
+/// - `InvalidState`
+///   - Asked for the ExitCode of a process that is not Exited.
 /// - `InvalidEnum`
 ///   - The passed info_type is unknown.
 pub fn get_process_info(hnd: u32, info_type: u32) -> Result<usize, UserspaceError> {
@@ -1148,6 +1834,81 @@ pub fn get_process_info(hnd: u32, info_type: u32) -> Result<usize, UserspaceErro
 
     match info_type {
         ProcessInfoType::ProcessState => Ok(target_proc.state().0 as usize),
+        ProcessInfoType::ExitCode => target_proc.exit_code()
+            .map(|exit_code| exit_code as usize)
+            .ok_or(UserspaceError::InvalidState),
+        _ => Err(UserspaceError::InvalidEnum)
+    }
+}
+
+/// Gets the current value of the system tick counter.
+///
+/// On i386, this is the Time Stamp Counter. It is the time base used for all
+/// the CPU time accounting, see [InfoType].
+pub fn get_system_tick() -> Result<u64, UserspaceError> {
+    Ok(i386::instructions::tsc::read())
+}
+
+/// Extract information about a thread, a process or the system.
+///
+/// Info Type                           | Handle | Sub ID   | Description
+/// ------------------------------------|--------|----------|--------------------------
+/// ThreadTickCount = 0xF0000002        | Thread | 0 or -1  | Ticks spent running by the thread.
+/// ThreadUserTickCount = 0x53000000    | Thread | 0        | Ticks spent running userspace code.
+/// ThreadKernelTickCount = 0x53000001  | Thread | 0        | Ticks spent running kernel code.
+/// ProcessUserTickCount = 0x53000002   | 0      | pid      | Ticks spent running userspace code by all the threads of the process.
+/// ProcessKernelTickCount = 0x53000003 | 0      | pid      | Ticks spent running kernel code by all the threads of the process.
+/// ProcessUsedMemorySize = 0x53000004  | 0      | pid      | Size of the memory mapped in the process' address space.
+/// ProcessImageBase = 0x53000005       | 0      | pid      | Address the main executable of the process was loaded at.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The passed handle is invalid or of the wrong type.
+///   - A handle was passed to an info type working on pids.
+/// - `InvalidCombination`
+///   - The passed sub_id is invalid for this info type.
+/// - `NoSuchEntry`
+///   - No living process has the given pid.
+/// - `InvalidEnum`
+///   - The passed info_type is unknown.
+pub fn get_info(info_type: u32, hnd: u32, sub_id: u64) -> Result<u64, UserspaceError> {
+    let info_type = InfoType(info_type);
+
+    let get_thread = || -> Result<Arc<ThreadStruct>, UserspaceError> {
+        get_current_process().phandles.lock().get_handle(hnd)?.as_thread_handle()?
+            .upgrade().ok_or(UserspaceError::InvalidHandle)
+    };
+    let get_process = || -> Result<Arc<ProcessStruct>, UserspaceError> {
+        if hnd != 0 {
+            return Err(UserspaceError::InvalidHandle);
+        }
+        let pid = usize::try_from(sub_id).map_err(|_| UserspaceError::NoSuchEntry)?;
+        ProcessStruct::find_by_pid(pid).ok_or(UserspaceError::NoSuchEntry)
+    };
+
+    match info_type {
+        InfoType::ThreadTickCount => {
+            if sub_id != 0 && sub_id != u64::max_value() {
+                return Err(UserspaceError::InvalidCombination);
+            }
+            Ok(get_thread()?.cpu_time.get().total_ticks())
+        },
+        InfoType::ThreadUserTickCount | InfoType::ThreadKernelTickCount => {
+            if sub_id != 0 {
+                return Err(UserspaceError::InvalidCombination);
+            }
+            let time = get_thread()?.cpu_time.get();
+            if info_type == InfoType::ThreadUserTickCount {
+                Ok(time.user_ticks)
+            } else {
+                Ok(time.kernel_ticks)
+            }
+        },
+        InfoType::ProcessUserTickCount => Ok(get_process()?.cpu_time().user_ticks),
+        InfoType::ProcessKernelTickCount => Ok(get_process()?.cpu_time().kernel_ticks),
+        InfoType::ProcessUsedMemorySize => Ok(get_process()?.pmemory.lock().used_memory_size() as u64),
+        InfoType::ProcessImageBase => Ok(get_process()?.entrypoint.addr() as u64),
         _ => Err(UserspaceError::InvalidEnum)
     }
 }
@@ -1211,7 +1972,7 @@ pub fn terminate_process(hnd: u32) -> Result<(), UserspaceError> {
         .get_handle(hnd)?.as_process()?;
 
     if Arc::ptr_eq(&scheduler::get_current_process(), &process) {
-        ProcessStruct::kill_current_process();
+        ProcessStruct::kill_current_process(coredump::signal_exit_code(coredump::SIGKILL));
     }
 
     process.terminate()?;
@@ -1231,17 +1992,474 @@ pub fn get_process_list(out_pids: u64, max_pids: u64) -> Result<usize, Userspace
     // We don't have a slab allocator or anything else, so we have a separate
     // array for this.
 
-    let process_list = crate::process::PROCESS_LIST.lock();
-    let iter = process_list.iter()
+    // Don't write to userspace with the process list locked.
+    let pids = crate::process::PROCESS_LIST.lock().iter()
         .take(max_pids.try_into().unwrap_or(usize::max_value()))
-        .enumerate();
-    let out_len = iter.len();
-    for (idx, item) in iter {
-        if let Some(item) = item.upgrade() {
-            unsafe {
-                *UserSpacePtrMut((out_pids as *mut u64).add(idx)) = item.pid as u64;
+        .map(|item| item.upgrade().map(|item| item.pid as u64))
+        .collect::<Vec<_>>();
+    let out_pids = UserSpacePtrMut::from_raw_parts_mut(out_pids as usize as *mut u64, pids.len());
+    out_pids.check()?;
+    for (idx, pid) in pids.iter().enumerate() {
+        if let Some(pid) = pid {
+            UserSpacePtrMut(out_pids.as_mut_ptr().wrapping_add(idx)).write(*pid)?;
+        }
+    }
+    Ok(pids.len())
+}
+
+/// Converts the value returned by a syscall into the 4 registers it is returned in.
+pub trait IntoRegisters {
+    /// Puts the value in the return registers, leaving the unused ones to 0.
+    fn into_registers(self) -> (usize, usize, usize, usize);
+}
+
+impl IntoRegisters for () {
+    fn into_registers(self) -> (usize, usize, usize, usize) { (0, 0, 0, 0) }
+}
+
+impl IntoRegisters for usize {
+    fn into_registers(self) -> (usize, usize, usize, usize) { (self, 0, 0, 0) }
+}
+
+impl IntoRegisters for (usize, usize) {
+    fn into_registers(self) -> (usize, usize, usize, usize) { (self.0, self.1, 0, 0) }
+}
+
+impl IntoRegisters for (usize, usize, usize) {
+    fn into_registers(self) -> (usize, usize, usize, usize) { (self.0, self.1, self.2, 0) }
+}
+
+impl IntoRegisters for (usize, usize, usize, usize) {
+    fn into_registers(self) -> (usize, usize, usize, usize) { self }
+}
+
+/// Dispatches to the various syscall handling functions based on `syscall_nr`.
+///
+/// This is shared by all the syscall entrypoints, which are responsible for fetching the
+/// arguments from and putting the return values in the registers of their ABI.
+///
+/// A process using an unknown syscall, or one it is not allowed to use, is killed.
+pub fn dispatch(syscall_nr: usize, args: [usize; 6]) -> Result<(usize, usize, usize, usize), UserspaceError> {
+    let [x0, x1, x2, x3, x4, x5] = args;
+    let syscall_name = SYSCALL_NAMES.get(syscall_nr).unwrap_or(&"Unknown");
+
+    debug!("Handling syscall {} - x0: {}, x1: {}, x2: {}, x3: {}, x4: {}, x5: {}",
+          syscall_name, x0, x1, x2, x3, x4, x5);
+
+    let allowed = get_current_process().capabilities.syscall_mask.get_bit(syscall_nr);
+
+    if cfg!(feature = "no-security-check") && !allowed {
+        let curproc = get_current_process();
+        error!("Process {} attempted to use unauthorized syscall {} ({:#04x})",
+               curproc.name, syscall_name, syscall_nr);
+    }
+
+    let allowed = cfg!(feature = "no-security-check") || allowed;
+
+    match (allowed, syscall_nr) {
+        // Horizon-inspired syscalls!
+        (true, nr::SetHeapSize) => set_heap_size(x0).map(IntoRegisters::into_registers),
+        (true, nr::QueryMemory) => query_memory(UserSpacePtrMut(x0 as _), x1, x2).map(IntoRegisters::into_registers),
+        (true, nr::ExitProcess) => exit_process().map(IntoRegisters::into_registers),
+        (true, nr::CreateThread) => create_thread(x0, x1, x2, x3 as _, x4 as _).map(IntoRegisters::into_registers),
+        (true, nr::StartThread) => start_thread(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::ExitThread) => exit_thread().map(IntoRegisters::into_registers),
+        (true, nr::SleepThread) => sleep_thread(x0).map(IntoRegisters::into_registers),
+        (true, nr::SignalEvent) => signal_event(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::ClearEvent) => clear_event(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::MapSharedMemory) => map_shared_memory(x0 as _, x1 as _, x2 as _, x3 as _).map(IntoRegisters::into_registers),
+        (true, nr::UnmapSharedMemory) => unmap_shared_memory(x0 as _, x1 as _, x2 as _).map(IntoRegisters::into_registers),
+        (true, nr::CloseHandle) => close_handle(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::ResetSignal) => reset_signal(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::WaitSynchronization) => wait_synchronization(UserSpacePtr::from_raw_parts(x0 as _, x1), x2).map(IntoRegisters::into_registers),
+        (true, nr::GetSystemTick) => get_system_tick().map(|tick| (tick as usize, (tick >> 32) as usize)).map(IntoRegisters::into_registers),
+        (true, nr::ConnectToNamedPort) => connect_to_named_port(UserSpacePtr(x0 as _)).map(IntoRegisters::into_registers),
+        (true, nr::SendSyncRequestWithUserBuffer) => send_sync_request_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2 as _).map(IntoRegisters::into_registers),
+        (true, nr::GetProcessId) => get_process_id(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::OutputDebugString) => output_debug_string(UserSpacePtr::from_raw_parts(x0 as _, x1), x2, UserSpacePtr::from_raw_parts(x3 as _, x4)).map(IntoRegisters::into_registers),
+        (true, nr::GetInfo) => get_info(x0 as _, x1 as _, (x2 as u64) | ((x3 as u64) << 32)).map(|info| (info as usize, (info >> 32) as usize)).map(IntoRegisters::into_registers),
+        (true, nr::DumpInfo) => dump_info(x0, UserSpacePtrMut::from_raw_parts_mut(x1 as _, x2)).map(IntoRegisters::into_registers),
+        (true, nr::CreateSession) => create_session(x0 != 0, x1 as _).map(IntoRegisters::into_registers),
+        (true, nr::AcceptSession) => accept_session(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::ReplyAndReceiveWithUserBuffer) => reply_and_receive_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), UserSpacePtr::from_raw_parts(x2 as _, x3), x4 as _, x5).map(IntoRegisters::into_registers),
+        (true, nr::CreateEvent) => create_event().map(IntoRegisters::into_registers),
+        (true, nr::SleepSystem) => sleep_system(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::CreateSharedMemory) => create_shared_memory(x0 as _, x1 as _, x2 as _).map(IntoRegisters::into_registers),
+        (true, nr::CreateInterruptEvent) => create_interrupt_event(x0, x1 as u32).map(IntoRegisters::into_registers),
+        (true, nr::QueryPhysicalAddress) => query_physical_address(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::GetProcessList) => get_process_list(x0 as _, x1 as _).map(IntoRegisters::into_registers),
+        (true, nr::CreatePort) => create_port(x0 as _, x1 != 0, UserSpacePtr(x2 as _)).map(IntoRegisters::into_registers),
+        (true, nr::ManageNamedPort) => manage_named_port(UserSpacePtr(x0 as _), x1 as _).map(IntoRegisters::into_registers),
+        (true, nr::ConnectToPort) => connect_to_port(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::SetProcessMemoryPermission) => set_process_memory_permission(x0 as _, x1 as _, x2 as _, x3 as _).map(IntoRegisters::into_registers),
+        (true, nr::MapProcessMemory) => map_process_memory(x0 as _, x1 as _, x2 as _, x3 as _).map(IntoRegisters::into_registers),
+        (true, nr::UnmapProcessMemory) => unmap_process_memory(x0 as _, x1 as _, x2 as _, x3 as _).map(IntoRegisters::into_registers),
+        (true, nr::QueryProcessMemory) => query_process_memory(UserSpacePtrMut(x0 as _), x1 as _, x2).map(IntoRegisters::into_registers),
+        (true, nr::CreateDeviceAddressSpace) => create_device_address_space(x0, x1).map(IntoRegisters::into_registers),
+        (true, nr::AttachDeviceAddressSpace) => attach_device_address_space(x0 as _, x1 as _).map(IntoRegisters::into_registers),
+        (true, nr::DetachDeviceAddressSpace) => detach_device_address_space(x0 as _, x1 as _).map(IntoRegisters::into_registers),
+        (true, nr::MapDeviceAddressSpaceByForce) => map_device_address_space_by_force(x0 as _, x1 as _, x2, x3, x4, x5 as _).map(IntoRegisters::into_registers),
+        (true, nr::MapDeviceAddressSpaceAligned) => map_device_address_space_aligned(x0 as _, x1 as _, x2, x3, x4, x5 as _).map(IntoRegisters::into_registers),
+        (true, nr::MapDeviceAddressSpace) => map_device_address_space(x0 as _, x1 as _, x2, x3, x4, x5 as _).map(IntoRegisters::into_registers),
+        (true, nr::UnmapDeviceAddressSpace) => unmap_device_address_space(x0 as _, x1 as _, x2, x3, x4).map(IntoRegisters::into_registers),
+        (true, nr::CreateProcess) => create_process(UserSpacePtr(x0 as _), UserSpacePtr::from_raw_parts(x1 as _, x2 * 4)).map(IntoRegisters::into_registers),
+        (true, nr::StartProcess) => start_process(x0 as _, x1 as _, x2 as _, x3 as _).map(IntoRegisters::into_registers),
+        (true, nr::TerminateProcess) => terminate_process(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::GetProcessInfo) => get_process_info(x0 as _, x1 as _).map(IntoRegisters::into_registers),
+
+        // sunrise extensions
+        (true, nr::MapFramebuffer) => map_framebuffer().map(IntoRegisters::into_registers),
+        (true, nr::MapMmioRegion) => map_mmio_region(x0, x1, x2, x3 != 0).map(IntoRegisters::into_registers),
+        (true, nr::SetThreadArea) => set_thread_area(x0).map(IntoRegisters::into_registers),
+        (true, nr::SetLogFilter) => set_log_filter(UserSpacePtr::from_raw_parts(x0 as _, x1), x2, x3 != 0).map(IntoRegisters::into_registers),
+        (true, nr::ControlProfiler) => control_profiler(x0 as _, UserSpacePtrMut::from_raw_parts_mut(x1 as _, x2)).map(IntoRegisters::into_registers),
+        (true, nr::GetProcessName) => get_process_name(x0, UserSpacePtrMut::from_raw_parts_mut(x1 as _, x2)).map(IntoRegisters::into_registers),
+        (true, nr::ReadCoreDump) => read_core_dump(x0 as _, x1, UserSpacePtrMut::from_raw_parts_mut(x2 as _, x3)).map(IntoRegisters::into_registers),
+        (true, nr::ReadKernelPanicReport) => read_kernel_panic_report(x0, UserSpacePtrMut::from_raw_parts_mut(x1 as _, x2)).map(IntoRegisters::into_registers),
+        (true, nr::ExitProcessWithCode) => exit_process_with_code(x0 as _).map(IntoRegisters::into_registers),
+        (true, nr::MapStackMirror) => map_stack_mirror(x0, x1, x2).map(IntoRegisters::into_registers),
+        (true, nr::UnmapStackMirror) => unmap_stack_mirror(x0, x1, x2).map(IntoRegisters::into_registers),
+        (true, nr::CreateContiguousSharedMemory) => create_contiguous_shared_memory(x0 as _, x1 as _, x2 as _).map(IntoRegisters::into_registers),
+
+        // Unknown/unauthorized syscall.
+        (false, _) => {
+            // Attempted to call unauthorized SVC. Horizon invokes usermode
+            // exception handling in some cases. Let's just kill the process for
+            // now.
+            let curproc = get_current_process();
+            error!("Process {} attempted to use unauthorized syscall {} ({:#04x}), killing",
+                   curproc.name, syscall_name, syscall_nr);
+            ProcessStruct::crash_current_process(coredump::SIGSYS);
+            Err(UserspaceError::NotImplemented)
+        },
+        _ => {
+            let curproc = get_current_process();
+            error!("Process {} attempted to use unknown syscall {} ({:#04x}), killing",
+                   curproc.name, syscall_name, syscall_nr);
+            ProcessStruct::crash_current_process(coredump::SIGSYS);
+            Err(UserspaceError::NotImplemented)
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod tests {
+    use super::*;
+    use crate::paging::lands::KernelLand;
+    use crate::testing::*;
+
+    /// Calls `f` with addresses of buffers bigger than 4 bytes the current
+    /// process can neither read nor write: null, unmapped, in KernelLand,
+    /// overflowing, and straddling a mapped and an unmapped page. The second
+    /// argument of `f` is true.
+    ///
+    /// Then calls `f` with the address of a read-only page, that must be
+    /// refused by writes. The second argument of `f` is false.
+    fn with_bogus_addresses<F: Fn(usize, bool)>(f: F) {
+        let process = get_current_process();
+        let (unmapped, straddling, read_only) = {
+            let mut pmemory = process.pmemory.lock();
+            let read_only = pmemory.find_available_space(PAGE_SIZE).unwrap();
+            pmemory.create_regular_mapping(read_only, PAGE_SIZE, MemoryType::Normal, MappingAccessRights::u_r()).unwrap();
+            // Only map the first page, the second one must stay unmapped.
+            let straddling = pmemory.find_available_space(2 * PAGE_SIZE).unwrap();
+            pmemory.create_regular_mapping(straddling, PAGE_SIZE, MemoryType::Normal, MappingAccessRights::u_rw()).unwrap();
+            let unmapped = pmemory.find_available_space(PAGE_SIZE).unwrap();
+            (unmapped, straddling, read_only)
+        };
+
+        f(0, true);
+        f(unmapped.addr(), true);
+        f(KernelLand::start_addr().addr(), true);
+        f(usize::max_value() - 4, true);
+        f(straddling.addr() + PAGE_SIZE - 4, true);
+        f(read_only.addr(), false);
+
+        let mut pmemory = process.pmemory.lock();
+        pmemory.unmap(straddling, PAGE_SIZE).unwrap();
+        pmemory.unmap(read_only, PAGE_SIZE).unwrap();
+    }
+
+    #[test_case]
+    fn svcs_refuse_bogus_input_pointers() {
+        with_bogus_addresses(|addr, unreadable| {
+            if !unreadable {
+                return;
             }
+            let bytes = UserSpacePtr::from_raw_parts(addr as *const u8, 0x20);
+            let handles = UserSpacePtr::from_raw_parts(addr as *const u32, 8);
+            assert_eq!(wait_synchronization(handles, 0), Err(UserspaceError::InvalidAddress));
+            assert_eq!(output_debug_string(bytes, 0, bytes), Err(UserspaceError::InvalidAddress));
+            assert_eq!(set_log_filter(bytes, 0, false), Err(UserspaceError::InvalidAddress));
+            assert_eq!(connect_to_named_port(UserSpacePtr(addr as *const [u8; 12])), Err(UserspaceError::InvalidAddress));
+            assert_eq!(manage_named_port(UserSpacePtr(addr as *const [u8; 12]), 1), Err(UserspaceError::InvalidAddress));
+            assert_eq!(create_process(UserSpacePtr(addr as *const ProcInfo), bytes), Err(UserspaceError::InvalidAddress));
+            assert_eq!(reply_and_receive_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(core::ptr::null_mut(), 0), handles, 0, 0),
+                Err(UserspaceError::InvalidAddress));
+        });
+    }
+
+    #[test_case]
+    fn svcs_refuse_bogus_output_pointers() {
+        let pid = get_current_process().pid;
+        with_bogus_addresses(|addr, _| {
+            let bytes = UserSpacePtrMut::from_raw_parts_mut(addr as *mut u8, 0x40);
+            assert_eq!(query_memory(UserSpacePtrMut(addr as *mut MemoryInfo), 0, 0), Err(UserspaceError::InvalidAddress));
+            assert_eq!(query_process_memory(UserSpacePtrMut(addr as *mut MemoryInfo), 0xFFFF8001, 0), Err(UserspaceError::InvalidAddress));
+            assert_eq!(send_sync_request_with_user_buffer(bytes, 0xFFFF), Err(UserspaceError::InvalidAddress));
+            assert_eq!(reply_and_receive_with_user_buffer(bytes, UserSpacePtr::from_raw_parts(core::ptr::null(), 0), 0, 0),
+                Err(UserspaceError::InvalidAddress));
+            assert_eq!(control_profiler(ProfilerCommand::Drain.0, UserSpacePtrMut::from_raw_parts_mut(addr as *mut ProfilerSample, 2)),
+                Err(UserspaceError::InvalidAddress));
+            assert_eq!(get_process_name(pid, bytes), Err(UserspaceError::InvalidAddress));
+            assert_eq!(read_core_dump(0xFFFF, 0, bytes), Err(UserspaceError::InvalidAddress));
+            assert_eq!(read_kernel_panic_report(0, bytes), Err(UserspaceError::InvalidAddress));
+            assert_eq!(dump_info(0, bytes), Err(UserspaceError::InvalidAddress));
+            assert_eq!(get_process_list(addr as u64, 8), Err(UserspaceError::InvalidAddress));
+        });
+    }
+
+    #[test_case]
+    fn svcs_refuse_bogus_addresses() {
+        let process = get_current_process();
+        let (unmapped, read_only) = {
+            let mut pmemory = process.pmemory.lock();
+            let read_only = pmemory.find_available_space(PAGE_SIZE).unwrap();
+            pmemory.create_regular_mapping(read_only, PAGE_SIZE, MemoryType::Normal, MappingAccessRights::u_r()).unwrap();
+            let unmapped = pmemory.find_available_space(PAGE_SIZE).unwrap();
+            (unmapped, read_only)
+        };
+        let shmem = create_shared_memory(PAGE_SIZE, MemoryPermissions::RW.bits(), MemoryPermissions::READABLE.bits()).unwrap() as u32;
+        // Mapping the memory of the current process would lock its memory twice.
+        let other = create_test_process("other", &[0x0F, 0x0B], &[]);
+        let other = process.phandles.lock().add_handle(Arc::new(Handle::Process(other)));
+        let rw = MemoryPermissions::RW.bits();
+        let last_page = usize::max_value() & !(PAGE_SIZE - 1);
+        let kernel = KernelLand::start_addr().addr();
+
+        // Nothing can be mapped there.
+        for &addr in &[0, kernel, last_page, read_only.addr(), unmapped.addr() + 4] {
+            assert!(map_shared_memory(shmem, addr, PAGE_SIZE, rw).is_err(), "mapped shared memory at {:#010x}", addr);
+            assert!(map_process_memory(addr, other, TEST_PROCESS_CODE_ADDR, PAGE_SIZE).is_err(), "mapped process memory at {:#010x}", addr);
         }
+        for &addr in &[0, kernel, last_page, TEST_PROCESS_CODE_ADDR + 4] {
+            assert!(map_process_memory(unmapped.addr(), other, addr, PAGE_SIZE).is_err(), "mapped process memory from {:#010x}", addr);
+        }
+        assert!(map_shared_memory(shmem, unmapped.addr(), last_page, rw).is_err());
+        assert!(map_process_memory(unmapped.addr(), other, TEST_PROCESS_CODE_ADDR, last_page).is_err());
+
+        // Nothing can be unmapped, reprotected or looked up there.
+        for &addr in &[0, kernel, last_page, unmapped.addr(), unmapped.addr() + 4] {
+            assert!(unmap_shared_memory(shmem, addr, PAGE_SIZE).is_err(), "unmapped shared memory at {:#010x}", addr);
+            assert!(unmap_process_memory(addr, other, TEST_PROCESS_CODE_ADDR, PAGE_SIZE).is_err(), "unmapped process memory at {:#010x}", addr);
+            assert!(set_process_memory_permission(0xFFFF8001, addr, PAGE_SIZE, MemoryPermissions::READABLE.bits()).is_err(),
+                "changed permissions at {:#010x}", addr);
+            assert!(query_physical_address(addr).is_err(), "found a physical address at {:#010x}", addr);
+        }
+
+        // The heap can't grow out of UserLand, or to a size that isn't a page multiple.
+        for &size in &[PAGE_SIZE + 4, last_page, kernel] {
+            assert!(set_heap_size(size).is_err(), "resized the heap to {:#010x}", size);
+        }
+
+        close_handle(shmem).unwrap();
+        close_handle(other).unwrap();
+        process.pmemory.lock().unmap(read_only, PAGE_SIZE).unwrap();
+    }
+
+    #[test_case]
+    fn contiguous_shared_memory_is_one_region() {
+        let size = 4 * PAGE_SIZE;
+        let rw = MemoryPermissions::RW.bits();
+        let handle = create_contiguous_shared_memory(size, rw, MemoryPermissions::DONT_CARE.bits()).unwrap() as u32;
+        let mem = get_current_process().phandles.lock().get_handle(handle).unwrap().as_shared_memory().unwrap();
+        assert_eq!(mem.size(), size);
+        assert_eq!(mem.frames().read().len(), 1);
+        drop(mem);
+        close_handle(handle).unwrap();
+
+        assert_eq!(create_contiguous_shared_memory(PAGE_SIZE + 4, rw, rw), Err(UserspaceError::InvalidSize));
+    }
+
+    #[test_case]
+    fn query_memory_writes_to_valid_pointers() {
+        let process = get_current_process();
+        let page = {
+            let mut pmemory = process.pmemory.lock();
+            let page = pmemory.find_available_space(PAGE_SIZE).unwrap();
+            pmemory.create_regular_mapping(page, PAGE_SIZE, MemoryType::Normal, MappingAccessRights::u_rw()).unwrap();
+            page
+        };
+
+        let meminfo = UserSpacePtrMut(page.addr() as *mut MemoryInfo);
+        assert_eq!(query_memory(meminfo, 0, page.addr() + 4), Ok(0));
+        let info = UserSpacePtr::from(meminfo).read().unwrap();
+        assert_eq!(info.baseaddr, page.addr());
+        assert_eq!(info.size, PAGE_SIZE);
+        assert_eq!(info.perms, MemoryPermissions::RW);
+
+        // A process can always query itself through its own handle.
+        assert_eq!(query_process_memory(meminfo, 0xFFFF8001, page.addr() + 4), Ok(0));
+        let info = UserSpacePtr::from(meminfo).read().unwrap();
+        assert_eq!(info.baseaddr, page.addr());
+        assert_eq!(info.size, PAGE_SIZE);
+        assert_eq!(query_process_memory(meminfo, 0xFFFF, page.addr()), Err(UserspaceError::InvalidHandle));
+
+        process.pmemory.lock().unmap(page, PAGE_SIZE).unwrap();
+    }
+
+    /// Queries the memory at `CODE` of the process whose handle is at
+    /// `DATA + 0x200`, in a MemoryInfo at `DATA`, stores the result of the
+    /// syscall at `DATA + 0x204`, and exits.
+    const QUERY_PROCESS_MEMORY_CODE: &[u8] = &[
+        0xB8, 0x76, 0x00, 0x00, 0x00,       // mov eax, QueryProcessMemory
+        0xBB, 0x00, 0x00, 0x50, 0x00,       // mov ebx, 0x500000
+        0x8B, 0x0D, 0x00, 0x02, 0x50, 0x00, // mov ecx, [0x500200]
+        0xBA, 0x00, 0x00, 0x40, 0x00,       // mov edx, 0x400000
+        0xCD, 0x80,                         // int 0x80
+        0xA3, 0x04, 0x02, 0x50, 0x00,       // mov [0x500204], eax
+        0xB8, 0x07, 0x00, 0x00, 0x00,       // mov eax, ExitProcess
+        0xCD, 0x80,                         // int 0x80
+        0x0F, 0x0B,                         // ud2
+    ];
+
+    /// Runs [QUERY_PROCESS_MEMORY_CODE] in a process with the debug flags
+    /// `debugger_flags`, on a second process with the debug flags
+    /// `debuggee_flags`.
+    ///
+    /// Returns the result of the syscall, and the MemoryInfo it wrote.
+    fn query_other_process_memory(debugger_flags: [u8; 4], debuggee_flags: [u8; 4]) -> (u32, MemoryInfo) {
+        assert_eq!(nr::QueryProcessMemory, 0x76);
+        assert_eq!(nr::ExitProcess, 0x07);
+
+        let debuggee = create_test_process_with_kacs("debuggee", &[0x0F, 0x0B], &debuggee_flags);
+        let mut kacs = syscall_kacs(&[nr::QueryProcessMemory, nr::ExitProcess]);
+        kacs.extend_from_slice(&debugger_flags);
+        let debugger = create_test_process_with_kacs("debugger", QUERY_PROCESS_MEMORY_CODE, &kacs);
+        let handle = debugger.phandles.lock().add_handle(Arc::new(Handle::Process(debuggee)));
+        write_process_memory(&debugger, TEST_PROCESS_DATA_ADDR + 0x200, &handle.to_le_bytes());
+        ProcessStruct::start(&debugger, 0, PAGE_SIZE).unwrap();
+        wait_process_exit(&debugger);
+
+        let mut result = [0; 4];
+        read_process_memory(&debugger, TEST_PROCESS_DATA_ADDR + 0x204, &mut result);
+        let mut info = [0; core::mem::size_of::<MemoryInfo>()];
+        read_process_memory(&debugger, TEST_PROCESS_DATA_ADDR, &mut info);
+        let info = unsafe {
+            // safe: MemoryInfo is a repr(C) struct of integers.
+            core::ptr::read_unaligned(info.as_ptr() as *const MemoryInfo)
+        };
+        (u32::from_le_bytes(result), info)
     }
-    Ok(out_len)
-}
\ No newline at end of file
+
+    #[test_case]
+    fn query_process_memory_of_debuggable_process() {
+        let (result, info) = query_other_process_memory(debug_flags_kac(false, true), debug_flags_kac(true, false));
+        assert_eq!(result, 0, "QueryProcessMemory returned an error");
+        assert_eq!(info.baseaddr, TEST_PROCESS_CODE_ADDR);
+        assert_eq!(info.size, PAGE_SIZE);
+        assert_eq!(info.memtype.ty(), MemoryType::CodeStatic);
+        assert_eq!(info.perms, MemoryPermissions::RX);
+    }
+
+    #[test_case]
+    fn query_process_memory_needs_debug_flags() {
+        let invalid_state = UserspaceError::InvalidState.make_ret();
+        let (result, _) = query_other_process_memory(debug_flags_kac(false, false), debug_flags_kac(true, false));
+        assert_eq!(result, invalid_state);
+        let (result, _) = query_other_process_memory(debug_flags_kac(false, true), debug_flags_kac(false, false));
+        assert_eq!(result, invalid_state);
+    }
+
+    #[test_case]
+    fn device_address_space_mappings() {
+        let process = get_current_process();
+        let memory = {
+            let mut pmemory = process.pmemory.lock();
+            let memory = pmemory.find_available_space(2 * PAGE_SIZE).unwrap();
+            pmemory.create_lazy_mapping(memory, 2 * PAGE_SIZE, MemoryType::Heap, MappingAccessRights::u_rw()).unwrap();
+            memory
+        };
+        let das = create_device_address_space(0x10_0000, 0x10_0000).unwrap() as u32;
+        let rw = MemoryPermissions::RW.bits();
+
+        assert_eq!(map_device_address_space_by_force(das, 0xFFFF8001, memory.addr(), 2 * PAGE_SIZE, 0x1F_F000, rw),
+            Err(UserspaceError::InvalidMemRange));
+        assert_eq!(map_device_address_space_by_force(das, 0xFFFF8001, memory.addr(), PAGE_SIZE, 0x10_0000, MemoryPermissions::RX.bits()),
+            Err(UserspaceError::InvalidMemPerms));
+        assert_eq!(map_device_address_space_by_force(das, 0xFFFF8001, memory.addr(), PAGE_SIZE, 0x10_0000, rw), Ok(()));
+        assert_eq!(map_device_address_space_by_force(das, 0xFFFF8001, memory.addr(), PAGE_SIZE, 0x10_0000, rw),
+            Err(UserspaceError::InvalidState));
+        // Only the rest of the block gets mapped.
+        assert_eq!(map_device_address_space(das, 0xFFFF8001, memory.addr() + PAGE_SIZE, 2 * PAGE_SIZE, 0x10_1000, rw), Ok(PAGE_SIZE));
+
+        assert_eq!(unmap_device_address_space(das, 0xFFFF8001, memory.addr() + PAGE_SIZE, 2 * PAGE_SIZE, 0x10_0000),
+            Err(UserspaceError::InvalidMemState));
+        assert_eq!(unmap_device_address_space(das, 0xFFFF8001, memory.addr(), 2 * PAGE_SIZE, 0x10_0000), Ok(()));
+
+        close_handle(das).unwrap();
+        process.pmemory.lock().unmap(memory, 2 * PAGE_SIZE).unwrap();
+    }
+
+    #[test_case]
+    fn shared_memory_errors() {
+        let r = MemoryPermissions::READABLE.bits();
+        let rw = MemoryPermissions::RW.bits();
+        let dont_care = MemoryPermissions::DONT_CARE.bits();
+
+        assert_eq!(create_shared_memory(0, rw, r), Err(UserspaceError::InvalidSize));
+        assert_eq!(create_shared_memory(PAGE_SIZE + 1, rw, r), Err(UserspaceError::InvalidSize));
+        assert_eq!(create_shared_memory(PAGE_SIZE, MemoryPermissions::RX.bits(), r), Err(UserspaceError::InvalidMemPerms));
+        assert_eq!(create_shared_memory(PAGE_SIZE, dont_care, r), Err(UserspaceError::InvalidMemPerms));
+        assert_eq!(create_shared_memory(PAGE_SIZE, rw, 0), Err(UserspaceError::InvalidMemPerms));
+
+        let shmem = create_shared_memory(2 * PAGE_SIZE, rw, dont_care).unwrap() as u32;
+        let other = create_shared_memory(2 * PAGE_SIZE, rw, r).unwrap() as u32;
+        let addr = get_current_process().pmemory.lock().find_available_space(2 * PAGE_SIZE).unwrap().addr();
+
+        assert_eq!(map_shared_memory(shmem, addr + 1, 2 * PAGE_SIZE, rw), Err(UserspaceError::InvalidAddress));
+        assert_eq!(map_shared_memory(shmem, addr, 2 * PAGE_SIZE + 1, rw), Err(UserspaceError::InvalidSize));
+        // It can't be mapped partially.
+        assert_eq!(map_shared_memory(shmem, addr, PAGE_SIZE, rw), Err(UserspaceError::InvalidSize));
+        assert_eq!(map_shared_memory(shmem, addr, 2 * PAGE_SIZE, dont_care), Err(UserspaceError::InvalidMemPerms));
+        // The owner must use its own permissions.
+        assert_eq!(map_shared_memory(shmem, addr, 2 * PAGE_SIZE, r), Err(UserspaceError::InvalidMemPerms));
+        assert_eq!(map_shared_memory(0xFFFF8001, addr, 2 * PAGE_SIZE, rw), Err(UserspaceError::InvalidHandle));
+        assert_eq!(map_shared_memory(shmem, KernelLand::start_addr().addr(), 2 * PAGE_SIZE, rw), Err(UserspaceError::InvalidAddress));
+        assert_eq!(map_shared_memory(shmem, addr, 2 * PAGE_SIZE, rw), Ok(()));
+        assert_eq!(map_shared_memory(other, addr, 2 * PAGE_SIZE, rw), Err(UserspaceError::InvalidAddress));
+
+        assert_eq!(unmap_shared_memory(shmem, addr + 1, 2 * PAGE_SIZE), Err(UserspaceError::InvalidAddress));
+        assert_eq!(unmap_shared_memory(shmem, addr, 0), Err(UserspaceError::InvalidSize));
+        // It can't be unmapped partially.
+        assert_eq!(unmap_shared_memory(shmem, addr, PAGE_SIZE), Err(UserspaceError::InvalidSize));
+        assert_eq!(unmap_shared_memory(shmem, addr + PAGE_SIZE, 2 * PAGE_SIZE), Err(UserspaceError::InvalidMemState));
+        assert_eq!(unmap_shared_memory(other, addr, 2 * PAGE_SIZE), Err(UserspaceError::InvalidMemState));
+        assert_eq!(unmap_shared_memory(0xFFFF8001, addr, 2 * PAGE_SIZE), Err(UserspaceError::InvalidHandle));
+        assert_eq!(unmap_shared_memory(shmem, addr, 2 * PAGE_SIZE), Ok(()));
+        assert_eq!(unmap_shared_memory(shmem, addr, 2 * PAGE_SIZE), Err(UserspaceError::InvalidMemState));
+
+        close_handle(shmem).unwrap();
+        close_handle(other).unwrap();
+    }
+
+    #[test_case]
+    fn shared_memory_frames_outlive_handle_and_mappings() {
+        let process = get_current_process();
+        let rw = MemoryPermissions::RW.bits();
+        let shmem = create_shared_memory(PAGE_SIZE, rw, rw).unwrap() as u32;
+        let frames = Arc::downgrade(process.phandles.lock().get_handle(shmem).unwrap().as_shared_memory().unwrap().frames());
+
+        let first = process.pmemory.lock().find_available_space(PAGE_SIZE).unwrap();
+        assert_eq!(map_shared_memory(shmem, first.addr(), PAGE_SIZE, rw), Ok(()));
+        let second = process.pmemory.lock().find_available_space(PAGE_SIZE).unwrap();
+        assert_eq!(map_shared_memory(shmem, second.addr(), PAGE_SIZE, rw), Ok(()));
+
+        close_handle(shmem).unwrap();
+        assert!(frames.upgrade().is_some());
+        process.pmemory.lock().unmap(first, PAGE_SIZE).unwrap();
+        assert!(frames.upgrade().is_some());
+        process.pmemory.lock().unmap(second, PAGE_SIZE).unwrap();
+        assert!(frames.upgrade().is_none());
+    }
+}
@@ -0,0 +1,230 @@
+//! In-QEMU kernel test harness
+//!
+//! When built with `cargo make test-kernel`, the kernel is compiled as a test
+//! binary for the `i386-unknown-none` target. Instead of loading the grub
+//! modules, [common_start] calls the `test_main` function generated by
+//! `custom_test_frameworks`, which hands every `#[test_case]` to
+//! [test_runner]. Tests run as kernel code in the context of the first
+//! process, after the kernel has been fully initialized, so they are free to
+//! create real processes, sleep on Waitables, and so on.
+//!
+//! Results are reported over the serial port. Once every test has run (or
+//! the kernel panicked), we exit QEMU through its `isa-debug-exit` device,
+//! so the exit status of QEMU tells whether the test suite succeeded.
+//!
+//! [common_start]: crate::common_start
+
+use core::fmt::Write;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::devices::rs232::{SerialLogger, SerialAttributes, SerialColor};
+use crate::i386::pio::Pio;
+use crate::io::Io;
+use crate::mem::VirtualAddress;
+use crate::paging::{PAGE_SIZE, MappingAccessRights};
+use crate::process::ProcessStruct;
+use crate::event;
+use sunrise_libkern::MemoryType;
+use sunrise_libkern::process::*;
+
+/// I/O port of QEMU's `isa-debug-exit` device. Must match the `iobase`
+/// passed to QEMU in the `test-kernel` task.
+const QEMU_EXIT_PORT: u16 = 0xf4;
+
+/// Exit code reported to QEMU's `isa-debug-exit` device.
+///
+/// QEMU exits with the status `(code << 1) | 1`, so success is reported as
+/// 33, and failure as 35.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    /// All the tests passed.
+    Success = 0x10,
+    /// A test failed, or the kernel panicked.
+    Failed = 0x11,
+}
+
+/// Exits QEMU with the given exit code.
+///
+/// If the `isa-debug-exit` device is missing, this halts forever instead.
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    Pio::<u32>::new(QEMU_EXIT_PORT).write(exit_code as u32);
+    loop { unsafe { llvm_asm!("HLT"); } }
+}
+
+/// A test case, as collected by `custom_test_frameworks`.
+///
+/// Implemented for every `fn()`, so that the test report can print the name
+/// of the function being run.
+pub trait Testable {
+    /// Runs the test, printing its name and its result on the serial port.
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        let _ = write!(SerialLogger, "{}... ", core::any::type_name::<T>());
+        self();
+        let _ = writeln!(SerialLogger, "{}[ok]{}",
+            SerialAttributes::fg(SerialColor::Green),
+            SerialAttributes::default());
+    }
+}
+
+/// Runs every test case of the kernel, and exits QEMU.
+///
+/// A failing test panics, which ends up in [test_panic] and exits QEMU with
+/// [QemuExitCode::Failed].
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let _ = writeln!(SerialLogger, "Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    let _ = writeln!(SerialLogger, "test result: {}ok{}. {} passed",
+        SerialAttributes::fg(SerialColor::Green),
+        SerialAttributes::default(),
+        tests.len());
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Reports the currently running test as failed, and exits QEMU.
+///
+/// Called by the kernel panic handler once the panic has been displayed.
+pub fn test_panic() -> ! {
+    let _ = writeln!(SerialLogger, "{}[failed]{}",
+        SerialAttributes::fg(SerialColor::LightRed),
+        SerialAttributes::default());
+    exit_qemu(QemuExitCode::Failed);
+}
+
+/// Address at which [spawn_test_process] loads the code of test processes.
+pub const TEST_PROCESS_CODE_ADDR: usize = 0x400000;
+
+/// Address of a zeroed read-write page mapped in every test process, that
+/// their code can use as scratch memory (IPC buffers, ...).
+pub const TEST_PROCESS_DATA_ADDR: usize = 0x500000;
+
+/// Builds a kernel capability entry allowing the given syscalls.
+///
+/// The resulting bytes can be passed as kacs to [ProcessStruct::new].
+pub fn syscall_kacs(syscalls: &[usize]) -> Vec<u8> {
+    let mut masks = [0u32; 8];
+    for &nr in syscalls {
+        masks[nr / 24] |= 1 << (nr % 24);
+    }
+    let mut kacs = Vec::new();
+    for (idx, mask) in masks.iter().enumerate().filter(|(_, mask)| **mask != 0) {
+        let entry = 0b01111 | (mask << 5) | ((idx as u32) << 29);
+        kacs.extend_from_slice(&entry.to_le_bytes());
+    }
+    kacs
+}
+
+/// Builds a debug flags kernel capability entry.
+///
+/// The resulting bytes can be appended to the kacs passed to
+/// [ProcessStruct::new].
+pub fn debug_flags_kac(can_be_debugged: bool, can_debug_others: bool) -> [u8; 4] {
+    let entry = 0xFFFF | ((can_be_debugged as u32) << 17) | ((can_debug_others as u32) << 18);
+    entry.to_le_bytes()
+}
+
+/// Creates a userspace process running the given raw i386 code, without
+/// starting it.
+///
+/// The code is copied at [TEST_PROCESS_CODE_ADDR], and a zeroed read-write
+/// page is mapped at [TEST_PROCESS_DATA_ADDR]. The process is only allowed
+/// to use the syscalls listed in `syscalls`.
+pub fn create_test_process(name: &str, code: &[u8], syscalls: &[usize]) -> Arc<ProcessStruct> {
+    create_test_process_with_kacs(name, code, &syscall_kacs(syscalls))
+}
+
+/// Same as [create_test_process], but the capabilities of the process are
+/// given as raw kacs, such as the ones returned by [syscall_kacs].
+pub fn create_test_process_with_kacs(name: &str, code: &[u8], kacs: &[u8]) -> Arc<ProcessStruct> {
+    let mut procname = [0; 12];
+    let len = core::cmp::min(name.len(), procname.len());
+    procname[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+    let mut flags = ProcInfoFlags(0);
+    flags.set_address_space_type(ProcInfoAddrSpace::AS32Bit);
+    flags.set_pool_partition(PoolPartition::Sysmodule);
+
+    let procinfo = ProcInfo {
+        name: procname,
+        process_category: ProcessCategory::KernelBuiltin,
+        title_id: 0,
+        code_addr: TEST_PROCESS_CODE_ADDR as _,
+        code_num_pages: 0,
+        flags,
+        resource_limit_handle: None,
+        system_resource_num_pages: 0
+    };
+
+    let process = ProcessStruct::new(&procinfo, Some(kacs))
+        .expect("Failed to create test process");
+
+    {
+        let mut pmemory = process.pmemory.lock();
+        let code_len = sunrise_libutils::align_up(code.len(), PAGE_SIZE);
+        pmemory.create_regular_mapping(VirtualAddress(TEST_PROCESS_CODE_ADDR), code_len, MemoryType::CodeStatic, MappingAccessRights::u_rx())
+            .expect("Failed to map test process code");
+        pmemory.create_regular_mapping(VirtualAddress(TEST_PROCESS_DATA_ADDR), PAGE_SIZE, MemoryType::Normal, MappingAccessRights::u_rw())
+            .expect("Failed to map test process data");
+
+        let mirror = pmemory.mirror_mapping(VirtualAddress(TEST_PROCESS_CODE_ADDR), code_len)
+            .expect("Failed to mirror test process code");
+        let dest = unsafe {
+            // safe: the mirror is kept alive until the end of the scope, and
+            // nothing else is using this freshly created mapping.
+            core::slice::from_raw_parts_mut(mirror.addr().addr() as *mut u8, mirror.len())
+        };
+        dest[..code.len()].copy_from_slice(code);
+    }
+
+    process
+}
+
+/// Creates and starts a userspace process running the given raw i386 code.
+///
+/// See [create_test_process].
+pub fn spawn_test_process(name: &str, code: &[u8], syscalls: &[usize]) -> Arc<ProcessStruct> {
+    let process = create_test_process(name, code, syscalls);
+    ProcessStruct::start(&process, 0, PAGE_SIZE)
+        .expect("Failed to start test process");
+    process
+}
+
+/// Waits for a process to reach the Exited state.
+pub fn wait_process_exit(process: &Arc<ProcessStruct>) {
+    while process.state() != ProcessState::Exited {
+        event::wait(Some(process as &dyn event::Waitable))
+            .expect("Failed to wait on the test process");
+        // Fails once the process is Exited, which is what we're waiting for.
+        let _ = process.clear_signal();
+    }
+}
+
+/// Writes `data` in the memory of `process` at `address`.
+pub fn write_process_memory(process: &ProcessStruct, address: usize, data: &[u8]) {
+    let pmemory = process.pmemory.lock();
+    let mirror = pmemory.mirror_mapping(VirtualAddress(address), data.len())
+        .expect("Failed to mirror process memory");
+    let dest = unsafe {
+        // safe: the mirror is kept alive until the end of the function.
+        core::slice::from_raw_parts_mut(mirror.addr().addr() as *mut u8, data.len())
+    };
+    dest.copy_from_slice(data);
+}
+
+/// Reads `out.len()` bytes from the memory of `process` at `address`.
+pub fn read_process_memory(process: &ProcessStruct, address: usize, out: &mut [u8]) {
+    let pmemory = process.pmemory.lock();
+    let mirror = pmemory.mirror_mapping(VirtualAddress(address), out.len())
+        .expect("Failed to mirror process memory");
+    let src = unsafe {
+        // safe: the mirror is kept alive until the end of the function.
+        core::slice::from_raw_parts(mirror.addr().addr() as *const u8, out.len())
+    };
+    out.copy_from_slice(src);
+}
@@ -40,6 +40,20 @@ pub fn set_kernel_timer_info(irq_number: u8, oscillator_frequency: u64, irq_peri
     });
 }
 
+/// Returns whether `irq` is the IRQ used by the kernel timer.
+///
+/// Always returns false if the timer info hasn't been initialized yet.
+pub fn is_timer_irq(irq: u8) -> bool {
+    KERNEL_TIMER_INFO.r#try().map(|info| info.irq_number == irq).unwrap_or(false)
+}
+
+/// Returns the period of the kernel timer IRQ in nanoseconds.
+///
+/// Returns None if the timer info hasn't been initialized yet.
+pub fn irq_period_ns() -> Option<u64> {
+    KERNEL_TIMER_INFO.r#try().map(|info| info.irq_period_ns)
+}
+
 /// Returns a stream of event that trigger every `ns` amount of nanoseconds.
 /// 
 /// # Note
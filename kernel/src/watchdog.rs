@@ -0,0 +1,167 @@
+//! Soft-lockup and hard-lockup detector
+//!
+//! Kernel hangs used to show up as a frozen VM, with nothing on the serial
+//! port. The watchdog turns them into a kernel panic, which displays the held
+//! locks and the state of every thread.
+//!
+//! It works from two periodic interrupts:
+//!
+//! * The kernel timer IRQ counts [ticks], and checks that the cpu didn't stay
+//!   in the kernel for more than [SOFT_LOCKUP_THRESHOLD_MS] without going
+//!   through the scheduler or returning to userspace. This catches threads
+//!   spinning forever with interrupts enabled, e.g. on a [SpinLock] they
+//!   already hold.
+//! * A spare HPET timer is routed to the cpu as an NMI, which is delivered
+//!   even when interrupts are disabled. If the timer IRQ didn't tick for
+//!   [HARD_LOCKUP_THRESHOLD_MS], the cpu is stuck with interrupts disabled,
+//!   usually because a [SpinLockIRQ] is never released.
+//!
+//! Without an HPET, only soft lockups are detected.
+//!
+//! [SpinLock]: crate::sync::SpinLock
+//! [SpinLockIRQ]: crate::sync::SpinLockIRQ
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::devices::hpet;
+use crate::i386::PrivilegeLevel;
+use crate::i386::structures::gdt::SegmentSelector;
+use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
+use crate::panic::{kernel_panic, PanicOrigin};
+use crate::scheduler;
+use crate::sync::lock_tracking::{self, LockKind};
+use crate::sync::spin_lock_irq::are_interrupts_disarmed;
+use crate::timer;
+
+/// Time the cpu may stay in the kernel without scheduling before we declare
+/// a soft lockup.
+pub const SOFT_LOCKUP_THRESHOLD_MS: u64 = 10_000;
+
+/// Time the timer IRQ may stay silent before we declare a hard lockup.
+pub const HARD_LOCKUP_THRESHOLD_MS: u64 = 5_000;
+
+/// Period of the watchdog NMI.
+const NMI_PERIOD_MS: u64 = 1_000;
+
+/// Number of kernel timer IRQs since boot.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Tick of the last time the scheduler ran, or we interrupted userspace.
+static LAST_TOUCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the scheduler ran at least once. Lockups are not detected before
+/// that, as early boot legitimately spends a long time in the kernel.
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the watchdog NMI was set up.
+static NMI_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// [TICKS] as seen by the last watchdog NMI.
+static NMI_LAST_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of consecutive watchdog NMIs that saw no timer tick.
+static NMI_STALLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of kernel timer IRQs since boot.
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts a number of [ticks] to milliseconds.
+///
+/// Returns 0 if the kernel timer isn't initialized yet.
+pub fn ticks_to_ms(ticks: usize) -> u64 {
+    timer::irq_period_ns().map(|period| ticks as u64 * period / 1_000_000).unwrap_or(0)
+}
+
+/// Sets up the watchdog NMI. Must be called after [init_timer].
+///
+/// [init_timer]: crate::devices::init_timer
+pub fn init() {
+    match unsafe { hpet::init_watchdog_timer(NMI_PERIOD_MS * 1_000_000) } {
+        Some(irq) => {
+            crate::i386::interrupt::route_nmi(irq);
+            NMI_ENABLED.store(true, Ordering::SeqCst);
+            info!("Watchdog NMI routed to IOAPIC input {}", irq);
+        }
+        None => info!("No HPET timer for the watchdog NMI, hard lockups won't be detected"),
+    }
+}
+
+/// Signals that the cpu is making progress.
+///
+/// Called every time the scheduler runs.
+pub fn touch() {
+    LAST_TOUCH.store(TICKS.load(Ordering::Relaxed), Ordering::Relaxed);
+    ARMED.store(true, Ordering::Relaxed);
+}
+
+/// Counts a tick, and checks for soft lockups. Called on every tick of the
+/// kernel timer, with the hardware context that was interrupted.
+pub fn timer_tick(hwcontext: &UserspaceHardwareContext) {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+
+    if SegmentSelector(hwcontext.cs as u16).rpl() != PrivilegeLevel::Ring0 {
+        // Userspace can run as long as it wants.
+        LAST_TOUCH.store(now, Ordering::Relaxed);
+        return;
+    }
+
+    if !ARMED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let stuck_ms = ticks_to_ms(now.wrapping_sub(LAST_TOUCH.load(Ordering::Relaxed)));
+    if stuck_ms >= SOFT_LOCKUP_THRESHOLD_MS {
+        kernel_panic(&PanicOrigin::Watchdog {
+            reason: format_args!("Soft lockup: thread {:?} stuck in the kernel for {}ms",
+                scheduler::try_get_current_thread_id(), stuck_ms),
+            hardware_context: hwcontext.clone()
+        });
+    }
+}
+
+/// Checks for hard lockups. Called on every NMI, with the hardware context
+/// that was interrupted.
+///
+/// Returns false if the watchdog NMI is not enabled, meaning this NMI is
+/// unexpected.
+pub fn nmi(hwcontext: &UserspaceHardwareContext) -> bool {
+    if !NMI_ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    if are_interrupts_disarmed() || !ARMED.load(Ordering::Relaxed) {
+        // Either we're panicking, and interrupts are disabled on purpose,
+        // or we're still booting.
+        return true;
+    }
+
+    let now = TICKS.load(Ordering::Relaxed);
+    if NMI_LAST_TICKS.swap(now, Ordering::Relaxed) != now {
+        NMI_STALLED.store(0, Ordering::Relaxed);
+        return true;
+    }
+
+    let stalled_ms = (NMI_STALLED.fetch_add(1, Ordering::Relaxed) as u64 + 1) * NMI_PERIOD_MS;
+    if stalled_ms >= HARD_LOCKUP_THRESHOLD_MS {
+        // Find the most recent SpinLockIRQ taken by the stuck thread, it's
+        // the most likely culprit.
+        let current = scheduler::try_get_current_thread_id();
+        let culprit = lock_tracking::held_locks()
+            .filter(|info| info.kind == LockKind::SpinLockIRQ && info.owner == current)
+            .max_by_key(|info| info.since);
+
+        match culprit {
+            Some(culprit) => kernel_panic(&PanicOrigin::Watchdog {
+                reason: format_args!("Hard lockup: interrupts disabled for at least {}ms, holding {}",
+                    stalled_ms, culprit),
+                hardware_context: hwcontext.clone()
+            }),
+            None => kernel_panic(&PanicOrigin::Watchdog {
+                reason: format_args!("Hard lockup: interrupts disabled for at least {}ms", stalled_ms),
+                hardware_context: hwcontext.clone()
+            }),
+        }
+    }
+    true
+}
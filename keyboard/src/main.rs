@@ -55,6 +55,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
@@ -18,6 +18,7 @@ use sunrise_libuser::io::{Io, Pio};
 use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use spin::RwLock;
 use sunrise_libuser::syscalls;
+use sunrise_libuser::caps;
 use sunrise_libuser::types::ReadableEvent;
 
 use sunrise_libuser::keyboard::HidKeyboardState;
@@ -486,18 +487,22 @@ impl PS2 {
 
 lazy_static! {
     /// Primary PS2 controller instance on a classical IBM/PC architecture.
-    static ref PRIMARY_PS2 : PS2 = PS2 {
-        status_port: Pio::<u8>::new(0x64),
-        data_port: Pio::<u8>::new(0x60),
-        event: syscalls::create_interrupt_event(1, 0).unwrap(),
-        is_capslocked: AtomicBool::new(false),
-        is_left_shift: AtomicBool::new(false),
-        is_right_shift: AtomicBool::new(false),
-        is_left_ctrl: AtomicBool::new(false),
-        is_right_ctrl: AtomicBool::new(false),
-        is_left_alt: AtomicBool::new(false),
-        is_right_alt: AtomicBool::new(false),
-        partial_input: RwLock::new(ArrayVec::new())
+    static ref PRIMARY_PS2 : PS2 = {
+        // Fail loudly rather than with a GPF on the first access.
+        assert!(caps::owns_ioport(0x60) && caps::owns_ioport(0x64), "PS2 ports are not in our capabilities");
+        PS2 {
+            status_port: Pio::<u8>::new(0x64),
+            data_port: Pio::<u8>::new(0x60),
+            event: syscalls::create_interrupt_event(1, 0).unwrap(),
+            is_capslocked: AtomicBool::new(false),
+            is_left_shift: AtomicBool::new(false),
+            is_right_shift: AtomicBool::new(false),
+            is_left_ctrl: AtomicBool::new(false),
+            is_right_ctrl: AtomicBool::new(false),
+            is_left_alt: AtomicBool::new(false),
+            is_right_alt: AtomicBool::new(false),
+            partial_input: RwLock::new(ArrayVec::new())
+        }
     };
 }
 
@@ -33,6 +33,8 @@ use static_assertions::assert_eq_size;
 use core::mem::size_of;
 
 pub mod process;
+pub mod profiler;
+pub mod power;
 
 bitflags! {
     /// Represents the current state of a memory region: why is it allocated, and
@@ -208,8 +210,8 @@ impl MemoryType {
             MemoryType::CodeMutable            => MemoryState::from_bits_truncate(0x03FEBD04),
             // PERM_CHANGE | IPC_SEND0 | IPC_SEND1 | IPC_SEND3 | MAP | TRANSFER | QUERY_PHYSICAL | MAP_DEVICE | MAP_DEVICE_ALIGNED | IPC_CMD | REFCNT | ATTR_CHANGE | CODE_MEM
             MemoryType::Heap                   => MemoryState::from_bits_truncate(0x037EBD05),
-            // REFCNT
-            MemoryType::SharedMemory           => MemoryState::from_bits_truncate(0x00402006),
+            // MAP_DEVICE | REFCNT. Unlike Horizon, we let drivers DMA to the buffers their clients share with them.
+            MemoryType::SharedMemory           => MemoryState::from_bits_truncate(0x00482006),
             // PERM_CHANGE | IPC_SEND1 | MAP_DEVICE | REFCNT
             MemoryType::Alias                  => MemoryState::from_bits_truncate(0x00482907),
             // DEBUG | IPC_SEND0 | IPC_SEND1 | IPC_SEND3 | PROCESS_PERM_CHANGE | UNMAP_PROCESS | QUERY_PHYSICAL | MAP_DEVICE | MAP_DEVICE_ALIGNED | REFCNT | MAP_PROCESS
@@ -276,6 +278,10 @@ bitflags! {
         const RW = MemoryPermissions::READABLE.bits() | MemoryPermissions::WRITABLE.bits();
         /// The area is RX.
         const RX = MemoryPermissions::READABLE.bits() | MemoryPermissions::EXECUTABLE.bits();
+
+        /// Only valid as the remote permissions of a shared memory: lets the
+        /// other processes choose the permissions they map it with.
+        const DONT_CARE = 1 << 28;
     }
 }
 
@@ -286,7 +292,7 @@ impl MemoryPermissions {
         // 0x2B is a bitmask where each bit represents an allowed
         // MemoryPermission state: NONE, R, RW, RX.
         // The permission is turned into an index into that bitmask.
-        if 1 << self.bits() & 0x2B != 0 {
+        if self.bits() < 8 && 1 << self.bits() & 0x2B != 0 {
             Ok(())
         } else {
             Err(error::KernelError::InvalidMemPerms)
@@ -296,7 +302,7 @@ impl MemoryPermissions {
 
 /// The structure returned by the `query_memory` syscall.
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct MemoryInfo {
     /// The base address of this memory region.
     ///
@@ -326,6 +332,9 @@ pub struct MemoryInfo {
 /// Found in the [TLS] of every thread.
 pub type IpcBuffer = [u8; 0x100];
 
+/// Offset of [TLS::exit_code] in the [TLS].
+pub const TLS_EXIT_CODE_OFFSET: usize = 0x1F0;
+
 /// Thread Local Storage region.
 ///
 /// The kernel allocates one for every thread, and makes a register point (indirectly) to it
@@ -347,7 +356,12 @@ pub struct TLS {
     /// Buffer used for IPC. Kernel reads, interprets, and copies data from/to it.
     pub ipc_command_buffer: IpcBuffer,
     /// reserved or unknown.
-    _reserved1: [u8; 0x200 - 16 - size_of::<IpcBuffer>() - size_of::<usize>()],
+    _reserved1: [u8; TLS_EXIT_CODE_OFFSET - 16 - size_of::<IpcBuffer>()],
+    /// Sunrise extension: exit code of the process, read by the kernel when this thread calls
+    /// `ExitProcess`. 0 unless set by the userspace.
+    pub exit_code: u32,
+    /// reserved or unknown.
+    _reserved2: [u8; 0x200 - TLS_EXIT_CODE_OFFSET - size_of::<u32>() - size_of::<usize>()],
     /// User controlled pointer to thread context. Not observed by the kernel.
     pub ptr_thread_context: usize,
 }
@@ -529,8 +543,17 @@ syscalls! {
     StartProcessEntrypoint = 0x81,
     MapMmioRegion = 0x82,
     SetThreadArea = 0x83,
+    SetLogFilter = 0x84,
+    ControlProfiler = 0x85,
+    GetProcessName = 0x86,
+    ReadCoreDump = 0x87,
+    ReadKernelPanicReport = 0x88,
+    ExitProcessWithCode = 0x89,
+    MapStackMirror = 0x8A,
+    UnmapStackMirror = 0x8B,
+    CreateContiguousSharedMemory = 0x8C,
 
     ---
     // Add SVCs before this line.
-    MaxSvc = 0x83
+    MaxSvc = 0x8C
 }
\ No newline at end of file
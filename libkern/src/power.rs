@@ -0,0 +1,12 @@
+//! Data-structures related to power management.
+
+enum_with_val! {
+    /// Operation to perform with `sleep_system`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct SleepSystemMode(pub u32) {
+        /// Powers the computer off.
+        Shutdown = 0,
+        /// Reboots the computer.
+        Reboot = 1,
+    }
+}
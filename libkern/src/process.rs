@@ -73,6 +73,7 @@ impl From<ProcInfoAddrSpace> for u32 {
 
 bitfield! {
     /// Miscelaneous flags.
+    #[derive(Clone, Copy)]
     pub struct ProcInfoFlags(u32);
     impl Debug;
 
@@ -123,7 +124,7 @@ enum_with_val! {
 
 /// Informations necessary for the create_process syscall.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ProcInfo {
     /// Name of the process (as seen by debuggers).
     pub name: [u8; 12],
@@ -219,5 +220,44 @@ enum_with_val! {
     pub struct ProcessInfoType(pub u32) {
         /// Get the state the process is currently in.
         ProcessState = 0,
+        /// Sunrise extension: Get the exit code of the process. The process
+        /// must be Exited.
+        ExitCode = 0x5300_0000,
+    }
+}
+
+enum_with_val! {
+    /// Kind of information to extract with `get_info`.
+    ///
+    /// All the tick counts are expressed in the unit returned by
+    /// `get_system_tick`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct InfoType(pub u32) {
+        /// Number of ticks the given thread spent running, in both userspace
+        /// and kernel. The handle must be a thread, and the sub_id must be
+        /// either 0 (the only core) or `u64::max_value()` (all cores).
+        ThreadTickCount = 0xF000_0002,
+        /// Sunrise extension: Number of ticks the given thread spent running
+        /// userspace code. The handle must be a thread, and the sub_id 0.
+        ThreadUserTickCount = 0x5300_0000,
+        /// Sunrise extension: Number of ticks the given thread spent running
+        /// kernel code. The handle must be a thread, and the sub_id 0.
+        ThreadKernelTickCount = 0x5300_0001,
+        /// Sunrise extension: Number of ticks the threads of a process spent
+        /// running userspace code. The handle must be 0, and the sub_id is the
+        /// pid of the process.
+        ProcessUserTickCount = 0x5300_0002,
+        /// Sunrise extension: Number of ticks the threads of a process spent
+        /// running kernel code. The handle must be 0, and the sub_id is the pid
+        /// of the process.
+        ProcessKernelTickCount = 0x5300_0003,
+        /// Sunrise extension: Size of the memory mapped in a process' address
+        /// space, in bytes. The handle must be 0, and the sub_id is the pid of
+        /// the process.
+        ProcessUsedMemorySize = 0x5300_0004,
+        /// Sunrise extension: Address the main executable of a process was
+        /// loaded at. The handle must be 0, and the sub_id is the pid of the
+        /// process.
+        ProcessImageBase = 0x5300_0005,
     }
-}
\ No newline at end of file
+}
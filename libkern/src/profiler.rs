@@ -0,0 +1,28 @@
+//! Data-structures related to the sampling profiler.
+
+enum_with_val! {
+    /// Operation to perform with `control_profiler`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ProfilerCommand(pub u32) {
+        /// Discards any previously recorded sample, and starts recording.
+        Start = 0,
+        /// Stops recording. Samples recorded so far are kept until drained.
+        Stop = 1,
+        /// Moves the recorded samples to the user-provided buffer.
+        Drain = 2,
+    }
+}
+
+/// A sample recorded by the profiler on a timer interrupt.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProfilerSample {
+    /// Pid of the process that was interrupted.
+    pub pid: u32,
+    /// Id of the thread that was interrupted.
+    pub tid: u32,
+    /// Instruction pointer at the time of the interrupt.
+    pub eip: u32,
+    /// 1 if the thread was running kernel code, 0 if it was running userspace.
+    pub in_kernel: u32,
+}
@@ -106,22 +106,29 @@ macro_rules! capabilities {
     (@handle_item $ident:ident, curcount=$count:expr, svcs=[$($svcs:expr),*], rawcaps=[$($raw_caps:expr),*],) => {
         #[cfg_attr(not(test), link_section = ".kernel_caps")]
         #[used]
-        static $ident: [u32; $count] = {
-            let mut kacs = [
-                // first 6 are SVCs
-                0 << 29 | 0b1111,
-                1 << 29 | 0b1111,
-                2 << 29 | 0b1111,
-                3 << 29 | 0b1111,
-                4 << 29 | 0b1111,
-                5 << 29 | 0b1111,
-                $($raw_caps,)*
-            ];
-            capabilities!(@generate_svc kacs, [$($svcs),*]);
-
-            kacs
-        };
+        static $ident: [u32; $count] = capabilities!(@generate_kacs svcs=[$($svcs),*], rawcaps=[$($raw_caps),*]);
+
+        // Loaded copy of the capabilities, so the process can look them up.
+        // See sunrise_libuser::caps::ioports.
+        #[cfg_attr(not(test), link_section = ".rodata.kernel_caps_copy")]
+        #[used]
+        static __KERNEL_CAPS_COPY: [u32; $count] = capabilities!(@generate_kacs svcs=[$($svcs),*], rawcaps=[$($raw_caps),*]);
     };
+    (@generate_kacs svcs=[$($svcs:expr),*], rawcaps=[$($raw_caps:expr),*]) => {{
+        let mut kacs = [
+            // first 6 are SVCs
+            0 << 29 | 0b1111,
+            1 << 29 | 0b1111,
+            2 << 29 | 0b1111,
+            3 << 29 | 0b1111,
+            4 << 29 | 0b1111,
+            5 << 29 | 0b1111,
+            $($raw_caps,)*
+        ];
+        capabilities!(@generate_svc kacs, [$($svcs),*]);
+
+        kacs
+    }};
     (@generate_svc $kac_svcs:ident, [$($svc:expr),*]) => {
         $($kac_svcs[$svc / 24] |= 1 << (($svc % 24) + 5);)*
     };
@@ -199,6 +206,53 @@ pub const fn ioport(ioport: u16) -> u32 {
    0b1111111111 | ((ioport as u32) << 11)
 }
 
+/// Kernel capability type of [ioport] capabilities: the number of trailing ones.
+const IO_PORTS_ALLOWED: u32 = 10;
+
+/// Allows the process to attach the PCI devices of the given class and
+/// subclass to a DeviceAddressSpace.
+#[allow(clippy::cast_lossless)] // Can't use From::from in const fn
+pub const fn pci_class(class: u8, subclass: u8) -> u32 {
+    0b111111111 | ((class as u32) << 16) | ((subclass as u32) << 24)
+}
+
+/// Gets the kernel capabilities this process was declared with, from the copy
+/// [capabilities!] puts in its read-only data.
+#[cfg(any(target_os = "sunrise", doc))]
+fn kernel_caps() -> &'static [u32] {
+    extern {
+        static __kernel_caps_start__: u32;
+        static __kernel_caps_end__: u32;
+    }
+    unsafe {
+        // Safety: the linker script puts those symbols around the copy of the
+        // capabilities, which lives in the read-only data for the whole
+        // program. It may be empty if this program has no capabilities!.
+        let start = &__kernel_caps_start__ as *const u32;
+        let end = &__kernel_caps_end__ as *const u32;
+        core::slice::from_raw_parts(start, (end as usize - start as usize) / core::mem::size_of::<u32>())
+    }
+}
+
+/// Gets the IO ports this process is allowed to access, as declared in its
+/// kernel capabilities.
+///
+/// This doesn't ask the kernel: drivers can check which ports they own as
+/// often as they want without paying for a syscall.
+#[cfg(any(target_os = "sunrise", doc))]
+pub fn ioports() -> impl Iterator<Item = u16> {
+    kernel_caps().iter()
+        .filter(|kac| (!**kac).trailing_zeros() == IO_PORTS_ALLOWED)
+        .map(|kac| (kac >> 11) as u16)
+}
+
+/// Checks whether this process is allowed to access the IO port `port`. See
+/// [ioports].
+#[cfg(any(target_os = "sunrise", doc))]
+pub fn owns_ioport(port: u16) -> bool {
+    ioports().any(|ioport| ioport == port)
+}
+
 /// Allows the process to create an IRQEvent for those IRQs. Each IRQ should be
 /// under or equal to 0xFF, or equal to 0x3FF, in which case the IRQ will be
 /// ignored.
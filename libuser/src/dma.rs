@@ -0,0 +1,247 @@
+//! DMA through a device address space
+//!
+//! The DMAs of a PCI device are translated by the IOMMU, it can only access the
+//! memory we mapped in its [DeviceAddressSpace]. We map our memory there at the
+//! same address it has in our address space, so the address we give to the
+//! device is simply the virtual address of the structure.
+//!
+//! Several structures can share a page, for instance a command list and a
+//! received FIS allocated next to each other on the heap. Pages are reference
+//! counted, and only unmapped when the last structure in them is unmapped.
+//!
+//! Without an IOMMU, or for devices that bypass it, the device accesses
+//! physical memory directly: see [Dma]. We give it the physical addresses of
+//! our buffers, found with [query_physical_address](syscalls::query_physical_address),
+//! and a buffer that is contiguous in our address space might be scattered in
+//! several physical segments.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::min;
+use crate::types::DeviceAddressSpace;
+use crate::error::{Error, KernelError, AhciError};
+use crate::mem::PAGE_SIZE;
+use crate::syscalls::{self, MemoryPermissions};
+use sunrise_libutils::{align_down, align_up};
+
+/// First address of the device address space, the start of UserLand.
+const DEVICE_ADDRESS_SPACE_START: usize = 0x0020_0000;
+/// Size of the device address space, up to the end of UserLand.
+const DEVICE_ADDRESS_SPACE_SIZE: usize = 0xC000_0000 - DEVICE_ADDRESS_SPACE_START;
+
+/// The device address space of a PCI device, and the pages we mapped there.
+#[derive(Debug)]
+pub struct DmaSpace {
+    /// The device address space the device is attached to.
+    das: DeviceAddressSpace,
+    /// The number of structures using each mapped page, and the permissions it
+    /// is mapped with, by address.
+    pages: BTreeMap<usize, (usize, MemoryPermissions)>,
+}
+
+impl DmaSpace {
+    /// Creates a device address space, and attaches the device with the given
+    /// requester id to it.
+    ///
+    /// From then on, the device can only DMA to the memory mapped with [map](DmaSpace::map).
+    ///
+    /// The process needs a [pci_class](crate::caps::pci_class) capability for
+    /// the class of the device.
+    pub fn new(device: u16) -> Result<DmaSpace, Error> {
+        let das = DeviceAddressSpace::new(DEVICE_ADDRESS_SPACE_START, DEVICE_ADDRESS_SPACE_SIZE)?;
+        das.attach(device)?;
+        Ok(DmaSpace { das, pages: BTreeMap::new() })
+    }
+
+    /// Makes `length` bytes at `buffer` accessible to the device with `perm`,
+    /// and returns the address the device must use to access them.
+    ///
+    /// A page already mapped by another structure is remapped with the union
+    /// of its permissions and `perm` if they don't cover `perm`. The device
+    /// cannot access the page while it is remapped.
+    ///
+    /// # Error
+    ///
+    /// * `buffer` is not memory that can be mapped in a device address space.
+    pub fn map<T: ?Sized>(&mut self, buffer: *const T, length: usize, perm: MemoryPermissions) -> Result<u64, Error> {
+        let address = buffer as *const u8 as usize;
+        let start = align_down(address, PAGE_SIZE);
+        let end = align_up(address + length, PAGE_SIZE);
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let mapped = match self.pages.get(&page) {
+                None => self.das.map(page, PAGE_SIZE, page, perm).map(|()| perm),
+                Some(&(_, mapped_perm)) if mapped_perm.contains(perm) => Ok(mapped_perm),
+                Some(&(_, mapped_perm)) => self.remap_page(page, mapped_perm, mapped_perm | perm),
+            };
+            match mapped {
+                Ok(mapped_perm) => {
+                    let (count, page_perm) = self.pages.entry(page).or_insert((0, mapped_perm));
+                    *count += 1;
+                    *page_perm = mapped_perm;
+                },
+                Err(err) => {
+                    self.unmap_pages(start, page);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(address as u64)
+    }
+
+    /// Remaps `page`, mapped with `old_perm`, with `new_perm`.
+    ///
+    /// # Error
+    ///
+    /// * Remapping the page failed. It is mapped back with `old_perm`.
+    fn remap_page(&mut self, page: usize, old_perm: MemoryPermissions, new_perm: MemoryPermissions) -> Result<MemoryPermissions, Error> {
+        self.das.unmap(page, PAGE_SIZE, page)?;
+        match self.das.map(page, PAGE_SIZE, page, new_perm) {
+            Ok(()) => Ok(new_perm),
+            Err(err) => {
+                // the other structures in this page must stay accessible.
+                if let Err(err) = self.das.map(page, PAGE_SIZE, page, old_perm) {
+                    error!("Failed to map page {:#010x} back in the device address space: {:?}", page, err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Revokes the device's access to `length` bytes at `buffer`, previously
+    /// mapped with [map](DmaSpace::map).
+    ///
+    /// The device must not be using them anymore.
+    pub fn unmap<T: ?Sized>(&mut self, buffer: *const T, length: usize) {
+        let address = buffer as *const u8 as usize;
+        self.unmap_pages(align_down(address, PAGE_SIZE), align_up(address + length, PAGE_SIZE));
+    }
+
+    /// Releases the pages in `[start; end)`, unmapping those no structure
+    /// uses anymore.
+    fn unmap_pages(&mut self, start: usize, end: usize) {
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let (count, _) = self.pages.get_mut(&page)
+                .expect("Unmapping a page that was never mapped for DMA");
+            *count -= 1;
+            if *count == 0 {
+                self.pages.remove(&page);
+                if let Err(err) = self.das.unmap(page, PAGE_SIZE, page) {
+                    error!("Failed to unmap page {:#010x} from the device address space: {:?}", page, err);
+                }
+            }
+        }
+    }
+}
+
+/// How a device accesses our memory.
+#[derive(Debug)]
+pub enum Dma {
+    /// The device is attached to this device address space.
+    Iommu(DmaSpace),
+    /// The device accesses physical memory.
+    Physical,
+}
+
+impl Dma {
+    /// Attaches the device with the given requester id to a new [DmaSpace].
+    ///
+    /// If the system has no IOMMU, the device keeps accessing physical memory.
+    ///
+    /// # Error
+    ///
+    /// * Creating the device address space, or attaching the device to it, failed.
+    pub fn new(device: u16) -> Result<Dma, Error> {
+        match DmaSpace::new(device) {
+            Ok(space) => Ok(Dma::Iommu(space)),
+            Err(Error::Kernel(KernelError::NotImplemented, _)) => {
+                warn!("No IOMMU, device {:#06x} will access physical memory.", device);
+                Ok(Dma::Physical)
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Makes `length` bytes at `buffer` accessible to the device with `perm`.
+    ///
+    /// Physical memory is always accessible, this only does something with an IOMMU.
+    ///
+    /// # Error
+    ///
+    /// * `buffer` is not memory that can be mapped in a device address space.
+    pub fn map<T: ?Sized>(&mut self, buffer: *const T, length: usize, perm: MemoryPermissions) -> Result<(), Error> {
+        match self {
+            Dma::Iommu(space) => space.map(buffer, length, perm).map(|_| ()),
+            Dma::Physical => Ok(()),
+        }
+    }
+
+    /// Revokes the device's access to `length` bytes at `buffer`, previously made accessible with
+    /// [map](Dma::map).
+    pub fn unmap<T: ?Sized>(&mut self, buffer: *const T, length: usize) {
+        if let Dma::Iommu(space) = self {
+            space.unmap(buffer, length)
+        }
+    }
+
+    /// Returns the address the device must use to access the byte at `address`, which must
+    /// have been [mapped](Dma::map).
+    ///
+    /// # Error
+    ///
+    /// * `address` does not map physical memory.
+    pub fn address(&self, address: usize) -> Result<u64, Error> {
+        match self {
+            Dma::Iommu(_) => Ok(address as u64),
+            Dma::Physical => {
+                let (physical_start, virtual_start, _) = syscalls::query_physical_address(address)?;
+                Ok((physical_start + (address - virtual_start)) as u64)
+            }
+        }
+    }
+
+    /// Returns the segments of `length` bytes at `buffer`, as the device sees them: their address
+    /// for the device, and their length.
+    ///
+    /// With an IOMMU, this is a single segment. The buffer must have been [mapped](Dma::map).
+    ///
+    /// # Error
+    ///
+    /// * `buffer` does not map physical memory.
+    pub fn segments(&self, buffer: usize, length: usize) -> Result<Vec<(u64, u32)>, Error> {
+        let mut segments: Vec<(u64, u32)> = Vec::new();
+        if let Dma::Iommu(_) = self {
+            segments.push((buffer as u64, length as u32));
+            return Ok(segments);
+        }
+        let mut address = buffer;
+        let end = buffer + length;
+        while address < end {
+            let (physical_start, virtual_start, region_length) = syscalls::query_physical_address(address)?;
+            let physical_address = physical_start + (address - virtual_start);
+            let segment_length = min(virtual_start + region_length, end) - address;
+            // merge with the previous segment if the physical memory follows it.
+            match segments.last_mut() {
+                Some((last_address, last_length)) if *last_address + u64::from(*last_length) == physical_address as u64 => {
+                    *last_length += segment_length as u32
+                },
+                _ => segments.push((physical_address as u64, segment_length as u32)),
+            }
+            address += segment_length;
+        }
+        Ok(segments)
+    }
+
+    /// Returns the device address of `length` bytes at `buffer`, which must be contiguous for the
+    /// device.
+    ///
+    /// # Error
+    ///
+    /// * BufferTooScattered: the buffer is not physically contiguous.
+    /// * `buffer` does not map physical memory.
+    pub fn contiguous(&self, buffer: usize, length: usize) -> Result<u64, Error> {
+        match &self.segments(buffer, length)?[..] {
+            [(address, _)] => Ok(*address),
+            _ => Err(AhciError::BufferTooScattered.into()),
+        }
+    }
+}
@@ -279,16 +279,18 @@ impl From<SmError> for Error {
 }
 
 enum_with_val! {
-    /// AHCI driver errors.
+    /// AHCI driver errors, also returned by the other disk drivers serving an IDisk.
     #[derive(PartialEq, Eq, Clone, Copy)]
     pub struct AhciError(u32) {
         /// Passed argument were found to be illegal.
         InvalidArg = 1,
-        /// Passed buffer for DMA is too physically scattered. This can only happen for read/writes
-        /// of 1985 sectors or more.
+        /// Passed buffer for DMA is too physically scattered. For AHCI, this can only happen for
+        /// read/writes of 1985 sectors or more.
         BufferTooScattered = 2,
         /// The hardware reported an error.
         IoError = 3,
+        /// Attempted to write to a read-only device, like a CD-ROM drive.
+        ReadOnly = 4,
     }
 }
 
@@ -60,6 +60,8 @@ pub mod futures;
 //pub mod ldr {}
 //#[gen_ipc(path = "../../ipcdefs/twili.id", prefix = "sunrise_libuser")]
 //pub mod twili {}
+//#[gen_ipc(path = "../../ipcdefs/serial.id", prefix = "sunrise_libuser")]
+//pub mod serial {}
 //#[gen_ipc(path = "../../ipcdefs/example.id", prefix = "sunrise_libuser")]
 //pub mod example {}
 include!(concat!(env!("OUT_DIR"), "/ipc_code.rs"));
@@ -70,6 +72,8 @@ pub mod terminal;
 pub mod ps2;
 pub mod window;
 pub mod zero_box;
+pub mod pci;
+pub mod dma;
 
 #[cfg(all(target_os = "sunrise", not(feature = "build-for-std-app")))]
 mod crt0;
@@ -100,13 +104,16 @@ pub static ALLOCATOR: allocator::Allocator = allocator::Allocator::new();
 #[cfg(all(target_os = "sunrise", not(test), feature = "lang-items", not(doc)))]
 #[lang = "eh_personality"] #[no_mangle] pub extern fn eh_personality() {}
 
+/// Exit code of a process that panicked, the same as Rust's std.
+pub const PANIC_EXIT_CODE: u32 = 101;
+
 /// Function called on `panic!` invocation. Prints the panic information to the
-/// kernel debug logger, and exits the process.
+/// kernel debug logger, and exits the process with [PANIC_EXIT_CODE].
 #[cfg(all(target_os = "sunrise", not(test), feature = "lang-items", not(doc)))]
 #[panic_handler] #[no_mangle]
 pub extern fn panic_fmt(p: &core::panic::PanicInfo<'_>) -> ! {
     let _ = syscalls::output_debug_string(&format!("{}", p), 10, "sunrise_libuser::panic_fmt");
-    syscalls::exit_process();
+    syscalls::exit_process_with_code(PANIC_EXIT_CODE);
 }
 
 // TODO: Don't panic in the oom handler, exit instead.
@@ -121,7 +128,7 @@ pub fn rust_oom(_: core::alloc::Layout) -> ! {
 }
 
 /// calls logger initialization, main, and finally exits the
-/// process.
+/// process with the code returned by main.
 ///
 /// # Safety
 ///
@@ -135,8 +142,8 @@ pub unsafe extern fn real_start() -> ! {
 
     log_impl::init();
     let (argc, argv) = (argv::argc(), argv::argv());
-    let _ret = main(argc, argv);
-    syscalls::exit_process();
+    let ret = main(argc, argv);
+    syscalls::exit_process_with_code(ret as u32);
 }
 
 /// A trait for implementing arbitrary return types in the `main` function.
@@ -1,8 +1,12 @@
 //! PCI discovery
 //!
-//! A minimal PCI implementation, that permits only discovering AHCI devices, and querying their BAR.
+//! A minimal PCI implementation, that permits only discovering devices, querying their BARs and
+//! capabilities, and letting them DMA and raise interrupts.
+//!
+//! Drivers using it must declare the [CONFIG_ADDRESS] and [CONFIG_DATA] ports in their
+//! capabilities.
 
-use sunrise_libutils::io::{Io, Pio};
+use crate::io::{Io, Pio};
 use spin::Mutex;
 use alloc::vec::Vec;
 
@@ -48,63 +52,72 @@ const MAX_FUNC: u8 = 15;
 /// The highest addressable register on a function on a slot on a bus.
 const MAX_REGISTER: u8 = 63;
 
+/// `Capabilities List` bit of the status register.
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+/// The maximum number of capabilities we follow, in case the list loops.
+const MAX_CAPABILITIES: usize = 48;
+
 /// A pci device, addressed by its bus number, slot, and function.
 #[derive(Debug, Copy, Clone)]
-#[allow(clippy::missing_docs_in_private_items)]
-struct PciDevice {
+pub struct PciDevice {
     /// The device's bus number.
-    bus: u8,
+    pub bus: u8,
     /// The device's slot number on its bus.
-    slot: u8,
+    pub slot: u8,
     /// The device's function number.
-    function: u8,
+    pub function: u8,
 
     /* [register 0x00] */
     /// Device id.
-    did: u16,
+    pub did: u16,
     /// Vendor id.
-    vid: u16,
+    pub vid: u16,
     /* [register 0x01] */
     /* status + command are volatile */
     /* [register 0x02] */
-    class: u8,
-    subclass: u8,
-    prog_if: u8,
-    rev_id: u8,
+    /// Class code.
+    pub class: u8,
+    /// Subclass.
+    pub subclass: u8,
+    /// Programming interface.
+    pub prog_if: u8,
+    /// Revision id.
+    pub rev_id: u8,
     /* [register 0x03] */
     /* bist is volatile */
-    header_type: u8,
-    latency_timer: u8,
-    cache_line_size: u8,
+    /// Header type. Bit 7 is set for multi-function devices.
+    pub header_type: u8,
+    /// Latency timer.
+    pub latency_timer: u8,
+    /// Cache line size.
+    pub cache_line_size: u8,
 
     /// Remaining registers values, based on header type.
-    header: PciHeader
+    pub header: PciHeader
 }
 
 /// Pci header when Header Type == 0x00 (General device).
 #[derive(Copy, Clone, Debug)]
 #[allow(clippy::missing_docs_in_private_items)]
-struct PciHeader00 {
-    bar0: BAR,
-    bar1: BAR,
-    bar2: BAR,
-    bar3: BAR,
-    bar4: BAR,
-    bar5: BAR,
-    cardbus_cis_ptr: u32,
-    subsystem_id: u16,
-    subsystem_vendor_id: u16,
-    expansion_rom_base_address: u32,
-    capabilities_ptr: u8,
-    max_latency: u8,
-    min_grant: u8,
-    interrupt_pin: u8,
-    interrupt_line: u8,
+#[allow(missing_docs)]
+pub struct PciHeader00 {
+    /// The six Base Address Registers. The upper half of a 64-bit BAR is decoded as
+    /// [BAR::Upper].
+    pub bars: [BAR; 6],
+    pub cardbus_cis_ptr: u32,
+    pub subsystem_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub expansion_rom_base_address: u32,
+    pub capabilities_ptr: u8,
+    pub max_latency: u8,
+    pub min_grant: u8,
+    pub interrupt_pin: u8,
+    pub interrupt_line: u8,
 }
 
 /// Contents of pci config registers 0x4-0xf, structure varies based on Header Type.
 #[derive(Copy, Clone, Debug)]
-enum PciHeader {
+pub enum PciHeader {
     /// header type == 0x00
     GeneralDevice(PciHeader00),
     /// header type == 0x01, not implemented
@@ -115,16 +128,34 @@ enum PciHeader {
     UnknownHeaderType(u8)
 }
 
-/// Base Address Registers. Minimal implementation, does not support 64-bits BARs.
+/// Base Address Registers.
 #[derive(Copy, Clone, Debug)]
-enum BAR {
+pub enum BAR {
     /// a memory space address and its size
     Memory(u32, u32),
-    /// an IO space address
-    Io(u32, u32)
+    /// a 64-bit memory space address and its size, spanning this BAR and the next one
+    Memory64(u64, u64),
+    /// an IO space address and its size
+    Io(u32, u32),
+    /// the upper half of the previous 64-bit BAR
+    Upper
+}
+
+/// A capability found in the capabilities list of a device.
+#[derive(Copy, Clone, Debug)]
+pub struct Capability {
+    /// The capability id, like `0x09` for vendor specific capabilities.
+    pub id: u8,
+    /// The offset of the capability in the configuration space, in octets. Always dword aligned.
+    pub offset: u8,
 }
 
 impl PciDevice {
+    /// The id the device tags its DMAs with, used by the IOMMU to identify it.
+    pub fn requester_id(&self) -> u16 {
+        u16::from(self.bus) << 8 | u16::from(self.slot) << 3 | u16::from(self.function)
+    }
+
     /// Checks if a device exists on given bus>slot>function.
     ///
     /// This is done by reading the Device ID - Vendor ID register (register 0).
@@ -162,12 +193,7 @@ impl PciDevice {
             let header_type = (pci_config_read_word(bus, slot, function, 3) >> 16) as u8;
             return match header_type & 0x7f {
                 0x00 => PciHeader::GeneralDevice(PciHeader00 {
-                    bar0: decode_bar(bus, slot, function, 4),
-                    bar1: decode_bar(bus, slot, function, 5),
-                    bar2: decode_bar(bus, slot, function, 6),
-                    bar3: decode_bar(bus, slot, function, 7),
-                    bar4: decode_bar(bus, slot, function, 8),
-                    bar5: decode_bar(bus, slot, function, 9),
+                    bars: decode_bars(bus, slot, function),
                     cardbus_cis_ptr: pci_config_read_word(bus, slot, function, 0xa),
                     subsystem_id:        (pci_config_read_word(bus, slot, function, 0xb) >> 16) as u16,
                     subsystem_vendor_id:  pci_config_read_word(bus, slot, function, 0xb) as u16,
@@ -183,9 +209,34 @@ impl PciDevice {
                 other => PciHeader::UnknownHeaderType(other)
             };
 
-            /// Decode an u32 to BAR.
-            /// 64-bit BARs are not supported.
-            fn decode_bar(bus: u8, slot: u8, function: u8, register: u8) -> BAR {
+            /// Decodes the six BARs of a general device, found in registers 4 to 9.
+            fn decode_bars(bus: u8, slot: u8, function: u8) -> [BAR; 6] {
+                let mut bars = [BAR::Upper; 6];
+                let mut index = 0;
+                while index < bars.len() {
+                    let register = 4 + index as u8;
+                    let (addr, length) = probe_bar(bus, slot, function, register);
+                    bars[index] = match (addr & 0x01, (addr >> 1) & 0b11) {
+                        (0, 0b10) if index + 1 < bars.len() => {
+                            // 64-bit memory space bar, the next register holds the upper half.
+                            let (addr_high, length_high) = probe_bar(bus, slot, function, register + 1);
+                            let addr = u64::from(addr_high) << 32 | u64::from(addr & 0xFFFF_FFF0);
+                            let length = u64::from(length_high) << 32 | u64::from(length & 0xFFFF_FFF0);
+                            index += 1;
+                            BAR::Memory64(addr, (!length).wrapping_add(1))
+                        },
+                        // memory space bar
+                        (0, _) => BAR::Memory(addr & 0xFFFF_FFF0, (!(length & 0xFFFF_FFF0)).wrapping_add(1)),
+                        // io space bar
+                        _ => BAR::Io(addr & 0xFFFF_FFFC, (!(length & 0xFFFF_FFFC)).wrapping_add(1))
+                    };
+                    index += 1;
+                }
+                bars
+            }
+
+            /// Reads a BAR register, and the mask of its size.
+            fn probe_bar(bus: u8, slot: u8, function: u8, register: u8) -> (u32, u32) {
                 // read bar address
                 let addr = pci_config_read_word(bus, slot, function, register);
                 // write to get length
@@ -194,41 +245,82 @@ impl PciDevice {
                 let length = pci_config_read_word(bus, slot, function, register);
                 // restore original value
                 pci_config_write_word(bus, slot, function, register, addr);
-                match addr & 0x01 {
-                    0 => {
-                        // memory space bar
-                        BAR::Memory(addr & 0xFFFF_FFF0, (!(length & 0xFFFF_FFF0)).wrapping_add(1))
-                    },
-                    _ => {
-                        // io space bar
-                        BAR::Io(addr & 0xFFFF_FFFC, (!(length & 0xFFFF_FFFC)).wrapping_add(1))
-                    }
-                }
+                (addr, length)
             }
         }
     }
 
     /// Reads a configuration space register.
-    fn read_config_register(&self, register: u8) -> u32 {
+    pub fn read_config_register(&self, register: u8) -> u32 {
         pci_config_read_word(self.bus, self.slot, self.function, register)
     }
 
     /// Writes to a configuration space register.
-    fn write_config_register(&self, register: u8, value: u32) {
+    pub fn write_config_register(&self, register: u8, value: u32) {
         pci_config_write_word(self.bus, self.slot, self.function, register, value)
     }
 
     // register 1
 
     /// Reads the status register.
-    fn status(&self) -> u16 {
+    pub fn status(&self) -> u16 {
         (self.read_config_register(1) >> 16) as u16
     }
 
     /// Reads the command register.
-    fn command(&self) -> u16 {
+    pub fn command(&self) -> u16 {
         (self.read_config_register(1) >> 0) as u16
     }
+
+    /// Lets the device assert its interrupt line, by clearing the `Interrupt Disable` bit
+    /// of the command register.
+    pub fn enable_interrupts(&self) {
+        // writing 0 to the status register has no effect.
+        self.write_config_register(1, u32::from(self.command() & !(1 << 10)))
+    }
+
+    /// Lets the device respond to accesses to its BARs, and DMA, by setting the
+    /// `I/O Space`, `Memory Space` and `Bus Master` bits of the command register.
+    pub fn enable_bus_master(&self) {
+        // writing 0 to the status register has no effect.
+        self.write_config_register(1, u32::from(self.command() | 0b111))
+    }
+
+    /// Gets the BAR at `index`, if this is a general device.
+    pub fn bar(&self, index: usize) -> Option<BAR> {
+        match self.header {
+            PciHeader::GeneralDevice(header00) => header00.bars.get(index).cloned(),
+            _ => None
+        }
+    }
+
+    /// Gets the interrupt line set-up during POST, if this is a general device.
+    pub fn interrupt_line(&self) -> Option<u8> {
+        match self.header {
+            PciHeader::GeneralDevice(header00) => Some(header00.interrupt_line),
+            _ => None
+        }
+    }
+
+    /// Walks the capabilities list of the device.
+    ///
+    /// The list is empty if the device doesn't have one, or is not a general device.
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> + '_ {
+        let mut next = match self.header {
+            PciHeader::GeneralDevice(header00) if self.status() & STATUS_CAPABILITIES_LIST != 0 => header00.capabilities_ptr & 0xFC,
+            _ => 0
+        };
+        core::iter::from_fn(move || {
+            if next == 0 {
+                return None;
+            }
+            let offset = next;
+            let header = self.read_config_register(offset / 4);
+            // the two bottom bits are reserved.
+            next = (header >> 8) as u8 & 0xFC;
+            Some(Capability { id: header as u8, offset })
+        }).take(MAX_CAPABILITIES)
+    }
 }
 
 /// Read one of the 64 32-bit registers of a pci bus>device>func.
@@ -284,7 +376,7 @@ fn pci_config_write_word(bus: u8, slot: u8, func: u8, register: u8, value: u32)
 /// A device is discovered when its `(bus, slot, function 0x00)[register 0x00] != 0xFFFF_FFFF`.
 /// Then, an additional [PciDevice] will be returned for every of its other functions that also
 /// return anything different from `0xFFFF_FFFF`.
-fn discover() -> Vec<PciDevice> {
+pub fn discover() -> Vec<PciDevice> {
     let mut devices = vec![];
     for bus in 0..MAX_BUS {
         for slot in 0..MAX_SLOT {
@@ -305,25 +397,3 @@ fn discover() -> Vec<PciDevice> {
     }
     devices
 }
-
-/// Gets the ahci controllers found by pci discovery.
-///
-/// # Returns
-///
-/// Returns the controller's BAR5 address and size, if one was found.
-pub fn get_ahci_controllers() -> Vec<(u32, u32)> {
-    discover().iter()
-        .filter(|device| device.class == 0x01 && device.subclass == 0x06 && device.prog_if == 0x01)
-        .map(|device| {
-            match device.header {
-                PciHeader::GeneralDevice(header00) => {
-                    match header00.bar5 {
-                        BAR::Memory(addr, size) => (addr, size),
-                        _ => panic!("PCI device with unexpected BAR 5")
-                    }
-                },
-                _ => panic!("PCI device with unexpected header")
-            }
-        })
-        .collect()
-}
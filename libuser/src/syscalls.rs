@@ -5,6 +5,8 @@ use crate::types::*;
 pub use sunrise_libkern::nr;
 pub use sunrise_libkern::{MemoryInfo, MemoryPermissions};
 pub use sunrise_libkern::process::*;
+pub use sunrise_libkern::profiler::*;
+pub use sunrise_libkern::power::*;
 use crate::error::KernelError;
 
 // Assembly blob can't get documented, but clippy requires it.
@@ -141,7 +143,77 @@ pub fn query_memory(addr: usize) -> Result<(MemoryInfo, usize), KernelError> {
     Ok((meminfo, pageinfo))
 }
 
+/// Query information about an address in the memory of another process. Same
+/// as [query_memory()], but for the process `process`.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The passed handle is invalid or not a process.
+/// - `InvalidState`
+///   - The process is not ours, and either we lack the `can_debug_others`
+///     debug flag in our kernel capabilities, or it lacks the
+///     `can_be_debugged` one.
+pub fn query_process_memory(process: &Process, addr: usize) -> Result<(MemoryInfo, usize), KernelError> {
+    let mut meminfo = MemoryInfo::default();
+    let (pageinfo, ..) = unsafe {
+        syscall(nr::QueryProcessMemory, &mut meminfo as *mut _ as usize, (process.0).0.get() as _, addr, 0, 0, 0)?
+    };
+    Ok((meminfo, pageinfo))
+}
+
+/// Mirrors the heap range at `srcaddr` to `dstaddr` as a Stack mapping, with
+/// a page guard right below it.
+///
+/// `srcaddr` keeps being mapped, it must not be used while it is mirrored.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - srcaddr or dstaddr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - The src memory pages does not have the MAP_ALLOWED state.
+///    - The dst memory pages, or the page below them, are not of the Unmapped type.
+/// - `InvalidMemRange`
+///    - The dst region is outside of the UserLand address space.
+pub fn map_stack_mirror(dstaddr: usize, srcaddr: usize, size: usize) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::MapStackMirror, dstaddr, srcaddr, size, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Unmaps a memory range mirrored with [map_stack_mirror()], and its page guard.
+///
+/// # Safety
+///
+/// This function unmaps the memory, invalidating any pointer to the given
+/// region. The user must take care that no pointers point to this region before
+/// calling this function.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - srcaddr or dstaddr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+/// - `InvalidMemState`
+///    - The dst region was not mapped by [map_stack_mirror()].
+/// - `InvalidMemRange`
+///    - The given dst range does not mirror the given src range.
+pub unsafe fn unmap_stack_mirror(dstaddr: usize, srcaddr: usize, size: usize) -> Result<(), KernelError> {
+    syscall(nr::UnmapStackMirror, dstaddr, srcaddr, size, 0, 0, 0)?;
+    Ok(())
+}
+
 /// Exits the process, killing all threads.
+///
+/// The exit code of the process is the one set with
+/// [set_my_exit_code()](crate::threads::set_my_exit_code), 0 by default.
 pub fn exit_process() -> ! {
     unsafe {
         match syscall(nr::ExitProcess, 0, 0, 0, 0, 0, 0) {
@@ -153,6 +225,22 @@ pub fn exit_process() -> ! {
     }
 }
 
+/// Sunrise extension: Exits the process with the given exit code, killing all
+/// threads.
+///
+/// The exit code can be retrieved by whoever holds a handle to the process
+/// with [get_process_info()].
+pub fn exit_process_with_code(exit_code: u32) -> ! {
+    unsafe {
+        match syscall(nr::ExitProcessWithCode, exit_code as _, 0, 0, 0, 0, 0) {
+            Ok(_) => (),
+            Err(err) => { let _ = output_debug_string(&format!("Failed to exit: {}", err), 10, "sunrise_libuser::syscalls::exit_process_with_code"); },
+        }
+        #[allow(clippy::empty_loop)]
+        loop {} // unreachable, but we can't panic, as panic! calls exit_process_with_code
+    }
+}
+
 /// Creates a thread in the current process.
 ///
 /// # Safety
@@ -224,13 +312,16 @@ pub(crate) fn clear_event(event: HandleRef) -> Result<(), KernelError> {
 /// Creates a shared memory handle.
 ///
 /// Allocates the given size bytes of physical memory to back the SharedMemory.
-/// myperm dictates the memory permissions this handle can be mapped as in the
+/// myperm dictates the memory permissions this handle must be mapped as in the
 /// current process, while otherperm dictates the permissions for other
-/// processes.
+/// processes. otherperm can be [MemoryPermissions::DONT_CARE] to let the other
+/// processes choose.
 ///
 /// # Errors
 ///
-/// - Errors if size is not page-aligned.
+/// - size must be page-aligned, and not 0.
+/// - myperm must be R-- or RW-.
+/// - otherperm must be R--, RW- or DONT_CARE.
 pub fn create_shared_memory(size: usize, myperm: MemoryPermissions, otherperm: MemoryPermissions) -> Result<SharedMemory, KernelError> {
     unsafe {
         let (out_handle, ..) = syscall(nr::CreateSharedMemory, size, myperm.bits() as _, otherperm.bits() as _, 0, 0, 0)?;
@@ -238,6 +329,24 @@ pub fn create_shared_memory(size: usize, myperm: MemoryPermissions, otherperm: M
     }
 }
 
+/// Creates a shared memory handle, backed by physically contiguous memory.
+///
+/// Same as [create_shared_memory()], for drivers of devices that access
+/// physical memory.
+///
+/// # Errors
+///
+/// - size must be page-aligned, and not 0.
+/// - myperm must be R-- or RW-.
+/// - otherperm must be R--, RW- or DONT_CARE.
+/// - `MemoryFull`: there is no free physical memory region this big.
+pub fn create_contiguous_shared_memory(size: usize, myperm: MemoryPermissions, otherperm: MemoryPermissions) -> Result<SharedMemory, KernelError> {
+    unsafe {
+        let (out_handle, ..) = syscall(nr::CreateContiguousSharedMemory, size, myperm.bits() as _, otherperm.bits() as _, 0, 0, 0)?;
+        Ok(SharedMemory(Handle::new(out_handle as _)))
+    }
+}
+
 /// Maps a shared memory.
 ///
 /// Maps a SharedMemory handle at the given address, with the given permission.
@@ -246,7 +355,8 @@ pub fn create_shared_memory(size: usize, myperm: MemoryPermissions, otherperm: M
 ///
 /// - addr must be page-aligned.
 /// - size must be equal to the size of the backing shared memory handle.
-/// - perm must be allowed.
+/// - perm must be the one the creator of the shared memory chose for this
+///   process, or R-- or RW- if it did not care.
 pub fn map_shared_memory(handle: &SharedMemory, addr: usize, size: usize, perm: MemoryPermissions) -> Result<(), KernelError> {
     unsafe {
         syscall(nr::MapSharedMemory, (handle.0).0.get() as _, addr, size, perm.bits() as _, 0, 0)?;
@@ -266,7 +376,7 @@ pub fn map_shared_memory(handle: &SharedMemory, addr: usize, size: usize, perm:
 ///
 /// # Errors:
 ///
-/// - addr must point to a mapping backed by the given handle
+/// - addr must be the start of a mapping backed by the given handle
 /// - Size must be equal to the size of the backing shared memory handle.
 pub unsafe fn unmap_shared_memory(handle: &SharedMemory, addr: usize, size: usize) -> Result<(), KernelError> {
     syscall(nr::UnmapSharedMemory, (handle.0).0.get() as _, addr, size, 0, 0, 0)?;
@@ -333,6 +443,16 @@ pub fn wait_synchronization(handles: &[HandleRef<'_>], timeout_ns: Option<usize>
     }
 }
 
+/// Gets the current value of the system tick counter.
+///
+/// This is the unit used by the tick counts returned by [get_info].
+pub fn get_system_tick() -> Result<u64, KernelError> {
+    unsafe {
+        let (low, high, ..) = syscall(nr::GetSystemTick, 0, 0, 0, 0, 0, 0)?;
+        Ok((low as u64) | ((high as u64) << 32))
+    }
+}
+
 /// Creates a session to the given named port.
 pub fn connect_to_named_port(s: &str) -> Result<ClientSession, KernelError> {
     unsafe {
@@ -361,6 +481,156 @@ pub fn output_debug_string(s: &str, level: usize, target: &str) -> Result<(), Ke
     }
 }
 
+/// Extract information about a thread, a process or the system. See
+/// [InfoType] for the meaning of `handle` and `sub_id` for each info type.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The passed handle is invalid or of the wrong type.
+///   - A handle was passed to an info type working on pids.
+/// - `InvalidCombination`
+///   - The passed sub_id is invalid for this info type.
+/// - `NoSuchEntry`
+///   - No living process has the given pid.
+/// - `InvalidEnum`
+///   - The passed info_type is unknown.
+pub fn get_info(info_type: InfoType, handle: Option<HandleRef<'_>>, sub_id: u64) -> Result<u64, KernelError> {
+    let handle = handle.map(|handle| handle.inner.get()).unwrap_or(0);
+    unsafe {
+        let (low, high, ..) = syscall(nr::GetInfo, info_type.0 as _, handle as _, sub_id as usize, (sub_id >> 32) as usize, 0, 0)?;
+        Ok((low as u64) | ((high as u64) << 32))
+    }
+}
+
+/// Replaces the kernel log filter with the given env_logger-style directive
+/// string (e.g. `sunrise_kernel::ipc=trace,info`).
+///
+/// If `pid` is None, the global filter is replaced. Otherwise, the filter only
+/// applies to the messages the given process sends through
+/// [output_debug_string()]. Passing an empty directive string along with a pid
+/// makes that process use the global filter again.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has the given pid.
+pub fn set_log_filter(directives: &str, pid: Option<u64>) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetLogFilter, directives.as_ptr() as _, directives.len(), pid.unwrap_or(0) as _, pid.is_some() as _, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Controls the kernel's sampling profiler.
+///
+/// For [ProfilerCommand::Drain], up to `samples.len()` samples are moved from
+/// the kernel buffer to `samples`, oldest first. Returns the number of samples
+/// written, along with the number of samples the kernel had to drop since the
+/// last drain because its buffer was full. Other commands ignore `samples` and
+/// return (0, 0).
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - The command is unknown.
+pub fn control_profiler(command: ProfilerCommand, samples: &mut [ProfilerSample]) -> Result<(usize, usize), KernelError> {
+    unsafe {
+        let (count, dropped, ..) = syscall(nr::ControlProfiler, command.0 as _, samples.as_mut_ptr() as _, samples.len(), 0, 0, 0)?;
+        Ok((count, dropped))
+    }
+}
+
+/// Powers the computer off or reboots it. Services should be asked to flush
+/// their data first.
+///
+/// Only returns on failure.
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - The mode is unknown.
+/// - `NotImplemented`
+///   - Shutting down is not supported by this system, or the firmware ignored
+///     the request.
+pub fn sleep_system(mode: SleepSystemMode) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SleepSystem, mode.0 as _, 0, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Gets the name of the process with the given pid, as given in its KIP or
+/// NPDM. The name is truncated if it doesn't fit in `name`.
+///
+/// Returns the length of the copied name.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No living process has the given pid.
+pub fn get_process_name(pid: u64, name: &mut [u8]) -> Result<usize, KernelError> {
+    unsafe {
+        let (copied_len, ..) = syscall(nr::GetProcessName, pid as _, name.as_mut_ptr() as _, name.len(), 0, 0, 0)?;
+        Ok(copied_len)
+    }
+}
+
+/// Reads the core dump the kernel made of a crashed process, starting at
+/// `offset` in the core file.
+///
+/// Returns the number of bytes read, which is only smaller than `buf.len()`
+/// when reaching the end of the file, along with the total size of the core
+/// file.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - `process` is not a valid process handle.
+/// - `InvalidState`
+///   - The process did not crash.
+pub fn read_core_dump(process: &Process, offset: usize, buf: &mut [u8]) -> Result<(usize, usize), KernelError> {
+    unsafe {
+        let (read, size, ..) = syscall(nr::ReadCoreDump, (process.0).0.get() as _, offset, buf.as_mut_ptr() as _, buf.len(), 0, 0)?;
+        Ok((read, size))
+    }
+}
+
+/// Reads the report of the kernel panic that ended the previous boot,
+/// starting at `offset`.
+///
+/// Returns the number of bytes read, which is only smaller than `buf.len()`
+/// when reaching the end of the report, along with the total size of the
+/// report.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///   - The previous boot did not end with a kernel panic.
+pub fn read_kernel_panic_report(offset: usize, buf: &mut [u8]) -> Result<(usize, usize), KernelError> {
+    unsafe {
+        let (read, size, ..) = syscall(nr::ReadKernelPanicReport, offset, buf.as_mut_ptr() as _, buf.len(), 0, 0, 0)?;
+        Ok((read, size))
+    }
+}
+
+/// Takes a snapshot of the kernel objects - processes with their handles and
+/// mappings, named ports, sessions and physical memory usage - and reads it
+/// starting at `offset`. The kernel also logs it when `offset` is 0.
+///
+/// Returns the number of bytes read, which is only smaller than `buf.len()`
+/// when reaching the end of the snapshot, along with the total size of the
+/// snapshot.
+///
+/// Every call takes a new snapshot, use a buffer big enough to read it in a
+/// single call to get a consistent one.
+pub fn dump_info(offset: usize, buf: &mut [u8]) -> Result<(usize, usize), KernelError> {
+    unsafe {
+        let (read, size, ..) = syscall(nr::DumpInfo, offset, buf.as_mut_ptr() as _, buf.len(), 0, 0, 0)?;
+        Ok((read, size))
+    }
+}
+
 /// Create an anonymous session.
 pub fn create_session(is_light: bool, unk: usize) -> Result<(ServerSession, ClientSession), KernelError> {
     unsafe {
@@ -445,6 +715,142 @@ pub fn query_physical_address(virtual_address: usize) -> Result<(usize, usize, u
     }
 }
 
+/// Creates an empty device address space, where only `[address; address + size)`
+/// can be mapped. PCI devices attached to it can only DMA to the memory mapped
+/// there.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - `address` is not page aligned.
+/// - `InvalidSize`
+///    - `size` is zero or not page aligned.
+///    - The range ends past the 39-bit device addresses the kernel can translate.
+/// - `NotImplemented`
+///    - The system has no IOMMU.
+/// - `ExceedingMaximum`
+///    - The IOMMU ran out of domain ids.
+pub fn create_device_address_space(address: usize, size: usize) -> Result<DeviceAddressSpace, KernelError> {
+    unsafe {
+        let (out_handle, ..) = syscall(nr::CreateDeviceAddressSpace, address, size, 0, 0, 0, 0)?;
+        Ok(DeviceAddressSpace(Handle::new(out_handle as _)))
+    }
+}
+
+/// Makes the DMAs of a PCI device go through a device address space. The
+/// device is identified by its requester id: `bus << 8 | device << 3 | function`.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///    - No device answers at this requester id.
+///    - The capabilities of this process don't allow the PCI class of the device,
+///      see [pci_class](crate::caps::pci_class).
+/// - `InvalidAddress`
+///    - No IOMMU translates the DMAs of this device.
+/// - `InvalidState`
+///    - The device is already attached to a device address space.
+pub fn attach_device_address_space(device: u16, das: &DeviceAddressSpace) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::AttachDeviceAddressSpace, device as _, (das.0).0.get() as _, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Blocks the DMAs of a PCI device attached to a device address space.
+///
+/// # Errors
+///
+/// - `InvalidState`
+///    - The device is not attached to this device address space.
+pub fn detach_device_address_space(device: u16, das: &DeviceAddressSpace) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::DetachDeviceAddressSpace, device as _, (das.0).0.get() as _, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Maps `size` bytes of the memory of `process` at `process_address` in a
+/// device address space at `device_address`, where devices can access them
+/// with `perm`. The memory stays allocated until it is unmapped from the
+/// device address space.
+///
+/// The memory must have the MAP_DEVICE_ALLOWED state, such as heap memory,
+/// and at least the permissions `perm`.
+///
+/// # Errors
+///
+/// - `InvalidMemPerms`
+///    - `perm` is not R, W or RW.
+/// - `InvalidMemRange`
+///    - The device range is not part of the device address space.
+/// - `InvalidAddress`
+///    - An address is not page aligned.
+/// - `InvalidSize`
+///    - `size` is zero or not page aligned.
+/// - `InvalidMemState`
+///    - The memory doesn't have the required state or permissions.
+/// - `InvalidState`
+///    - Part of the device range is already mapped.
+pub fn map_device_address_space_by_force(das: &DeviceAddressSpace, process: &Process, process_address: usize, size: usize, device_address: usize, perm: MemoryPermissions) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::MapDeviceAddressSpaceByForce, (das.0).0.get() as _, (process.0).0.get() as _, process_address, size, device_address, perm.bits() as _)?;
+        Ok(())
+    }
+}
+
+/// Same as [map_device_address_space_by_force()], but the memory must have
+/// the MAP_DEVICE_ALIGNED_ALLOWED state, and the addresses the same offset in
+/// a 2MiB large page.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - The addresses have a different large page offset.
+/// - All the errors of [map_device_address_space_by_force()].
+pub fn map_device_address_space_aligned(das: &DeviceAddressSpace, process: &Process, process_address: usize, size: usize, device_address: usize, perm: MemoryPermissions) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::MapDeviceAddressSpaceAligned, (das.0).0.get() as _, (process.0).0.get() as _, process_address, size, device_address, perm.bits() as _)?;
+        Ok(())
+    }
+}
+
+/// Same as [map_device_address_space_by_force()], but only maps the part of
+/// the range in the same memory block as `process_address`.
+///
+/// # Return
+///
+/// The size that got mapped.
+///
+/// # Errors
+///
+/// - All the errors of [map_device_address_space_by_force()].
+pub fn map_device_address_space(das: &DeviceAddressSpace, process: &Process, process_address: usize, size: usize, device_address: usize, perm: MemoryPermissions) -> Result<usize, KernelError> {
+    unsafe {
+        let (mapped_size, ..) = syscall(nr::MapDeviceAddressSpace, (das.0).0.get() as _, (process.0).0.get() as _, process_address, size, device_address, perm.bits() as _)?;
+        Ok(mapped_size)
+    }
+}
+
+/// Unmaps `size` bytes at `device_address` from a device address space. They
+/// must have been mapped from the memory of `process` at `process_address`,
+/// by one or more map calls covering exactly this range.
+///
+/// # Errors
+///
+/// - `InvalidMemRange`
+///    - The device range is not part of the device address space.
+/// - `InvalidSize`
+///    - `size` is zero.
+/// - `InvalidMemState`
+///    - The range doesn't match the mappings.
+pub fn unmap_device_address_space(das: &DeviceAddressSpace, process: &Process, process_address: usize, size: usize, device_address: usize) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::UnmapDeviceAddressSpace, (das.0).0.get() as _, (process.0).0.get() as _, process_address, size, device_address, 0)?;
+        Ok(())
+    }
+}
+
 /// Creates an anonymous port.
 pub fn create_port(max_sessions: u32, is_light: bool, name_ptr: &[u8]) -> Result<(ClientPort, ServerPort), KernelError> {
     unsafe {
@@ -677,11 +1083,16 @@ pub fn start_process(process_handle: &Process, main_thread_prio: u32, default_cp
 /// -----------------|--------------------------
 /// ProcessState = 0 | The state the current process is in. Returns an instance
 ///                  | of [sunrise_libkern::process::ProcessState].
+/// ExitCode = 0x5300_0000 | Sunrise extension. The exit code of the process,
+///                  | see [exit_process_with_code()]. 128 + the unix signal if
+///                  | it crashed or was terminated.
 ///
 /// # Errors
 ///
 /// - `InvalidHandle`
 ///   - The passed handle is invalid or not a process.
+/// - `InvalidState`
+///   - Asked for the ExitCode of a process that is not Exited.
 /// - `InvalidEnum`
 ///   - The passed info_type is unknown.
 pub fn get_process_info(process_handle: &Process, ty: ProcessInfoType) -> Result<u32, KernelError> {
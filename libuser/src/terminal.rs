@@ -38,7 +38,7 @@ pub enum WindowSize {
 pub struct Terminal {
     /// Internal write buffer.
     buffer: ArrayVec<[u8; 256]>,
-    /// The vi pipe backing this terminal.
+    /// The pipe backing this terminal, usually created by vi.
     pipe: crate::twili::IPipeProxy
 }
 
@@ -78,7 +78,8 @@ impl Terminal {
         let bpp = 32;
         let size = height * width * bpp / 8;
 
-        let sharedmem = SharedMemory::new(align_up(size, PAGE_SIZE as _) as _, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE, MemoryPermissions::READABLE)?;
+        // vi draws the terminal in it, so it must be able to write.
+        let sharedmem = SharedMemory::new(align_up(size, PAGE_SIZE as _) as _, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
         let pipe = vi.create_terminal(&sharedmem, top, left, width, height)?;
 
         Ok(Terminal {
@@ -87,6 +88,18 @@ impl Terminal {
         })
     }
 
+    /// Creates a Terminal backed by an existing pipe, such as the one returned
+    /// by the serial service.
+    ///
+    /// The pipe should behave like a vi terminal: echo what is typed, and
+    /// buffer reads until a \n is received.
+    pub fn from_pipe(pipe: crate::twili::IPipeProxy) -> Terminal {
+        Terminal {
+            pipe,
+            buffer: ArrayVec::new()
+        }
+    }
+
     /// Flush the write buffer and draw the text.
     pub fn draw(&mut self) -> Result<(), Error> {
         if !self.buffer.is_empty() {
@@ -76,11 +76,20 @@ use spin::Once;
 pub const DEFAULT_STACK_SIZE: usize = 0x8000;
 
 /// Stack allocation informations
+///
+/// The stack is allocated on the heap, and mirrored with [map_stack_mirror] to a free
+/// region, with a page guard below it. The thread only ever uses the mirror, so
+/// overflowing its stack faults on the page guard instead of corrupting the heap.
+///
+/// [map_stack_mirror]: syscalls::map_stack_mirror
 #[derive(Debug)]
 struct StackContext {
-    /// The addresss of the allocated stack
+    /// The addresss of the allocated stack, in the heap.
     stack_address: *const u8,
 
+    /// The address of the mirror of the stack, right above the page guard.
+    mirror_address: *const u8,
+
     /// The stack layout.
     stack_layout: Layout
 }
@@ -93,32 +102,63 @@ impl StackContext {
     /// - `InvalidSize`
     ///   - The size passed was 0
     ///   - The size overflows when rounded up to the nearest multiple of PAGE_SIZE.
+    /// - `MemoryFull`
+    ///   - The heap could not fit the stack.
+    /// - `AddressSpaceExhausted`
+    ///   - No free region could fit the stack and its page guard.
     pub fn new(stack_size: usize) -> Result<Self, Error> {
         if stack_size == 0 {
             return Err(KernelError::InvalidSize.into());
         }
 
+        let stack_size = sunrise_libutils::align_up_checked(stack_size, crate::mem::PAGE_SIZE)
+            .ok_or(KernelError::InvalidSize)?;
         let stack_layout = Layout::from_size_align(stack_size, crate::mem::PAGE_SIZE)
             .or(Err(KernelError::InvalidSize))?;
 
-        Ok(StackContext {
-            stack_address: unsafe {
-                // Safety: We error from the function early if stack_size is 0. We don't care much about whether the block is initialized.
-                alloc(stack_layout) as *const u8
-            },
-            stack_layout
-        })
+        let stack_address = unsafe {
+            // Safety: We error from the function early if stack_size is 0. We don't care much about whether the block is initialized.
+            alloc(stack_layout) as *const u8
+        };
+        if stack_address.is_null() {
+            return Err(KernelError::MemoryFull.into());
+        }
+
+        let mirror_address = crate::mem::find_free_address(stack_size + crate::mem::PAGE_SIZE, crate::mem::PAGE_SIZE)
+            .map(|guard_address| guard_address + crate::mem::PAGE_SIZE)
+            .and_then(|mirror_address| {
+                syscalls::map_stack_mirror(mirror_address, stack_address as usize, stack_size)?;
+                Ok(mirror_address)
+            });
+
+        match mirror_address {
+            Ok(mirror_address) => Ok(StackContext {
+                stack_address,
+                mirror_address: mirror_address as *const u8,
+                stack_layout
+            }),
+            Err(err) => {
+                unsafe {
+                    // Safety: We just allocated it, with this same layout.
+                    dealloc(stack_address as *mut u8, stack_layout);
+                }
+                Err(err)
+            }
+        }
     }
 
     /// Get the address of the stack top.
     pub fn get_stack_top(&self) -> *const u8 {
-        self.stack_address.wrapping_add(self.stack_layout.size())
+        self.mirror_address.wrapping_add(self.stack_layout.size())
     }
 }
 
 impl Drop for StackContext {
     fn drop(&mut self) {
         unsafe {
+            // Safety: The thread using the mirror is dead by now, nobody points in it anymore.
+            syscalls::unmap_stack_mirror(self.mirror_address as usize, self.stack_address as usize, self.stack_layout.size())
+                .expect("Failed to unmap the stack mirror");
             // Safety: The stack_address is guaranteed to be valid (it was allocated on construction). We also keep the layout around to ensure it stays the same between alloc and dealloc.
             dealloc(self.stack_address as *mut u8, self.stack_layout);
         }
@@ -242,6 +282,19 @@ pub fn get_my_ipc_buffer() -> *mut IpcBuffer {
     }
 }
 
+/// Sets the exit code of the process in this thread's [TLS] region.
+///
+/// The kernel reports it to whoever waits on the process once this thread
+/// exits it with [exit_process](crate::syscalls::exit_process).
+pub fn set_my_exit_code(exit_code: u32) {
+    unsafe {
+        // safe: - get_my_tls returns a valid 0x200 aligned ptr,
+        //       - .exit_code is correctly aligned in the TLS region to u32,
+        //       - only this thread accesses its TLS.
+        (*get_my_tls_region()).exit_code = exit_code;
+    }
+}
+
 /// Libuser's representation of a thread.
 ///
 /// This is the low-level representation of a thread, kind to `pthread_t` on Unix.
@@ -273,14 +326,6 @@ impl Thread {
     /// Allocates the stack, sets up the context and TLS, and calls `svcCreateThread`.
     ///
     /// [`start`]: Thread::start
-    // todo: Libuser Thread stack guard
-    // body: Currently the stack of every non-main thread is allocated in the heap, and no page
-    // body: guard protects from stack-overflowing and rewriting all the heap.
-    // body:
-    // body: This is of course terrible for security, as with this stack overflowing is U.B.
-    // body:
-    // body: The simpler way to fix this would be to continue allocating the stack on the heap,
-    // body: but remap the last page with no permissions with the yet unimplemented svcMapMemory syscall.
     pub fn create(entry: fn (usize) -> (), arg: usize, stack_size: usize) -> Result<Self, Error> {
 
         let tls_elf = Once::new();
@@ -503,6 +503,18 @@ impl Process {
         Ok(ProcessState(info as u8))
     }
 
+    /// Get the exit code of the given process. See
+    /// [syscalls::exit_process_with_code].
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidState`
+    ///   - The process is not Exited yet.
+    pub fn exit_code(&self) -> Result<u32, Error> {
+        let info = syscalls::get_process_info(self, ProcessInfoType::ExitCode)?;
+        Ok(info as u32)
+    }
+
     /// Waits for the process to change state. Use [Process::state] to get the
     /// new state and [Process::reset_signal] to reset the signaled state.
     ///
@@ -559,14 +571,22 @@ impl SharedMemory {
     /// Creates a new Shared Memory handle. The physical memory underlying this
     /// shared memory will span `length` bytes.
     ///
-    /// Myperm and otherperm are masks of which permissions are allowed when
-    /// mapping the shared memory in the current process and other processes
-    /// respectively.
+    /// Myperm and otherperm are the permissions the shared memory must be
+    /// mapped with in the current process and other processes respectively.
+    /// Otherperm can be [MemoryPermissions::DONT_CARE] to let the other
+    /// processes choose.
     pub fn new(length: usize, myperm: MemoryPermissions, otherperm: MemoryPermissions) -> Result<SharedMemory, Error> {
         syscalls::create_shared_memory(length, myperm, otherperm)
             .map_err(|v| v.into())
     }
 
+    /// Creates a new Shared Memory handle, backed by physically contiguous
+    /// memory. See [new](SharedMemory::new).
+    pub fn new_contiguous(length: usize, myperm: MemoryPermissions, otherperm: MemoryPermissions) -> Result<SharedMemory, Error> {
+        syscalls::create_contiguous_shared_memory(length, myperm, otherperm)
+            .map_err(|v| v.into())
+    }
+
     /// Maps the current shared memory at the given address, consuming the handle
     /// and returning a MappedMemoryRegion. Note that the size must be equal to
     /// the length of the SharedMemory.
@@ -632,6 +652,65 @@ impl Drop for MappedSharedMemory {
     }
 }
 
+/// The address space of some PCI devices, translated by the IOMMU. Created
+/// with [DeviceAddressSpace::new()].
+///
+/// Devices attached to it can only DMA to the memory the current process
+/// mapped there.
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct DeviceAddressSpace(pub Handle);
+
+impl DeviceAddressSpace {
+    /// Creates an empty device address space, where only
+    /// `[address; address + size)` can be mapped.
+    pub fn new(address: usize, size: usize) -> Result<DeviceAddressSpace, Error> {
+        syscalls::create_device_address_space(address, size)
+            .map_err(|v| v.into())
+    }
+
+    /// Makes the DMAs of the PCI device with the given requester id go through
+    /// this address space.
+    pub fn attach(&self, device: u16) -> Result<(), Error> {
+        syscalls::attach_device_address_space(device, self)
+            .map_err(|v| v.into())
+    }
+
+    /// Blocks the DMAs of the PCI device with the given requester id.
+    pub fn detach(&self, device: u16) -> Result<(), Error> {
+        syscalls::detach_device_address_space(device, self)
+            .map_err(|v| v.into())
+    }
+
+    /// Maps `size` bytes of the current process' memory at `address` in this
+    /// address space at `device_address`, accessible by the devices with
+    /// `perm`.
+    pub fn map(&self, address: usize, size: usize, device_address: usize, perm: MemoryPermissions) -> Result<(), Error> {
+        syscalls::map_device_address_space_by_force(self, &Process::current(), address, size, device_address, perm)
+            .map_err(|v| v.into())
+    }
+
+    /// Same as [map](DeviceAddressSpace::map), but requires memory that can
+    /// be mapped with large pages, at addresses with the same large page offset.
+    pub fn map_aligned(&self, address: usize, size: usize, device_address: usize, perm: MemoryPermissions) -> Result<(), Error> {
+        syscalls::map_device_address_space_aligned(self, &Process::current(), address, size, device_address, perm)
+            .map_err(|v| v.into())
+    }
+
+    /// Same as [map](DeviceAddressSpace::map), but stops at the end of the
+    /// memory block `address` is part of. Returns the size that got mapped.
+    pub fn map_partial(&self, address: usize, size: usize, device_address: usize, perm: MemoryPermissions) -> Result<usize, Error> {
+        syscalls::map_device_address_space(self, &Process::current(), address, size, device_address, perm)
+            .map_err(|v| v.into())
+    }
+
+    /// Unmaps a range mapped by the current process at `address`.
+    pub fn unmap(&self, address: usize, size: usize, device_address: usize) -> Result<(), Error> {
+        syscalls::unmap_device_address_space(self, &Process::current(), address, size, device_address)
+            .map_err(|v| v.into())
+    }
+}
+
 /// Process ID, as returned by IPC.
 ///
 /// Each process in Horizon is given a unique, non-reusable PID. It may be used
@@ -4,6 +4,7 @@
 
 use core::slice;
 use core::cmp::Ordering;
+use alloc::vec::Vec;
 use xmas_elf::ElfFile;
 use xmas_elf::program::{ProgramHeader, Type::Load, SegmentData};
 use sunrise_libuser::syscalls::{self, map_process_memory};
@@ -67,6 +68,37 @@ pub fn get_size(elf: &ElfFile<'_>) -> Result<usize, Error> {
     Ok(size)
 }
 
+/// Checks whether the given kernel access controls have the debug flags
+/// capability, with its `can_debug_others` bit set.
+pub fn can_debug_others(kacs: &[u8]) -> bool {
+    kacs.chunks_exact(4)
+        .map(|kac| u32::from_le_bytes([kac[0], kac[1], kac[2], kac[3]]))
+        // Debug flags: the 16 low bits are set, bit 16 is clear.
+        .any(|kac| kac & 0x1FFFF == 0xFFFF && kac & (1 << 18) != 0)
+}
+
+/// Returns the given kernel access controls, with the `can_be_debugged` bit of
+/// their debug flags capability set, adding the capability if it is missing.
+///
+/// Every process we start can be inspected by the processes allowed to debug
+/// others, such as `pmap`.
+pub fn with_can_be_debugged(kacs: &[u8]) -> Vec<u8> {
+    let mut kacs = Vec::from(kacs);
+    let mut found = false;
+    for kac in kacs.chunks_exact_mut(4) {
+        let value = u32::from_le_bytes([kac[0], kac[1], kac[2], kac[3]]);
+        // Debug flags: the 16 low bits are set, bit 16 is clear.
+        if value & 0x1FFFF == 0xFFFF {
+            kac.copy_from_slice(&(value | (1 << 17)).to_le_bytes());
+            found = true;
+        }
+    }
+    if !found {
+        kacs.extend_from_slice(&sunrise_libuser::caps::debug_flags(true, false).to_le_bytes());
+    }
+    kacs
+}
+
 /// Gets the desired kernel access controls for a process based on the
 /// .kernel_caps section in its elf
 pub fn get_kacs<'a>(elf: &'a ElfFile<'_>) -> Option<&'a [u8]> {
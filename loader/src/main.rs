@@ -20,6 +20,11 @@
 //!   - main.npdm
 //!   - flags/
 //!     - boot.flag
+//!     - serial.flag
+//!
+//! Titles with a `boot.flag` are started at boot. Titles with a `serial.flag`
+//! are started once those are, with their stdin, stdout and stderr connected
+//! to the serial line.
 
 #![no_std]
 #[macro_use]
@@ -32,6 +37,8 @@ use core::slice;
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use sunrise_libuser::fs::{DirectoryEntry, DirectoryEntryType, FileSystemPath, IFileSystemProxy, IFileSystemServiceProxy};
 use sunrise_libuser::{kip_header, capabilities};
@@ -39,6 +46,8 @@ use sunrise_libuser::ipc::server::{port_handler};
 use sunrise_libuser::futures::{WaitableManager, WorkQueue};
 use sunrise_libuser::error::{Error, LoaderError, PmError, KernelError};
 use sunrise_libuser::ldr::ILoaderInterfaceAsync;
+use sunrise_libuser::serial::ISerialServiceProxy;
+use sunrise_libuser::twili::ITwiliManagerServiceProxy;
 use sunrise_libuser::syscalls::{self, map_process_memory};
 use sunrise_libuser::types::{Pid, Process, ReadableEvent, WritableEvent, HandleRef};
 use sunrise_libkern::process::*;
@@ -59,13 +68,106 @@ mod elf_loader;
 const MAX_ELF_SIZE: u64 = 128 * 1024 * 1024;
 
 lazy_static! {
-    static ref PROCESSES: Mutex<BTreeMap<u64, (Process, String)>> = Mutex::new(BTreeMap::new());
+    /// The processes we started, their name, and whether they have the
+    /// `can_debug_others` debug flag. The task watching a process keeps it
+    /// alive until it saved its core dump, even if it was already reaped by
+    /// `wait`.
+    static ref PROCESSES: Mutex<BTreeMap<u64, Arc<(Process, String, bool)>>> = Mutex::new(BTreeMap::new());
     /// Public ReadableEvent that gets signaled when a process state changes.
     /// Other processes can get it by using the get_process_state_changed_event
     /// command.
     static ref PROCESS_STATE_CHANGED: (WritableEvent, ReadableEvent) = syscalls::create_event().unwrap();
 }
 
+/// Size of the chunks the core dumps are copied in.
+const CORE_DUMP_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Creates `/var/crash`, if it doesn't exist yet.
+fn create_crash_directory(fs: &IFileSystemProxy) {
+    let mut raw_path: FileSystemPath = [0; 0x300];
+    for dir in &["/var", "/var/crash"] {
+        (&mut raw_path[0..dir.len()]).copy_from_slice(dir.as_bytes());
+        // Fails if the directory already exists.
+        let _ = fs.create_directory(&raw_path);
+    }
+}
+
+/// Saves the core dump the kernel made of a crashed process to
+/// `/var/crash/<name>-<pid>.core`.
+///
+/// Does nothing if the process did not crash.
+fn save_core_dump(fs: &IFileSystemProxy, process: &Process, name: &str, pid: u64) -> Result<(), Error> {
+    let mut buf = vec![0; CORE_DUMP_CHUNK_SIZE];
+    let (mut read, size) = match syscalls::read_core_dump(process, 0, &mut buf) {
+        Ok(res) => res,
+        Err(KernelError::InvalidState) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    create_crash_directory(fs);
+
+    let val = format!("/var/crash/{}-{}.core", name, pid);
+    let mut raw_path: FileSystemPath = [0; 0x300];
+    (&mut raw_path[0..val.len()]).copy_from_slice(val.as_bytes());
+    let _ = fs.create_file(0, 0, &raw_path);
+    let file = fs.open_file(6, &raw_path)?;
+    file.set_size(0)?;
+
+    let mut offset = 0;
+    while read != 0 {
+        file.write(0, offset as u64, read as u64, &buf[..read])?;
+        offset += read;
+        if offset >= size {
+            break;
+        }
+        read = syscalls::read_core_dump(process, offset, &mut buf)?.0;
+    }
+
+    info!("Saved core dump of {} (pid {}) to {}", name, pid, val);
+    Ok(())
+}
+
+/// Saves the report of the kernel panic that ended the previous boot to the
+/// first free `/var/crash/kernel-<n>.txt`.
+///
+/// Does nothing if the previous boot did not end with a kernel panic.
+fn save_kernel_panic_report(fs: &IFileSystemProxy) -> Result<(), Error> {
+    let mut buf = vec![0; CORE_DUMP_CHUNK_SIZE];
+    let (mut read, size) = match syscalls::read_kernel_panic_report(0, &mut buf) {
+        Ok(res) => res,
+        Err(KernelError::InvalidState) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    create_crash_directory(fs);
+
+    let mut n = 0;
+    let (val, raw_path) = loop {
+        let val = format!("/var/crash/kernel-{}.txt", n);
+        let mut raw_path: FileSystemPath = [0; 0x300];
+        (&mut raw_path[0..val.len()]).copy_from_slice(val.as_bytes());
+        if fs.open_file(1, &raw_path).is_err() {
+            break (val, raw_path);
+        }
+        n += 1;
+    };
+    fs.create_file(0, 0, &raw_path)?;
+    let file = fs.open_file(6, &raw_path)?;
+
+    let mut offset = 0;
+    while read != 0 {
+        file.write(0, offset as u64, read as u64, &buf[..read])?;
+        offset += read;
+        if offset >= size {
+            break;
+        }
+        read = syscalls::read_kernel_panic_report(offset, &mut buf)?.0;
+    }
+
+    warn!("The previous boot ended with a kernel panic, its report was saved to {}", val);
+    Ok(())
+}
+
 /// Start the given titleid by loading its content from the provided filesystem.
 fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], env: &[u8], start: bool) -> Result<Pid, Error> {
     info!("Booting titleid {}", titlename);
@@ -114,7 +216,7 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], env: &[u8], start:
     let aslr_base = 0x400000;
 
     let kacs = match elf_loader::get_kacs(&elf) {
-        Some(kacs) => kacs,
+        Some(kacs) => elf_loader::with_can_be_debugged(kacs),
         None => {
             error!("TitleID {} did not have a KAC section. Bailing.", titlename);
             return Err(LoaderError::InvalidKacs.into());
@@ -207,7 +309,32 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8], env: &[u8], start:
     }
 
     let pid = process.pid()?;
-    PROCESSES.lock().insert(pid.0, (process, titlename.to_string()));
+    let can_debug_others = elf_loader::can_debug_others(&kacs);
+    PROCESSES.lock().insert(pid.0, Arc::new((process, titlename.to_string(), can_debug_others)));
+
+    Ok(pid)
+}
+
+/// Start the given titleid with its stdin, stdout and stderr connected to the
+/// serial line.
+///
+/// Used for titles with a `serial.flag`, such as a shell to interact with the
+/// system headless. Twili must already be running.
+fn boot_on_serial(fs: &IFileSystemProxy, titlename: &str) -> Result<Pid, Error> {
+    let pid = boot(fs, titlename, &[], &[], false)?;
+
+    let serial = ISerialServiceProxy::raw_new()?;
+    let twili = ITwiliManagerServiceProxy::raw_new()?;
+    twili.register_pipes(pid.0, serial.open_pipe()?, serial.open_pipe()?, serial.open_pipe()?)?;
+
+    debug!("Starting process.");
+    let processes = PROCESSES.lock();
+    let process = &processes.get(&pid.0)
+        .ok_or(PmError::PidNotFound)?.0;
+    if let Err(err) = process.start(0, 0, PAGE_SIZE as u32 * 32) {
+        error!("Failed to start titleid {}: {}", titlename, err);
+        return Err(err)
+    }
 
     Ok(pid)
 }
@@ -238,12 +365,12 @@ impl ILoaderInterfaceAsync for LoaderIface {
 
     fn launch_title(&mut self, workqueue: WorkQueue<'static>, pid: u64) -> FutureObj<'_, Result<(), Error>> {
         let res = (|| -> Result<(), Error> {
-            let lock = PROCESSES.lock();
-            let process = lock.get(&pid)
+            let process = PROCESSES.lock().get(&pid).cloned()
                 .ok_or(PmError::PidNotFound)?;
             debug!("Starting process.");
 
-            let process_static = (process.0).0.as_ref_static();
+            let watched = Arc::clone(&process);
+            let process_static = (watched.0).0.as_ref_static();
 
             // TODO: Move the handling of PROCESS_STATE_CHANGED to a single dedicated task.
             // BODY: We currently handle PROCESS_STATE_CHANGED signaling in one
@@ -256,20 +383,8 @@ impl ILoaderInterfaceAsync for LoaderIface {
                         error!("{:?}", err);
                         return;
                     }
-                    let lock = PROCESSES.lock();
-
-                    let process = match lock.get(&pid)
-                        .ok_or(PmError::PidNotFound)
-                    {
-                        Ok(process) => process,
-                        Err(err) => {
-                            error!("{:?}", err);
-                            return;
-                        }
-                    };
-
                     let old_state = current_state;
-                    let new_state = match process.0.state() {
+                    let new_state = match watched.0.state() {
                         Ok(state) => state,
                         Err(err) => {
                             log::error!("{:?}", err);
@@ -277,6 +392,11 @@ impl ILoaderInterfaceAsync for LoaderIface {
                         }
                     };
                     current_state = new_state;
+                    if new_state == ProcessState::Exited {
+                        if let Err(err) = save_core_dump(&*BOOT_FROM_FS, &watched.0, &watched.1, pid) {
+                            error!("Failed to save the core dump of {} (pid {}): {:?}", watched.1, pid, err);
+                        }
+                    }
                     if old_state != new_state {
                         if let Err(err) = PROCESS_STATE_CHANGED.0.signal() {
                             error!("{:?}", err);
@@ -284,7 +404,7 @@ impl ILoaderInterfaceAsync for LoaderIface {
                         }
                     }
 
-                    match process.0.reset_signal() {
+                    match watched.0.reset_signal() {
                         Ok(()) | Err(Error::Kernel(KernelError::InvalidState, _)) => (),
                         Err(err) => {
                             log::error!("{:?}", err);
@@ -335,9 +455,10 @@ impl ILoaderInterfaceAsync for LoaderIface {
                 };
 
                 if process.state()? == ProcessState::Exited {
+                    let exit_code = process.exit_code()?;
+                    // The task watching the process saves its core dump.
                     lock.remove(&pid);
-                    // TODO: Return exit state.
-                    return Ok(0);
+                    return Ok(exit_code);
                 }
             }
         }))
@@ -358,6 +479,21 @@ impl ILoaderInterfaceAsync for LoaderIface {
         }))
     }
 
+    fn get_process_handle(&mut self, _workqueue: WorkQueue<'static>, caller: Pid, pid: u64) -> FutureObj<'_, Result<HandleRef<'static>, Error>> {
+        FutureObj::new(Box::new(async move {
+            let processes = PROCESSES.lock();
+            // Only hand process handles out to debuggers we started.
+            if !processes.get(&caller.0).map(|caller| caller.2).unwrap_or(false) {
+                return Err(KernelError::InvalidState.into());
+            }
+            let process = &processes.get(&pid)
+                .ok_or(PmError::PidNotFound)?.0;
+            // The handle gets copied to the client before the process can be
+            // reaped and its handle closed.
+            Ok((process.0).0.as_ref_static())
+        }))
+    }
+
     fn kill(&mut self, _workqueue: WorkQueue<'static>, pid: u64) -> FutureObj<'_, Result<(), Error>> {
         FutureObj::new(Box::new(async move {
             let processes = PROCESSES.lock();
@@ -383,9 +519,16 @@ impl ILoaderInterfaceAsync for LoaderIface {
 fn main() {
     let fs = &*BOOT_FROM_FS;
 
+    if let Err(err) = save_kernel_panic_report(fs) {
+        error!("Failed to save the kernel panic report: {:?}", err);
+    }
+
     let mut raw_path: FileSystemPath = [0; 0x300];
     (&mut raw_path[0..4]).copy_from_slice(b"/bin");
 
+    // Titles to start on the serial line, once twili was booted.
+    let mut serial_titles = Vec::new();
+
     if let Ok(directory) = fs.open_directory(1, &raw_path) {
         let mut entries: [DirectoryEntry; 12] = [DirectoryEntry {
             path: [0; 0x300],
@@ -405,32 +548,43 @@ fn main() {
             for entry in entries {
                 raw_path = entry.path;
                 let endpos = raw_path.iter().position(|v| *v == 0).unwrap_or_else(|| raw_path.len());
-                if endpos > 0x300 - 16 {
+                if endpos > 0x300 - 18 {
                     error!("Path too big in /bin.");
                     continue;
                 }
+                let endtitle = entry.path.iter()
+                    .enumerate()
+                    .skip(5)
+                    .find(|(_, v)| **v == b'/' || **v == b'\0')
+                    .map(|(idx, _)| idx).unwrap_or_else(|| entry.path.len());
+                let titleid = if let Ok(titleid) = str::from_utf8(&entry.path[5..endtitle]) {
+                    titleid
+                } else {
+                    error!("Non-ASCII titleid found in /boot.");
+                    continue;
+                };
                 raw_path[endpos..endpos + 16].copy_from_slice(b"/flags/boot.flag");
                 if fs.get_entry_type(&raw_path).is_ok() {
-                    let endpos = entry.path.iter()
-                        .enumerate()
-                        .skip(5)
-                        .find(|(_, v)| **v == b'/' || **v == b'\0')
-                        .map(|(idx, _)| idx).unwrap_or_else(|| entry.path.len());
-                    if let Ok(titleid) = str::from_utf8(&entry.path[5..endpos]) {
-                        if let Err(err) = boot(&fs, titleid, &[], &[], true) {
-                            error!("Failed to boot {}: {:?}.", titleid, err);
-                        }
-                    } else {
-                        error!("Non-ASCII titleid found in /boot.");
-                        continue;
+                    if let Err(err) = boot(&fs, titleid, &[], &[], true) {
+                        error!("Failed to boot {}: {:?}.", titleid, err);
                     }
                 }
+                raw_path[endpos..endpos + 18].copy_from_slice(b"/flags/serial.flag");
+                if fs.get_entry_type(&raw_path).is_ok() {
+                    serial_titles.push(titleid.to_string());
+                }
             }
         }
     } else {
         warn!("No /bin folder on filesystem!");
     }
 
+    for titleid in serial_titles {
+        if let Err(err) = boot_on_serial(&fs, &titleid) {
+            error!("Failed to boot {} on the serial line: {:?}.", titleid, err);
+        }
+    }
+
     let mut man = WaitableManager::new();
 
     let handler = port_handler(man.work_queue(), "ldr:shel", LoaderIface::dispatch).unwrap();
@@ -455,10 +609,13 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
         sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ReadCoreDump,
+        sunrise_libuser::syscalls::nr::ReadKernelPanicReport,
 
         sunrise_libuser::syscalls::nr::SetHeapSize,
         sunrise_libuser::syscalls::nr::QueryMemory,
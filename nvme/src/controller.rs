@@ -0,0 +1,733 @@
+//! NVMe controller
+//!
+//! A controller is found on the PCI, its registers in BAR0. It is driven through queues of
+//! commands:
+//!
+//! - the admin queues, to identify the controller and its namespaces, and to create the other
+//!   queues. We only use them during the initialization, polling their completions.
+//! - one pair of I/O queues, to read and write the namespaces. Their completions raise an
+//!   interrupt.
+//!
+//! See the NVMe 1.4 spec, section 7.6.1 for the initialization sequence.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Formatter};
+use core::mem::size_of;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use sunrise_libuser::dma::Dma;
+use sunrise_libuser::error::{Error, AhciError};
+use sunrise_libuser::io::{Io, Mmio};
+use sunrise_libuser::mem::PAGE_SIZE;
+use sunrise_libuser::syscalls::{sleep_thread, MemoryPermissions};
+use sunrise_libuser::zero_box::{ZeroBox, ZeroInitialized};
+use sunrise_libutils::align_down;
+use static_assertions::assert_eq_size;
+
+use crate::queue::{Command, QueuePair, QUEUE_SIZE};
+
+/// Admin command opcode: Create I/O Submission Queue.
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+/// Admin command opcode: Create I/O Completion Queue.
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+/// Admin command opcode: Identify.
+const ADMIN_IDENTIFY: u8 = 0x06;
+/// NVM command opcode: Write.
+const NVM_WRITE: u8 = 0x01;
+/// NVM command opcode: Read.
+const NVM_READ: u8 = 0x02;
+
+/// Identify CNS value: the Identify Namespace data structure of a namespace.
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+/// Identify CNS value: the Identify Controller data structure.
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+
+/// The identifier of our only pair of I/O queues.
+const IO_QUEUE_ID: u16 = 1;
+
+/// The maximum number of sectors we transfer in a single command.
+///
+/// Its 1MiB span at most 257 pages, so a single page of PRP entries always describes it.
+const MAX_SECTORS_PER_COMMAND: u64 = 2048;
+
+/// The direction of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// Read sectors from the namespace.
+    Read,
+    /// Write sectors to the namespace.
+    Write,
+}
+
+/// The controller registers, found at BAR0.
+///
+/// The doorbells follow at BAR0 + 0x1000. See NVMe 1.4 spec section 3.1.
+#[repr(packed)]
+#[allow(clippy::missing_docs_in_private_items)]
+pub struct Registers {
+    /// Controller Capabilities.
+    cap: Mmio<u64>,
+    /// Version.
+    vs: Mmio<u32>,
+    /// Interrupt Mask Set.
+    intms: Mmio<u32>,
+    /// Interrupt Mask Clear.
+    intmc: Mmio<u32>,
+    /// Controller Configuration.
+    cc: Mmio<u32>,
+    _rsv: Mmio<u32>,
+    /// Controller Status.
+    csts: Mmio<u32>,
+    /// NVM Subsystem Reset.
+    nssr: Mmio<u32>,
+    /// Admin Queue Attributes.
+    aqa: Mmio<u32>,
+    /// Admin Submission Queue Base Address.
+    asq: Mmio<u64>,
+    /// Admin Completion Queue Base Address.
+    acq: Mmio<u64>,
+}
+
+assert_eq_size!(Registers, [u8; 0x38]);
+
+/// The offset of the doorbells from the registers.
+const DOORBELLS_OFFSET: usize = 0x1000;
+
+/// `CC.EN`: enables the controller.
+const CC_ENABLE: u32 = 1 << 0;
+/// `CC.IOSQES`: the size of an I/O submission queue entry, 2^6 bytes.
+const CC_IOSQES: u32 = 6 << 16;
+/// `CC.IOCQES`: the size of an I/O completion queue entry, 2^4 bytes.
+const CC_IOCQES: u32 = 4 << 20;
+/// `CSTS.RDY`: the controller is ready to process commands.
+const CSTS_READY: u32 = 1 << 0;
+/// `CSTS.CFS`: the controller encountered a fatal error.
+const CSTS_FATAL: u32 = 1 << 1;
+
+/// A page, page aligned, the buffer of Identify commands.
+#[repr(C, align(4096))]
+struct IdentifyOutput([u8; PAGE_SIZE]);
+unsafe impl ZeroInitialized for IdentifyOutput {}
+
+/// A page of PRP entries, describing the pages of a transfer after its first one.
+#[repr(C, align(4096))]
+struct PrpList([u64; PAGE_SIZE / size_of::<u64>()]);
+unsafe impl ZeroInitialized for PrpList {}
+
+/// The state of a command identifier.
+#[derive(Debug)]
+pub enum Slot {
+    /// No command is using this identifier.
+    Free,
+    /// A command was submitted with this identifier, and the controller is processing it.
+    ///
+    /// Holds the waker of the task waiting for its completion, if it already polled it.
+    Submitted(Option<Waker>),
+    /// The command was completed, but the task that submitted it hasn't collected its result yet.
+    Completed(Result<(), Error>),
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Slot::Free
+    }
+}
+
+/// An NVMe controller.
+///
+/// Manages the controller's queues, and submits the read and write commands of its namespaces.
+///
+/// # Commands
+///
+/// I/O commands are identified by their slot, the index of its [Slot] and of its [PrpList].
+/// Up to `QUEUE_SIZE - 1` commands can be outstanding at the same time, as many as fit in the
+/// submission queue. Tasks submitting a command when every slot is in use are woken up when
+/// one is released.
+///
+/// Commands are completed by [handle_interrupt], which is called when the controller raises an
+/// interrupt. It wakes up the tasks waiting for them.
+///
+/// [handle_interrupt]: Controller::handle_interrupt
+///
+/// # Memory
+///
+/// A controller is responsible for its queues, PRP lists and identify buffer, which it maps
+/// in its device address space. When dropped, the controller is disabled, and they are unmapped.
+///
+/// # Lifetime
+///
+/// A Controller holds a reference to its registers, in the mapping of BAR0. As this mapping is
+/// never unmapped, the lifetime of this reference is `'static`.
+pub struct Controller {
+    /// The controller registers, found at BAR0.
+    registers: &'static mut Registers,
+    /// How the controller accesses memory, shared with the buffers of our clients.
+    dma: Arc<Mutex<Dma>>,
+    /// The admin queues.
+    admin: QueuePair,
+    /// The I/O queues.
+    io: QueuePair,
+    /// The buffer of Identify commands.
+    identify: ZeroBox<IdentifyOutput>,
+    /// The device address of the identify buffer.
+    identify_address: u64,
+    /// The PRP list of every slot, and its device address.
+    prp_lists: Vec<(ZeroBox<PrpList>, u64)>,
+    /// The state of every slot.
+    slots: Vec<Slot>,
+    /// The wakers of the tasks waiting for a slot to be released.
+    slot_waiters: Vec<Waker>,
+    /// The maximum number of sectors a single command can transfer.
+    max_sectors_per_command: u64,
+    /// The number of namespaces of the controller, the highest valid namespace identifier.
+    namespace_count: u32,
+    /// CAP.TO, the worst case time to become ready, in 500ms units. Also bounds the time we
+    /// wait for an admin command.
+    ready_timeout: u64,
+    /// Whether an admin command timed out. The controller was then disabled, and every command
+    /// fails.
+    failed: bool,
+}
+
+impl Controller {
+    /// Initializes the controller whose BAR0, of `bar0_size` bytes, is mapped at `bar0`.
+    ///
+    /// The controller is reset, its admin and I/O queues are created, and it is identified.
+    /// Its interrupts are masked, until [enable_interrupts](Controller::enable_interrupts).
+    ///
+    /// # Error
+    ///
+    /// Returns `None` and logs the reason if the controller could not be initialized.
+    ///
+    /// # Unsafety
+    ///
+    /// `bar0` must be the address of the mapping of the controller's BAR0, which must never be
+    /// unmapped, and must not be referenced by anything else.
+    pub unsafe fn init(bar0: usize, bar0_size: usize, mut dma: Dma) -> Option<Controller> {
+        let registers = &mut *(bar0 as *mut Registers);
+        let cap = registers.cap.read();
+        // CAP.DSTRD, the doorbell stride.
+        let doorbell_stride = 4 << ((cap >> 32) & 0xF);
+        // CAP.TO, the worst case time to become ready, in 500ms units.
+        let ready_timeout = (cap >> 24) & 0xFF;
+        // CAP.MPSMIN, CAP.CSS.NCSS, and CAP.MQES.
+        if (cap >> 48) & 0xF != 0 || (cap >> 37) & 1 == 0 || cap & 0xFFFF < QUEUE_SIZE as u64 - 1 {
+            error!("NVMe {:#010x}, initialization failed: unsupported capabilities {:#x}.", bar0, cap);
+            return None;
+        }
+        // the doorbells of our two pairs of queues.
+        if bar0_size < DOORBELLS_OFFSET + 4 * doorbell_stride {
+            error!("NVMe {:#010x}, initialization failed: BAR0 too small for its doorbells.", bar0);
+            return None;
+        }
+
+        let admin = QueuePair::new(0, bar0 + DOORBELLS_OFFSET, doorbell_stride);
+        let io = QueuePair::new(IO_QUEUE_ID, bar0 + DOORBELLS_OFFSET, doorbell_stride);
+        let identify = ZeroBox::<IdentifyOutput>::new_zeroed();
+        let (admin_addresses, io_addresses, identify_address, prp_lists) = match map_structures(&mut dma, &admin, &io, &identify) {
+            Ok(mapped) => mapped,
+            Err(e) => {
+                error!("NVMe {:#010x}, initialization failed: failed mapping its structures, {:?}.", bar0, e);
+                return None;
+            }
+        };
+        // from now on, dropping the controller disables it and unmaps its structures.
+        let mut controller = Controller {
+            registers,
+            dma: Arc::new(Mutex::new(dma)),
+            admin,
+            io,
+            identify,
+            identify_address,
+            slots: (0..prp_lists.len()).map(|_| Slot::Free).collect(),
+            prp_lists,
+            slot_waiters: Vec::new(),
+            max_sectors_per_command: MAX_SECTORS_PER_COMMAND,
+            namespace_count: 0,
+            ready_timeout,
+            failed: false,
+        };
+        if !controller.disable(ready_timeout) {
+            error!("NVMe {:#010x}, initialization failed: failed disabling the controller.", bar0);
+            return None;
+        }
+        if let Err(e) = controller.enable(ready_timeout, admin_addresses) {
+            error!("NVMe {:#010x}, initialization failed: failed enabling the controller, {:?}.", bar0, e);
+            return None;
+        }
+        if let Err(e) = controller.identify_controller() {
+            error!("NVMe {:#010x}, initialization failed: failed identifying the controller, {:?}.", bar0, e);
+            return None;
+        }
+        if let Err(e) = controller.create_io_queues(io_addresses) {
+            error!("NVMe {:#010x}, initialization failed: failed creating the I/O queues, {:?}.", bar0, e);
+            return None;
+        }
+        Some(controller)
+    }
+
+    /// Waits for `CSTS.RDY` to be `ready`, for up to `timeout` * 500ms.
+    ///
+    /// Returns false if it timed out, or the controller reported a fatal error.
+    fn wait_ready(&self, ready: bool, timeout: u64) -> bool {
+        for _ in 0..=timeout * 500 {
+            let csts = self.registers.csts.read();
+            if csts & CSTS_FATAL != 0 {
+                return false;
+            }
+            if (csts & CSTS_READY != 0) == ready {
+                return true;
+            }
+            sleep_thread(Duration::from_millis(1).as_nanos() as _).unwrap();
+        }
+        false
+    }
+
+    /// Disables the controller, aborting all its commands, and waits for it to be done.
+    ///
+    /// Returns false if it didn't complete in time.
+    fn disable(&mut self, timeout: u64) -> bool {
+        let cc = self.registers.cc.read();
+        if cc & CC_ENABLE != 0 {
+            self.registers.cc.write(cc & !CC_ENABLE);
+        }
+        self.wait_ready(false, timeout)
+    }
+
+    /// Enables the controller with the admin queues found at the given device addresses, and
+    /// waits for it to be ready.
+    ///
+    /// Its interrupts are masked, admin commands are polled.
+    ///
+    /// # Error
+    ///
+    /// * IoError: the controller didn't become ready in time.
+    fn enable(&mut self, timeout: u64, (admin_submission, admin_completion): (u64, u64)) -> Result<(), Error> {
+        // mask the only vector, used by pin-based interrupts.
+        self.registers.intms.write(1);
+        self.registers.aqa.write(((QUEUE_SIZE as u32 - 1) << 16) | (QUEUE_SIZE as u32 - 1));
+        self.registers.asq.write(admin_submission);
+        self.registers.acq.write(admin_completion);
+        // NVM command set, 4KiB memory pages, round robin arbitration.
+        self.registers.cc.write(CC_IOSQES | CC_IOCQES | CC_ENABLE);
+        if !self.wait_ready(true, timeout) {
+            return Err(AhciError::IoError.into());
+        }
+        Ok(())
+    }
+
+    /// Unmasks the interrupts of the controller, raised when an I/O command is completed.
+    pub fn enable_interrupts(&mut self) {
+        self.registers.intmc.write(1);
+    }
+
+    /// Submits an admin command, and polls the admin completion queue until it is completed,
+    /// for up to CAP.TO * 500ms.
+    ///
+    /// Only one admin command is outstanding at a time, so its identifier is always 0.
+    ///
+    /// If the command times out, or the controller reports a fatal error, the controller is
+    /// disabled and marked as failed, as its admin queues can't be trusted anymore.
+    ///
+    /// # Returns
+    ///
+    /// The command specific dword 0 of the completion.
+    ///
+    /// # Error
+    ///
+    /// * IoError: the controller failed the command, timed out, or has already failed.
+    ///
+    /// # Unsafety
+    ///
+    /// The buffers of the command must be mapped in the device address space.
+    unsafe fn admin_command(&mut self, command: Command) -> Result<u32, Error> {
+        if self.failed {
+            return Err(AhciError::IoError.into());
+        }
+        self.admin.submit(command);
+        let mut completion = None;
+        for _ in 0..=self.ready_timeout * 500 {
+            completion = self.admin.pop_completion();
+            if completion.is_some() || self.registers.csts.read() & CSTS_FATAL != 0 {
+                break;
+            }
+            sleep_thread(Duration::from_millis(1).as_nanos() as _).unwrap();
+        }
+        let completion = match completion {
+            Some(completion) => completion,
+            None => {
+                error!("NVMe admin command {:#x?} was never completed, disabling the controller", command);
+                self.failed = true;
+                self.disable(self.ready_timeout);
+                return Err(AhciError::IoError.into());
+            }
+        };
+        self.admin.acknowledge_completions();
+        if completion.is_success() {
+            Ok(completion.dw0)
+        } else {
+            error!("NVMe admin command {:#x?} failed: {:#x?}", command, completion);
+            Err(AhciError::IoError.into())
+        }
+    }
+
+    /// Sends an Identify command, whose output is written to the identify buffer.
+    ///
+    /// # Error
+    ///
+    /// * IoError: the controller failed the command.
+    fn identify(&mut self, cns: u32, nsid: u32) -> Result<&IdentifyOutput, Error> {
+        let mut command = Command::new(ADMIN_IDENTIFY, 0);
+        command.nsid = nsid;
+        command.prp1 = self.identify_address;
+        command.cdw10 = cns;
+        unsafe {
+            // safe: the identify buffer is always mapped.
+            self.admin_command(command)?;
+        }
+        Ok(&*self.identify)
+    }
+
+    /// Identifies the controller, gets its number of namespaces, and caps the size of a transfer
+    /// to what it supports.
+    ///
+    /// # Error
+    ///
+    /// * IoError: the controller failed the command.
+    fn identify_controller(&mut self) -> Result<(), Error> {
+        let output = &self.identify(IDENTIFY_CONTROLLER, 0)?.0;
+        // MDTS: maximum data transfer size, in units of the minimum memory page size. 0 is unlimited.
+        let mdts = output[77];
+        let namespaces = u32::from_le_bytes([output[516], output[517], output[518], output[519]]);
+        if mdts != 0 {
+            let max_sectors = (PAGE_SIZE as u64 / 512) << mdts;
+            self.max_sectors_per_command = core::cmp::min(MAX_SECTORS_PER_COMMAND, max_sectors);
+        }
+        self.namespace_count = namespaces;
+        Ok(())
+    }
+
+    /// Creates our pair of I/O queues, found at the given device addresses.
+    ///
+    /// The completion queue raises an interrupt on the pin-based vector.
+    ///
+    /// # Error
+    ///
+    /// * IoError: the controller failed the command.
+    fn create_io_queues(&mut self, (submission, completion): (u64, u64)) -> Result<(), Error> {
+        let queue_size_and_id = ((QUEUE_SIZE as u32 - 1) << 16) | u32::from(IO_QUEUE_ID);
+
+        let mut command = Command::new(ADMIN_CREATE_IO_CQ, 0);
+        command.prp1 = completion;
+        command.cdw10 = queue_size_and_id;
+        // interrupt vector 0, interrupts enabled, contiguous.
+        command.cdw11 = 0b11;
+        unsafe {
+            // safe: the queue is always mapped.
+            self.admin_command(command)?;
+        }
+
+        let mut command = Command::new(ADMIN_CREATE_IO_SQ, 0);
+        command.prp1 = submission;
+        command.cdw10 = queue_size_and_id;
+        // completed in our completion queue, contiguous.
+        command.cdw11 = u32::from(IO_QUEUE_ID) << 16 | 1;
+        unsafe {
+            // safe: the queue is always mapped.
+            self.admin_command(command)?;
+        }
+        Ok(())
+    }
+
+    /// Identifies the namespaces of the controller.
+    ///
+    /// # Returns
+    ///
+    /// The identifier and number of 512-octet sectors of every active namespace whose logical
+    /// blocks are 512 octets long. Other namespaces are skipped, as are those we failed
+    /// identifying.
+    pub fn namespaces(&mut self) -> Vec<(u32, u64)> {
+        let mut namespaces = Vec::new();
+        for nsid in 1..=self.namespace_count {
+            let output = match self.identify(IDENTIFY_NAMESPACE, nsid) {
+                Ok(output) => &output.0,
+                Err(e) => {
+                    warn!("NVMe namespace {}: failed identifying it, {:?}.", nsid, e);
+                    if self.failed {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            // NSZE: namespace size, in logical blocks. 0 for inactive namespaces.
+            let mut nsze = [0; 8];
+            nsze.copy_from_slice(&output[0..8]);
+            let blocks = u64::from_le_bytes(nsze);
+            // FLBAS: the LBA format in use, indexing the LBAF table.
+            let format = usize::from(output[26] & 0xF);
+            // LBADS: the size of a logical block, as a power of 2.
+            let block_size_shift = output[128 + 4 * format + 2];
+            if blocks == 0 {
+                continue;
+            }
+            if block_size_shift != 9 {
+                warn!("NVMe namespace {}: unsupported logical block size {}.", nsid, 1u64 << block_size_shift);
+                continue;
+            }
+            namespaces.push((nsid, blocks));
+        }
+        namespaces
+    }
+
+    /// Returns how the controller accesses memory.
+    pub fn dma(&self) -> &Arc<Mutex<Dma>> {
+        &self.dma
+    }
+
+    /// Returns the maximum number of sectors a single command can transfer.
+    pub fn max_sectors_per_command(&self) -> u64 {
+        self.max_sectors_per_command
+    }
+
+    /// Submits a transfer of `sector_count` sectors of namespace `nsid` starting from `lba`,
+    /// to or from the buffer at `buffer`, in a free slot.
+    ///
+    /// If every slot is in use, the task is registered to be woken up when one is released.
+    ///
+    /// # Returns
+    ///
+    /// The slot the command was submitted in. Its result must be collected with
+    /// [poll_completion](Controller::poll_completion).
+    ///
+    /// # Unsafety
+    ///
+    /// `buffer` must be a dword aligned buffer of at least `sector_count * 512` bytes, which must
+    /// stay [mapped](Dma::map) for the controller until the command is completed.
+    ///
+    /// # Error
+    ///
+    /// * getting the physical address of a page of `buffer` failed.
+    /// * IoError: the controller has failed.
+    ///
+    /// # Panics
+    ///
+    /// * `sector_count` is 0, or more than `max_sectors_per_command`.
+    pub unsafe fn poll_submit(&mut self, cx: &mut Context, transfer: Transfer, nsid: u32, buffer: usize, lba: u64, sector_count: u64) -> Poll<Result<usize, Error>> {
+        assert!(sector_count != 0 && sector_count <= self.max_sectors_per_command, "Invalid sector count {}", sector_count);
+        if self.failed {
+            return Poll::Ready(Err(AhciError::IoError.into()));
+        }
+        let slot = match self.slots.iter().position(|slot| matches!(slot, Slot::Free)) {
+            Some(slot) => slot,
+            None => {
+                self.slot_waiters.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+
+        let opcode = match transfer {
+            Transfer::Read => NVM_READ,
+            Transfer::Write => NVM_WRITE,
+        };
+        let mut command = Command::new(opcode, slot as u16);
+        command.nsid = nsid;
+        command.cdw10 = lba as u32;
+        command.cdw11 = (lba >> 32) as u32;
+        // NLB is 0's based.
+        command.cdw12 = (sector_count - 1) as u32;
+        match self.fill_prps(slot, buffer, sector_count as usize * 512) {
+            Ok((prp1, prp2)) => {
+                command.prp1 = prp1;
+                command.prp2 = prp2;
+            },
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+
+        self.io.submit(command);
+        self.slots[slot] = Slot::Submitted(None);
+        Poll::Ready(Ok(slot))
+    }
+
+    /// Gets the PRP entries of a command in `slot` transferring `length` bytes of `buffer`.
+    ///
+    /// PRP1 points to the data in the first page, PRP2 to the second page if the transfer
+    /// ends there, or to the PRP list of the slot, which we fill with the pages after the first
+    /// one. Each page is translated on its own, as the controller might access physical memory.
+    ///
+    /// # Error
+    ///
+    /// * getting the physical address of a page of `buffer` failed.
+    fn fill_prps(&mut self, slot: usize, buffer: usize, length: usize) -> Result<(u64, u64), Error> {
+        let dma = self.dma.lock();
+        let prp1 = dma.address(buffer)?;
+        let first_page_length = PAGE_SIZE - buffer % PAGE_SIZE;
+        if length <= first_page_length {
+            return Ok((prp1, 0));
+        }
+        let second_page = align_down(buffer, PAGE_SIZE) + PAGE_SIZE;
+        let pages = (length - first_page_length + PAGE_SIZE - 1) / PAGE_SIZE;
+        if pages == 1 {
+            return Ok((prp1, dma.address(second_page)?));
+        }
+        let (prp_list, prp_list_address) = &mut self.prp_lists[slot];
+        for (index, entry) in prp_list.0.iter_mut().take(pages).enumerate() {
+            *entry = dma.address(second_page + index * PAGE_SIZE)?;
+        }
+        Ok((prp1, *prp_list_address))
+    }
+
+    /// Collects the result of the command submitted in `slot`, releasing the slot.
+    ///
+    /// If the command is not completed yet, the task is registered to be woken up when it is.
+    ///
+    /// # Panics
+    ///
+    /// * No command was submitted in `slot`.
+    pub fn poll_completion(&mut self, cx: &mut Context, slot: usize) -> Poll<Result<(), Error>> {
+        match core::mem::replace(&mut self.slots[slot], Slot::Free) {
+            Slot::Completed(result) => {
+                for waker in self.slot_waiters.drain(..) {
+                    waker.wake();
+                }
+                Poll::Ready(result)
+            },
+            Slot::Submitted(_) => {
+                self.slots[slot] = Slot::Submitted(Some(cx.waker().clone()));
+                Poll::Pending
+            },
+            Slot::Free => panic!("Waiting for the completion of free slot {}", slot),
+        }
+    }
+
+    /// Handles the interrupts of the controller.
+    ///
+    /// Completes every command posted to the I/O completion queue, waking up the task waiting for
+    /// it, and acknowledges them, which de-asserts the interrupt.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the controller had no new completion, as the IRQ line may be shared with
+    /// other devices, and `Some(())` otherwise.
+    pub fn handle_interrupt(&mut self) -> Option<()> {
+        let mut completed_any = false;
+        while let Some(completion) = self.io.pop_completion() {
+            completed_any = true;
+            let result = if completion.is_success() {
+                Ok(())
+            } else {
+                error!("NVMe command failed: {:#x?}", completion);
+                Err(AhciError::IoError.into())
+            };
+            let slot = match self.slots.get_mut(usize::from(completion.command_id)) {
+                Some(slot) => slot,
+                None => {
+                    error!("NVMe completion of unknown command {}", completion.command_id);
+                    continue;
+                }
+            };
+            if let Slot::Submitted(waker) = slot {
+                let waker = waker.take();
+                *slot = Slot::Completed(result);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+        if !completed_any {
+            return None;
+        }
+        self.io.acknowledge_completions();
+        Some(())
+    }
+}
+
+/// Maps the queues and the identify buffer of a controller in its device address space, and
+/// allocates and maps a PRP list for each of its slots.
+///
+/// # Returns
+///
+/// The device addresses of the admin queues, of the I/O queues and of the identify buffer, and
+/// the PRP lists with their device addresses.
+///
+/// # Error
+///
+/// * mapping a structure, or getting its device address, failed. Nothing is left mapped.
+#[allow(clippy::type_complexity)]
+fn map_structures(dma: &mut Dma, admin: &QueuePair, io: &QueuePair, identify: &IdentifyOutput)
+                  -> Result<((u64, u64), (u64, u64), u64, Vec<(ZeroBox<PrpList>, u64)>), Error> {
+    admin.map(dma)?;
+    if let Err(e) = io.map(dma) {
+        admin.unmap(dma);
+        return Err(e);
+    }
+    if let Err(e) = dma.map(identify, size_of::<IdentifyOutput>(), MemoryPermissions::WRITABLE) {
+        io.unmap(dma);
+        admin.unmap(dma);
+        return Err(e);
+    }
+    let mut prp_lists = Vec::new();
+    for _ in 0..QUEUE_SIZE - 1 {
+        let prp_list = ZeroBox::<PrpList>::new_zeroed();
+        if let Err(e) = dma.map(&*prp_list, size_of::<PrpList>(), MemoryPermissions::READABLE) {
+            unmap_structures(dma, admin, io, identify, &prp_lists);
+            return Err(e);
+        }
+        prp_lists.push((prp_list, 0));
+    }
+
+    // every structure is a single page, so it is contiguous for the controller.
+    let addresses = admin.addresses(dma).and_then(|admin_addresses| {
+        let io_addresses = io.addresses(dma)?;
+        let identify_address = dma.address(identify as *const IdentifyOutput as usize)?;
+        for (prp_list, address) in prp_lists.iter_mut() {
+            *address = dma.address(&**prp_list as *const PrpList as usize)?;
+        }
+        Ok((admin_addresses, io_addresses, identify_address))
+    });
+    match addresses {
+        Ok((admin_addresses, io_addresses, identify_address)) => Ok((admin_addresses, io_addresses, identify_address, prp_lists)),
+        Err(e) => {
+            unmap_structures(dma, admin, io, identify, &prp_lists);
+            Err(e)
+        }
+    }
+}
+
+/// Unmaps the structures of a controller mapped by [map_structures].
+///
+/// The controller must not be using them anymore.
+fn unmap_structures(dma: &mut Dma, admin: &QueuePair, io: &QueuePair, identify: &IdentifyOutput, prp_lists: &[(ZeroBox<PrpList>, u64)]) {
+    for (prp_list, _) in prp_lists {
+        dma.unmap(&**prp_list, size_of::<PrpList>());
+    }
+    dma.unmap(identify, size_of::<IdentifyOutput>());
+    io.unmap(dma);
+    admin.unmap(dma);
+}
+
+impl Drop for Controller {
+    /// Dropping a controller disables it, and unmaps its structures.
+    fn drop(&mut self) {
+        if !self.disable(self.ready_timeout) {
+            // the controller might still be using them, leak them instead.
+            error!("NVMe controller failed disabling, leaking its structures");
+            return;
+        }
+        unmap_structures(&mut self.dma.lock(), &self.admin, &self.io, &self.identify, &self.prp_lists);
+    }
+}
+
+impl Debug for Controller {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("Controller")
+            .field("version", &self.registers.vs.read())
+            .field("max_sectors_per_command", &self.max_sectors_per_command)
+            .field("admin", &self.admin)
+            .field("io", &self.io)
+            .finish()
+    }
+}
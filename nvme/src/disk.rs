@@ -0,0 +1,314 @@
+//! NVMe namespace
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::mem::size_of;
+
+use spin::Mutex;
+
+use sunrise_libuser::error::{Error, AhciError};
+use sunrise_libuser::ahci::IDiskAsync as IDiskInterface;
+use sunrise_libuser::dma::Dma;
+use sunrise_libuser::futures::WorkQueue;
+use sunrise_libuser::futures_rs::future::{self, FutureObj};
+use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+use sunrise_libuser::syscalls::MemoryPermissions;
+use sunrise_libuser::types::{MappedSharedMemory, SharedMemory};
+use sunrise_libuser::ahci::Block;
+
+use crate::controller::{Controller, Transfer};
+
+/// A namespace of an NVMe controller, exposed as a disk.
+///
+/// Its commands are submitted to the I/O queues of its controller, shared with the other
+/// namespaces of the controller.
+#[derive(Debug)]
+pub struct Disk {
+    /// The controller of this namespace.
+    controller: Arc<Mutex<Controller>>,
+    /// The identifier of this namespace.
+    nsid: u32,
+    /// Number of addressable sectors of this disk. Each sector is 512 octets.
+    sectors: u64,
+}
+
+impl Disk {
+    /// Creates the disk of namespace `nsid` of `controller`, of `sectors` 512-octet sectors.
+    pub fn new(controller: Arc<Mutex<Controller>>, nsid: u32, sectors: u64) -> Disk {
+        Disk { controller, nsid, sectors }
+    }
+
+    /// Checks the arguments of a transfer of `sector_count` sectors starting from `lba`,
+    /// to or from a buffer of `buffer_len` bytes at `buffer`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - `buffer` is not dword aligned,
+    ///     - `buffer_len` is smaller than `sector_count` sectors,
+    ///     - `lba + sector_count` is higher than the number of addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    fn check_transfer(&self, buffer: usize, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
+        if sector_count == 0 || buffer % 4 != 0 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        if sector_count.checked_mul(512).filter(|size| *size <= (buffer_len as u64)).is_none() {
+            return Err(AhciError::InvalidArg.into());
+        }
+        if lba.checked_add(sector_count).filter(|sum| *sum <= self.sectors).is_none() {
+            return Err(AhciError::InvalidArg.into());
+        }
+        Ok(())
+    }
+}
+
+/// Reads or writes `sector_count` sectors starting from `lba`, to or from the `buffer_len` bytes
+/// at `buffer`.
+///
+/// The buffer is mapped in the device address space of the controller, and the transfer is split
+/// in as many commands as needed, submitted as slots are released. They are all processed
+/// concurrently, along with the commands submitted by other tasks.
+///
+/// The buffer must stay valid until the returned future is resolved.
+///
+/// # Error
+///
+/// - see [Disk::check_transfer].
+/// - mapping `buffer` in the device address space failed.
+/// - see [Controller::poll_submit](crate::controller::Controller::poll_submit).
+/// - IoError: the controller failed a command.
+async fn transfer_sectors(disk: Arc<Disk>, transfer: Transfer, buffer: usize, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
+    disk.check_transfer(buffer, buffer_len, lba, sector_count)?;
+    let length = sector_count as usize * 512;
+    let (dma, max_sectors) = {
+        let controller = disk.controller.lock();
+        (Arc::clone(controller.dma()), controller.max_sectors_per_command())
+    };
+    let perm = match transfer {
+        Transfer::Read => MemoryPermissions::WRITABLE,
+        Transfer::Write => MemoryPermissions::READABLE,
+    };
+    dma.lock().map(buffer as *const u8, length, perm)?;
+
+    let mut slots = Vec::new();
+    let mut result = Ok(());
+    let mut done = 0;
+    while done < sector_count {
+        let count = min(sector_count - done, max_sectors);
+        let submitted = future::poll_fn(|cx| unsafe {
+            // safe: the buffer is dword aligned, and stays mapped until every command is completed.
+            disk.controller.lock().poll_submit(cx, transfer, disk.nsid, buffer + done as usize * 512, lba + done, count)
+        }).await;
+        match submitted {
+            Ok(slot) => slots.push(slot),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+        done += count;
+    }
+    // wait for every command, even on error, the controller might still be using the buffer.
+    for slot in slots {
+        let completed = future::poll_fn(|cx| disk.controller.lock().poll_completion(cx, slot)).await;
+        if result.is_ok() {
+            result = completed;
+        }
+    }
+    // the commands are over, the controller is done with the buffer.
+    dma.lock().unmap(buffer as *const u8, length);
+    result
+}
+
+/// A buffer shared with a client, registered with
+/// [register_shared_buffer](IDiskInterface::register_shared_buffer).
+///
+/// It is mapped in our address space, and in the device address space of the controller for as
+/// long as it is registered, so the transfers using it don't have to map it again.
+#[derive(Debug)]
+struct SharedBuffer {
+    /// Our mapping of the shared memory.
+    memory: MappedSharedMemory,
+    /// The device address space of the controller.
+    dma: Arc<Mutex<Dma>>,
+}
+
+impl SharedBuffer {
+    /// Maps the `size` bytes of `buffer`, in our address space and in the device address space.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `size` is 0, or not page aligned.
+    /// - `size` is not the size of the shared memory.
+    /// - mapping it in the device address space failed.
+    fn new(buffer: SharedMemory, size: u64, dma: Arc<Mutex<Dma>>) -> Result<Self, Error> {
+        let size = size as usize;
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        let address = find_free_address(size, PAGE_SIZE)?;
+        let memory = buffer.map(address, size, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
+        // the controller both reads and writes it.
+        dma.lock().map(memory.as_ptr(), size, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
+        Ok(SharedBuffer { memory, dma })
+    }
+
+    /// Returns the address of the buffer at `offset`, and the number of bytes after it.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `offset` is out of the buffer.
+    fn range(&self, offset: u64) -> Result<(usize, usize), Error> {
+        if offset >= self.memory.len() as u64 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        Ok((self.memory.as_ptr() as usize + offset as usize, self.memory.len() - offset as usize))
+    }
+}
+
+impl Drop for SharedBuffer {
+    /// Unmaps the buffer from the device address space. No transfer can be using it, as they
+    /// hold a reference to it.
+    fn drop(&mut self) {
+        self.dma.lock().unmap(self.memory.as_ptr(), self.memory.len());
+    }
+}
+
+/// Transfers sectors to or from the shared buffer, at `offset`.
+///
+/// # Error
+///
+/// - InvalidArg: no buffer was registered, or `offset` is out of it.
+/// - see [transfer_sectors].
+async fn transfer_shared(disk: Arc<Disk>, shared_buffer: Option<Arc<SharedBuffer>>, transfer: Transfer, offset: u64, lba: u64, sector_count: u64) -> Result<(), Error> {
+    let shared_buffer = shared_buffer.ok_or(AhciError::InvalidArg)?;
+    let (buffer, buffer_len) = shared_buffer.range(offset)?;
+    // holding the shared buffer keeps it mapped until the transfer is over.
+    transfer_sectors(disk, transfer, buffer, buffer_len, lba, sector_count).await
+}
+
+/// Interface to a disk.
+///
+/// Each session has its own interface, and its own shared buffer.
+#[derive(Debug, Clone)]
+pub struct IDisk {
+    /// The disk this session accesses.
+    disk: Arc<Disk>,
+    /// The buffer registered by the client, if any.
+    shared_buffer: Option<Arc<SharedBuffer>>,
+}
+
+impl IDisk {
+    /// Creates an IDisk from the wrapped [Disk].
+    pub fn new(value: Arc<Disk>) -> Self {
+        Self { disk: value, shared_buffer: None }
+    }
+}
+
+impl IDiskInterface for IDisk {
+    /// Returns the number of addressable 512-octet sectors for this disk.
+    fn sector_count<'a>(&'a mut self, _work_queue: WorkQueue<'static>) -> FutureObj<'a, Result<u64, Error>> {
+        FutureObj::new(Box::new(future::ready(Ok(self.disk.sectors))))
+    }
+
+    /// Reads sectors from disk.
+    ///
+    /// Reads `sector_count` sectors starting from `lba`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the controller failed reading the sectors.
+    fn read_dma<'a>(&'a mut self, _work_queue: WorkQueue<'static>, address: u64, out_blocks: &'a mut [Block]) -> FutureObj<'a, Result<(), Error>> {
+        let disk = Arc::clone(&self.disk);
+        FutureObj::new(Box::new(async move {
+            let buffer = out_blocks.as_mut_ptr() as usize;
+            let buffer_len = out_blocks.len() * size_of::<Block>();
+            transfer_sectors(disk, Transfer::Read, buffer, buffer_len, address, out_blocks.len() as u64).await
+        }))
+    }
+
+    /// Writes sectors to disk.
+    ///
+    /// Writes `sector_count` sectors starting from `lba`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the controller failed writing the sectors.
+    fn write_dma<'a>(&'a mut self, _work_queue: WorkQueue<'static>, address: u64, in_blocks: &'a [Block]) -> FutureObj<'a, Result<(), Error>> {
+        let disk = Arc::clone(&self.disk);
+        FutureObj::new(Box::new(async move {
+            let buffer = in_blocks.as_ptr() as usize;
+            let buffer_len = in_blocks.len() * size_of::<Block>();
+            transfer_sectors(disk, Transfer::Write, buffer, buffer_len, address, in_blocks.len() as u64).await
+        }))
+    }
+
+    /// Registers a buffer shared with the client, for [read_shared] and [write_shared].
+    ///
+    /// It replaces the previously registered buffer, which is released once the transfers
+    /// using it are over.
+    ///
+    /// [read_shared]: IDiskInterface::read_shared
+    /// [write_shared]: IDiskInterface::write_shared
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `size` is 0, or not page aligned.
+    /// - `size` is not the size of the shared memory.
+    /// - mapping it in the device address space failed.
+    fn register_shared_buffer<'a>(&'a mut self, _work_queue: WorkQueue<'static>, buffer: SharedMemory, size: u64) -> FutureObj<'a, Result<(), Error>> {
+        let dma = Arc::clone(self.disk.controller.lock().dma());
+        let registered = SharedBuffer::new(buffer, size, dma).map(|shared_buffer| {
+            self.shared_buffer = Some(Arc::new(shared_buffer));
+        });
+        FutureObj::new(Box::new(future::ready(registered)))
+    }
+
+    /// Reads sectors from disk to the shared buffer.
+    ///
+    /// Reads `sector_count` sectors starting from `lba`, to the shared buffer at `offset`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - no buffer was registered,
+    ///     - `offset` is not dword aligned,
+    ///     - `offset + sector_count * 512` is out of the shared buffer,
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the controller failed reading the sectors.
+    fn read_shared<'a>(&'a mut self, _work_queue: WorkQueue<'static>, offset: u64, lba: u64, sector_count: u64) -> FutureObj<'a, Result<(), Error>> {
+        let transfer = transfer_shared(Arc::clone(&self.disk), self.shared_buffer.clone(), Transfer::Read, offset, lba, sector_count);
+        FutureObj::new(Box::new(transfer))
+    }
+
+    /// Writes sectors to disk from the shared buffer.
+    ///
+    /// Writes `sector_count` sectors starting from `lba`, from the shared buffer at `offset`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - no buffer was registered,
+    ///     - `offset` is not dword aligned,
+    ///     - `offset + sector_count * 512` is out of the shared buffer,
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the controller failed writing the sectors.
+    fn write_shared<'a>(&'a mut self, _work_queue: WorkQueue<'static>, offset: u64, lba: u64, sector_count: u64) -> FutureObj<'a, Result<(), Error>> {
+        let transfer = transfer_shared(Arc::clone(&self.disk), self.shared_buffer.clone(), Transfer::Write, offset, lba, sector_count);
+        FutureObj::new(Box::new(transfer))
+    }
+}
@@ -0,0 +1,305 @@
+//! NVMe driver module
+//!
+//! This driver discovers NVMe controllers on the PCI, initialize them,
+//! and exposes IPC endpoints to read and write sectors from/to their namespaces.
+//!
+//! In QEMU, an NVMe controller with a single namespace is attached with
+//! `-drive file=nvme.img,if=none,format=raw,id=nvm -device nvme,serial=deadbeef,drive=nvm`.
+//!
+//! # Features
+//!
+//! Every active namespace whose logical blocks are 512 octets long is exposed as a disk.
+//!
+//! Here's a list of wonderful features this driver does **not** provide:
+//!
+//! - hotplug/remove of a controller or of a namespace
+//! - MSI and MSI-X, we use the legacy interrupt line
+//! - more than one pair of I/O queues per controller
+//! - namespaces with other logical block sizes, or with metadata
+//! - flush, dataset management and write zeroes commands
+//!
+//! # Interface
+//!
+//! This driver exposes the same IPC interfaces as the AHCI driver: [NvmeInterface],
+//! registered as `"nvme:\0"`, and some [IDisk]s.
+//!
+//! Basically at initialization the driver will assign an id to every discovered namespace.
+//! You can then ask the [NvmeInterface] to give you a session to any [IDisk] from its id,
+//! and finally read/write some sectors.
+//!
+//! # DMA
+//!
+//! A controller only accesses the memory we map in its device address space, at the address
+//! it has in ours. Without an IOMMU, it accesses physical memory instead. See [Dma].
+//!
+//! # Parallelism
+//!
+//! This driver is single-threaded, but asynchronous: every [IDisk] session is served by its own
+//! future on the [WaitableManager], and a command is submitted to the controller without
+//! blocking.
+//!
+//! As many commands can be outstanding on a controller as fit in its I/O submission queue,
+//! shared by all its namespaces. The controller raises an interrupt when it completes some.
+//! For each controller, a future waits on its interrupt event, and completes its commands,
+//! waking up the tasks that were waiting for them.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![deny(missing_docs)]
+#![deny(clippy::missing_docs_in_private_items)]
+#![deny(intra_doc_link_resolution_failure)]
+
+extern crate alloc;
+#[macro_use]
+extern crate sunrise_libuser;
+#[macro_use]
+extern crate log;
+
+mod queue;
+mod controller;
+mod disk;
+
+use crate::controller::Controller;
+use crate::disk::{Disk, IDisk};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use sunrise_libuser::error::{Error, AhciError};
+use sunrise_libuser::dma::Dma;
+use sunrise_libuser::futures::{WaitableManager, WorkQueue};
+use sunrise_libuser::ipc::server::{port_handler, new_session_wrapper};
+use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+use sunrise_libuser::types::ReadableEvent;
+use spin::Mutex;
+use sunrise_libuser::syscalls;
+use sunrise_libuser::pci::{self, PciDevice, BAR};
+use sunrise_libuser::ahci::{AhciInterface as IAhciInterface, IDiskProxy, IDiskAsync as _};
+use sunrise_libuser::futures_rs::future::FutureObj;
+use sunrise_libutils::align_up;
+
+/// Array of discovered disk.
+///
+/// At startup, the driver will initialize each controller it discovers,
+/// and populate this vec with their namespaces.
+///
+/// As hotplug/remove of a disk is not supported, this array remains
+/// unchanged for the rest of the driver's execution.
+///
+/// A disk id is just the index of a disk in this array.
+static DISKS: Mutex<Vec<Arc<Disk>>> = Mutex::new(Vec::new());
+
+/// NVMe driver initialisation.
+///
+/// 1. Discover NVMe controllers on the PCI.
+/// 2. For every found controller:
+///     - Subscribe to its interrupt line.
+///     - Initialize it, and push a [Disk] for each of its namespaces in [DISKS].
+/// 3. Start the event loop, handling the interrupts of every controller.
+///
+/// The `"nvme:\0"` port is registered even if no controller was found, as clients wait for it.
+fn main() {
+    debug!("NVMe driver starting up");
+    let mut controllers = Vec::new();
+    for device in pci::discover().iter()
+        .filter(|device| device.class == 0x01 && device.subclass == 0x08 && device.prog_if == 0x02)
+    {
+        let (bar0, bar0_size) = match map_bar0(device) {
+            Some(bar0) => bar0,
+            None => continue,
+        };
+        let irq = match device.interrupt_line() {
+            Some(irq) if irq != 0xFF => irq,
+            _ => {
+                error!("NVMe {:#010x}, initialization failed: no interrupt line.", bar0);
+                continue;
+            }
+        };
+        let irq_event = match syscalls::create_interrupt_event(irq as usize, 0) {
+            Ok(irq_event) => irq_event,
+            Err(e) => {
+                error!("NVMe {:#010x}, initialization failed: failed subscribing to IRQ {}, {:?}.", bar0, irq, e);
+                continue;
+            }
+        };
+        device.enable_bus_master();
+        device.enable_interrupts();
+        let dma = match Dma::new(device.requester_id()) {
+            Ok(dma) => dma,
+            Err(e) => {
+                error!("NVMe {:#010x}, initialization failed: failed attaching it to a device address space, {:?}.", bar0, e);
+                continue;
+            }
+        };
+        let controller = unsafe {
+            // safe: we just mapped BAR0, and never unmap it.
+            Controller::init(bar0, bar0_size, dma)
+        };
+        if let Some(mut controller) = controller {
+            let namespaces = controller.namespaces();
+            controller.enable_interrupts();
+            let controller = Arc::new(Mutex::new(controller));
+            DISKS.lock().extend(namespaces.into_iter()
+                .map(|(nsid, sectors)| Arc::new(Disk::new(Arc::clone(&controller), nsid, sectors))));
+            controllers.push((irq_event, controller));
+        }
+    }
+    debug!("NVMe initialised disks : {:#x?}", DISKS);
+
+    // event loop
+    let mut man = WaitableManager::new();
+    let handler = port_handler(man.work_queue(), "nvme:\0", NvmeInterface::dispatch).unwrap();
+    man.work_queue().spawn(FutureObj::new(Box::new(handler)));
+    for (irq_event, controller) in controllers {
+        let interrupts_future = handle_interrupts(man.work_queue(), irq_event, controller);
+        man.work_queue().spawn(FutureObj::new(Box::new(interrupts_future)));
+    }
+    man.run();
+}
+
+/// Maps the BAR0 of a controller, where its registers are found.
+///
+/// Returns the address it was mapped at, and its size. The mapping is never unmapped.
+///
+/// # Error
+///
+/// Returns `None` and logs the reason if the BAR is not a memory space BAR, or is above 4GiB.
+fn map_bar0(device: &PciDevice) -> Option<(usize, usize)> {
+    let (address, size) = match device.bar(0) {
+        Some(BAR::Memory(address, size)) => (address as usize, size as usize),
+        Some(BAR::Memory64(address, size)) if address.saturating_add(size) <= 0x1_0000_0000 => (address as usize, size as usize),
+        bar => {
+            error!("Device {:?}: cannot map BAR0 {:x?}.", device.requester_id(), bar);
+            return None
+        }
+    };
+    let mapping_size = align_up(size, PAGE_SIZE);
+    let mapped = find_free_address(mapping_size, PAGE_SIZE)
+        .and_then(|virtual_address| {
+            syscalls::map_mmio_region(address, mapping_size, virtual_address, true)?;
+            Ok(virtual_address)
+        });
+    match mapped {
+        Ok(virtual_address) => Some((virtual_address, size)),
+        Err(e) => {
+            error!("Device {:?}: failed mapping BAR0, {:?}.", device.requester_id(), e);
+            None
+        }
+    }
+}
+
+/// Task responsible for handling the interrupts of a controller, completing the commands of its
+/// namespaces.
+// https://github.com/rust-lang/rust-clippy/issues/3988
+// Should remove on next toolchain upgrade.
+#[allow(clippy::needless_lifetimes)]
+async fn handle_interrupts(work_queue: WorkQueue<'_>, irq_event: ReadableEvent, controller: Arc<Mutex<Controller>>) {
+    loop {
+        irq_event.wait_async_cb(work_queue.clone(), || controller.lock().handle_interrupt()).await;
+    }
+}
+
+/// Main interface to the NVMe driver.
+///
+/// Registered under the name `"nvme:\0"` to the Service Manager, after the discovery stage.
+///
+/// Provides an endpoint to get the number of discovered disks,
+/// and another one to get a session to a given disk.
+///
+/// As hotplug/remove of a disk is not supported, a disk id remains valid for the whole
+/// lifetime of the NVMe driver.
+#[derive(Default, Debug, Clone)]
+struct NvmeInterface;
+
+impl IAhciInterface for NvmeInterface {
+    /// Returns the number of discovered disks.
+    ///
+    /// Any number in the range `0..disk_count()` is considered a valid disk id.
+    fn discovered_disks_count(&mut self, _manager: WorkQueue<'static>) -> Result<u32, Error> {
+        Ok(DISKS.lock().len() as u32)
+    }
+
+    /// Gets the interface to a disk.
+    ///
+    /// This creates a session to an [IDisk].
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `disk_id` is not a valid disk id.
+    fn get_disk(&mut self, work_queue: WorkQueue<'static>, disk_id: u32,) -> Result<IDiskProxy, Error> {
+        let idisk = IDisk::new(Arc::clone(
+            DISKS.lock().get(disk_id as usize)
+            .ok_or(AhciError::InvalidArg)?
+        ));
+        let (server, client) = syscalls::create_session(false, 0)?;
+        let wrapper = new_session_wrapper(work_queue.clone(), server, idisk, IDisk::dispatch);
+        work_queue.spawn(FutureObj::new(Box::new(wrapper)));
+        Ok(IDiskProxy::from(client))
+    }
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"nvme\0\0\0\0\0\0\0\0",
+    title_id: 0x0200000000000102,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::CreateSharedMemory,
+        sunrise_libuser::syscalls::nr::MapSharedMemory,
+        sunrise_libuser::syscalls::nr::UnmapSharedMemory,
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::CreateInterruptEvent,
+        sunrise_libuser::syscalls::nr::CreateDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::AttachDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::MapDeviceAddressSpaceByForce,
+        sunrise_libuser::syscalls::nr::UnmapDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::QueryPhysicalAddress,
+        sunrise_libuser::syscalls::nr::MapMmioRegion,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+        sunrise_libuser::syscalls::nr::ReplyAndReceiveWithUserBuffer,
+        sunrise_libuser::syscalls::nr::AcceptSession,
+        sunrise_libuser::syscalls::nr::CreateSession,
+    ],
+    raw_caps: [
+        // todo: IRQ capabilities at runtime
+        // body: Currently IRQ capabilities are declared at compile-time.
+        // body:
+        // body: However, for PCI, the IRQ line we want to subscribe to
+        // body: can only be determined at runtime by reading the `Interrupt Line` register
+        // body: that has been set-up during POST.
+        // body:
+        // body: For now we declare the lines the BIOS usually routes PCI interrupts to.
+        sunrise_libuser::caps::irq_pair(5, 9), sunrise_libuser::caps::irq_pair(10, 11),
+        sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 0), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 1), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 2), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 3),
+        sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 0), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 1), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 2), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 3),
+        // NVMe controllers.
+        sunrise_libuser::caps::pci_class(0x01, 0x08),
+    ]
+});
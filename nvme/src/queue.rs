@@ -0,0 +1,260 @@
+//! Submission and completion queues
+//!
+//! The host submits commands to a controller by writing them to a submission queue, and ringing
+//! its tail doorbell. The controller posts their completions to a completion queue, toggling
+//! their phase tag, and the host acknowledges them by ringing the queue's head doorbell.
+//!
+//! We always pair a submission queue with its own completion queue, of the same size.
+//! See NVMe 1.4 spec section 4.1.
+
+use core::fmt::{self, Debug, Formatter};
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use sunrise_libuser::dma::Dma;
+use sunrise_libuser::error::Error;
+use sunrise_libuser::io::{Io, Mmio};
+use sunrise_libuser::syscalls::MemoryPermissions;
+use sunrise_libuser::zero_box::{ZeroBox, ZeroInitialized};
+use static_assertions::assert_eq_size;
+
+/// The number of entries of every queue we create.
+///
+/// A submission queue of 64 entries fills exactly a page.
+pub const QUEUE_SIZE: usize = 64;
+
+/// A submission queue entry.
+///
+/// See NVMe 1.4 spec section 4.2.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Command {
+    /// Opcode in bits 0-7, and command identifier in bits 16-31.
+    pub cdw0: u32,
+    /// The namespace the command applies to, or 0.
+    pub nsid: u32,
+    /// Reserved.
+    pub reserved: u64,
+    /// Metadata pointer, unused.
+    pub mptr: u64,
+    /// First PRP entry: the address of the data.
+    pub prp1: u64,
+    /// Second PRP entry: the address of the second page of the data, or of a PRP list.
+    pub prp2: u64,
+    /// Command specific dword 10.
+    pub cdw10: u32,
+    /// Command specific dword 11.
+    pub cdw11: u32,
+    /// Command specific dword 12.
+    pub cdw12: u32,
+    /// Command specific dword 13.
+    pub cdw13: u32,
+    /// Command specific dword 14.
+    pub cdw14: u32,
+    /// Command specific dword 15.
+    pub cdw15: u32,
+}
+
+assert_eq_size!(Command, [u8; 64]);
+
+impl Command {
+    /// Creates a command with the given opcode and identifier, all its other fields cleared.
+    pub fn new(opcode: u8, command_id: u16) -> Command {
+        Command {
+            cdw0: u32::from(opcode) | u32::from(command_id) << 16,
+            ..Command::default()
+        }
+    }
+}
+
+/// A completion queue entry.
+///
+/// See NVMe 1.4 spec section 4.6.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Completion {
+    /// Command specific dword 0.
+    pub dw0: u32,
+    /// Reserved.
+    pub dw1: u32,
+    /// The head of the submission queue, once the controller fetched this command.
+    pub sq_head: u16,
+    /// The submission queue of the command.
+    pub sq_id: u16,
+    /// The identifier of the command.
+    pub command_id: u16,
+    /// Phase tag in bit 0, and status field in bits 1-15.
+    pub status: u16,
+}
+
+assert_eq_size!(Completion, [u8; 16]);
+
+impl Completion {
+    /// The phase tag, toggled by the controller every time it wraps around the queue.
+    fn phase(&self) -> bool {
+        self.status & 1 != 0
+    }
+
+    /// Whether the command completed successfully: its status code and status code type are 0.
+    pub fn is_success(&self) -> bool {
+        self.status >> 1 == 0
+    }
+}
+
+/// The entries of a submission queue, page aligned.
+#[repr(C, align(4096))]
+struct SubmissionEntries([Command; QUEUE_SIZE]);
+unsafe impl ZeroInitialized for SubmissionEntries {}
+
+/// The entries of a completion queue, page aligned.
+#[repr(C, align(4096))]
+struct CompletionEntries([Completion; QUEUE_SIZE]);
+unsafe impl ZeroInitialized for CompletionEntries {}
+
+/// A submission queue, and its completion queue.
+///
+/// The queues are allocated with the pair, and must be mapped in the device address space of the
+/// controller before it is told about them, see [map](QueuePair::map).
+pub struct QueuePair {
+    /// The identifier of both queues, 0 for the admin queues.
+    id: u16,
+    /// The submission queue.
+    submission: ZeroBox<SubmissionEntries>,
+    /// The completion queue.
+    completion: ZeroBox<CompletionEntries>,
+    /// The index of the next submission queue entry we will write.
+    submission_tail: u16,
+    /// The index of the next completion queue entry we will read.
+    completion_head: u16,
+    /// The phase tag of the completion entries posted in this pass through the queue.
+    phase: bool,
+    /// The address of the submission queue tail doorbell.
+    submission_doorbell: usize,
+    /// The address of the completion queue head doorbell.
+    completion_doorbell: usize,
+}
+
+impl Debug for QueuePair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("QueuePair")
+            .field("id", &self.id)
+            .field("submission_tail", &self.submission_tail)
+            .field("completion_head", &self.completion_head)
+            .field("phase", &self.phase)
+            .finish()
+    }
+}
+
+impl QueuePair {
+    /// Allocates the queues `id`, whose doorbells are found after the controller registers at
+    /// `doorbells`, `stride` bytes apart.
+    ///
+    /// # Unsafety
+    ///
+    /// `doorbells` must be the address of the doorbells of a controller, which must stay mapped
+    /// for the lifetime of the pair.
+    pub unsafe fn new(id: u16, doorbells: usize, stride: usize) -> QueuePair {
+        QueuePair {
+            id,
+            submission: ZeroBox::new_zeroed(),
+            completion: ZeroBox::new_zeroed(),
+            submission_tail: 0,
+            completion_head: 0,
+            // the controller posts the first pass with the phase tag set.
+            phase: true,
+            submission_doorbell: doorbells + (2 * usize::from(id)) * stride,
+            completion_doorbell: doorbells + (2 * usize::from(id) + 1) * stride,
+        }
+    }
+
+    /// Returns the identifier of both queues.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Maps both queues in the device address space of the controller.
+    ///
+    /// # Error
+    ///
+    /// * mapping the queues failed. Nothing is left mapped.
+    pub fn map(&self, dma: &mut Dma) -> Result<(), Error> {
+        dma.map(&*self.submission, size_of::<SubmissionEntries>(), MemoryPermissions::READABLE)?;
+        if let Err(e) = dma.map(&*self.completion, size_of::<CompletionEntries>(), MemoryPermissions::WRITABLE) {
+            dma.unmap(&*self.submission, size_of::<SubmissionEntries>());
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Returns the device addresses of the submission queue, and of the completion queue.
+    ///
+    /// Each queue fits in a page, so it is contiguous for the controller.
+    ///
+    /// # Error
+    ///
+    /// * the queues are not mapped.
+    pub fn addresses(&self, dma: &Dma) -> Result<(u64, u64), Error> {
+        let submission = dma.address(&*self.submission as *const SubmissionEntries as usize)?;
+        let completion = dma.address(&*self.completion as *const CompletionEntries as usize)?;
+        Ok((submission, completion))
+    }
+
+    /// Unmaps both queues from the device address space of the controller.
+    ///
+    /// The controller must not be using them anymore.
+    pub fn unmap(&self, dma: &mut Dma) {
+        dma.unmap(&*self.completion, size_of::<CompletionEntries>());
+        dma.unmap(&*self.submission, size_of::<SubmissionEntries>());
+    }
+
+    /// Writes `command` to the submission queue, and rings its tail doorbell.
+    ///
+    /// There must be at most `QUEUE_SIZE - 1` outstanding commands, or the queue would overflow.
+    ///
+    /// # Unsafety
+    ///
+    /// The buffers of the command must stay mapped until it is completed.
+    pub unsafe fn submit(&mut self, command: Command) {
+        ptr::write_volatile(&mut self.submission.0[usize::from(self.submission_tail)], command);
+        self.submission_tail = (self.submission_tail + 1) % QUEUE_SIZE as u16;
+        // the controller must see the command before the new tail.
+        fence(Ordering::SeqCst);
+        (*(self.submission_doorbell as *mut Mmio<u32>)).write(u32::from(self.submission_tail));
+    }
+
+    /// Takes the next completion the controller posted, if any.
+    ///
+    /// The entries are only released to the controller by
+    /// [acknowledge_completions](QueuePair::acknowledge_completions).
+    pub fn pop_completion(&mut self) -> Option<Completion> {
+        let completion = unsafe {
+            // safe: the entry is in our completion queue, and is a plain struct.
+            ptr::read_volatile(&self.completion.0[usize::from(self.completion_head)])
+        };
+        if completion.phase() != self.phase {
+            return None;
+        }
+        // read the rest of the entry after its phase tag.
+        fence(Ordering::SeqCst);
+        let completion = unsafe {
+            // safe: see above.
+            ptr::read_volatile(&self.completion.0[usize::from(self.completion_head)])
+        };
+        self.completion_head = (self.completion_head + 1) % QUEUE_SIZE as u16;
+        if self.completion_head == 0 {
+            self.phase = !self.phase;
+        }
+        Some(completion)
+    }
+
+    /// Rings the completion queue head doorbell, releasing the entries we popped to the
+    /// controller.
+    ///
+    /// The controller de-asserts its interrupt once every posted entry is acknowledged.
+    pub fn acknowledge_completions(&mut self) {
+        unsafe {
+            // safe: our creator guarantees the doorbells stay mapped.
+            (*(self.completion_doorbell as *mut Mmio<u32>)).write(u32::from(self.completion_head));
+        }
+    }
+}
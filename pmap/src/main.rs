@@ -0,0 +1,126 @@
+//! Process memory map
+//!
+//! Walks the address space of another process, printing every region it has
+//! mapped: its address range, size, permissions, type and attributes.
+//!
+//! The process handle is obtained from the loader, and its memory is queried
+//! with QueryProcessMemory, which requires the `can_debug_others` debug flag,
+//! and the inspected process to have the `can_be_debugged` one. The loader
+//! gives the latter to every process it starts.
+//!
+//! Usage: `pmap <pid>`
+
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+use std::os::sunrise::prelude::*;
+use std::{env, process};
+
+use sunrise_libkern::{MemoryInfo, MemoryPermissions, MemoryType};
+use sunrise_libuser::caps;
+use sunrise_libuser::error::Error;
+use sunrise_libuser::ldr::ILoaderInterfaceProxy;
+use sunrise_libuser::syscalls;
+use sunrise_libuser::types::Process;
+
+/// Usage string.
+static USAGE: &str = "usage: pmap <pid>";
+
+/// Formats permissions as `rwx`.
+fn format_perms(perms: MemoryPermissions) -> String {
+    let mut out = String::new();
+    out.push(if perms.contains(MemoryPermissions::READABLE) { 'r' } else { '-' });
+    out.push(if perms.contains(MemoryPermissions::WRITABLE) { 'w' } else { '-' });
+    out.push(if perms.contains(MemoryPermissions::EXECUTABLE) { 'x' } else { '-' });
+    out
+}
+
+/// Gets every mapped region of the process, in address order.
+fn regions(process: &Process) -> Result<Vec<MemoryInfo>, Error> {
+    let mut regions = Vec::new();
+    let mut addr = 0usize;
+    loop {
+        let (info, _) = syscalls::query_process_memory(process, addr)?;
+        if info.memtype.ty() != MemoryType::Unmapped {
+            regions.push(info);
+        }
+        // The last region ends at the top of the address space.
+        match info.baseaddr.checked_add(info.size) {
+            Some(next) if next > addr => addr = next,
+            _ => return Ok(regions),
+        }
+    }
+}
+
+/// Prints the memory map of the process `pid`.
+fn pmap(pid: u64) -> Result<(), Error> {
+    let loader = ILoaderInterfaceProxy::raw_new()?;
+    let mut name = [0; 32];
+    let copied_len = loader.get_name(pid, &mut name)?;
+    let process = loader.get_process_handle(pid)?;
+
+    println!("{}: {}", pid, String::from_utf8_lossy(&name[..copied_len as usize]));
+    println!("{:<21} {:>10} {:<4} {:<22} Attributes", "Address", "Size", "Perm", "Type");
+    let mut total = 0;
+    for region in regions(&process)? {
+        println!("{:#010x}-{:#010x} {:>6} KiB {:<4} {:<22} {:?}",
+                 region.baseaddr, region.baseaddr.wrapping_add(region.size),
+                 region.size / 1024, format_perms(region.perms),
+                 format!("{:?}", region.memtype.ty()), region.memattr);
+        total += region.size;
+    }
+    println!("Total: {} KiB", total / 1024);
+    Ok(())
+}
+
+/// The entry point of the program.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let pid = match args.get(1).map(|pid| pid.parse()) {
+        Some(Ok(pid)) if args.len() == 2 => pid,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = pmap(pid) {
+        eprintln!("pmap: {:?}", err);
+        process::exit(1);
+    }
+}
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        nr::SleepThread,
+        nr::ExitProcess,
+        nr::ExitProcessWithCode,
+        nr::CreateThread,
+        nr::StartThread,
+        nr::MapStackMirror,
+        nr::UnmapStackMirror,
+        nr::ExitThread,
+        nr::CloseHandle,
+        nr::WaitSynchronization,
+        nr::OutputDebugString,
+        nr::SetThreadArea,
+
+        nr::ConnectToNamedPort,
+        nr::SetHeapSize,
+        nr::SendSyncRequestWithUserBuffer,
+        nr::QueryMemory,
+        nr::CreateSharedMemory,
+        nr::MapSharedMemory,
+        nr::UnmapSharedMemory,
+
+        nr::QueryProcessMemory,
+    ],
+    raw_caps: [caps::debug_flags(false, true)],
+});
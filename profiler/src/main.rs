@@ -0,0 +1,376 @@
+//! Sampling profiler
+//!
+//! Drives the kernel's sampling profiler, and turns the samples it records
+//! into a folded-stack file that can be fed to `flamegraph.pl` or `inferno` on
+//! the host.
+//!
+//! Each sample is symbolized using the symbol table of the ELF it was taken
+//! in: the kernel ELF for kernel samples, and the process' own ELF for
+//! userspace ones. ELFs are looked up on the filesystem, in
+//! `/bin/<name>/main` for titles started by the loader, and in
+//! `/boot/sunrise-<name>` for kernel builtins and the kernel itself.
+//!
+//! Usage:
+//!
+//! - `profiler start`: starts recording samples.
+//! - `profiler stop [-t] [OUTPUT]`: stops recording, and writes the samples
+//!   recorded since `start` to OUTPUT.
+//! - `profiler record SECONDS [-t] [OUTPUT]`: records samples for the given
+//!   number of seconds, and writes them to OUTPUT.
+//!
+//! OUTPUT defaults to `/profile.folded`. When `-t` is passed, samples are
+//! additionally split per thread.
+
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+use std::os::sunrise::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::rc::Rc;
+use std::time::Duration;
+use std::{env, fs, thread};
+
+use sunrise_libuser::ldr::ILoaderInterfaceProxy;
+use sunrise_libuser::syscalls::{self, InfoType, ProfilerCommand, ProfilerSample};
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::Entry;
+
+/// Where the folded stacks are written when no output is given.
+const DEFAULT_OUTPUT: &str = "system:/profile.folded";
+
+/// Path of the kernel ELF.
+const KERNEL_ELF: &str = "system:/boot/sunrise-kernel";
+
+/// Time between two drains of the kernel buffer while recording.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of samples drained from the kernel at once.
+const DRAIN_BATCH: usize = 512;
+
+/// A function symbol of an ELF.
+#[derive(Debug)]
+struct Symbol {
+    /// Address of the first instruction of the function, relative to the
+    /// image base.
+    start: u64,
+    /// Size of the function, in bytes.
+    size: u64,
+    /// Demangled name of the function.
+    name: String,
+}
+
+/// The symbol table of an ELF, sorted by address.
+#[derive(Debug, Default)]
+struct SymbolTable(Vec<Symbol>);
+
+impl SymbolTable {
+    /// Loads the `.symtab` of the ELF at the given path.
+    ///
+    /// Returns None if the file doesn't exist, isn't an ELF, or was stripped.
+    fn load(path: &str) -> Option<SymbolTable> {
+        let data = fs::read(path).ok()?;
+        let elf = ElfFile::new(&data).ok()?;
+        let symtab = match elf.find_section_by_name(".symtab")?.get_data(&elf).ok()? {
+            SectionData::SymbolTable32(symtab) => symtab,
+            _ => return None
+        };
+
+        let mut symbols: Vec<Symbol> = symtab.iter()
+            .filter(|entry| entry.size() != 0)
+            .filter_map(|entry| Some(Symbol {
+                start: entry.value(),
+                size: entry.size(),
+                // Some demangled names contain `;`, which would be mistaken for
+                // a frame separator in the folded output.
+                name: format!("{:#}", rustc_demangle::demangle(entry.get_name(&elf).ok()?)).replace(';', ":"),
+            }))
+            .collect();
+        symbols.sort_by_key(|symbol| symbol.start);
+        Some(SymbolTable(symbols))
+    }
+
+    /// Finds the function containing the given image-relative address.
+    fn lookup(&self, addr: u64) -> Option<&str> {
+        let idx = match self.0.binary_search_by_key(&addr, |symbol| symbol.start) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let symbol = &self.0[idx];
+        if addr < symbol.start + symbol.size {
+            Some(&symbol.name)
+        } else {
+            None
+        }
+    }
+}
+
+/// What we know about a sampled process.
+#[derive(Debug)]
+struct ProcessInfo {
+    /// Name of the process, used as the root frame of its stacks.
+    name: String,
+    /// Address the process' ELF was loaded at.
+    base: u64,
+    /// Symbols of the process' ELF, if it could be found.
+    symbols: Option<Rc<SymbolTable>>,
+}
+
+/// Turns samples into folded stacks, caching process information and symbol
+/// tables along the way.
+#[derive(Debug)]
+struct Symbolizer {
+    /// Connection to the loader, used to get the name of the titles it started.
+    loader: Option<ILoaderInterfaceProxy>,
+    /// Symbols of the kernel.
+    kernel_symbols: Option<SymbolTable>,
+    /// Information about the processes seen so far, by pid.
+    processes: HashMap<u32, ProcessInfo>,
+    /// Symbol tables loaded so far, by process name.
+    symbol_tables: HashMap<String, Option<Rc<SymbolTable>>>,
+    /// Whether to add a frame for the thread below the process frame.
+    per_thread: bool,
+    /// Number of samples of every folded stack.
+    folded: BTreeMap<String, u64>,
+}
+
+impl Symbolizer {
+    /// Creates a new, empty, Symbolizer.
+    fn new(per_thread: bool) -> Symbolizer {
+        let kernel_symbols = SymbolTable::load(KERNEL_ELF);
+        if kernel_symbols.is_none() {
+            eprintln!("profiler: no symbols for the kernel, expected them in {}", KERNEL_ELF);
+        }
+
+        Symbolizer {
+            loader: ILoaderInterfaceProxy::raw_new().ok(),
+            kernel_symbols,
+            processes: HashMap::new(),
+            symbol_tables: HashMap::new(),
+            per_thread,
+            folded: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the name of a process, asking the loader first, as it knows the
+    /// full title name, and the kernel otherwise.
+    fn process_name(&self, pid: u32) -> Option<String> {
+        let mut name = [0; 32];
+        if let Some(loader) = &self.loader {
+            if let Ok(copied_len) = loader.get_name(u64::from(pid), &mut name) {
+                return Some(String::from_utf8_lossy(&name[..copied_len as usize]).into_owned());
+            }
+        }
+        let copied_len = syscalls::get_process_name(u64::from(pid), &mut name).ok()?;
+        Some(String::from_utf8_lossy(&name[..copied_len]).into_owned())
+    }
+
+    /// Fetches the information about a process, if it wasn't already.
+    ///
+    /// Processes must be looked up while they're alive. Those that died before
+    /// their first sample was processed are named after their pid, and are
+    /// left unsymbolized.
+    fn fetch_process_info(&mut self, pid: u32) {
+        if !self.processes.contains_key(&pid) {
+            let name = self.process_name(pid);
+            let base = syscalls::get_info(InfoType::ProcessImageBase, None, u64::from(pid)).unwrap_or(0);
+            let symbols = match &name {
+                Some(name) => self.symbol_tables.entry(name.clone())
+                    .or_insert_with(|| {
+                        let symbols = SymbolTable::load(&format!("system:/bin/{}/main", name))
+                            .or_else(|| SymbolTable::load(&format!("system:/boot/sunrise-{}", name)));
+                        if symbols.is_none() {
+                            eprintln!("profiler: no symbols for {}", name);
+                        }
+                        symbols.map(Rc::new)
+                    })
+                    .clone(),
+                None => None,
+            };
+            let name = name.unwrap_or_else(|| format!("pid-{}", pid));
+            self.processes.insert(pid, ProcessInfo { name, base, symbols });
+        }
+    }
+
+    /// Symbolizes a sample and accounts it in its folded stack.
+    fn add(&mut self, sample: &ProfilerSample) {
+        self.fetch_process_info(sample.pid);
+        let info = &self.processes[&sample.pid];
+
+        let mut stack = info.name.clone();
+        if self.per_thread {
+            let _ = write!(stack, ";thread-{}", sample.tid);
+        }
+
+        let eip = u64::from(sample.eip);
+        let symbol = if sample.in_kernel != 0 {
+            stack.push_str(";[kernel]");
+            self.kernel_symbols.as_ref().and_then(|symbols| symbols.lookup(eip))
+        } else {
+            info.symbols.as_ref().and_then(|symbols| symbols.lookup(eip.wrapping_sub(info.base)))
+        };
+        match symbol {
+            Some(symbol) => { let _ = write!(stack, ";{}", symbol); },
+            None => { let _ = write!(stack, ";{:#010x}", eip); },
+        }
+
+        *self.folded.entry(stack).or_insert(0) += 1;
+    }
+
+    /// Moves all the samples out of the kernel buffer, and symbolizes them.
+    fn drain(&mut self) -> Result<(), Error> {
+        let mut samples = vec![ProfilerSample::default(); DRAIN_BATCH];
+        loop {
+            let (count, dropped) = syscalls::control_profiler(ProfilerCommand::Drain, &mut samples)?;
+            if dropped != 0 {
+                eprintln!("profiler: {} samples were dropped by the kernel", dropped);
+            }
+            for sample in &samples[..count] {
+                self.add(sample);
+            }
+            if count < samples.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes the folded stacks to the given path, one `stack count` per line.
+    fn write(&self, path: &str) -> Result<(), Error> {
+        let mut out = String::new();
+        for (stack, count) in &self.folded {
+            let _ = writeln!(out, "{} {}", stack, count);
+        }
+        fs::write(path, out)?;
+        let total: u64 = self.folded.values().sum();
+        println!("profiler: wrote {} samples to {}", total, path);
+        Ok(())
+    }
+}
+
+/// Errors that can occur while profiling.
+#[derive(Debug)]
+enum Error {
+    /// A profiler syscall failed.
+    Kernel(sunrise_libuser::error::KernelError),
+    /// Writing the output failed.
+    Io(std::io::Error),
+}
+
+impl From<sunrise_libuser::error::KernelError> for Error {
+    fn from(err: sunrise_libuser::error::KernelError) -> Error {
+        Error::Kernel(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// Usage string.
+static USAGE: &str = "usage: profiler start
+       profiler stop [-t] [OUTPUT]
+       profiler record SECONDS [-t] [OUTPUT]";
+
+/// Stops the profiler, and writes the samples it recorded to `output`.
+fn stop(per_thread: bool, output: &str) -> Result<(), Error> {
+    syscalls::control_profiler(ProfilerCommand::Stop, &mut [])?;
+    let mut symbolizer = Symbolizer::new(per_thread);
+    symbolizer.drain()?;
+    symbolizer.write(output)
+}
+
+/// Waits for `seconds` seconds while the profiler is running.
+///
+/// The kernel buffer is drained every second, so processes get looked up
+/// while they're still alive and the buffer doesn't fill up.
+fn wait_and_drain(symbolizer: &mut Symbolizer, seconds: u64) -> Result<(), Error> {
+    for _ in 0..seconds {
+        thread::sleep(DRAIN_INTERVAL);
+        symbolizer.drain()?;
+    }
+    Ok(())
+}
+
+/// Records samples for `seconds` seconds, and writes them to `output`.
+fn record(seconds: u64, per_thread: bool, output: &str) -> Result<(), Error> {
+    let mut symbolizer = Symbolizer::new(per_thread);
+    syscalls::control_profiler(ProfilerCommand::Start, &mut [])?;
+    let result = wait_and_drain(&mut symbolizer, seconds);
+    // Always stop the profiler, even if draining failed.
+    syscalls::control_profiler(ProfilerCommand::Stop, &mut [])?;
+    result?;
+    symbolizer.drain()?;
+    symbolizer.write(output)
+}
+
+/// The entry point of the program.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    // skip argv[0], the name of the program.
+    let mut args: Vec<&str> = args.iter().skip(1).map(|arg| &**arg).collect();
+    let per_thread = args.contains(&"-t");
+    args.retain(|arg| *arg != "-t");
+
+    let result = match args[..] {
+        ["start"] => syscalls::control_profiler(ProfilerCommand::Start, &mut [])
+            .map(|_| ())
+            .map_err(Error::from),
+        ["stop"] => stop(per_thread, DEFAULT_OUTPUT),
+        ["stop", output] => stop(per_thread, output),
+        ["record", seconds] | ["record", seconds, _] => match seconds.parse() {
+            Ok(seconds) => record(seconds, per_thread, args.get(2).copied().unwrap_or(DEFAULT_OUTPUT)),
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                return;
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return;
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("profiler: {:?}", err);
+    }
+}
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        nr::SleepThread,
+        nr::ExitProcess,
+        nr::ExitProcessWithCode,
+        nr::CreateThread,
+        nr::StartThread,
+        nr::MapStackMirror,
+        nr::UnmapStackMirror,
+        nr::ExitThread,
+        nr::CloseHandle,
+        nr::WaitSynchronization,
+        nr::OutputDebugString,
+        nr::SetThreadArea,
+
+        nr::ConnectToNamedPort,
+        nr::SetHeapSize,
+        nr::SendSyncRequestWithUserBuffer,
+        nr::QueryMemory,
+        nr::CreateSharedMemory,
+        nr::MapSharedMemory,
+        nr::UnmapSharedMemory,
+
+        nr::GetInfo,
+        nr::ControlProfiler,
+        nr::GetProcessName,
+    ]
+});
@@ -0,0 +1,107 @@
+#!/usr/bin/env python3
+"""Runs the userspace integration tests of SunriseOS.
+
+Boots the given ISO and disk image headless in QEMU, and waits for the
+testrunner (see testrunner/src/main.rs) to report its results on the serial
+port. The testrunner only runs if the disk image contains
+/bin/testrunner/flags/boot.flag, which `cargo make test-userspace` takes care
+of.
+
+The TAP lines are extracted from the kernel logs and printed on stdout. The
+exit status is 0 if every test passed, and 1 otherwise - including when QEMU
+dies or the timeout expires before the report is complete.
+"""
+
+import argparse
+import re
+import subprocess
+import sys
+import threading
+
+# Kernel log lines look like `[INFO] - <target> - <process> - <message>`,
+# with the level possibly surrounded by color escape codes.
+ANSI_ESCAPE = re.compile(r'\x1b\[[0-9;]*m')
+TAP_LINE = re.compile(r'^\[\s*\w+\s*\] - tap - .*? - (.*)$')
+PLAN = re.compile(r'^1\.\.(\d+)$')
+RESULT = re.compile(r'^(not )?ok \d+')
+
+
+def qemu_command(iso, disk):
+    return [
+        'qemu-system-i386',
+        '-boot', 'd',
+        '-cdrom', iso,
+        '-serial', 'stdio',
+        '-display', 'none',
+        '-no-reboot',
+        '-device', 'intel-iommu',
+        '-drive', 'id=diskA,file={},format=raw,if=none'.format(disk),
+        '-device', 'ahci,id=ahci',
+        '-device', 'ide-drive,drive=diskA,bus=ahci.0',
+        '-machine', 'q35',
+        '-m', '512M',
+    ]
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument('--iso', default='os.iso')
+    parser.add_argument('--disk', default='DISK-test.img')
+    parser.add_argument('--timeout', type=int, default=600,
+                        help='seconds to wait for the whole report')
+    parser.add_argument('--log', help='file to save the whole serial output to')
+    args = parser.parse_args()
+
+    qemu = subprocess.Popen(qemu_command(args.iso, args.disk),
+                            stdin=subprocess.DEVNULL, stdout=subprocess.PIPE)
+    # Kill qemu if the report takes too long to come.
+    timer = threading.Timer(args.timeout, qemu.kill)
+    timer.start()
+
+    log = open(args.log, 'wb') if args.log else None
+    planned = None
+    results = 0
+    failures = 0
+    bailed_out = False
+    try:
+        for raw_line in qemu.stdout:
+            if log:
+                log.write(raw_line)
+            line = ANSI_ESCAPE.sub('', raw_line.decode('utf-8', 'replace')).rstrip('\r\n')
+            match = TAP_LINE.match(line)
+            if not match:
+                continue
+
+            tap = match.group(1)
+            print(tap, flush=True)
+            if PLAN.match(tap):
+                planned = int(PLAN.match(tap).group(1))
+            elif RESULT.match(tap):
+                results += 1
+                if tap.startswith('not '):
+                    failures += 1
+            elif tap.startswith('Bail out!'):
+                bailed_out = True
+                break
+
+            if planned is not None and results == planned:
+                break
+    finally:
+        timer.cancel()
+        qemu.kill()
+        qemu.wait()
+        if log:
+            log.close()
+
+    if bailed_out:
+        print('# testrunner bailed out', file=sys.stderr)
+        return 1
+    if planned is None or results != planned:
+        print('# incomplete report: got {} results out of {}'.format(
+            results, planned if planned is not None else 'unknown'), file=sys.stderr)
+        return 1
+    return 1 if failures else 0
+
+
+if __name__ == '__main__':
+    sys.exit(main())
@@ -0,0 +1,242 @@
+//! Serial Service
+//!
+//! This service drives the COM1 serial line, and exposes it as an IPipe. The
+//! loader uses it to start a shell on the serial line, which allows running
+//! SunriseOS headless, and to script it from the host.
+//!
+//! The kernel keeps sending its logs on COM1 too, so they will be interleaved
+//! with the pipe's output.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate sunrise_libuser;
+
+extern crate alloc;
+
+mod uart;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use sunrise_libuser::futures::{WaitableManager, WorkQueue};
+use sunrise_libuser::ipc::server::{port_handler, new_session_wrapper};
+use sunrise_libuser::futures_rs::future::FutureObj;
+use sunrise_libuser::serial::ISerialService;
+use sunrise_libuser::twili::{IPipeAsync, IPipeProxy};
+use sunrise_libuser::types::{ReadableEvent, WritableEvent};
+use sunrise_libuser::error::Error;
+use sunrise_libuser::syscalls;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use crate::uart::Uart;
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"serial\0\0\0\0\0\0",
+    title_id: 0x0200000000001070,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
+
+        sunrise_libuser::syscalls::nr::ReplyAndReceiveWithUserBuffer,
+        sunrise_libuser::syscalls::nr::AcceptSession,
+        sunrise_libuser::syscalls::nr::CreateSession,
+
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::CreateInterruptEvent,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+
+        sunrise_libuser::syscalls::nr::CreateEvent,
+        sunrise_libuser::syscalls::nr::SignalEvent,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+
+        sunrise_libuser::syscalls::nr::QueryMemory,
+    ],
+    raw_caps: [
+        sunrise_libuser::caps::ioport(COM1_BASE),
+        sunrise_libuser::caps::ioport(COM1_BASE + 1),
+        sunrise_libuser::caps::ioport(COM1_BASE + 4),
+        sunrise_libuser::caps::ioport(COM1_BASE + 5),
+        sunrise_libuser::caps::irq_pair(COM1_IRQ, 0x3FF)
+    ]
+});
+
+/// The base IO port of COM1.
+const COM1_BASE: u16 = 0x3F8;
+/// The IRQ line of COM1.
+const COM1_IRQ: u16 = 4;
+
+/// Ctrl-D, ends a read early.
+const END_OF_TRANSMISSION: u8 = 0x04;
+/// Backspace, as sent by some terminals.
+const BACKSPACE: u8 = 0x08;
+/// Delete, as sent by most terminals when pressing backspace.
+const DELETE: u8 = 0x7F;
+
+/// Maximum number of received bytes kept in [RX_QUEUE]. Bytes received while
+/// it is full are dropped.
+const RX_QUEUE_CAPACITY: usize = 4096;
+
+/// Whether the received characters are echoed back on the line.
+static ECHO: AtomicBool = AtomicBool::new(true);
+
+lazy_static! {
+    /// The COM1 UART.
+    static ref UART: Mutex<Uart> = Mutex::new(Uart::new(COM1_BASE));
+    /// The COM1 IRQ.
+    static ref IRQ_EVENT: ReadableEvent = syscalls::create_interrupt_event(COM1_IRQ as usize, 0).unwrap();
+    /// The bytes received on the line, not yet returned by a read. Holds at
+    /// most [RX_QUEUE_CAPACITY] bytes.
+    static ref RX_QUEUE: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::with_capacity(RX_QUEUE_CAPACITY));
+    /// Signaled when bytes are pushed to [RX_QUEUE].
+    static ref DATA_EVENT: (WritableEvent, ReadableEvent) = syscalls::create_event().unwrap();
+}
+
+/// Task moving the received bytes to [RX_QUEUE] at every IRQ, so they are not
+/// lost when nobody is reading.
+///
+/// Once the queue is full, the UART is still drained, but the bytes are
+/// dropped.
+// https://github.com/rust-lang/rust-clippy/issues/3988
+// Should remove on next toolchain upgrade.
+#[allow(clippy::needless_lifetimes)]
+async fn receive_bytes(work_queue: WorkQueue<'_>) {
+    loop {
+        IRQ_EVENT.wait_async_cb(work_queue.clone(), || {
+            let mut uart = UART.lock();
+            let mut queue = RX_QUEUE.lock();
+            let old_len = queue.len();
+            let mut dropped = 0;
+            while let Some(byte) = uart.try_read_byte() {
+                if queue.len() < RX_QUEUE_CAPACITY {
+                    queue.push_back(byte);
+                } else {
+                    dropped += 1;
+                }
+            }
+            if dropped != 0 {
+                log::warn!("Serial input queue full, dropped {} bytes", dropped);
+            }
+            if queue.len() != old_len { Some(()) } else { None }
+        }).await;
+        let _ = DATA_EVENT.0.signal();
+    }
+}
+
+/// Entry point interface.
+#[derive(Default, Debug, Clone)]
+struct SerialService;
+
+impl ISerialService for SerialService {
+    fn open_pipe(&mut self, manager: WorkQueue<'static>) -> Result<IPipeProxy, Error> {
+        let (server, client) = syscalls::create_session(false, 0)?;
+        let wrapper = new_session_wrapper(manager.clone(), server, SerialPipe, SerialPipe::dispatch);
+        manager.spawn(FutureObj::new(Box::new(wrapper)));
+        Ok(IPipeProxy::from(client))
+    }
+
+    fn set_echo(&mut self, _manager: WorkQueue<'static>, enabled: bool) -> Result<(), Error> {
+        ECHO.store(enabled, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// An IPipe to the serial line. Reads and writes mimic vi's terminal pipe.
+#[derive(Default, Debug, Clone)]
+struct SerialPipe;
+
+impl IPipeAsync for SerialPipe {
+    fn read<'a>(&'a mut self, work_queue: WorkQueue<'static>, buf: &'a mut [u8]) -> FutureObj<'a, Result<u64, Error>> {
+        FutureObj::new(Box::new(async move {
+            // Reads a whole line.
+            let mut i = 0;
+            while i < buf.len() {
+                let byte = DATA_EVENT.1.wait_async_cb(work_queue.clone(), || RX_QUEUE.lock().pop_front()).await;
+                let echo = ECHO.load(Ordering::SeqCst);
+
+                match byte {
+                    END_OF_TRANSMISSION => {
+                        // Ctrl-d pressed, early return.
+                        return Ok(i as u64);
+                    },
+                    BACKSPACE | DELETE => {
+                        // Don't delete further than the first character.
+                        if i != 0 {
+                            i -= 1;
+                            if echo {
+                                UART.lock().write_bytes(b"\x08 \x08");
+                            }
+                        }
+                    },
+                    b'\r' | b'\n' => {
+                        buf[i] = b'\n';
+                        i += 1;
+                        UART.lock().write_bytes(b"\n");
+                        return Ok(i as u64);
+                    },
+                    byte => {
+                        buf[i] = byte;
+                        i += 1;
+                        if echo {
+                            UART.lock().write_byte(byte);
+                        }
+                    }
+                }
+            }
+            Ok(i as u64)
+        }))
+    }
+
+    fn write<'a>(&'a mut self, _manager: WorkQueue<'static>, data: &'a [u8]) -> FutureObj<'a, Result<(), Error>> {
+        FutureObj::new(Box::new(async move {
+            UART.lock().write_bytes(data);
+            Ok(())
+        }))
+    }
+}
+
+fn main() {
+    // Take control of the UART before the IRQ can fire.
+    lazy_static::initialize(&UART);
+
+    let mut man = WaitableManager::new();
+    let handler = port_handler(man.work_queue(), "serial", SerialService::dispatch).unwrap();
+
+    man.work_queue().spawn(FutureObj::new(Box::new(handler)));
+
+    let receive_future = receive_bytes(man.work_queue());
+
+    man.work_queue().spawn(FutureObj::new(Box::new(receive_future)));
+    man.run();
+}
@@ -0,0 +1,77 @@
+//! 16550 UART driver
+//!
+//! The kernel already configured the line (38400 bauds, 8N1, FIFOs enabled)
+//! to send its logs, so we only enable the "received data available"
+//! interrupt, and leave the rest alone.
+
+use sunrise_libuser::io::{Io, Pio};
+
+/// Line Status Register bit: a received byte is waiting in RBR.
+const LSR_DATA_READY: u8 = 1 << 0;
+/// Line Status Register bit: the transmit holding register is empty.
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// Interrupt Enable Register bit: raise an IRQ when a byte is received.
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+
+/// Modem Control Register: DTR, RTS, and OUT2, which gates the IRQ line on PCs.
+const MCR_DTR_RTS_OUT2: u8 = 0x0B;
+
+/// The IO ports of a 16550 UART.
+#[derive(Debug)]
+pub struct Uart {
+    /// Receive Buffer Register when read, Transmit Holding Register when written.
+    data_port: Pio<u8>,
+    /// Interrupt Enable Register.
+    interrupt_enable_port: Pio<u8>,
+    /// Modem Control Register.
+    modem_control_port: Pio<u8>,
+    /// Line Status Register.
+    line_status_port: Pio<u8>,
+}
+
+impl Uart {
+    /// Takes control of the UART whose ports start at `base`, and enables its
+    /// receive interrupt.
+    pub fn new(base: u16) -> Uart {
+        let mut uart = Uart {
+            data_port: Pio::new(base),
+            interrupt_enable_port: Pio::new(base + 1),
+            modem_control_port: Pio::new(base + 4),
+            line_status_port: Pio::new(base + 5),
+        };
+
+        uart.modem_control_port.write(MCR_DTR_RTS_OUT2);
+        uart.interrupt_enable_port.write(IER_RECEIVED_DATA);
+        // Drop whatever was typed before we were there, it would keep the
+        // interrupt line raised.
+        while uart.try_read_byte().is_some() {}
+
+        uart
+    }
+
+    /// Reads a received byte, if any.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.line_status_port.read() & LSR_DATA_READY != 0 {
+            Some(self.data_port.read())
+        } else {
+            None
+        }
+    }
+
+    /// Sends a byte, waiting for the transmit register to be free.
+    pub fn write_byte(&mut self, byte: u8) {
+        while self.line_status_port.read() & LSR_THR_EMPTY == 0 {}
+        self.data_port.write(byte);
+    }
+
+    /// Sends bytes on the line, turning `\n` into `\r\n`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+    }
+}
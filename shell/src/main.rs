@@ -32,7 +32,8 @@ use crate::libuser::ldr::{ILoaderInterfaceProxy};
 use crate::libuser::error::{Error, FileSystemError};
 use crate::libuser::syscalls;
 use crate::libuser::ps2::Keyboard;
-use crate::libuser::twili::ITwiliManagerServiceProxy;
+use crate::libuser::serial::ISerialServiceProxy;
+use crate::libuser::twili::{ITwiliManagerServiceProxy, ITwiliServiceProxy};
 use crate::libuser::threads::Thread;
 
 use sunrise_libkern::process::ProcessState;
@@ -57,7 +58,10 @@ lazy_static! {
 
 /// Asks the user to login repeatedly. Returns with an error if the /etc/passwd
 /// file is invalid or doesn't exist.
-fn login(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &IFileSystemProxy) -> Result<(), Error> {
+///
+/// The password is read from the keyboard without echo. Without a keyboard, it
+/// is read from the terminal like any other line.
+fn login(mut terminal: &mut Terminal, mut keyboard: Option<&mut Keyboard>, filesystem: &IFileSystemProxy) -> Result<(), Error> {
     let mut ipc_path = [0x0; 0x300];
     ipc_path[..b"/etc/passwd".len()].copy_from_slice(b"/etc/passwd");
 
@@ -82,7 +86,10 @@ fn login(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &IFil
         let username = username.trim_end_matches('\n');
 
         let _ = writeln!(&mut terminal, "Password: ");
-        let password = get_next_line_no_echo(keyboard);
+        let password = match &mut keyboard {
+            Some(keyboard) => get_next_line_no_echo(keyboard),
+            None => get_next_line_no_serial_echo(&mut terminal),
+        };
         let password = password.trim_end_matches('\n');
 
         let hash = sha1::Sha1::from(&password).digest().bytes();
@@ -104,6 +111,23 @@ fn login(mut terminal: &mut Terminal, keyboard: &mut Keyboard, filesystem: &IFil
     }
 }
 
+/// Reads a line from the terminal, like [get_next_line], with the echo of the
+/// serial line disabled.
+///
+/// Used to read passwords when the shell runs on the serial line. If the
+/// serial service can't be reached, the line is read with the echo on.
+fn get_next_line_no_serial_echo(terminal: &mut Terminal) -> String {
+    let serial = ISerialServiceProxy::raw_new().ok();
+    if let Some(serial) = &serial {
+        let _ = serial.set_echo(false);
+    }
+    let line = get_next_line(terminal);
+    if let Some(serial) = &serial {
+        let _ = serial.set_echo(true);
+    }
+    line
+}
+
 /// Read key presses until a \n is detected, and return the string
 /// (excluding \n). Don't print the key presses on stdout.
 pub fn get_next_line_no_echo(keyboard: &mut Keyboard) -> String {
@@ -267,7 +291,9 @@ pub enum Job {
     /// This job is a builtin running in a separate thread.
     BuiltIn {
         /// This job's underlying thread handle.
-        thread: Thread
+        thread: Thread,
+        /// The result of the builtin, set when its thread exits.
+        ret: Arc<Once<Result<(), Error>>>,
     },
     /// This job is an external binary running in a different process.
     Process {
@@ -280,7 +306,7 @@ impl Job {
     /// Let this job start running.
     pub fn start(&self, loader: &ILoaderInterfaceProxy) -> Result<(), Error> {
         match self {
-            Job::BuiltIn { thread } => thread.start(),
+            Job::BuiltIn { thread, .. } => thread.start(),
             Job::Process { pid } => loader.launch_title(*pid)
         }
     }
@@ -347,12 +373,13 @@ pub fn generate_jobs(mut terminal: &mut Terminal, filesystem: &IFileSystemProxy,
         let stderr = terminal.clone_pipe().unwrap();
 
         let job = if let Some((f, _)) = subcommands::SUBCOMMANDS.get(cmdname) {
+            let ret = Arc::new(Once::new());
             let thread = Thread::create(subcommands::run, Box::into_raw(Box::new(subcommands::RunArgs {
                 stdin, stdout, stderr, f: *f,
                 args: line.split_whitespace().map(|v| v.to_string()).collect::<Vec<String>>(),
-                ret: Arc::new(Once::new()),
+                ret: ret.clone(),
             })) as usize, 4096 * 4)?;
-            Job::BuiltIn { thread }
+            Job::BuiltIn { thread, ret }
         } else {
             // Try to run it as an external binary.
             let args = cmd.args.iter().map(|v| format!("\"{}\" ", v)).collect::<String>();
@@ -368,9 +395,35 @@ pub fn generate_jobs(mut terminal: &mut Terminal, filesystem: &IFileSystemProxy,
     Ok(processes)
 }
 
+/// Gets the exit status of a builtin from its result: 0 if it succeeded, 1 if
+/// it failed or didn't return.
+fn builtin_status(ret: &Once<Result<(), Error>>) -> u32 {
+    match ret.r#try() {
+        Some(Ok(())) => 0,
+        _ => 1,
+    }
+}
+
+/// Replaces `$?` in a command line with the exit status of the last command.
+fn expand_last_status(line: &str, last_status: u32) -> String {
+    line.replace("$?", &last_status.to_string())
+}
+
+/// Opens the terminal the shell runs in.
+///
+/// If our parent registered pipes for us in twili, such as the shell the loader
+/// starts on the serial line, stdin is used as the terminal, both for reading
+/// and writing. Otherwise, a vi window is created, and the PS/2 keyboard is
+/// used to read passwords.
+fn open_terminal() -> (Terminal, Option<Keyboard>) {
+    match ITwiliServiceProxy::new().and_then(|twili| twili.open_pipes()) {
+        Ok((stdin, _stdout, _stderr)) => (Terminal::from_pipe(stdin), None),
+        Err(_) => (Terminal::new(WindowSize::FontLines(-1, false)).unwrap(), Some(Keyboard::new().unwrap()))
+    }
+}
+
 fn main() {
-    let mut terminal = Terminal::new(WindowSize::FontLines(-1, false)).unwrap();
-    let mut keyboard = Keyboard::new().unwrap();
+    let (mut terminal, mut keyboard) = open_terminal();
     let twili = ITwiliManagerServiceProxy::new().unwrap();
     let loader = ILoaderInterfaceProxy::raw_new().unwrap();
 
@@ -381,12 +434,15 @@ fn main() {
 
     cat(&mut terminal, &filesystem, "/etc/motd").unwrap();
 
-    if let Err(err) = login(&mut terminal, &mut keyboard, &filesystem) {
+    if let Err(err) = login(&mut terminal, keyboard.as_mut(), &filesystem) {
         error!("Error while setting up login: {:?}", err);
     }
 
+    // Exit status of the last command of the last line, for `$?`.
+    let mut last_status = 0;
+
     loop {
-        let line = get_next_line(&mut terminal);
+        let line = expand_last_status(&get_next_line(&mut terminal), last_status);
         let jobs = match generate_jobs(&mut terminal, &filesystem, &twili, &loader, &line) {
             Ok(jobs) => jobs,
             Err(err) => {
@@ -396,16 +452,22 @@ fn main() {
         };
 
         let mut waiters = vec![process_state_changed_event.0.as_ref()];
+        // Index in jobs of the builtin waited on by each waiter after the event.
+        let mut builtins = vec![];
         let mut pids = vec![];
+        let mut statuses = vec![None; jobs.len()];
 
         let mut finished_count = 0;
-        for job in &jobs {
+        for (idx, job) in jobs.iter().enumerate() {
             if let Err(err) = job.start(&loader) {
                 let _ = writeln!(&mut terminal, "Failed to start process: {:?}", err);
             }
             match job {
-                Job::BuiltIn { thread } => waiters.push(thread.as_thread_ref().0.as_ref_static()),
-                Job::Process { pid } => pids.push(pid),
+                Job::BuiltIn { thread, .. } => {
+                    waiters.push(thread.as_thread_ref().0.as_ref_static());
+                    builtins.push(idx);
+                },
+                Job::Process { pid } => pids.push((idx, *pid)),
             }
         }
 
@@ -419,8 +481,8 @@ fn main() {
                 Ok(0) => {
                     let _ = process_state_changed_event.clear();
                     // Check all the pids, hopefully one died.
-                    pids.retain(|pid| {
-                        let state = match loader.get_state(**pid) {
+                    pids.retain(|&(idx, pid)| {
+                        let state = match loader.get_state(pid) {
                             Ok(state) => state,
                             Err(err) => {
                                 finished_count += 1;
@@ -431,6 +493,11 @@ fn main() {
                         let is_exited = ProcessState(state) == ProcessState::Exited;
                         if is_exited {
                             finished_count += 1;
+                            // Reap the process and get its exit code.
+                            match loader.wait(pid) {
+                                Ok(status) => statuses[idx] = Some(status),
+                                Err(err) => error!("{:?}", err),
+                            }
                         }
                         !is_exited
                     })
@@ -439,9 +506,17 @@ fn main() {
                     // Subprocess died?
                     finished_count += 1;
                     waiters.remove(idx);
+                    let job_idx = builtins.remove(idx - 1);
+                    if let Job::BuiltIn { ret, .. } = &jobs[job_idx] {
+                        statuses[job_idx] = Some(builtin_status(ret));
+                    }
                 }
             }
         }
+
+        if let Some(status) = statuses.last() {
+            last_status = status.unwrap_or(1);
+        }
     }
 }
 
@@ -567,6 +642,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         libuser::syscalls::nr::SleepThread,
         libuser::syscalls::nr::ExitProcess,
+        libuser::syscalls::nr::ExitProcessWithCode,
         libuser::syscalls::nr::CloseHandle,
         libuser::syscalls::nr::WaitSynchronization,
         libuser::syscalls::nr::OutputDebugString,
@@ -577,6 +653,8 @@ capabilities!(CAPABILITIES = Capabilities {
         libuser::syscalls::nr::QueryMemory,
         libuser::syscalls::nr::CreateThread,
         libuser::syscalls::nr::StartThread,
+        libuser::syscalls::nr::MapStackMirror,
+        libuser::syscalls::nr::UnmapStackMirror,
         libuser::syscalls::nr::ExitThread,
         libuser::syscalls::nr::MapSharedMemory,
         libuser::syscalls::nr::UnmapSharedMemory,
@@ -585,5 +663,10 @@ capabilities!(CAPABILITIES = Capabilities {
         libuser::syscalls::nr::CreateSharedMemory,
         libuser::syscalls::nr::CreateInterruptEvent,
         libuser::syscalls::nr::GetProcessList,
+        libuser::syscalls::nr::SetLogFilter,
+        libuser::syscalls::nr::GetSystemTick,
+        libuser::syscalls::nr::GetInfo,
+        libuser::syscalls::nr::DumpInfo,
+        libuser::syscalls::nr::SleepSystem,
     ]
 });
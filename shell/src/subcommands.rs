@@ -20,9 +20,14 @@ mod test_divide_by_zero;
 mod test_page_fault;
 mod connect;
 mod ps;
+mod dumpinfo;
 mod kill;
 mod help;
 mod exit;
+mod logfilter;
+mod top;
+mod shutdown;
+mod reboot;
 
 use sunrise_libuser::error::Error;
 use sunrise_libuser::twili::IPipeProxy;
@@ -52,7 +57,12 @@ lazy_static! {
         subcommands.insert("test_page_fault", (test_page_fault::main as _, test_page_fault::HELP));
         subcommands.insert("connect", (connect::main as _, connect::HELP));
         subcommands.insert("ps", (ps::main as _, ps::HELP));
+        subcommands.insert("dumpinfo", (dumpinfo::main as _, dumpinfo::HELP));
         subcommands.insert("kill", (kill::main as _, kill::HELP));
+        subcommands.insert("logfilter", (logfilter::main as _, logfilter::HELP));
+        subcommands.insert("top", (top::main as _, top::HELP));
+        subcommands.insert("shutdown", (shutdown::main as _, shutdown::HELP));
+        subcommands.insert("reboot", (reboot::main as _, reboot::HELP));
         subcommands.insert("help", (help::main as _, help::HELP));
         subcommands
     };
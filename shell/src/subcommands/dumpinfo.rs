@@ -0,0 +1,31 @@
+//! Dump the state of the kernel objects.
+//!
+//! Prints every process with its handles and mappings, the named ports, the
+//! pending IPC requests and the physical memory usage. The kernel also writes
+//! the snapshot to its log. Useful to chase handle and memory leaks.
+
+use core::fmt::Write;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sunrise_libuser::error::Error;
+use sunrise_libuser::twili::IPipeProxy;
+use sunrise_libuser::syscalls;
+
+/// Help string.
+pub static HELP: &str = "dumpinfo: Dump the state of the kernel objects";
+
+/// Read the snapshot of the kernel objects and print it.
+pub fn main(_stdin: IPipeProxy, mut stdout: IPipeProxy, _stderr: IPipeProxy, _args: Vec<String>) -> Result<(), Error> {
+    // Read it in one go to get a consistent snapshot, growing the buffer if
+    // it was too small.
+    let mut buf = vec![0; 0x4000];
+    loop {
+        let (read, size) = syscalls::dump_info(0, &mut buf)?;
+        if read == size {
+            let _ = write!(&mut stdout, "{}", String::from_utf8_lossy(&buf[..read]));
+            return Ok(())
+        }
+        buf.resize(size + 0x1000, 0);
+    }
+}
@@ -8,12 +8,13 @@ use sunrise_libuser::twili::IPipeProxy;
 use sunrise_libuser::syscalls;
 
 /// Help string.
-pub static HELP: &str = "exit: Exit this process";
+pub static HELP: &str = "exit [code]: Exit this process with the given code, 0 by default";
 
-/// Print the current working directory.
-pub fn main(_stdin: IPipeProxy, _stdout: IPipeProxy, _stderr: IPipeProxy, _args: Vec<String>) -> Result<(), Error> {
+/// Exits the shell.
+pub fn main(_stdin: IPipeProxy, _stdout: IPipeProxy, _stderr: IPipeProxy, args: Vec<String>) -> Result<(), Error> {
+    let code = args.get(1).and_then(|code| code.parse().ok()).unwrap_or(0);
     // Kinda dangerous. Doesn't close all pending IPC sockets.
-    syscalls::exit_process();
+    syscalls::exit_process_with_code(code);
     unreachable!();
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,41 @@
+//! Change the kernel log filter at runtime.
+//!
+//! Takes an env_logger-style directive string, such as
+//! `sunrise_kernel::ipc=trace,info`, and makes the kernel use it instead of the
+//! filter passed on the command line.
+//!
+//! When `-p <pid>` is given, the filter only applies to the messages logged by
+//! this process through `output_debug_string`. An empty directive string (`""`)
+//! resets the process to the global filter.
+
+use core::fmt::Write;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sunrise_libuser::error::Error;
+use sunrise_libuser::twili::IPipeProxy;
+use sunrise_libuser::syscalls;
+
+/// Help string.
+pub static HELP: &str = "logfilter [-p <pid>] <directives>: Change the kernel log filter";
+
+/// Parse the arguments and replace the global or per-process log filter.
+pub fn main(_stdin: IPipeProxy, mut stdout: IPipeProxy, _stderr: IPipeProxy, args: Vec<String>) -> Result<(), Error> {
+    let (pid, directives) = match (args.get(1).map(|v| &**v), args.get(2), args.get(3)) {
+        (Some("-p"), Some(pid), directives) => match str::parse(pid) {
+            Ok(pid) => (Some(pid), directives.map(|v| v.trim_matches('"')).unwrap_or("")),
+            Err(_) => {
+                let _ = writeln!(&mut stdout, "usage: logfilter [-p <pid>] <directives>");
+                return Ok(())
+            }
+        },
+        (Some(directives), None, None) => (None, directives),
+        _ => {
+            let _ = writeln!(&mut stdout, "usage: logfilter [-p <pid>] <directives>");
+            return Ok(())
+        }
+    };
+
+    syscalls::set_log_filter(directives, pid)?;
+    Ok(())
+}
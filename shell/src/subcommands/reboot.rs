@@ -0,0 +1,23 @@
+//! Reboot the computer.
+//!
+//! Services are first asked to write back their cached data, so nothing is
+//! lost.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sunrise_libuser::error::Error;
+use sunrise_libuser::twili::IPipeProxy;
+use sunrise_libuser::syscalls::{self, SleepSystemMode};
+
+use super::shutdown::flush_services;
+
+/// Help string.
+pub static HELP: &str = "reboot: Reboot the computer";
+
+/// Flush the services, and reboot.
+pub fn main(_stdin: IPipeProxy, mut stdout: IPipeProxy, _stderr: IPipeProxy, _args: Vec<String>) -> Result<(), Error> {
+    flush_services(&mut stdout);
+    syscalls::sleep_system(SleepSystemMode::Reboot)?;
+    Ok(())
+}
@@ -0,0 +1,33 @@
+//! Power the computer off.
+//!
+//! Services are first asked to write back their cached data, so nothing is
+//! lost.
+
+use core::fmt::Write;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sunrise_libuser::error::Error;
+use sunrise_libuser::fs::IFileSystemServiceProxy;
+use sunrise_libuser::twili::IPipeProxy;
+use sunrise_libuser::syscalls::{self, SleepSystemMode};
+
+/// Help string.
+pub static HELP: &str = "shutdown: Power the computer off";
+
+/// Ask the services to flush their data. Failures are reported, but don't
+/// prevent shutting down.
+pub fn flush_services(stdout: &mut IPipeProxy) {
+    let result = IFileSystemServiceProxy::raw_new()
+        .and_then(|fs| fs.flush_disks());
+    if let Err(err) = result {
+        let _ = writeln!(stdout, "Failed to flush the disks: {:?}", err);
+    }
+}
+
+/// Flush the services, and power off.
+pub fn main(_stdin: IPipeProxy, mut stdout: IPipeProxy, _stderr: IPipeProxy, _args: Vec<String>) -> Result<(), Error> {
+    flush_services(&mut stdout);
+    syscalls::sleep_system(SleepSystemMode::Shutdown)?;
+    Ok(())
+}
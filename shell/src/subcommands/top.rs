@@ -0,0 +1,147 @@
+//! Display the running processes and their resource usage.
+//!
+//! Opens a full-screen window listing every living process, along with the
+//! share of CPU time it used since the last refresh and the amount of memory
+//! mapped in its address space. The list is refreshed every second, until `q`
+//! is pressed.
+//!
+//! The memory usage is the kernel's accounting of the address space of the
+//! process, read with GetInfo's `ProcessUsedMemorySize`. Unlike summing the
+//! regions returned by QueryProcessMemory, it needs no debug rights over the
+//! process, and counts the pages mapped several times only once.
+
+use core::fmt::Write;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sunrise_libuser::error::Error;
+use sunrise_libuser::twili::IPipeProxy;
+use sunrise_libuser::syscalls::{self, InfoType};
+use sunrise_libuser::ldr::ILoaderInterfaceProxy;
+use sunrise_libuser::ps2::Keyboard;
+use sunrise_libuser::terminal::{Terminal, WindowSize};
+
+/// Help string.
+pub static HELP: &str = "top: Display running processes and their resource usage. Press q to quit";
+
+/// Time between two refreshes of the process list, in nanoseconds.
+const REFRESH_INTERVAL_NS: usize = 1_000_000_000;
+
+/// Time between two checks of the keyboard, in nanoseconds.
+const KEYBOARD_POLL_INTERVAL_NS: usize = 50_000_000;
+
+/// Resource usage of a process at a given point in time.
+#[derive(Debug, Clone, Copy)]
+struct ProcessSample {
+    /// Ticks spent running userspace code since the process started.
+    user_ticks: u64,
+    /// Ticks spent running kernel code since the process started.
+    kernel_ticks: u64,
+    /// Size of the memory mapped in the address space of the process, in bytes.
+    memory: u64,
+}
+
+/// Samples the resource usage of the process with the given pid.
+fn sample_process(pid: u64) -> Result<ProcessSample, Error> {
+    Ok(ProcessSample {
+        user_ticks: syscalls::get_info(InfoType::ProcessUserTickCount, None, pid)?,
+        kernel_ticks: syscalls::get_info(InfoType::ProcessKernelTickCount, None, pid)?,
+        memory: syscalls::get_info(InfoType::ProcessUsedMemorySize, None, pid)?,
+    })
+}
+
+/// Samples the resource usage of all the living processes, indexed by pid.
+///
+/// Processes that die while being sampled are silently skipped.
+fn sample_processes() -> Result<BTreeMap<u64, ProcessSample>, Error> {
+    let mut pids = [0; 256];
+    let pid_read = syscalls::get_process_list(&mut pids)?;
+
+    let mut samples = BTreeMap::new();
+    for pid in &pids[..core::cmp::min(pid_read, pids.len())] {
+        match sample_process(*pid) {
+            Ok(sample) => { samples.insert(*pid, sample); },
+            Err(err) => log::debug!("Failed to sample pid {}: {:?}", pid, err),
+        }
+    }
+    Ok(samples)
+}
+
+/// Returns `ticks` as a per-mille of `total`.
+fn permille(ticks: u64, total: u64) -> u64 {
+    if total == 0 {
+        0
+    } else {
+        ticks.saturating_mul(1000) / total
+    }
+}
+
+/// Draws the process list on the terminal.
+///
+/// The CPU usage is computed from the difference between `previous` and
+/// `current`, which were sampled `elapsed_ticks` apart.
+fn draw(terminal: &mut Terminal, loader: &ILoaderInterfaceProxy, previous: &BTreeMap<u64, ProcessSample>, current: &BTreeMap<u64, ProcessSample>, elapsed_ticks: u64) {
+    // (pid, user ticks, kernel ticks, memory) since the last refresh.
+    let mut rows: Vec<(u64, u64, u64, u64)> = current.iter().map(|(pid, sample)| {
+        let (prev_user, prev_kernel) = previous.get(pid)
+            .map(|prev| (prev.user_ticks, prev.kernel_ticks))
+            .unwrap_or((0, 0));
+        (*pid, sample.user_ticks.saturating_sub(prev_user), sample.kernel_ticks.saturating_sub(prev_kernel), sample.memory)
+    }).collect();
+    rows.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.cmp(&b.0)));
+
+    let busy_ticks: u64 = rows.iter().map(|row| row.1 + row.2).sum();
+    let busy = permille(busy_ticks, elapsed_ticks);
+
+    let _ = write!(terminal, "\x0C");
+    let _ = writeln!(terminal, "Processes: {}, CPU: {}.{}% busy. Press q to quit.", rows.len(), busy / 10, busy % 10);
+    let _ = writeln!(terminal);
+    let _ = writeln!(terminal, "{:>5}  {:<16} {:>6} {:>6} {:>6} {:>10}", "PID", "NAME", "%CPU", "%USER", "%KERN", "MEM");
+
+    for (pid, user_ticks, kernel_ticks, memory) in rows {
+        let mut name = [0; 16];
+        let name = match loader.get_name(pid, &mut name) {
+            Ok(copied_len) => String::from_utf8_lossy(&name[..copied_len as usize]).into_owned(),
+            Err(_) => String::from("<Unknown>"),
+        };
+        let cpu = permille(user_ticks + kernel_ticks, elapsed_ticks);
+        let user = permille(user_ticks, elapsed_ticks);
+        let kernel = permille(kernel_ticks, elapsed_ticks);
+        let _ = writeln!(terminal, "{:>5}  {:<16} {:>4}.{} {:>4}.{} {:>4}.{} {:>9}K",
+            pid, name,
+            cpu / 10, cpu % 10,
+            user / 10, user % 10,
+            kernel / 10, kernel % 10,
+            memory / 1024);
+    }
+    let _ = terminal.draw();
+}
+
+/// Shows the process list in a new window, refreshing it every second. When
+/// `q` is pressed, the window is closed and control is given back to the
+/// caller.
+pub fn main(_stdin: IPipeProxy, _stdout: IPipeProxy, _stderr: IPipeProxy, _args: Vec<String>) -> Result<(), Error> {
+    let loader = ILoaderInterfaceProxy::raw_new()?;
+    let mut keyboard = Keyboard::new()?;
+    let mut terminal = Terminal::new(WindowSize::Fullscreen)?;
+
+    let mut previous_tick = syscalls::get_system_tick()?;
+    let mut previous = sample_processes()?;
+
+    loop {
+        for _ in 0..REFRESH_INTERVAL_NS / KEYBOARD_POLL_INTERVAL_NS {
+            if keyboard.try_read_key() == Some('q') {
+                return Ok(());
+            }
+            syscalls::sleep_thread(KEYBOARD_POLL_INTERVAL_NS)?;
+        }
+
+        let tick = syscalls::get_system_tick()?;
+        let current = sample_processes()?;
+        draw(&mut terminal, &loader, &previous, &current, tick.saturating_sub(previous_tick));
+
+        previous_tick = tick;
+        previous = current;
+    }
+}
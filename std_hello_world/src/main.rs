@@ -116,8 +116,11 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
         sunrise_libuser::syscalls::nr::CreateThread,
         sunrise_libuser::syscalls::nr::StartThread,
+        sunrise_libuser::syscalls::nr::MapStackMirror,
+        sunrise_libuser::syscalls::nr::UnmapStackMirror,
         sunrise_libuser::syscalls::nr::ExitThread,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
@@ -0,0 +1,343 @@
+//! Userspace integration test runner
+//!
+//! Started by the loader at boot when `/bin/testrunner/flags/boot.flag`
+//! exists. Runs every test listed in `/etc/testrunner.manifest`, one after
+//! the other, and reports the results in the [TAP] format on the kernel's
+//! debug output - the serial port.
+//!
+//! Each line of the manifest is a test, in the form:
+//!
+//! ```text
+//! <title> [args...] [$?=<status>] [=> <expected output>]
+//! ```
+//!
+//! The title is created through the loader exactly like the shell would,
+//! with its stdout and stderr connected to twili pipes. A test passes if the
+//! process exits with the expected status - 0 unless given with `$?=` -, did
+//! not crash (the loader did not save a core dump for it), finished within
+//! [TEST_TIMEOUT_MS], and - if an expected output was given - printed it to
+//! its stdout or stderr. Empty lines and lines starting with `#` are ignored.
+//!
+//! Every TAP line is logged with the `tap` target, so a host can pick them up
+//! among the rest of the kernel logs. See `scripts/run-userspace-tests.py`.
+//!
+//! [TAP]: https://testanything.org/tap-version-13-specification.html
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate alloc;
+
+#[macro_use]
+extern crate sunrise_libuser;
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use sunrise_libuser::error::{Error, KernelError};
+use sunrise_libuser::fs::{IFileSystemServiceProxy, IFileSystemProxy, FileSystemPath};
+use sunrise_libuser::ldr::ILoaderInterfaceProxy;
+use sunrise_libuser::syscalls::{self, ProcessState};
+use sunrise_libuser::threads::Thread;
+use sunrise_libuser::twili::{ITwiliManagerServiceProxy, IPipeProxy};
+use sunrise_libuser::types::ReadableEvent;
+
+/// Path of the list of tests to run.
+const MANIFEST_PATH: &str = "/etc/testrunner.manifest";
+
+/// Time a test is given to exit before being killed, in milliseconds.
+const TEST_TIMEOUT_MS: usize = 60_000;
+
+/// Interval at which the state of a running test is checked, in milliseconds.
+const POLL_INTERVAL_MS: usize = 100;
+
+/// Time given to the output of an exited test to be fully read, in
+/// milliseconds.
+///
+/// The pipes of a process that never opened them are never closed, so we
+/// can't wait for an end of file forever.
+const OUTPUT_GRACE_PERIOD_MS: usize = 1_000;
+
+/// Log target of the TAP lines.
+const TAP_TARGET: &str = "tap";
+
+/// A test from the manifest.
+#[derive(Debug)]
+struct TestCase {
+    /// Title to run.
+    title: String,
+    /// Arguments passed to the title, including its name.
+    args: Vec<String>,
+    /// Exit status the test must exit with for it to pass.
+    expected_status: u32,
+    /// Text the test must print for it to pass.
+    expected_output: Option<String>,
+}
+
+impl TestCase {
+    /// Parses a line of the manifest. Returns None for empty lines and
+    /// comments.
+    fn parse(line: &str) -> Option<TestCase> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let mut split = line.splitn(2, "=>");
+        let command = split.next().unwrap_or("");
+        let expected_output = split.next()
+            .map(|expected| expected.trim().to_string())
+            .filter(|expected| !expected.is_empty());
+
+        let mut expected_status = 0;
+        let mut args: Vec<String> = Vec::new();
+        for arg in command.split_whitespace() {
+            match arg.trim_start_matches("$?=").parse() {
+                Ok(status) if arg.starts_with("$?=") => expected_status = status,
+                _ => args.push(arg.to_string()),
+            }
+        }
+        let title = args.get(0)?.clone();
+        Some(TestCase { title, args, expected_status, expected_output })
+    }
+
+    /// Name of the test as printed in the report.
+    fn name(&self) -> String {
+        self.args.join(" ")
+    }
+}
+
+/// Outputs a line of the TAP report.
+fn tap(line: &str) {
+    let _ = syscalls::output_debug_string(line, 50, TAP_TARGET);
+}
+
+/// Reads the whole content of a file.
+fn read_file(filesystem: &IFileSystemProxy, path: &str) -> Result<Vec<u8>, Error> {
+    let mut ipc_path: FileSystemPath = [0; 0x300];
+    ipc_path[..path.len()].copy_from_slice(path.as_bytes());
+
+    let file = filesystem.open_file(1, &ipc_path)?;
+    let mut content = Vec::new();
+    let mut buffer = [0; 0x200];
+    loop {
+        let read_size = file.read(0, content.len() as u64, buffer.len() as u64, &mut buffer)?;
+        content.extend_from_slice(&buffer[..read_size as usize]);
+        if read_size != buffer.len() as u64 {
+            break;
+        }
+    }
+    Ok(content)
+}
+
+/// Checks whether the given path exists.
+fn file_exists(filesystem: &IFileSystemProxy, path: &str) -> bool {
+    let mut ipc_path: FileSystemPath = [0; 0x300];
+    ipc_path[..path.len()].copy_from_slice(path.as_bytes());
+    filesystem.get_entry_type(&ipc_path).is_ok()
+}
+
+/// The structure sent to [read_output]. Holds the read side of the pipes of
+/// a test, and where to store what was read from them.
+struct OutputReader {
+    /// Read side of the test's stdout and stderr pipes.
+    pipes: Vec<IPipeProxy>,
+    /// Everything read from the pipes so far.
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+/// Trampoline function of the output reader threads. Takes a pointer to an
+/// [OutputReader] box as an argument, and reads its pipes until they are
+/// closed.
+fn read_output(arg: usize) {
+    let reader: Box<OutputReader> = unsafe { Box::from_raw(arg as *mut OutputReader) };
+    let mut buffer = [0; 0x200];
+    for pipe in &reader.pipes {
+        loop {
+            match pipe.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => reader.output.lock().extend_from_slice(&buffer[..read as usize]),
+            }
+        }
+    }
+}
+
+/// Services used to run the tests.
+struct Runner {
+    /// Used to create, start and wait on the tests.
+    loader: ILoaderInterfaceProxy,
+    /// Used to create the pipes of the tests.
+    twili: ITwiliManagerServiceProxy,
+    /// Used to read the manifest and look for core dumps.
+    filesystem: IFileSystemProxy,
+    /// Signaled by the loader whenever a process changes state.
+    process_state_changed: ReadableEvent,
+}
+
+impl Runner {
+    /// Waits for the given process to exit, for at most [TEST_TIMEOUT_MS].
+    ///
+    /// Returns false if the process is still running.
+    fn wait_exited(&self, pid: u64) -> Result<bool, Error> {
+        for _ in 0..TEST_TIMEOUT_MS / POLL_INTERVAL_MS {
+            if ProcessState(self.loader.get_state(pid)?) == ProcessState::Exited {
+                return Ok(true);
+            }
+            match syscalls::wait_synchronization(&[self.process_state_changed.0.as_ref()], Some(POLL_INTERVAL_MS * 1_000_000)) {
+                Ok(_) => { let _ = self.process_state_changed.clear(); },
+                Err(KernelError::Timeout) => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(ProcessState(self.loader.get_state(pid)?) == ProcessState::Exited)
+    }
+
+    /// Runs a test, returning why it failed, if it did.
+    ///
+    /// The output of the test is returned either way.
+    fn run(&self, test: &TestCase) -> Result<(Option<String>, Vec<u8>), Error> {
+        let (stdin, stdin_write) = self.twili.create_pipe()?;
+        // Tests get an empty stdin.
+        drop(stdin_write);
+        let (stdout_read, stdout) = self.twili.create_pipe()?;
+        let (stderr_read, stderr) = self.twili.create_pipe()?;
+
+        let args = test.args.iter().map(|arg| format!("\"{}\" ", arg)).collect::<String>();
+        let pid = self.loader.create_title(test.title.as_bytes(), args.as_bytes(), b"PWD=system:/\0")?;
+        self.twili.register_pipes(pid, stdin, stdout, stderr)?;
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let reader = Box::new(OutputReader { pipes: vec![stdout_read, stderr_read], output: output.clone() });
+        let reader_thread = Thread::create(read_output, Box::into_raw(reader) as usize, 4096 * 4)?;
+        reader_thread.start()?;
+
+        self.loader.launch_title(pid)?;
+
+        let mut failure = None;
+        if !self.wait_exited(pid)? {
+            failure = Some(format!("timed out after {}ms", TEST_TIMEOUT_MS));
+            self.loader.kill(pid)?;
+        }
+        let exit_status = self.loader.wait(pid)?;
+
+        // Don't hang forever on pipes that never get closed, the reader
+        // thread gets leaked in that case.
+        let _ = syscalls::wait_synchronization(&[reader_thread.as_thread_ref().0.as_ref()], Some(OUTPUT_GRACE_PERIOD_MS * 1_000_000));
+        let output = output.lock().clone();
+
+        let core_dump = format!("/var/crash/{}-{}.core", test.title, pid);
+        if failure.is_none() && file_exists(&self.filesystem, &core_dump) {
+            failure = Some(format!("crashed, see {}", core_dump));
+        }
+        if failure.is_none() && exit_status != test.expected_status {
+            failure = Some(format!("exited with status {}, expected {}", exit_status, test.expected_status));
+        }
+        if let (None, Some(expected)) = (&failure, &test.expected_output) {
+            if !String::from_utf8_lossy(&output).contains(&**expected) {
+                failure = Some(format!("output did not contain {:?}", expected));
+            }
+        }
+
+        Ok((failure, output))
+    }
+}
+
+/// Runs all the tests of the manifest, reporting the results as TAP.
+fn run_tests() -> Result<(), Error> {
+    let fs_proxy = IFileSystemServiceProxy::raw_new()?;
+    let loader = ILoaderInterfaceProxy::raw_new()?;
+    let process_state_changed = loader.get_process_state_changed_event()?;
+    let runner = Runner {
+        loader,
+        twili: ITwiliManagerServiceProxy::new()?,
+        filesystem: fs_proxy.open_disk_partition(0, 0)?,
+        process_state_changed,
+    };
+
+    let manifest = read_file(&runner.filesystem, MANIFEST_PATH)?;
+    let tests: Vec<TestCase> = String::from_utf8_lossy(&manifest).lines()
+        .filter_map(TestCase::parse)
+        .collect();
+
+    tap("TAP version 13");
+    tap(&format!("1..{}", tests.len()));
+
+    let mut failed = 0;
+    for (idx, test) in tests.iter().enumerate() {
+        let number = idx + 1;
+        let (failure, output) = match runner.run(test) {
+            Ok(res) => res,
+            Err(err) => (Some(format!("failed to run: {:?}", err)), Vec::new()),
+        };
+
+        match failure {
+            None => tap(&format!("ok {} - {}", number, test.name())),
+            Some(reason) => {
+                failed += 1;
+                tap(&format!("not ok {} - {} # {}", number, test.name(), reason));
+                for line in String::from_utf8_lossy(&output).lines() {
+                    tap(&format!("# {}", line));
+                }
+            }
+        }
+    }
+
+    tap(&format!("# {} passed, {} failed", tests.len() - failed, failed));
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run_tests() {
+        tap(&format!("Bail out! {:?}", err));
+    }
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"testrunner\0\0",
+    title_id: 0x0200000000001070,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::CreateThread,
+        sunrise_libuser::syscalls::nr::StartThread,
+        sunrise_libuser::syscalls::nr::MapStackMirror,
+        sunrise_libuser::syscalls::nr::UnmapStackMirror,
+        sunrise_libuser::syscalls::nr::ExitThread,
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+    ]
+});
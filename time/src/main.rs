@@ -31,6 +31,7 @@ mod timezone;
 use alloc::prelude::v1::*;
 
 use sunrise_libuser::syscalls;
+use sunrise_libuser::caps;
 use sunrise_libuser::futures::{WaitableManager, WorkQueue};
 use sunrise_libuser::ipc::server::{new_session_wrapper, port_handler};
 use sunrise_libuser::futures_rs::future::FutureObj;
@@ -57,6 +58,7 @@ capabilities!(CAPABILITIES = Capabilities {
     svcs: [
         sunrise_libuser::syscalls::nr::SleepThread,
         sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
         sunrise_libuser::syscalls::nr::CloseHandle,
         sunrise_libuser::syscalls::nr::WaitSynchronization,
         sunrise_libuser::syscalls::nr::OutputDebugString,
@@ -120,6 +122,8 @@ struct Rtc {
 impl Rtc {
     /// Create a new RTC with the default IBM PC values.
     pub fn new() -> Rtc {
+        // Fail loudly rather than with a GPF on the first access.
+        assert!(caps::owns_ioport(0x70) && caps::owns_ioport(0x71), "RTC ports are not in our capabilities");
         let irq = syscalls::create_interrupt_event(0x08, 0).expect("IRQ cannot be acquired");
 
         let rtc = Rtc {
@@ -186,6 +186,7 @@ impl Terminal {
         for mychar in string.chars() {
             match mychar {
                 '\n'   => { self.line_feed(); }
+                '\x0C' => { self.clear(); }
                 '\x08' => {
                     self.move_pos_back();
                     let empty_glyph = GlyphBitmap { width: 0, height: 0, top: 0, left: 0, data: Vec::new() };
@@ -0,0 +1,734 @@
+//! Virtio block device
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt::{self, Debug, Formatter};
+use core::mem::size_of;
+use core::ptr;
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+
+use sunrise_libuser::error::{Error, AhciError};
+use sunrise_libuser::ahci::IDiskAsync as IDiskInterface;
+use sunrise_libuser::dma::Dma;
+use sunrise_libuser::futures::WorkQueue;
+use sunrise_libuser::futures_rs::future::{self, FutureObj};
+use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+use sunrise_libuser::pci::PciDevice;
+use sunrise_libuser::syscalls::MemoryPermissions;
+use sunrise_libuser::types::{MappedSharedMemory, SharedMemory};
+use sunrise_libuser::ahci::Block;
+
+use crate::transport::*;
+use crate::virtqueue::Virtqueue;
+
+/// Feature bit: the device reports the maximum number of segments of a request in `seg_max`.
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+/// Feature bit: the device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// Feature bit: the device complies with virtio 1.0. Required by the modern interface.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// Feature bit: the device goes through the IOMMU.
+const VIRTIO_F_ACCESS_PLATFORM: u64 = 1 << 33;
+
+/// Offset of `capacity`, the number of 512-octet sectors, in the device configuration.
+const CONFIG_CAPACITY: usize = 0;
+/// Offset of `seg_max`, the maximum number of segments of a request, in the device configuration.
+const CONFIG_SEG_MAX: usize = 12;
+
+/// Request type: read sectors.
+const VIRTIO_BLK_T_IN: u32 = 0;
+/// Request type: write sectors.
+const VIRTIO_BLK_T_OUT: u32 = 1;
+/// Request status: success.
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// The maximum number of elements of the request queue we use.
+///
+/// The queue of the legacy interface can only be used with the size chosen by the device.
+const MAX_QUEUE_SIZE: u16 = 256;
+
+/// The maximum number of sectors we transfer in a single request.
+const MAX_SECTORS_PER_REQUEST: u64 = 256;
+
+/// The direction of a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// Read sectors from the device.
+    Read,
+    /// Write sectors to the device.
+    Write,
+}
+
+/// The device-readable header, and the device-writable status, of a request.
+///
+/// See virtio 1.0 spec section 5.2.6. The data buffers of the request go in-between.
+///
+/// Aligned so it never crosses a page boundary, and is always physically contiguous.
+#[repr(C, align(32))]
+#[derive(Debug, Default)]
+struct RequestBlock {
+    /// Either [VIRTIO_BLK_T_IN] or [VIRTIO_BLK_T_OUT].
+    request_type: u32,
+    /// Reserved, must be 0.
+    reserved: u32,
+    /// The first sector of the transfer.
+    sector: u64,
+    /// Written by the device, [VIRTIO_BLK_S_OK] on success.
+    status: u8,
+}
+
+/// The size of the header of a request, the fields read by the device.
+const REQUEST_HEADER_SIZE: usize = 16;
+
+/// The state of a request.
+#[derive(Debug)]
+pub enum Slot {
+    /// No request is using this head descriptor.
+    Free,
+    /// A request was made available with this head descriptor, and the device is processing it.
+    ///
+    /// Holds the waker of the task waiting for its completion, if it already polled it.
+    Issued(Option<Waker>),
+    /// The request was completed, but the task that issued it hasn't collected its result yet.
+    Completed(Result<(), Error>),
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Slot::Free
+    }
+}
+
+/// A virtio block device.
+///
+/// Drives the device through its [Transport], and provides functions to read and write sectors.
+///
+/// # Requests
+///
+/// Requests are made available in the only virtqueue of the device, each of them as a chain of
+/// descriptors: its header, its data buffers, and its status. As many requests can be
+/// outstanding as there are descriptors to make them. Tasks making a request when there aren't
+/// enough free descriptors are woken up when some are freed.
+///
+/// A request is identified by the head of its chain, which indexes its [RequestBlock] and its
+/// [Slot].
+///
+/// Requests are completed by [handle_interrupt], which is called when the device raises an
+/// interrupt. It wakes up the tasks waiting for them.
+///
+/// [handle_interrupt]: Disk::handle_interrupt
+///
+/// # Memory
+///
+/// A disk is responsible for the virtqueue and the request blocks, which it makes accessible to
+/// the device. When dropped, the device is reset, and they are made inaccessible again.
+pub struct Disk {
+    /// The interface the device is driven through.
+    transport: Transport,
+    /// How the device accesses our memory.
+    dma: Arc<Mutex<Dma>>,
+    /// The request queue.
+    queue: Virtqueue,
+    /// The header and status of every request, indexed by its head descriptor.
+    requests: Box<[RequestBlock]>,
+    /// The device addresses of the header and of the status of every request block.
+    request_addresses: Vec<(u64, u64)>,
+    /// The state of every request, indexed by its head descriptor.
+    slots: Vec<Slot>,
+    /// The wakers of the tasks waiting for descriptors to be freed.
+    descriptor_waiters: Vec<Waker>,
+    /// The maximum number of data buffers of a request.
+    max_segments: usize,
+    /// Number of addressable sectors of this disk. Each sector is 512 octets.
+    sectors: u64,
+    /// Indicates if the device is read-only.
+    read_only: bool,
+}
+
+impl Disk {
+    /// Initializes a virtio block device.
+    ///
+    /// Follows the initialization sequence of virtio 1.0 spec section 3.1: the device is reset,
+    /// we negotiate the features we understand, set up its request queue, and read its
+    /// configuration.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` and logs the reason if the device could not be initialized. The device is
+    /// left reset and marked `FAILED`.
+    pub fn init(device: &PciDevice) -> Option<Disk> {
+        let mut transport = Transport::new(device)?;
+        if !transport.reset() {
+            error!("Device {:?}: failed resetting.", device.requester_id());
+            return None
+        }
+        transport.add_status(STATUS_ACKNOWLEDGE);
+        transport.add_status(STATUS_DRIVER);
+
+        match Self::setup(&mut transport, device) {
+            Ok((dma, queue, requests, request_addresses, max_segments, sectors, read_only)) => {
+                transport.add_status(STATUS_DRIVER_OK);
+                let slots = (0..queue.size()).map(|_| Slot::Free).collect();
+                Some(Disk {
+                    transport,
+                    dma,
+                    queue,
+                    requests,
+                    request_addresses,
+                    slots,
+                    descriptor_waiters: Vec::new(),
+                    max_segments,
+                    sectors,
+                    read_only,
+                })
+            },
+            Err(e) => {
+                error!("Device {:?}: initialization failed, {:?}.", device.requester_id(), e);
+                // make sure the device forgets about our memory.
+                transport.reset();
+                transport.add_status(STATUS_FAILED);
+                None
+            }
+        }
+    }
+
+    /// Negotiates the features of the device, sets up its request queue, and reads its
+    /// configuration.
+    ///
+    /// # Returns
+    ///
+    /// How the device accesses our memory, the request queue, the request blocks and their
+    /// device addresses, the maximum number of data buffers of a request, the number of sectors,
+    /// and whether the device is read-only.
+    ///
+    /// # Error
+    ///
+    /// * IoError: the device doesn't support our features, or has no request queue.
+    /// * attaching the device to a device address space failed.
+    /// * allocating the request queue or the request blocks failed.
+    #[allow(clippy::type_complexity)]
+    fn setup(transport: &mut Transport, device: &PciDevice) -> Result<(Arc<Mutex<Dma>>, Virtqueue, Box<[RequestBlock]>, Vec<(u64, u64)>, usize, u64, bool), Error> {
+        let device_features = transport.device_features();
+        let mut supported = VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_RO;
+        if transport.is_modern() {
+            if device_features & VIRTIO_F_VERSION_1 == 0 {
+                error!("Device {:?}: modern device without VIRTIO_F_VERSION_1.", device.requester_id());
+                return Err(AhciError::IoError.into());
+            }
+            supported |= VIRTIO_F_VERSION_1 | VIRTIO_F_ACCESS_PLATFORM;
+        }
+        let features = device_features & supported;
+        transport.set_driver_features(features);
+        if transport.is_modern() {
+            transport.add_status(STATUS_FEATURES_OK);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                error!("Device {:?}: rejected features {:#x}.", device.requester_id(), features);
+                return Err(AhciError::IoError.into());
+            }
+        }
+
+        let mut dma = if features & VIRTIO_F_ACCESS_PLATFORM != 0 {
+            Dma::new(device.requester_id())?
+        } else {
+            Dma::Physical
+        };
+
+        let queue_size = match transport.queue_max_size(0) {
+            0 => {
+                error!("Device {:?}: no request queue.", device.requester_id());
+                return Err(AhciError::IoError.into());
+            },
+            max_size if transport.is_modern() => min(max_size, MAX_QUEUE_SIZE),
+            size => size,
+        };
+        let queue = Virtqueue::new(queue_size, &mut dma)?;
+
+        let requests: Box<[RequestBlock]> = (0..queue_size).map(|_| RequestBlock::default()).collect();
+        let requests_length = requests.len() * size_of::<RequestBlock>();
+        dma.map(requests.as_ptr(), requests_length, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
+        let request_addresses = requests.iter()
+            .map(|request| {
+                let header = request as *const RequestBlock as usize;
+                let status = &request.status as *const u8 as usize;
+                Ok((dma.contiguous(header, REQUEST_HEADER_SIZE)?, dma.contiguous(status, 1)?))
+            })
+            .collect::<Result<Vec<(u64, u64)>, Error>>();
+        let request_addresses = match request_addresses {
+            Ok(request_addresses) => request_addresses,
+            Err(e) => {
+                dma.unmap(requests.as_ptr(), requests_length);
+                let (memory, length) = queue.memory();
+                dma.unmap(memory, length);
+                return Err(e);
+            }
+        };
+
+        let (desc, avail, used) = queue.device_addresses();
+        transport.set_queue(0, queue_size, desc, avail, used);
+
+        let seg_max = if features & VIRTIO_BLK_F_SEG_MAX != 0 {
+            transport.read_config_u32(CONFIG_SEG_MAX) as usize
+        } else {
+            1
+        };
+        // a request also needs a descriptor for its header and one for its status.
+        let max_segments = min(seg_max, usize::from(queue_size) - 2);
+        let sectors = transport.read_config_u64(CONFIG_CAPACITY);
+        let read_only = features & VIRTIO_BLK_F_RO != 0;
+
+        Ok((Arc::new(Mutex::new(dma)), queue, requests, request_addresses, max_segments, sectors, read_only))
+    }
+
+    /// Checks the arguments of a transfer of `sector_count` sectors starting from `lba`,
+    /// to or from a buffer of `buffer_len` bytes.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - `buffer_len` is smaller than `sector_count` sectors,
+    ///     - `lba + sector_count` is higher than the number of addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    fn check_transfer(&self, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
+        if sector_count == 0 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        if sector_count.checked_mul(512).filter(|size| *size <= (buffer_len as u64)).is_none() {
+            return Err(AhciError::InvalidArg.into());
+        }
+        if lba.checked_add(sector_count).filter(|sum| *sum <= self.sectors).is_none() {
+            return Err(AhciError::InvalidArg.into());
+        }
+        Ok(())
+    }
+
+    /// Makes a request available to the device, transferring sectors starting from `lba` to or
+    /// from the data buffers `segments`, found at the given device addresses.
+    ///
+    /// If there are not enough free descriptors, the task is registered to be woken up when
+    /// some are freed.
+    ///
+    /// # Returns
+    ///
+    /// The head descriptor of the request. Its result must be collected with
+    /// [poll_completion](Disk::poll_completion).
+    ///
+    /// # Unsafety
+    ///
+    /// `segments` must be accessible to the device until the request is completed.
+    ///
+    /// # Panics
+    ///
+    /// * there are more than `max_segments` segments.
+    unsafe fn poll_issue(&mut self, cx: &mut Context, transfer: Transfer, lba: u64, segments: &[(u64, u32)]) -> Poll<u16> {
+        assert!(segments.len() <= self.max_segments, "Request with too many segments");
+        let head = match self.queue.next_head(segments.len() + 2) {
+            Some(head) => head,
+            None => {
+                self.descriptor_waiters.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        let (header_address, status_address) = self.request_addresses[usize::from(head)];
+        let device_writable = transfer == Transfer::Read;
+        let mut buffers = Vec::with_capacity(segments.len() + 2);
+        buffers.push((header_address, REQUEST_HEADER_SIZE as u32, false));
+        buffers.extend(segments.iter().map(|&(address, length)| (address, length, device_writable)));
+        buffers.push((status_address, 1, true));
+
+        let request = &mut self.requests[usize::from(head)];
+        ptr::write_volatile(&mut request.request_type, match transfer {
+            Transfer::Read => VIRTIO_BLK_T_IN,
+            Transfer::Write => VIRTIO_BLK_T_OUT,
+        });
+        ptr::write_volatile(&mut request.sector, lba);
+        ptr::write_volatile(&mut request.status, 0xFF);
+
+        let added = self.queue.add(&buffers);
+        debug_assert_eq!(added, Some(head));
+        if self.queue.needs_notification() {
+            self.transport.notify(0);
+        }
+        self.slots[usize::from(head)] = Slot::Issued(None);
+        Poll::Ready(head)
+    }
+
+    /// Collects the result of the request whose head descriptor is `head`.
+    ///
+    /// If the request is not completed yet, the task is registered to be woken up when it is.
+    ///
+    /// # Panics
+    ///
+    /// * No request was issued with `head`.
+    fn poll_completion(&mut self, cx: &mut Context, head: u16) -> Poll<Result<(), Error>> {
+        let slot = &mut self.slots[usize::from(head)];
+        match core::mem::replace(slot, Slot::Free) {
+            Slot::Completed(result) => Poll::Ready(result),
+            Slot::Issued(_) => {
+                *slot = Slot::Issued(Some(cx.waker().clone()));
+                Poll::Pending
+            },
+            Slot::Free => panic!("Waiting for the completion of free request {}", head),
+        }
+    }
+
+    /// Handles the interrupts of this device.
+    ///
+    /// Acknowledges the interrupt, and completes every request the device is done with, waking up
+    /// the tasks waiting for them, and the ones waiting for descriptors.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the device had no pending interrupt, as the IRQ line may be shared with
+    /// other devices, and `Some(())` otherwise.
+    pub fn handle_interrupt(&mut self) -> Option<()> {
+        if self.transport.read_isr() == 0 {
+            return None
+        }
+        while let Some((head, _)) = self.queue.pop_used() {
+            let status = unsafe {
+                // safe: the device is done with the request, and the status is a plain u8.
+                ptr::read_volatile(&self.requests[usize::from(head)].status)
+            };
+            let result = if status == VIRTIO_BLK_S_OK {
+                Ok(())
+            } else {
+                Err(AhciError::IoError.into())
+            };
+            match core::mem::replace(&mut self.slots[usize::from(head)], Slot::Completed(result)) {
+                Slot::Issued(Some(waker)) => waker.wake(),
+                Slot::Issued(None) => (),
+                slot => panic!("Device completed request {} in state {:?}", head, slot),
+            }
+        }
+        for waker in self.descriptor_waiters.drain(..) {
+            waker.wake();
+        }
+        Some(())
+    }
+}
+
+/// Splits a transfer to or from the data buffers `segments` in requests.
+///
+/// A request has up to `max_segments` segments, up to [MAX_SECTORS_PER_REQUEST] sectors, and
+/// transfers a whole number of sectors. Segments are split as needed.
+///
+/// # Error
+///
+/// - BufferTooScattered: a request cannot transfer a whole sector within `max_segments` segments.
+fn split_requests(segments: &[(u64, u32)], max_segments: usize) -> Result<Vec<Vec<(u64, u32)>>, Error> {
+    let max_length = MAX_SECTORS_PER_REQUEST * 512;
+    let mut requests = Vec::new();
+    let mut request = Vec::new();
+    let mut request_length = 0u64;
+    for &(address, length) in segments {
+        let (mut address, mut length) = (address, u64::from(length));
+        while length > 0 {
+            let mut take = min(length, max_length - request_length);
+            if request.len() + 1 == max_segments {
+                // the last segment of a request ends on a sector boundary.
+                take = ((request_length + take) & !511).saturating_sub(request_length);
+            }
+            if take == 0 {
+                if request_length == 0 || request_length % 512 != 0 {
+                    return Err(AhciError::BufferTooScattered.into());
+                }
+                requests.push(core::mem::replace(&mut request, Vec::new()));
+                request_length = 0;
+                continue;
+            }
+            request.push((address, take as u32));
+            request_length += take;
+            address += take;
+            length -= take;
+            if request_length == max_length || request.len() == max_segments {
+                requests.push(core::mem::replace(&mut request, Vec::new()));
+                request_length = 0;
+            }
+        }
+    }
+    if !request.is_empty() {
+        requests.push(request);
+    }
+    Ok(requests)
+}
+
+/// Reads or writes `sector_count` sectors starting from `lba`, to or from the `buffer_len` bytes
+/// at `buffer`.
+///
+/// The buffer is made accessible to the device, and the transfer is split in as many requests
+/// as needed, made available as descriptors are freed. They are all processed concurrently,
+/// along with the requests made by other tasks.
+///
+/// The buffer must stay valid until the returned future is resolved.
+///
+/// # Error
+///
+/// - see [Disk::check_transfer].
+/// - ReadOnly: the device is read-only, and `transfer` is a write.
+/// - making `buffer` accessible to the device failed.
+/// - see [split_requests].
+/// - IoError: the device failed processing a request.
+async fn transfer_sectors(disk: Arc<Mutex<Disk>>, transfer: Transfer, buffer: usize, buffer_len: usize, lba: u64, sector_count: u64) -> Result<(), Error> {
+    let length = sector_count as usize * 512;
+    let requests = {
+        let disk = disk.lock();
+        disk.check_transfer(buffer_len, lba, sector_count)?;
+        if disk.read_only && transfer == Transfer::Write {
+            return Err(AhciError::ReadOnly.into());
+        }
+        let perm = match transfer {
+            Transfer::Read => MemoryPermissions::WRITABLE,
+            Transfer::Write => MemoryPermissions::READABLE,
+        };
+        let mut dma = disk.dma.lock();
+        dma.map(buffer as *const u8, length, perm)?;
+        let requests = dma.segments(buffer, length)
+            .and_then(|segments| split_requests(&segments, disk.max_segments));
+        if requests.is_err() {
+            dma.unmap(buffer as *const u8, length);
+        }
+        requests?
+    };
+
+    let mut heads = Vec::new();
+    let mut request_lba = lba;
+    for segments in &requests {
+        let head = future::poll_fn(|cx| unsafe {
+            // safe: the buffer stays accessible until every request is completed.
+            disk.lock().poll_issue(cx, transfer, request_lba, segments)
+        }).await;
+        heads.push(head);
+        request_lba += segments.iter().map(|&(_, length)| u64::from(length)).sum::<u64>() / 512;
+    }
+    // wait for every request, even on error, the device might still be using the buffer.
+    let mut result = Ok(());
+    for head in heads {
+        let completed = future::poll_fn(|cx| disk.lock().poll_completion(cx, head)).await;
+        if result.is_ok() {
+            result = completed;
+        }
+    }
+    // the requests are over, the device is done with the buffer.
+    disk.lock().dma.lock().unmap(buffer as *const u8, length);
+    result
+}
+
+impl Drop for Disk {
+    /// Dropping a disk resets the device, and makes our memory inaccessible to it.
+    fn drop(&mut self) {
+        self.transport.reset();
+        let mut dma = self.dma.lock();
+        dma.unmap(self.requests.as_ptr(), self.requests.len() * size_of::<RequestBlock>());
+        let (memory, length) = self.queue.memory();
+        dma.unmap(memory, length);
+    }
+}
+
+impl Debug for Disk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("Disk")
+            .field("sectors", &self.sectors)
+            .field("read_only", &self.read_only)
+            .field("queue_size", &self.queue.size())
+            .field("max_segments", &self.max_segments)
+            .field("transport", &self.transport)
+            .finish()
+    }
+}
+
+/// A buffer shared with a client, registered with
+/// [register_shared_buffer](IDiskInterface::register_shared_buffer).
+///
+/// It is mapped in our address space, and made accessible to the device for as long as it is
+/// registered, so the transfers using it don't have to map it again.
+#[derive(Debug)]
+struct SharedBuffer {
+    /// Our mapping of the shared memory.
+    memory: MappedSharedMemory,
+    /// How the device accesses our memory.
+    dma: Arc<Mutex<Dma>>,
+}
+
+impl SharedBuffer {
+    /// Maps the `size` bytes of `buffer`, in our address space and for the device.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `size` is 0, or not page aligned.
+    /// - `size` is not the size of the shared memory.
+    /// - making it accessible to the device failed.
+    fn new(buffer: SharedMemory, size: u64, dma: Arc<Mutex<Dma>>) -> Result<Self, Error> {
+        let size = size as usize;
+        if size == 0 || size % PAGE_SIZE != 0 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        let address = find_free_address(size, PAGE_SIZE)?;
+        let memory = buffer.map(address, size, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
+        // the device both reads and writes it.
+        dma.lock().map(memory.as_ptr(), size, MemoryPermissions::READABLE | MemoryPermissions::WRITABLE)?;
+        Ok(SharedBuffer { memory, dma })
+    }
+
+    /// Returns the address of the buffer at `offset`, and the number of bytes after it.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `offset` is out of the buffer.
+    fn range(&self, offset: u64) -> Result<(usize, usize), Error> {
+        if offset >= self.memory.len() as u64 {
+            return Err(AhciError::InvalidArg.into());
+        }
+        Ok((self.memory.as_ptr() as usize + offset as usize, self.memory.len() - offset as usize))
+    }
+}
+
+impl Drop for SharedBuffer {
+    /// Makes the buffer inaccessible to the device. No transfer can be using it, as they
+    /// hold a reference to it.
+    fn drop(&mut self) {
+        self.dma.lock().unmap(self.memory.as_ptr(), self.memory.len());
+    }
+}
+
+/// Transfers sectors to or from the shared buffer, at `offset`.
+///
+/// # Error
+///
+/// - InvalidArg: no buffer was registered, or `offset` is out of it.
+/// - see [transfer_sectors].
+async fn transfer_shared(disk: Arc<Mutex<Disk>>, shared_buffer: Option<Arc<SharedBuffer>>, transfer: Transfer, offset: u64, lba: u64, sector_count: u64) -> Result<(), Error> {
+    let shared_buffer = shared_buffer.ok_or(AhciError::InvalidArg)?;
+    let (buffer, buffer_len) = shared_buffer.range(offset)?;
+    // holding the shared buffer keeps it accessible until the transfer is over.
+    transfer_sectors(disk, transfer, buffer, buffer_len, lba, sector_count).await
+}
+
+/// Interface to a disk.
+///
+/// Each session has its own interface, and its own shared buffer.
+#[derive(Debug, Clone)]
+pub struct IDisk {
+    /// The disk this session accesses.
+    disk: Arc<Mutex<Disk>>,
+    /// The buffer registered by the client, if any.
+    shared_buffer: Option<Arc<SharedBuffer>>,
+}
+
+impl IDisk {
+    /// Creates an IDisk from the wrapped [Disk].
+    pub fn new(value: Arc<Mutex<Disk>>) -> Self {
+        Self { disk: value, shared_buffer: None }
+    }
+}
+
+impl IDiskInterface for IDisk {
+    /// Returns the number of addressable 512-octet sectors for this disk.
+    fn sector_count<'a>(&'a mut self, _work_queue: WorkQueue<'static>) -> FutureObj<'a, Result<u64, Error>> {
+        FutureObj::new(Box::new(future::ready(Ok(self.disk.lock().sectors))))
+    }
+
+    /// Reads sectors from disk.
+    ///
+    /// Reads `sector_count` sectors starting from `lba`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - BufferTooScattered: the buffer is too physically scattered for the device.
+    /// - IoError: the device failed reading the sectors.
+    fn read_dma<'a>(&'a mut self, _work_queue: WorkQueue<'static>, address: u64, out_blocks: &'a mut [Block]) -> FutureObj<'a, Result<(), Error>> {
+        let disk = Arc::clone(&self.disk);
+        FutureObj::new(Box::new(async move {
+            let buffer = out_blocks.as_mut_ptr() as usize;
+            let buffer_len = out_blocks.len() * size_of::<Block>();
+            transfer_sectors(disk, Transfer::Read, buffer, buffer_len, address, out_blocks.len() as u64).await
+        }))
+    }
+
+    /// Writes sectors to disk.
+    ///
+    /// Writes `sector_count` sectors starting from `lba`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - BufferTooScattered: the buffer is too physically scattered for the device.
+    /// - IoError: the device failed writing the sectors.
+    /// - ReadOnly: the device is read-only.
+    fn write_dma<'a>(&'a mut self, _work_queue: WorkQueue<'static>, address: u64, in_blocks: &'a [Block]) -> FutureObj<'a, Result<(), Error>> {
+        let disk = Arc::clone(&self.disk);
+        FutureObj::new(Box::new(async move {
+            let buffer = in_blocks.as_ptr() as usize;
+            let buffer_len = in_blocks.len() * size_of::<Block>();
+            transfer_sectors(disk, Transfer::Write, buffer, buffer_len, address, in_blocks.len() as u64).await
+        }))
+    }
+
+    /// Registers a buffer shared with the client, for [read_shared] and [write_shared].
+    ///
+    /// It replaces the previously registered buffer, which is released once the transfers
+    /// using it are over.
+    ///
+    /// [read_shared]: IDiskInterface::read_shared
+    /// [write_shared]: IDiskInterface::write_shared
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `size` is 0, or not page aligned.
+    /// - `size` is not the size of the shared memory.
+    /// - making it accessible to the device failed.
+    fn register_shared_buffer<'a>(&'a mut self, _work_queue: WorkQueue<'static>, buffer: SharedMemory, size: u64) -> FutureObj<'a, Result<(), Error>> {
+        let dma = Arc::clone(&self.disk.lock().dma);
+        let registered = SharedBuffer::new(buffer, size, dma).map(|shared_buffer| {
+            self.shared_buffer = Some(Arc::new(shared_buffer));
+        });
+        FutureObj::new(Box::new(future::ready(registered)))
+    }
+
+    /// Reads sectors from disk to the shared buffer.
+    ///
+    /// Reads `sector_count` sectors starting from `lba`, to the shared buffer at `offset`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - no buffer was registered,
+    ///     - `offset + sector_count * 512` is out of the shared buffer,
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the device failed reading the sectors.
+    fn read_shared<'a>(&'a mut self, _work_queue: WorkQueue<'static>, offset: u64, lba: u64, sector_count: u64) -> FutureObj<'a, Result<(), Error>> {
+        let transfer = transfer_shared(Arc::clone(&self.disk), self.shared_buffer.clone(), Transfer::Read, offset, lba, sector_count);
+        FutureObj::new(Box::new(transfer))
+    }
+
+    /// Writes sectors to disk from the shared buffer.
+    ///
+    /// Writes `sector_count` sectors starting from `lba`, from the shared buffer at `offset`.
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg:
+    ///     - no buffer was registered,
+    ///     - `offset + sector_count * 512` is out of the shared buffer,
+    ///     - `lba`, `sector_count`, or `lba + sector_count` is higher than the number of
+    ///        addressable sectors on this disk,
+    ///     - `sector_count` == 0.
+    /// - IoError: the device failed writing the sectors.
+    /// - ReadOnly: the device is read-only.
+    fn write_shared<'a>(&'a mut self, _work_queue: WorkQueue<'static>, offset: u64, lba: u64, sector_count: u64) -> FutureObj<'a, Result<(), Error>> {
+        let transfer = transfer_shared(Arc::clone(&self.disk), self.shared_buffer.clone(), Transfer::Write, offset, lba, sector_count);
+        FutureObj::new(Box::new(transfer))
+    }
+}
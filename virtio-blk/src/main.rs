@@ -0,0 +1,275 @@
+//! virtio-blk driver module
+//!
+//! This driver discovers virtio block devices on the PCI, initialize them,
+//! and exposes IPC endpoints to read and write sectors from/to a disk.
+//!
+//! In QEMU, a virtio block device is attached with `-drive file=disk.img,if=virtio,format=raw`,
+//! or with `-device virtio-blk-pci` for more control over its options.
+//!
+//! # Features
+//!
+//! Both the legacy interface, found in the IO space of devices predating virtio 1.0, and the
+//! modern interface, found in memory space, are supported. See the [transport] module.
+//!
+//! Here's a list of wonderful features this driver does **not** provide:
+//!
+//! - hotplug/remove of a device
+//! - MSI-X, we use the legacy interrupt line
+//! - multiple request queues
+//! - packed virtqueues, indirect descriptors, and event indexes
+//! - flush, discard and write zeroes requests
+//!
+//! # Interface
+//!
+//! This driver exposes the same IPC interfaces as the AHCI driver: [VirtioBlkInterface],
+//! registered as `"vblk:\0"`, and some [IDisk]s.
+//!
+//! Basically at initialization the driver will assign an id to every discovered disk.
+//! You can then ask the [VirtioBlkInterface] to give you a session to any [IDisk] from its id,
+//! and finally read/write some sectors.
+//!
+//! # DMA
+//!
+//! A virtio device only goes through the IOMMU when the `VIRTIO_F_ACCESS_PLATFORM` feature is
+//! negotiated, which legacy devices don't have. Otherwise, we give it physical addresses.
+//! See [Dma](sunrise_libuser::dma::Dma).
+//!
+//! # Parallelism
+//!
+//! This driver is single-threaded, but asynchronous: every [IDisk] session is served by its own
+//! future on the [WaitableManager], and a request is made available to the device without
+//! blocking.
+//!
+//! As many requests can be outstanding on a disk as fit in its request queue. The device raises
+//! an interrupt when it completes some. For each device, a future waits on its interrupt event,
+//! and completes its requests, waking up the tasks that were waiting for them.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![deny(missing_docs)]
+#![deny(clippy::missing_docs_in_private_items)]
+#![deny(intra_doc_link_resolution_failure)]
+
+extern crate alloc;
+#[macro_use]
+extern crate sunrise_libuser;
+#[macro_use]
+extern crate log;
+
+mod transport;
+mod virtqueue;
+mod disk;
+
+use crate::disk::{Disk, IDisk};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use sunrise_libuser::error::{Error, AhciError};
+use sunrise_libuser::futures::{WaitableManager, WorkQueue};
+use sunrise_libuser::ipc::server::{port_handler, new_session_wrapper};
+use sunrise_libuser::types::ReadableEvent;
+use spin::Mutex;
+use sunrise_libuser::syscalls;
+use sunrise_libuser::pci;
+use sunrise_libuser::ahci::{AhciInterface as IAhciInterface, IDiskProxy, IDiskAsync as _};
+use sunrise_libuser::futures_rs::future::FutureObj;
+
+/// The PCI vendor id of virtio devices.
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+/// The PCI device id of transitional block devices, which may only have the legacy interface.
+const VIRTIO_BLK_TRANSITIONAL_DEVICE_ID: u16 = 0x1001;
+/// The PCI device id of modern block devices: `0x1040` + the virtio device id, 2.
+const VIRTIO_BLK_MODERN_DEVICE_ID: u16 = 0x1042;
+
+/// Array of discovered disk.
+///
+/// At startup, the driver will initialize each disk it discovers,
+/// and populate this vec.
+///
+/// As hotplug/remove of a disk is not supported, this array remains
+/// unchanged for the rest of the driver's execution.
+///
+/// A disk id is just the index of a disk in this array.
+static DISKS: Mutex<Vec<Arc<Mutex<Disk>>>> = Mutex::new(Vec::new());
+
+/// virtio-blk driver initialisation.
+///
+/// 1. Discover virtio block devices on the PCI.
+/// 2. For every found device:
+///     - Subscribe to its interrupt line.
+///     - Initialize it, and push the created [Disk] in [DISKS].
+/// 3. Start the event loop, handling the interrupts of every device.
+///
+/// The `"vblk:\0"` port is registered even if no device was found, as clients wait for it.
+fn main() {
+    debug!("virtio-blk driver starting up");
+    let mut disks = Vec::new();
+    for device in pci::discover().iter()
+        .filter(|device| device.vid == VIRTIO_VENDOR_ID)
+        .filter(|device| device.did == VIRTIO_BLK_TRANSITIONAL_DEVICE_ID || device.did == VIRTIO_BLK_MODERN_DEVICE_ID)
+    {
+        let irq = match device.interrupt_line() {
+            Some(irq) if irq != 0xFF => irq,
+            _ => {
+                error!("Device {:?}, initialization failed: no interrupt line.", device.requester_id());
+                continue;
+            }
+        };
+        let irq_event = match syscalls::create_interrupt_event(irq as usize, 0) {
+            Ok(irq_event) => irq_event,
+            Err(e) => {
+                error!("Device {:?}, initialization failed: failed subscribing to IRQ {}, {:?}.", device.requester_id(), irq, e);
+                continue;
+            }
+        };
+        device.enable_bus_master();
+        device.enable_interrupts();
+        if let Some(disk) = Disk::init(device) {
+            let disk = Arc::new(Mutex::new(disk));
+            DISKS.lock().push(Arc::clone(&disk));
+            disks.push((irq_event, disk));
+        }
+    }
+    debug!("virtio-blk initialised disks : {:#x?}", DISKS);
+
+    // event loop
+    let mut man = WaitableManager::new();
+    let handler = port_handler(man.work_queue(), "vblk:\0", VirtioBlkInterface::dispatch).unwrap();
+    man.work_queue().spawn(FutureObj::new(Box::new(handler)));
+    for (irq_event, disk) in disks {
+        let interrupts_future = handle_interrupts(man.work_queue(), irq_event, disk);
+        man.work_queue().spawn(FutureObj::new(Box::new(interrupts_future)));
+    }
+    man.run();
+}
+
+/// Task responsible for handling the interrupts of a device, completing its requests.
+// https://github.com/rust-lang/rust-clippy/issues/3988
+// Should remove on next toolchain upgrade.
+#[allow(clippy::needless_lifetimes)]
+async fn handle_interrupts(work_queue: WorkQueue<'_>, irq_event: ReadableEvent, disk: Arc<Mutex<Disk>>) {
+    loop {
+        irq_event.wait_async_cb(work_queue.clone(), || disk.lock().handle_interrupt()).await;
+    }
+}
+
+/// Main interface to the virtio-blk driver.
+///
+/// Registered under the name `"vblk:\0"` to the Service Manager, after the discovery stage.
+///
+/// Provides an endpoint to get the number of discovered disks,
+/// and another one to get a session to a given disk.
+///
+/// As hotplug/remove of a disk is not supported, a disk id remains valid for the whole
+/// lifetime of the virtio-blk driver.
+#[derive(Default, Debug, Clone)]
+struct VirtioBlkInterface;
+
+impl IAhciInterface for VirtioBlkInterface {
+    /// Returns the number of discovered disks.
+    ///
+    /// Any number in the range `0..disk_count()` is considered a valid disk id.
+    fn discovered_disks_count(&mut self, _manager: WorkQueue<'static>) -> Result<u32, Error> {
+        Ok(DISKS.lock().len() as u32)
+    }
+
+    /// Gets the interface to a disk.
+    ///
+    /// This creates a session to an [IDisk].
+    ///
+    /// # Error
+    ///
+    /// - InvalidArg: `disk_id` is not a valid disk id.
+    fn get_disk(&mut self, work_queue: WorkQueue<'static>, disk_id: u32,) -> Result<IDiskProxy, Error> {
+        let idisk = IDisk::new(Arc::clone(
+            DISKS.lock().get(disk_id as usize)
+            .ok_or(AhciError::InvalidArg)?
+        ));
+        let (server, client) = syscalls::create_session(false, 0)?;
+        let wrapper = new_session_wrapper(work_queue.clone(), server, idisk, IDisk::dispatch);
+        work_queue.spawn(FutureObj::new(Box::new(wrapper)));
+        Ok(IDiskProxy::from(client))
+    }
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"virtio-blk\0\0",
+    title_id: 0x0200000000000101,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::ExitProcessWithCode,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::CreateSharedMemory,
+        sunrise_libuser::syscalls::nr::CreateContiguousSharedMemory,
+        sunrise_libuser::syscalls::nr::MapSharedMemory,
+        sunrise_libuser::syscalls::nr::UnmapSharedMemory,
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::CreateInterruptEvent,
+        sunrise_libuser::syscalls::nr::QueryPhysicalAddress,
+        sunrise_libuser::syscalls::nr::CreateDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::AttachDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::MapDeviceAddressSpaceByForce,
+        sunrise_libuser::syscalls::nr::UnmapDeviceAddressSpace,
+        sunrise_libuser::syscalls::nr::MapMmioRegion,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+        sunrise_libuser::syscalls::nr::ReplyAndReceiveWithUserBuffer,
+        sunrise_libuser::syscalls::nr::AcceptSession,
+        sunrise_libuser::syscalls::nr::CreateSession,
+    ],
+    raw_caps: [
+        // todo: IRQ capabilities at runtime
+        // body: Currently IRQ capabilities are declared at compile-time.
+        // body:
+        // body: However, for PCI, the IRQ line we want to subscribe to
+        // body: can only be determined at runtime by reading the `Interrupt Line` register
+        // body: that has been set-up during POST.
+        // body:
+        // body: For now we declare the lines the BIOS usually routes PCI interrupts to.
+        sunrise_libuser::caps::irq_pair(5, 9), sunrise_libuser::caps::irq_pair(10, 11),
+        sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 0), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 1), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 2), sunrise_libuser::caps::ioport(pci::CONFIG_ADDRESS + 3),
+        sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 0), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 1), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 2), sunrise_libuser::caps::ioport(pci::CONFIG_DATA    + 3),
+        // Virtio block devices, which present themselves as SCSI controllers.
+        sunrise_libuser::caps::pci_class(0x01, 0x00),
+        // todo: legacy virtio IO ports at runtime
+        // body: Like the IRQ lines, the IO space BAR of a legacy virtio device is only known
+        // body: at runtime. For now we declare the registers of a device whose IO BAR the BIOS
+        // body: assigned at 0xC000, the first address it usually hands out. Other legacy devices
+        // body: cannot be driven, only their modern interface can.
+        sunrise_libuser::caps::ioport(0xC000), sunrise_libuser::caps::ioport(0xC001), sunrise_libuser::caps::ioport(0xC002), sunrise_libuser::caps::ioport(0xC003),
+        sunrise_libuser::caps::ioport(0xC004), sunrise_libuser::caps::ioport(0xC005), sunrise_libuser::caps::ioport(0xC006), sunrise_libuser::caps::ioport(0xC007),
+        sunrise_libuser::caps::ioport(0xC008), sunrise_libuser::caps::ioport(0xC009), sunrise_libuser::caps::ioport(0xC00A), sunrise_libuser::caps::ioport(0xC00B),
+        sunrise_libuser::caps::ioport(0xC00C), sunrise_libuser::caps::ioport(0xC00D), sunrise_libuser::caps::ioport(0xC00E), sunrise_libuser::caps::ioport(0xC00F),
+        sunrise_libuser::caps::ioport(0xC010), sunrise_libuser::caps::ioport(0xC011), sunrise_libuser::caps::ioport(0xC012), sunrise_libuser::caps::ioport(0xC013),
+        sunrise_libuser::caps::ioport(0xC014), sunrise_libuser::caps::ioport(0xC015), sunrise_libuser::caps::ioport(0xC016), sunrise_libuser::caps::ioport(0xC017),
+        sunrise_libuser::caps::ioport(0xC018), sunrise_libuser::caps::ioport(0xC019), sunrise_libuser::caps::ioport(0xC01A), sunrise_libuser::caps::ioport(0xC01B),
+        sunrise_libuser::caps::ioport(0xC01C), sunrise_libuser::caps::ioport(0xC01D), sunrise_libuser::caps::ioport(0xC01E), sunrise_libuser::caps::ioport(0xC01F),
+        sunrise_libuser::caps::ioport(0xC020), sunrise_libuser::caps::ioport(0xC021), sunrise_libuser::caps::ioport(0xC022), sunrise_libuser::caps::ioport(0xC023),
+    ]
+});
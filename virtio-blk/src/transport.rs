@@ -0,0 +1,544 @@
+//! Virtio over PCI
+//!
+//! A virtio device exposes its registers on the PCI in one of two ways:
+//!
+//! - the legacy interface, found in the IO space BAR0 of devices predating virtio 1.0.
+//! - the modern interface, found in memory space BARs, at locations described by
+//!   vendor-specific PCI capabilities. See virtio 1.0 spec section 4.1.4.
+//!
+//! Transitional devices expose both, in which case we use the modern one.
+//!
+//! Both interfaces expose the same operations: a device status, feature bits to negotiate,
+//! virtqueues to set up and notify, an ISR status to acknowledge interrupts, and a
+//! device-specific configuration.
+
+use core::fmt::{self, Debug, Formatter};
+use core::mem::size_of;
+use sunrise_libuser::caps;
+use sunrise_libuser::io::{Io, Mmio, Pio};
+use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+use sunrise_libuser::pci::{PciDevice, BAR};
+use sunrise_libuser::syscalls;
+use sunrise_libutils::align_up;
+use static_assertions::assert_eq_size;
+
+/// `ACKNOWLEDGE` device status bit: we found the device.
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+/// `DRIVER` device status bit: we know how to drive the device.
+pub const STATUS_DRIVER: u8 = 2;
+/// `DRIVER_OK` device status bit: the driver is set up, and ready to drive the device.
+pub const STATUS_DRIVER_OK: u8 = 4;
+/// `FEATURES_OK` device status bit: we acknowledged the features we understand.
+pub const STATUS_FEATURES_OK: u8 = 8;
+/// `FAILED` device status bit: we gave up on the device.
+pub const STATUS_FAILED: u8 = 128;
+
+/// Register offsets of the legacy interface, in BAR0.
+mod legacy {
+    /// Device features, 32 bits.
+    pub const DEVICE_FEATURES: u16 = 0x00;
+    /// Driver features, 32 bits.
+    pub const DRIVER_FEATURES: u16 = 0x04;
+    /// Page frame number of the selected queue, 32 bits.
+    pub const QUEUE_ADDRESS: u16 = 0x08;
+    /// Size of the selected queue, 16 bits.
+    pub const QUEUE_SIZE: u16 = 0x0C;
+    /// Queue select, 16 bits.
+    pub const QUEUE_SELECT: u16 = 0x0E;
+    /// Queue notify, 16 bits.
+    pub const QUEUE_NOTIFY: u16 = 0x10;
+    /// Device status, 8 bits.
+    pub const DEVICE_STATUS: u16 = 0x12;
+    /// ISR status, 8 bits.
+    pub const ISR_STATUS: u16 = 0x13;
+    /// Start of the device-specific configuration, when MSI-X is disabled.
+    pub const DEVICE_CONFIG: u16 = 0x14;
+}
+
+/// The number of bytes of the device-specific configuration we can read through the legacy
+/// interface.
+///
+/// We must own the IO ports from BAR0 to the end of this configuration to use it.
+pub const LEGACY_DEVICE_CONFIG_LEN: u16 = 0x10;
+
+/// PCI capability id of vendor-specific capabilities, which describe the modern interface.
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+/// `cfg_type` of the capability locating the common configuration.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+/// `cfg_type` of the capability locating the notification area.
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+/// `cfg_type` of the capability locating the ISR status.
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+/// `cfg_type` of the capability locating the device-specific configuration.
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// The number of times we poll the device status after a reset, waiting for it to be done.
+const RESET_TIMEOUT: usize = 0x10000;
+
+/// The common configuration of the modern interface.
+///
+/// See virtio 1.0 spec section 4.1.4.3.
+#[repr(packed)]
+#[allow(clippy::missing_docs_in_private_items)]
+struct CommonCfg {
+    device_feature_select: Mmio<u32>,
+    device_feature: Mmio<u32>,
+    driver_feature_select: Mmio<u32>,
+    driver_feature: Mmio<u32>,
+    msix_config: Mmio<u16>,
+    num_queues: Mmio<u16>,
+    device_status: Mmio<u8>,
+    config_generation: Mmio<u8>,
+    queue_select: Mmio<u16>,
+    queue_size: Mmio<u16>,
+    queue_msix_vector: Mmio<u16>,
+    queue_enable: Mmio<u16>,
+    queue_notify_off: Mmio<u16>,
+    /// Lower and upper halves of the address of the descriptor table.
+    queue_desc: [Mmio<u32>; 2],
+    /// Lower and upper halves of the address of the available ring.
+    queue_driver: [Mmio<u32>; 2],
+    /// Lower and upper halves of the address of the used ring.
+    queue_device: [Mmio<u32>; 2],
+}
+
+assert_eq_size!(CommonCfg, [u8; 0x38]);
+
+/// The location of a structure of the modern interface, read from its capability.
+#[derive(Debug, Clone, Copy)]
+struct CapabilityLocation {
+    /// The BAR the structure is found in.
+    bar: usize,
+    /// The offset of the structure in the BAR.
+    offset: usize,
+    /// The length of the structure.
+    length: usize,
+}
+
+/// The legacy interface, in IO space.
+#[derive(Debug)]
+pub struct LegacyTransport {
+    /// The first IO port of BAR0.
+    base: u16,
+}
+
+/// The modern interface, in memory space.
+pub struct ModernTransport {
+    /// The common configuration.
+    common: &'static mut CommonCfg,
+    /// The ISR status.
+    isr: &'static Mmio<u8>,
+    /// Virtual address of the notification area.
+    notify: usize,
+    /// The multiplier of the queues' `queue_notify_off`, giving their offset in the notification area.
+    notify_off_multiplier: u32,
+    /// Virtual address of the device-specific configuration.
+    device_config: usize,
+    /// Length of the device-specific configuration.
+    device_config_len: usize,
+    /// The offset of the selected queue in the notification area.
+    queue_notify_offsets: [usize; 1],
+}
+
+impl Debug for ModernTransport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.debug_struct("ModernTransport")
+            .field("notify", &self.notify)
+            .field("notify_off_multiplier", &self.notify_off_multiplier)
+            .field("device_config", &self.device_config)
+            .finish()
+    }
+}
+
+/// The interface a virtio device is driven through.
+#[derive(Debug)]
+pub enum Transport {
+    /// The legacy interface, in IO space.
+    Legacy(LegacyTransport),
+    /// The modern interface, in memory space.
+    Modern(ModernTransport),
+}
+
+impl Transport {
+    /// Finds the interface of a virtio device.
+    ///
+    /// Uses the modern interface if the device has one, and the legacy one otherwise.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` and logs the reason if neither can be used. This can happen if:
+    ///
+    /// * the modern structures are in a BAR we cannot map.
+    /// * the device only has the legacy interface, and its IO ports were not declared in our
+    ///   capabilities.
+    pub fn new(device: &PciDevice) -> Option<Transport> {
+        if device.capabilities().any(|cap| cap.id == PCI_CAP_ID_VENDOR) {
+            return ModernTransport::new(device).map(Transport::Modern);
+        }
+        LegacyTransport::new(device).map(Transport::Legacy)
+    }
+
+    /// Whether this is the modern interface.
+    pub fn is_modern(&self) -> bool {
+        match self {
+            Transport::Legacy(_) => false,
+            Transport::Modern(_) => true,
+        }
+    }
+
+    /// Resets the device, stopping all its DMAs.
+    ///
+    /// Returns false if the device didn't complete its reset in time.
+    pub fn reset(&mut self) -> bool {
+        self.set_status(0);
+        // Legacy devices are reset as soon as the status is written,
+        // modern ones report it by reading back 0.
+        (0..RESET_TIMEOUT).any(|_| self.status() == 0)
+    }
+
+    /// Reads the device status.
+    pub fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy(legacy) => legacy.read_u8(legacy::DEVICE_STATUS),
+            Transport::Modern(modern) => modern.common.device_status.read(),
+        }
+    }
+
+    /// Writes the device status.
+    pub fn set_status(&mut self, status: u8) {
+        match self {
+            Transport::Legacy(legacy) => legacy.write_u8(legacy::DEVICE_STATUS, status),
+            Transport::Modern(modern) => modern.common.device_status.write(status),
+        }
+    }
+
+    /// Sets `bits` in the device status.
+    pub fn add_status(&mut self, bits: u8) {
+        let status = self.status();
+        self.set_status(status | bits);
+    }
+
+    /// Reads the features offered by the device.
+    ///
+    /// The legacy interface only has the first 32 feature bits.
+    pub fn device_features(&mut self) -> u64 {
+        match self {
+            Transport::Legacy(legacy) => u64::from(legacy.read_u32(legacy::DEVICE_FEATURES)),
+            Transport::Modern(modern) => {
+                modern.common.device_feature_select.write(0);
+                let low = modern.common.device_feature.read();
+                modern.common.device_feature_select.write(1);
+                let high = modern.common.device_feature.read();
+                u64::from(high) << 32 | u64::from(low)
+            }
+        }
+    }
+
+    /// Writes the features the driver accepted.
+    pub fn set_driver_features(&mut self, features: u64) {
+        match self {
+            Transport::Legacy(legacy) => legacy.write_u32(legacy::DRIVER_FEATURES, features as u32),
+            Transport::Modern(modern) => {
+                modern.common.driver_feature_select.write(0);
+                modern.common.driver_feature.write(features as u32);
+                modern.common.driver_feature_select.write(1);
+                modern.common.driver_feature.write((features >> 32) as u32);
+            }
+        }
+    }
+
+    /// Reads the maximum size of the virtqueue `queue`, 0 if it doesn't exist.
+    ///
+    /// The size of a legacy queue is fixed, it can only be used with this size.
+    pub fn queue_max_size(&mut self, queue: u16) -> u16 {
+        match self {
+            Transport::Legacy(legacy) => {
+                legacy.write_u16(legacy::QUEUE_SELECT, queue);
+                legacy.read_u16(legacy::QUEUE_SIZE)
+            },
+            Transport::Modern(modern) => {
+                if queue >= modern.common.num_queues.read() {
+                    return 0;
+                }
+                modern.common.queue_select.write(queue);
+                modern.common.queue_size.read()
+            }
+        }
+    }
+
+    /// Sets up the virtqueue `queue`, whose descriptor table, available ring and used ring are
+    /// found at the given device addresses, and enables it.
+    ///
+    /// The legacy interface only takes the address of the descriptor table: the rings must
+    /// follow it, laid out as described in virtio 1.0 spec section 2.4.2, and it must be page
+    /// aligned, below 16TiB.
+    ///
+    /// # Panics
+    ///
+    /// * `queue` is not 0, we only support one queue.
+    pub fn set_queue(&mut self, queue: u16, size: u16, desc: u64, avail: u64, used: u64) {
+        assert_eq!(queue, 0, "Only queue 0 is supported");
+        match self {
+            Transport::Legacy(legacy) => {
+                legacy.write_u16(legacy::QUEUE_SELECT, queue);
+                legacy.write_u32(legacy::QUEUE_ADDRESS, (desc / PAGE_SIZE as u64) as u32);
+            },
+            Transport::Modern(modern) => {
+                let common = &mut *modern.common;
+                common.queue_select.write(queue);
+                common.queue_size.write(size);
+                // we use the legacy interrupt line.
+                common.queue_msix_vector.write(0xFFFF);
+                common.queue_desc[0].write(desc as u32);
+                common.queue_desc[1].write((desc >> 32) as u32);
+                common.queue_driver[0].write(avail as u32);
+                common.queue_driver[1].write((avail >> 32) as u32);
+                common.queue_device[0].write(used as u32);
+                common.queue_device[1].write((used >> 32) as u32);
+                let notify_off = common.queue_notify_off.read();
+                modern.queue_notify_offsets[queue as usize] = usize::from(notify_off) * modern.notify_off_multiplier as usize;
+                common.queue_enable.write(1);
+            }
+        }
+    }
+
+    /// Notifies the device that new buffers are available in the virtqueue `queue`.
+    pub fn notify(&mut self, queue: u16) {
+        match self {
+            Transport::Legacy(legacy) => legacy.write_u16(legacy::QUEUE_NOTIFY, queue),
+            Transport::Modern(modern) => {
+                let address = modern.notify + modern.queue_notify_offsets[queue as usize];
+                unsafe {
+                    // safe: the notification address of the queue is in the mapping of the
+                    //       notification area, which is never unmapped.
+                    (*(address as *mut Mmio<u16>)).write(queue)
+                }
+            }
+        }
+    }
+
+    /// Reads the ISR status, which acknowledges the interrupt and de-asserts the interrupt line.
+    ///
+    /// Bit 0 is set when a virtqueue was used, bit 1 when the configuration changed.
+    pub fn read_isr(&mut self) -> u8 {
+        match self {
+            Transport::Legacy(legacy) => legacy.read_u8(legacy::ISR_STATUS),
+            Transport::Modern(modern) => modern.isr.read(),
+        }
+    }
+
+    /// Reads the 32-bit field at `offset` in the device-specific configuration.
+    ///
+    /// # Panics
+    ///
+    /// * the field is out of the configuration we can read.
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        match self {
+            Transport::Legacy(legacy) => {
+                assert!(offset + size_of::<u32>() <= LEGACY_DEVICE_CONFIG_LEN as usize, "Reading out of the device configuration");
+                legacy.read_u32(legacy::DEVICE_CONFIG + offset as u16)
+            },
+            Transport::Modern(modern) => {
+                assert!(offset + size_of::<u32>() <= modern.device_config_len, "Reading out of the device configuration");
+                unsafe {
+                    // safe: the field is in the mapping of the device configuration, which is
+                    //       never unmapped.
+                    (*((modern.device_config + offset) as *const Mmio<u32>)).read()
+                }
+            }
+        }
+    }
+
+    /// Reads the 64-bit field at `offset` in the device-specific configuration.
+    ///
+    /// The two halves are read again if the device changed its configuration in-between.
+    ///
+    /// # Panics
+    ///
+    /// * the field is out of the configuration we can read.
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let low = self.read_config_u32(offset);
+            let high = self.read_config_u32(offset + size_of::<u32>());
+            if generation == self.config_generation() {
+                return u64::from(high) << 32 | u64::from(low);
+            }
+        }
+    }
+
+    /// Reads the configuration generation, which changes when the device-specific
+    /// configuration changes. The legacy interface doesn't have one.
+    fn config_generation(&self) -> u8 {
+        match self {
+            Transport::Legacy(_) => 0,
+            Transport::Modern(modern) => modern.common.config_generation.read(),
+        }
+    }
+}
+
+impl LegacyTransport {
+    /// Finds the legacy interface of a device, in its IO space BAR0.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` and logs the reason if BAR0 is not an IO space BAR, or if we don't own
+    /// its IO ports.
+    fn new(device: &PciDevice) -> Option<LegacyTransport> {
+        let base = match device.bar(0) {
+            Some(BAR::Io(base, size)) if size >= u32::from(legacy::DEVICE_CONFIG + LEGACY_DEVICE_CONFIG_LEN) => base as u16,
+            bar => {
+                error!("Device {:?}: unexpected legacy BAR0 {:x?}.", device.requester_id(), bar);
+                return None
+            }
+        };
+        let end = base + legacy::DEVICE_CONFIG + LEGACY_DEVICE_CONFIG_LEN;
+        if let Some(port) = (base..end).find(|port| !caps::owns_ioport(*port)) {
+            error!("Device {:?}: its legacy interface is at IO ports {:#06x}-{:#06x}, but we don't own port {:#06x}.",
+                   device.requester_id(), base, end, port);
+            return None
+        }
+        Some(LegacyTransport { base })
+    }
+
+    /// Reads the 8-bit register at `offset`.
+    fn read_u8(&self, offset: u16) -> u8 {
+        Pio::<u8>::new(self.base + offset).read()
+    }
+
+    /// Reads the 16-bit register at `offset`.
+    fn read_u16(&self, offset: u16) -> u16 {
+        Pio::<u16>::new(self.base + offset).read()
+    }
+
+    /// Reads the 32-bit register at `offset`.
+    fn read_u32(&self, offset: u16) -> u32 {
+        Pio::<u32>::new(self.base + offset).read()
+    }
+
+    /// Writes the 8-bit register at `offset`.
+    fn write_u8(&mut self, offset: u16, value: u8) {
+        Pio::<u8>::new(self.base + offset).write(value)
+    }
+
+    /// Writes the 16-bit register at `offset`.
+    fn write_u16(&mut self, offset: u16, value: u16) {
+        Pio::<u16>::new(self.base + offset).write(value)
+    }
+
+    /// Writes the 32-bit register at `offset`.
+    fn write_u32(&mut self, offset: u16, value: u32) {
+        Pio::<u32>::new(self.base + offset).write(value)
+    }
+}
+
+impl ModernTransport {
+    /// Finds the modern interface of a device, from its vendor-specific capabilities, and maps
+    /// the BARs its structures are found in.
+    ///
+    /// # Error
+    ///
+    /// Returns `None` and logs the reason if a structure is missing, or in a BAR we cannot map.
+    fn new(device: &PciDevice) -> Option<ModernTransport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device_config = None;
+        for cap in device.capabilities().filter(|cap| cap.id == PCI_CAP_ID_VENDOR) {
+            let register = cap.offset / 4;
+            let cfg_type = (device.read_config_register(register) >> 24) as u8;
+            let location = CapabilityLocation {
+                bar: device.read_config_register(register + 1) as u8 as usize,
+                offset: device.read_config_register(register + 2) as usize,
+                length: device.read_config_register(register + 3) as usize,
+            };
+            // the first capability of each type is the preferred one.
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG if common.is_none() => common = Some(location),
+                VIRTIO_PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = Some((location, device.read_config_register(register + 4)))
+                },
+                VIRTIO_PCI_CAP_ISR_CFG if isr.is_none() => isr = Some(location),
+                VIRTIO_PCI_CAP_DEVICE_CFG if device_config.is_none() => device_config = Some(location),
+                _ => ()
+            }
+        }
+        let (common, (notify, notify_off_multiplier), isr, device_config) = match (common, notify, isr, device_config) {
+            (Some(common), Some(notify), Some(isr), Some(device_config))
+                if common.length >= size_of::<CommonCfg>() && isr.length >= 1 => (common, notify, isr, device_config),
+            structures => {
+                error!("Device {:?}: missing virtio structures {:x?}.", device.requester_id(), structures);
+                return None
+            }
+        };
+
+        let mut bars: [Option<(usize, usize)>; 6] = [None; 6];
+        let mut address_of = |location: CapabilityLocation| -> Option<usize> {
+            let bar = match bars.get_mut(location.bar) {
+                Some(bar) => bar,
+                None => {
+                    error!("Device {:?}: virtio structure in invalid BAR {}.", device.requester_id(), location.bar);
+                    return None
+                }
+            };
+            if bar.is_none() {
+                *bar = Some(map_bar(device, location.bar)?);
+            }
+            let (address, size) = (*bar)?;
+            if location.offset.saturating_add(location.length) > size {
+                error!("Device {:?}: virtio structure out of BAR {}.", device.requester_id(), location.bar);
+                return None
+            }
+            Some(address + location.offset)
+        };
+
+        let common_address = address_of(common)?;
+        let notify_address = address_of(notify)?;
+        let isr_address = address_of(isr)?;
+        let device_config_address = address_of(device_config)?;
+        unsafe {
+            // safe: the structures are in the mappings of their BARs, which are never unmapped,
+            //       and nobody else references them.
+            Some(ModernTransport {
+                common: &mut *(common_address as *mut CommonCfg),
+                isr: &*(isr_address as *const Mmio<u8>),
+                notify: notify_address,
+                notify_off_multiplier,
+                device_config: device_config_address,
+                device_config_len: device_config.length,
+                queue_notify_offsets: [0; 1],
+            })
+        }
+    }
+}
+
+/// Maps the memory space BAR `index` of a device.
+///
+/// Returns the address it was mapped at, and its size. The mapping is never unmapped.
+///
+/// # Error
+///
+/// Returns `None` and logs the reason if the BAR is not a memory space BAR, or is above 4GiB.
+fn map_bar(device: &PciDevice, index: usize) -> Option<(usize, usize)> {
+    let (address, size) = match device.bar(index) {
+        Some(BAR::Memory(address, size)) => (address as usize, size as usize),
+        Some(BAR::Memory64(address, size)) if address.saturating_add(size) <= 0x1_0000_0000 => (address as usize, size as usize),
+        bar => {
+            error!("Device {:?}: cannot map BAR{} {:x?}.", device.requester_id(), index, bar);
+            return None
+        }
+    };
+    let mapping_size = align_up(size, PAGE_SIZE);
+    let mapped = find_free_address(mapping_size, PAGE_SIZE)
+        .and_then(|virtual_address| {
+            syscalls::map_mmio_region(address, mapping_size, virtual_address, true)?;
+            Ok(virtual_address)
+        });
+    match mapped {
+        Ok(virtual_address) => Some((virtual_address, size)),
+        Err(e) => {
+            error!("Device {:?}: failed mapping BAR{}, {:?}.", device.requester_id(), index, e);
+            None
+        }
+    }
+}
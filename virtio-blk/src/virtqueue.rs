@@ -0,0 +1,235 @@
+//! Split virtqueues
+//!
+//! A virtqueue is how the driver hands buffers to the device, and how the device gives them back.
+//! It is made of three parts, see virtio 1.0 spec section 2.4:
+//!
+//! - the descriptor table, describing the buffers. A request is a chain of descriptors.
+//! - the available ring, where the driver puts the head of the chains it offers to the device.
+//! - the used ring, where the device puts the head of the chains it is done with.
+//!
+//! The three parts are allocated together, laid out as required by the legacy interface, which
+//! only takes the address of the descriptor table. See virtio 1.0 spec section 2.4.2.
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use sunrise_libuser::dma::Dma;
+use sunrise_libuser::error::Error;
+use sunrise_libuser::mem::{find_free_address, PAGE_SIZE};
+use sunrise_libuser::syscalls::MemoryPermissions;
+use sunrise_libuser::types::{MappedSharedMemory, SharedMemory};
+use sunrise_libutils::align_up;
+
+/// Descriptor flag: the chain continues with the descriptor in the `next` field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// Descriptor flag: the buffer is written by the device, otherwise it is read by the device.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// Used ring flag: the device doesn't need to be notified when buffers are made available.
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/// The size of a descriptor: `addr: u64, len: u32, flags: u16, next: u16`.
+const DESCRIPTOR_SIZE: usize = 16;
+/// The size of an element of the used ring: `id: u32, len: u32`.
+const USED_ELEMENT_SIZE: usize = 8;
+
+/// A split virtqueue.
+///
+/// The descriptors of the chains we offered to the device are not free until the device puts
+/// them in the used ring. The descriptors of a chain are reported used by [pop_used], which
+/// returns the head of the chain, identifying the request it was made for.
+///
+/// The virtqueue memory must be accessible to the device, it is made so by [new] in the given
+/// [Dma]. When the virtqueue is not used by the device anymore, it must be unmapped by our owner,
+/// using [memory].
+///
+/// [pop_used]: Virtqueue::pop_used
+/// [new]: Virtqueue::new
+/// [memory]: Virtqueue::memory
+#[derive(Debug)]
+pub struct Virtqueue {
+    /// The memory of the descriptor table and of the two rings.
+    memory: MappedSharedMemory,
+    /// The number of descriptors, and of elements of both rings.
+    size: u16,
+    /// The offset of the available ring in [memory](Virtqueue::memory).
+    avail_offset: usize,
+    /// The offset of the used ring in [memory](Virtqueue::memory).
+    used_offset: usize,
+    /// The device addresses of the descriptor table, available ring, and used ring.
+    device_addresses: (u64, u64, u64),
+    /// The descriptors we can use to make new chains.
+    free: Vec<u16>,
+    /// Our copy of the index of the available ring, the number of chains we made available.
+    avail_idx: u16,
+    /// The index of the next element of the used ring we will read.
+    last_used: u16,
+}
+
+impl Virtqueue {
+    /// Allocates a virtqueue of `size` elements, and makes it accessible to the device.
+    ///
+    /// When the device accesses physical memory, the descriptor table and the rings must be
+    /// physically contiguous, and so must be the whole virtqueue for the legacy interface. We
+    /// always allocate it in physically contiguous memory.
+    ///
+    /// # Error
+    ///
+    /// * allocating or mapping the memory failed.
+    ///
+    /// # Panics
+    ///
+    /// * `size` is not a power of 2.
+    pub fn new(size: u16, dma: &mut Dma) -> Result<Virtqueue, Error> {
+        assert!(size.is_power_of_two(), "Virtqueue size must be a power of 2");
+        let queue_size = usize::from(size);
+        let avail_offset = DESCRIPTOR_SIZE * queue_size;
+        let used_offset = align_up(avail_offset + 6 + 2 * queue_size, PAGE_SIZE);
+        let length = used_offset + align_up(6 + USED_ELEMENT_SIZE * queue_size, PAGE_SIZE);
+
+        let rw = MemoryPermissions::READABLE | MemoryPermissions::WRITABLE;
+        let memory = SharedMemory::new_contiguous(length, rw, MemoryPermissions::DONT_CARE)?;
+        let address = find_free_address(length, PAGE_SIZE)?;
+        let memory = memory.map(address, length, rw)?;
+        unsafe {
+            // safe: we just mapped this memory, nobody else is using it.
+            ptr::write_bytes(memory.as_mut_ptr(), 0, length);
+        }
+        let base = memory.as_ptr() as usize;
+        let desc = dma.contiguous(base, length)?;
+        dma.map(memory.as_ptr(), length, rw)?;
+        Ok(Virtqueue {
+            memory,
+            size,
+            avail_offset,
+            used_offset,
+            device_addresses: (desc, desc + avail_offset as u64, desc + used_offset as u64),
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            last_used: 0,
+        })
+    }
+
+    /// Returns the number of descriptors, and of elements of both rings.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the device addresses of the descriptor table, the available ring, and the used
+    /// ring, to be given to the device.
+    pub fn device_addresses(&self) -> (u64, u64, u64) {
+        self.device_addresses
+    }
+
+    /// Returns the address and length of the virtqueue memory.
+    pub fn memory(&self) -> (*const u8, usize) {
+        (self.memory.as_ptr(), self.memory.len())
+    }
+
+    /// Returns the head of the next chain of `length` descriptors made by [add](Virtqueue::add),
+    /// or None if there are not enough free descriptors.
+    pub fn next_head(&self, length: usize) -> Option<u16> {
+        self.free.len().checked_sub(length).and_then(|index| self.free.get(index)).copied()
+    }
+
+    /// Reads the `T` at `offset` in the virtqueue memory.
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        debug_assert!(offset + core::mem::size_of::<T>() <= self.memory.len());
+        unsafe {
+            // safe: offset is in the virtqueue memory, and the fields are naturally aligned.
+            ptr::read_volatile(self.memory.as_ptr().add(offset) as *const T)
+        }
+    }
+
+    /// Writes the `T` at `offset` in the virtqueue memory.
+    fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        debug_assert!(offset + core::mem::size_of::<T>() <= self.memory.len());
+        unsafe {
+            // safe: offset is in the virtqueue memory, and the fields are naturally aligned.
+            ptr::write_volatile(self.memory.as_mut_ptr().add(offset) as *mut T, value)
+        }
+    }
+
+    /// Makes a chain of descriptors for `buffers`, and makes it available to the device.
+    ///
+    /// `buffers` are described by their device address, their length, and whether they are
+    /// written by the device. The device expects all the buffers it reads to come before the
+    /// ones it writes.
+    ///
+    /// The device might have to be notified of the new chain, see
+    /// [needs_notification](Virtqueue::needs_notification).
+    ///
+    /// # Returns
+    ///
+    /// The head of the chain, which [pop_used](Virtqueue::pop_used) returns once the device is
+    /// done with it, or None if there are not enough free descriptors.
+    ///
+    /// # Unsafety
+    ///
+    /// The buffers must stay accessible to the device until the chain is used.
+    pub unsafe fn add(&mut self, buffers: &[(u64, u32, bool)]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let descriptors = self.free.split_off(self.free.len() - buffers.len());
+        for (index, &(address, length, device_writable)) in buffers.iter().enumerate() {
+            let descriptor = usize::from(descriptors[index]) * DESCRIPTOR_SIZE;
+            let mut flags = if device_writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            let next = match descriptors.get(index + 1) {
+                Some(next) => {
+                    flags |= VIRTQ_DESC_F_NEXT;
+                    *next
+                },
+                None => 0,
+            };
+            self.write(descriptor, address);
+            self.write(descriptor + 8, length);
+            self.write(descriptor + 12, flags);
+            self.write(descriptor + 14, next);
+        }
+
+        let head = descriptors[0];
+        let ring_element = self.avail_offset + 4 + 2 * usize::from(self.avail_idx % self.size);
+        self.write(ring_element, head);
+        // the device must see the chain before the new index.
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.write(self.avail_offset + 2, self.avail_idx);
+        Some(head)
+    }
+
+    /// Whether the device wants to be notified of the chains we just made available.
+    pub fn needs_notification(&self) -> bool {
+        // read the flags after the device could see the new index.
+        fence(Ordering::SeqCst);
+        self.read::<u16>(self.used_offset) & VIRTQ_USED_F_NO_NOTIFY == 0
+    }
+
+    /// Takes the next chain the device is done with from the used ring, and frees its descriptors.
+    ///
+    /// # Returns
+    ///
+    /// The head of the chain, and the number of bytes the device wrote to its buffers, or None
+    /// if the device didn't use any new chain.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if self.read::<u16>(self.used_offset + 2) == self.last_used {
+            return None;
+        }
+        // read the element after the device wrote it.
+        fence(Ordering::SeqCst);
+        let ring_element = self.used_offset + 4 + USED_ELEMENT_SIZE * usize::from(self.last_used % self.size);
+        let head = self.read::<u32>(ring_element) as u16;
+        let written = self.read::<u32>(ring_element + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut descriptor = head;
+        loop {
+            self.free.push(descriptor);
+            let offset = usize::from(descriptor) * DESCRIPTOR_SIZE;
+            if self.read::<u16>(offset + 12) & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            descriptor = self.read(offset + 14);
+        }
+        Some((head, written))
+    }
+}